@@ -19,6 +19,7 @@ use parity_db::{Key, Value};
 pub trait Db: Send + Sync + 'static {
 	type Options;
 
+	#[allow(dead_code)]
 	fn open(path: &std::path::Path) -> Self;
 	fn with_options(options: &Self::Options) -> Self;
 	fn get(&self, key: &Key) -> Option<Value>;
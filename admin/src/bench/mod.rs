@@ -31,10 +31,13 @@ use std::{
 		Arc,
 	},
 	thread,
+	time::{Duration, Instant},
 };
 
 static COMMITS: AtomicUsize = AtomicUsize::new(0);
-//static QUERIES: AtomicUsize = AtomicUsize::new(0);
+static QUERIES: AtomicUsize = AtomicUsize::new(0);
+// One past the last commit written by any writer.
+static WRITTEN: AtomicUsize = AtomicUsize::new(0);
 
 const COMMIT_SIZE: usize = 100;
 
@@ -45,6 +48,9 @@ const KEY_RESTART: Key = [1u8; 32];
 const COMMIT_PRUNE_SIZE: usize = 90;
 const COMMIT_PRUNE_WINDOW: usize = 2000;
 
+// Number of values checked by each periodic verification.
+const VERIFY_SAMPLE: usize = 1000;
+
 pub(super) struct BenchAdapter(parity_db::Db);
 
 impl BenchDb for BenchAdapter {
@@ -57,7 +63,7 @@ impl BenchDb for BenchAdapter {
 	fn with_options(options: &Self::Options) -> Self {
 		let mut db_options = options.0.clone();
 		if options.1.compress {
-			for mut c in &mut db_options.columns {
+			for c in &mut db_options.columns {
 				c.compression = CompressionType::Lz4;
 			}
 		}
@@ -73,12 +79,17 @@ impl BenchDb for BenchAdapter {
 	}
 }
 
-/// Stress tests (warning erase db first).
+/// Stress tests: concurrent writers and readers, verifying values (warning erase db first).
 #[derive(Debug, StructOpt)]
 pub struct Stress {
 	#[structopt(flatten)]
 	pub shared: Shared,
 
+	/// Database path. Same as `--base-path`. The database is created in a `test_db_stress`
+	/// directory under it.
+	#[structopt(parse(from_os_str))]
+	pub path: Option<std::path::PathBuf>,
+
 	/// Number of reading threads [default: 4].
 	#[structopt(long)]
 	pub readers: Option<usize>,
@@ -114,6 +125,65 @@ pub struct Stress {
 	/// Use btree index.
 	#[structopt(long)]
 	pub ordered: bool,
+
+	/// Value size distribution: `kusama`, `fixed:<size>`, `uniform:<min>-<max>` or a comma
+	/// separated list of `<size>:<weight>` [default: kusama].
+	#[structopt(long)]
+	pub value_size_dist: Option<SizeDistribution>,
+
+	/// Check a sample of written values every given number of seconds while writing.
+	#[structopt(long)]
+	pub verify_interval: Option<u64>,
+}
+
+/// Distribution of generated value sizes.
+#[derive(Clone, Debug)]
+pub enum SizeDistribution {
+	/// Sizes with their relative weights.
+	Histogram(Vec<(u32, u32)>),
+	/// Sizes drawn uniformly from an inclusive range.
+	Uniform(u32, u32),
+}
+
+impl std::str::FromStr for SizeDistribution {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let parse =
+			|n: &str| n.trim().parse::<u32>().map_err(|e| format!("Invalid number {:?}: {}", n, e));
+		if s == "kusama" {
+			return Ok(SizeDistribution::Histogram(sizes::KUSAMA_STATE_DISTRIBUTION.to_vec()))
+		}
+		if let Some(size) = s.strip_prefix("fixed:") {
+			return Ok(SizeDistribution::Histogram(vec![(parse(size)?, 1)]))
+		}
+		if let Some(range) = s.strip_prefix("uniform:") {
+			let (min, max) =
+				range.split_once('-').ok_or_else(|| format!("Invalid range {:?}", range))?;
+			let (min, max) = (parse(min)?, parse(max)?);
+			if min > max {
+				return Err(format!("Empty range {:?}", range))
+			}
+			return Ok(SizeDistribution::Uniform(min, max))
+		}
+		let mut histogram = Vec::new();
+		for entry in s.split(',') {
+			let (size, weight) = entry
+				.split_once(':')
+				.ok_or_else(|| format!("Expected <size>:<weight>, got {:?}", entry))?;
+			let weight = parse(weight)?;
+			if weight > 0 {
+				histogram.push((parse(size)?, weight));
+			}
+		}
+		if histogram.is_empty() {
+			return Err("Size distribution has no weight".into())
+		}
+		if histogram.iter().map(|(_, weight)| *weight as u64).sum::<u64>() > u32::MAX as u64 {
+			return Err("Size distribution weights are too large".into())
+		}
+		Ok(SizeDistribution::Histogram(histogram))
+	}
 }
 
 #[derive(Clone)]
@@ -126,7 +196,10 @@ pub struct Args {
 	pub append: bool,
 	pub no_check: bool,
 	pub compress: bool,
+	#[allow(dead_code)]
 	pub ordered: bool,
+	pub value_sizes: SizeDistribution,
+	pub verify_interval: Option<Duration>,
 }
 
 impl Stress {
@@ -135,12 +208,16 @@ impl Stress {
 			readers: self.readers.unwrap_or(4),
 			writers: self.writers.unwrap_or(1),
 			commits: self.commits.unwrap_or(100_000),
-			seed: self.seed.clone(),
+			seed: self.seed,
 			append: self.append,
 			archive: self.archive,
 			no_check: self.no_check,
 			compress: self.compress,
 			ordered: self.ordered,
+			value_sizes: self.value_size_dist.clone().unwrap_or_else(|| {
+				SizeDistribution::Histogram(sizes::KUSAMA_STATE_DISTRIBUTION.to_vec())
+			}),
+			verify_interval: self.verify_interval.map(Duration::from_secs),
 		}
 	}
 }
@@ -148,9 +225,18 @@ impl Stress {
 struct SizePool {
 	distribution: std::collections::BTreeMap<u32, u32>,
 	total: u32,
+	uniform: Option<(u32, u32)>,
 }
 
 impl SizePool {
+	fn new(sizes: &SizeDistribution) -> SizePool {
+		match sizes {
+			SizeDistribution::Histogram(h) => Self::from_histogram(h),
+			SizeDistribution::Uniform(min, max) =>
+				SizePool { distribution: Default::default(), total: 0, uniform: Some((*min, *max)) },
+		}
+	}
+
 	fn from_histogram(h: &[(u32, u32)]) -> SizePool {
 		let mut distribution = std::collections::BTreeMap::default();
 		let mut total = 0;
@@ -158,18 +244,21 @@ impl SizePool {
 			total += count;
 			distribution.insert(total, *size);
 		}
-		SizePool { distribution, total }
+		SizePool { distribution, total, uniform: None }
 	}
 
 	fn value(&self, seed: u64, compressable: bool) -> Vec<u8> {
 		let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
-		let sr = (rng.next_u64() % self.total as u64) as u32;
-		let mut range = self
-			.distribution
-			.range((std::ops::Bound::Included(sr), std::ops::Bound::Unbounded));
-		let size = *range.next().unwrap().1 as usize;
-		let mut v = Vec::new();
-		v.resize(size, 0);
+		let size = if let Some((min, max)) = self.uniform {
+			(min as u64 + rng.next_u64() % (max - min + 1) as u64) as usize
+		} else {
+			let sr = (rng.next_u64() % self.total as u64) as u32;
+			let mut range = self
+				.distribution
+				.range((std::ops::Bound::Included(sr), std::ops::Bound::Unbounded));
+			*range.next().unwrap().1 as usize
+		};
+		let mut v = vec![0; size];
 		let fill = if !compressable { size } else { size / 2 };
 		rng.fill_bytes(&mut v[..fill]);
 		v
@@ -185,22 +274,83 @@ impl SizePool {
 
 fn informant(shutdown: Arc<AtomicBool>, total: usize, start: usize) {
 	let mut last = start;
+	let mut last_queries = 0;
 	let mut last_time = std::time::Instant::now();
 	while !shutdown.load(Ordering::Relaxed) {
 		thread::sleep(std::time::Duration::from_secs(1));
 		let commits = COMMITS.load(Ordering::Acquire);
+		let queries = QUERIES.load(Ordering::Acquire);
 		let now = std::time::Instant::now();
+		let elapsed = (now - last_time).as_secs_f64();
 		println!(
-			"{}/{} commits, {} cps",
+			"{}/{} commits, {} cps, {} qps",
 			commits - start,
 			total,
-			((commits - last) as f64) / (now - last_time).as_secs_f64()
+			((commits - last) as f64) / elapsed,
+			((queries - last_queries) as f64) / elapsed,
 		);
 		last = commits;
+		last_queries = queries;
 		last_time = now;
 	}
 }
 
+// A random value written since `start_commit` that is never pruned.
+fn written_key(rng: &mut impl RngCore, args: &Args, start_commit: usize) -> Option<u64> {
+	let written = WRITTEN.load(Ordering::Acquire);
+	if written <= start_commit {
+		return None
+	}
+	let nc = start_commit as u64 + rng.next_u64() % (written - start_commit) as u64;
+	let first = if args.archive { 0 } else { COMMIT_PRUNE_SIZE as u64 };
+	let index = first + rng.next_u64() % (COMMIT_SIZE as u64 - first);
+	Some(nc * COMMIT_SIZE as u64 + index + args.seed.unwrap_or(0))
+}
+
+fn check_value<D: BenchDb>(db: &D, pool: &SizePool, args: &Args, key: u64, present: bool) {
+	let expected = if present { Some(pool.value(key, args.compress)) } else { None };
+	let value = db.get(&pool.key(key));
+	QUERIES.fetch_add(1, Ordering::Relaxed);
+	if value != expected {
+		panic!(
+			"Value mismatch for key {}: expected {:?} bytes, found {:?} bytes",
+			key,
+			expected.map(|v| v.len()),
+			value.map(|v| v.len()),
+		);
+	}
+}
+
+// Check a sample of the values written so far. Pruned values are only checked with a single
+// writer, since concurrent writers insert them again.
+fn verify<D: BenchDb>(
+	db: &D,
+	pool: &SizePool,
+	args: &Args,
+	rng: &mut impl RngCore,
+	start_commit: usize,
+) {
+	let start = Instant::now();
+	let mut checked = 0;
+	for _ in 0..VERIFY_SAMPLE {
+		if let Some(key) = written_key(rng, args, start_commit) {
+			check_value(db, pool, args, key, true);
+			checked += 1;
+		}
+	}
+	let pruned = WRITTEN.load(Ordering::Acquire).saturating_sub(COMMIT_PRUNE_WINDOW + 1);
+	if !args.archive && args.writers == 1 && pruned > start_commit {
+		for _ in 0..VERIFY_SAMPLE {
+			let nc = start_commit as u64 + rng.next_u64() % (pruned - start_commit) as u64;
+			let index = rng.next_u64() % COMMIT_PRUNE_SIZE as u64;
+			let key = nc * COMMIT_SIZE as u64 + index + args.seed.unwrap_or(0);
+			check_value(db, pool, args, key, false);
+			checked += 1;
+		}
+	}
+	println!("Verified {} values in {} seconds", checked, start.elapsed().as_secs_f64());
+}
+
 fn writer<D: BenchDb>(
 	db: Arc<D>,
 	args: Arc<Args>,
@@ -232,22 +382,35 @@ fn writer<D: BenchDb>(
 
 		db.commit(commit.drain(..));
 		COMMITS.fetch_add(1, Ordering::Release);
+		WRITTEN.fetch_max(n + 1, Ordering::Release);
 		commit.clear();
 	}
 	commit.clear();
 }
 
-fn reader<D: BenchDb>(_db: Arc<D>, shutdown: Arc<AtomicBool>) {
-	// Query a random  key
+fn reader<D: BenchDb>(
+	db: Arc<D>,
+	args: Arc<Args>,
+	pool: Arc<SizePool>,
+	shutdown: Arc<AtomicBool>,
+	start_commit: usize,
+	index: usize,
+) {
+	let mut rng = rand::rngs::SmallRng::seed_from_u64(index as u64);
+	// Query a random key
 	while !shutdown.load(Ordering::Relaxed) {
-		thread::sleep(std::time::Duration::from_millis(500));
+		if let Some(key) = written_key(&mut rng, &args, start_commit) {
+			check_value(&*db, &pool, &args, key, true);
+		} else {
+			thread::sleep(std::time::Duration::from_millis(10));
+		}
 	}
 }
 
 pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 	let args = Arc::new(args);
 	let shutdown = Arc::new(AtomicBool::new(false));
-	let pool = Arc::new(SizePool::from_histogram(&sizes::KUSAMA_STATE_DISTRIBUTION));
+	let pool = Arc::new(SizePool::new(&args.value_sizes));
 	let db = Arc::new(db) as Arc<D>;
 	let start = std::time::Instant::now();
 
@@ -261,7 +424,8 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 		0
 	};
 
-	COMMITS.store(start_commit as usize, Ordering::SeqCst);
+	COMMITS.store(start_commit, Ordering::SeqCst);
+	WRITTEN.store(start_commit, Ordering::SeqCst);
 
 	{
 		let commits = args.commits;
@@ -273,11 +437,13 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 	for i in 0..args.readers {
 		let db = db.clone();
 		let shutdown = shutdown.clone();
+		let pool = pool.clone();
+		let args = args.clone();
 
 		threads.push(
 			thread::Builder::new()
 				.name(format!("reader {}", i))
-				.spawn(move || reader(db, shutdown))
+				.spawn(move || reader(db, args, pool, shutdown, start_commit, i))
 				.unwrap(),
 		);
 	}
@@ -296,8 +462,16 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 		);
 	}
 
+	let mut rng = rand::rngs::SmallRng::seed_from_u64(args.readers as u64);
+	let mut next_verify = args.verify_interval.map(|interval| Instant::now() + interval);
 	while COMMITS.load(Ordering::Relaxed) < start_commit + args.commits {
 		thread::sleep(std::time::Duration::from_millis(50));
+		if let (Some(next), Some(interval)) = (next_verify, args.verify_interval) {
+			if Instant::now() >= next {
+				verify(&*db, &pool, &args, &mut rng, start_commit);
+				next_verify = Some(Instant::now() + interval);
+			}
+		}
 	}
 	shutdown.store(true, Ordering::SeqCst);
 
@@ -320,19 +494,25 @@ pub fn run_internal<D: BenchDb>(args: Args, db: D) {
 		return
 	}
 
-	// Verify content
+	// Verify content. Concurrent writers share the key range, so only commits up to the
+	// furthest writer are checked and pruned values are only checked with a single writer.
 	let start = std::time::Instant::now();
 	let pruned_per_commit = if args.archive { 0u64 } else { COMMIT_PRUNE_SIZE as u64 };
 	let mut queries = 0;
+	let commits = WRITTEN.load(Ordering::SeqCst) - start_commit;
 	for nc in start_commit as u64..(start_commit + commits) as u64 {
 		let counter = nc - start_commit as u64;
-		if counter % 1000 == 0 {
+		if counter.is_multiple_of(1000) {
 			println!("Query {}/{}", counter, commits,);
 		}
 		let commits = (start_commit + commits) as u64;
 		let prune_window: u64 = COMMIT_PRUNE_WINDOW as u64;
 		let offset = args.seed.unwrap_or(0);
-		let start = if !args.archive && commits > prune_window && nc < commits - prune_window {
+		let start = if !args.archive &&
+			args.writers == 1 &&
+			commits > prune_window &&
+			nc < commits - prune_window
+		{
 			let end = nc * COMMIT_SIZE as u64 + pruned_per_commit + offset;
 			for key in (nc * COMMIT_SIZE as u64) + offset..end {
 				let k = pool.key(key);
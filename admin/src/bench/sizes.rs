@@ -15,7 +15,7 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 /// Kusama value size distribution
-pub const KUSAMA_STATE_DISTRIBUTION: &'static [(u32, u32)] = &[
+pub const KUSAMA_STATE_DISTRIBUTION: &[(u32, u32)] = &[
 	(32, 35),
 	(33, 20035),
 	(34, 5369),
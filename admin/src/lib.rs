@@ -29,16 +29,15 @@ pub fn run() -> Result<(), String> {
 
 	let mut builder = Builder::from_default_env();
 	let mut logs = cli.shared().log.clone();
-	if logs.len() == 0 {
+	if logs.is_empty() {
 		logs.push("info".to_string());
 	}
 	builder.parse_filters(logs.as_slice().join(",").as_str());
 	builder.init();
 
 	let db_path = cli
-		.shared()
-		.base_path
-		.clone()
+		.path()
+		.cloned()
 		.unwrap_or_else(|| std::env::current_dir().expect("Cannot resolve current dir"));
 	let nb_column = cli.shared().columns.unwrap_or(1);
 	let mut options = if let Some(metadata) = parity_db::Options::load_metadata(&db_path)
@@ -60,23 +59,22 @@ pub fn run() -> Result<(), String> {
 	options.sync_wal = !cli.shared().no_sync;
 	options.sync_data = !cli.shared().no_sync;
 	options.stats = cli.shared().with_stats;
-	println!("Options {:?}, {:?}", cli, options);
+	log::info!("Options {:?}, {:?}", cli, options);
 	match cli.subcommand {
 		SubCommand::Stats(stat) => {
 			let db = parity_db::Db::open_read_only(&options)
 				.map_err(|e| format!("Invalid db: {:?}", e))?;
 			if stat.clear {
-				db.clear_stats(stat.column.clone());
+				db.clear_stats(stat.column);
 			} else {
-				let mut out = std::io::stdout();
-				db.collect_stats(&mut out, stat.column.clone());
+				write_stats(&db, stat.column, &mut std::io::stdout())?;
 			}
 		},
 		SubCommand::Migrate(args) => {
 			use parity_db::Options;
 			let dest_meta = Options::load_metadata_file(&args.dest_meta)
 				.map_err(|e| format!("Error loading dest metadata: {:?}", e))?
-				.ok_or_else(|| format!("Error opening dest metadata file"))?;
+				.ok_or_else(|| "Error opening dest metadata file".to_string())?;
 
 			let dest_columns = dest_meta.columns;
 
@@ -87,7 +85,8 @@ pub fn run() -> Result<(), String> {
 			std::fs::create_dir_all(&args.dest_path)
 				.map_err(|e| format!("Error creating dest dir: {:?}", e))?;
 
-			let mut dest_options = Options::with_columns(&args.dest_path, dest_columns.len() as u8);
+			let mut dest_options =
+				Options::with_columns(&args.dest_path, dest_columns.len() as u16);
 			dest_options.columns = dest_columns;
 			dest_options.sync_wal = false;
 			dest_options.sync_data = false;
@@ -101,12 +100,12 @@ pub fn run() -> Result<(), String> {
 			}
 		},
 		SubCommand::Check(check) => {
-			let db = parity_db::Db::open_read_only(&options)
-				.map_err(|e| format!("Invalid db: {:?}", e))?;
-			if !check.index_value {
-				// Note that we should use enum parameter instead.
-				return Err("Requires one of the following check flag: --index-value".to_string())
+			let db = if check.repair {
+				parity_db::Db::open(&options)
+			} else {
+				parity_db::Db::open_read_only(&options)
 			}
+			.map_err(|e| format!("Invalid db: {:?}", e))?;
 			let check_param = parity_db::CheckOptions::new(
 				check.column,
 				check.range_start,
@@ -114,7 +113,91 @@ pub fn run() -> Result<(), String> {
 				check.display,
 				check.display_value_max,
 			);
-			db.dump(check_param).map_err(|e| format!("Check error: {:?}", e))?;
+			let report = db.check(check_param).map_err(|e| format!("Check error: {:?}", e))?;
+			let mut unrepaired = 0;
+			let mut corrupted = Vec::new();
+			for entry in &report.corrupted {
+				let repair = if check.repair {
+					Some(db.force_remove_at(entry.col, entry.tier, entry.index))
+				} else {
+					None
+				};
+				if !matches!(repair, Some(Ok(()))) {
+					unrepaired += 1;
+				}
+				if check.json {
+					corrupted.push(serde_json::json!({
+						"column": entry.col,
+						"chunk": entry.chunk,
+						"tier": entry.tier,
+						"index": entry.index,
+						"error": entry.error,
+						"repaired": matches!(repair, Some(Ok(()))),
+						"repair_error": repair.and_then(|r| r.err()).map(|e| e.to_string()),
+					}));
+				} else {
+					println!(
+						"Corrupted entry: column {}, chunk {}, tier {}, index {}: {}",
+						entry.col,
+						entry.chunk,
+						entry.tier,
+						entry.index,
+						entry.error.as_deref().unwrap_or("free slot"),
+					);
+					match repair {
+						Some(Ok(())) => println!("\tRemoved"),
+						Some(Err(e)) => println!("\tRepair failed: {:?}", e),
+						None => (),
+					}
+				}
+			}
+			if check.json {
+				let json = serde_json::json!({
+					"entries": report.entries,
+					"corrupted": corrupted,
+					"unrepaired": unrepaired,
+				});
+				println!("{}", json);
+			} else {
+				println!(
+					"Checked {} entries, {} corrupted, {} left unrepaired",
+					report.entries,
+					report.corrupted.len(),
+					unrepaired,
+				);
+			}
+			if unrepaired > 0 {
+				return Err(format!("{} corrupted entries", unrepaired))
+			}
+		},
+		SubCommand::Dump(dump) => {
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			let file = std::fs::File::create(&dump.output)
+				.map_err(|e| format!("Error creating output file: {:?}", e))?;
+			let mut writer = std::io::BufWriter::new(file);
+			let count = match &dump.prefix {
+				Some(prefix) => {
+					let prefix =
+						hex::decode(prefix).map_err(|e| format!("Invalid prefix: {:?}", e))?;
+					db.export_prefix(dump.col, &prefix, &mut writer)
+				},
+				None => db.export(dump.col, &mut writer),
+			}
+			.map_err(|e| format!("Dump error: {:?}", e))?;
+			std::io::Write::flush(&mut writer)
+				.map_err(|e| format!("Error writing output file: {:?}", e))?;
+			println!("Dumped {} entries", count);
+		},
+		SubCommand::Restore(restore) => {
+			let db = parity_db::Db::open_or_create(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			let file = std::fs::File::open(&restore.input)
+				.map_err(|e| format!("Error opening input file: {:?}", e))?;
+			let count = db
+				.import(restore.col, std::io::BufReader::new(file))
+				.map_err(|e| format!("Restore error: {:?}", e))?;
+			println!("Restored {} entries", count);
 		},
 		SubCommand::Flush(_flush) => {
 			let _db = parity_db::Db::open(&options).map_err(|e| format!("Invalid db: {:?}", e))?;
@@ -155,7 +238,7 @@ pub struct Shared {
 	/// Indicate the number of column, when using
 	/// a new or temporary db, defaults to one.
 	#[structopt(long)]
-	pub columns: Option<u8>,
+	pub columns: Option<u16>,
 
 	/// Sets a custom logging filter. Syntax is <target>=<level>, e.g. -lsync=debug.
 	///
@@ -183,7 +266,7 @@ pub struct Cli {
 
 #[derive(Debug, StructOpt)]
 pub enum SubCommand {
-	/// Show stats.
+	/// Show column stats.
 	Stats(Stats),
 	/// Migrate db (update version or change column options).
 	Migrate(Migrate),
@@ -191,6 +274,10 @@ pub enum SubCommand {
 	Flush(Flush),
 	/// Check db content.
 	Check(Check),
+	/// Write a column to a file in the export format.
+	Dump(Dump),
+	/// Import a file written by `dump` into a column.
+	Restore(Restore),
 	/// Stress tests.
 	Stress(bench::Stress),
 }
@@ -206,26 +293,79 @@ impl SubCommand {
 }
 
 impl Cli {
+	// Database path given to the subcommand, or with `--base-path`.
+	fn path(&self) -> Option<&PathBuf> {
+		match &self.subcommand {
+			SubCommand::Stats(Stats { path: Some(path), .. }) |
+			SubCommand::Check(Check { path: Some(path), .. }) |
+			SubCommand::Dump(Dump { path: Some(path), .. }) |
+			SubCommand::Restore(Restore { path: Some(path), .. }) => Some(path),
+			SubCommand::Stress(bench::Stress { path: Some(path), .. }) => Some(path),
+			_ => self.shared().base_path.as_ref(),
+		}
+	}
+
 	fn shared(&self) -> &Shared {
 		match &self.subcommand {
 			SubCommand::Stats(stats) => &stats.shared,
 			SubCommand::Migrate(stats) => &stats.shared,
 			SubCommand::Flush(flush) => &flush.shared,
+			SubCommand::Dump(dump) => &dump.shared,
+			SubCommand::Restore(restore) => &restore.shared,
 			SubCommand::Check(check) => &check.shared,
 			SubCommand::Stress(bench) => &bench.shared,
 		}
 	}
 }
 
-/// Show stats for columns.
+/// Write column sizes, tier occupancy and collected stats for `column`, or for all columns.
+pub fn write_stats(
+	db: &parity_db::Db,
+	column: Option<u16>,
+	out: &mut impl std::io::Write,
+) -> Result<(), String> {
+	let sizes = db.column_sizes().map_err(|e| format!("Stats error: {:?}", e))?;
+	for (col, size) in sizes.iter().enumerate() {
+		let col = col as u16;
+		if column.is_some_and(|c| c != col) {
+			continue
+		}
+		let info = db.column_info(col).map_err(|e| format!("Stats error: {:?}", e))?;
+		let write_err = |e| format!("Stats error: {:?}", e);
+		writeln!(
+			out,
+			"Column {} ({:?}): {} entries, {} live bytes, {} disk bytes",
+			col, info.kind, size.entries, size.live_bytes, size.disk_bytes,
+		)
+		.map_err(write_err)?;
+		if let Some(keys) = size.keys {
+			writeln!(out, "Keys: {}", keys).map_err(write_err)?;
+		}
+		writeln!(out, "Tier occupancy:").map_err(write_err)?;
+		for (tier, entries) in size.tier_entries.iter().enumerate() {
+			if *entries != 0 {
+				writeln!(out, "    {} ({} bytes): {}", tier, info.tier_entry_sizes[tier], entries)
+					.map_err(write_err)?;
+			}
+		}
+	}
+	db.collect_stats(out, column);
+	Ok(())
+}
+
+/// Show stats for columns: entry counts, tier occupancy and the value size histogram.
 #[derive(Debug, StructOpt)]
 pub struct Stats {
 	#[structopt(flatten)]
 	pub shared: Shared,
 
+	/// Database path. Same as `--base-path`.
+	#[structopt(parse(from_os_str))]
+	pub path: Option<PathBuf>,
+
 	/// Only show stat for the given column.
 	#[structopt(long)]
-	pub column: Option<u8>,
+	pub column: Option<u16>,
 
 	/// Clear current stats.
 	#[structopt(long)]
@@ -241,7 +381,7 @@ pub struct Migrate {
 	/// Force migration of given columns, even if
 	/// column option are unchanged (eg to repack table).
 	#[structopt(long)]
-	pub force_columns: Vec<u8>,
+	pub force_columns: Vec<u16>,
 
 	/// Overwrite source after each
 	/// column processing.
@@ -274,12 +414,24 @@ pub struct Check {
 	#[structopt(flatten)]
 	pub shared: Shared,
 
+	/// Database path. Same as `--base-path`.
+	#[structopt(parse(from_os_str))]
+	pub path: Option<PathBuf>,
+
+	/// Remove corrupted entries. Their values are lost.
+	#[structopt(long)]
+	pub repair: bool,
+
+	/// Print the report as JSON.
+	#[structopt(long)]
+	pub json: bool,
+
 	/// Only process a given column.
 	#[structopt(long)]
-	pub column: Option<u8>,
+	pub column: Option<u16>,
 
 	/// Parse indexes and
-	/// lookup values.
+	/// lookup values. This is the only check and is always done.
 	#[structopt(long)]
 	pub index_value: bool,
 
@@ -301,3 +453,80 @@ pub struct Check {
 	#[structopt(long)]
 	pub display_value_max: Option<u64>,
 }
+
+/// Write a column to a file in the export format.
+#[derive(Debug, StructOpt)]
+pub struct Dump {
+	#[structopt(flatten)]
+	pub shared: Shared,
+
+	/// Database path. Same as `--base-path`.
+	#[structopt(parse(from_os_str))]
+	pub path: Option<PathBuf>,
+
+	/// Column to dump.
+	#[structopt(long)]
+	pub col: u16,
+
+	/// Output file.
+	#[structopt(long, parse(from_os_str))]
+	pub output: PathBuf,
+
+	/// Only dump keys starting with this hex encoded prefix. Ordered columns only.
+	#[structopt(long)]
+	pub prefix: Option<String>,
+}
+
+/// Import a file written by `dump` into a column.
+#[derive(Debug, StructOpt)]
+pub struct Restore {
+	#[structopt(flatten)]
+	pub shared: Shared,
+
+	/// Database path. Same as `--base-path`. The database is created if it does not exist.
+	#[structopt(parse(from_os_str))]
+	pub path: Option<PathBuf>,
+
+	/// Column to restore into.
+	#[structopt(long)]
+	pub col: u16,
+
+	/// Input file.
+	#[structopt(long, parse(from_os_str))]
+	pub input: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::write_stats;
+
+	#[test]
+	fn test_write_stats() {
+		let tmp = tempfile::tempdir().unwrap();
+		let mut options = parity_db::Options::with_columns(tmp.path(), 2);
+		options.stats = true;
+		let db = parity_db::Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec())), (0, b"b", Some(vec![2; 100]))])
+			.unwrap();
+		drop(db);
+		let db = parity_db::Db::open_read_only(&options).unwrap();
+
+		let mut out = Vec::new();
+		write_stats(&db, Some(0), &mut out).unwrap();
+		let out = String::from_utf8(out).unwrap();
+		let size = &db.column_sizes().unwrap()[0];
+		assert!(out.starts_with(&format!(
+			"Column 0 (Hashed): 2 entries, {} live bytes, {} disk bytes\nKeys: 2\nTier occupancy:\n",
+			size.live_bytes, size.disk_bytes
+		)));
+		// The one byte value is in the smallest tier.
+		assert!(out.contains("\n    0 ("));
+		assert!(out.contains("Total values: 2\n"));
+		assert!(!out.contains("Column 1"));
+
+		let mut out = Vec::new();
+		write_stats(&db, None, &mut out).unwrap();
+		let out = String::from_utf8(out).unwrap();
+		assert!(out.contains("Column 1 (Hashed): 0 entries, 0 live bytes, 0 disk bytes\n"));
+	}
+}
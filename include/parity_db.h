@@ -0,0 +1,78 @@
+/*
+ * C API for parity-db. Build the library with
+ * `cargo rustc --release --features ffi --crate-type cdylib`.
+ * See `src/ffi.rs` for the documentation of each function.
+ */
+
+#ifndef PARITY_DB_H
+#define PARITY_DB_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+	PARITY_DB_OK = 0,
+	PARITY_DB_NOT_FOUND = 1,
+	PARITY_DB_INVALID_ARGUMENT = 2,
+	PARITY_DB_IO = 3,
+	PARITY_DB_CORRUPTION = 4,
+	PARITY_DB_INVALID_CONFIGURATION = 5,
+	PARITY_DB_INVALID_INPUT = 6,
+	PARITY_DB_LOCKED = 7,
+	PARITY_DB_BACKGROUND = 8,
+	PARITY_DB_DATABASE_NOT_FOUND = 9,
+	PARITY_DB_OTHER = 10,
+	PARITY_DB_QUOTA_EXCEEDED = 11,
+	PARITY_DB_PANIC = 12,
+} ParityDbStatus;
+
+typedef struct {
+	bool preimage;
+	bool uniform;
+	bool ref_counted;
+	bool btree_index;
+	/* 0 for no compression, 1 for lz4, 2 for snappy. */
+	uint8_t compression;
+} ParityDbColumnOptions;
+
+typedef struct {
+	uint16_t col;
+	const uint8_t *key;
+	size_t key_len;
+	/* Value to insert, or NULL to remove the key. */
+	const uint8_t *value;
+	size_t value_len;
+} ParityDbOp;
+
+typedef struct ParityDb ParityDb;
+
+typedef bool (*ParityDbIterCallback)(void *context, const uint8_t *key, size_t key_len,
+	const uint8_t *value, size_t value_len);
+
+const char *parity_db_last_error(void);
+
+ParityDbStatus parity_db_open(const char *path, const ParityDbColumnOptions *columns,
+	uint16_t num_columns, ParityDb **db);
+
+void parity_db_close(ParityDb *db);
+
+ParityDbStatus parity_db_get(const ParityDb *db, uint16_t col, const uint8_t *key,
+	size_t key_len, uint8_t **value, size_t *value_len);
+
+void parity_db_free_value(uint8_t *value, size_t value_len);
+
+ParityDbStatus parity_db_commit(const ParityDb *db, const ParityDbOp *ops, size_t num_ops);
+
+ParityDbStatus parity_db_iterate(const ParityDb *db, uint16_t col, ParityDbIterCallback callback,
+	void *context);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PARITY_DB_H */
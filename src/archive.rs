@@ -0,0 +1,280 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Archive of enacted log records.
+//!
+//! Archive files are named `changes{first_record_id}`. Each file is a sequence of frames:
+//! record id (u64 LE), record length (u32 LE), followed by the log record exactly as it was
+//! written to the log, including the trailing checksum.
+
+use crate::{
+	error::{Error, Result},
+	options::Options,
+};
+use parking_lot::Mutex;
+use std::{
+	collections::VecDeque,
+	convert::TryInto,
+	io::{Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+const FRAME_HEADER_SIZE: usize = 12;
+const ARCHIVE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+struct Archived {
+	// Archive files as (first record id, size).
+	files: VecDeque<(u64, u64)>,
+	current: Option<std::fs::File>,
+	last_record_id: u64,
+	dirty: bool,
+}
+
+pub struct ChangeArchive {
+	path: PathBuf,
+	max_bytes: Option<u64>,
+	archived: Mutex<Archived>,
+}
+
+fn file_path(root: &Path, first_record_id: u64) -> PathBuf {
+	let mut path: PathBuf = root.into();
+	path.push(format!("changes{}", first_record_id));
+	path
+}
+
+// Archive files in the directory, sorted by the first record id.
+fn list_files(root: &Path) -> Result<Vec<(u64, u64)>> {
+	let mut files = Vec::new();
+	for entry in std::fs::read_dir(root)? {
+		let entry = entry?;
+		if let Some(name) = entry.file_name().as_os_str().to_str() {
+			if entry.metadata()?.is_file() && name.starts_with("changes") {
+				if let Ok(id) = std::str::FromStr::from_str(&name[7..]) {
+					files.push((id, entry.metadata()?.len()));
+				}
+			}
+		}
+	}
+	files.sort_unstable();
+	Ok(files)
+}
+
+// Read the next complete frame at the current position. Returns `None` at the end of the file
+// or if the last frame is incomplete.
+fn read_frame(file: &mut std::fs::File) -> Result<Option<(u64, Vec<u8>)>> {
+	let mut header = [0u8; FRAME_HEADER_SIZE];
+	match file.read_exact(&mut header) {
+		Ok(()) => (),
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e.into()),
+	}
+	let record_id = u64::from_le_bytes(header[0..8].try_into().unwrap());
+	let len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+	let mut data = vec![0; len as usize];
+	match file.read_exact(&mut data) {
+		Ok(()) => Ok(Some((record_id, data))),
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+		Err(e) => Err(e.into()),
+	}
+}
+
+impl ChangeArchive {
+	pub fn open(options: &Options) -> Result<Option<ChangeArchive>> {
+		let path = match &options.change_archive {
+			Some(path) => path.clone(),
+			None => return Ok(None),
+		};
+		std::fs::create_dir_all(&path)?;
+		let files: VecDeque<_> = list_files(&path)?.into();
+		let mut last_record_id = 0;
+		let mut current = None;
+		if let Some((first_record_id, _)) = files.back() {
+			let mut file = std::fs::OpenOptions::new()
+				.read(true)
+				.write(true)
+				.open(file_path(&path, *first_record_id))?;
+			let mut end = 0;
+			while let Some((record_id, data)) = read_frame(&mut file)? {
+				last_record_id = record_id;
+				end += (FRAME_HEADER_SIZE + data.len()) as u64;
+			}
+			if end != file.metadata()?.len() {
+				log::debug!(target: "parity-db", "Truncating incomplete archive record at {}", end);
+				file.set_len(end)?;
+			}
+			file.seek(SeekFrom::Start(end))?;
+			current = Some(file);
+		}
+		log::debug!(target: "parity-db", "Opened change archive, last record {}", last_record_id);
+		Ok(Some(ChangeArchive {
+			path,
+			max_bytes: options.change_archive_max_bytes,
+			archived: Mutex::new(Archived { files, current, last_record_id, dirty: false }),
+		}))
+	}
+
+	/// Id of the last archived record, 0 if the archive is empty.
+	pub fn last_record_id(&self) -> u64 {
+		self.archived.lock().last_record_id
+	}
+
+	/// Append an enacted record. Records that are already archived are ignored.
+	pub fn append(&self, record_id: u64, record: &[u8]) -> Result<()> {
+		let mut archived = self.archived.lock();
+		if record_id <= archived.last_record_id {
+			return Ok(())
+		}
+		let rotate = archived.files.back().is_none_or(|(_, size)| *size >= ARCHIVE_FILE_SIZE);
+		if rotate {
+			if let Some(file) = archived.current.take() {
+				file.sync_data()?;
+			}
+			let file = std::fs::OpenOptions::new()
+				.create(true)
+				.truncate(true)
+				.write(true)
+				.open(file_path(&self.path, record_id))?;
+			log::debug!(target: "parity-db", "Started archive file for record {}", record_id);
+			archived.current = Some(file);
+			archived.files.push_back((record_id, 0));
+			self.prune(&mut archived)?;
+		}
+		let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + record.len());
+		frame.extend_from_slice(&record_id.to_le_bytes());
+		frame.extend_from_slice(&(record.len() as u32).to_le_bytes());
+		frame.extend_from_slice(record);
+		archived.current.as_mut().unwrap().write_all(&frame)?;
+		archived.files.back_mut().unwrap().1 += frame.len() as u64;
+		archived.last_record_id = record_id;
+		archived.dirty = true;
+		Ok(())
+	}
+
+	// Remove the oldest files while the archive is over the size limit.
+	fn prune(&self, archived: &mut Archived) -> Result<()> {
+		let max_bytes = match self.max_bytes {
+			Some(max_bytes) => max_bytes,
+			None => return Ok(()),
+		};
+		let mut total: u64 = archived.files.iter().map(|(_, size)| size).sum();
+		while total > max_bytes && archived.files.len() > 1 {
+			let (first_record_id, size) = archived.files.pop_front().unwrap();
+			log::debug!(target: "parity-db", "Removing archive file for record {}", first_record_id);
+			std::fs::remove_file(file_path(&self.path, first_record_id))?;
+			total -= size;
+		}
+		Ok(())
+	}
+
+	/// fsync archived records to disk.
+	pub fn flush(&self) -> Result<()> {
+		let mut archived = self.archived.lock();
+		if archived.dirty {
+			if let Some(file) = &archived.current {
+				file.sync_data()?;
+			}
+			archived.dirty = false;
+		}
+		Ok(())
+	}
+
+	/// Stream records starting with `from_record_id`. 0 starts with the oldest archived record.
+	pub fn stream(&self, from_record_id: u64) -> Result<ChangeStream> {
+		let archived = self.archived.lock();
+		if let Some((first_record_id, _)) = archived.files.front() {
+			if from_record_id != 0 && from_record_id < *first_record_id {
+				return Err(Error::InvalidInput(format!(
+					"Record {} is no longer archived. Oldest archived record is {}",
+					from_record_id, first_record_id
+				)))
+			}
+		}
+		Ok(ChangeStream {
+			path: self.path.clone(),
+			from_record_id,
+			file: None,
+			first_record_id: None,
+		})
+	}
+}
+
+/// Iterator over archived log records, returned by `Db::tail_changes`. Yields
+/// `(record_id, record)` pairs in record order. Iteration ends when all archived records were
+/// read; calling `next` again later returns records archived in the meantime.
+pub struct ChangeStream {
+	path: PathBuf,
+	// Next record to return.
+	from_record_id: u64,
+	// Archive file being read and its first record id.
+	file: Option<std::fs::File>,
+	first_record_id: Option<u64>,
+}
+
+impl ChangeStream {
+	// Switch to the next archive file that may contain `from_record_id`.
+	fn next_file(&mut self) -> Result<bool> {
+		let files = list_files(&self.path)?;
+		let next = match self.first_record_id {
+			None => files.iter().rev().find(|(id, _)| *id <= self.from_record_id).or(files.first()),
+			Some(current) => files.iter().find(|(id, _)| *id > current),
+		};
+		match next {
+			Some((id, _)) => match std::fs::File::open(file_path(&self.path, *id)) {
+				Ok(file) => {
+					self.file = Some(file);
+					self.first_record_id = Some(*id);
+					Ok(true)
+				},
+				// Removed by retention in the meantime.
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::InvalidInput(
+					format!("Record {} is no longer archived", self.from_record_id),
+				)),
+				Err(e) => Err(e.into()),
+			},
+			None => Ok(false),
+		}
+	}
+
+	fn read_next(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+		loop {
+			if let Some(file) = &mut self.file {
+				let pos = file.stream_position()?;
+				match read_frame(file)? {
+					Some((record_id, _)) if record_id < self.from_record_id => continue,
+					Some((record_id, data)) => {
+						self.from_record_id = record_id + 1;
+						return Ok(Some((record_id, data)))
+					},
+					None => {
+						// Rewind past a partially written frame so it is read again once complete.
+						file.seek(SeekFrom::Start(pos))?;
+					},
+				}
+			}
+			if !self.next_file()? {
+				return Ok(None)
+			}
+		}
+	}
+}
+
+impl Iterator for ChangeStream {
+	type Item = Result<(u64, Vec<u8>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.read_next().transpose()
+	}
+}
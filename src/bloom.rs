@@ -0,0 +1,173 @@
+// Copyright 2015-2021 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+/// Bloom filter of the keys stored in a hash indexed column.
+use crate::{column::ColId, error::Result, index::TableId as IndexTableId, Key};
+use std::{
+	collections::VecDeque,
+	io::{Read, Write},
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+const MAGIC: &[u8; 8] = b"pdbbloom";
+const HASHES: u64 = 6;
+// Only the top 50 bits of the key prefix can be recovered from index entries, so the
+// filter is built from these when rebuilt from the index.
+const PREFIX_SHIFT: u32 = 14;
+
+pub struct BloomFilter {
+	bits: Vec<AtomicU64>,
+	// Set once all keys in the column were added.
+	ready: AtomicBool,
+}
+
+/// Index tables left to scan when rebuilding a filter, and the next chunk of the first one.
+pub struct BloomRebuild {
+	pub tables: VecDeque<IndexTableId>,
+	pub chunk: u64,
+}
+
+impl BloomFilter {
+	pub fn new(bytes: usize) -> BloomFilter {
+		let words = bytes.div_ceil(8).max(1);
+		BloomFilter {
+			bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+			ready: AtomicBool::new(false),
+		}
+	}
+
+	pub fn file_name(col: ColId) -> String {
+		format!("bloom_{:02}", col)
+	}
+
+	fn positions(&self, key: &Key) -> impl Iterator<Item = u64> {
+		let prefix = u64::from_be_bytes(key[0..8].try_into().unwrap()) >> PREFIX_SHIFT;
+		let step = prefix.wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(29) | 1;
+		let total = self.bits.len() as u64 * 64;
+		(0..HASHES).map(move |i| prefix.wrapping_add(i.wrapping_mul(step)) % total)
+	}
+
+	pub fn insert(&self, key: &Key) {
+		for bit in self.positions(key) {
+			self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+		}
+	}
+
+	/// `false` if the key is definitely not in the column. Always `true` until the filter is
+	/// ready.
+	pub fn may_contain(&self, key: &Key) -> bool {
+		if !self.ready.load(Ordering::Acquire) {
+			return true
+		}
+		self.positions(key).all(|bit| {
+			self.bits[(bit / 64) as usize].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0
+		})
+	}
+
+	/// Size of the filter in memory.
+	pub fn bytes(&self) -> u64 {
+		(self.bits.len() * std::mem::size_of::<AtomicU64>()) as u64
+	}
+
+	pub fn is_ready(&self) -> bool {
+		self.ready.load(Ordering::Acquire)
+	}
+
+	pub fn set_ready(&self) {
+		self.ready.store(true, Ordering::Release);
+	}
+
+	/// Write the filter to `path`, followed by a checksum.
+	pub fn save(&self, path: &std::path::Path) -> Result<()> {
+		let mut data = Vec::with_capacity(self.bits.len() * 8 + 20);
+		data.extend_from_slice(MAGIC);
+		data.extend_from_slice(&(self.bits.len() as u64).to_le_bytes());
+		for word in self.bits.iter() {
+			data.extend_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+		}
+		let checksum = crc32fast::hash(&data);
+		data.extend_from_slice(&checksum.to_le_bytes());
+		let mut file = std::fs::File::create(path)?;
+		file.write_all(&data)?;
+		crate::file::sync_file(&file)?;
+		Ok(())
+	}
+
+	/// Load a filter saved with `save` and mark it ready. Returns `false` if the file is
+	/// missing, damaged or was saved with a different size.
+	pub fn load(&self, path: &std::path::Path) -> Result<bool> {
+		let mut data = Vec::new();
+		match std::fs::File::open(path) {
+			Ok(mut file) => file.read_to_end(&mut data)?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+			Err(e) => return Err(e.into()),
+		};
+		if data.len() != self.bits.len() * 8 + 20 || &data[0..8] != MAGIC {
+			return Ok(false)
+		}
+		let (body, checksum) = data.split_at(data.len() - 4);
+		if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) ||
+			u64::from_le_bytes(body[8..16].try_into().unwrap()) != self.bits.len() as u64
+		{
+			return Ok(false)
+		}
+		for (word, bytes) in self.bits.iter().zip(body[16..].chunks_exact(8)) {
+			word.store(u64::from_le_bytes(bytes.try_into().unwrap()), Ordering::Relaxed);
+		}
+		self.set_ready();
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::BloomFilter;
+	use crate::Key;
+
+	fn key(i: u32) -> Key {
+		let mut key = Key::default();
+		key.copy_from_slice(blake2_rfc::blake2b::blake2b(32, &[], &i.to_le_bytes()).as_bytes());
+		key
+	}
+
+	#[test]
+	fn save_and_load() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join(BloomFilter::file_name(0));
+		let filter = BloomFilter::new(1024);
+		for i in 0..100 {
+			filter.insert(&key(i));
+		}
+		assert!(filter.may_contain(&key(1000)));
+		filter.set_ready();
+		assert!((0..100).all(|i| filter.may_contain(&key(i))));
+		let false_positives = (100..10100).filter(|i| filter.may_contain(&key(*i))).count();
+		assert!(false_positives < 100);
+		filter.save(&path).unwrap();
+
+		let loaded = BloomFilter::new(1024);
+		assert!(loaded.load(&path).unwrap());
+		assert!((0..100).all(|i| loaded.may_contain(&key(i))));
+		assert!(!BloomFilter::new(2048).load(&path).unwrap());
+
+		let mut data = std::fs::read(&path).unwrap();
+		data[20] ^= 1;
+		std::fs::write(&path, data).unwrap();
+		let damaged = BloomFilter::new(1024);
+		assert!(!damaged.load(&path).unwrap());
+		assert!(!damaged.is_ready());
+	}
+}
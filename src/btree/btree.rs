@@ -117,7 +117,7 @@ impl BTree {
 			Node::default()
 		} else {
 			let root = BTreeTable::get_encoded_entry(root, log, tables)?;
-			Node::from_encoded(root)
+			Node::from_encoded(root, tables.prefix_compression)
 		})
 	}
 }
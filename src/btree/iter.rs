@@ -271,3 +271,28 @@ impl BTreeIterState {
 		Ok(())
 	}
 }
+
+/// Iterator over the entries of a multimap column, see `Db::iter_multimap`.
+pub struct MultimapIterator<'a>(BTreeIterator<'a>);
+
+impl<'a> MultimapIterator<'a> {
+	pub(crate) fn new(iter: BTreeIterator<'a>) -> Self {
+		MultimapIterator(iter)
+	}
+
+	/// Position the iterator at the first value of `key`, or of the next key.
+	pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+		self.0.seek(&multimap_prefix(key))
+	}
+
+	/// Next key and value. Keys with multiple values are returned once for each value.
+	#[allow(clippy::should_implement_trait)]
+	pub fn next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+		match self.0.next()? {
+			Some((encoded, _)) => decode_multimap_key(&encoded)
+				.map(Some)
+				.ok_or_else(|| Error::Corruption("Invalid multimap entry".into())),
+			None => Ok(None),
+		}
+	}
+}
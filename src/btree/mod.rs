@@ -46,7 +46,7 @@ use crate::{
 		Entry as ValueTableEntry, Value, ValueTable,
 	},
 };
-pub use iter::BTreeIterator;
+pub use iter::{BTreeIterator, MultimapIterator};
 use node::SeparatorInner;
 use parking_lot::RwLock;
 
@@ -55,6 +55,47 @@ mod btree;
 mod iter;
 mod node;
 
+// Entries of multimap columns are stored under the key followed by the value. Zero bytes of
+// the key are escaped as `0, 0xff` and the key ends with `0, 0`, so that entries are ordered by
+// key first and all entries of a key share a prefix.
+pub fn multimap_prefix(key: &[u8]) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(key.len() + 2);
+	for b in key {
+		encoded.push(*b);
+		if *b == 0 {
+			encoded.push(0xff);
+		}
+	}
+	encoded.extend_from_slice(&[0, 0]);
+	encoded
+}
+
+pub fn multimap_key(key: &[u8], value: &[u8]) -> Vec<u8> {
+	let mut encoded = multimap_prefix(key);
+	encoded.extend_from_slice(value);
+	encoded
+}
+
+// Split a stored multimap entry into key and value.
+pub fn decode_multimap_key(encoded: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+	let mut key = Vec::new();
+	let mut i = 0;
+	while i < encoded.len() {
+		if encoded[i] == 0 {
+			match encoded.get(i + 1)? {
+				0 => return Some((key, encoded[i + 2..].to_vec())),
+				0xff => key.push(0),
+				_ => return None,
+			}
+			i += 2;
+		} else {
+			key.push(encoded[i]);
+			i += 1;
+		}
+	}
+	None
+}
+
 const ORDER: usize = 8;
 const ORDER_CHILD: usize = ORDER + 1;
 const NULL_ADDRESS: Address = Address::from_u64(0);
@@ -84,15 +125,13 @@ impl Entry {
 		Entry { encoded: ValueTableEntry::new(enc) }
 	}
 
-	fn read_separator(&mut self) -> Option<SeparatorInner> {
+	fn read_separator(&mut self, prefix: &[u8]) -> Option<SeparatorInner> {
 		if self.encoded.offset() == self.encoded.inner_mut().len() {
 			return None
 		}
 		let value = self.encoded.read_u64();
-		let head = self.encoded.read_slice(1);
-		let head = head[0];
-		let size = if head == u8::MAX { self.encoded.read_u32() as usize } else { head as usize };
-		let key = self.encoded.read_slice(size).to_vec();
+		let mut key = prefix.to_vec();
+		key.extend_from_slice(self.read_key());
 		if value == 0 {
 			return None
 		}
@@ -101,18 +140,28 @@ impl Entry {
 	}
 
 	fn write_separator(&mut self, key: &[u8], value: Address) {
-		let size = key.len();
 		let inner_size = self.encoded.inner_mut().len();
-		if size >= u8::MAX as usize {
-			self.encoded.inner_mut().resize(inner_size + 8 + 1 + 4 + size, 0);
-		} else {
-			self.encoded.inner_mut().resize(inner_size + 8 + 1 + size, 0);
-		};
+		self.encoded.inner_mut().resize(inner_size + 8, 0);
 		self.encoded.write_u64(value.as_u64());
+		self.write_key(key);
+	}
+
+	fn read_key(&mut self) -> &[u8] {
+		let head = self.encoded.read_slice(1);
+		let head = head[0];
+		let size = if head == u8::MAX { self.encoded.read_u32() as usize } else { head as usize };
+		self.encoded.read_slice(size)
+	}
+
+	fn write_key(&mut self, key: &[u8]) {
+		let size = key.len();
+		let inner_size = self.encoded.inner_mut().len();
 		if size >= u8::MAX as usize {
+			self.encoded.inner_mut().resize(inner_size + 1 + 4 + size, 0);
 			self.encoded.write_slice(&[u8::MAX]);
 			self.encoded.write_u32(size as u32);
 		} else {
+			self.encoded.inner_mut().resize(inner_size + 1 + size, 0);
 			self.encoded.write_slice(&[size as u8]);
 		}
 		self.encoded.write_slice(key);
@@ -146,6 +195,7 @@ pub struct BTreeTable {
 	tables: RwLock<Vec<ValueTable>>,
 	ref_counted: bool,
 	compression: Compress,
+	prefix_compression: bool,
 }
 
 impl BTreeTable {
@@ -166,7 +216,9 @@ impl BTreeTable {
 			id,
 			tables: RwLock::new(values),
 			ref_counted: options.ref_counted,
-			compression: Compress::new(options.compression, options.compression_threshold),
+			compression: Compress::new(options.compression, options.compression_threshold)
+				.with_min_savings(options.compression_min_savings),
+			prefix_compression: options.prefix_compression,
 		})
 	}
 
@@ -189,7 +241,7 @@ impl BTreeTable {
 		log: &impl LogQuery,
 	) -> Result<Option<(u8, Value)>> {
 		let tables = self.tables.read();
-		let btree = self.locked(&*tables);
+		let btree = self.locked(&tables);
 		Column::get_value(key, address, btree, log)
 	}
 
@@ -207,7 +259,7 @@ impl BTreeTable {
 			return Ok(None)
 		}
 		let record_id = 0; // lifetime of Btree is the query, so no invalidate.
-				   // keeping log locked when parsing tree.
+					 // keeping log locked when parsing tree.
 		let tree = BTree::new(Some(btree_header.root), btree_header.depth, record_id);
 		tree.get(key, values, log)
 	}
@@ -228,6 +280,8 @@ impl BTreeTable {
 			preimage: false,
 			compression: &self.compression,
 			col: self.id,
+			prefix_compression: self.prefix_compression,
+			inline: None,
 		}
 	}
 
@@ -270,6 +324,30 @@ impl BTreeTable {
 		Ok(())
 	}
 
+	pub fn with_value_table<R>(&self, tier: u8, f: impl FnOnce(&ValueTable) -> R) -> R {
+		f(&self.tables.read()[tier as usize])
+	}
+
+	pub fn value_entry_size(&self, tier: u8) -> Option<u16> {
+		self.tables.read().get(tier as usize).map(|t| t.entry_size)
+	}
+
+	pub fn filled_entries(&self) -> (u64, u64) {
+		let tables = self.tables.read();
+		tables.iter().fold((0, 0), |(entries, bytes), t| {
+			let filled = t.filled_entries();
+			(entries + filled, bytes + filled * t.entry_size as u64)
+		})
+	}
+
+	pub fn mapped_bytes(&self) -> u64 {
+		self.tables.read().iter().map(|t| t.mapped_bytes()).sum()
+	}
+
+	pub fn file_bytes(&self) -> u64 {
+		self.tables.read().iter().map(|t| t.file_bytes()).sum()
+	}
+
 	pub fn refresh_metadata(&self) -> Result<()> {
 		let tables = self.tables.read();
 		for t in tables.iter() {
@@ -278,6 +356,10 @@ impl BTreeTable {
 		Ok(())
 	}
 
+	pub fn with_value_tables<R>(&self, f: impl FnOnce(&[ValueTable]) -> R) -> R {
+		f(&self.tables.read())
+	}
+
 	pub fn write_plan(
 		tables: TablesRef,
 		btree: &mut BTree,
@@ -334,6 +416,13 @@ impl BTreeTable {
 		}
 
 		let mut entry = Entry::empty();
+		let prefix = if tables.prefix_compression {
+			let prefix = node.shared_prefix();
+			entry.write_key(prefix);
+			prefix.len()
+		} else {
+			0
+		};
 		let mut i_children = 0;
 		let mut i_separator = 0;
 		loop {
@@ -347,14 +436,14 @@ impl BTreeTable {
 				break
 			}
 			if let Some(sep) = &node.separators.as_mut()[i_separator].separator {
-				entry.write_separator(&sep.key, sep.value);
+				entry.write_separator(&sep.key[prefix..], sep.value);
 				i_separator += 1
 			} else {
 				break
 			}
 		}
 
-		let old_comp = tables.compression;
+		// `tables` is a local copy, so there is no need to restore the compression afterwards.
 		tables.compression = &crate::compress::NO_COMPRESSION;
 		let mut write_node = || {
 			Ok(if let Some(existing) = node_id {
@@ -382,10 +471,7 @@ impl BTreeTable {
 				)?)
 			})
 		};
-		let result = write_node();
-		tables.compression = old_comp;
-
-		result
+		write_node()
 	}
 }
 
@@ -393,18 +479,29 @@ pub mod commit_overlay {
 	use super::*;
 	use crate::{
 		column::{ColId, Column},
-		db::BTreeCommitOverlay,
+		db::{BTreeCommitOverlay, CommitOutcome},
 		error::Result,
 	};
+	use std::collections::{BTreeSet, HashMap, HashSet};
 
 	pub struct BTreeChangeSet {
 		pub col: ColId,
 		pub changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+		// Positions in `changes` of inserts that leave existing keys untouched.
+		pub if_absent: HashSet<usize>,
+		// Positions in `changes` of multimap keys to remove with all their values. Expanded to
+		// the removal of each entry when the commit is queued.
+		pub remove_prefix: BTreeSet<usize>,
 	}
 
 	impl BTreeChangeSet {
 		pub fn new(col: ColId) -> Self {
-			BTreeChangeSet { col, changes: Default::default() }
+			BTreeChangeSet {
+				col,
+				changes: Default::default(),
+				if_absent: Default::default(),
+				remove_prefix: Default::default(),
+			}
 		}
 
 		pub fn push(&mut self, k: &[u8], v: Option<Vec<u8>>) {
@@ -420,8 +517,13 @@ pub mod commit_overlay {
 			options: &Options,
 		) {
 			let ref_counted = options.columns[self.col as usize].ref_counted;
-			for commit in self.changes.iter() {
+			for (i, commit) in self.changes.iter().enumerate() {
 				match commit {
+					// Conditional inserts are only visible once written.
+					(key, Some(value)) if self.if_absent.contains(&i) => {
+						*bytes += key.len();
+						*bytes += value.len();
+					},
 					(key, Some(value)) => {
 						*bytes += key.len();
 						*bytes += value.len();
@@ -456,13 +558,49 @@ pub mod commit_overlay {
 			btree: &BTreeTable,
 			writer: &mut LogWriter,
 			ops: &mut u64,
+			outcomes: Option<&mut Vec<CommitOutcome>>,
 		) -> Result<()> {
 			let record_id = writer.record_id();
 
 			let locked_tables = btree.tables.read();
-			let locked = btree.locked(&*locked_tables);
+			let locked = btree.locked(&locked_tables);
 			let mut tree = BTree::open(locked, writer, record_id)?;
 
+			if outcomes.is_some() || !self.if_absent.is_empty() {
+				// Keys changed earlier in this commit, and whether they exist afterwards.
+				let mut changed: HashMap<&[u8], bool> = HashMap::new();
+				let mut skipped = HashSet::new();
+				let mut results = Vec::with_capacity(self.changes.len());
+				for (i, (key, value)) in self.changes.iter().enumerate() {
+					let exists = match changed.get(key.as_slice()) {
+						Some(exists) => *exists,
+						None => tree.get(key, locked, &*writer)?.is_some(),
+					};
+					results.push(match (exists, value.is_some()) {
+						(true, true) if self.if_absent.contains(&i) => {
+							skipped.insert(i);
+							CommitOutcome::Unchanged
+						},
+						(false, true) => CommitOutcome::Inserted,
+						(true, true) => CommitOutcome::Replaced,
+						(true, false) => CommitOutcome::Deleted,
+						(false, false) => CommitOutcome::NotFound,
+					});
+					changed.insert(key, value.is_some());
+				}
+				if let Some(outcomes) = outcomes {
+					outcomes.extend(results);
+				}
+				if !skipped.is_empty() {
+					let mut i = 0;
+					self.changes.retain(|_| {
+						i += 1;
+						!skipped.contains(&(i - 1))
+					});
+				}
+				self.if_absent.clear();
+			}
+
 			let mut btree_header =
 				BTreeHeader { root: tree.root_index.unwrap_or(NULL_ADDRESS), depth: tree.depth };
 			let old_btree_header = btree_header.clone();
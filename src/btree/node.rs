@@ -592,8 +592,23 @@ impl Node {
 		self.changed = true;
 	}
 
-	pub fn from_encoded(enc: Vec<u8>) -> Self {
+	// Longest prefix shared by all separator keys.
+	pub fn shared_prefix(&self) -> &[u8] {
+		let mut keys = self.separators.iter().filter_map(|s| s.separator.as_ref().map(|s| &s.key));
+		let first = match keys.next() {
+			Some(first) => first.as_slice(),
+			None => return &[],
+		};
+		let mut len = first.len();
+		for key in keys {
+			len = first[..len].iter().zip(key.iter()).take_while(|(a, b)| a == b).count();
+		}
+		&first[..len]
+	}
+
+	pub fn from_encoded(enc: Vec<u8>, prefix_compression: bool) -> Self {
 		let mut entry = Entry::from_encoded(enc);
+		let prefix = if prefix_compression { entry.read_key().to_vec() } else { Vec::new() };
 		let mut node =
 			Node { separators: Default::default(), children: Default::default(), changed: false };
 		let mut i_children = 0;
@@ -606,7 +621,7 @@ impl Node {
 			if i_children == ORDER_CHILD {
 				break
 			}
-			if let Some(sep) = entry.read_separator() {
+			if let Some(sep) = entry.read_separator(&prefix) {
 				node.separators.as_mut()[i_separator].separator = Some(sep);
 				i_separator += 1
 			} else {
@@ -821,7 +836,7 @@ impl Node {
 	) -> Result<Option<Self>> {
 		if let Some(ix) = self.children[i].entry_index {
 			let entry = BTreeTable::get_encoded_entry(ix, log, values)?;
-			return Ok(Some(Self::from_encoded(entry)))
+			return Ok(Some(Self::from_encoded(entry, values.prefix_compression)))
 		}
 		Ok(None)
 	}
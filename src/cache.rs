@@ -0,0 +1,254 @@
+// Copyright 2015-2021 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! In-memory LRU caches of values and index chunks read from the column tables.
+
+use crate::{
+	db::Value,
+	index::{Chunk, TableId as IndexTableId},
+};
+use parking_lot::Mutex;
+use std::{
+	borrow::Borrow,
+	collections::{BTreeMap, HashMap},
+	hash::Hash,
+};
+
+// Bytes charged for each cached entry on top of the key and value.
+const ENTRY_OVERHEAD: usize = 64;
+
+// Entries up to a byte budget, evicting the least recently used first.
+struct Lru<K, V> {
+	budget: usize,
+	// Value, its charged size and the stamp of its last access.
+	entries: HashMap<K, (V, usize, u64)>,
+	// Keys ordered by last access.
+	order: BTreeMap<u64, K>,
+	next_stamp: u64,
+	bytes: usize,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Lru<K, V> {
+	fn new(budget: usize) -> Self {
+		Lru {
+			budget,
+			entries: Default::default(),
+			order: Default::default(),
+			next_stamp: 0,
+			bytes: 0,
+		}
+	}
+
+	fn get<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<V>
+	where
+		K: Borrow<Q>,
+	{
+		self.get_with(key, V::clone)
+	}
+
+	fn get_with<Q: Hash + Eq + ?Sized, R>(&mut self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+	where
+		K: Borrow<Q>,
+	{
+		let stamp = self.next_stamp;
+		let (value, _, last) = self.entries.get_mut(key)?;
+		let value = f(value);
+		let last = std::mem::replace(last, stamp);
+		self.next_stamp += 1;
+		let key = self.order.remove(&last).expect("Cached keys are ordered");
+		self.order.insert(stamp, key);
+		Some(value)
+	}
+
+	fn insert(&mut self, key: K, value: V, size: usize) {
+		let size = size + ENTRY_OVERHEAD;
+		self.remove(&key);
+		if size > self.budget {
+			return
+		}
+		while self.bytes + size > self.budget {
+			let (_, oldest) = self.order.pop_first().expect("Budget is exceeded by cached values");
+			let (_, oldest_size, _) =
+				self.entries.remove(&oldest).expect("Ordered keys are cached");
+			self.bytes -= oldest_size;
+		}
+		let stamp = self.next_stamp;
+		self.next_stamp += 1;
+		self.bytes += size;
+		self.order.insert(stamp, key.clone());
+		self.entries.insert(key, (value, size, stamp));
+	}
+
+	fn remove<Q: Hash + Eq + ?Sized>(&mut self, key: &Q)
+	where
+		K: Borrow<Q>,
+	{
+		if let Some((_, size, stamp)) = self.entries.remove(key) {
+			self.order.remove(&stamp);
+			self.bytes -= size;
+		}
+	}
+
+	fn retain(&mut self, mut keep: impl FnMut(&K) -> bool) {
+		let removed: Vec<K> = self.entries.keys().filter(|k| !keep(k)).cloned().collect();
+		for key in removed {
+			self.remove(&key);
+		}
+	}
+
+	fn clear(&mut self) {
+		*self = Lru::new(self.budget);
+	}
+}
+
+/// Values cached by key, up to a byte budget.
+pub struct ValueCache {
+	lru: Mutex<Lru<Vec<u8>, Value>>,
+}
+
+impl ValueCache {
+	pub fn new(budget: usize) -> ValueCache {
+		ValueCache { lru: Mutex::new(Lru::new(budget)) }
+	}
+
+	#[cfg(test)]
+	pub fn get(&self, key: &[u8]) -> Option<Value> {
+		self.lru.lock().get(key)
+	}
+
+	/// Append the cached value to `value`. Returns `false` if it is not cached.
+	pub fn get_into(&self, key: &[u8], value: &mut Vec<u8>) -> bool {
+		self.lru
+			.lock()
+			.get_with(key, |cached| value.extend_from_slice(cached))
+			.is_some()
+	}
+
+	/// Cache a value read from the tables. Must not race with `remove` for the same key, or a
+	/// stale value may be cached.
+	pub fn insert(&self, key: &[u8], value: &[u8]) {
+		self.lru.lock().insert(key.to_vec(), value.to_vec(), key.len() + value.len())
+	}
+
+	pub fn remove(&self, key: &[u8]) {
+		self.lru.lock().remove(key)
+	}
+
+	pub fn clear(&self) {
+		self.lru.lock().clear()
+	}
+
+	/// Size of the cached values, including per entry overhead.
+	pub fn bytes(&self) -> usize {
+		self.lru.lock().bytes
+	}
+}
+
+struct ChunkLru {
+	lru: Lru<(IndexTableId, u64), Chunk>,
+	// Incremented on every invalidation.
+	epoch: u64,
+}
+
+/// Index chunks cached by table and chunk index, up to a byte budget. Shared by all columns.
+pub struct ChunkCache {
+	inner: Mutex<ChunkLru>,
+}
+
+impl ChunkCache {
+	pub fn new(budget: usize) -> ChunkCache {
+		ChunkCache { inner: Mutex::new(ChunkLru { lru: Lru::new(budget), epoch: 0 }) }
+	}
+
+	/// Cached chunk content. On a miss returns the epoch to pass to `insert`.
+	pub fn get(&self, id: IndexTableId, index: u64) -> std::result::Result<Chunk, u64> {
+		let mut inner = self.inner.lock();
+		inner.lru.get(&(id, index)).ok_or(inner.epoch)
+	}
+
+	/// Cache a chunk read from the file. The chunk is dropped if anything was invalidated since
+	/// `epoch` was obtained, since the read might have raced with a write.
+	pub fn insert(&self, id: IndexTableId, index: u64, chunk: &Chunk, epoch: u64) {
+		let mut inner = self.inner.lock();
+		if inner.epoch == epoch {
+			inner.lru.insert((id, index), *chunk, chunk.len());
+		}
+	}
+
+	/// Drop a chunk after it was written to the file.
+	pub fn invalidate(&self, id: IndexTableId, index: u64) {
+		let mut inner = self.inner.lock();
+		inner.epoch += 1;
+		inner.lru.remove(&(id, index));
+	}
+
+	/// Size of the cached chunks, including per entry overhead.
+	pub fn bytes(&self) -> usize {
+		self.inner.lock().lru.bytes
+	}
+
+	/// Drop all chunks of a table.
+	pub fn invalidate_table(&self, id: IndexTableId) {
+		let mut inner = self.inner.lock();
+		inner.epoch += 1;
+		inner.lru.retain(|(table, _)| *table != id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ChunkCache, ValueCache, ENTRY_OVERHEAD};
+	use crate::index::{TableId as IndexTableId, EMPTY_CHUNK};
+
+	#[test]
+	fn evicts_least_recently_used() {
+		let cache = ValueCache::new(3 * (ENTRY_OVERHEAD + 2));
+		cache.insert(b"a", b"1");
+		cache.insert(b"b", b"2");
+		cache.insert(b"c", b"3");
+		assert_eq!(cache.get(b"a"), Some(b"1".to_vec()));
+		cache.insert(b"d", b"4");
+		assert_eq!(cache.get(b"b"), None);
+		assert_eq!(cache.get(b"a"), Some(b"1".to_vec()));
+		assert_eq!(cache.get(b"c"), Some(b"3".to_vec()));
+		assert_eq!(cache.get(b"d"), Some(b"4".to_vec()));
+
+		cache.remove(b"a");
+		assert_eq!(cache.get(b"a"), None);
+		cache.insert(b"e", &[0; 4 * ENTRY_OVERHEAD]);
+		assert_eq!(cache.get(b"e"), None);
+		cache.clear();
+		assert_eq!(cache.get(b"c"), None);
+	}
+
+	#[test]
+	fn chunk_insert_after_invalidation_is_dropped() {
+		let cache = ChunkCache::new(4096);
+		let id = IndexTableId::new(0, 16);
+		let epoch = cache.get(id, 1).unwrap_err();
+		cache.invalidate(id, 2);
+		cache.insert(id, 1, &EMPTY_CHUNK, epoch);
+		assert!(cache.get(id, 1).is_err());
+
+		let epoch = cache.get(id, 1).unwrap_err();
+		cache.insert(id, 1, &EMPTY_CHUNK, epoch);
+		assert_eq!(cache.get(id, 1), Ok(EMPTY_CHUNK));
+		cache.invalidate_table(IndexTableId::new(1, 16));
+		assert_eq!(cache.get(id, 1), Ok(EMPTY_CHUNK));
+		cache.invalidate_table(id);
+		assert!(cache.get(id, 1).is_err());
+	}
+}
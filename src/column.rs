@@ -15,32 +15,56 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
+	bloom::{BloomFilter, BloomRebuild},
 	btree::BTreeTable,
+	cache::ChunkCache,
 	compress::Compress,
-	db::check::CheckDisplay,
+	db::{
+		check::{CheckDisplay, CheckReport, CorruptedEntry},
+		CommitOutcome,
+	},
 	display::hex,
 	error::{Error, Result},
-	index::{Address, IndexTable, PlanOutcome, TableId as IndexTableId},
-	log::{Log, LogAction, LogOverlays, LogQuery, LogReader, LogWriter},
-	options::{ColumnOptions, Metadata, Options},
-	stats::ColumnStats,
+	file::{FileOptions, Residency, PAGE_SIZE},
+	index::{
+		Address, Chunk as IndexChunk, IndexTable, MapAdvice, PlanOutcome, TableId as IndexTableId,
+		CHUNK_ENTRIES, INLINE_TIER,
+	},
+	log::{
+		InsertValueAction, Log, LogAction, LogOverlays, LogQuery, LogReader, LogWriter, NoOverlays,
+	},
+	metrics::MetricsSink,
+	options::{ColumnOptions, Metadata, Options, SlowOpThresholds},
+	stats::{ColumnStats, CompressionStats, IndexStats, ValueSizeHistogram},
 	table::{
-		key::{TableKey, TableKeyQuery},
+		key::{TableKey, TableKeyQuery, PARTIAL_SIZE},
 		TableId as ValueTableId, Value, ValueTable, SIZE_TIERS,
 	},
-	Key,
+	trace::{Op, OpTimer},
+	Key, KEY_SIZE,
 };
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use std::{
 	collections::VecDeque,
-	sync::atomic::{AtomicU64, Ordering},
+	ops::Range,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
 };
 
 const MIN_INDEX_BITS: u8 = 16;
-// Measured in index entries
-const MAX_REINDEX_BATCH: usize = 8192;
-
-pub type ColId = u8;
+// Index chunks read by `HashColumn::approximate_key_count` in each index table.
+const KEY_COUNT_SAMPLE_CHUNKS: u64 = 1024;
+// Index chunks and value table slots in a part of a column returned by `iter_shards`.
+const ITER_SHARD_CHUNKS: u64 = 256;
+const ITER_SHARD_SLOTS: u64 = 4096;
+// Value table bytes read by a scan before they are dropped from the page cache.
+const SCAN_BLOCK_BYTES: u64 = 1024 * 1024;
+// Index chunks scanned in one step of a bloom filter rebuild.
+const MAX_BLOOM_REBUILD_CHUNKS: u64 = 4096;
+
+pub type ColId = u16;
 pub type Salt = [u8; 32];
 
 // The size tiers follow log distribution. Generated with the following code:
@@ -105,10 +129,16 @@ pub struct HashColumn {
 	uniform_keys: bool,
 	collect_stats: bool,
 	ref_counted: bool,
+	inline_values: bool,
 	salt: Salt,
 	stats: ColumnStats,
 	compression: Compress,
 	db_version: u32,
+	metrics: Option<Arc<dyn MetricsSink>>,
+	slow_ops: SlowOpThresholds,
+	index_cache: Option<Arc<ChunkCache>>,
+	bloom: Option<BloomFilter>,
+	bloom_rebuild: Mutex<Option<BloomRebuild>>,
 }
 
 #[derive(Clone, Copy)]
@@ -118,6 +148,45 @@ pub struct TablesRef<'a> {
 	pub col: ColId,
 	pub preimage: bool,
 	pub ref_counted: bool,
+	// Btree nodes store the shared key prefix once.
+	pub prefix_compression: bool,
+	// Values stored in the index entry, if enabled for the column.
+	pub inline: Option<InlineValues<'a>>,
+}
+
+/// Values stored in index entries of a column with `ColumnOptions::inline_values`.
+#[derive(Clone, Copy)]
+pub struct InlineValues<'a> {
+	column: &'a HashColumn,
+	// Largest value that fits an index entry.
+	bytes: usize,
+}
+
+impl TablesRef<'_> {
+	// Value held by `address` itself, see `Address::inline`.
+	fn inline_value(&self, address: Address) -> Option<Vec<u8>> {
+		(self.inline.is_some() && address.size_tier() == INLINE_TIER)
+			.then(|| address.inline_value())
+	}
+
+	// Index entries only hold a part of the key, so a value is only stored there if `key` is
+	// its hash, which is checked on lookup.
+	fn fits_inline(&self, key: &TableKey, value: &[u8]) -> bool {
+		self.inline.is_some_and(|inline| value.len() <= inline.bytes) &&
+			self.is_inline_key(key, value)
+	}
+
+	fn is_inline_key(&self, key: &TableKey, value: &[u8]) -> bool {
+		match (self.inline, key) {
+			(Some(inline), TableKey::Partial(key)) => *key == inline.column.inline_key(value),
+			_ => false,
+		}
+	}
+
+	// First size tier used for values. Tier 0 is reserved for inline values.
+	fn first_tier(&self) -> usize {
+		usize::from(self.inline.is_some())
+	}
 }
 
 pub struct IterState {
@@ -127,9 +196,56 @@ pub struct IterState {
 	pub value: Vec<u8>,
 }
 
+/// Value table entry returned by slot order iteration.
+pub struct ValueIterState {
+	/// Hashed key. Only the last `PARTIAL_SIZE` bytes are valid if `partial_key` is set.
+	pub key: Key,
+	pub partial_key: bool,
+	pub rc: u32,
+	pub value: Vec<u8>,
+}
+
+/// Raw index entry of a hash column, see `Db::iter_index_while`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+	/// Bits of the index table holding the entry. Differs from the current table while the
+	/// column is being reindexed.
+	pub index_bits: u8,
+	/// Index of the chunk holding the entry.
+	pub chunk_index: u64,
+	/// Position of the entry within the chunk.
+	pub sub_index: u8,
+	/// Entry as stored.
+	pub raw: u64,
+	/// Value table tier and slot the entry points to.
+	pub size_tier: u8,
+	pub offset: u64,
+	/// Key bits stored in the entry, not including the chunk index bits.
+	pub partial_key: u64,
+	/// Leading bytes of the hashed key that can be recovered from the chunk index and the
+	/// partial key.
+	pub key_prefix: [u8; 8],
+}
+
+/// Part of a hash column that can be iterated over on its own, see `HashColumn::iter_shards`.
+#[derive(Clone)]
+pub enum IterShard {
+	/// Chunks `chunks` of index table `table`.
+	Index { table: IndexTableId, chunks: Range<u64>, multipart_only: bool },
+	/// Slots `slots` of value table `tier` of a preimage column.
+	Values { tier: u8, slots: Range<u64> },
+}
+
+/// Pages of the value tables of a column that were in the OS page cache when a scan with
+/// `HashColumn::iter_tables_while` started. Pages that the scan reads and that were not cached
+/// are dropped again, so that a one-off scan does not evict the working set.
+pub struct ScanCache {
+	tables: Vec<Option<Residency>>,
+}
+
 enum IterStateOrCorrupted {
 	Item(IterState),
-	Corrupted(crate::index::Entry, Option<Error>),
+	Corrupted { chunk_index: u64, size_tier: u8, offset: u64, error: Option<Error> },
 }
 
 #[inline]
@@ -151,57 +267,312 @@ pub fn hash_key(key: &[u8], salt: &Salt, uniform: bool, db_version: u32) -> Key
 	k
 }
 
+/// Prefix `value` with `key`, for columns that store the original keys.
+pub fn encode_keyed_value(key: &[u8], value: &[u8]) -> Vec<u8> {
+	let mut encoded = Vec::with_capacity(4 + key.len() + value.len());
+	encoded.extend_from_slice(&(key.len() as u32).to_le_bytes());
+	encoded.extend_from_slice(key);
+	encoded.extend_from_slice(value);
+	encoded
+}
+
+/// Split a value written with `encode_keyed_value` into the key and the value.
+pub fn decode_keyed_value(encoded: &[u8]) -> Result<(&[u8], &[u8])> {
+	if encoded.len() < 4 {
+		return Err(Error::Corruption("Missing stored key".into()))
+	}
+	let len = u32::from_le_bytes(encoded[0..4].try_into().unwrap()) as usize;
+	if encoded.len() < 4 + len {
+		return Err(Error::Corruption("Bad stored key length".into()))
+	}
+	Ok((&encoded[4..4 + len], &encoded[4 + len..]))
+}
+
+/// Recover the original key of a uniform column from the key returned by `hash_key`.
+pub fn unhash_uniform_key(key: &Key, salt: &Salt, db_version: u32) -> Key {
+	let mut k = *key;
+	if db_version > 5 {
+		for (k, s) in k.iter_mut().zip(salt.iter()) {
+			*k ^= s;
+		}
+	}
+	k
+}
+
+// Entries in chunks `start..` of `index`, estimated from about `samples` evenly spaced chunks.
+fn count_index_entries(index: &IndexTable, start: u64, samples: u64, log: &Log) -> u64 {
+	let total = index.id.total_chunks().saturating_sub(start);
+	if total == 0 {
+		return 0
+	}
+	let step = (total / samples).max(1);
+	let mut entries = 0u64;
+	let mut read = 0u64;
+	for chunk_index in (start..index.id.total_chunks()).step_by(step as usize) {
+		let chunk = index.entries(chunk_index, log.overlays());
+		entries += chunk.iter().filter(|e| !e.is_empty()).count() as u64;
+		read += 1;
+	}
+	(entries as u128 * total as u128 / read as u128) as u64
+}
+
 pub struct ReindexBatch {
 	pub drop_index: Option<IndexTableId>,
 	pub batch: Vec<(Key, Address)>,
+	/// Source index and the first chunk of the batch.
+	pub start: Option<(IndexTableId, u64)>,
 }
 
 impl HashColumn {
 	pub fn get(&self, key: &Key, log: &impl LogQuery) -> Result<Option<Value>> {
+		let mut value = Vec::new();
+		Ok(self.get_into(key, log, &mut value)?.then_some(value))
+	}
+
+	/// Same as `get`, appending the value to `value`. Returns `false` if there is no such key.
+	pub fn get_into(&self, key: &Key, log: &impl LogQuery, value: &mut Vec<u8>) -> Result<bool> {
+		Ok(self.lookup(key, log, value, true)?.is_some())
+	}
+
+	/// Read the first part of the value of `key` into `value`, to read the rest with
+	/// `read_part`. Returns the size tier and the index of the next part, 0 if the value is
+	/// complete. Compressed values are always read in full.
+	pub fn get_first_part(
+		&self,
+		key: &Key,
+		log: &impl LogQuery,
+		value: &mut Vec<u8>,
+	) -> Result<Option<(u8, u64)>> {
+		self.lookup(key, log, value, false)
+	}
+
+	/// See `ValueTable::rewritten`. Values split into parts are kept in the last size tier.
+	pub fn rewritten(&self) -> u64 {
+		self.tables.read().value.last().map_or(0, |table| table.rewritten())
+	}
+
+	/// Append the part of a value at `index` of size tier `tier` to `value`. Returns the index
+	/// of the next part, 0 if this was the last one.
+	pub fn read_part(
+		&self,
+		tier: u8,
+		index: u64,
+		log: &impl LogQuery,
+		value: &mut Vec<u8>,
+	) -> Result<u64> {
 		let tables = self.tables.read();
-		let values = self.as_ref(&tables.value);
-		if let Some((tier, value)) = self.get_in_index(key, &tables.index, values, log)? {
+		let table = &tables.value[tier as usize];
+		match table.query_parts(&mut TableKeyQuery::Fetch(None), index, 1, false, log, value)? {
+			Some((_, _, next)) => Ok(next),
+			None => Err(Error::Corruption(format!("{}: Missing value part {}", table.id, index))),
+		}
+	}
+
+	/// Index of the next part and size of the data of the part at `index` of size tier `tier`,
+	/// if it is not the last part of the value. See `ValueTable::skip_part`.
+	pub fn skip_part(
+		&self,
+		tier: u8,
+		index: u64,
+		log: &impl LogQuery,
+	) -> Result<Option<(u64, usize)>> {
+		self.tables.read().value[tier as usize].skip_part(index, log)
+	}
+
+	// Append the value of `key` to `value`, or only its first part unless `all` is set.
+	// Returns the size tier and the index of the next part.
+	fn lookup(
+		&self,
+		key: &Key,
+		log: &impl LogQuery,
+		value: &mut Vec<u8>,
+		all: bool,
+	) -> Result<Option<(u8, u64)>> {
+		if self.bloom.as_ref().is_some_and(|bloom| !bloom.may_contain(key)) {
 			if self.collect_stats {
-				self.stats.query_hit(tier);
+				self.stats.query_miss();
+			}
+			return Ok(None)
+		}
+		let tables = self.tables.read();
+		let values = self.as_ref(&tables);
+		let mut found = self.get_in_index(key, &tables.index, values, log, value, all)?;
+		if found.is_none() {
+			for r in &self.reindex.read().queue {
+				found = self.get_in_index(key, r, values, log, value, all)?;
+				if found.is_some() {
+					break
+				}
 			}
-			return Ok(Some(value))
 		}
-		for r in &self.reindex.read().queue {
-			if let Some((tier, value)) = self.get_in_index(key, r, values, log)? {
+		match found {
+			Some((tier, next)) => {
 				if self.collect_stats {
 					self.stats.query_hit(tier);
 				}
-				return Ok(Some(value))
+				if let Some(metrics) = &self.metrics {
+					metrics.tier_read(self.col, tier);
+				}
+				Ok(Some((tier, next)))
+			},
+			None => {
+				if self.collect_stats {
+					self.stats.query_miss();
+				}
+				Ok(None)
+			},
+		}
+	}
+
+	/// Same as `get` for each of `keys`. Values whose first parts are in adjacent slots of a
+	/// value table are read from the file together, and these reads are submitted as one
+	/// batch, see `file::read_batch`.
+	pub fn get_many(&self, keys: &[Key], log: &impl LogQuery) -> Result<Vec<Option<Value>>> {
+		let mut result = vec![None; keys.len()];
+		// Keys that are looked up one by one: those not found in the current index, stored in
+		// the index entry or sharing the partial key with other entries.
+		let mut single = Vec::new();
+		{
+			let tables = self.tables.read();
+			let values = self.as_ref(&tables);
+			let index_bits = tables.index.id.index_bits();
+			let mut located = Vec::new();
+			for (i, key) in keys.iter().enumerate() {
+				let (entry, _) = tables.index.get(key, 0, log);
+				let address = entry.address(index_bits);
+				if entry.is_empty() || self.is_inline(address) {
+					single.push(i);
+				} else {
+					located.push((address.size_tier(), address.offset(), i));
+				}
+			}
+			located.sort_unstable();
+			// Runs of keys with values in adjacent slots, each read with a single request.
+			let mut runs = Vec::new();
+			let mut start = 0;
+			while start < located.len() {
+				let (tier, first, _) = located[start];
+				let entry_size = values.tables[tier as usize].entry_size as u64;
+				let max_slots = (crate::table::READ_BATCH_BYTES / entry_size).max(1);
+				let mut end = start + 1;
+				while end < located.len() &&
+					located[end].0 == tier &&
+					located[end].1 <= located[end - 1].1 + 1 &&
+					located[end].1 - first < max_slots
+				{
+					end += 1;
+				}
+				let len = ((located[end - 1].1 - first + 1) * entry_size) as usize;
+				runs.push((start..end, len));
+				start = end;
+			}
+			let mut batch = vec![0u8; runs.iter().map(|(_, len)| len).sum()];
+			// Number of slots of each run that were read from the file.
+			let mut read = Vec::with_capacity(runs.len());
+			// See `ValueTable::iter_range_while`.
+			let generation = log.generation();
+			{
+				let mut requests = Vec::new();
+				let mut rest = batch.as_mut_slice();
+				for (run, len) in &runs {
+					let (tier, first, _) = located[run.start];
+					let table = &values.tables[tier as usize];
+					let (buf, tail) = std::mem::take(&mut rest).split_at_mut(*len);
+					rest = tail;
+					match table.slot_read(first, buf) {
+						Some(request) => {
+							read.push((request.buf.len() / table.entry_size as usize) as u64);
+							requests.push(request);
+						},
+						None => read.push(0),
+					}
+				}
+				crate::file::read_batch(&mut requests)?;
+			}
+			let mut offset_in_batch = 0;
+			for ((run, len), read) in runs.into_iter().zip(read) {
+				let (tier, first, _) = located[run.start];
+				let table = &values.tables[tier as usize];
+				let entry_size = table.entry_size as usize;
+				let buf = &batch[offset_in_batch..offset_in_batch + len];
+				offset_in_batch += len;
+				for &(_, offset, i) in &located[run] {
+					let slot = offset - first;
+					let prefetched = (slot < read).then(|| {
+						&buf[slot as usize * entry_size..(slot as usize + 1) * entry_size]
+					});
+					let key = TableKey::Partial(keys[i]);
+					let mut value = Vec::new();
+					let mut found = table.query_prefetched(
+						&mut TableKeyQuery::Check(&key),
+						offset,
+						prefetched,
+						log,
+						&mut value,
+					)?;
+					if prefetched.is_some() && log.generation() != generation {
+						value.clear();
+						found = table.query_prefetched(
+							&mut TableKeyQuery::Check(&key),
+							offset,
+							None,
+							log,
+							&mut value,
+						)?;
+					}
+					match found {
+						Some((compressed, _rc)) => {
+							if compressed {
+								value = self.compression.decompress(&value)?;
+							}
+							if self.collect_stats {
+								self.stats.query_hit(tier);
+							}
+							if let Some(metrics) = &self.metrics {
+								metrics.tier_read(self.col, tier);
+							}
+							result[i] = Some(value);
+						},
+						None => single.push(i),
+					}
+				}
 			}
 		}
-		if self.collect_stats {
-			self.stats.query_miss();
+		for i in single {
+			result[i] = self.get(&keys[i], log)?;
 		}
-		Ok(None)
+		Ok(result)
 	}
 
 	pub fn get_size(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<u32>> {
 		self.get(key, log).map(|v| v.map(|v| v.len() as u32))
 	}
 
+	// Append the value of `key` to `value`, see `lookup`.
 	fn get_in_index(
 		&self,
 		key: &Key,
 		index: &IndexTable,
 		tables: TablesRef,
 		log: &impl LogQuery,
-	) -> Result<Option<(u8, Value)>> {
+		value: &mut Vec<u8>,
+		all: bool,
+	) -> Result<Option<(u8, u64)>> {
 		let (mut entry, mut sub_index) = index.get(key, 0, log);
 		while !entry.is_empty() {
 			let address = entry.address(index.id.index_bits());
-			let value = Column::get_value(
+			let timer = OpTimer::start(Op::ValueRead, &self.slow_ops);
+			let found = Column::get_value_parts(
 				TableKeyQuery::Check(&TableKey::Partial(*key)),
 				address,
 				tables,
 				log,
+				value,
+				all,
 			)?;
-			match value {
-				Some(result) => return Ok(Some(result)),
+			timer.finish(&ValueTableId::new(self.col, address.size_tier()), address.offset());
+			match found {
+				Some(found) => return Ok(Some(found)),
 				None => {
 					let (next_entry, next_index) = index.get(key, sub_index + 1, log);
 					entry = next_entry;
@@ -212,77 +583,161 @@ impl HashColumn {
 		Ok(None)
 	}
 
-	pub fn as_ref<'a>(&'a self, tables: &'a [ValueTable]) -> TablesRef<'a> {
+	fn as_ref<'a>(&'a self, tables: &'a Tables) -> TablesRef<'a> {
 		TablesRef {
-			tables,
+			tables: &tables.value,
 			preimage: self.preimage,
 			col: self.col,
 			ref_counted: self.ref_counted,
 			compression: &self.compression,
+			prefix_compression: false,
+			inline: self.inline_values.then(|| InlineValues {
+				column: self,
+				bytes: Address::inline_capacity(tables.index.id.index_bits()),
+			}),
 		}
 	}
 }
 
 impl Column {
 	pub fn get_value(
-		mut key: TableKeyQuery,
+		key: TableKeyQuery,
 		address: Address,
 		tables: TablesRef,
 		log: &impl LogQuery,
 	) -> Result<Option<(u8, Value)>> {
+		let mut value = Vec::new();
+		let tier = Self::get_value_into(key, address, tables, log, &mut value)?;
+		Ok(tier.map(|tier| (tier, value)))
+	}
+
+	/// Same as `get_value`, appending the value to `value`. A compressed value is decompressed
+	/// into a new buffer that replaces `value`, so this should be called with `value` empty.
+	pub fn get_value_into(
+		key: TableKeyQuery,
+		address: Address,
+		tables: TablesRef,
+		log: &impl LogQuery,
+		value: &mut Vec<u8>,
+	) -> Result<Option<u8>> {
+		let found = Self::get_value_parts(key, address, tables, log, value, true)?;
+		Ok(found.map(|(tier, _next)| tier))
+	}
+
+	/// Same as `get_value_into`, reading only the first part of a multipart value unless `all`
+	/// is set. Returns the size tier and the index of the next part, 0 if the value is complete.
+	pub fn get_value_parts(
+		mut key: TableKeyQuery,
+		address: Address,
+		tables: TablesRef,
+		log: &impl LogQuery,
+		value: &mut Vec<u8>,
+		all: bool,
+	) -> Result<Option<(u8, u64)>> {
+		if let Some(inline) = tables.inline_value(address) {
+			if let TableKeyQuery::Check(key) = key {
+				if !tables.is_inline_key(key, &inline) {
+					return Ok(None)
+				}
+			}
+			value.extend_from_slice(&inline);
+			return Ok(Some((INLINE_TIER, 0)))
+		}
 		let size_tier = address.size_tier() as usize;
-		if let Some((value, compressed, _rc)) =
-			tables.tables[size_tier].query(&mut key, address.offset(), log)?
-		{
-			let value = if compressed { tables.compression.decompress(&value)? } else { value };
-			return Ok(Some((size_tier as u8, value)))
+		let table = &tables.tables[size_tier];
+		match table.query_parts(&mut key, address.offset(), 0, all, log, value)? {
+			Some((compressed, _rc, mut next)) => {
+				if compressed {
+					// Compressed values are decompressed in full.
+					if next != 0 {
+						let mut rest = TableKeyQuery::Fetch(None);
+						if table.query_parts(&mut rest, next, 1, true, log, value)?.is_none() {
+							return Err(Error::Corruption(format!(
+								"{}: Missing value part {}",
+								table.id, next
+							)))
+						}
+						next = 0;
+					}
+					*value = tables.compression.decompress(value)?;
+				}
+				Ok(Some((size_tier as u8, next)))
+			},
+			None => Ok(None),
 		}
-		Ok(None)
 	}
 
 	pub fn compress(
-		compression: &Compress,
 		key: &TableKey,
 		value: &[u8],
-		tables: &[ValueTable],
+		tables: &TablesRef,
+		stats: Option<&ColumnStats>,
 	) -> (Option<Vec<u8>>, usize) {
+		let compression = tables.compression;
 		let (len, result) = if value.len() > compression.threshold as usize {
-			let cvalue = compression.compress(value);
-			if cvalue.len() < value.len() {
-				(cvalue.len(), Some(cvalue))
-			} else {
-				(value.len(), None)
+			let cvalue = compression.compress_value(value);
+			let len = cvalue.as_ref().map_or(value.len(), |c| c.len());
+			if let Some(stats) = stats.filter(|_| compression.is_enabled()) {
+				stats.compressed(value.len() as u32, len as u32);
 			}
+			(len, cvalue)
 		} else {
 			(value.len(), None)
 		};
-		let target_tier = tables
+		(result, Self::target_tier(key, len, tables.tables, tables.first_tier()))
+	}
+
+	// Tier of a stored value of `len` bytes, starting with tier `first`.
+	fn target_tier(key: &TableKey, len: usize, tables: &[ValueTable], first: usize) -> usize {
+		let target_tier = tables[first..]
 			.iter()
-			.position(|t| t.value_size(key).map_or(false, |s| len <= s as usize));
-		let target_tier = match target_tier {
-			Some(tier) => tier as usize,
+			.position(|t| t.value_size(key).is_some_and(|s| len <= s as usize));
+		match target_tier {
+			Some(tier) => first + tier,
 			None => {
 				log::trace!(target: "parity-db", "Using blob {}", key);
 				tables.len() - 1
 			},
-		};
-
-		(result, target_tier)
+		}
 	}
 
-	pub fn open(col: ColId, options: &Options, metadata: &Metadata) -> Result<Column> {
-		let path = &options.path;
-		let arc_path = std::sync::Arc::new(path.clone());
+	pub fn open(
+		col: ColId,
+		options: &Options,
+		metadata: &Metadata,
+		index_cache: Option<Arc<ChunkCache>>,
+	) -> Result<Column> {
+		let path = options.column_path(col);
+		let arc_path = std::sync::Arc::new(path.to_path_buf());
 		let column_options = &metadata.columns[col as usize];
 		let db_version = metadata.version;
-		let value = (0..SIZE_TIERS)
-			.map(|i| Self::open_table(arc_path.clone(), col, i as u8, column_options, db_version))
+		let value: Vec<ValueTable> = (0..SIZE_TIERS)
+			.map(|i| {
+				Self::open_table(
+					arc_path.clone(),
+					col,
+					i as u8,
+					column_options,
+					db_version,
+					FileOptions {
+						direct_io: options.direct_io,
+						mmap: column_options.mmap_tables,
+						growth: options.file_growth,
+						storage: options.storage.clone(),
+					},
+				)
+			})
 			.collect::<Result<_>>()?;
+		if options.preopen_threads.is_some() {
+			for table in value.iter() {
+				table.preopen()?;
+			}
+		}
 
 		if column_options.btree_index {
 			Ok(Column::Tree(BTreeTable::open(col, value, metadata)?))
 		} else {
-			Ok(Column::Hash(HashColumn::open(col, value, options, metadata)?))
+			Ok(Column::Hash(HashColumn::open(col, value, options, metadata, index_cache)?))
 		}
 	}
 
@@ -292,10 +747,11 @@ impl Column {
 		tier: u8,
 		options: &ColumnOptions,
 		db_version: u32,
+		file_options: FileOptions,
 	) -> Result<ValueTable> {
 		let id = ValueTableId::new(col, tier);
 		let entry_size = SIZES.get(tier as usize).cloned();
-		ValueTable::open(path, id, entry_size, options, db_version)
+		ValueTable::open(path, id, entry_size, options, db_version, file_options)
 	}
 }
 
@@ -305,11 +761,18 @@ impl HashColumn {
 		value: Vec<ValueTable>,
 		options: &Options,
 		metadata: &Metadata,
+		index_cache: Option<Arc<ChunkCache>>,
 	) -> Result<HashColumn> {
-		let (index, reindexing, stats) = Self::open_index(&options.path, col)?;
+		let path = options.column_path(col);
 		let collect_stats = options.stats;
-		let path = &options.path;
+		let metrics = options.metrics.clone();
+		let slow_ops = options.slow_ops;
+		let advice =
+			MapAdvice { huge_pages: options.index_huge_pages, prefetch: options.index_prefetch };
 		let options = &metadata.columns[col as usize];
+		let span_bits = (options.index_chunk_entries / CHUNK_ENTRIES).trailing_zeros() as u8;
+		let (index, reindexing, stats) =
+			Self::open_index(path, col, span_bits, advice, &index_cache)?;
 		let db_version = metadata.version;
 		Ok(HashColumn {
 			col,
@@ -319,11 +782,19 @@ impl HashColumn {
 			preimage: options.preimage,
 			uniform_keys: options.uniform,
 			ref_counted: options.ref_counted,
+			inline_values: options.inline_values,
 			collect_stats,
 			salt: metadata.salt,
 			stats,
-			compression: Compress::new(options.compression, options.compression_threshold),
+			compression: Compress::new(options.compression, options.compression_threshold)
+				.with_min_savings(options.compression_min_savings),
 			db_version,
+			metrics,
+			slow_ops,
+			index_cache,
+			bloom: (options.bloom_filter_bytes > 0)
+				.then(|| BloomFilter::new(options.bloom_filter_bytes)),
+			bloom_rebuild: Mutex::new(None),
 		})
 	}
 
@@ -340,16 +811,50 @@ impl HashColumn {
 		Ok(())
 	}
 
+	pub fn flush_values(&self) -> Result<()> {
+		let tables = self.tables.read();
+		for t in tables.value.iter() {
+			t.flush()?;
+		}
+		Ok(())
+	}
+
+	// Run `f` on index table `id`, if it is still in use.
+	fn with_index_table<R>(&self, id: IndexTableId, f: impl FnOnce(&IndexTable) -> R) -> Option<R> {
+		let tables = self.tables.read();
+		if tables.index.id == id {
+			return Some(f(&tables.index))
+		}
+		self.reindex.read().queue.iter().find(|r| r.id == id).map(f)
+	}
+
+	/// Content of index chunk `index` in table `id` as written to the file.
+	pub fn index_chunk(&self, id: IndexTableId, index: u64) -> Option<IndexChunk> {
+		self.with_index_table(id, |table| table.chunk(index)).flatten()
+	}
+
+	/// Restore index chunk `index` in table `id` from a checkpoint. Chunks of tables that were
+	/// dropped in the meantime are ignored.
+	pub fn write_index_chunk(&self, id: IndexTableId, index: u64, data: &IndexChunk) -> Result<()> {
+		self.with_index_table(id, |table| table.write_chunk(index, data))
+			.unwrap_or(Ok(()))
+	}
+
 	fn open_index(
 		path: &std::path::Path,
 		col: ColId,
+		span_bits: u8,
+		advice: MapAdvice,
+		cache: &Option<Arc<ChunkCache>>,
 	) -> Result<(IndexTable, VecDeque<IndexTable>, ColumnStats)> {
 		let mut reindexing = VecDeque::new();
 		let mut top = None;
 		let mut stats = ColumnStats::empty();
 		for bits in (MIN_INDEX_BITS..65).rev() {
 			let id = IndexTableId::new(col, bits);
-			if let Some(table) = IndexTable::open_existing(path, id)? {
+			if let Some(table) =
+				IndexTable::open_existing(path, id, span_bits, advice, cache.clone())?
+			{
 				if top.is_none() {
 					stats = table.load_stats();
 					top = Some(table);
@@ -360,7 +865,13 @@ impl HashColumn {
 		}
 		let table = match top {
 			Some(table) => table,
-			None => IndexTable::create_new(path, IndexTableId::new(col, MIN_INDEX_BITS)),
+			None => IndexTable::create_new(
+				path,
+				IndexTableId::new(col, MIN_INDEX_BITS),
+				span_bits,
+				advice,
+				cache.clone(),
+			),
 		};
 		Ok((table, reindexing, stats))
 	}
@@ -369,6 +880,7 @@ impl HashColumn {
 		tables: RwLockUpgradableReadGuard<'a, Tables>,
 		reindex: RwLockUpgradableReadGuard<'b, Reindex>,
 		path: &std::path::Path,
+		cache: &Option<Arc<ChunkCache>>,
 	) -> (RwLockUpgradableReadGuard<'a, Tables>, RwLockUpgradableReadGuard<'b, Reindex>) {
 		let mut tables = RwLockUpgradableReadGuard::upgrade(tables);
 		let mut reindex = RwLockUpgradableReadGuard::upgrade(reindex);
@@ -380,7 +892,13 @@ impl HashColumn {
 		// Start reindex
 		let new_index_id =
 			IndexTableId::new(tables.index.id.col(), tables.index.id.index_bits() + 1);
-		let new_table = IndexTable::create_new(path, new_index_id);
+		let new_table = IndexTable::create_new(
+			path,
+			new_index_id,
+			tables.index.span_bits(),
+			tables.index.advice(),
+			cache.clone(),
+		);
 		let old_table = std::mem::replace(&mut tables.index, new_table);
 		reindex.queue.push_back(old_table);
 		(
@@ -397,13 +915,18 @@ impl HashColumn {
 	) -> Result<PlanOutcome> {
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
-		if Self::search_index(key, &tables.index, &*tables, log)?.is_some() {
+		// Reindexed entries only come with the key prefix, but the key of an inline value is
+		// known in full.
+		let key =
+			&if self.is_inline(address) { self.inline_key(&address.inline_value()) } else { *key };
+		if self.search_index(key, &tables.index, &tables, log)?.is_some() {
 			return Ok(PlanOutcome::Skipped)
 		}
 		match tables.index.write_insert_plan(key, address, None, log)? {
 			PlanOutcome::NeedReindex => {
 				log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
-				let _lock = Self::trigger_reindex(tables, reindex, self.path.as_path());
+				let _lock =
+					Self::trigger_reindex(tables, reindex, self.path.as_path(), &self.index_cache);
 				self.write_reindex_plan(key, address, log)?;
 				Ok(PlanOutcome::NeedReindex)
 			},
@@ -411,7 +934,17 @@ impl HashColumn {
 		}
 	}
 
+	fn is_inline(&self, address: Address) -> bool {
+		self.inline_values && address.size_tier() == INLINE_TIER
+	}
+
+	// Hashed key of a value stored in the index entry, which is the preimage of its key.
+	fn inline_key(&self, value: &[u8]) -> Key {
+		self.hash_key(blake2_rfc::blake2b::blake2b(32, &[], value).as_bytes())
+	}
+
 	fn search_index<'a>(
+		&self,
 		key: &Key,
 		index: &'a IndexTable,
 		tables: &'a Tables,
@@ -422,11 +955,16 @@ impl HashColumn {
 			let existing_address = existing_entry.address(index.id.index_bits());
 			let existing_tier = existing_address.size_tier();
 			let table_key = TableKey::Partial(*key);
-			if tables.value[existing_tier as usize].has_key_at(
-				existing_address.offset(),
-				&table_key,
-				log,
-			)? {
+			let found = if self.is_inline(existing_address) {
+				self.inline_key(&existing_address.inline_value()) == *key
+			} else {
+				tables.value[existing_tier as usize].has_key_at(
+					existing_address.offset(),
+					&table_key,
+					log,
+				)?
+			};
+			if found {
 				return Ok(Some((index, sub_index, existing_address)))
 			}
 
@@ -438,18 +976,19 @@ impl HashColumn {
 	}
 
 	fn search_all_indexes<'a>(
+		&self,
 		key: &Key,
 		tables: &'a Tables,
 		reindex: &'a Reindex,
 		log: &LogWriter,
 	) -> Result<Option<(&'a IndexTable, usize, Address)>> {
-		if let Some(r) = Self::search_index(key, &tables.index, tables, log)? {
+		if let Some(r) = self.search_index(key, &tables.index, tables, log)? {
 			return Ok(Some(r))
 		}
 		// Check old indexes
 		// TODO: don't search if index precedes reindex progress
 		for index in &reindex.queue {
-			if let Some(r) = Self::search_index(key, index, tables, log)? {
+			if let Some(r) = self.search_index(key, index, tables, log)? {
 				return Ok(Some(r))
 			}
 		}
@@ -460,23 +999,107 @@ impl HashColumn {
 		&self,
 		key: &Key,
 		value: Option<&[u8]>,
+		if_absent: bool,
 		log: &mut LogWriter,
-	) -> Result<PlanOutcome> {
+	) -> Result<(PlanOutcome, CommitOutcome)> {
+		if let (Some(bloom), Some(_)) = (&self.bloom, value) {
+			bloom.insert(key);
+		}
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
-		let existing = Self::search_all_indexes(key, &*tables, &*reindex, log)?;
+		let existing = self.search_all_indexes(key, &tables, &reindex, log)?;
 		if let Some((table, sub_index, existing_address)) = existing {
-			self.write_plan_existing(&*tables, key, value, log, table, sub_index, existing_address)
+			if if_absent && !self.ref_counted {
+				return Ok((PlanOutcome::Skipped, CommitOutcome::Unchanged))
+			}
+			self.write_plan_existing(&tables, key, value, log, table, sub_index, existing_address)
 		} else if let Some(value) = value {
 			let (r, _, _) = self.write_plan_new(tables, reindex, key, value, log)?;
-			Ok(r)
+			Ok((r, CommitOutcome::Inserted))
 		} else {
 			log::trace!(target: "parity-db", "{}: Deleting missing key {}", tables.index.id, hex(key));
 			if self.collect_stats {
 				self.stats.remove_miss();
 			}
-			Ok(PlanOutcome::Skipped)
+			Ok((PlanOutcome::Skipped, CommitOutcome::NotFound))
+		}
+	}
+
+	/// Add `delta` to the counter stored under `key`. A counter that reaches zero is removed.
+	pub fn write_increment_plan(
+		&self,
+		key: &Key,
+		delta: i64,
+		log: &mut LogWriter,
+	) -> Result<(PlanOutcome, CommitOutcome)> {
+		let current = {
+			let tables = self.tables.read();
+			let reindex = self.reindex.read();
+			match self.search_all_indexes(key, &tables, &reindex, log)? {
+				Some((_, _, address)) => Column::get_value(
+					TableKeyQuery::Check(&TableKey::Partial(*key)),
+					address,
+					self.as_ref(&tables),
+					log,
+				)?
+				.map(|(_, value)| value),
+				None => None,
+			}
+		};
+		let current = match current {
+			Some(value) => i64::from_le_bytes(value.as_slice().try_into().map_err(|_| {
+				Error::Corruption(format!("Bad counter value size for {}", hex(key)))
+			})?),
+			None => 0,
+		};
+		match current.wrapping_add(delta) {
+			0 => self.write_plan(key, None, false, log),
+			value => self.write_plan(key, Some(&value.to_le_bytes()), false, log),
+		}
+	}
+
+	/// Append `value` to the value of `key`, or insert it if there is no such key. Only the last
+	/// part of an uncompressed multipart value is rewritten. Other values are read, extended
+	/// and replaced.
+	pub fn write_append_plan(
+		&self,
+		key: &Key,
+		value: &[u8],
+		log: &mut LogWriter,
+	) -> Result<(PlanOutcome, CommitOutcome)> {
+		if let Some(bloom) = &self.bloom {
+			bloom.insert(key);
+		}
+		let tables = self.tables.upgradable_read();
+		let reindex = self.reindex.upgradable_read();
+		let (index, sub_index, address) =
+			match self.search_all_indexes(key, &tables, &reindex, log)? {
+				Some(existing) => existing,
+				None => {
+					let (r, _, _) = self.write_plan_new(tables, reindex, key, value, log)?;
+					return Ok((r, CommitOutcome::Inserted))
+				},
+			};
+		let table_key = TableKey::Partial(*key);
+		if !self.is_inline(address) {
+			let table = &tables.value[address.size_tier() as usize];
+			if let Some(len) = table.write_append_plan(address.offset(), &table_key, value, log)? {
+				if self.collect_stats {
+					let new_len = (len + value.len()) as u32;
+					self.stats.replace_val(len as u32, len as u32, new_len, new_len);
+				}
+				return Ok((PlanOutcome::Written, CommitOutcome::Appended))
+			}
 		}
+		let query = TableKeyQuery::Check(&table_key);
+		let (_, mut current) = Column::get_value(query, address, self.as_ref(&tables), log)?
+			.ok_or_else(|| {
+				Error::Corruption(format!("{}: Missing value {}", tables.index.id, hex(key)))
+			})?;
+		current.extend_from_slice(value);
+		let (plan, _) =
+			self.write_plan_existing(&tables, key, Some(&current), log, index, sub_index, address)?;
+		Ok((plan, CommitOutcome::Appended))
 	}
 
 	#[allow(clippy::too_many_arguments)]
@@ -489,33 +1112,44 @@ impl HashColumn {
 		index: &IndexTable,
 		sub_index: usize,
 		existing_address: Address,
-	) -> Result<PlanOutcome> {
+	) -> Result<(PlanOutcome, CommitOutcome)> {
 		let stats = if self.collect_stats { Some(&self.stats) } else { None };
 
 		let table_key = TableKey::Partial(*key);
+		let values = self.as_ref(tables);
+		let outcome = match value {
+			Some(_) if values.ref_counted => CommitOutcome::RefIncremented,
+			Some(_) if values.preimage => CommitOutcome::Unchanged,
+			Some(_) => CommitOutcome::Replaced,
+			None => CommitOutcome::Deleted,
+		};
 		match Column::write_existing_value_plan(
 			&table_key,
-			self.as_ref(&tables.value),
+			values,
 			existing_address,
 			value,
 			log,
 			stats,
 		)? {
-			(Some(outcome), _) => return Ok(outcome),
+			// A removal that leaves the value in place only dereferenced it.
+			(Some(plan), _) if value.is_none() => return Ok((plan, CommitOutcome::RefDecremented)),
+			(Some(plan), _) => return Ok((plan, outcome)),
 			(None, Some(value_address)) =>
 				if value.is_some() {
 					// If it was found in an older index we just insert a new entry. Reindex won't
 					// overwrite it.
 					let sub_index =
 						if index.id == tables.index.id { Some(sub_index) } else { None };
-					return tables.index.write_insert_plan(key, value_address, sub_index, log)
+					let plan =
+						tables.index.write_insert_plan(key, value_address, sub_index, log)?;
+					return Ok((plan, outcome))
 				} else {
 					log::trace!(target: "parity-db", "{}: Removing from index {}", tables.index.id, hex(key));
 					index.write_remove_plan(key, sub_index, log)?;
 				},
 			_ => unreachable!(),
 		}
-		Ok(PlanOutcome::Skipped)
+		Ok((PlanOutcome::Skipped, outcome))
 	}
 
 	fn write_plan_new<'a, 'b>(
@@ -530,19 +1164,15 @@ impl HashColumn {
 		RwLockUpgradableReadGuard<'a, Tables>,
 		RwLockUpgradableReadGuard<'b, Reindex>,
 	)> {
-		let stats = self.collect_stats.then(|| &self.stats);
+		let stats = self.collect_stats.then_some(&self.stats);
 		let table_key = TableKey::Partial(*key);
-		let address = Column::write_new_value_plan(
-			&table_key,
-			self.as_ref(&tables.value),
-			value,
-			log,
-			stats,
-		)?;
+		let address =
+			Column::write_new_value_plan(&table_key, self.as_ref(&tables), value, log, stats)?;
 		match tables.index.write_insert_plan(key, address, None, log)? {
 			PlanOutcome::NeedReindex => {
 				log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
-				let (tables, reindex) = Self::trigger_reindex(tables, reindex, self.path.as_path());
+				let (tables, reindex) =
+					Self::trigger_reindex(tables, reindex, self.path.as_path(), &self.index_cache);
 				let (_, t, r) = self.write_plan_new(tables, reindex, key, value, log)?;
 				Ok((PlanOutcome::NeedReindex, t, r))
 			},
@@ -550,13 +1180,100 @@ impl HashColumn {
 		}
 	}
 
+	/// Write an uncompressed value of `len` bytes, passing the space for each part to `fill`.
+	/// If `fill` fails, the value is not written and the existing value is left in place.
+	/// Existing values of ref counted or preimage columns are handled as with `write_plan` and
+	/// `fill` is not called. The value is always stored in a value table.
+	pub fn write_stream_plan(
+		&self,
+		key: &Key,
+		len: usize,
+		mut fill: impl FnMut(&mut [u8]) -> bool,
+		log: &mut LogWriter,
+	) -> Result<PlanOutcome> {
+		if let Some(bloom) = &self.bloom {
+			bloom.insert(key);
+		}
+		let stats = self.collect_stats.then_some(&self.stats);
+		let table_key = TableKey::Partial(*key);
+		let address = {
+			let tables = self.tables.upgradable_read();
+			let reindex = self.reindex.upgradable_read();
+			let values = self.as_ref(&tables);
+			let existing = self.search_all_indexes(key, &tables, &reindex, log)?;
+			if let Some((_, _, address)) = existing {
+				if values.ref_counted {
+					log::trace!(target: "parity-db", "{}: Increment ref {}", tables.index.id, hex(key));
+					tables.value[address.size_tier() as usize]
+						.write_inc_ref(address.offset(), log)?;
+					return Ok(PlanOutcome::Written)
+				}
+				if values.preimage {
+					return Ok(PlanOutcome::Skipped)
+				}
+			}
+			// The new value is written to new slots, so that the existing value is kept if
+			// `fill` fails.
+			let tier = Column::target_tier(&table_key, len, &tables.value, values.first_tier());
+			let mut complete = true;
+			let offset = tables.value[tier].write_insert_stream_plan(
+				&table_key,
+				len,
+				|part| complete = complete && fill(part),
+				log,
+			)?;
+			if !complete {
+				tables.value[tier].write_remove_plan(offset, log)?;
+				return Ok(PlanOutcome::Skipped)
+			}
+			log::trace!(target: "parity-db", "{}: Streamed {}, size = {}", tables.index.id, hex(key), len);
+			let address = Address::new(offset, tier as u8);
+			match existing {
+				Some((index, sub_index, existing_address)) => {
+					if let Some(stats) = stats {
+						let (cur_size, uncompressed) =
+							Column::stored_sizes(&table_key, &values, existing_address, log)?;
+						stats.replace_val(cur_size, uncompressed, len as u32, len as u32);
+					}
+					if !self.is_inline(existing_address) {
+						tables.value[existing_address.size_tier() as usize]
+							.write_remove_plan(existing_address.offset(), log)?;
+					}
+					if index.id == tables.index.id {
+						return tables.index.write_insert_plan(key, address, Some(sub_index), log)
+					}
+				},
+				None =>
+					if let Some(stats) = stats {
+						stats.insert_val(len as u32, len as u32);
+					},
+			}
+			address
+		};
+		self.write_reindex_plan(key, address, log)
+	}
+
 	pub fn enact_plan(&self, action: LogAction, log: &mut LogReader) -> Result<()> {
 		let tables = self.tables.read();
 		let reindex = self.reindex.read();
 		match action {
 			LogAction::InsertIndex(record) => {
 				if tables.index.id == record.table {
-					tables.index.enact_plan(record.index, log)?;
+					let mut written = tables.index.enact_plan(record.index, log)?;
+					// Keys of records applied with `Db::apply_log_record` were not added to the
+					// filter when the record was written.
+					if let Some(bloom) = &self.bloom {
+						let entries = tables.index.entries(record.index, &NoOverlays);
+						while written != 0 {
+							let i = written.trailing_zeros() as usize;
+							written &= !(1 << i);
+							if !entries[i].is_empty() {
+								bloom.insert(
+									&tables.index.recover_key_prefix(record.index, entries[i]),
+								);
+							}
+						}
+					}
 				} else if let Some(table) = reindex.queue.iter().find(|r| r.id == record.table) {
 					table.enact_plan(record.index, log)?;
 				} else {
@@ -599,7 +1316,12 @@ impl HashColumn {
 						"Missing table {}, starting reindex",
 						record.table,
 					);
-					let _lock = Self::trigger_reindex(tables, reindex, self.path.as_path());
+					let _lock = Self::trigger_reindex(
+						tables,
+						reindex,
+						self.path.as_path(),
+						&self.index_cache,
+					);
 					return self.validate_plan(LogAction::InsertIndex(record), log)
 				}
 			},
@@ -625,112 +1347,606 @@ impl HashColumn {
 		Ok(())
 	}
 
-	pub fn refresh_metadata(&self) -> Result<()> {
+	/// Check if `index` is an allocated slot of value table `tier`.
+	pub fn has_slot(&self, tier: u8, index: u64) -> bool {
+		self.tables
+			.read()
+			.value
+			.get(tier as usize)
+			.is_some_and(|table| index > 0 && index <= table.filled_entries())
+	}
+
+	/// Tombstone slot `index` of value table `tier` and remove all index entries pointing to it.
+	/// Returns the number of removed index entries and whether the slot was tombstoned. A slot
+	/// that is already free is left as it is.
+	pub fn write_force_remove_plan(
+		&self,
+		tier: u8,
+		index: u64,
+		log: &mut LogWriter,
+	) -> Result<(u64, bool)> {
 		let tables = self.tables.read();
-		for t in tables.value.iter() {
-			t.refresh_metadata()?;
+		let reindex = self.reindex.read();
+		let table = match tables.value.get(tier as usize) {
+			Some(table) if index > 0 && index <= table.filled_entries() => table,
+			_ => return Err(Error::Corruption(format!("No slot {} in tier {}", index, tier))),
+		};
+		let address = Address::new(index, tier);
+		let mut removed = tables.index.write_remove_address_plan(address, log)?;
+		for index_table in reindex.queue.iter() {
+			removed += index_table.write_remove_address_plan(address, log)?;
 		}
-		Ok(())
+		if table.is_tombstone(index, log)? {
+			return Ok((removed, false))
+		}
+		table.write_force_remove_plan(index, log)?;
+		Ok((removed, true))
 	}
 
-	pub fn write_stats(&self, writer: &mut impl std::io::Write) {
-		let tables = self.tables.read();
-		tables.index.write_stats(&self.stats);
-		self.stats.write_summary(writer, tables.index.id.col());
+	pub fn value_entry_size(&self, tier: u8) -> Option<u16> {
+		self.tables.read().value.get(tier as usize).map(|t| t.entry_size)
 	}
 
-	pub fn clear_stats(&self) {
-		let tables = self.tables.read();
-		let empty_stats = ColumnStats::empty();
-		tables.index.write_stats(&empty_stats);
+	pub fn index_bits(&self) -> u8 {
+		self.tables.read().index.id.index_bits()
 	}
 
-	pub fn iter_while(&self, log: &Log, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
-		let action = |state| match state {
-			IterStateOrCorrupted::Item(item) => Ok(f(item)),
-			IterStateOrCorrupted::Corrupted(..) =>
-				Err(Error::Corruption("Missing indexed value".into())),
-		};
-		self.iter_while_inner(log, action, 0, true)
+	pub fn filled_entries(&self) -> (u64, u64) {
+		let tables = self.tables.read();
+		tables.value.iter().fold((0, 0), |(entries, bytes), t| {
+			let filled = t.filled_entries();
+			(entries + filled, bytes + filled * t.entry_size as u64)
+		})
 	}
 
-	fn iter_while_inner(
+	pub fn mapped_bytes(&self) -> u64 {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let index: u64 = std::iter::once(&tables.index)
+			.chain(reindex.queue.iter())
+			.map(|index| index.mapped_bytes())
+			.sum();
+		index + tables.value.iter().map(|t| t.mapped_bytes()).sum::<u64>()
+	}
+
+	pub fn file_bytes(&self) -> u64 {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let index: u64 = std::iter::once(&tables.index)
+			.chain(reindex.queue.iter())
+			.map(|index| index.file_bytes())
+			.sum();
+		index + tables.value.iter().map(|t| t.file_bytes()).sum::<u64>()
+	}
+
+	pub fn bloom_bytes(&self) -> u64 {
+		self.bloom.as_ref().map_or(0, |bloom| bloom.bytes())
+	}
+
+	pub fn refresh_metadata(&self) -> Result<()> {
+		let tables = self.tables.read();
+		for t in tables.value.iter() {
+			t.refresh_metadata()?;
+		}
+		Ok(())
+	}
+
+	pub fn with_value_tables<R>(&self, f: impl FnOnce(&[ValueTable]) -> R) -> R {
+		f(&self.tables.read().value)
+	}
+
+	pub fn write_stats(&self, writer: &mut impl std::io::Write) {
+		let tables = self.tables.read();
+		tables.index.write_stats(&self.stats);
+		self.stats.write_summary(writer, tables.index.id.col());
+	}
+
+	// Persist stats in the index file.
+	pub fn save_stats(&self) -> Result<()> {
+		if self.collect_stats {
+			let tables = self.tables.read();
+			// The index file may not exist yet if all changes are still in the log.
+			if tables.index.is_init() || !self.stats.is_empty() {
+				tables.index.save_stats(&self.stats)?;
+			}
+		}
+		Ok(())
+	}
+
+	pub fn clear_stats(&self) {
+		let tables = self.tables.read();
+		let empty_stats = ColumnStats::empty();
+		tables.index.write_stats(&empty_stats);
+	}
+
+	pub fn iter_while(&self, log: &Log, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
+		let action = |state| self.iter_step(state, &mut f);
+		self.iter_while_inner(log.overlays(), action, 0, true, false)
+	}
+
+	/// Start a scan with `iter_tables_while`.
+	pub fn scan_cache(&self) -> ScanCache {
+		let tables = self.tables.read();
+		ScanCache { tables: tables.value.iter().map(|table| table.residency()).collect() }
+	}
+
+	/// Same as `iter_shard_while`, reading the table files only. Entries that are only in the
+	/// log overlay are not seen, so logs must be enacted first and enactment paused meanwhile.
+	/// Values that were not in the OS page cache when the scan started are dropped from it
+	/// once read, except for small slots, see `end_scan`.
+	pub fn iter_tables_while(
+		&self,
+		shard: &IterShard,
+		cache: &ScanCache,
+		mut f: impl FnMut(IterState) -> bool,
+	) -> Result<()> {
+		let action = |state| self.iter_step(state, &mut f);
+		self.iter_shard_inner(shard, &NoOverlays, Some(cache), action)
+	}
+
+	/// Drop the pages of small value table slots that were not in the OS page cache when the
+	/// scan started, once it is done. They hold values read at different points of the scan.
+	/// Index tables are mapped and serve lookups, so they are left in the page cache.
+	pub fn end_scan(&self, cache: &ScanCache) {
+		let tables = self.tables.read();
+		for (table, residency) in tables.value.iter().zip(&cache.tables) {
+			if let Some(residency) = residency {
+				if (table.entry_size as u64) < PAGE_SIZE {
+					table.drop_all_uncached(residency);
+				}
+			}
+		}
+	}
+
+	// Pass an entry found by `iter_while` to `f`. Fails for entries that can't be reported.
+	fn iter_step(
+		&self,
+		state: IterStateOrCorrupted,
+		f: &mut impl FnMut(IterState) -> bool,
+	) -> Result<bool> {
+		match state {
+			IterStateOrCorrupted::Item(item) => Ok(f(item)),
+			IterStateOrCorrupted::Corrupted { .. } =>
+				Err(Error::Corruption("Missing indexed value".into())),
+		}
+	}
+
+	/// Split the iteration done by `iter_while` into parts of the current tables, to iterate
+	/// over with `iter_shard_while`.
+	pub fn iter_shards(&self) -> Vec<IterShard> {
+		let tables = self.tables.read();
+		let mut shards = Vec::new();
+		if self.preimage {
+			for (tier, table) in tables.value[..tables.value.len() - 1].iter().enumerate() {
+				let end = table.filled_entries() + 1;
+				shards.extend((1..end).step_by(ITER_SHARD_SLOTS as usize).map(|start| {
+					IterShard::Values {
+						tier: tier as u8,
+						slots: start..(start + ITER_SHARD_SLOTS).min(end),
+					}
+				}));
+			}
+		}
+		let index = &tables.index;
+		let end = index.id.total_chunks();
+		shards.extend((0..end).step_by(ITER_SHARD_CHUNKS as usize).map(|start| IterShard::Index {
+			table: index.id,
+			chunks: start..(start + ITER_SHARD_CHUNKS).min(end),
+			multipart_only: self.preimage,
+		}));
+		shards
+	}
+
+	#[cfg(feature = "rayon")]
+	/// Same as `iter_while`, over part `shard` of the column. Fails if the index table of the
+	/// shard was dropped after a reindex in the meantime.
+	pub fn iter_shard_while(
+		&self,
+		shard: &IterShard,
+		log: &Log,
+		mut f: impl FnMut(IterState) -> bool,
+	) -> Result<()> {
+		let action = |state| self.iter_step(state, &mut f);
+		self.iter_shard_inner(shard, log.overlays(), None, action)
+	}
+
+	fn iter_shard_inner(
+		&self,
+		shard: &IterShard,
+		log: &impl LogQuery,
+		cache: Option<&ScanCache>,
+		action: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
+	) -> Result<()> {
+		let tables = self.tables.read();
+		match shard {
+			IterShard::Values { tier, slots } => self.iter_preimage_slots(
+				&tables.value[*tier as usize],
+				slots.clone(),
+				log,
+				cache.and_then(|cache| cache.tables[*tier as usize].as_ref()),
+				action,
+			),
+			IterShard::Index { table, chunks, multipart_only } => {
+				let reindex = self.reindex.read();
+				let source = std::iter::once(&tables.index)
+					.chain(reindex.queue.iter())
+					.find(|index| index.id == *table)
+					.ok_or_else(|| {
+						Error::InvalidInput(format!("{}: Index dropped during iteration", table))
+					})?;
+				self.iter_chunks(
+					&tables.value,
+					source,
+					chunks.clone(),
+					*multipart_only,
+					log,
+					cache,
+					action,
+				)
+			},
+		}
+	}
+
+	/// Iterate over all entries of the current index table, then of the tables that are still
+	/// being reindexed, in chunk order.
+	pub fn iter_index_while(&self, log: &Log, mut f: impl FnMut(IndexEntry) -> bool) -> Result<()> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			let index_bits = index.id.index_bits();
+			for chunk_index in 0..index.id.total_chunks() {
+				for (sub_index, entry) in
+					index.entries(chunk_index, log.overlays()).iter().enumerate()
+				{
+					if entry.is_empty() {
+						continue
+					}
+					let address = entry.address(index_bits);
+					let mut key_prefix = [0u8; 8];
+					key_prefix
+						.copy_from_slice(&index.recover_key_prefix(chunk_index, *entry)[0..8]);
+					let entry = IndexEntry {
+						index_bits,
+						chunk_index,
+						sub_index: sub_index as u8,
+						raw: entry.as_u64(),
+						size_tier: address.size_tier(),
+						offset: address.offset(),
+						partial_key: entry.partial_key(index_bits),
+						key_prefix,
+					};
+					if !f(entry) {
+						return Ok(())
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	pub fn index_stats(&self, log: &Log) -> IndexStats {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let index = &tables.index;
+		let mut stats = IndexStats {
+			index_bits: index.id.index_bits(),
+			chunk_histogram: vec![0; index.chunk_entries() + 1],
+			..Default::default()
+		};
+		// Chunks of a group are filled as one.
+		let span = 1u64 << index.span_bits();
+		for group in 0..index.id.total_chunks() / span {
+			let filled: usize = (group * span..(group + 1) * span)
+				.map(|c| index.entries(c, log.overlays()).iter().filter(|e| !e.is_empty()).count())
+				.sum();
+			stats.entries += filled as u64;
+			stats.max_chunk_entries = stats.max_chunk_entries.max(filled as u64);
+			stats.chunk_histogram[filled] += 1;
+		}
+		stats.full_chunks = stats.chunk_histogram[index.chunk_entries()];
+		for index in reindex.queue.iter() {
+			for chunk_index in 0..index.id.total_chunks() {
+				stats.reindex_entries += index
+					.entries(chunk_index, log.overlays())
+					.iter()
+					.filter(|e| !e.is_empty())
+					.count() as u64;
+			}
+		}
+		stats
+	}
+
+	/// Number of keys in the column. Reads the whole index. Chunks of an index that is being
+	/// reindexed are only counted until they are copied into the current index, so the count
+	/// may be briefly off by the batch that is being copied.
+	pub fn key_count(&self, log: &Log) -> u64 {
+		self.count_keys(log, u64::MAX)
+	}
+
+	/// Estimate of `key_count` from a fixed number of index chunks of each index table. Exact
+	/// as long as the index is small enough to be read whole.
+	pub fn approximate_key_count(&self, log: &Log) -> u64 {
+		self.count_keys(log, KEY_COUNT_SAMPLE_CHUNKS)
+	}
+
+	fn count_keys(&self, log: &Log, samples: u64) -> u64 {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let progress = reindex.progress.load(Ordering::Relaxed);
+		let mut count = count_index_entries(&tables.index, 0, samples, log);
+		for (i, index) in reindex.queue.iter().enumerate() {
+			let start = if i == 0 { progress } else { 0 };
+			count += count_index_entries(index, start, samples, log);
+		}
+		count
+	}
+
+	/// Iterate over values in value table slot order. This reads the tables sequentially, but
+	/// the full key can only be recovered for preimage columns. Values that span multiple
+	/// slots are still looked up through the index.
+	pub fn iter_values_while(
 		&self,
 		log: &Log,
+		mut f: impl FnMut(ValueIterState) -> bool,
+	) -> Result<()> {
+		let mut error = None;
+		let mut done = false;
+		{
+			let tables = self.tables.read();
+			for table in &tables.value[..tables.value.len() - 1] {
+				table.iter_while(log.overlays(), |_index, rc, value, compressed, partial| {
+					let value = if compressed {
+						match self.compression.decompress(&value) {
+							Ok(value) => value,
+							Err(e) => {
+								error = Some(e);
+								return false
+							},
+						}
+					} else {
+						value
+					};
+					let mut key = Key::default();
+					key[KEY_SIZE - PARTIAL_SIZE..].copy_from_slice(partial);
+					let mut partial_key = true;
+					if self.preimage {
+						let hash = blake2_rfc::blake2b::blake2b(32, &[], &value);
+						let hash = self.hash_key(hash.as_bytes());
+						if hash[KEY_SIZE - PARTIAL_SIZE..] == key[KEY_SIZE - PARTIAL_SIZE..] {
+							key = hash;
+							partial_key = false;
+						}
+					}
+					done = !f(ValueIterState { key, partial_key, rc, value });
+					!done
+				})?;
+				if let Some(e) = error.take() {
+					return Err(e)
+				}
+				if done {
+					return Ok(())
+				}
+			}
+		}
+		let action = |state| match state {
+			IterStateOrCorrupted::Item(IterState { key, rc, value, .. }) =>
+				Ok(f(ValueIterState { key, partial_key: false, rc, value })),
+			IterStateOrCorrupted::Corrupted { .. } =>
+				Err(Error::Corruption("Missing indexed value".into())),
+		};
+		self.iter_while_inner(log.overlays(), action, 0, false, true)
+	}
+
+	fn iter_while_inner(
+		&self,
+		log: &impl LogQuery,
 		mut f: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
 		start_chunk: u64,
 		skip_preimage_indexes: bool,
+		multipart_only: bool,
 	) -> Result<()> {
 		let tables = self.tables.read();
 		let source = &tables.index;
+		let multipart_only = multipart_only || (skip_preimage_indexes && self.preimage);
 
 		if skip_preimage_indexes && self.preimage {
 			// It is much faster to iterate over the value table than index.
 			// We have to assume hashing scheme however.
 			for table in &tables.value[..tables.value.len() - 1] {
-				log::debug!( target: "parity-db", "{}: Iterating table {}", source.id, table.id);
-				table.iter_while(&*log.overlays(), |index, rc, value, compressed| {
-					let value = if compressed {
-						if let Ok(value) = self.compression.decompress(&value) {
-							value
-						} else {
-							return false
-						}
-					} else {
-						value
-					};
-					let key = blake2_rfc::blake2b::blake2b(32, &[], &value);
-					let key = self.hash_key(key.as_bytes());
-					let state = IterStateOrCorrupted::Item(IterState {
-						chunk_index: index,
-						key,
-						rc,
-						value,
-					});
-					f(state).unwrap_or(false)
-				})?;
-				log::debug!( target: "parity-db", "{}: Done Iterating table {}", source.id, table.id);
+				self.iter_preimage_slots(table, 1..u64::MAX, log, None, &mut f)?;
 			}
 		}
+		let chunks = start_chunk..source.id.total_chunks();
+		self.iter_chunks(&tables.value, source, chunks, multipart_only, log, None, f)
+	}
 
-		for c in start_chunk..source.id.total_chunks() {
-			let entries = source.entries(c, &*log.overlays());
-			for entry in entries.iter() {
-				if entry.is_empty() {
-					continue
-				}
-				let (size_tier, offset) = if self.db_version >= 4 {
-					let address = entry.address(source.id.index_bits());
-					(address.size_tier(), address.offset())
+	// Iterate over values in slots `slots` of `table` of a preimage column. Keys are the hashes
+	// of the values. With `residency` the slots are read ahead, and the pages that were not
+	// cached are dropped from the page cache after each block.
+	fn iter_preimage_slots(
+		&self,
+		table: &ValueTable,
+		slots: Range<u64>,
+		log: &impl LogQuery,
+		residency: Option<&Residency>,
+		mut f: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
+	) -> Result<()> {
+		log::debug!(target: "parity-db", "{}: Iterating slots {:?}", table.id, slots);
+		let more = std::cell::Cell::new(true);
+		let mut visit = |index, rc, value: Vec<u8>, compressed, _partial: &_| {
+			let value = if compressed {
+				if let Ok(value) = self.compression.decompress(&value) {
+					value
 				} else {
-					let addr_bits = source.id.index_bits() + 10;
-					let address = Address::from_u64(entry.as_u64() & ((1u64 << addr_bits) - 1));
-					let size_tier = (address.as_u64() & 0x0f) as u8;
-					let offset = address.as_u64() >> 4;
-					(size_tier, offset)
-				};
+					more.set(false);
+					return false
+				}
+			} else {
+				value
+			};
+			let key = blake2_rfc::blake2b::blake2b(32, &[], &value);
+			let key = self.hash_key(key.as_bytes());
+			let state =
+				IterStateOrCorrupted::Item(IterState { chunk_index: index, key, rc, value });
+			more.set(f(state).unwrap_or(false));
+			more.get()
+		};
+		if let Some(residency) = residency {
+			table.advise_sequential(true);
+			let block = (SCAN_BLOCK_BYTES / table.entry_size as u64).max(1);
+			let end = slots.end.min(table.filled_entries() + 1);
+			let entry_size = table.entry_size as u64;
+			let mut start = slots.start.max(1);
+			// First slot of the page that was only partly dropped.
+			let mut dropped = start;
+			let mut result = Ok(());
+			while more.get() && result.is_ok() && start < end {
+				let block_end = (start + block).min(end);
+				result = table.iter_range_while(start..block_end, log, &mut visit);
+				table.drop_uncached(residency, dropped..block_end);
+				dropped = block_end * entry_size / PAGE_SIZE * PAGE_SIZE / entry_size;
+				start = block_end;
+			}
+			table.advise_sequential(false);
+			result?;
+		} else {
+			table.iter_range_while(slots.clone(), log, visit)?;
+		}
+		log::debug!(target: "parity-db", "{}: Done iterating slots {:?}", table.id, slots);
+		Ok(())
+	}
 
-				if skip_preimage_indexes &&
-					self.preimage && size_tier as usize != tables.value.len() - 1
+	// Iterate over the entries of index chunks `chunks` of `source`. With `cache` values that
+	// were not cached when the scan started are dropped from the page cache once read.
+	#[allow(clippy::too_many_arguments)]
+	fn iter_chunks(
+		&self,
+		values: &[ValueTable],
+		source: &IndexTable,
+		chunks: Range<u64>,
+		multipart_only: bool,
+		log: &impl LogQuery,
+		cache: Option<&ScanCache>,
+		mut f: impl FnMut(IterStateOrCorrupted) -> Result<bool>,
+	) -> Result<()> {
+		for c in chunks {
+			let entries = source.entries(c, log);
+			let locations: Vec<_> = entries
+				.iter()
+				.map(|entry| {
+					if entry.is_empty() {
+						return None
+					}
+					let (size_tier, offset) = if self.db_version >= 4 {
+						let address = entry.address(source.id.index_bits());
+						if self.is_inline(address) {
+							return None
+						}
+						(address.size_tier(), address.offset())
+					} else {
+						let addr_bits = source.id.index_bits() + 10;
+						let address = Address::from_u64(entry.as_u64() & ((1u64 << addr_bits) - 1));
+						let size_tier = (address.as_u64() & 0x0f) as u8;
+						let offset = address.as_u64() >> 4;
+						(size_tier, offset)
+					};
+					if multipart_only && size_tier as usize != values.len() - 1 {
+						return None
+					}
+					Some((size_tier as usize, offset))
+				})
+				.collect();
+
+			// First slots of all values in the chunk are read in a single batch.
+			let mut slots = vec![
+				0u8;
+				locations
+					.iter()
+					.flatten()
+					.map(|(tier, _)| values[*tier].entry_size as usize)
+					.sum()
+			];
+			let mut prefetched = vec![None; entries.len()];
+			let generation;
+			{
+				let mut requests = Vec::new();
+				let mut rest = slots.as_mut_slice();
+				let mut start = 0;
+				for (i, (tier, offset)) in
+					locations.iter().enumerate().filter_map(|(i, l)| Some((i, (*l)?)))
 				{
-					continue
+					let table = &values[tier];
+					let len = table.entry_size as usize;
+					let (slot, tail) = std::mem::take(&mut rest).split_at_mut(len);
+					rest = tail;
+					if let Some(request) = table.slot_read(offset, slot) {
+						requests.push(request);
+						prefetched[i] = Some(start..start + len);
+					}
+					start += len;
+				}
+				generation = log.generation();
+				if crate::file::read_batch(&mut requests).is_err() {
+					// Read the values one by one to report errors for each of them.
+					prefetched.iter_mut().for_each(|p| *p = None);
+				}
+			}
+
+			for (i, entry) in entries.iter().enumerate() {
+				let (size_tier, offset) = match locations[i] {
+					Some(location) => location,
+					None => {
+						if let Some(state) = self.inline_state(source, c, *entry) {
+							if !f(state)? {
+								return Ok(())
+							}
+						}
+						continue
+					},
+				};
+				let mut value = values[size_tier].get_with_meta(
+					offset,
+					prefetched[i].clone().map(|range| &slots[range]),
+					log,
+				);
+				if prefetched[i].is_some() && log.generation() != generation {
+					// The slot may have been written after the batch was read, see
+					// `ValueTable::iter_range_while`.
+					value = values[size_tier].get_with_meta(offset, None, log);
+				}
+				if let Some(residency) = cache.and_then(|cache| cache.tables[size_tier].as_ref()) {
+					values[size_tier].drop_uncached_value(residency, offset, log);
 				}
-				let value =
-					tables.value[size_tier as usize].get_with_meta(offset, &*log.overlays());
+				let corrupted = |error| IterStateOrCorrupted::Corrupted {
+					chunk_index: c,
+					size_tier: size_tier as u8,
+					offset,
+					error,
+				};
 				let (value, rc, pk, compressed) = match value {
 					Ok(Some(v)) => v,
 					Ok(None) => {
-						f(IterStateOrCorrupted::Corrupted(*entry, None))?;
+						f(corrupted(None))?;
 						continue
 					},
 					Err(e) => {
-						f(IterStateOrCorrupted::Corrupted(*entry, Some(e)))?;
+						f(corrupted(Some(e)))?;
 						continue
 					},
 				};
 				let mut key = source.recover_key_prefix(c, *entry);
 				key[6..].copy_from_slice(&pk);
-				let value = if compressed { self.compression.decompress(&value)? } else { value };
+				let value = if compressed {
+					match self.compression.decompress(&value) {
+						Ok(value) => value,
+						Err(e) => {
+							f(corrupted(Some(e)))?;
+							continue
+						},
+					}
+				} else {
+					value
+				};
 				log::debug!(
 					target: "parity-db",
 					"{}: Iterating at {}/{}, key={:?}, pk={:?}",
@@ -750,7 +1966,44 @@ impl HashColumn {
 		Ok(())
 	}
 
-	fn dump(&self, log: &Log, check_param: &crate::CheckOptions, col: ColId) -> Result<()> {
+	// Iteration state of an entry that holds the value itself. The key is the hash of the
+	// value, which must match the key bits of the entry.
+	fn inline_state(
+		&self,
+		index: &IndexTable,
+		chunk_index: u64,
+		entry: crate::index::Entry,
+	) -> Option<IterStateOrCorrupted> {
+		let address = entry.address(index.id.index_bits());
+		if entry.is_empty() || self.db_version < 4 || !self.is_inline(address) {
+			return None
+		}
+		let value = address.inline_value();
+		let key = self.inline_key(&value);
+		let prefix = |k: &Key| {
+			u64::from_be_bytes(k[..8].try_into().unwrap()) >> (64 - index.recovered_key_bits())
+		};
+		if prefix(&key) != prefix(&index.recover_key_prefix(chunk_index, entry)) {
+			return Some(IterStateOrCorrupted::Corrupted {
+				chunk_index,
+				size_tier: INLINE_TIER,
+				offset: address.offset(),
+				error: Some(Error::Corruption(format!(
+					"Inline value in column {} does not match its key",
+					self.col
+				))),
+			})
+		}
+		Some(IterStateOrCorrupted::Item(IterState { chunk_index, key, rc: 1, value }))
+	}
+
+	fn check(
+		&self,
+		log: &Log,
+		check_param: &crate::CheckOptions,
+		col: ColId,
+		report: &mut CheckReport,
+	) -> Result<()> {
 		let start_chunk = check_param.from.unwrap_or(0);
 		let end_chunk = check_param.bound;
 
@@ -759,7 +2012,7 @@ impl HashColumn {
 		log::info!(target: "parity-db", "Starting full index iteration at {:?}", start_time);
 		log::info!(target: "parity-db", "for {} chunks of column {}", self.tables.read().index.id.total_chunks(), col);
 		self.iter_while_inner(
-			log,
+			log.overlays(),
 			|state| match state {
 				IterStateOrCorrupted::Item(IterState { chunk_index, key, rc, value }) => {
 					if Some(chunk_index) == end_chunk {
@@ -768,6 +2021,7 @@ impl HashColumn {
 					if chunk_index % step == 0 {
 						log::info!(target: "parity-db", "Chunk iteration at {}", chunk_index);
 					}
+					report.entries += 1;
 
 					match check_param.display {
 						CheckDisplay::Full => {
@@ -791,41 +2045,65 @@ impl HashColumn {
 					}
 					Ok(true)
 				},
-				IterStateOrCorrupted::Corrupted(entry, e) => {
-					log::info!("Corrupted value for index entry: {}:\n\t{:?}", entry.as_u64(), e);
+				IterStateOrCorrupted::Corrupted { chunk_index, size_tier, offset, error } => {
+					if Some(chunk_index) == end_chunk {
+						return Ok(false)
+					}
+					log::info!(
+						"Corrupted value for index entry in chunk {} at {}:\n\t{:?}",
+						chunk_index,
+						Address::new(offset, size_tier),
+						error,
+					);
+					report.entries += 1;
+					report.corrupted.push(CorruptedEntry {
+						col,
+						chunk: chunk_index,
+						tier: size_tier,
+						index: offset,
+						error: error.map(|e| e.to_string()),
+					});
 					Ok(true)
 				},
 			},
 			start_chunk,
 			false,
+			false,
 		)?;
 
 		log::info!(target: "parity-db", "Ended full index check, elapsed {:?}", start_time.elapsed());
 		Ok(())
 	}
 
-	pub fn reindex(&self, log: &Log) -> Result<ReindexBatch> {
+	/// Collect up to `max_batch` index entries to move to the new index.
+	pub fn reindex(&self, log: &Log, max_batch: usize) -> Result<ReindexBatch> {
 		let tables = self.tables.read();
 		let reindex = self.reindex.read();
 		let mut plan = Vec::new();
 		let mut drop_index = None;
+		let mut start = None;
 		if let Some(source) = reindex.queue.front() {
 			let progress = reindex.progress.load(Ordering::Relaxed);
 			if progress != source.id.total_chunks() {
+				start = Some((source.id, progress));
 				let mut source_index = progress;
-				if source_index % 500 == 0 {
+				if source_index.is_multiple_of(500) {
 					log::debug!(target: "parity-db", "{}: Reindexing at {}/{}", tables.index.id, source_index, source.id.total_chunks());
 				}
 				log::debug!(target: "parity-db", "{}: Continue reindex at {}/{}", tables.index.id, source_index, source.id.total_chunks());
-				while source_index < source.id.total_chunks() && plan.len() < MAX_REINDEX_BATCH {
+				while source_index < source.id.total_chunks() && plan.len() < max_batch {
 					log::trace!(target: "parity-db", "{}: Reindexing {}", source.id, source_index);
-					let entries = source.entries(source_index, &*log.overlays());
+					let entries = source.entries(source_index, log.overlays());
 					for entry in entries.iter() {
 						if entry.is_empty() {
 							continue
 						}
 						// We only need key prefix to reindex.
 						let key = source.recover_key_prefix(source_index, *entry);
+						// Keeps a filter rebuild that already scanned the new index complete.
+						if let Some(bloom) = &self.bloom {
+							bloom.insert(&key);
+						}
 						plan.push((key, entry.address(source.id.index_bits())))
 					}
 					source_index += 1;
@@ -838,13 +2116,125 @@ impl HashColumn {
 				}
 			}
 		}
-		Ok(ReindexBatch { drop_index, batch: plan })
+		Ok(ReindexBatch { drop_index, batch: plan, start })
+	}
+
+	/// Load the persisted bloom filter, or schedule a rebuild from the index if it is missing or
+	/// damaged. The file is removed unless `keep_file` is set, since it goes stale with the
+	/// first write. It is saved again on clean shutdown.
+	pub fn open_bloom(&self, keep_file: bool) -> Result<()> {
+		let mut path = self.path.clone();
+		path.push(BloomFilter::file_name(self.col));
+		if let Some(bloom) = &self.bloom {
+			if bloom.load(&path)? {
+				log::debug!(target: "parity-db", "Loaded bloom filter for column {}", self.col);
+			} else {
+				let tables = self.tables.read();
+				let reindex = self.reindex.read();
+				let ids: VecDeque<_> = reindex
+					.queue
+					.iter()
+					.chain(std::iter::once(&tables.index))
+					.filter(|t| t.is_init())
+					.map(|t| t.id)
+					.collect();
+				if ids.is_empty() {
+					bloom.set_ready();
+				} else {
+					log::debug!(target: "parity-db", "Rebuilding bloom filter for column {}", self.col);
+					*self.bloom_rebuild.lock() = Some(BloomRebuild { tables: ids, chunk: 0 });
+				}
+			}
+		}
+		if !keep_file && path.exists() {
+			std::fs::remove_file(&path)?;
+			crate::file::sync_dir(&self.path)?;
+		}
+		Ok(())
+	}
+
+	/// Add keys from the next batch of index chunks to a bloom filter that is being rebuilt.
+	/// Returns `true` if there is more work.
+	pub fn rebuild_bloom(&self, log: &Log) -> Result<bool> {
+		let bloom = match &self.bloom {
+			Some(bloom) => bloom,
+			None => return Ok(false),
+		};
+		let mut rebuild = self.bloom_rebuild.lock();
+		let state = match &mut *rebuild {
+			Some(state) => state,
+			None => return Ok(false),
+		};
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let mut scanned = 0;
+		while let Some(id) = state.tables.front().cloned() {
+			// Tables dropped after reindex had their keys added when they were moved.
+			let table = if tables.index.id == id {
+				Some(&tables.index)
+			} else {
+				reindex.queue.iter().find(|t| t.id == id)
+			};
+			let table = match table {
+				Some(table) if state.chunk < id.total_chunks() => table,
+				_ => {
+					state.tables.pop_front();
+					state.chunk = 0;
+					continue
+				},
+			};
+			if scanned == MAX_BLOOM_REBUILD_CHUNKS {
+				return Ok(true)
+			}
+			for entry in table.entries(state.chunk, log.overlays()).iter() {
+				if !entry.is_empty() {
+					bloom.insert(&table.recover_key_prefix(state.chunk, *entry));
+				}
+			}
+			state.chunk += 1;
+			scanned += 1;
+		}
+		*rebuild = None;
+		bloom.set_ready();
+		log::debug!(target: "parity-db", "Rebuilt bloom filter for column {}", self.col);
+		Ok(false)
+	}
+
+	/// Persist a complete bloom filter.
+	pub fn save_bloom(&self) -> Result<()> {
+		if let Some(bloom) = self.bloom.as_ref().filter(|bloom| bloom.is_ready()) {
+			let mut path = self.path.clone();
+			path.push(BloomFilter::file_name(self.col));
+			bloom.save(&path)?;
+		}
+		Ok(())
+	}
+
+	/// Start moving the index to a new table with twice as many chunks. Returns `false` if a
+	/// reindex is already in progress.
+	pub fn grow_index(&self) -> bool {
+		let tables = self.tables.upgradable_read();
+		let reindex = self.reindex.upgradable_read();
+		if !reindex.queue.is_empty() {
+			return false
+		}
+		let _lock = Self::trigger_reindex(tables, reindex, self.path.as_path(), &self.index_cache);
+		true
+	}
+
+	/// Processed and total chunks of the index that is being reindexed.
+	pub fn reindex_progress(&self) -> Option<(u64, u64)> {
+		let reindex = self.reindex.read();
+		reindex
+			.queue
+			.front()
+			.map(|source| (reindex.progress.load(Ordering::Relaxed), source.id.total_chunks()))
 	}
 
 	pub fn drop_index(&self, id: IndexTableId) -> Result<()> {
 		log::debug!(target: "parity-db", "Dropping {}", id);
 		let mut reindex = self.reindex.write();
-		if reindex.queue.front_mut().map_or(false, |index| index.id == id) {
+		if reindex.queue.front_mut().is_some_and(|index| index.id == id) {
 			let table = reindex.queue.pop_front();
 			reindex.progress.store(0, Ordering::Relaxed);
 			table.unwrap().drop_file()?;
@@ -867,6 +2257,7 @@ impl Column {
 		stats: Option<&ColumnStats>,
 	) -> Result<(Option<PlanOutcome>, Option<Address>)> {
 		let tier = address.size_tier() as usize;
+		let inline = tables.inline_value(address).is_some();
 		if let Some(val) = value.as_ref() {
 			if tables.ref_counted {
 				log::trace!(target: "parity-db", "{}: Increment ref {}", tables.col, key);
@@ -878,30 +2269,25 @@ impl Column {
 				return Ok((Some(PlanOutcome::Skipped), None))
 			}
 
-			let (cval, target_tier) = Column::compress(tables.compression, key, val, tables.tables);
+			if tables.fits_inline(key, val) {
+				if let Some(stats) = stats {
+					let (cur_size, uncompressed) =
+						Column::stored_sizes(key, &tables, address, log)?;
+					stats.replace_val(cur_size, uncompressed, val.len() as u32, val.len() as u32);
+				}
+				log::trace!(target: "parity-db", "{}: Replacing inline {}", tables.col, key);
+				if !inline {
+					tables.tables[tier].write_remove_plan(address.offset(), log)?;
+				}
+				return Ok((None, Some(Address::inline(val))))
+			}
+			let (cval, target_tier) = Column::compress(key, val, &tables, stats);
 			let (cval, compressed) =
 				cval.as_ref().map(|cval| (cval.as_slice(), true)).unwrap_or((val, false));
 
 			if let Some(stats) = stats {
-				let (cur_size, compressed) =
-					tables.tables[tier].size(key, address.offset(), log)?.unwrap_or((0, false));
-				if compressed {
-					// This is very costly.
-					let compressed = tables.tables[tier]
-						.get(key, address.offset(), log)?
-						.expect("Same query as size")
-						.0;
-					let uncompressed = tables.compression.decompress(compressed.as_slice())?;
-
-					stats.replace_val(
-						cur_size,
-						uncompressed.len() as u32,
-						val.len() as u32,
-						cval.len() as u32,
-					);
-				} else {
-					stats.replace_val(cur_size, cur_size, val.len() as u32, cval.len() as u32);
-				}
+				let (cur_size, uncompressed) = Column::stored_sizes(key, &tables, address, log)?;
+				stats.replace_val(cur_size, uncompressed, val.len() as u32, cval.len() as u32);
 			}
 			if tier == target_tier {
 				log::trace!(target: "parity-db", "{}: Replacing {}", tables.col, key);
@@ -915,7 +2301,9 @@ impl Column {
 				Ok((Some(PlanOutcome::Written), None))
 			} else {
 				log::trace!(target: "parity-db", "{}: Replacing in a new table {}", tables.col, key);
-				tables.tables[tier].write_remove_plan(address.offset(), log)?;
+				if !inline {
+					tables.tables[tier].write_remove_plan(address.offset(), log)?;
+				}
 				let new_offset =
 					tables.tables[target_tier].write_insert_plan(key, cval, log, compressed)?;
 				let new_address = Address::new(new_offset, target_tier as u8);
@@ -924,20 +2312,7 @@ impl Column {
 		} else {
 			// Deletion
 			let cur_size = if stats.is_some() {
-				let (cur_size, compressed) =
-					tables.tables[tier].size(key, address.offset(), log)?.unwrap_or((0, false));
-				Some(if compressed {
-					// This is very costly.
-					let compressed = tables.tables[tier]
-						.get(key, address.offset(), log)?
-						.expect("Same query as size")
-						.0;
-					let uncompressed = tables.compression.decompress(compressed.as_slice())?;
-
-					(cur_size, uncompressed.len() as u32)
-				} else {
-					(cur_size, cur_size)
-				})
+				Some(Column::stored_sizes(key, &tables, address, log)?)
 			} else {
 				None
 			};
@@ -947,7 +2322,9 @@ impl Column {
 				removed
 			} else {
 				log::trace!(target: "parity-db", "{}: Deleting {}", tables.col, key);
-				tables.tables[tier].write_remove_plan(address.offset(), log)?;
+				if !inline {
+					tables.tables[tier].write_remove_plan(address.offset(), log)?;
+				}
 				true
 			};
 			if remove {
@@ -963,6 +2340,31 @@ impl Column {
 		}
 	}
 
+	// Stored and uncompressed size of the value at `address`.
+	fn stored_sizes(
+		key: &TableKey,
+		tables: &TablesRef,
+		address: Address,
+		log: &LogWriter,
+	) -> Result<(u32, u32)> {
+		if let Some(inline) = tables.inline_value(address) {
+			return Ok((inline.len() as u32, inline.len() as u32))
+		}
+		let tier = address.size_tier() as usize;
+		let (cur_size, compressed) =
+			tables.tables[tier].size(key, address.offset(), log)?.unwrap_or((0, false));
+		if !compressed {
+			return Ok((cur_size, cur_size))
+		}
+		// This is very costly.
+		let compressed = tables.tables[tier]
+			.get(key, address.offset(), log)?
+			.expect("Same query as size")
+			.0;
+		let uncompressed = tables.compression.decompress(compressed.as_slice())?;
+		Ok((cur_size, uncompressed.len() as u32))
+	}
+
 	pub fn write_new_value_plan(
 		key: &TableKey,
 		tables: TablesRef,
@@ -970,7 +2372,14 @@ impl Column {
 		log: &mut LogWriter,
 		stats: Option<&ColumnStats>,
 	) -> Result<Address> {
-		let (cval, target_tier) = Column::compress(tables.compression, key, val, tables.tables);
+		if tables.fits_inline(key, val) {
+			log::trace!(target: "parity-db", "{}: Inserting new inline {}, size = {}", tables.col, key, val.len());
+			if let Some(stats) = stats {
+				stats.insert_val(val.len() as u32, val.len() as u32);
+			}
+			return Ok(Address::inline(val))
+		}
+		let (cval, target_tier) = Column::compress(key, val, &tables, stats);
 		let (cval, compressed) =
 			cval.as_ref().map(|cval| (cval.as_slice(), true)).unwrap_or((val, false));
 
@@ -1012,6 +2421,108 @@ impl Column {
 		}
 	}
 
+	/// Flush value tables only.
+	pub fn flush_values(&self) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.flush_values(),
+			Column::Tree(column) => column.flush(),
+		}
+	}
+
+	fn with_value_table<R>(&self, tier: u8, f: impl FnOnce(&ValueTable) -> R) -> R {
+		match self {
+			Column::Hash(column) => f(&column.tables.read().value[tier as usize]),
+			Column::Tree(column) => column.with_value_table(tier, f),
+		}
+	}
+
+	/// Read a value table write from the log without applying it. See `apply_value_plans`.
+	pub fn read_value_plan(
+		&self,
+		record: &InsertValueAction,
+		log: &mut LogReader,
+	) -> Result<Vec<u8>> {
+		self.with_value_table(record.table.size_tier(), |table| {
+			table.read_plan_data(record.index, log)
+		})
+	}
+
+	/// Apply value table writes read with `read_value_plan` to consecutive slots, starting with
+	/// the slot of `record`.
+	pub fn apply_value_plans(&self, record: &InsertValueAction, data: &[Vec<u8>]) -> Result<()> {
+		self.with_value_table(record.table.size_tier(), |table| {
+			table.write_plans_data(record.index, data)
+		})
+	}
+
+	/// Entry size of value table `tier`, if the column has such a tier.
+	pub fn value_entry_size(&self, tier: u8) -> Option<u16> {
+		match self {
+			Column::Hash(column) => column.value_entry_size(tier),
+			Column::Tree(column) => column.value_entry_size(tier),
+		}
+	}
+
+	pub fn open_bloom(&self, keep_file: bool) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.open_bloom(keep_file),
+			Column::Tree(_) => Ok(()),
+		}
+	}
+
+	pub fn rebuild_bloom(&self, log: &Log) -> Result<bool> {
+		match self {
+			Column::Hash(column) => column.rebuild_bloom(log),
+			Column::Tree(_) => Ok(false),
+		}
+	}
+
+	pub fn save_bloom(&self) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.save_bloom(),
+			Column::Tree(_) => Ok(()),
+		}
+	}
+
+	/// Bits of the current index table. `None` for btree columns.
+	pub fn index_bits(&self) -> Option<u8> {
+		match self {
+			Column::Hash(column) => Some(column.index_bits()),
+			Column::Tree(_) => None,
+		}
+	}
+
+	/// Number of allocated value table entries and bytes they occupy.
+	pub fn filled_entries(&self) -> (u64, u64) {
+		match self {
+			Column::Hash(column) => column.filled_entries(),
+			Column::Tree(column) => column.filled_entries(),
+		}
+	}
+
+	/// Length of the memory maps of index and value table files.
+	pub fn mapped_bytes(&self) -> u64 {
+		match self {
+			Column::Hash(column) => column.mapped_bytes(),
+			Column::Tree(column) => column.mapped_bytes(),
+		}
+	}
+
+	/// Size of the index and value table files, as tracked in memory.
+	pub fn file_bytes(&self) -> u64 {
+		match self {
+			Column::Hash(column) => column.file_bytes(),
+			Column::Tree(column) => column.file_bytes(),
+		}
+	}
+
+	pub fn bloom_bytes(&self) -> u64 {
+		match self {
+			Column::Hash(column) => column.bloom_bytes(),
+			Column::Tree(_) => 0,
+		}
+	}
+
 	pub fn refresh_metadata(&self) -> Result<()> {
 		match self {
 			Column::Hash(column) => column.refresh_metadata(),
@@ -1019,6 +2530,14 @@ impl Column {
 		}
 	}
 
+	/// Call `f` with the value tables of the column, by size tier.
+	pub fn with_value_tables<R>(&self, f: impl FnOnce(&[ValueTable]) -> R) -> R {
+		match self {
+			Column::Hash(column) => column.with_value_tables(f),
+			Column::Tree(column) => column.with_value_tables(f),
+		}
+	}
+
 	pub fn write_stats(&self, writer: &mut impl std::io::Write) {
 		match self {
 			Column::Hash(column) => column.write_stats(writer),
@@ -1033,9 +2552,36 @@ impl Column {
 		}
 	}
 
-	pub fn dump(&self, log: &Log, check_param: &crate::CheckOptions, col: ColId) -> Result<()> {
+	pub fn compression_stats(&self) -> CompressionStats {
+		match self {
+			Column::Hash(column) => column.stats.compression(),
+			Column::Tree(_column) => Default::default(),
+		}
+	}
+
+	pub fn value_size_histogram(&self) -> ValueSizeHistogram {
+		match self {
+			Column::Hash(column) => column.stats.value_histogram(),
+			Column::Tree(_column) => Default::default(),
+		}
+	}
+
+	pub fn save_stats(&self) -> Result<()> {
+		match self {
+			Column::Hash(column) => column.save_stats(),
+			Column::Tree(_column) => Ok(()),
+		}
+	}
+
+	pub fn check(
+		&self,
+		log: &Log,
+		check_param: &crate::CheckOptions,
+		col: ColId,
+		report: &mut CheckReport,
+	) -> Result<()> {
 		match self {
-			Column::Hash(column) => column.dump(log, check_param, col),
+			Column::Hash(column) => column.check(log, check_param, col, report),
 			Column::Tree(_column) => Ok(()),
 		}
 	}
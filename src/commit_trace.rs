@@ -0,0 +1,491 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Trace of everything written to the log, see `Options::commit_trace`. Replaying the trace
+//! with `Db::replay_trace` writes the same log records in the same order, so that the state
+//! of a database can be reproduced on another machine. All integers are little endian.
+//!
+//! Header:
+//! [MAGIC: 8][SALT: 32]
+//!
+//! Followed by a sequence of events:
+//! [KIND: 1][LEN: 4][DATA]
+//!
+//! A commit event holds the changesets written in one log record, after multimap removals
+//! were expanded:
+//! [COMMITS: 4] and for each commit
+//! [INDEXED: 4] and for each column [COL: 2][CHANGES: 4][[FLAGS: 1][KEY: 32][VALUE]]
+//! [BTREE: 4] and for each column [COL: 2][CHANGES: 4][[FLAGS: 1][KEY_LEN: 4][KEY][VALUE]]
+//! [FORCE_REMOVE: 4][[COL: 2][TIER: 1][INDEX: 8]]
+//! `VALUE` is [VALUE_LEN: 4][VALUE] and only present with `FLAG_VALUE`.
+//! A reindex event holds the size of the batch, [MAX_ENTRIES: 8].
+//! A vacuum event holds a step of the vacuum of a value table, see `VacuumStep`:
+//! [COL: 2][TIER: 1][FILLED: 8][LAST_REMOVED: 8][COMPLETE: 1][LINKS: 4][[INDEX: 8][NEXT: 8]]
+//!
+//! An incomplete last event is ignored and removed when the trace is opened for writing.
+
+use crate::{
+	btree::commit_overlay::BTreeChangeSet,
+	column::{ColId, Salt},
+	db::{CommitChangeSet, IndexedChangeSet},
+	error::{Error, Result},
+	table::VacuumStep,
+};
+use parking_lot::Mutex;
+use std::{
+	convert::TryInto,
+	io::{Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"PDBTRC02";
+const HEADER_SIZE: usize = 40;
+const EVENT_HEADER_SIZE: usize = 5;
+
+const EVENT_COMMIT: u8 = 1;
+const EVENT_REINDEX: u8 = 2;
+const EVENT_GROW_INDEX: u8 = 3;
+const EVENT_VACUUM: u8 = 4;
+const EVENT_STREAM: u8 = 5;
+const EVENT_LOG_RECORD: u8 = 6;
+
+const FLAG_VALUE: u8 = 0x01;
+const FLAG_IF_ABSENT: u8 = 0x02;
+const FLAG_APPEND: u8 = 0x04;
+const FLAG_INCREMENT: u8 = 0x08;
+
+/// Traced event.
+pub enum TraceEvent {
+	/// Commits written to the log in a single record.
+	Commit(Vec<CommitChangeSet>),
+	/// A reindex record moving up to this many index entries was written.
+	Reindex(usize),
+	/// Reindexing of a column was started with `Db::trigger_reindex`.
+	GrowIndex(ColId),
+	/// A vacuum record was written for a step of the vacuum of a value table.
+	Vacuum { col: ColId, tier: u8, step: VacuumStep },
+	/// A value of `len` bytes was written with `Db::put_stream`. `value` holds the bytes that
+	/// were read from the stream, and is short if reading failed.
+	Stream { col: ColId, key: Vec<u8>, len: u64, value: Vec<u8> },
+	/// A record was added with `Db::apply_log_record`.
+	LogRecord(Vec<u8>),
+}
+
+pub struct TraceWriter {
+	file: Mutex<std::fs::File>,
+}
+
+// Read the next complete event at the current position. Returns `None` at the end of the file
+// or if the last event is incomplete.
+fn read_event(file: &mut impl Read) -> Result<Option<(u8, Vec<u8>)>> {
+	let mut header = [0u8; EVENT_HEADER_SIZE];
+	match file.read_exact(&mut header) {
+		Ok(()) => (),
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e.into()),
+	}
+	let len = u32::from_le_bytes(header[1..5].try_into().unwrap());
+	let mut data = Vec::new();
+	file.take(len as u64).read_to_end(&mut data)?;
+	if data.len() != len as usize {
+		return Ok(None)
+	}
+	Ok(Some((header[0], data)))
+}
+
+fn read_header(file: &mut impl Read) -> Result<Salt> {
+	let mut header = [0u8; HEADER_SIZE];
+	file.read_exact(&mut header)?;
+	if &header[0..8] != MAGIC {
+		return Err(Error::Corruption("Bad commit trace header".into()))
+	}
+	Ok(header[8..].try_into().unwrap())
+}
+
+impl TraceWriter {
+	/// Open the trace at `path` for appending, or create it. An existing trace must have been
+	/// recorded with the same `salt`.
+	pub fn open(path: &Path, salt: &Salt) -> Result<TraceWriter> {
+		let mut file = std::fs::OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)?;
+		if file.metadata()?.len() == 0 {
+			file.write_all(MAGIC)?;
+			file.write_all(salt)?;
+		} else {
+			if read_header(&mut file)? != *salt {
+				return Err(Error::InvalidConfiguration(format!(
+					"Commit trace {} was recorded with another salt",
+					path.display()
+				)))
+			}
+			let mut end = HEADER_SIZE as u64;
+			while let Some((_, data)) = read_event(&mut file)? {
+				end += (EVENT_HEADER_SIZE + data.len()) as u64;
+			}
+			if end != file.metadata()?.len() {
+				log::debug!(target: "parity-db", "Truncating incomplete trace event at {}", end);
+				file.set_len(end)?;
+			}
+			file.seek(SeekFrom::Start(end))?;
+		}
+		Ok(TraceWriter { file: Mutex::new(file) })
+	}
+
+	fn write(&self, kind: u8, data: &[u8]) -> Result<()> {
+		let mut event = Vec::with_capacity(EVENT_HEADER_SIZE + data.len());
+		event.push(kind);
+		event.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		event.extend_from_slice(data);
+		self.file.lock().write_all(&event)?;
+		Ok(())
+	}
+
+	pub fn commit<'a>(
+		&self,
+		commits: impl ExactSizeIterator<Item = &'a CommitChangeSet>,
+	) -> Result<()> {
+		let mut data = Vec::new();
+		put_u32(&mut data, commits.len());
+		for commit in commits {
+			encode_commit(&mut data, commit);
+		}
+		self.write(EVENT_COMMIT, &data)
+	}
+
+	pub fn reindex(&self, max_entries: usize) -> Result<()> {
+		self.write(EVENT_REINDEX, &(max_entries as u64).to_le_bytes())
+	}
+
+	pub fn grow_index(&self, col: ColId) -> Result<()> {
+		self.write(EVENT_GROW_INDEX, &col.to_le_bytes())
+	}
+
+	pub fn vacuum(&self, col: ColId, tier: u8, step: &VacuumStep) -> Result<()> {
+		let mut data = Vec::with_capacity(24 + step.links.len() * 16);
+		data.extend_from_slice(&col.to_le_bytes());
+		data.push(tier);
+		data.extend_from_slice(&step.filled.to_le_bytes());
+		data.extend_from_slice(&step.last_removed.to_le_bytes());
+		data.push(step.complete as u8);
+		put_u32(&mut data, step.links.len());
+		for (index, next) in &step.links {
+			data.extend_from_slice(&index.to_le_bytes());
+			data.extend_from_slice(&next.to_le_bytes());
+		}
+		self.write(EVENT_VACUUM, &data)
+	}
+
+	pub fn stream(&self, col: ColId, key: &[u8], len: u64, value: &[u8]) -> Result<()> {
+		let mut data = Vec::with_capacity(18 + key.len() + value.len());
+		data.extend_from_slice(&col.to_le_bytes());
+		put_vec(&mut data, key);
+		data.extend_from_slice(&len.to_le_bytes());
+		put_vec(&mut data, value);
+		self.write(EVENT_STREAM, &data)
+	}
+
+	pub fn log_record(&self, record: &[u8]) -> Result<()> {
+		self.write(EVENT_LOG_RECORD, record)
+	}
+}
+
+fn put_u32(data: &mut Vec<u8>, value: usize) {
+	data.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn put_vec(data: &mut Vec<u8>, value: &[u8]) {
+	put_u32(data, value.len());
+	data.extend_from_slice(value);
+}
+
+fn encode_commit(data: &mut Vec<u8>, commit: &CommitChangeSet) {
+	// Columns are sorted, so that the trace does not depend on hash map order.
+	let mut indexed: Vec<_> = commit.indexed.values().collect();
+	indexed.sort_by_key(|c| c.col);
+	put_u32(data, indexed.len());
+	for changes in indexed {
+		data.extend_from_slice(&changes.col.to_le_bytes());
+		put_u32(data, changes.changes.len());
+		for (i, (key, value)) in changes.changes.iter().enumerate() {
+			let mut flags = if value.is_some() { FLAG_VALUE } else { 0 };
+			if changes.if_absent.contains(&i) {
+				flags |= FLAG_IF_ABSENT;
+			}
+			if changes.appends.contains(&i) {
+				flags |= FLAG_APPEND;
+			}
+			if changes.increments.contains(&i) {
+				flags |= FLAG_INCREMENT;
+			}
+			data.push(flags);
+			data.extend_from_slice(key);
+			if let Some(value) = value {
+				put_vec(data, value);
+			}
+		}
+	}
+	let mut btree: Vec<_> = commit.btree_indexed.values().collect();
+	btree.sort_by_key(|c| c.col);
+	put_u32(data, btree.len());
+	for changes in btree {
+		data.extend_from_slice(&changes.col.to_le_bytes());
+		put_u32(data, changes.changes.len());
+		for (i, (key, value)) in changes.changes.iter().enumerate() {
+			let mut flags = if value.is_some() { FLAG_VALUE } else { 0 };
+			if changes.if_absent.contains(&i) {
+				flags |= FLAG_IF_ABSENT;
+			}
+			data.push(flags);
+			put_vec(data, key);
+			if let Some(value) = value {
+				put_vec(data, value);
+			}
+		}
+	}
+	put_u32(data, commit.force_remove.len());
+	for (col, tier, index) in commit.force_remove.iter() {
+		data.extend_from_slice(&col.to_le_bytes());
+		data.push(*tier);
+		data.extend_from_slice(&index.to_le_bytes());
+	}
+}
+
+// Reads fields of an event.
+struct Decoder<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+		if self.data.len() < len {
+			return Err(Error::Corruption("Truncated commit trace event".into()))
+		}
+		let (bytes, rest) = self.data.split_at(len);
+		self.data = rest;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+	}
+
+	fn u32(&mut self) -> Result<usize> {
+		Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()) as usize)
+	}
+
+	fn u64(&mut self) -> Result<u64> {
+		Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+	}
+
+	fn vec(&mut self) -> Result<Vec<u8>> {
+		let len = self.u32()?;
+		Ok(self.bytes(len)?.to_vec())
+	}
+
+	fn value(&mut self, flags: u8) -> Result<Option<Vec<u8>>> {
+		Ok(if flags & FLAG_VALUE != 0 { Some(self.vec()?) } else { None })
+	}
+
+	fn finish(&self) -> Result<()> {
+		if !self.data.is_empty() {
+			return Err(Error::Corruption("Unexpected data in commit trace event".into()))
+		}
+		Ok(())
+	}
+
+	fn commit(&mut self) -> Result<CommitChangeSet> {
+		let mut commit = CommitChangeSet::default();
+		for _ in 0..self.u32()? {
+			let mut changes = IndexedChangeSet::new(self.u16()?);
+			for i in 0..self.u32()? {
+				let flags = self.u8()?;
+				let key = self.bytes(32)?.try_into().unwrap();
+				changes.changes.push((key, self.value(flags)?));
+				if flags & FLAG_IF_ABSENT != 0 {
+					changes.if_absent.insert(i);
+				}
+				if flags & FLAG_APPEND != 0 {
+					changes.appends.insert(i);
+				}
+				if flags & FLAG_INCREMENT != 0 {
+					changes.increments.insert(i);
+				}
+			}
+			commit.indexed.insert(changes.col, changes);
+		}
+		for _ in 0..self.u32()? {
+			let mut changes = BTreeChangeSet::new(self.u16()?);
+			for i in 0..self.u32()? {
+				let flags = self.u8()?;
+				let key = self.vec()?;
+				changes.changes.push((key, self.value(flags)?));
+				if flags & FLAG_IF_ABSENT != 0 {
+					changes.if_absent.insert(i);
+				}
+			}
+			commit.btree_indexed.insert(changes.col, changes);
+		}
+		for _ in 0..self.u32()? {
+			let (col, tier) = (self.u16()?, self.u8()?);
+			commit.force_remove.push((col, tier, self.u64()?));
+		}
+		Ok(commit)
+	}
+}
+
+pub struct TraceReader {
+	file: std::io::BufReader<std::fs::File>,
+	salt: Salt,
+}
+
+impl TraceReader {
+	pub fn open(path: &Path) -> Result<TraceReader> {
+		let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+		let salt = read_header(&mut file)?;
+		Ok(TraceReader { file, salt })
+	}
+
+	/// Salt of the traced database.
+	pub fn salt(&self) -> Salt {
+		self.salt
+	}
+
+	pub fn next(&mut self) -> Result<Option<TraceEvent>> {
+		let (kind, data) = match read_event(&mut self.file)? {
+			Some(event) => event,
+			None => return Ok(None),
+		};
+		let mut decoder = Decoder { data: &data };
+		let event = match kind {
+			EVENT_COMMIT => {
+				let count = decoder.u32()?;
+				let mut commits = Vec::new();
+				for _ in 0..count {
+					commits.push(decoder.commit()?);
+				}
+				TraceEvent::Commit(commits)
+			},
+			EVENT_REINDEX => TraceEvent::Reindex(decoder.u64()? as usize),
+			EVENT_GROW_INDEX => TraceEvent::GrowIndex(decoder.u16()?),
+			EVENT_VACUUM => {
+				let (col, tier) = (decoder.u16()?, decoder.u8()?);
+				let (filled, last_removed) = (decoder.u64()?, decoder.u64()?);
+				let complete = decoder.u8()? != 0;
+				let mut links = Vec::new();
+				for _ in 0..decoder.u32()? {
+					links.push((decoder.u64()?, decoder.u64()?));
+				}
+				let step = VacuumStep { links, last_removed, filled, complete };
+				TraceEvent::Vacuum { col, tier, step }
+			},
+			EVENT_STREAM => {
+				let col = decoder.u16()?;
+				let key = decoder.vec()?;
+				let len = decoder.u64()?;
+				TraceEvent::Stream { col, key, len, value: decoder.vec()? }
+			},
+			EVENT_LOG_RECORD => return Ok(Some(TraceEvent::LogRecord(data))),
+			kind => return Err(Error::Corruption(format!("Unknown commit trace event {}", kind))),
+		};
+		decoder.finish()?;
+		Ok(Some(event))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn commit_trace_roundtrip() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("trace");
+		let mut commit = CommitChangeSet::default();
+		let mut indexed = IndexedChangeSet::new(0);
+		indexed.changes.push(([1; 32], Some(vec![1, 2, 3])));
+		indexed.changes.push(([2; 32], None));
+		indexed.changes.push(([3; 32], Some(5i64.to_le_bytes().to_vec())));
+		indexed.increments.insert(2);
+		commit.indexed.insert(0, indexed);
+		let mut btree = BTreeChangeSet::new(1);
+		btree.push(b"key", Some(b"value".to_vec()));
+		btree.if_absent.insert(0);
+		commit.btree_indexed.insert(1, btree);
+		commit.force_remove.push((0, 3, 7));
+
+		let writer = TraceWriter::open(&path, &[5; 32]).unwrap();
+		writer.commit(std::iter::once(&commit)).unwrap();
+		writer.grow_index(2).unwrap();
+		drop(writer);
+		assert!(TraceWriter::open(&path, &[6; 32]).is_err());
+		// Incomplete events are removed when appending.
+		let len = std::fs::metadata(&path).unwrap().len();
+		std::fs::OpenOptions::new()
+			.append(true)
+			.open(&path)
+			.unwrap()
+			.write_all(&[4, 9])
+			.unwrap();
+		let writer = TraceWriter::open(&path, &[5; 32]).unwrap();
+		assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+		let step =
+			VacuumStep { links: vec![(5, 0), (3, 5)], last_removed: 3, filled: 9, complete: true };
+		writer.vacuum(1, 4, &step).unwrap();
+		writer.stream(0, b"stream", 200, &[8; 100]).unwrap();
+		writer.reindex(100).unwrap();
+		drop(writer);
+
+		let mut reader = TraceReader::open(&path).unwrap();
+		assert_eq!(reader.salt(), [5; 32]);
+		match reader.next().unwrap() {
+			Some(TraceEvent::Commit(commits)) => {
+				assert_eq!(commits.len(), 1);
+				let indexed = &commits[0].indexed[&0];
+				assert_eq!(indexed.changes, commit.indexed[&0].changes);
+				assert_eq!(indexed.increments, commit.indexed[&0].increments);
+				assert!(indexed.if_absent.is_empty() && indexed.appends.is_empty());
+				let btree = &commits[0].btree_indexed[&1];
+				assert_eq!(btree.changes, commit.btree_indexed[&1].changes);
+				assert_eq!(btree.if_absent, commit.btree_indexed[&1].if_absent);
+				assert_eq!(commits[0].force_remove, vec![(0, 3, 7)]);
+			},
+			_ => panic!("Expected a commit"),
+		}
+		assert!(matches!(reader.next().unwrap(), Some(TraceEvent::GrowIndex(2))));
+		match reader.next().unwrap() {
+			Some(TraceEvent::Vacuum { col, tier, step: read }) =>
+				assert_eq!((col, tier, read), (1, 4, step)),
+			_ => panic!("Expected a vacuum"),
+		}
+		match reader.next().unwrap() {
+			Some(TraceEvent::Stream { col, key, len, value }) => assert_eq!(
+				(col, key.as_slice(), len, value),
+				(0, &b"stream"[..], 200, vec![8; 100])
+			),
+			_ => panic!("Expected a stream"),
+		}
+		assert!(matches!(reader.next().unwrap(), Some(TraceEvent::Reindex(100))));
+		assert!(reader.next().unwrap().is_none());
+	}
+}
@@ -17,6 +17,15 @@
 //! Compression utility and types.
 
 use crate::error::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Rolling rate of compression attempts that saved enough, in 1/RATE_ONE units. Each attempt
+// moves the rate 1/RATE_WINDOW of the way towards 0 or RATE_ONE.
+const RATE_ONE: u32 = 1024;
+const RATE_WINDOW: u32 = 16;
+// Below this rate compression is only attempted for one value in PROBE_INTERVAL.
+const SKIP_RATE: u32 = RATE_ONE / 16;
+const PROBE_INTERVAL: u32 = 64;
 
 /// Different compression type
 /// allowend and their u8 representation.
@@ -32,16 +41,62 @@ pub enum CompressionType {
 pub struct Compress {
 	inner: Compressor,
 	pub threshold: u32,
+	// Values that compress by less than this percentage are stored uncompressed.
+	min_savings: u8,
+	success_rate: AtomicU32,
+	skipped: AtomicU32,
 }
 
 impl Compress {
 	pub fn new(kind: CompressionType, threshold: u32) -> Self {
-		Compress { inner: kind.into(), threshold }
+		Compress {
+			inner: kind.into(),
+			threshold,
+			min_savings: 0,
+			success_rate: AtomicU32::new(RATE_ONE),
+			skipped: AtomicU32::new(0),
+		}
+	}
+
+	/// Store values uncompressed unless compression saves at least `percent` of their size,
+	/// and mostly stop trying when recent values did not.
+	pub fn with_min_savings(mut self, percent: u8) -> Self {
+		self.min_savings = percent.min(100);
+		self
+	}
+
+	/// Compress a value if that is worth it. Returns `None` if the value should be stored as
+	/// is.
+	pub fn compress_value(&self, value: &[u8]) -> Option<Vec<u8>> {
+		if self.min_savings == 0 {
+			let compressed = self.compress(value);
+			return (compressed.len() < value.len()).then_some(compressed)
+		}
+		if self.success_rate.load(Ordering::Relaxed) < SKIP_RATE &&
+			!self.skipped.fetch_add(1, Ordering::Relaxed).is_multiple_of(PROBE_INTERVAL)
+		{
+			return None
+		}
+		let compressed = self.compress(value);
+		let saved = compressed.len() * 100 <= value.len() * (100 - self.min_savings as usize);
+		let rate = self.success_rate.load(Ordering::Relaxed);
+		let rate = rate - rate / RATE_WINDOW + if saved { RATE_ONE / RATE_WINDOW } else { 0 };
+		self.success_rate.store(rate, Ordering::Relaxed);
+		saved.then_some(compressed)
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		!matches!(self.inner, Compressor::NoCompression(_))
 	}
 }
 
-pub const NO_COMPRESSION: Compress =
-	Compress { inner: Compressor::NoCompression(NoCompression), threshold: u32::MAX };
+pub static NO_COMPRESSION: Compress = Compress {
+	inner: Compressor::NoCompression(NoCompression),
+	threshold: u32::MAX,
+	min_savings: 0,
+	success_rate: AtomicU32::new(RATE_ONE),
+	skipped: AtomicU32::new(0),
+};
 
 enum Compressor {
 	NoCompression(NoCompression),
@@ -185,4 +240,37 @@ mod tests {
 			assert_eq!(original, round_tripped);
 		}
 	}
+
+	#[test]
+	fn test_min_savings() {
+		let compress = Compress::new(CompressionType::Lz4, 0).with_min_savings(50);
+		let mut seed = 0x2545f491u32;
+		let random: Vec<u8> = (0..1000)
+			.map(|_| {
+				seed ^= seed << 13;
+				seed ^= seed >> 17;
+				seed ^= seed << 5;
+				seed as u8
+			})
+			.collect();
+		assert!(compress.compress_value(&[0; 1000]).is_some());
+		// Values that only compress a little are stored as is.
+		let mut half = random[..600].to_vec();
+		half.extend_from_slice(&[0; 400]);
+		assert!(compress.compress_value(&half).is_none());
+
+		// Incompressible values make the rate drop, after which only probes are compressed.
+		for _ in 0..64 {
+			assert!(compress.compress_value(&random).is_none());
+		}
+		let rate = compress.success_rate.load(Ordering::Relaxed);
+		assert!(rate < SKIP_RATE);
+		for _ in 0..PROBE_INTERVAL {
+			assert!(compress.compress_value(&random).is_none());
+		}
+		assert_eq!(compress.success_rate.load(Ordering::Relaxed), rate - rate / RATE_WINDOW);
+		// A probe that compresses well resumes compression.
+		while compress.compress_value(&[0; 1000]).is_none() {}
+		assert!(compress.compress_value(&[0; 1000]).is_some());
+	}
 }
@@ -15,17 +15,35 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-	btree::{commit_overlay::BTreeChangeSet, BTreeIterator, BTreeTable},
-	column::{hash_key, ColId, Column, IterState, ReindexBatch},
+	archive::ChangeStream,
+	btree::{
+		commit_overlay::BTreeChangeSet, multimap_key, multimap_prefix, BTreeIterator, BTreeTable,
+		MultimapIterator,
+	},
+	cache::{ChunkCache, ValueCache},
+	column::{
+		decode_keyed_value, encode_keyed_value, hash_key, unhash_uniform_key, ColId, Column,
+		IndexEntry, IterState, ReindexBatch,
+	},
+	commit_trace::{TraceEvent, TraceReader, TraceWriter},
+	compress::Compress,
 	error::{Error, Result},
-	index::PlanOutcome,
-	log::{Log, LogAction},
-	options::Options,
-	Key,
+	export::{ExportReader, ExportWriter, Header as ExportHeader, KeyKind},
+	index::{IndexTable, PlanOutcome, TableId as IndexTableId, EMPTY_CHUNK},
+	log::{CheckpointChunks, Cleared, InsertValueAction, Log, LogAction, LogReader, ReplayStop},
+	options::{
+		ColumnOptions, CompactionDecision, CompactionFilter, Metadata, OnCorruption, OnDiskFull,
+		Options, SyncMode, Worker, WorkerThreads, CHECKPOINT_VERSION, CURRENT_VERSION,
+		SPLIT_COMMIT_VERSION,
+	},
+	snapshot::{self, SnapshotWriter},
+	stats::{CompressionStats, IndexStats, ValueSizeHistogram},
+	table::{key::PARTIAL_SIZE, FreeListVacuum, VacuumStep, SIZE_TIERS},
+	trace::{Op, OpTimer},
+	Key, KEY_SIZE,
 };
-use fs2::FileExt;
-use parking_lot::{Condvar, Mutex, RwLock};
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 /// The database objects is split into `Db` and `DbInner`.
 /// `Db` creates shared `DbInner` instance and manages background
 /// worker threads that all use the inner object.
@@ -42,9 +60,12 @@ use std::collections::{BTreeMap, HashMap, VecDeque};
 /// cleanup_worker: Flush tables by calling `fsync`, and cleanup log.
 /// Each background worker is signalled with a conditional variable once
 /// there is some work to be done.
-use std::sync::{
-	atomic::{AtomicBool, AtomicU64, Ordering},
-	Arc,
+use std::{
+	sync::{
+		atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
 };
 
 // Max size of commit queue. (Keys + Values). If the queue is
@@ -54,14 +75,431 @@ const MAX_COMMIT_QUEUE_BYTES: usize = 16 * 1024 * 1024;
 // Max size of log overlay. If the overlay is full, processing
 // of commit queue is blocked.
 const MAX_LOG_QUEUE_BYTES: i64 = 128 * 1024 * 1024;
-// Minimum size of log file before it is considered full.
-const MIN_LOG_SIZE_BYTES: u64 = 64 * 1024 * 1024;
-// Number of log files to keep after flush.
-const KEEP_LOGS: usize = 16;
+// Most value table writes joined into a single vectored write when enacting the log.
+const MAX_VALUE_WRITE_BATCH: usize = 1024;
+// Number of imported entries per commit.
+const IMPORT_BATCH_SIZE: usize = 10240;
+// Largest reference count accepted by imports. Each reference is imported as a separate insert.
+const MAX_IMPORT_REFS: u32 = 1 << 20;
+// Text file in the database directory recording forced removals.
+const REPAIR_JOURNAL: &str = "repair_journal";
+// Text file in a copy made by `Db::checkpoint` or `Db::copy_to` with the last record in it.
+const BACKUP_RECORD: &str = "backup_record";
+// Text file in the database directory recording damaged entries skipped by reads.
+const CORRUPTION_JOURNAL: &str = "corruption_journal";
+// Buffers kept for reads with `Db::get_ref`, and the largest buffer kept.
+const MAX_READ_BUFFERS: usize = 64;
+const MAX_READ_BUFFER_SIZE: usize = 64 * 1024;
+// Size of the buffers a streamed value is read into by `Db::put_stream`.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+// Number of entries per column compared by `Db::copy_to`.
+const COPY_VERIFY_SAMPLES: u64 = 1024;
+// Bytes at the start of each table file compared by `Db::copy_to`. Covers table headers.
+const COPY_HEADER_BYTES: usize = 16 * 1024;
+// Max size of the application metadata of a column. It is kept in the metadata file.
+const MAX_COLUMN_METADATA_SIZE: usize = 4096;
+// Logical time that passes with each tick with `Options::deterministic_seed`.
+const LOGICAL_TICK: Duration = Duration::from_millis(1);
 
 /// Value is just a vector of bytes. Value sizes up to 4Gb are allowed.
 pub type Value = Vec<u8>;
 
+/// Time spent by a commit in each stage of the commit pipeline.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitTimings {
+	/// Building the change set and hashing the keys.
+	pub planning: Duration,
+	/// Waiting in the commit queue, including waiting for the queue to drain.
+	pub queueing: Duration,
+	/// Writing the log record.
+	pub log_append: Duration,
+	/// Flushing the log record from memory to the log file. Includes an fsync only if
+	/// `Options::sync_wal` or `Options::sync_mode` ask for one.
+	pub log_flush: Duration,
+	/// Moving the log file on to enactment and waiting for the record to be applied to the
+	/// tables.
+	pub enactment: Duration,
+}
+
+/// A single change, see `Db::commit_operations`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation<K, V> {
+	/// Insert or replace a value. Increments the reference count in reference counted columns.
+	Set(K, V),
+	/// Remove a value, or decrement its reference count in reference counted columns.
+	Dereference(K),
+	/// Insert a value if the key does not exist. Otherwise the entry is left untouched, except
+	/// that reference counted columns increment its reference count.
+	InsertIfAbsent(K, V),
+	/// Append bytes to the value, or insert them if the key does not exist. Only the last part
+	/// of a large value is rewritten, unless it is compressed. Not supported for btree columns
+	/// and columns with `ColumnOptions::ref_counted`, `ColumnOptions::preimage` or
+	/// `ColumnOptions::store_keys` set.
+	Append(K, V),
+	/// Add to a counter in a column with `ColumnOptions::counters` set. Missing counters start
+	/// at zero and counters that reach zero are removed. Overflows wrap around.
+	Increment(K, i64),
+}
+
+/// Effect of a single change, see `Db::commit_with_outcomes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitOutcome {
+	/// The key did not exist and was inserted.
+	Inserted,
+	/// The value of an existing key was replaced.
+	Replaced,
+	/// The key exists and is left as it is. Values in preimage columns are never replaced.
+	Unchanged,
+	/// The key was removed.
+	Deleted,
+	/// The reference count of an existing value was incremented.
+	RefIncremented,
+	/// The reference count of a value was decremented, and the value is still referenced.
+	RefDecremented,
+	/// The key to remove did not exist.
+	NotFound,
+	/// Bytes were appended to the value of an existing key.
+	Appended,
+}
+
+/// Column space usage, see `Db::column_sizes`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnSize {
+	/// Size of the index and value table files, including value tables kept in
+	/// `Options::storage`.
+	pub disk_bytes: u64,
+	/// Bytes used by value table entries that hold data. Removed entries that are not reused
+	/// yet are not included.
+	pub live_bytes: u64,
+	/// Number of value table entries that hold data. Values that don't fit into a single entry
+	/// take several.
+	pub entries: u64,
+	/// Number of value table entries that hold data in each value table tier.
+	pub tier_entries: Vec<u64>,
+	/// Number of index entries, i.e. keys. `None` for btree columns, which have no index.
+	pub keys: Option<u64>,
+}
+
+/// Estimated memory held by the database, see `Db::memory_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+	/// Usage of each column.
+	pub columns: Vec<ColumnMemoryStats>,
+	/// Keys and values of queued commits that are not yet written to the log.
+	pub commit_queue_bytes: u64,
+	/// Buffers of the log files being written and read.
+	pub log_buffer_bytes: u64,
+	/// Index chunks in the cache shared by all columns, see `Options::index_cache_size`.
+	pub index_cache_bytes: u64,
+}
+
+impl MemoryStats {
+	/// Sum of all allocations. Memory maps are not included, since the OS loads and evicts
+	/// their pages as needed.
+	pub fn total_bytes(&self) -> u64 {
+		self.commit_queue_bytes +
+			self.log_buffer_bytes +
+			self.index_cache_bytes +
+			self.columns.iter().map(|c| c.total_bytes()).sum::<u64>()
+	}
+}
+
+/// Estimated memory held for a column, see `MemoryStats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnMemoryStats {
+	/// Latest values of queued commits, used to answer reads until they are logged.
+	pub commit_overlay_bytes: u64,
+	/// Index chunks and value table entries that are logged but not yet written to the tables.
+	pub log_overlay_bytes: u64,
+	/// Cached values, see `ColumnOptions::cache_size`.
+	pub value_cache_bytes: u64,
+	/// Bloom filter of the index, see `ColumnOptions::bloom_filter_bytes`.
+	pub bloom_filter_bytes: u64,
+	/// Length of the memory maps of the index and value table files.
+	pub mapped_bytes: u64,
+}
+
+impl ColumnMemoryStats {
+	/// Sum of all allocations, without memory maps.
+	pub fn total_bytes(&self) -> u64 {
+		self.commit_overlay_bytes +
+			self.log_overlay_bytes +
+			self.value_cache_bytes +
+			self.bloom_filter_bytes
+	}
+}
+
+/// Column index kind, see `ColumnInfo`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnKind {
+	/// Hash index over keys hashed with the database salt.
+	Hashed,
+	/// Hash index over keys used as they are. See `ColumnOptions::uniform`.
+	NoHash,
+	/// Ordered btree index.
+	BTree,
+}
+
+/// Column layout and configuration, see `Db::column_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnInfo {
+	pub kind: ColumnKind,
+	/// Options in effect. Also describes the value format: reference counts, compression,
+	/// stored keys and preimage keys.
+	pub options: ColumnOptions,
+	/// Database format version.
+	pub version: u32,
+	/// Entry size of each value table tier. Values that don't fit into any tier span several
+	/// entries of the last tier.
+	pub tier_entry_sizes: Vec<u16>,
+	/// Bits of the current index table. `None` for btree columns.
+	pub index_bits: Option<u8>,
+}
+
+/// Identity of a database, see `Db::info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DbInfo {
+	/// Random UUID assigned when the database is created. Databases created by older versions
+	/// are assigned one the first time they are opened for writing.
+	pub id: Option<String>,
+	/// Creation time in seconds since the unix epoch. `None` for databases created by older
+	/// versions.
+	pub created: Option<u64>,
+	/// Database format version.
+	pub version: u32,
+}
+
+/// Outcome of the log replay when the database was opened, see `Db::replay_report`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+	/// Number of log records replayed.
+	pub records: u64,
+	/// Last record applied to the tables. Records after it were discarded or not written.
+	pub cutoff: u64,
+	/// Why the replay stopped before the end of the log. `None` if the whole log was replayed.
+	pub stop: Option<ReplayStop>,
+}
+
+/// State of background work, see `Db::health`.
+#[derive(Clone, Debug)]
+pub enum Health {
+	/// Background work is running.
+	Healthy,
+	/// Log enactment is paused because the disk is full, see `OnDiskFull::Retry`. It resumes
+	/// on its own once a retry succeeds.
+	DiskFull,
+	/// A background worker failed. The database has to be reopened.
+	Failed(Arc<Error>),
+}
+
+/// Returned by `Db::commit_with_ack`.
+pub struct CommitHandle {
+	db: Arc<DbInner>,
+	commit_id: u64,
+}
+
+impl CommitHandle {
+	/// Block until the commit is written to the log, the log is fsynced and the commit is
+	/// applied to the tables. Use a blocking task to wait from async code.
+	pub fn wait(self) -> Result<()> {
+		self.db.wait_for_commit(self.commit_id, true)
+	}
+
+	/// Fence token for this commit and all commits before it.
+	pub fn fence(&self) -> FenceToken {
+		FenceToken { commit_id: self.commit_id }
+	}
+}
+
+/// Changes buffered by `Db::transaction`. Reads see the buffered changes before the database.
+/// Nothing is written until `commit` is called; dropping the transaction discards it.
+pub struct Transaction<'a> {
+	db: &'a Db,
+	changes: Vec<(ColId, Vec<u8>, Option<Value>)>,
+	// Position of the latest change of each key in `changes`.
+	latest: HashMap<(ColId, Vec<u8>), usize>,
+}
+
+impl<'a> Transaction<'a> {
+	pub fn set(&mut self, col: ColId, key: &[u8], value: Value) {
+		self.push(col, key, Some(value))
+	}
+
+	pub fn remove(&mut self, col: ColId, key: &[u8]) {
+		self.push(col, key, None)
+	}
+
+	fn push(&mut self, col: ColId, key: &[u8], value: Option<Value>) {
+		self.latest.insert((col, key.to_vec()), self.changes.len());
+		self.changes.push((col, key.to_vec(), value));
+	}
+
+	/// Get a value, taking buffered changes into account. For reference counted columns a
+	/// buffered removal hides the value even if other references remain.
+	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		match self.latest.get(&(col, key.to_vec())) {
+			Some(index) => Ok(self.changes[*index].2.clone()),
+			None => self.db.get(col, key),
+		}
+	}
+
+	/// Number of buffered changes.
+	pub fn len(&self) -> usize {
+		self.changes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.changes.is_empty()
+	}
+
+	/// Commit all buffered changes at once, in the order they were made.
+	pub fn commit(self) -> Result<()> {
+		self.db.commit(self.changes)
+	}
+}
+
+/// Value returned by `Db::get_ref`. The buffer holding it is reused for later reads once the
+/// guard is dropped.
+pub struct ValueGuard<'a> {
+	value: Vec<u8>,
+	pool: &'a Mutex<Vec<Vec<u8>>>,
+}
+
+impl std::ops::Deref for ValueGuard<'_> {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.value
+	}
+}
+
+impl AsRef<[u8]> for ValueGuard<'_> {
+	fn as_ref(&self) -> &[u8] {
+		&self.value
+	}
+}
+
+impl std::fmt::Debug for ValueGuard<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.value.fmt(f)
+	}
+}
+
+impl Drop for ValueGuard<'_> {
+	fn drop(&mut self) {
+		release_read_buffer(self.pool, std::mem::take(&mut self.value));
+	}
+}
+
+fn release_read_buffer(pool: &Mutex<Vec<Vec<u8>>>, mut buffer: Vec<u8>) {
+	if buffer.capacity() > MAX_READ_BUFFER_SIZE {
+		return
+	}
+	let mut pool = pool.lock();
+	if pool.len() < MAX_READ_BUFFERS {
+		buffer.clear();
+		pool.push(buffer);
+	}
+}
+
+/// Reader of a value returned by `Db::get_reader`. Parts of a large value are read from the
+/// tables as they are consumed.
+pub struct ValueReader<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	tier: u8,
+	// Index of the next part to read, 0 if there are no more.
+	next: u64,
+	buf: Vec<u8>,
+	pos: usize,
+	// `HashColumn::rewritten` before the first part was read.
+	rewritten: u64,
+}
+
+impl std::io::Read for ValueReader<'_> {
+	fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+		if !self
+			.fill_buf()
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?
+		{
+			return Ok(0)
+		}
+		let len = out.len().min(self.buf.len() - self.pos);
+		out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+		self.pos += len;
+		Ok(len)
+	}
+}
+
+impl ValueReader<'_> {
+	// Read the next part if the buffered one is consumed. Returns `false` at the end of the
+	// value.
+	fn fill_buf(&mut self) -> Result<bool> {
+		while self.pos == self.buf.len() {
+			if self.next == 0 {
+				return Ok(false)
+			}
+			self.buf.clear();
+			self.pos = 0;
+			self.next = self.db.read_part(self.col, self.tier, self.next, &mut self.buf)?;
+			self.check_rewritten()?;
+		}
+		Ok(true)
+	}
+
+	// Fail if the parts read may belong to another value, because the value was removed or
+	// replaced since its first part was read.
+	fn check_rewritten(&self) -> Result<()> {
+		if self.db.rewritten(self.col) != self.rewritten {
+			return Err(Error::InvalidInput("Value was changed while it was read".into()))
+		}
+		Ok(())
+	}
+
+	// Append up to `len` bytes to `value`.
+	fn read_to(&mut self, mut len: u64, value: &mut Vec<u8>) -> Result<()> {
+		while len > 0 && self.fill_buf()? {
+			let n = len.min((self.buf.len() - self.pos) as u64) as usize;
+			value.extend_from_slice(&self.buf[self.pos..self.pos + n]);
+			self.pos += n;
+			len -= n as u64;
+		}
+		Ok(())
+	}
+
+	// Skip `len` bytes. Parts that are skipped in full are not read.
+	fn skip(&mut self, mut len: u64) -> Result<()> {
+		loop {
+			let buffered = (self.buf.len() - self.pos) as u64;
+			if len <= buffered {
+				self.pos += len as usize;
+				return Ok(())
+			}
+			len -= buffered;
+			self.buf.clear();
+			self.pos = 0;
+			if self.next == 0 {
+				return Ok(())
+			}
+			match self.db.skip_part(self.col, self.tier, self.next)? {
+				Some((next, part_len)) if part_len as u64 <= len => {
+					len -= part_len as u64;
+					self.next = next;
+				},
+				_ =>
+					self.next = self.db.read_part(self.col, self.tier, self.next, &mut self.buf)?,
+			}
+			self.check_rewritten()?;
+		}
+	}
+}
+
+/// Marks a position in the commit sequence. See `Db::read_fence`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FenceToken {
+	commit_id: u64,
+}
+
 // Commit data passed to `commit`
 #[derive(Default)]
 struct Commit {
@@ -73,9 +511,142 @@ struct Commit {
 	bytes: usize,
 	// Operations.
 	changeset: CommitChangeSet,
+	// Time the commit was submitted, only set when timings, metrics or a coalescing window are
+	// enabled.
+	queued: Option<std::time::Instant>,
+	// Timings are requested by `commit_with_timings`.
+	timed: bool,
+	// Outcomes are requested by `commit_with_outcomes`.
+	outcomes: bool,
+	// Changes passed to commit hooks once the commit is enacted.
+	notification: Option<Arc<CommitNotification>>,
+}
+
+// Enacted log records that are not reported as enacted yet. See `Options::enact_batch_records`.
+#[derive(Default)]
+struct EnactBatch {
+	records: usize,
+	bytes: u64,
+	last_record_id: Option<u64>,
+}
+
+impl EnactBatch {
+	fn enabled(options: &Options) -> bool {
+		options.enact_batch_records.is_some() || options.enact_batch_bytes.is_some()
+	}
+
+	// Add an enacted record. Returns `true` and resets the batch if it is complete.
+	fn push(&mut self, record_id: u64, bytes: u64, options: &Options) -> bool {
+		self.records += 1;
+		self.bytes += bytes;
+		self.last_record_id = Some(record_id);
+		let complete = !Self::enabled(options) ||
+			options.enact_batch_records.is_some_and(|records| self.records >= records) ||
+			options.enact_batch_bytes.is_some_and(|bytes| self.bytes >= bytes);
+		if complete {
+			*self = Default::default();
+		}
+		complete
+	}
+
+	// Reset the batch, returning the last record in it.
+	fn take(&mut self) -> Option<u64> {
+		std::mem::take(self).last_record_id
+	}
+}
+
+// Value table writes of a log record to consecutive slots of a table, such as the parts of a
+// multipart value. These are applied with a single vectored write.
+#[derive(Default)]
+struct ValueWriteBatch {
+	pending: Option<(InsertValueAction, Vec<Vec<u8>>)>,
+}
+
+impl ValueWriteBatch {
+	// Add a write, applying the pending ones first if it does not continue them.
+	fn push(&mut self, columns: &[Column], record: InsertValueAction, data: Vec<u8>) -> Result<()> {
+		if let Some((start, parts)) = &mut self.pending {
+			let entry_size =
+				columns[start.table.col() as usize].value_entry_size(start.table.size_tier());
+			let last_full =
+				parts.last().is_some_and(|p| Some(p.len()) == entry_size.map(|s| s as usize));
+			if start.table == record.table &&
+				start.index + parts.len() as u64 == record.index &&
+				last_full && parts.len() < MAX_VALUE_WRITE_BATCH
+			{
+				parts.push(data);
+				return Ok(())
+			}
+		}
+		self.apply(columns)?;
+		self.pending = Some((record, vec![data]));
+		Ok(())
+	}
+
+	fn apply(&mut self, columns: &[Column]) -> Result<()> {
+		match self.pending.take() {
+			Some((start, parts)) =>
+				columns[start.table.col() as usize].apply_value_plans(&start, &parts),
+			None => Ok(()),
+		}
+	}
+}
+
+// Index chunks written since the last checkpoint. See `Options::checkpoint_interval`.
+#[derive(Default)]
+struct CheckpointState {
+	// Log bytes written since the last checkpoint.
+	logged_bytes: u64,
+	dirty: HashSet<(IndexTableId, u64)>,
+}
+
+// Background scheduling drawn from `Options::deterministic_seed`.
+struct Schedule {
+	rng: rand::rngs::StdRng,
+	// Logical time since the database was opened.
+	clock: Duration,
+}
+
+// Vacuuming of value tables. See `Db::vacuum` and `Options::auto_vacuum`.
+struct VacuumState {
+	// Time of the last check for fragmented tables.
+	last_check: Instant,
+	// Vacuums in progress, done in order.
+	jobs: VecDeque<VacuumJob>,
+	// Vacuum records and the tables they rewrote, by column and size tier. The free space at
+	// the end of the tables is released once the record is enacted.
+	pending: Vec<(u64, ColId, usize)>,
+}
+
+// Vacuum of a value table in progress.
+struct VacuumJob {
+	col: ColId,
+	tier: usize,
+	// The table is left as it is unless at least this fraction of its allocated entries is
+	// removed, which is only known once the list of removed entries is read.
+	threshold: f64,
+	vacuum: FreeListVacuum,
+}
+
+// Commit changes grouped by column, as passed to commit hooks.
+type CommitNotification = BTreeMap<ColId, Vec<(Vec<u8>, Option<Value>)>>;
+
+/// Commit stage reported to commit hooks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitStage {
+	/// The commit was added to the commit queue. The changes are visible to readers.
+	Queued,
+	/// The commit was written to the table files.
+	Enacted,
 }
 
-// Pending commits. This may not grow beyond `MAX_COMMIT_QUEUE_BYTES` bytes.
+/// Callback registered with `Db::on_commit`. Called once per column changed by the commit.
+pub type CommitHook = Box<dyn Fn(CommitStage, ColId, &[(Vec<u8>, Option<Value>)]) + Send + Sync>;
+
+// File regions as `(offset, len)`, by file name.
+type DirtyRegions = HashMap<String, BTreeSet<(u64, u64)>>;
+
+// Pending commits. This may not grow beyond `DbInner::commit_queue_limit` bytes.
 #[derive(Default)]
 struct CommitQueue {
 	// Log record.
@@ -93,21 +664,98 @@ struct DbInner {
 	log: Log,
 	commit_queue: Mutex<CommitQueue>,
 	commit_queue_full_cv: Condvar,
+	// Queue sizes that block commits and the log worker, see `Options::max_overlay_bytes`.
+	commit_queue_limit: usize,
+	log_queue_limit: i64,
 	log_worker_wait: WaitCondvar<bool>,
 	commit_worker_wait: Arc<WaitCondvar<bool>>,
 	// Overlay of most recent values in the commit queue.
 	commit_overlay: RwLock<Vec<CommitOverlay>>,
+	// Per column cache of values read from the tables. Entries are removed when a commit
+	// leaves the commit overlay. Readers hold the commit overlay lock while filling the cache,
+	// so they can't cache a value that was just replaced.
+	value_cache: Vec<Option<ValueCache>>,
+	// Index chunk cache shared by all hash columns.
+	index_cache: Option<Arc<ChunkCache>>,
 	log_queue_wait: WaitCondvar<i64>, /* This may underflow occasionally, but is bound for 0
 	                                   * eventually */
 	flush_worker_wait: Arc<WaitCondvar<bool>>,
 	cleanup_worker_wait: WaitCondvar<bool>,
+	sync_worker_wait: WaitCondvar<bool>,
 	last_enacted: AtomicU64,
 	next_reindex: AtomicU64,
+	// Earliest time for the next reindex record when reindexing is throttled.
+	reindex_resume: Mutex<Option<Instant>>,
+	// Last periodic log sync, see `SyncMode::Periodic`.
+	last_sync: Mutex<Instant>,
+	// Last periodic table flush, see `Options::sync_interval`.
+	last_data_sync: Mutex<Instant>,
+	pause: WaitCondvar<PauseState>,
+	// Last commit written to the log and the log record that contains it.
+	last_logged_commit: AtomicU64,
+	last_logged_record: AtomicU64,
+	// Signalled when a commit is logged or a log record is enacted.
+	progress_wait: WaitCondvar<()>,
+	commit_hooks: RwLock<Vec<CommitHook>>,
+	// Logged commits waiting to be reported as enacted, with their log record.
+	pending_notifications: Mutex<VecDeque<(u64, Arc<CommitNotification>)>>,
+	// Queueing and log append time and the log record of commits that requested timings.
+	commit_timings: Mutex<HashMap<u64, (Duration, Duration, u64)>>,
+	// Outcomes of commits made with `commit_with_outcomes`, by commit id, in change order for
+	// each column.
+	commit_outcomes: Mutex<HashMap<u64, HashMap<ColId, Vec<CommitOutcome>>>>,
+	// Held while enacting a log record. Keeps table files consistent for `checkpoint`.
+	enact_lock: Mutex<()>,
+	// Held while writing a log record. Records are written by the log worker, and by
+	// `put_stream` and `apply_log_record` on the calling thread.
+	log_writer: Mutex<()>,
+	enact_batch: Mutex<EnactBatch>,
+	checkpoint: Mutex<CheckpointState>,
+	vacuum: Mutex<VacuumState>,
+	// Scheduling of background work with `Options::deterministic_seed`.
+	schedule: Option<Mutex<Schedule>>,
+	// Start of logical time.
+	opened: Instant,
+	// Trace of the log records, see `Options::commit_trace`. Written while holding `log_writer`,
+	// so that events are in log order.
+	trace: Option<TraceWriter>,
+	// Last record covered by a checkpoint restored on replay.
+	replay_checkpoint: u64,
+	// Record of the backup and last record to replay for `Db::restore_to`.
+	restore: Option<(u64, u64)>,
+	// Table file regions written since a relocation started.
+	relocation: Mutex<Option<DirtyRegions>>,
+	// New location of the database once relocated. Commits are rejected from then on.
+	relocated: RwLock<Option<std::path::PathBuf>>,
 	bg_err: Mutex<Option<Arc<Error>>>,
+	// Time of the last attempt to enact a record that failed because the disk is full, while
+	// enactment is paused with `OnDiskFull::Retry`.
+	disk_full: Mutex<Option<Instant>>,
+	// Damaged entries read as missing with `OnCorruption::Quarantine`, by column and key as
+	// stored in the column.
+	quarantined: RwLock<HashSet<(ColId, Vec<u8>)>>,
+	// Damaged entries recorded in the corruption journal since the database was opened, keyed as
+	// `quarantined`. Repeated reads of an entry are only recorded once.
+	journaled: Mutex<HashSet<(ColId, Vec<u8>)>>,
+	// Buffers for `Db::get_ref`.
+	read_buffers: Mutex<Vec<Vec<u8>>>,
+	// Application metadata of columns, as stored in the metadata file.
+	column_metadata: RwLock<BTreeMap<ColId, Vec<u8>>>,
+	info: DbInfo,
+	replay_report: ReplayReport,
 	db_version: u32,
 	_lock_file: std::fs::File,
 }
 
+// Workers stopped with `Db::pause_background`.
+#[derive(Default)]
+struct PauseState {
+	// Workers that stop at the start of their next round.
+	paused: HashSet<Worker>,
+	// Workers that are stopped.
+	parked: HashSet<Worker>,
+}
+
 struct WaitCondvar<S> {
 	cv: Condvar,
 	work: Mutex<S>,
@@ -133,39 +781,124 @@ impl WaitCondvar<bool> {
 		}
 		*work = false;
 	}
+
+	// Wait for a signal or until `timeout` passes.
+	fn wait_for(&self, timeout: Duration) {
+		let mut work = self.work.lock();
+		if !*work {
+			self.cv.wait_for(&mut work, timeout);
+		}
+		*work = false;
+	}
 }
 
 impl DbInner {
+	// Open all columns, spreading them over `preopen_threads` threads if set. Each thread takes
+	// the next unopened column until all are done.
+	fn open_columns(
+		options: &Options,
+		metadata: &Metadata,
+		index_cache: &Option<Arc<ChunkCache>>,
+	) -> Result<Vec<Column>> {
+		let num_columns = metadata.columns.len();
+		let open = |c: usize| Column::open(c as ColId, options, metadata, index_cache.clone());
+		let threads = options.preopen_threads.unwrap_or(1).min(num_columns);
+		if threads <= 1 {
+			return (0..num_columns).map(open).collect()
+		}
+		let next = AtomicUsize::new(0);
+		let mut opened: Vec<(usize, Result<Column>)> = std::thread::scope(|scope| {
+			let handles: Vec<_> = (0..threads)
+				.map(|_| {
+					scope.spawn(|| {
+						let mut opened = Vec::new();
+						loop {
+							let c = next.fetch_add(1, Ordering::Relaxed);
+							if c >= num_columns {
+								break
+							}
+							opened.push((c, open(c)));
+						}
+						opened
+					})
+				})
+				.collect();
+			handles
+				.into_iter()
+				.flat_map(|handle| handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+				.collect()
+		});
+		opened.sort_by_key(|(c, _)| *c);
+		log::debug!(target: "parity-db", "Opened {} columns on {} threads", num_columns, threads);
+		opened.into_iter().map(|(_, column)| column).collect()
+	}
+
 	fn open(options: &Options, inner_options: &InternalOptions) -> Result<DbInner> {
 		if inner_options.create {
-			std::fs::create_dir_all(&options.path)?
-		};
-		let mut lock_path: std::path::PathBuf = options.path.clone();
-		lock_path.push("lock");
-		let lock_file = std::fs::OpenOptions::new()
-			.create(true)
-			.read(true)
-			.write(true)
-			.open(lock_path.as_path())?;
-		if !inner_options.skip_check_lock {
-			lock_file.try_lock_exclusive().map_err(Error::Locked)?;
-		}
-
-		let metadata = options.load_and_validate_metadata(inner_options.create)?;
-		let mut columns = Vec::with_capacity(metadata.columns.len());
-		let mut commit_overlay = Vec::with_capacity(metadata.columns.len());
-		let log = Log::open(options)?;
-		let last_enacted = log.replay_record_id().unwrap_or(2) - 1;
-		for c in 0..metadata.columns.len() {
-			let column = Column::open(c as ColId, options, &metadata)?;
-			commit_overlay.push(CommitOverlay::new());
-			columns.push(column);
+			std::fs::create_dir_all(&options.path)?;
+			for c in options.columns.iter() {
+				if let Some(path) = &c.path_override {
+					std::fs::create_dir_all(path)?;
+				}
+			}
+		};
+		for (c, column) in options.columns.iter().enumerate() {
+			if column.path_override.as_ref().is_some_and(|path| !path.is_dir()) {
+				return Err(Error::InvalidConfiguration(format!(
+					"Path of column {} does not exist: {}",
+					c,
+					options.column_path(c as ColId).display()
+				)))
+			}
+		}
+		let lock_file =
+			crate::lock::lock(&options.path, !inner_options.skip_check_lock, options.force_unlock)?;
+
+		let mut metadata = options.load_and_validate_metadata(inner_options.create)?;
+		if metadata.id.is_none() && !inner_options.read_only {
+			metadata.id = Some(crate::options::new_database_id());
+			metadata.write_database_entries(&options.path)?;
+		}
+		let commit_overlay = (0..metadata.columns.len()).map(|_| CommitOverlay::new()).collect();
+		let log = Log::open(options, metadata.version)?;
+		// Record ids continue after the archived records, so that they identify a record in
+		// the change stream across restarts.
+		let last_enacted = match log.replay_record_id() {
+			Some(record_id) => record_id - 1,
+			None => log.archive().map_or(1, |a| a.last_record_id().max(1)),
+		};
+		let index_cache = (options.index_cache_size > 0)
+			.then(|| Arc::new(ChunkCache::new(options.index_cache_size)));
+		let columns = Self::open_columns(options, &metadata, &index_cache)?;
+		for column in columns.iter() {
+			column.open_bloom(inner_options.read_only)?;
 		}
 		log::debug!(target: "parity-db", "Opened db {:?}, metadata={:?}", options, metadata);
+		let value_cache = options
+			.columns
+			.iter()
+			.map(|c| (c.cache_size > 0).then(|| ValueCache::new(c.cache_size)))
+			.collect();
 		let mut options = options.clone();
 		if options.salt.is_none() {
 			options.salt = Some(metadata.salt);
 		}
+		let trace = match &options.commit_trace {
+			Some(path) if !inner_options.read_only =>
+				Some(TraceWriter::open(path, &options.salt.unwrap_or_default())?),
+			_ => None,
+		};
+		let schedule = options.deterministic_seed.map(|seed| {
+			Mutex::new(Schedule {
+				rng: rand::SeedableRng::seed_from_u64(seed),
+				clock: Duration::ZERO,
+			})
+		});
+		let opened = Instant::now();
+		let (commit_queue_limit, log_queue_limit) = match options.max_overlay_bytes {
+			Some(max) => ((max / 9) as usize, (max - max / 9) as i64),
+			None => (MAX_COMMIT_QUEUE_BYTES, MAX_LOG_QUEUE_BYTES),
+		};
 
 		Ok(DbInner {
 			columns,
@@ -174,46 +907,321 @@ impl DbInner {
 			log,
 			commit_queue: Mutex::new(Default::default()),
 			commit_queue_full_cv: Condvar::new(),
+			commit_queue_limit,
+			log_queue_limit,
 			log_worker_wait: WaitCondvar::new(),
 			commit_worker_wait: Arc::new(WaitCondvar::new()),
 			commit_overlay: RwLock::new(commit_overlay),
+			value_cache,
+			index_cache,
 			log_queue_wait: WaitCondvar::new(),
 			flush_worker_wait: Arc::new(WaitCondvar::new()),
 			cleanup_worker_wait: WaitCondvar::new(),
+			sync_worker_wait: WaitCondvar::new(),
 			next_reindex: AtomicU64::new(1),
+			reindex_resume: Mutex::new(None),
+			last_sync: Mutex::new(opened),
+			last_data_sync: Mutex::new(opened),
+			pause: WaitCondvar::new(),
 			last_enacted: AtomicU64::new(last_enacted),
+			last_logged_commit: AtomicU64::new(0),
+			last_logged_record: AtomicU64::new(0),
+			progress_wait: WaitCondvar::new(),
+			commit_timings: Mutex::new(HashMap::new()),
+			commit_outcomes: Mutex::new(HashMap::new()),
+			commit_hooks: RwLock::new(Vec::new()),
+			pending_notifications: Mutex::new(VecDeque::new()),
+			enact_lock: Mutex::new(()),
+			log_writer: Mutex::new(()),
+			enact_batch: Mutex::new(Default::default()),
+			checkpoint: Mutex::new(Default::default()),
+			vacuum: Mutex::new(VacuumState {
+				last_check: opened,
+				jobs: VecDeque::new(),
+				pending: Vec::new(),
+			}),
+			schedule,
+			opened,
+			trace,
+			replay_checkpoint: 0,
+			restore: inner_options.restore,
+			relocation: Mutex::new(None),
+			relocated: RwLock::new(None),
 			bg_err: Mutex::new(None),
+			disk_full: Mutex::new(None),
+			quarantined: Default::default(),
+			journaled: Default::default(),
+			read_buffers: Mutex::new(Vec::new()),
+			column_metadata: RwLock::new(metadata.column_metadata),
+			info: DbInfo { id: metadata.id, created: metadata.created, version: metadata.version },
+			replay_report: Default::default(),
 			db_version: metadata.version,
 			_lock_file: lock_file,
 		})
 	}
 
 	fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		let mut value = Vec::new();
+		Ok(self.get_into(col, key, &mut value)?.then_some(value))
+	}
+
+	// Read the value of `key` into `value`, which should be empty. Returns `false` if there is
+	// no such key.
+	fn get_into(&self, col: ColId, key: &[u8], value: &mut Vec<u8>) -> Result<bool> {
+		if !self.get_stored_into(col, key, value)? {
+			return Ok(false)
+		}
+		if !self.options.columns[col as usize].store_keys {
+			return Ok(true)
+		}
+		let (stored_key, stored_value) = decode_keyed_value(value)?;
+		if stored_key != key {
+			value.clear();
+			return Ok(false)
+		}
+		let start = value.len() - stored_value.len();
+		value.drain(..start);
+		Ok(true)
+	}
+
+	// Apply `Options::on_corruption` to `read` of `key`.
+	fn read_checked<T>(
+		&self,
+		col: ColId,
+		key: &[u8],
+		read: impl FnOnce() -> Result<Option<T>>,
+	) -> Result<Option<T>> {
+		if self.options.on_corruption == OnCorruption::Fail {
+			return read()
+		}
+		let stored_key = || match &self.columns[col as usize] {
+			Column::Hash(column) => column.hash_key(key).to_vec(),
+			Column::Tree(_) => key.to_vec(),
+		};
+		{
+			let quarantined = self.quarantined.read();
+			if !quarantined.is_empty() && quarantined.contains(&(col, stored_key())) {
+				return Ok(None)
+			}
+		}
+		match read() {
+			Err(e @ (Error::Corruption(_) | Error::Compression | Error::InvalidValueData)) => {
+				let stored_key = stored_key();
+				if self.journaled.lock().insert((col, stored_key.clone())) {
+					self.journal_corruption(col, key, &e)?;
+				}
+				if self.options.on_corruption == OnCorruption::Quarantine {
+					self.quarantined.write().insert((col, stored_key));
+				}
+				Ok(None)
+			},
+			result => result,
+		}
+	}
+
+	// Record a damaged entry in the corruption journal. This runs on reads, so the journal is not
+	// synced.
+	fn journal_corruption(&self, col: ColId, key: &[u8], error: &Error) -> Result<()> {
+		let action = match self.options.on_corruption {
+			OnCorruption::Quarantine => "quarantine",
+			_ => "skip",
+		};
+		let line =
+			format!("col={} key={} action={} error={}", col, hex::encode(key), action, error);
+		log::warn!(target: "parity-db", "Corrupted entry: {}", line);
+		let mut path = self.options.path.clone();
+		path.push(CORRUPTION_JOURNAL);
+		let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+		std::io::Write::write_all(&mut file, format!("{}\n", line).as_bytes())?;
+		Ok(())
+	}
+
+	fn get_reader(&self, col: ColId, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+		let reader = |value, tier, next, rewritten| ValueReader {
+			db: self,
+			col,
+			tier,
+			next,
+			buf: value,
+			pos: 0,
+			rewritten,
+		};
+		let mut value = Vec::new();
+		let column = match &self.columns[col as usize] {
+			Column::Hash(column) if !self.options.columns[col as usize].store_keys => column,
+			_ => return Ok(self.get_into(col, key, &mut value)?.then(|| reader(value, 0, 0, 0))),
+		};
+		let key = column.hash_key(key);
+		let overlay = self.commit_overlay.read();
+		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get_ref(&key)) {
+			return Ok(v.map(|v| reader(v.clone(), 0, 0, 0)))
+		}
+		if let Some(cache) = &self.value_cache[col as usize] {
+			let hit = cache.get_into(&key, &mut value);
+			if let Some(metrics) = &self.options.metrics {
+				metrics.cache_lookup(col, hit);
+			}
+			if hit {
+				return Ok(Some(reader(value, 0, 0, 0)))
+			}
+		}
+		let rewritten = column.rewritten();
+		let found = column.get_first_part(&key, self.log.overlays(), &mut value)?;
+		Ok(found.map(|(tier, next)| reader(value, tier, next, rewritten)))
+	}
+
+	fn read_part(&self, col: ColId, tier: u8, index: u64, value: &mut Vec<u8>) -> Result<u64> {
+		match &self.columns[col as usize] {
+			Column::Hash(column) => column.read_part(tier, index, self.log.overlays(), value),
+			Column::Tree(_) => Err(Error::InvalidInput("Not supported for btree columns".into())),
+		}
+	}
+
+	fn rewritten(&self, col: ColId) -> u64 {
+		match &self.columns[col as usize] {
+			Column::Hash(column) => column.rewritten(),
+			Column::Tree(_) => 0,
+		}
+	}
+
+	fn skip_part(&self, col: ColId, tier: u8, index: u64) -> Result<Option<(u64, usize)>> {
+		match &self.columns[col as usize] {
+			Column::Hash(column) => column.skip_part(tier, index, self.log.overlays()),
+			Column::Tree(_) => Err(Error::InvalidInput("Not supported for btree columns".into())),
+		}
+	}
+
+	fn get_range(&self, col: ColId, key: &[u8], offset: u64, len: u64) -> Result<Option<Value>> {
+		let mut reader = match self.get_reader(col, key)? {
+			Some(reader) => reader,
+			None => return Ok(None),
+		};
+		reader.skip(offset)?;
+		let mut value = Vec::new();
+		reader.read_to(len, &mut value)?;
+		Ok(Some(value))
+	}
+
+	// Get the value as written to the tables.
+	fn get_stored(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		let mut value = Vec::new();
+		Ok(self.get_stored_into(col, key, &mut value)?.then_some(value))
+	}
+
+	// Same as `get` for each of `keys`. Values of hash columns that are not in memory are read
+	// with `HashColumn::get_many`.
+	fn get_many<K: AsRef<[u8]>>(&self, col: ColId, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		let column = match &self.columns[col as usize] {
+			Column::Hash(column) if !self.options.columns[col as usize].store_keys => column,
+			_ => return keys.iter().map(|key| self.get(col, key.as_ref())).collect(),
+		};
+		let mut result = vec![None; keys.len()];
+		let mut pending = Vec::new();
+		let mut hashed = Vec::new();
+		let overlay = self.commit_overlay.read();
+		for (i, key) in keys.iter().enumerate() {
+			let key = column.hash_key(key.as_ref());
+			if let Some(v) = overlay.get(col as usize).and_then(|o| o.get_ref(&key)) {
+				result[i] = v.cloned();
+				continue
+			}
+			if let Some(cache) = &self.value_cache[col as usize] {
+				let mut value = Vec::new();
+				let hit = cache.get_into(&key, &mut value);
+				if let Some(metrics) = &self.options.metrics {
+					metrics.cache_lookup(col, hit);
+				}
+				if hit {
+					result[i] = Some(value);
+					continue
+				}
+			}
+			pending.push(i);
+			hashed.push(key);
+		}
+		let found = column.get_many(&hashed, self.log.overlays())?;
+		for ((i, key), value) in pending.into_iter().zip(hashed).zip(found) {
+			if let (Some(cache), Some(value)) = (&self.value_cache[col as usize], &value) {
+				cache.insert(&key, value);
+			}
+			result[i] = value;
+		}
+		Ok(result)
+	}
+
+	// Same as `get_stored`, reading into `value`, which should be empty.
+	fn get_stored_into(&self, col: ColId, key: &[u8], value: &mut Vec<u8>) -> Result<bool> {
 		match &self.columns[col as usize] {
 			Column::Hash(column) => {
 				let key = column.hash_key(key);
 				let overlay = self.commit_overlay.read();
 				// Check commit overlay first
-				if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key)) {
-					return Ok(v)
+				if let Some(v) = overlay.get(col as usize).and_then(|o| o.get_ref(&key)) {
+					value.extend_from_slice(v.map_or(&[], |v| v.as_slice()));
+					return Ok(v.is_some())
 				}
-				// Go into tables and log overlay.
-				let log = self.log.overlays();
-				column.get(&key, log)
+				self.cached_get(col, &key, value, |value| {
+					// Go into tables and log overlay.
+					let log = self.log.overlays();
+					column.get_into(&key, log, value)
+				})
 			},
 			Column::Tree(column) => {
 				let overlay = self.commit_overlay.read();
 				if let Some(l) = overlay.get(col as usize).and_then(|o| o.btree_get(key)) {
-					return Ok(l.cloned())
+					value.extend_from_slice(l.map_or(&[], |v| v.as_slice()));
+					return Ok(l.is_some())
 				}
-				// We lock log, if btree structure changed while reading that would be an issue.
-				let log = self.log.overlays().read();
-				column.with_locked(|btree| BTreeTable::get(key, &*log, btree))
+				self.cached_get(col, key, value, |value| {
+					// We lock log, if btree structure changed while reading that would be an
+					// issue.
+					let log = self.log.overlays().read();
+					match column.with_locked(|btree| BTreeTable::get(key, &*log, btree))? {
+						Some(v) => {
+							*value = v;
+							Ok(true)
+						},
+						None => Ok(false),
+					}
+				})
 			},
 		}
 	}
 
+	// Read a value through the column value cache. Must be called with the commit overlay
+	// locked.
+	fn cached_get(
+		&self,
+		col: ColId,
+		key: &[u8],
+		value: &mut Vec<u8>,
+		read: impl FnOnce(&mut Vec<u8>) -> Result<bool>,
+	) -> Result<bool> {
+		let cache = match &self.value_cache[col as usize] {
+			Some(cache) => cache,
+			None => return read(value),
+		};
+		let hit = cache.get_into(key, value);
+		if let Some(metrics) = &self.options.metrics {
+			metrics.cache_lookup(col, hit);
+		}
+		if hit {
+			return Ok(true)
+		}
+		if read(value)? {
+			cache.insert(key, value);
+			return Ok(true)
+		}
+		Ok(false)
+	}
+
 	fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
+		if self.options.columns[col as usize].store_keys {
+			return Ok(self.get(col, key)?.map(|v| v.len() as u32))
+		}
+		if self.value_cache[col as usize].is_some() {
+			return Ok(self.get_stored(col, key)?.map(|v| v.len() as u32))
+		}
 		match &self.columns[col as usize] {
 			Column::Hash(column) => {
 				let key = column.hash_key(key);
@@ -238,7 +1246,7 @@ impl DbInner {
 		}
 	}
 
-	pub fn btree_iter(&self, col: ColId) -> Result<BTreeIterator> {
+	pub fn btree_iter(&self, col: ColId) -> Result<BTreeIterator<'_>> {
 		match &self.columns[col as usize] {
 			Column::Hash(_column) =>
 				Err(Error::InvalidConfiguration("Not an indexed column.".to_string())),
@@ -251,36 +1259,235 @@ impl DbInner {
 
 	// Commit simply adds the data to the queue and to the overlay and
 	// exits as early as possible.
-	fn commit<I, K>(&self, tx: I) -> Result<()>
+	// Queue the commit and return its id.
+	fn commit<I, K>(&self, tx: I) -> Result<u64>
 	where
 		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
 		K: AsRef<[u8]>,
 	{
-		let mut commit: CommitChangeSet = Default::default();
-		for (c, k, v) in tx.into_iter() {
-			if self.options.columns[c as usize].btree_index {
-				commit
-					.btree_indexed
-					.entry(c)
-					.or_insert_with(|| BTreeChangeSet::new(c))
-					.push(k.as_ref(), v)
-			} else {
-				commit.indexed.entry(c).or_insert_with(|| IndexedChangeSet::new(c)).push(
-					k.as_ref(),
-					v,
-					&self.options,
-					self.db_version,
-				)
-			}
-		}
-
-		self.commit_raw(commit)
+		let (changeset, notification) = self.changeset(tx);
+		self.queue_commit(changeset, false, false, notification)
 	}
 
-	fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
-		{
-			let mut queue = self.commit_queue.lock();
-			if queue.bytes > MAX_COMMIT_QUEUE_BYTES {
+	fn commit_with_timings<I, K>(&self, tx: I) -> Result<CommitTimings>
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let start = Instant::now();
+		let (changeset, notification) = self.changeset(tx);
+		let planning = start.elapsed();
+		let commit_id = self.queue_commit(changeset, true, false, notification)?;
+		// Always take the entry, so that it is not left behind if the commit failed.
+		let logged = self.wait_logged(commit_id);
+		let timings = self.commit_timings.lock().remove(&commit_id);
+		logged?;
+		let (queueing, log_append, record_id) = timings.unwrap_or_default();
+		let start = Instant::now();
+		self.flush_logs(0)?;
+		let log_flush = start.elapsed();
+		let start = Instant::now();
+		self.flush_logs(0)?;
+		self.wait_enacted(record_id)?;
+		let enactment = start.elapsed();
+		Ok(CommitTimings { planning, queueing, log_append, log_flush, enactment })
+	}
+
+	fn commit_operations<I, K>(&self, tx: I) -> Result<Vec<CommitOutcome>>
+	where
+		I: IntoIterator<Item = (ColId, Operation<K, Value>)>,
+		K: AsRef<[u8]>,
+	{
+		// Position of each change in the change set of its column.
+		let mut order = Vec::new();
+		let mut counts: HashMap<ColId, usize> = HashMap::new();
+		let mut unsupported = None;
+		let tx = tx.into_iter().inspect(|(c, op)| {
+			let count = counts.entry(*c).or_default();
+			order.push((*c, *count));
+			*count += 1;
+			let options = &self.options.columns[*c as usize];
+			let supported = match op {
+				Operation::Append(..) =>
+					!(options.btree_index ||
+						options.ref_counted ||
+						options.preimage || options.store_keys ||
+						options.counters),
+				Operation::Increment(..) => options.counters,
+				_ => true,
+			};
+			if !supported {
+				unsupported = Some(*c);
+			}
+		});
+		let (changeset, notification) = self.operations_changeset(tx);
+		if let Some(c) = unsupported {
+			return Err(Error::InvalidInput(format!("Operation is not supported in column {}", c)))
+		}
+		let commit_id = self.queue_commit(changeset, false, true, notification)?;
+		self.wait_logged(commit_id)?;
+		let outcomes = self.commit_outcomes.lock().remove(&commit_id).unwrap_or_default();
+		Ok(order.into_iter().map(|(c, i)| outcomes[&c][i]).collect())
+	}
+
+	fn changeset<I, K>(&self, tx: I) -> (CommitChangeSet, Option<Arc<CommitNotification>>)
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.operations_changeset(tx.into_iter().map(|(c, k, v)| match v {
+			Some(v) => (c, Operation::Set(k, v)),
+			None => (c, Operation::Dereference(k)),
+		}))
+	}
+
+	// Build the change set. Also collects the changes for commit hooks if there are any.
+	fn operations_changeset<I, K>(
+		&self,
+		tx: I,
+	) -> (CommitChangeSet, Option<Arc<CommitNotification>>)
+	where
+		I: IntoIterator<Item = (ColId, Operation<K, Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let mut commit: CommitChangeSet = Default::default();
+		let mut notification = if self.commit_hooks.read().is_empty() {
+			None
+		} else {
+			Some(CommitNotification::new())
+		};
+		for (c, op) in tx.into_iter() {
+			let if_absent = matches!(op, Operation::InsertIfAbsent(..));
+			let append = matches!(op, Operation::Append(..));
+			let increment = matches!(op, Operation::Increment(..));
+			let (k, v) = match op {
+				Operation::Set(k, v) |
+				Operation::InsertIfAbsent(k, v) |
+				Operation::Append(k, v) => (k, Some(v)),
+				Operation::Dereference(k) => (k, None),
+				Operation::Increment(k, delta) => (k, Some(delta.to_le_bytes().to_vec())),
+			};
+			if let Some(notification) = &mut notification {
+				notification.entry(c).or_default().push((k.as_ref().to_vec(), v.clone()));
+			}
+			if self.options.columns[c as usize].btree_index {
+				let changes =
+					commit.btree_indexed.entry(c).or_insert_with(|| BTreeChangeSet::new(c));
+				if if_absent {
+					changes.if_absent.insert(changes.changes.len());
+				}
+				if self.options.columns[c as usize].multimap {
+					match v {
+						Some(v) => changes.push(&multimap_key(k.as_ref(), &v), Some(Vec::new())),
+						None => {
+							changes.remove_prefix.insert(changes.changes.len());
+							changes.push(&multimap_prefix(k.as_ref()), None)
+						},
+					}
+				} else {
+					changes.push(k.as_ref(), v)
+				}
+			} else {
+				let changes = commit.indexed.entry(c).or_insert_with(|| IndexedChangeSet::new(c));
+				if if_absent {
+					changes.if_absent.insert(changes.changes.len());
+				}
+				if append {
+					changes.appends.insert(changes.changes.len());
+				}
+				if increment {
+					changes.increments.insert(changes.changes.len());
+				}
+				changes.push(k.as_ref(), v, &self.options, self.db_version)
+			}
+		}
+		(commit, notification.map(Arc::new))
+	}
+
+	fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
+		let notification = if self.commit_hooks.read().is_empty() {
+			None
+		} else {
+			let mut notification = CommitNotification::new();
+			for (c, indexed) in &commit.indexed {
+				notification
+					.entry(*c)
+					.or_default()
+					.extend(indexed.changes.iter().map(|(k, v)| (k.to_vec(), v.clone())));
+			}
+			for (c, btree) in &commit.btree_indexed {
+				notification.entry(*c).or_default().extend(btree.changes.iter().cloned());
+			}
+			Some(Arc::new(notification))
+		};
+		self.queue_commit(commit, false, false, notification).map(|_| ())
+	}
+
+	// Size of the index and value table files.
+	fn total_size(&self) -> u64 {
+		self.columns.iter().map(|c| c.file_bytes()).sum()
+	}
+
+	// Enforce `Options::max_total_size`. Commits that only remove values get
+	// `Options::total_size_grace` bytes on top of the limit.
+	fn check_total_size(&self, commit: &CommitChangeSet) -> Result<()> {
+		let limit = match self.options.max_total_size {
+			Some(limit) => limit,
+			None => return Ok(()),
+		};
+		let size = self.total_size();
+		if size <= limit {
+			return Ok(())
+		}
+		let removals_only =
+			commit.indexed.values().all(|c| c.changes.iter().all(|(_, v)| v.is_none())) &&
+				commit
+					.btree_indexed
+					.values()
+					.all(|c| c.changes.iter().all(|(_, v)| v.is_none()));
+		if removals_only && size <= limit.saturating_add(self.options.total_size_grace) {
+			return Ok(())
+		}
+		log::debug!(target: "parity-db", "Rejected commit, tables take {} bytes", size);
+		Err(Error::QuotaExceeded)
+	}
+
+	// Add the commit to the queue and return its id.
+	fn queue_commit(
+		&self,
+		mut commit: CommitChangeSet,
+		timed: bool,
+		outcomes: bool,
+		notification: Option<Arc<CommitNotification>>,
+	) -> Result<u64> {
+		let queued = if timed ||
+			self.options.metrics.is_some() ||
+			self.options.coalesce_commit_window.is_some()
+		{
+			Some(Instant::now())
+		} else {
+			None
+		};
+		for (c, indexed) in &commit.indexed {
+			if self.options.columns[*c as usize].counters &&
+				indexed.changes.iter().any(|(_, v)| v.as_ref().is_some_and(|v| v.len() != 8))
+			{
+				return Err(Error::InvalidInput(format!(
+					"Counter values of column {} must be 8 bytes",
+					c
+				)))
+			}
+		}
+		self.check_total_size(&commit)?;
+		if let Some(max) = self.options.max_overlay_bytes {
+			if self.manual_workers() && !self.is_paused() {
+				// No worker threads to make room, do the work here.
+				self.tick_until(|| self.overlay_bytes() <= max)?;
+			}
+		}
+		let record_id = {
+			let mut queue = self.commit_queue.lock();
+			if queue.bytes > self.commit_queue_limit && !self.manual_workers() {
 				log::debug!(target: "parity-db", "Waiting, queue size={}", queue.bytes);
 				self.commit_queue_full_cv.wait(&mut queue);
 			}
@@ -290,6 +1497,14 @@ impl DbInner {
 					return Err(Error::Background(err.clone()))
 				}
 			}
+			if let Some(path) = &*self.relocated.read() {
+				return Err(Error::InvalidConfiguration(format!(
+					"Database was relocated to {}",
+					path.display()
+				)))
+			}
+			// Done while holding the queue, so that no other commit is queued in between.
+			self.expand_multimap_removals(&mut commit)?;
 
 			let mut overlay = self.commit_overlay.write();
 
@@ -315,7 +1530,36 @@ impl DbInner {
 				);
 			}
 
-			let commit = Commit { id: record_id, changeset: commit, bytes };
+			{
+				// Written keys are no longer damaged.
+				let mut quarantined = self.quarantined.write();
+				let mut journaled = self.journaled.lock();
+				for damaged in [&mut *quarantined, &mut *journaled] {
+					if damaged.is_empty() {
+						continue
+					}
+					for (c, indexed) in &commit.indexed {
+						for (key, _) in &indexed.changes {
+							damaged.remove(&(*c, key.to_vec()));
+						}
+					}
+					for (c, btree) in &commit.btree_indexed {
+						for (key, _) in &btree.changes {
+							damaged.remove(&(*c, key.clone()));
+						}
+					}
+				}
+			}
+
+			let commit = Commit {
+				id: record_id,
+				changeset: commit,
+				bytes,
+				queued,
+				timed,
+				outcomes,
+				notification: notification.clone(),
+			};
 
 			log::debug!(
 				target: "parity-db",
@@ -326,31 +1570,250 @@ impl DbInner {
 			queue.commits.push_back(commit);
 			queue.bytes += bytes;
 			self.log_worker_wait.signal();
+			record_id
+		};
+		if let Some(notification) = notification {
+			self.notify(CommitStage::Queued, &notification);
+		}
+		Ok(record_id)
+	}
+
+	// Write a value read from `reader` in a log record of its own, after all queued commits.
+	fn put_stream(
+		&self,
+		col: ColId,
+		key: &[u8],
+		reader: &mut dyn std::io::Read,
+		len: u64,
+	) -> Result<()> {
+		let column = match self.columns.get(col as usize) {
+			Some(Column::Hash(column)) => column,
+			Some(Column::Tree(_)) =>
+				return Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => return Err(Error::InvalidInput(format!("No such column {}", col))),
+		};
+		let prefix = if self.options.columns[col as usize].store_keys {
+			encode_keyed_value(key, &[])
+		} else {
+			Vec::new()
+		};
+		let len = usize::try_from(len)
+			.ok()
+			.and_then(|len| len.checked_add(prefix.len()))
+			.filter(|len| *len <= u32::MAX as usize)
+			.ok_or_else(|| Error::InvalidInput(format!("Value is too large: {} bytes", len)))?;
+		self.check_bg_err()?;
+		if self.options.max_total_size.is_some_and(|limit| self.total_size() > limit) {
+			return Err(Error::QuotaExceeded)
+		}
+		if let Some(path) = &*self.relocated.read() {
+			return Err(Error::InvalidConfiguration(format!(
+				"Database was relocated to {}",
+				path.display()
+			)))
+		}
+		let plain_key = key;
+		let key = column.hash_key(key);
+		let payload = (key.len() + len) as u64;
+		// The value is read before taking the log writer, so that a slow reader does not hold
+		// back other writes. Chunks are released as they are copied to the log record.
+		let mut chunks = VecDeque::new();
+		let mut left = len - prefix.len();
+		while left > 0 {
+			let mut chunk = vec![0; left.min(STREAM_CHUNK_SIZE)];
+			std::io::Read::read_exact(reader, &mut chunk)?;
+			left -= chunk.len();
+			chunks.push_back(chunk);
+		}
+		let traced = self
+			.trace
+			.as_ref()
+			.map(|_| chunks.iter().flatten().copied().collect::<Vec<u8>>());
+		if !prefix.is_empty() {
+			chunks.push_front(prefix);
+		}
+		// Earlier commits are logged first, so that they can't overwrite the value.
+		let log_writer = loop {
+			let log_writer = self.log_writer.lock();
+			let last_queued = self.last_queued_commit();
+			if self.last_logged_commit.load(Ordering::SeqCst) >= last_queued {
+				break log_writer
+			}
+			std::mem::drop(log_writer);
+			self.wait_logged(last_queued)?;
+		};
+		let mut pos = 0;
+		let mut writer = self.log.begin_record();
+		let record_id = writer.record_id();
+		let written = column
+			.write_stream_plan(
+				&key,
+				len,
+				|part| {
+					let mut filled = 0;
+					while filled < part.len() {
+						let Some(chunk) = chunks.front() else { return false };
+						let n = (chunk.len() - pos).min(part.len() - filled);
+						part[filled..filled + n].copy_from_slice(&chunk[pos..pos + n]);
+						filled += n;
+						pos += n;
+						if pos == chunk.len() {
+							chunks.pop_front();
+							pos = 0;
+						}
+					}
+					true
+				},
+				&mut writer,
+			)
+			.and_then(|plan| {
+				for c in self.columns.iter() {
+					c.complete_plan(&mut writer)?;
+				}
+				Ok((plan, self.log.end_record(writer.drain())?))
+			});
+		let (plan, bytes) = match written {
+			Ok(written) => written,
+			Err(e) => {
+				// The record can't be abandoned, same as a failed commit in the log worker.
+				self.store_err(Err(e));
+				return self.check_bg_err()
+			},
+		};
+		self.account_logged(payload, bytes);
+		{
+			let _overlay = self.commit_overlay.write();
+			if let Some(cache) = &self.value_cache[col as usize] {
+				cache.remove(&key);
+			}
+		}
+		self.last_logged_record.fetch_max(record_id, Ordering::SeqCst);
+		if let (Some(trace), Some(traced)) = (&self.trace, &traced) {
+			trace.stream(col, plain_key, traced.len() as u64, traced)?;
+		}
+		std::mem::drop(log_writer);
+		self.signal_progress();
+		self.quarantined.write().remove(&(col, key.to_vec()));
+		self.journaled.lock().remove(&(col, key.to_vec()));
+		if let PlanOutcome::NeedReindex = plan {
+			self.start_reindex(record_id);
+		}
+		log::debug!(
+			target: "parity-db",
+			"Streamed {} bytes to column {} in record {}",
+			len,
+			col,
+			record_id,
+		);
+		Ok(())
+	}
+
+	// Add the removal of each entry of removed multimap keys, as stored or as added earlier in
+	// the same commit. Entries added again later in the commit are kept.
+	fn expand_multimap_removals(&self, commit: &mut CommitChangeSet) -> Result<()> {
+		for (c, changes) in commit.btree_indexed.iter_mut() {
+			for i in std::mem::take(&mut changes.remove_prefix) {
+				let prefix = changes.changes[i].0.clone();
+				let mut removed = BTreeSet::new();
+				let mut iter = self.btree_iter(*c)?;
+				iter.seek(&prefix)?;
+				while let Some((key, _)) = iter.next()? {
+					if !key.starts_with(&prefix) {
+						break
+					}
+					removed.insert(key);
+				}
+				for (key, value) in &changes.changes[..i] {
+					if value.is_some() && key.starts_with(&prefix) {
+						removed.insert(key.clone());
+					}
+				}
+				for (key, value) in &changes.changes[i + 1..] {
+					if value.is_some() {
+						removed.remove(key);
+					}
+				}
+				changes.changes.extend(removed.into_iter().map(|key| (key, None)));
+			}
 		}
 		Ok(())
 	}
 
+	fn notify(&self, stage: CommitStage, notification: &CommitNotification) {
+		for hook in self.commit_hooks.read().iter() {
+			for (col, changes) in notification.iter() {
+				hook(stage, *col, changes);
+			}
+		}
+	}
+
+	// Report commits up to `record_id` as enacted.
+	fn notify_enacted(&self, record_id: u64) {
+		loop {
+			let notification = {
+				let mut pending = self.pending_notifications.lock();
+				match pending.front() {
+					Some((id, _)) if *id <= record_id => pending.pop_front().map(|(_, n)| n),
+					_ => None,
+				}
+			};
+			match notification {
+				Some(notification) => self.notify(CommitStage::Enacted, &notification),
+				None => break,
+			}
+		}
+	}
+
+	// Add a record of commits with `payload` bytes of keys and values written to the log to the
+	// queue of records to flush.
+	fn account_logged(&self, payload: u64, bytes: u64) {
+		self.checkpoint_logged(bytes);
+		let mut logged_bytes = self.log_queue_wait.work.lock();
+		*logged_bytes += bytes as i64;
+		if let Some(metrics) = &self.options.metrics {
+			metrics.log_queue_bytes(*logged_bytes);
+			metrics.log_written(payload, bytes);
+		}
+		self.flush_worker_wait.signal();
+	}
+
 	fn process_commits(&self) -> Result<bool> {
 		{
 			// Wait if the queue is full.
 			let mut queue = self.log_queue_wait.work.lock();
-			if !self.shutdown.load(Ordering::Relaxed) && *queue > MAX_LOG_QUEUE_BYTES {
+			if !self.shutdown.load(Ordering::Relaxed) &&
+				!self.manual_workers() &&
+				*queue > self.log_queue_limit
+			{
 				log::debug!(target: "parity-db", "Waiting, log_bytes={}", queue);
 				self.log_queue_wait.cv.wait(&mut queue);
 			}
 		}
-		let commit = {
+		self.wait_coalesce_window();
+		let mut commits = {
 			let mut queue = self.commit_queue.lock();
-			if let Some(commit) = queue.commits.pop_front() {
-				queue.bytes -= commit.bytes;
+			let mut commits = Vec::new();
+			let mut bytes = 0;
+			while let Some(commit) = queue.commits.front() {
+				// The first commit is always taken, others only if they fit.
+				match self.options.coalesce_commit_bytes {
+					_ if commits.is_empty() => (),
+					Some(max) if bytes + commit.bytes <= max => (),
+					_ => break,
+				}
+				bytes += commit.bytes;
+				commits.extend(queue.commits.pop_front());
+			}
+			if !commits.is_empty() {
+				queue.bytes -= bytes;
 				log::debug!(
 					target: "parity-db",
 					"Removed {}. Still queued commits {} bytes",
-					commit.bytes,
+					bytes,
 					queue.bytes,
 				);
-				if queue.bytes <= MAX_COMMIT_QUEUE_BYTES &&
-					(queue.bytes + commit.bytes) > MAX_COMMIT_QUEUE_BYTES
+				if queue.bytes <= self.commit_queue_limit &&
+					(queue.bytes + bytes) > self.commit_queue_limit
 				{
 					// Past the waiting threshold.
 					log::debug!(
@@ -359,15 +1822,43 @@ impl DbInner {
 					);
 					self.commit_queue_full_cv.notify_one();
 				}
-				Some(commit)
-			} else {
-				None
 			}
+			commits
 		};
 
-		if let Some(mut commit) = commit {
-			let mut reindex = false;
-			let mut writer = self.log.begin_record();
+		if commits.is_empty() {
+			return Ok(false)
+		}
+		let started = Instant::now();
+		let timer = OpTimer::start(Op::Commit, &self.options.slow_ops);
+		let mut reindex = false;
+		let _log_writer = self.log_writer.lock();
+		if let Some(trace) = &self.trace {
+			trace.commit(commits.iter().map(|c| &c.changeset))?;
+		}
+		let mut writer = self.log.begin_record();
+		let mut ops: u64 = 0;
+		// Changes written to the current record of a split commit. Older formats can't replay
+		// split commits.
+		let mut chunk_ops: u64 = 0;
+		let chunk_size = self
+			.options
+			.commit_chunk_size
+			.filter(|_| self.db_version >= SPLIT_COMMIT_VERSION);
+		let mut begin_chunk = |writer: &mut crate::log::LogWriter, changes: u64| -> Result<()> {
+			match chunk_size {
+				Some(size) if chunk_ops > 0 && chunk_ops + changes > size as u64 => {
+					let bytes = self.log.continue_record(writer)?;
+					self.account_logged(0, bytes);
+					chunk_ops = changes;
+				},
+				_ => chunk_ops += changes,
+			}
+			Ok(())
+		};
+		let mut outcomes = Vec::with_capacity(commits.len());
+		let mut repairs = Vec::new();
+		for commit in commits.iter_mut() {
 			log::debug!(
 				target: "parity-db",
 				"Processing commit {}, record {}, {} bytes",
@@ -375,13 +1866,15 @@ impl DbInner {
 				writer.record_id(),
 				commit.bytes,
 			);
-			let mut ops: u64 = 0;
+			let mut commit_outcomes = HashMap::new();
 			for (c, key_values) in commit.changeset.indexed.iter() {
 				key_values.write_plan(
 					&self.columns[*c as usize],
 					&mut writer,
 					&mut ops,
 					&mut reindex,
+					&mut begin_chunk,
+					commit.outcomes.then(|| commit_outcomes.entry(*c).or_default()),
 				)?;
 			}
 
@@ -392,52 +1885,268 @@ impl DbInner {
 							"Not an indexed column.".to_string(),
 						)),
 					Column::Tree(column) => {
-						btree.write_plan(column, &mut writer, &mut ops)?;
+						begin_chunk(&mut writer, btree.changes.len() as u64)?;
+						btree.write_plan(
+							column,
+							&mut writer,
+							&mut ops,
+							commit.outcomes.then(|| commit_outcomes.entry(*c).or_default()),
+						)?;
 					},
 				}
 			}
 
-			// Collect final changes to value tables
-			for c in self.columns.iter() {
-				c.complete_plan(&mut writer)?;
+			for (c, tier, index) in commit.changeset.force_remove.iter() {
+				match &self.columns[*c as usize] {
+					Column::Hash(column) => {
+						let removed = column.write_force_remove_plan(*tier, *index, &mut writer)?;
+						repairs.push((*c, *tier, *index, removed));
+					},
+					Column::Tree(_) =>
+						return Err(Error::InvalidConfiguration(
+							"Not an indexed column.".to_string(),
+						)),
+				}
 			}
-			let record_id = writer.record_id();
-			let l = writer.drain();
+			outcomes.push(commit_outcomes);
+		}
 
-			let bytes = {
-				let bytes = self.log.end_record(l)?;
-				let mut logged_bytes = self.log_queue_wait.work.lock();
-				*logged_bytes += bytes as i64;
-				self.flush_worker_wait.signal();
-				bytes
-			};
+		// Collect final changes to value tables
+		for c in self.columns.iter() {
+			c.complete_plan(&mut writer)?;
+		}
+		let record_id = writer.record_id();
+		let l = writer.drain();
+
+		let bytes = self.log.end_record(l)?;
+		timer.finish_record(record_id);
+		self.account_logged(commits.iter().map(|c| c.bytes as u64).sum(), bytes);
+		for (c, tier, index, removed) in repairs {
+			self.journal_repair(record_id, c, tier, index, removed)?;
+		}
 
-			{
-				// Cleanup the commit overlay.
-				let mut overlay = self.commit_overlay.write();
+		{
+			// Cleanup the commit overlay.
+			let mut overlay = self.commit_overlay.write();
+			for commit in commits.iter_mut() {
 				for (c, key_values) in commit.changeset.indexed.iter() {
 					key_values.clean_overlay(&mut overlay[*c as usize], commit.id);
+					if let Some(cache) = &self.value_cache[*c as usize] {
+						for (key, _) in key_values.changes.iter() {
+							cache.remove(key);
+						}
+					}
 				}
 				for (c, iterset) in commit.changeset.btree_indexed.iter_mut() {
+					if let Some(cache) = &self.value_cache[*c as usize] {
+						for (key, _) in iterset.changes.iter() {
+							cache.remove(key);
+						}
+					}
 					iterset.clean_overlay(&mut overlay[*c as usize].btree_indexed, commit.id);
 				}
+				for (c, _, _) in commit.changeset.force_remove.iter() {
+					if let Some(cache) = &self.value_cache[*c as usize] {
+						cache.clear();
+					}
+				}
+			}
+		}
+		let last_commit = commits.last().map_or(0, |c| c.id);
+		for (mut commit, outcomes) in commits.into_iter().zip(outcomes) {
+			if let Some(queued) = commit.queued {
+				if let Some(metrics) = &self.options.metrics {
+					metrics.commit(queued.elapsed());
+				}
+				if commit.timed {
+					self.commit_timings
+						.lock()
+						.insert(commit.id, (started - queued, started.elapsed(), record_id));
+				}
+			}
+			if commit.outcomes {
+				self.commit_outcomes.lock().insert(commit.id, outcomes);
+			}
+			if let Some(notification) = commit.notification.take() {
+				self.pending_notifications.lock().push_back((record_id, notification));
+			}
+		}
+		self.last_logged_record.store(record_id, Ordering::SeqCst);
+		self.last_logged_commit.store(last_commit, Ordering::SeqCst);
+		self.signal_progress();
+
+		if reindex {
+			self.start_reindex(record_id);
+		}
+
+		log::debug!(
+			target: "parity-db",
+			"Processed commits up to {} (record {}), {} ops, {} bytes written",
+			last_commit,
+			record_id,
+			ops,
+			bytes,
+		);
+		Ok(true)
+	}
+
+	// Wait until the commit queue holds `Options::coalesce_commit_bytes` or the first queued
+	// commit is older than `Options::coalesce_commit_window`.
+	fn wait_coalesce_window(&self) {
+		let (Some(window), Some(max)) =
+			(self.options.coalesce_commit_window, self.options.coalesce_commit_bytes)
+		else {
+			return
+		};
+		if self.manual_workers() {
+			return
+		}
+		loop {
+			let remaining = {
+				let queue = self.commit_queue.lock();
+				match queue.commits.front().and_then(|c| c.queued) {
+					Some(queued) if queue.bytes < max && !self.shutdown.load(Ordering::SeqCst) =>
+						window.checked_sub(queued.elapsed()),
+					_ => None,
+				}
+			};
+			match remaining {
+				Some(remaining) if !remaining.is_zero() => self.log_worker_wait.wait_for(remaining),
+				_ => break,
 			}
+		}
+	}
+
+	// Record a forced removal in the repair journal.
+	fn journal_repair(
+		&self,
+		record_id: u64,
+		col: ColId,
+		tier: u8,
+		index: u64,
+		(removed, cleared): (u64, bool),
+	) -> Result<()> {
+		let line = match (removed, cleared) {
+			(removed, true) => format!(
+				"record={} col={} tier={} index={} action=force_remove index_entries={}",
+				record_id, col, tier, index, removed
+			),
+			(0, false) => format!(
+				"record={} col={} tier={} index={} action=skipped reason=already_free",
+				record_id, col, tier, index
+			),
+			(removed, false) => format!(
+				"record={} col={} tier={} index={} action=remove_dangling index_entries={}",
+				record_id, col, tier, index, removed
+			),
+		};
+		log::warn!(target: "parity-db", "Repair: {}", line);
+		let mut path = self.options.path.clone();
+		path.push(REPAIR_JOURNAL);
+		let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+		std::io::Write::write_all(&mut file, format!("{}\n", line).as_bytes())?;
+		file.sync_data()?;
+		Ok(())
+	}
+
+	fn force_remove_at(&self, col: ColId, tier: u8, index: u64) -> Result<()> {
+		match self.columns.get(col as usize) {
+			Some(Column::Hash(column)) if column.has_slot(tier, index) => (),
+			Some(Column::Hash(_)) =>
+				return Err(Error::InvalidInput(format!("No slot {} in tier {}", index, tier))),
+			Some(Column::Tree(_)) =>
+				return Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => return Err(Error::InvalidInput(format!("No such column {}", col))),
+		}
+		let commit =
+			CommitChangeSet { force_remove: vec![(col, tier, index)], ..Default::default() };
+		let commit_id = self.queue_commit(commit, false, false, None)?;
+		self.wait_for_commit(commit_id, false)
+	}
 
-			if reindex {
-				self.start_reindex(record_id);
+	fn trigger_reindex(&self, col: ColId) -> Result<bool> {
+		let column = match self.columns.get(col as usize) {
+			Some(Column::Hash(column)) => column,
+			Some(Column::Tree(_)) =>
+				return Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => return Err(Error::InvalidInput(format!("No such column {}", col))),
+		};
+		{
+			let _log_writer = self.log_writer.lock();
+			if !column.grow_index() {
+				return Ok(false)
 			}
+			if let Some(trace) = &self.trace {
+				trace.grow_index(col)?;
+			}
+			// Entries logged so far are moved once they are enacted.
+			let record_id = self
+				.last_logged_record
+				.load(Ordering::SeqCst)
+				.max(self.last_enacted.load(Ordering::SeqCst))
+				.max(1);
+			self.start_reindex(record_id);
+		}
+		self.log_worker_wait.signal();
+		Ok(true)
+	}
 
-			log::debug!(
-				target: "parity-db",
-				"Processed commit {} (record {}), {} ops, {} bytes written",
-				commit.id,
-				record_id,
-				ops,
-				bytes,
-			);
-			Ok(true)
-		} else {
-			Ok(false)
+	fn reindex_progress(&self, col: ColId) -> Option<(u64, u64)> {
+		match self.columns.get(col as usize) {
+			Some(Column::Hash(column)) => column.reindex_progress(),
+			_ => None,
+		}
+	}
+
+	// Continue rebuilding bloom filters. Returns `true` if there is more work.
+	fn rebuild_bloom_filters(&self) -> Result<bool> {
+		let mut more_work = false;
+		for c in self.columns.iter() {
+			more_work |= c.rebuild_bloom(&self.log)?;
+		}
+		Ok(more_work)
+	}
+
+	// Time for background timers. Logical with `Options::deterministic_seed`.
+	fn now(&self) -> Instant {
+		match &self.schedule {
+			Some(schedule) => self.opened + schedule.lock().clock,
+			None => Instant::now(),
+		}
+	}
+
+	// Index entries moved by the next reindex record.
+	fn reindex_batch(&self) -> usize {
+		match &self.schedule {
+			Some(schedule) =>
+				rand::Rng::gen_range(&mut schedule.lock().rng, 1..=self.options.reindex_batch),
+			None => self.options.reindex_batch,
+		}
+	}
+
+	// Time left until the next reindex record may be written.
+	fn reindex_delay(&self) -> Option<Duration> {
+		let now = self.now();
+		let mut resume = self.reindex_resume.lock();
+		match *resume {
+			Some(at) if at > now => Some(at - now),
+			Some(_) => {
+				*resume = None;
+				None
+			},
+			None => None,
+		}
+	}
+
+	// Delay the next reindex record after one of `bytes` was written, as configured with
+	// `Options::reindex_pause` and `Options::reindex_io_limit`.
+	fn throttle_reindex(&self, bytes: u64) {
+		let mut delay = self.options.reindex_pause.unwrap_or_default();
+		if let Some(limit) = self.options.reindex_io_limit {
+			delay = delay.max(Duration::from_secs_f64(bytes as f64 / limit as f64));
+		}
+		if !delay.is_zero() {
+			*self.reindex_resume.lock() = Some(self.now() + delay);
 		}
 	}
 
@@ -450,12 +2159,31 @@ impl DbInner {
 		if next_reindex == 0 || next_reindex > self.last_enacted.load(Ordering::SeqCst) {
 			return Ok(false)
 		}
-		// Process any pending reindexes
-		for column in self.columns.iter() {
+		if self.reindex_delay().is_some() {
+			return Ok(false)
+		}
+		self.write_reindex_record(self.reindex_batch())
+	}
+
+	// Write a record moving up to `max_entries` index entries of the first column that is
+	// being reindexed. Returns `false` if no column is.
+	fn write_reindex_record(&self, max_entries: usize) -> Result<bool> {
+		for (col, column) in self.columns.iter().enumerate() {
 			let column = if let Column::Hash(c) = column { c } else { continue };
-			let ReindexBatch { drop_index, batch } = column.reindex(&self.log)?;
+			let timer = OpTimer::start(Op::Reindex, &self.options.slow_ops);
+			let ReindexBatch { drop_index, batch, start } =
+				column.reindex(&self.log, max_entries)?;
+			if let (Some(metrics), Some((done, total))) =
+				(&self.options.metrics, column.reindex_progress())
+			{
+				metrics.reindex_progress(col as ColId, done, total);
+			}
 			if !batch.is_empty() || drop_index.is_some() {
 				let mut next_reindex = false;
+				let _log_writer = self.log_writer.lock();
+				if let Some(trace) = &self.trace {
+					trace.reindex(max_entries)?;
+				}
 				let mut writer = self.log.begin_record();
 				log::debug!(
 					target: "parity-db",
@@ -477,6 +2205,10 @@ impl DbInner {
 
 				let mut logged_bytes = self.log_queue_wait.work.lock();
 				let bytes = self.log.end_record(l)?;
+				if let Some((table, chunk)) = start {
+					timer.finish(&table, chunk);
+				}
+				self.checkpoint_logged(bytes);
 				log::debug!(
 					target: "parity-db",
 					"Created reindex record {}, {} bytes",
@@ -484,10 +2216,15 @@ impl DbInner {
 					bytes,
 				);
 				*logged_bytes += bytes as i64;
+				if let Some(metrics) = &self.options.metrics {
+					metrics.log_queue_bytes(*logged_bytes);
+					metrics.log_written(0, bytes);
+				}
 				if next_reindex {
 					self.start_reindex(record_id);
 				}
 				self.flush_worker_wait.signal();
+				self.throttle_reindex(bytes);
 				return Ok(true)
 			}
 		}
@@ -495,111 +2232,199 @@ impl DbInner {
 		Ok(false)
 	}
 
-	fn enact_logs(&self, validation_mode: bool) -> Result<bool> {
-		let cleared = {
-			let reader = match self.log.read_next(validation_mode) {
-				Ok(reader) => reader,
-				Err(Error::Corruption(_)) if validation_mode => {
-					log::debug!(target: "parity-db", "Bad log header");
+	// Read and validate the rest of a log record. Index chunks of a checkpoint record are
+	// collected into `checkpoint`. Returns `false` if the record is invalid.
+	fn validate_record(
+		&self,
+		reader: &mut LogReader,
+		checkpoint: &mut Option<(u64, CheckpointChunks)>,
+	) -> Result<()> {
+		loop {
+			let next = match reader.next() {
+				Ok(next) => next,
+				Err(e) => {
+					log::debug!(target: "parity-db", "Error reading log: {:?}", e);
+					return Err(e)
+				},
+			};
+			match next {
+				LogAction::BeginRecord =>
+					return Err(Error::Corruption("Unexpected log header".into())),
+				LogAction::EndRecord => return Ok(()),
+				LogAction::InsertIndex(insertion) => {
+					let col = insertion.table.col() as usize;
+					if let Err(e) =
+						self.columns[col].validate_plan(LogAction::InsertIndex(insertion), reader)
+					{
+						log::warn!(target: "parity-db", "Error replaying log: {:?}. Reverting", e);
+						return Err(e)
+					}
+				},
+				LogAction::InsertValue(insertion) => {
+					let col = insertion.table.col() as usize;
+					if let Err(e) =
+						self.columns[col].validate_plan(LogAction::InsertValue(insertion), reader)
+					{
+						log::warn!(target: "parity-db", "Error replaying log: {:?}. Reverting", e);
+						return Err(e)
+					}
+				},
+				LogAction::DropTable(_) => continue,
+				LogAction::Checkpoint(covered) => *checkpoint = Some((covered, Vec::new())),
+				LogAction::CheckpointIndex(insertion) => {
+					let mut chunk = EMPTY_CHUNK;
+					if let Err(e) = reader.read(&mut chunk) {
+						log::debug!(target: "parity-db", "Error reading log: {:?}", e);
+						return Err(e)
+					}
+					match checkpoint {
+						Some((_, chunks)) => chunks.push((insertion.table, insertion.index, chunk)),
+						None => return Err(Error::Corruption("Unexpected checkpoint data".into())),
+					}
+				},
+			}
+		}
+	}
+
+	// Check that all parts of a split commit that starts with the record just validated are in
+	// the log, so that it is enacted in full or not at all. Leaves the reader at the end of the
+	// first record. Returns the last record of the commit, or `None` if it is incomplete.
+	fn validate_transaction(&self, reader: &mut LogReader) -> Option<u64> {
+		let id = match reader.transaction() {
+			Some((id, false)) if id == reader.record_id() => id,
+			_ => return Some(reader.record_id()),
+		};
+		let mut record_id = reader.record_id();
+		loop {
+			match reader.next() {
+				Ok(LogAction::BeginRecord) => (),
+				Ok(_) => return None,
+				Err(e) => {
+					log::debug!(target: "parity-db", "Incomplete transaction {}: {:?}", id, e);
+					return None
+				},
+			}
+			if reader.record_id() != record_id + 1 ||
+				self.validate_record(reader, &mut None).is_err()
+			{
+				return None
+			}
+			record_id += 1;
+			match reader.transaction() {
+				Some((part_id, last)) if part_id == id =>
+					if last {
+						break
+					},
+				_ => return None,
+			}
+		}
+		log::debug!(
+			target: "parity-db",
+			"Validated transaction {}, records {}..={}",
+			id,
+			id,
+			record_id,
+		);
+		let valid = reader.reset().is_ok() &&
+			matches!(reader.next(), Ok(LogAction::BeginRecord)) &&
+			self.validate_record(reader, &mut None).is_ok();
+		valid.then_some(record_id)
+	}
+
+	fn enact_logs(&self, validation_mode: bool) -> Result<bool> {
+		if !validation_mode && self.disk_full_delay().is_some_and(|delay| !delay.is_zero()) {
+			return Ok(false)
+		}
+		let enact_lock = self.enact_lock.lock();
+		let cleared = {
+			let reader = match self.log.read_next(validation_mode) {
+				Ok(reader) => reader,
+				Err(Error::Corruption(_)) if validation_mode => {
+					log::debug!(target: "parity-db", "Bad log header");
 					self.log.clear_replay_logs()?;
 					return Ok(false)
 				},
 				Err(e) => return Err(e),
 			};
 			if let Some(mut reader) = reader {
+				let timer = OpTimer::start(Op::Enact, &self.options.slow_ops);
 				log::debug!(
 					target: "parity-db",
 					"Enacting log {}",
 					reader.record_id(),
 				);
+				let mut skip = false;
 				if validation_mode {
-					if reader.record_id() != self.last_enacted.load(Ordering::Relaxed) + 1 {
-						log::warn!(
+					let expected = self.last_enacted.load(Ordering::Relaxed) + 1;
+					let mut last = reader.record_id();
+					let stop = if reader.record_id() != expected {
+						Some(ReplayStop::MissingRecord { expected, found: reader.record_id() })
+					} else {
+						// Validate all records before applying anything
+						let (log, offset) = reader.position();
+						match self.validate_record(&mut reader, &mut None) {
+							Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+								Some(ReplayStop::TornRecord { log, offset }),
+							Err(e) => Some(ReplayStop::CorruptRecord {
+								log,
+								offset,
+								reason: e.to_string(),
+							}),
+							Ok(()) => match self.validate_transaction(&mut reader) {
+								Some(record_id) => {
+									last = record_id;
+									None
+								},
+								None => Some(ReplayStop::IncompleteTransaction {
+									record_id: reader.record_id(),
+								}),
+							},
+						}
+					};
+					if let Some(stop) = stop {
+						std::mem::drop(reader);
+						self.log.stop_replay(stop);
+						self.log.clear_replay_logs()?;
+						return Ok(false)
+					}
+					if self.restore.is_some_and(|(_, until)| last > until) {
+						log::debug!(
 							target: "parity-db",
-							"Log sequence error. Expected record {}, got {}",
-							self.last_enacted.load(Ordering::Relaxed) + 1,
+							"Restore point reached before record {}",
 							reader.record_id(),
 						);
 						std::mem::drop(reader);
 						self.log.clear_replay_logs()?;
 						return Ok(false)
 					}
-					// Validate all records before applying anything
-					loop {
-						let next = match reader.next() {
-							Ok(next) => next,
-							Err(e) => {
-								log::debug!(target: "parity-db", "Error reading log: {:?}", e);
-								std::mem::drop(reader);
-								self.log.clear_replay_logs()?;
-								return Ok(false)
-							},
-						};
-						match next {
-							LogAction::BeginRecord => {
-								log::debug!(target: "parity-db", "Unexpected log header");
-								std::mem::drop(reader);
-								self.log.clear_replay_logs()?;
-								return Ok(false)
-							},
-							LogAction::EndRecord => break,
-							LogAction::InsertIndex(insertion) => {
-								let col = insertion.table.col() as usize;
-								if let Err(e) = self.columns[col]
-									.validate_plan(LogAction::InsertIndex(insertion), &mut reader)
-								{
-									log::warn!(target: "parity-db", "Error replaying log: {:?}. Reverting", e);
-									std::mem::drop(reader);
-									self.log.clear_replay_logs()?;
-									return Ok(false)
-								}
-							},
-							LogAction::InsertValue(insertion) => {
-								let col = insertion.table.col() as usize;
-								if let Err(e) = self.columns[col]
-									.validate_plan(LogAction::InsertValue(insertion), &mut reader)
-								{
-									log::warn!(target: "parity-db", "Error replaying log: {:?}. Reverting", e);
-									std::mem::drop(reader);
-									self.log.clear_replay_logs()?;
-									return Ok(false)
-								}
-							},
-							LogAction::DropTable(_) => continue,
-						}
+					if reader.record_id() <= self.replay_checkpoint {
+						// Covered by a restored checkpoint.
+						skip = true;
+					} else {
+						reader.reset()?;
+						reader.next()?;
 					}
-					reader.reset()?;
-					reader.next()?;
-				}
-				loop {
-					match reader.next()? {
-						LogAction::BeginRecord =>
-							return Err(Error::Corruption("Bad log record".into())),
-						LogAction::EndRecord => break,
-						LogAction::InsertIndex(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertIndex(insertion), &mut reader)?;
-						},
-						LogAction::InsertValue(insertion) => {
-							self.columns[insertion.table.col() as usize]
-								.enact_plan(LogAction::InsertValue(insertion), &mut reader)?;
-						},
-						LogAction::DropTable(id) => {
-							log::debug!(
-								target: "parity-db",
-								"Dropping index {}",
-								id,
-							);
-							match &self.columns[id.col() as usize] {
-								Column::Hash(col) => {
-									col.drop_index(id)?;
-									// Check if there's another reindex on the next iteration
-									self.start_reindex(reader.record_id());
-								},
-								Column::Tree(_) => (),
-							}
+				}
+				// Value table writes are collected and applied in parallel when replaying.
+				let parallel = validation_mode && self.options.replay_threads > 1;
+				let table_bytes = if skip {
+					0
+				} else {
+					match self.enact_record(&mut reader, parallel) {
+						Ok(bytes) => bytes,
+						Err(e) if !validation_mode && self.retry_disk_full(&e) => {
+							// The record is applied again from the start once there is space.
+							reader.reset()?;
+							return Ok(false)
 						},
+						Err(e) => return Err(e),
 					}
+				};
+				if !validation_mode {
+					self.resume_disk_full();
+				}
+				if let (Some(metrics), false) = (&self.options.metrics, skip) {
+					metrics.tables_written(table_bytes);
 				}
 				log::debug!(
 					target: "parity-db",
@@ -610,7 +2435,7 @@ impl DbInner {
 				let record_id = reader.record_id();
 				let bytes = reader.read_bytes();
 				let cleared = reader.drain();
-				self.last_enacted.store(record_id, Ordering::SeqCst);
+				timer.finish_record(record_id);
 				Some((record_id, cleared, bytes))
 			} else {
 				log::debug!(target: "parity-db", "End of log");
@@ -619,7 +2444,24 @@ impl DbInner {
 		};
 
 		if let Some((record_id, cleared, bytes)) = cleared {
-			self.log.end_read(cleared, record_id);
+			self.track_relocation(&cleared);
+			self.track_checkpoint(&cleared);
+			self.log.end_read(cleared, record_id)?;
+			let complete =
+				validation_mode || self.enact_batch.lock().push(record_id, bytes, &self.options);
+			if complete {
+				self.complete_enacted(record_id, validation_mode)?;
+			}
+			std::mem::drop(enact_lock);
+			if complete {
+				self.notify_enacted(record_id);
+				self.signal_progress();
+				let next_reindex = self.next_reindex.load(Ordering::SeqCst);
+				if next_reindex != 0 && next_reindex <= record_id {
+					// Reindex may be waiting for this record.
+					self.log_worker_wait.signal();
+				}
+			}
 			{
 				if !validation_mode {
 					let mut queue = self.log_queue_wait.work.lock();
@@ -634,8 +2476,11 @@ impl DbInner {
 						);
 					}
 					*queue -= bytes as i64;
-					if *queue <= MAX_LOG_QUEUE_BYTES &&
-						(*queue + bytes as i64) > MAX_LOG_QUEUE_BYTES
+					if let Some(metrics) = &self.options.metrics {
+						metrics.log_queue_bytes(*queue);
+					}
+					if *queue <= self.log_queue_limit &&
+						(*queue + bytes as i64) > self.log_queue_limit
 					{
 						self.log_queue_wait.cv.notify_one();
 					}
@@ -644,12 +2489,151 @@ impl DbInner {
 			}
 			Ok(true)
 		} else {
+			// Complete a partial batch at the end of the log.
+			let pending = self.enact_batch.lock().take();
+			if let Some(record_id) = pending {
+				self.complete_enacted(record_id, validation_mode)?;
+				std::mem::drop(enact_lock);
+				self.notify_enacted(record_id);
+				self.signal_progress();
+			}
 			Ok(false)
 		}
 	}
 
+	// Apply the actions of the record read by `reader` to the tables. Returns the number of
+	// bytes written.
+	fn enact_record(&self, reader: &mut LogReader, parallel: bool) -> Result<u64> {
+		let mut value_writes = Vec::new();
+		let mut value_batch = ValueWriteBatch::default();
+		let mut table_bytes = 0;
+		loop {
+			match reader.next()? {
+				LogAction::BeginRecord => return Err(Error::Corruption("Bad log record".into())),
+				LogAction::EndRecord => break,
+				LogAction::InsertIndex(insertion) => {
+					self.columns[insertion.table.col() as usize]
+						.enact_plan(LogAction::InsertIndex(insertion), reader)?;
+					table_bytes += EMPTY_CHUNK.len() as u64;
+				},
+				LogAction::InsertValue(insertion) if parallel => {
+					let data = self.columns[insertion.table.col() as usize]
+						.read_value_plan(&insertion, reader)?;
+					table_bytes += data.len() as u64;
+					value_writes.push((insertion, data));
+				},
+				LogAction::InsertValue(insertion) => {
+					let data = self.columns[insertion.table.col() as usize]
+						.read_value_plan(&insertion, reader)?;
+					table_bytes += data.len() as u64;
+					value_batch.push(&self.columns, insertion, data)?;
+				},
+				LogAction::DropTable(id) => {
+					log::debug!(
+						target: "parity-db",
+						"Dropping index {}",
+						id,
+					);
+					match &self.columns[id.col() as usize] {
+						Column::Hash(col) => {
+							col.drop_index(id)?;
+							// Check if there's another reindex on the next iteration
+							self.start_reindex(reader.record_id());
+						},
+						Column::Tree(_) => (),
+					}
+				},
+				LogAction::Checkpoint(_) => (),
+				LogAction::CheckpointIndex(_) => {
+					// Checkpoints are only used on replay.
+					let mut chunk = EMPTY_CHUNK;
+					reader.read(&mut chunk)?;
+				},
+			}
+		}
+		value_batch.apply(&self.columns)?;
+		if !value_writes.is_empty() {
+			self.apply_value_writes(value_writes)?;
+		}
+		Ok(table_bytes)
+	}
+
+	// Whether enactment pauses after error `e`, see `OnDiskFull::Retry`.
+	fn retry_disk_full(&self, e: &Error) -> bool {
+		if !e.is_disk_full() || self.options.on_disk_full == OnDiskFull::Fail {
+			return false
+		}
+		log::warn!(target: "parity-db", "Disk full, log enactment paused: {}", e);
+		let now = self.now();
+		let mut disk_full = self.disk_full.lock();
+		*disk_full = Some(now);
+		// Make sure a flush worker is not left waiting for enactment on shutdown.
+		if self.shutdown.load(Ordering::SeqCst) {
+			self.log.interrupt_flush();
+		}
+		true
+	}
+
+	// Called once a record was enacted.
+	fn resume_disk_full(&self) {
+		if self.disk_full.lock().take().is_some() {
+			log::info!(target: "parity-db", "Log enactment resumed");
+			self.flush_worker_wait.signal();
+		}
+	}
+
+	// Time until enactment paused by a full disk is retried. `None` if not paused.
+	fn disk_full_delay(&self) -> Option<Duration> {
+		let OnDiskFull::Retry(interval) = self.options.on_disk_full else { return None };
+		let paused = (*self.disk_full.lock())?;
+		Some(interval.saturating_sub(self.now().saturating_duration_since(paused)))
+	}
+
+	// Apply value table writes, spreading tables over `Options::replay_threads` threads. Writes
+	// to the same table are applied in order by a single thread.
+	fn apply_value_writes(&self, writes: Vec<(InsertValueAction, Vec<u8>)>) -> Result<()> {
+		let threads = self.options.replay_threads;
+		let mut partitions: Vec<Vec<_>> = (0..threads).map(|_| Vec::new()).collect();
+		for (record, data) in writes {
+			partitions[record.table.as_u32() as usize % threads].push((record, data));
+		}
+		std::thread::scope(|scope| {
+			let handles: Vec<_> = partitions
+				.into_iter()
+				.filter(|partition| !partition.is_empty())
+				.map(|partition| {
+					scope.spawn(move || -> Result<()> {
+						let mut batch = ValueWriteBatch::default();
+						for (record, data) in partition {
+							batch.push(&self.columns, record, data)?;
+						}
+						batch.apply(&self.columns)
+					})
+				})
+				.collect();
+			handles.into_iter().try_for_each(|handle| {
+				handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))
+			})
+		})
+	}
+
+	// Mark records up to `record_id` as enacted. When batching is enabled the tables are flushed
+	// first. Called with `enact_lock` held.
+	fn complete_enacted(&self, record_id: u64, validation_mode: bool) -> Result<()> {
+		if !validation_mode && self.options.sync_data && EnactBatch::enabled(&self.options) {
+			for c in self.columns.iter() {
+				c.flush()?;
+			}
+		}
+		log::trace!(target: "parity-db", "Completed enactment up to record {}", record_id);
+		self.last_enacted.store(record_id, Ordering::SeqCst);
+		Ok(())
+	}
+
 	fn flush_logs(&self, min_log_size: u64) -> Result<bool> {
+		let timer = OpTimer::start(Op::LogFlush, &self.options.slow_ops);
 		let (flush_next, read_next, cleanup_next) = self.log.flush_one(min_log_size)?;
+		timer.finish_op();
 		if read_next {
 			self.commit_worker_wait.signal();
 		}
@@ -660,7 +2644,7 @@ impl DbInner {
 	}
 
 	fn clean_logs(&self) -> Result<bool> {
-		let keep_logs = if self.options.sync_data { 0 } else { KEEP_LOGS };
+		let keep_logs = if self.options.sync_data { 0 } else { self.options.log_rotation_count };
 		let num_cleanup = self.log.num_dirty_logs();
 		if num_cleanup > keep_logs {
 			if self.options.sync_data {
@@ -684,603 +2668,6428 @@ impl DbInner {
 	}
 
 	fn replay_all_logs(&mut self) -> Result<()> {
+		match self.restore {
+			// Checkpoints in archived logs may be past the restore point.
+			Some((backup, _)) => self.check_restore(backup)?,
+			None => self.restore_checkpoints()?,
+		}
+		let mut records = 0;
 		while let Some(id) = self.log.replay_next()? {
 			log::debug!(target: "parity-db", "Replaying database log {}", id);
-			while self.enact_logs(true)? {}
+			while self.enact_logs(true)? {
+				records += 1;
+			}
 		}
+		self.replay_report = ReplayReport {
+			records,
+			cutoff: self.last_enacted.load(Ordering::SeqCst),
+			stop: self.log.replay_stop(),
+		};
 		// Re-read any cached metadata
 		for c in self.columns.iter() {
 			c.refresh_metadata()?;
 		}
+		// New records must not reuse IDs of the records that were enacted or discarded.
+		self.log.skip_record_ids(self.last_enacted.load(Ordering::SeqCst));
 		log::debug!(target: "parity-db", "Replay is complete.");
 		Ok(())
 	}
 
-	fn shutdown(&self) {
-		self.shutdown.store(true, Ordering::SeqCst);
-		self.log_queue_wait.cv.notify_one();
-		self.flush_worker_wait.signal();
-		self.log_worker_wait.signal();
-		self.commit_worker_wait.signal();
-		self.cleanup_worker_wait.signal();
+	fn signal_progress(&self) {
+		let _work = self.progress_wait.work.lock();
+		self.progress_wait.cv.notify_all();
 	}
 
-	fn kill_logs(&self) -> Result<()> {
-		{
-			if let Some(err) = self.bg_err.lock().as_ref() {
-				// On error the log reader may be left in inconsistent state. So it is important
-				// to no attempt any further log enactment.
-				log::debug!(target: "parity-db", "Shutdown with error state {}", err);
-				return Ok(())
-			}
+	// Memory held by queued commits and the log overlay, see `Options::max_overlay_bytes`.
+	fn overlay_bytes(&self) -> u64 {
+		self.commit_queue.lock().bytes as u64 + self.log_backlog_bytes()
+	}
+
+	fn log_backlog_bytes(&self) -> u64 {
+		// The counter may briefly go below zero, see `log_queue_wait`.
+		(*self.log_queue_wait.work.lock()).max(0) as u64
+	}
+
+	// Id of the last commit added to the queue, 0 if there were none.
+	fn last_queued_commit(&self) -> u64 {
+		let queue = self.commit_queue.lock();
+		if queue.record_id == 0 {
+			0
+		} else {
+			queue.record_id + 1
 		}
-		log::debug!(target: "parity-db", "Processing leftover commits");
-		// Finish logged records and proceed to log and enact queued commits.
-		while self.enact_logs(false)? {}
+	}
+
+	// Block until all commits queued so far are enacted.
+	fn wait_for_queued_commits(&self) -> Result<()> {
+		self.wait_for_commit(self.last_queued_commit(), false)
+	}
+
+	// Block until commit `commit_id` and all commits before it are enacted. With `sync_log`
+	// the log is also fsynced, even if `sync_wal` is off.
+	fn wait_for_commit(&self, commit_id: u64, sync_log: bool) -> Result<()> {
+		if commit_id == 0 {
+			return Ok(())
+		}
+		self.wait_logged(commit_id)?;
+		let record_id = self.last_logged_record.load(Ordering::SeqCst);
+		if sync_log {
+			self.log.sync()?;
+		}
+		// Move the record past the log writer and reader, regardless of the log size.
 		self.flush_logs(0)?;
-		while self.process_commits()? {}
-		while self.enact_logs(false)? {}
 		self.flush_logs(0)?;
-		while self.enact_logs(false)? {}
-		self.clean_all_logs()?;
-		self.log.kill_logs()?;
-		if self.options.stats {
-			let mut path = self.options.path.clone();
-			path.push("stats.txt");
-			match std::fs::File::create(path) {
-				Ok(file) => {
-					let mut writer = std::io::BufWriter::new(file);
-					self.collect_stats(&mut writer, None)
-				},
-				Err(e) => log::warn!(target: "parity-db", "Error creating stats file: {:?}", e),
-			}
+		self.wait_enacted(record_id)
+	}
+
+	fn wait_logged(&self, commit_id: u64) -> Result<()> {
+		if self.manual_workers() {
+			return self.tick_until(|| self.last_logged_commit.load(Ordering::SeqCst) >= commit_id)
+		}
+		let mut work = self.progress_wait.work.lock();
+		while self.last_logged_commit.load(Ordering::SeqCst) < commit_id {
+			self.check_bg_err()?;
+			self.progress_wait.cv.wait(&mut work);
 		}
 		Ok(())
 	}
 
-	fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) {
-		if let Some(col) = column {
-			self.columns[col as usize].write_stats(writer);
-		} else {
-			for c in self.columns.iter() {
-				c.write_stats(writer);
+	fn apply_log_record(&self, record: &[u8]) -> Result<u64> {
+		self.check_bg_err()?;
+		if let Some(path) = &*self.relocated.read() {
+			return Err(Error::InvalidConfiguration(format!(
+				"Database was relocated to {}",
+				path.display()
+			)))
+		}
+		let (record_id, complete) = {
+			let _log_writer = self.log_writer.lock();
+			let appended = self
+				.log
+				.append_record(record, |reader| self.validate_record(reader, &mut None))?;
+			if let Some(trace) = &self.trace {
+				trace.log_record(record)?;
 			}
+			appended
+		};
+		self.last_logged_record.fetch_max(record_id, Ordering::SeqCst);
+		log::debug!(target: "parity-db", "Applying log record {}", record_id);
+		if !complete {
+			// Enacted together with the last part of the commit.
+			return Ok(record_id)
 		}
+		self.flush_logs(0)?;
+		self.flush_logs(0)?;
+		self.wait_enacted(record_id)?;
+		// Table headers were written by the other database.
+		for c in self.columns.iter() {
+			c.refresh_metadata()?;
+		}
+		Ok(record_id)
 	}
 
-	fn clear_stats(&self, column: Option<u8>) {
-		if let Some(col) = column {
-			self.columns[col as usize].clear_stats();
-		} else {
-			for c in self.columns.iter() {
-				c.clear_stats();
-			}
+	// Write the log records of a traced event, then flush and enact them. Background work is
+	// only done as traced, so that the log records match the traced database.
+	fn replay_event(&self, event: TraceEvent) -> Result<()> {
+		match event {
+			TraceEvent::Commit(commits) => {
+				for commit in commits {
+					self.queue_commit(commit, false, false, None)?;
+				}
+				self.process_commits()?;
+			},
+			TraceEvent::Reindex(max_entries) =>
+				if !self.write_reindex_record(max_entries)? {
+					return Err(Error::Corruption("Replay diverged from trace: no reindex".into()))
+				},
+			TraceEvent::GrowIndex(col) => {
+				self.trigger_reindex(col)?;
+			},
+			TraceEvent::Vacuum { col, tier, step } => {
+				let tier = tier as usize;
+				let (record_id, released) =
+					self.write_vacuum_record(self.log_writer.lock(), col, tier, &step)?;
+				if released > 0 {
+					self.vacuum.lock().pending.push((record_id, col, tier));
+				}
+			},
+			TraceEvent::Stream { col, key, len, value } => {
+				match self.put_stream(col, &key, &mut value.as_slice(), len) {
+					Err(Error::Io(_)) if (value.len() as u64) < len => (),
+					result => result?,
+				}
+			},
+			TraceEvent::LogRecord(record) => {
+				let (record_id, complete) = {
+					let _log_writer = self.log_writer.lock();
+					self.log
+						.append_record(&record, |reader| self.validate_record(reader, &mut None))?
+				};
+				self.last_logged_record.fetch_max(record_id, Ordering::SeqCst);
+				if complete {
+					self.enact_replayed()?;
+					for c in self.columns.iter() {
+						c.refresh_metadata()?;
+					}
+				}
+				return Ok(())
+			},
 		}
+		self.enact_replayed()
 	}
 
-	fn store_err(&self, result: Result<()>) {
-		if let Err(e) = result {
-			log::warn!(target: "parity-db", "Background worker error: {}", e);
-			let mut err = self.bg_err.lock();
-			if err.is_none() {
-				*err = Some(Arc::new(e));
-				self.shutdown();
+	fn enact_replayed(&self) -> Result<()> {
+		loop {
+			let more = self.flush_logs(0)?;
+			while self.enact_logs(false)? {}
+			if !more {
+				break
 			}
-			self.commit_queue_full_cv.notify_one();
 		}
+		self.clean_logs()?;
+		self.release_vacuumed()?;
+		self.check_bg_err()
 	}
 
-	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		match &self.columns[c as usize] {
-			Column::Hash(column) => column.iter_while(&self.log, f),
-			Column::Tree(_) => unimplemented!(),
+	fn wait_enacted(&self, record_id: u64) -> Result<()> {
+		if self.manual_workers() {
+			return self.tick_until(|| self.last_enacted.load(Ordering::SeqCst) >= record_id)
 		}
+		let mut work = self.progress_wait.work.lock();
+		while self.last_enacted.load(Ordering::SeqCst) < record_id {
+			self.check_bg_err()?;
+			self.progress_wait.cv.wait(&mut work);
+		}
+		Ok(())
 	}
-}
 
-pub struct Db {
-	inner: Arc<DbInner>,
-	commit_thread: Option<std::thread::JoinHandle<()>>,
-	flush_thread: Option<std::thread::JoinHandle<()>>,
-	log_thread: Option<std::thread::JoinHandle<()>>,
-	cleanup_thread: Option<std::thread::JoinHandle<()>>,
-	join_on_shutdown: bool,
-}
+	// Make commits queued so far durable.
+	fn flush(&self) -> Result<()> {
+		self.wait_logged(self.last_queued_commit())?;
+		self.log.sync()?;
+		for c in self.columns.iter() {
+			c.flush()?;
+		}
+		Ok(())
+	}
 
-impl Db {
-	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Result<Db> {
-		let options = Options::with_columns(path, num_columns);
-		let inner_options = InternalOptions { create: true, ..Default::default() };
-		Self::open_inner(&options, &inner_options)
+	fn check_bg_err(&self) -> Result<()> {
+		if let Some(err) = &*self.bg_err.lock() {
+			return Err(Error::Background(err.clone()))
+		}
+		Ok(())
 	}
 
-	/// Open the database with given options.
-	pub fn open(options: &Options) -> Result<Db> {
-		let inner_options = InternalOptions::default();
-		Self::open_inner(options, &inner_options)
+	// Operations that copy table files need them to be stored as files.
+	fn check_file_storage(&self) -> Result<()> {
+		if self.options.storage.is_some() {
+			return Err(Error::InvalidConfiguration(
+				"Not supported with a custom storage backend".into(),
+			))
+		}
+		Ok(())
 	}
 
-	/// Create the database using given options.
-	pub fn open_or_create(options: &Options) -> Result<Db> {
-		let inner_options = InternalOptions { create: true, ..Default::default() };
-		Self::open_inner(options, &inner_options)
+	// Names of the index and value table files, with the column they belong to.
+	fn column_files(&self) -> Result<Vec<(ColId, String)>> {
+		let mut dirs: Vec<&std::path::Path> = Vec::new();
+		for c in 0..self.columns.len() {
+			let dir = self.options.column_path(c as ColId);
+			if !dirs.contains(&dir) {
+				dirs.push(dir);
+			}
+		}
+		let mut files = Vec::new();
+		for dir in dirs {
+			for entry in std::fs::read_dir(dir)? {
+				let entry = entry?;
+				if let Some(file) = entry.file_name().to_str() {
+					let col = (0..self.columns.len() as ColId).find(|c| {
+						crate::index::TableId::is_file_name(*c, file) ||
+							crate::table::TableId::is_file_name(*c, file)
+					});
+					// Files of a column that were left in another directory are ignored.
+					if let Some(col) = col.filter(|c| self.options.column_path(*c) == dir) {
+						files.push((col, file.to_string()));
+					}
+				}
+			}
+		}
+		Ok(files)
 	}
 
-	pub fn open_read_only(options: &Options) -> Result<Db> {
-		let inner_options = InternalOptions { read_only: true, ..Default::default() };
-		Self::open_inner(options, &inner_options)
+	// Names of the index and value table files.
+	fn table_files(&self) -> Result<Vec<String>> {
+		Ok(self.column_files()?.into_iter().map(|(_, file)| file).collect())
 	}
 
-	fn open_inner(options: &Options, inner_options: &InternalOptions) -> Result<Db> {
-		assert!(options.is_valid());
-		let mut db = DbInner::open(options, inner_options)?;
-		// This needs to be call before log thread: so first reindexing
-		// will run in correct state.
-		db.replay_all_logs()?;
-		let db = Arc::new(db);
-		if inner_options.read_only {
-			return Ok(Db {
-				inner: db,
-				commit_thread: None,
-				flush_thread: None,
-				log_thread: None,
-				cleanup_thread: None,
-				join_on_shutdown: inner_options.commit_stages.join_on_shutdown(),
-			})
+	// Path of a table file returned by `table_files`.
+	fn table_file_path(&self, file: &str) -> std::path::PathBuf {
+		let col = (0..self.columns.len() as ColId).find(|c| {
+			crate::index::TableId::is_file_name(*c, file) ||
+				crate::table::TableId::is_file_name(*c, file)
+		});
+		match col {
+			Some(col) => self.options.column_path(col).join(file),
+			None => self.options.path.join(file),
 		}
-		let start_threads =
-			matches!(inner_options.commit_stages, EnableCommitPipelineStages::Standard);
-		let commit_thread = if start_threads {
-			let commit_worker_db = db.clone();
-			Some(std::thread::spawn(move || {
-				commit_worker_db.store_err(Self::commit_worker(commit_worker_db.clone()))
-			}))
-		} else {
-			None
-		};
-		let flush_thread = if start_threads {
-			let flush_worker_db = db.clone();
-			let min_log_size =
-				if matches!(inner_options.commit_stages, EnableCommitPipelineStages::DbFile) {
-					0
-				} else {
-					MIN_LOG_SIZE_BYTES
-				};
-			Some(std::thread::spawn(move || {
-				flush_worker_db.store_err(Self::flush_worker(flush_worker_db.clone(), min_log_size))
-			}))
-		} else {
-			None
-		};
-		let log_thread = if start_threads {
-			let log_worker_db = db.clone();
-			Some(std::thread::spawn(move || {
-				log_worker_db.store_err(Self::log_worker(log_worker_db.clone()))
-			}))
-		} else {
-			None
-		};
-		let cleanup_thread = if start_threads {
-			let cleanup_worker_db = db.clone();
-			Some(std::thread::spawn(move || {
-				cleanup_worker_db.store_err(Self::cleanup_worker(cleanup_worker_db.clone()))
-			}))
-		} else {
-			None
-		};
-		Ok(Db {
-			inner: db,
-			commit_thread,
-			flush_thread,
-			log_thread,
-			cleanup_thread,
-			join_on_shutdown: inner_options.commit_stages.join_on_shutdown(),
-		})
 	}
 
-	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		self.inner.get(col, key)
+	// Releases that only know older formats can't replay checkpoint records, so these are not
+	// written to databases in such formats.
+	fn checkpoint_interval(&self) -> Option<u64> {
+		self.options
+			.checkpoint_interval
+			.filter(|_| self.db_version >= CHECKPOINT_VERSION)
 	}
 
-	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
-		self.inner.get_size(col, key)
+	fn track_checkpoint(&self, cleared: &Cleared) {
+		if self.checkpoint_interval().is_some() {
+			self.checkpoint.lock().dirty.extend(cleared.index().iter().cloned());
+		}
 	}
 
-	pub fn iter(&self, col: ColId) -> Result<BTreeIterator> {
-		self.inner.btree_iter(col)
+	fn checkpoint_logged(&self, bytes: u64) {
+		if self.checkpoint_interval().is_some() {
+			self.checkpoint.lock().logged_bytes += bytes;
+		}
 	}
 
-	pub fn commit<I, K>(&self, tx: I) -> Result<()>
-	where
-		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
-		K: AsRef<[u8]>,
-	{
-		self.inner.commit(tx)
+	// Write a checkpoint record once enough was logged since the last one. Enactment is paused
+	// while value tables are flushed and the index chunks are captured.
+	fn process_checkpoint(&self) -> Result<()> {
+		match self.checkpoint_interval() {
+			Some(interval) if self.checkpoint.lock().logged_bytes >= interval => (),
+			_ => return Ok(()),
+		}
+		let enact_lock = self.enact_lock.lock();
+		let dirty = {
+			let mut state = self.checkpoint.lock();
+			state.logged_bytes = 0;
+			std::mem::take(&mut state.dirty)
+		};
+		let covered = self.last_enacted.load(Ordering::SeqCst);
+		if self.options.sync_data {
+			for c in self.columns.iter() {
+				c.flush_values()?;
+			}
+		}
+		let mut chunks = Vec::with_capacity(dirty.len());
+		for (table, index) in dirty {
+			if let Column::Hash(column) = &self.columns[table.col() as usize] {
+				if let Some(chunk) = column.index_chunk(table, index) {
+					chunks.push((table, index, chunk));
+				}
+			}
+		}
+		let num_chunks = chunks.len();
+		let log_writer = self.log_writer.lock();
+		let mut writer = self.log.begin_record();
+		writer.checkpoint(covered, chunks);
+		let record_id = writer.record_id();
+		let l = writer.drain();
+		let mut logged_bytes = self.log_queue_wait.work.lock();
+		let bytes = self.log.end_record(l)?;
+		std::mem::drop(log_writer);
+		std::mem::drop(enact_lock);
+		log::debug!(
+			target: "parity-db",
+			"Created checkpoint record {} for records up to {}, {} chunks, {} bytes",
+			record_id,
+			covered,
+			num_chunks,
+			bytes,
+		);
+		*logged_bytes += bytes as i64;
+		if let Some(metrics) = &self.options.metrics {
+			metrics.log_queue_bytes(*logged_bytes);
+			metrics.log_written(0, bytes);
+		}
+		self.flush_worker_wait.signal();
+		Ok(())
 	}
 
-	pub fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
-		self.inner.commit_raw(commit)
+	// Vacuum fragmented tables if `Options::auto_vacuum` is due, a step at a time. During quiet
+	// hours every table with removed entries is vacuumed. Returns `true` if there is more work.
+	fn process_vacuum(&self) -> Result<bool> {
+		let vacuum = match &self.options.auto_vacuum {
+			Some(vacuum) => vacuum,
+			None => return Ok(false),
+		};
+		let now = self.now();
+		let due = {
+			let mut state = self.vacuum.lock();
+			let due = state.jobs.is_empty() &&
+				now.saturating_duration_since(state.last_check) >= vacuum.interval;
+			if due {
+				state.last_check = now;
+			}
+			due
+		};
+		if due {
+			// Logical time starts at midnight.
+			let now = match &self.schedule {
+				Some(schedule) => schedule.lock().clock.as_secs(),
+				None => std::time::SystemTime::now()
+					.duration_since(std::time::UNIX_EPOCH)
+					.map_or(0, |t| t.as_secs()),
+			};
+			let threshold =
+				if vacuum.is_quiet_hour((now / 3600 % 24) as u8) { 0.0 } else { vacuum.threshold };
+			self.queue_vacuum(threshold, vacuum.max_tables);
+		}
+		Ok(self.vacuum_step(self.vacuum_batch())?.is_some())
 	}
 
-	pub fn num_columns(&self) -> u8 {
-		self.inner.columns.len() as u8
+	// Time until the next check of `Options::auto_vacuum`.
+	fn vacuum_delay(&self) -> Option<Duration> {
+		let vacuum = self.options.auto_vacuum.as_ref()?;
+		let last_check = self.vacuum.lock().last_check;
+		Some(vacuum.interval.saturating_sub(self.now().saturating_duration_since(last_check)))
 	}
 
-	pub fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
-		self.inner.iter_column_while(c, f)
+	// Removed entries read or rewritten by the next vacuum step.
+	fn vacuum_batch(&self) -> usize {
+		match &self.schedule {
+			Some(schedule) =>
+				rand::Rng::gen_range(&mut schedule.lock().rng, 1..=self.options.vacuum_batch),
+			None => self.options.vacuum_batch,
+		}
 	}
 
-	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = false;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				db.commit_worker_wait.wait();
-			}
+	// Queue vacuums of up to `max_tables` value tables with at least `threshold` of their
+	// allocated entries removed, most fragmented first. Tables whose removed entries were not
+	// counted yet are queued last, and left as they are if they turn out to be below the
+	// threshold.
+	fn queue_vacuum(&self, threshold: f64, max_tables: usize) {
+		let mut state = self.vacuum.lock();
+		let mut candidates = Vec::new();
+		for (col, column) in self.columns.iter().enumerate() {
+			let col = col as ColId;
+			column.with_value_tables(|tables| {
+				for (tier, table) in tables.iter().enumerate() {
+					let queued = state.jobs.iter().any(|job| job.col == col && job.tier == tier);
+					if queued || !table.needs_vacuum() {
+						continue
+					}
+					let filled = table.filled_entries() as f64;
+					match table.counted_free_entries() {
+						Some(free) if free > 0 && free as f64 >= threshold * filled =>
+							candidates.push((free as f64 / filled, col, tier)),
+						Some(_) => (),
+						None => candidates.push((-1.0, col, tier)),
+					}
+				}
+			});
+		}
+		candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+		candidates.truncate(max_tables);
+		// The lists of removed entries must be consistent when the vacuums start.
+		let _log_writer = self.log_writer.lock();
+		for (_, col, tier) in candidates {
+			let vacuum =
+				self.columns[col as usize].with_value_tables(|tables| tables[tier].start_vacuum());
+			state.jobs.push_back(VacuumJob { col, tier, threshold, vacuum });
+		}
+	}
 
-			more_work = db.enact_logs(false)?;
+	// Do a step of the first queued vacuum. Reads up to `max_entries` of the table's removed
+	// entries, without holding the log writer, or writes a record rewriting up to `max_entries`
+	// of them once they are read. A vacuum is abandoned if the table changes meanwhile. Returns
+	// the number of entries dropped from the end of the table, `None` if there was no vacuum.
+	fn vacuum_step(&self, max_entries: usize) -> Result<Option<u64>> {
+		let mut state = self.vacuum.lock();
+		let job = match state.jobs.front_mut() {
+			Some(job) => job,
+			None => return Ok(None),
+		};
+		let (col, tier) = (job.col, job.tier);
+		let column = &self.columns[col as usize];
+		let changed = |vacuum: &FreeListVacuum| {
+			column.with_value_tables(|tables| tables[tier].free_list_version() != vacuum.version())
+		};
+		if !job.vacuum.is_read() {
+			let read = column.with_value_tables(|tables| {
+				job.vacuum.read(&tables[tier], max_entries, self.log.overlays())
+			});
+			match read {
+				Ok(false) if !changed(&job.vacuum) => return Ok(Some(0)),
+				Ok(true) => {
+					let _log_writer = self.log_writer.lock();
+					if !changed(&job.vacuum) {
+						column.with_value_tables(|tables| tables[tier].read_vacuum(&job.vacuum));
+						let (free, filled) =
+							(job.vacuum.free_entries(), job.vacuum.filled_entries());
+						if job.vacuum.is_needed() && free as f64 >= job.threshold * filled as f64 {
+							return Ok(Some(0))
+						}
+						state.jobs.pop_front();
+						return Ok(Some(0))
+					}
+				},
+				// Errors may be caused by reading a list that changed meanwhile.
+				Err(e) if !changed(&job.vacuum) => return Err(e),
+				_ => (),
+			}
+			log::debug!(target: "parity-db", "Abandoned vacuum of {}-{}, the table changed", col, tier);
+			state.jobs.pop_front();
+			return Ok(Some(0))
 		}
-		log::debug!(target: "parity-db", "Commit worker shutdown");
-		Ok(())
+		let log_writer = self.log_writer.lock();
+		let step = if changed(&job.vacuum) {
+			log::debug!(target: "parity-db", "Abandoned vacuum of {}-{}, the table changed", col, tier);
+			None
+		} else {
+			job.vacuum.plan(max_entries)
+		};
+		let step = match step {
+			Some(step) => step,
+			None => {
+				state.jobs.pop_front();
+				return Ok(Some(0))
+			},
+		};
+		if step.complete {
+			state.jobs.pop_front();
+		}
+		let (record_id, released) = self.write_vacuum_record(log_writer, col, tier, &step)?;
+		if released > 0 {
+			state.pending.push((record_id, col, tier));
+		}
+		Ok(Some(released))
 	}
 
-	fn log_worker(db: Arc<DbInner>) -> Result<()> {
-		// Start with pending reindex.
-		let mut more_work = db.process_reindex()?;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				db.log_worker_wait.wait();
+	// Write a vacuum record for a step of the vacuum of a value table. Returns the id of the
+	// record and the number of entries dropped from the end of the table.
+	fn write_vacuum_record(
+		&self,
+		log_writer: MutexGuard<()>,
+		col: ColId,
+		tier: usize,
+		step: &VacuumStep,
+	) -> Result<(u64, u64)> {
+		if let Some(trace) = &self.trace {
+			trace.vacuum(col, tier as u8, step)?;
+		}
+		let mut writer = self.log.begin_record();
+		let record_id = writer.record_id();
+		let written = (|| {
+			let released = self.columns[col as usize]
+				.with_value_tables(|tables| tables[tier].write_vacuum_step(step, &mut writer));
+			for c in self.columns.iter() {
+				c.complete_plan(&mut writer)?;
 			}
+			Ok((released, self.log.end_record(writer.drain())?))
+		})();
+		let (released, bytes) = match written {
+			Ok(written) => written,
+			Err(e) => {
+				// The record can't be abandoned, same as a failed commit in the log worker.
+				self.store_err(Err(e));
+				self.check_bg_err()?;
+				return Ok((record_id, 0))
+			},
+		};
+		self.account_logged(0, bytes);
+		self.last_logged_record.fetch_max(record_id, Ordering::SeqCst);
+		std::mem::drop(log_writer);
+		self.signal_progress();
+		log::debug!(
+			target: "parity-db",
+			"Vacuum record {} for {}-{}, {} entries released",
+			record_id,
+			col,
+			tier,
+			released,
+		);
+		Ok((record_id, released))
+	}
 
-			let more_commits = db.process_commits()?;
-			let more_reindex = db.process_reindex()?;
-			more_work = more_commits || more_reindex;
+	// Give the free space at the end of tables rewritten by enacted vacuum records back to the
+	// file system.
+	fn release_vacuumed(&self) -> Result<()> {
+		let enacted = self.last_enacted.load(Ordering::SeqCst);
+		let ready: Vec<_> = {
+			let mut state = self.vacuum.lock();
+			let (ready, pending) =
+				state.pending.drain(..).partition(|(record_id, _, _)| *record_id <= enacted);
+			state.pending = pending;
+			ready
+		};
+		if ready.is_empty() {
+			return Ok(())
+		}
+		// Keeps the number of filled entries from changing.
+		let _log_writer = self.log_writer.lock();
+		for (_, col, tier) in ready {
+			self.columns[col as usize]
+				.with_value_tables(|tables| tables[tier].release_free_space())?;
 		}
-		log::debug!(target: "parity-db", "Log worker shutdown");
 		Ok(())
 	}
 
-	fn flush_worker(db: Arc<DbInner>, min_log_size: u64) -> Result<()> {
-		let mut more_work = false;
-		while !db.shutdown.load(Ordering::SeqCst) {
-			if !more_work {
-				db.flush_worker_wait.wait();
+	// Restore index chunks from checkpoint records in the logs queued for replay. Records
+	// covered by the last checkpoint are then validated but not enacted.
+	fn restore_checkpoints(&mut self) -> Result<()> {
+		let mut next_record_id = self.last_enacted.load(Ordering::SeqCst) + 1;
+		let mut covered = 0;
+		self.log.scan_replay_logs(true, |reader| {
+			if reader.record_id() != next_record_id {
+				return Ok(false)
+			}
+			let mut checkpoint = None;
+			if self.validate_record(reader, &mut checkpoint).is_err() {
+				return Ok(false)
+			}
+			next_record_id += 1;
+			if let Some((record_covered, chunks)) = checkpoint {
+				log::debug!(
+					target: "parity-db",
+					"Restoring checkpoint for records up to {}, {} chunks",
+					record_covered,
+					chunks.len(),
+				);
+				for (table, index, chunk) in chunks.iter() {
+					if let Column::Hash(column) = &self.columns[table.col() as usize] {
+						column.write_index_chunk(*table, *index, chunk)?;
+					}
+				}
+				// Restored chunks are not on disk yet.
+				if self.checkpoint_interval().is_some() {
+					let mut state = self.checkpoint.lock();
+					state.dirty.extend(chunks.into_iter().map(|(table, index, _)| (table, index)));
+				}
+				covered = record_covered;
+			}
+			Ok(true)
+		})?;
+		if covered > 0 {
+			self.replay_checkpoint = covered;
+			if let Some(record_id) = self.log.skip_replay_logs(covered) {
+				self.last_enacted.store(record_id - 1, Ordering::SeqCst);
 			}
-			more_work = db.flush_logs(min_log_size)?;
 		}
-		log::debug!(target: "parity-db", "Flush worker shutdown");
 		Ok(())
 	}
 
-	fn cleanup_worker(db: Arc<DbInner>) -> Result<()> {
-		let mut more_work = true;
-		while !db.shutdown.load(Ordering::SeqCst) || more_work {
-			if !more_work {
-				db.cleanup_worker_wait.wait();
+	// Check that the logs queued for replay continue past the `backup` record without gaps.
+	// Replaying records older than the backup is only safe if the newer ones follow.
+	fn check_restore(&self, backup: u64) -> Result<()> {
+		let mut next_record_id = self.last_enacted.load(Ordering::SeqCst) + 1;
+		self.log.scan_replay_logs(true, |reader| {
+			if reader.record_id() != next_record_id ||
+				self.validate_record(reader, &mut None).is_err()
+			{
+				return Ok(false)
 			}
-			more_work = db.clean_logs()?;
+			next_record_id += 1;
+			Ok(true)
+		})?;
+		if next_record_id <= backup {
+			return Err(Error::InvalidInput(format!(
+				"Archived logs end at record {}, before the backup at record {}",
+				next_record_id - 1,
+				backup,
+			)))
 		}
-		log::debug!(target: "parity-db", "Cleanup worker shutdown");
 		Ok(())
 	}
 
-	pub fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<u8>) {
-		self.inner.collect_stats(writer, column)
+	fn track_relocation(&self, cleared: &Cleared) {
+		let mut relocation = self.relocation.lock();
+		if let Some(dirty) = relocation.as_mut() {
+			for (table, index) in cleared.index() {
+				dirty
+					.entry(table.file_name())
+					.or_default()
+					.insert(IndexTable::chunk_region(*index));
+			}
+			for (table, index) in cleared.values() {
+				let column = &self.columns[table.col() as usize];
+				if let Some(size) = column.value_entry_size(table.size_tier()) {
+					let region = (index * size as u64, size as u64);
+					dirty.entry(table.file_name()).or_default().insert(region);
+				}
+			}
+		}
 	}
 
-	pub fn clear_stats(&self, column: Option<u8>) {
-		self.inner.clear_stats(column)
+	fn relocate(&self, path: &std::path::Path, rate_limit: Option<u64>) -> Result<()> {
+		if path.join("metadata").exists() {
+			return Err(Error::InvalidInput(format!(
+				"Relocation path {} already contains a database",
+				path.display()
+			)))
+		}
+		if self.relocated.read().is_some() {
+			return Err(Error::InvalidInput("Database was already relocated".into()))
+		}
+		std::fs::create_dir_all(path)?;
+		{
+			// Start tracking at a record boundary.
+			let _enact_lock = self.enact_lock.lock();
+			*self.relocation.lock() = Some(Default::default());
+		}
+		let result = self.copy_to_relocation(path, rate_limit);
+		*self.relocation.lock() = None;
+		if result.is_err() {
+			*self.relocated.write() = None;
+		}
+		result
 	}
 
-	pub fn dump(&self, check_param: check::CheckOptions) -> Result<()> {
-		if let Some(col) = check_param.column {
-			self.inner.columns[col as usize].dump(&self.inner.log, &check_param, col)?;
-		} else {
-			for (ix, c) in self.inner.columns.iter().enumerate() {
-				c.dump(&self.inner.log, &check_param, ix as ColId)?;
-			}
+	fn copy_to_relocation(&self, path: &std::path::Path, rate_limit: Option<u64>) -> Result<()> {
+		self.check_file_storage()?;
+		use crate::file::{copy_region, sync_file, RateLimit};
+
+		let open_source = |file: &str| std::fs::File::open(self.table_file_path(file));
+		let mut rate = RateLimit::new(rate_limit);
+		// Bulk copy while the database is in use.
+		let mut copied = HashMap::new();
+		for file in self.table_files()? {
+			let mut from = open_source(&file)?;
+			let mut to = std::fs::File::create(path.join(&file))?;
+			let len = from.metadata()?.len();
+			copy_region(&mut from, &mut to, 0, len, &mut rate)?;
+			copied.insert(file, len);
+		}
+		log::debug!(target: "parity-db", "Relocation: copied {} table files", copied.len());
+
+		// Stop accepting commits and let the queued ones reach the tables.
+		{
+			let _queue = self.commit_queue.lock();
+			*self.relocated.write() = Some(path.to_path_buf());
+		}
+		self.wait_for_queued_commits()?;
+
+		// Copy what changed during the bulk copy.
+		let _enact_lock = self.enact_lock.lock();
+		for c in self.columns.iter() {
+			c.flush()?;
+		}
+		let mut dirty = self.relocation.lock().take().unwrap_or_default();
+		let mut unlimited = RateLimit::new(None);
+		let current = self.table_files()?;
+		for file in current.iter() {
+			let mut from = open_source(file)?;
+			let len = from.metadata()?.len();
+			let to = match copied.get(file) {
+				None => {
+					let mut to = std::fs::File::create(path.join(file))?;
+					copy_region(&mut from, &mut to, 0, len, &mut unlimited)?;
+					to
+				},
+				Some(copied_len) => {
+					let mut to =
+						std::fs::OpenOptions::new().read(true).write(true).open(path.join(file))?;
+					to.set_len(len)?;
+					if len > *copied_len {
+						copy_region(
+							&mut from,
+							&mut to,
+							*copied_len,
+							len - copied_len,
+							&mut unlimited,
+						)?;
+					}
+					let mut regions = dirty.remove(file).unwrap_or_default();
+					if (0..self.columns.len())
+						.any(|c| crate::index::TableId::is_file_name(c as ColId, file))
+					{
+						regions.insert(IndexTable::meta_region());
+					}
+					for (offset, region_len) in regions {
+						copy_region(&mut from, &mut to, offset, region_len, &mut unlimited)?;
+					}
+					to
+				},
+			};
+			sync_file(&to)?;
 		}
+		for file in copied.keys().filter(|f| !current.contains(f)) {
+			std::fs::remove_file(path.join(file))?;
+		}
+		crate::file::clone_file(&self.options.path.join("metadata"), &path.join("metadata"))?;
+		log::debug!(
+			target: "parity-db",
+			"Relocated to {}, record {}",
+			path.display(),
+			self.last_enacted.load(Ordering::SeqCst),
+		);
 		Ok(())
 	}
-}
 
-impl Drop for Db {
-	fn drop(&mut self) {
-		if self.join_on_shutdown {
-			self.inner.shutdown();
-			self.log_thread.take().map(|t| t.join());
-			self.flush_thread.take().map(|t| t.join());
-			self.commit_thread.take().map(|t| t.join());
-			self.cleanup_thread.take().map(|t| t.join());
-			if let Err(e) = self.inner.kill_logs() {
-				log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
-			}
+	fn column_info(&self, col: ColId) -> Result<ColumnInfo> {
+		let column = self
+			.columns
+			.get(col as usize)
+			.ok_or_else(|| Error::InvalidInput(format!("Column {} does not exist", col)))?;
+		let options = self.options.columns[col as usize].clone();
+		let kind = match column {
+			Column::Tree(_) => ColumnKind::BTree,
+			Column::Hash(_) if options.uniform => ColumnKind::NoHash,
+			Column::Hash(_) => ColumnKind::Hashed,
+		};
+		let tier_entry_sizes =
+			(0..SIZE_TIERS).filter_map(|tier| column.value_entry_size(tier as u8)).collect();
+		Ok(ColumnInfo {
+			kind,
+			options,
+			version: self.db_version,
+			tier_entry_sizes,
+			index_bits: column.index_bits(),
+		})
+	}
+
+	fn memory_stats(&self) -> MemoryStats {
+		let columns = {
+			let commit_overlay = self.commit_overlay.read();
+			let log_overlays = self.log.overlays().read();
+			self.columns
+				.iter()
+				.enumerate()
+				.map(|(col, column)| ColumnMemoryStats {
+					commit_overlay_bytes: commit_overlay[col].bytes(),
+					log_overlay_bytes: log_overlays.bytes(col as ColId),
+					value_cache_bytes: self.value_cache[col]
+						.as_ref()
+						.map_or(0, |cache| cache.bytes() as u64),
+					bloom_filter_bytes: column.bloom_bytes(),
+					mapped_bytes: column.mapped_bytes(),
+				})
+				.collect()
+		};
+		MemoryStats {
+			columns,
+			commit_queue_bytes: self.commit_queue.lock().bytes as u64,
+			log_buffer_bytes: self.log.buffer_bytes(),
+			index_cache_bytes: self.index_cache.as_ref().map_or(0, |cache| cache.bytes() as u64),
 		}
 	}
-}
 
-pub type IndexedCommitOverlay = HashMap<Key, (u64, Option<Value>), crate::IdentityBuildHasher>;
-pub type BTreeCommitOverlay = BTreeMap<Vec<u8>, (u64, Option<Value>)>;
+	fn column_sizes(&self) -> Result<Vec<ColumnSize>> {
+		let mut sizes = Vec::with_capacity(self.columns.len());
+		for column in self.columns.iter() {
+			let mut size = ColumnSize::default();
+			column.with_value_tables(|tables| -> Result<()> {
+				for table in tables {
+					let free = table.free_entries(self.log.overlays())?;
+					let live = table.filled_entries().saturating_sub(free);
+					size.entries += live;
+					size.live_bytes += live * table.entry_size as u64;
+					size.tier_entries.push(live);
+					size.disk_bytes += table.storage_size()?;
+				}
+				Ok(())
+			})?;
+			if let Column::Hash(column) = column {
+				size.keys = Some(column.key_count(&self.log));
+			}
+			sizes.push(size);
+		}
+		for (col, file) in self.column_files()? {
+			let path = self.options.column_path(col).join(file);
+			sizes[col as usize].disk_bytes += std::fs::metadata(path)?.len();
+		}
+		Ok(sizes)
+	}
 
-pub struct CommitOverlay {
-	indexed: IndexedCommitOverlay,
-	btree_indexed: BTreeCommitOverlay,
-}
+	fn checkpoint(&self, path: &std::path::Path) -> Result<()> {
+		self.prepare_copy(path)?;
+		// Tables are not modified while enactment is paused. Commits are still accepted and
+		// logged, so writers only stall if the log queue fills up in the meantime.
+		let _enact_lock = self.enact_lock.lock();
+		self.copy_tables(path, crate::file::clone_file)?;
+		self.write_backup_record(path)?;
+		log::debug!(
+			target: "parity-db",
+			"Created checkpoint at {}, record {}",
+			path.display(),
+			self.last_enacted.load(Ordering::SeqCst),
+		);
+		Ok(())
+	}
 
-impl CommitOverlay {
-	fn new() -> Self {
-		CommitOverlay { indexed: Default::default(), btree_indexed: Default::default() }
+	fn copy_to(&self, path: &std::path::Path) -> Result<()> {
+		self.prepare_copy(path)?;
+		let _enact_lock = self.enact_lock.lock();
+		self.copy_tables(path, crate::file::copy_file)?;
+		self.verify_copy(path)?;
+		self.write_backup_record(path)?;
+		log::debug!(
+			target: "parity-db",
+			"Copied database to {}, record {}",
+			path.display(),
+			self.last_enacted.load(Ordering::SeqCst),
+		);
+		Ok(())
 	}
 
-	#[cfg(test)]
-	fn is_empty(&self) -> bool {
-		self.indexed.is_empty() && self.btree_indexed.is_empty()
+	// Create the directory for a copy of the database and wait for queued commits to be
+	// enacted, so that the copy includes them.
+	fn prepare_copy(&self, path: &std::path::Path) -> Result<()> {
+		self.check_file_storage()?;
+		let mut metadata_path = path.to_path_buf();
+		metadata_path.push("metadata");
+		if metadata_path.exists() {
+			return Err(Error::InvalidInput(format!(
+				"Path {} already contains a database",
+				path.display()
+			)))
+		}
+		std::fs::create_dir_all(path)?;
+		self.wait_for_queued_commits()
 	}
-}
 
-impl CommitOverlay {
-	fn get_ref(&self, key: &[u8]) -> Option<Option<&Value>> {
-		self.indexed.get(key).map(|(_, v)| v.as_ref())
+	// Copy table files and metadata to `path`. Must be called with `enact_lock` held.
+	fn copy_tables(
+		&self,
+		path: &std::path::Path,
+		copy: impl Fn(&std::path::Path, &std::path::Path) -> Result<()>,
+	) -> Result<()> {
+		for c in self.columns.iter() {
+			c.flush()?;
+		}
+		for file in self.table_files()? {
+			copy(&self.table_file_path(&file), &path.join(&file))?;
+		}
+		copy(&self.options.path.join("metadata"), &path.join("metadata"))
 	}
 
-	fn get(&self, key: &[u8]) -> Option<Option<Value>> {
-		self.get_ref(key).map(|v| v.cloned())
+	// Record the last record applied to the copied tables, so that `Db::restore_to` knows where
+	// to continue. Must be called with `enact_lock` held.
+	fn write_backup_record(&self, path: &std::path::Path) -> Result<()> {
+		write_backup_record(path, self.backup_record_id())
 	}
 
-	fn get_size(&self, key: &[u8]) -> Option<Option<u32>> {
-		self.get_ref(key).map(|res| res.as_ref().map(|b| b.len() as u32))
+	// Last record applied to the tables. Must be called with `enact_lock` held.
+	fn backup_record_id(&self) -> u64 {
+		self.enact_batch
+			.lock()
+			.last_record_id
+			.unwrap_or_else(|| self.last_enacted.load(Ordering::SeqCst))
 	}
 
-	fn btree_get(&self, key: &[u8]) -> Option<Option<&Value>> {
-		self.btree_indexed.get(key).map(|(_, v)| v.as_ref())
+	fn export_snapshot(&self, writer: &mut dyn std::io::Write) -> Result<u64> {
+		self.check_file_storage()?;
+		self.wait_for_queued_commits()?;
+		let _enact_lock = self.enact_lock.lock();
+		for c in self.columns.iter() {
+			c.flush()?;
+		}
+		let mut out = SnapshotWriter::new(writer)?;
+		for file in self.table_files()? {
+			out.write_file(&file, &self.table_file_path(&file))?;
+		}
+		let record_id = self.backup_record_id();
+		out.write_data(BACKUP_RECORD, format!("{}\n", record_id).as_bytes())?;
+		// Last, so that an interrupted import does not leave a database that can be opened.
+		out.write_file("metadata", &self.options.path.join("metadata"))?;
+		let files = out.finish()?;
+		log::debug!(
+			target: "parity-db",
+			"Exported snapshot of {} files, record {}",
+			files,
+			record_id,
+		);
+		Ok(record_id)
 	}
 
-	pub fn btree_next(
-		&self,
-		last_key: &Option<Vec<u8>>,
-		from_seek: bool,
-	) -> Option<(Value, Option<Value>)> {
-		if let Some(key) = last_key.as_ref() {
-			let mut iter = self.btree_indexed.range::<Vec<u8>, _>(key..);
-			if let Some((k, (_, v))) = iter.next() {
-				if from_seek || k != key {
-					return Some((k.clone(), v.clone()))
-				}
-			} else {
-				return None
+	// Check that table files copied to `path` have the same size and header, then open the copy
+	// and compare a sample of its entries with the tables. Must be called with `enact_lock`
+	// held.
+	fn verify_copy(&self, path: &std::path::Path) -> Result<()> {
+		for file in self.table_files()? {
+			let source = self.table_file_path(&file);
+			let dest = path.join(&file);
+			if !same_header(&source, &dest)? {
+				return Err(Error::Corruption(format!("Copy of {} does not match", file)))
 			}
-			iter.next().map(|(k, (_, v))| (k.clone(), v.clone()))
-		} else {
-			self.btree_indexed
-				.range::<Vec<u8>, _>(..)
-				.next()
-				.map(|(k, (_, v))| (k.clone(), v.clone()))
 		}
+		let mut options = self.options.clone();
+		options.path = path.to_path_buf();
+		options.change_archive = None;
+		options.metrics = None;
+		for c in options.columns.iter_mut() {
+			c.path_override = None;
+		}
+		let copy = Db::open_read_only(&options)?;
+		// Read the tables only, the log may contain newer changes.
+		let no_log = crate::log::LogOverlays::default();
+		for (c, column) in self.columns.iter().enumerate() {
+			let stride = (column.filled_entries().0 / COPY_VERIFY_SAMPLES).max(1);
+			let mismatch = |key: &[u8]| {
+				Error::Corruption(format!(
+					"Copied entry {} of column {} does not match",
+					hex::encode(key),
+					c
+				))
+			};
+			let mut n = 0u64;
+			match (column, &copy.inner.columns[c]) {
+				(Column::Hash(column), Column::Hash(copied)) => {
+					let mut result = Ok(());
+					copied.iter_while(&copy.inner.log, |state| {
+						n += 1;
+						if !n.is_multiple_of(stride) {
+							return true
+						}
+						result = match column.get(&state.key, &no_log) {
+							Ok(Some(value)) if value == state.value => Ok(()),
+							Ok(_) => Err(mismatch(&state.key)),
+							Err(e) => Err(e),
+						};
+						result.is_ok()
+					})?;
+					result?;
+				},
+				(Column::Tree(column), Column::Tree(_)) => {
+					let mut iter = copy.inner.btree_iter(c as ColId)?;
+					iter.seek(&[])?;
+					while let Some((key, value)) = iter.next()? {
+						n += 1;
+						if !n.is_multiple_of(stride) {
+							continue
+						}
+						let source =
+							column.with_locked(|btree| BTreeTable::get(&key, &no_log, btree))?;
+						if source.as_ref() != Some(&value) {
+							return Err(mismatch(&key))
+						}
+					}
+				},
+				_ =>
+					return Err(Error::Corruption(format!(
+						"Copied column {} has a different type",
+						c
+					))),
+			}
+		}
+		Ok(())
+	}
+
+	fn shutdown(&self) {
+		self.shutdown.store(true, Ordering::SeqCst);
+		self.log_queue_wait.cv.notify_one();
+		self.flush_worker_wait.signal();
+		self.log_worker_wait.signal();
+		self.commit_worker_wait.signal();
+		self.cleanup_worker_wait.signal();
+		self.sync_worker_wait.signal();
+		if self.disk_full.lock().is_some() {
+			self.log.interrupt_flush();
+		}
+		let _pause = self.pause.work.lock();
+		self.pause.cv.notify_all();
+	}
+
+	// Stop the calling worker here while it is paused.
+	fn pause_point(&self, worker: Worker) {
+		let mut state = self.pause.work.lock();
+		if !state.paused.contains(&worker) {
+			return
+		}
+		log::debug!(target: "parity-db", "Paused {} worker", worker.name());
+		state.parked.insert(worker);
+		self.pause.cv.notify_all();
+		while state.paused.contains(&worker) && !self.shutdown.load(Ordering::SeqCst) {
+			self.pause.cv.wait(&mut state);
+		}
+		state.parked.remove(&worker);
+	}
+
+	// Pause `running` workers and wait until they are stopped. Workers that write the log stop
+	// first, since they may be waiting for the log to be enacted.
+	fn pause_background(&self, running: &[Worker]) {
+		for stage in [[Worker::Log, Worker::Flush], [Worker::Enact, Worker::Cleanup]] {
+			self.pause.work.lock().paused.extend(stage);
+			self.log_worker_wait.signal();
+			self.log_queue_wait.cv.notify_one();
+			self.flush_worker_wait.signal();
+			self.commit_worker_wait.signal();
+			self.cleanup_worker_wait.signal();
+			let mut state = self.pause.work.lock();
+			while stage.iter().any(|w| running.contains(w) && !state.parked.contains(w)) &&
+				!self.shutdown.load(Ordering::SeqCst)
+			{
+				self.pause.cv.wait(&mut state);
+			}
+		}
+	}
+
+	fn resume_background(&self) {
+		let mut state = self.pause.work.lock();
+		state.paused.clear();
+		self.pause.cv.notify_all();
+	}
+
+	fn is_paused(&self) -> bool {
+		!self.pause.work.lock().paused.is_empty()
+	}
+
+	fn kill_logs(&self) -> Result<()> {
+		{
+			if let Some(err) = self.bg_err.lock().as_ref() {
+				// On error the log reader may be left in inconsistent state. So it is important
+				// to no attempt any further log enactment.
+				log::debug!(target: "parity-db", "Shutdown with error state {}", err);
+				return Ok(())
+			}
+		}
+		log::debug!(target: "parity-db", "Processing leftover commits");
+		// Finish logged records and proceed to log and enact queued commits.
+		if !self.enact_all_logs()? {
+			return Ok(())
+		}
+		self.flush_logs(0)?;
+		while self.process_commits()? {}
+		// It takes two flushes to hand the commits just logged over to the reader.
+		for _ in 0..2 {
+			if !self.enact_all_logs()? {
+				return Ok(())
+			}
+			self.flush_logs(0)?;
+		}
+		if !self.enact_all_logs()? {
+			return Ok(())
+		}
+		self.clean_all_logs()?;
+		self.log.kill_logs()?;
+		for c in self.columns.iter() {
+			c.save_bloom()?;
+			c.save_stats()?;
+		}
+		if self.options.stats {
+			let mut path = self.options.path.clone();
+			path.push("stats.txt");
+			match std::fs::File::create(path) {
+				Ok(file) => {
+					let mut writer = std::io::BufWriter::new(file);
+					self.collect_stats(&mut writer, None)
+				},
+				Err(e) => log::warn!(target: "parity-db", "Error creating stats file: {:?}", e),
+			}
+		}
+		Ok(())
+	}
+
+	// Enact the records handed over to the log reader. Returns `false` if enactment is paused
+	// because the disk is full. The remaining records are then kept for replay.
+	fn enact_all_logs(&self) -> Result<bool> {
+		while self.enact_logs(false)? {}
+		if self.disk_full.lock().is_some() {
+			log::warn!(target: "parity-db", "Shutdown with log enactment paused by a full disk");
+			return Ok(false)
+		}
+		Ok(true)
+	}
+
+	fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<ColId>) {
+		if let Some(col) = column {
+			self.columns[col as usize].write_stats(writer);
+		} else {
+			for c in self.columns.iter() {
+				c.write_stats(writer);
+			}
+		}
+	}
+
+	fn clear_stats(&self, column: Option<ColId>) {
+		if let Some(col) = column {
+			self.columns[col as usize].clear_stats();
+		} else {
+			for c in self.columns.iter() {
+				c.clear_stats();
+			}
+		}
+	}
+
+	fn manual_workers(&self) -> bool {
+		self.options.worker_threads == WorkerThreads::Manual
+	}
+
+	// Do a round of the work of all background workers. Returns `true` if there is more work.
+	// Errors are kept like errors of worker threads, so the database can't be used after one.
+	fn tick(&self) -> Result<bool> {
+		self.check_bg_err()?;
+		if self.is_paused() {
+			return Ok(false)
+		}
+		let mut more_work = false;
+		let result = (|| {
+			if self.schedule.is_some() {
+				more_work = self.seeded_tick()?;
+				return Ok(())
+			}
+			let more_commits = self.process_commits()?;
+			let more_reindex = self.process_reindex()?;
+			self.process_checkpoint()?;
+			let more_vacuum = self.process_vacuum()?;
+			let more_bloom = self.rebuild_bloom_filters()?;
+			// A flush would wait for the paused log reader.
+			let more_flush =
+				self.disk_full_delay().is_none() && self.flush_logs(self.options.max_log_size)?;
+			let more_enact = self.enact_logs(false)?;
+			let more_cleanup = self.clean_logs()?;
+			self.release_vacuumed()?;
+			self.periodic_sync()?;
+			more_work = more_commits ||
+				more_reindex || more_vacuum ||
+				more_bloom || more_flush ||
+				more_enact || more_cleanup;
+			Ok(())
+		})();
+		self.store_err(result);
+		self.check_bg_err()?;
+		Ok(more_work)
+	}
+
+	// Background work of a tick with `Options::deterministic_seed`. The same steps as in an
+	// ordinary tick run in an order drawn from the seed, and logs are flushed either right away
+	// or once they reach `Options::max_log_size`, as drawn.
+	fn seeded_tick(&self) -> Result<bool> {
+		let (order, min_log_size) = {
+			let mut schedule = self.schedule.as_ref().expect("Checked by the caller").lock();
+			schedule.clock += LOGICAL_TICK;
+			let mut order: Vec<usize> = (0..10).collect();
+			rand::seq::SliceRandom::shuffle(order.as_mut_slice(), &mut schedule.rng);
+			let flush_now = rand::Rng::gen_bool(&mut schedule.rng, 0.5);
+			(order, if flush_now { 0 } else { self.options.max_log_size })
+		};
+		let mut more_work = false;
+		for step in order {
+			more_work |= match step {
+				0 => self.process_commits()?,
+				1 => self.process_reindex()?,
+				2 => self.process_checkpoint().map(|_| false)?,
+				3 => self.process_vacuum()?,
+				4 => self.rebuild_bloom_filters()?,
+				// Flushing would wait for the log reader, which only moves on in a later step.
+				5 =>
+					self.disk_full_delay().is_none() &&
+						!self.log.flush_waits_for_reader() &&
+						self.flush_logs(min_log_size)?,
+				6 => self.enact_logs(false)?,
+				7 => self.clean_logs()?,
+				8 => self.release_vacuumed().map(|_| false)?,
+				_ => self.periodic_sync().map(|_| false)?,
+			};
+		}
+		Ok(more_work)
+	}
+
+	// Sync the log and flush the tables if it is time to, according to `Options::sync_mode`
+	// and `Options::sync_interval`.
+	fn periodic_sync(&self) -> Result<()> {
+		let now = self.now();
+		if let SyncMode::Periodic(period) = self.options.sync_mode {
+			let mut last_sync = self.last_sync.lock();
+			if now.saturating_duration_since(*last_sync) >= period {
+				self.log.sync()?;
+				*last_sync = now;
+			}
+		}
+		if let Some(interval) = self.options.sync_interval {
+			let mut last_sync = self.last_data_sync.lock();
+			if now.saturating_duration_since(*last_sync) >= interval {
+				self.log.sync()?;
+				for c in self.columns.iter() {
+					c.flush()?;
+				}
+				*last_sync = now;
+			}
+		}
+		Ok(())
+	}
+
+	// Time between checks of `periodic_sync`, if it has anything to do.
+	fn sync_period(&self) -> Option<Duration> {
+		let log_period = match self.options.sync_mode {
+			SyncMode::Periodic(period) => Some(period),
+			_ => None,
+		};
+		log_period.into_iter().chain(self.options.sync_interval).min()
+	}
+
+	// Tick until `done` returns `true`, flushing the log regardless of its size when there is
+	// nothing else to do.
+	fn tick_until(&self, done: impl Fn() -> bool) -> Result<()> {
+		while !done() {
+			if self.is_paused() {
+				return Err(Error::InvalidInput("Background work is paused".into()))
+			}
+			if !self.tick()? {
+				if self.disk_full_delay().is_some() {
+					return Err(std::io::ErrorKind::StorageFull.into())
+				}
+				self.flush_logs(0)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn store_err(&self, result: Result<()>) {
+		if let Err(e) = result {
+			log::warn!(target: "parity-db", "Background worker error: {}", e);
+			let mut err = self.bg_err.lock();
+			if err.is_none() {
+				*err = Some(Arc::new(e));
+				self.shutdown();
+			}
+			self.commit_queue_full_cv.notify_one();
+			self.signal_progress();
+		}
+	}
+
+	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		match &self.columns[c as usize] {
+			Column::Hash(column) => column.iter_while(&self.log, f),
+			Column::Tree(_) => unimplemented!(),
+		}
+	}
+
+	fn bulk_iter_column_while(&self, c: ColId, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
+		let column = match self.columns.get(c as usize) {
+			Some(Column::Hash(column)) => column,
+			Some(Column::Tree(_)) =>
+				return Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => return Err(Error::InvalidInput(format!("No such column {}", c))),
+		};
+		self.wait_for_queued_commits()?;
+		let cache = column.scan_cache();
+		let result = (|| {
+			for shard in column.iter_shards() {
+				// Tables are not modified while enactment is paused, and hold everything
+				// enacted so far. `f` is called with enactment resumed, so that it may commit.
+				let batch = {
+					let _enact_lock = self.enact_lock.lock();
+					let mut batch = Vec::new();
+					column.iter_tables_while(&shard, &cache, |item| {
+						batch.push(item);
+						true
+					})?;
+					batch
+				};
+				for item in batch {
+					if !f(item) {
+						return Ok(())
+					}
+				}
+			}
+			Ok(())
+		})();
+		column.end_scan(&cache);
+		result
+	}
+}
+
+pub struct Db {
+	inner: Arc<DbInner>,
+	commit_thread: Option<std::thread::JoinHandle<()>>,
+	flush_thread: Option<std::thread::JoinHandle<()>>,
+	log_thread: Option<std::thread::JoinHandle<()>>,
+	cleanup_thread: Option<std::thread::JoinHandle<()>>,
+	sync_thread: Option<std::thread::JoinHandle<()>>,
+	join_on_shutdown: bool,
+}
+
+impl Db {
+	pub fn with_columns(path: &std::path::Path, num_columns: ColId) -> Result<Db> {
+		let options = Options::with_columns(path, num_columns);
+		let inner_options = InternalOptions { create: true, ..Default::default() };
+		Self::open_inner(&options, &inner_options)
+	}
+
+	/// Open the database with given options.
+	pub fn open(options: &Options) -> Result<Db> {
+		let inner_options = InternalOptions::default();
+		Self::open_inner(options, &inner_options)
+	}
+
+	/// Create the database using given options.
+	pub fn open_or_create(options: &Options) -> Result<Db> {
+		let inner_options = InternalOptions { create: true, ..Default::default() };
+		Self::open_inner(options, &inner_options)
+	}
+
+	pub fn open_read_only(options: &Options) -> Result<Db> {
+		let inner_options = InternalOptions { read_only: true, ..Default::default() };
+		Self::open_inner(options, &inner_options)
+	}
+
+	fn open_inner(options: &Options, inner_options: &InternalOptions) -> Result<Db> {
+		assert!(options.is_valid());
+		let mut db = DbInner::open(options, inner_options)?;
+		// This needs to be call before log thread: so first reindexing
+		// will run in correct state.
+		db.replay_all_logs()?;
+		let db = Arc::new(db);
+		if inner_options.read_only {
+			return Ok(Db {
+				inner: db,
+				commit_thread: None,
+				flush_thread: None,
+				log_thread: None,
+				cleanup_thread: None,
+				sync_thread: None,
+				join_on_shutdown: inner_options.commit_stages.join_on_shutdown(),
+			})
+		}
+		let start_threads =
+			matches!(inner_options.commit_stages, EnableCommitPipelineStages::Standard) &&
+				options.worker_threads == WorkerThreads::Dedicated;
+		let commit_thread = if start_threads {
+			Some(Self::spawn_worker(&db, Worker::Enact, Self::commit_worker)?)
+		} else {
+			None
+		};
+		let flush_thread = if start_threads {
+			let min_log_size =
+				if matches!(inner_options.commit_stages, EnableCommitPipelineStages::DbFile) {
+					0
+				} else {
+					// Start flushing before the log worker blocks on a full log queue.
+					options.max_log_size.min(db.log_queue_limit as u64 / 2)
+				};
+			Some(Self::spawn_worker(&db, Worker::Flush, move |db| {
+				Self::flush_worker(db, min_log_size)
+			})?)
+		} else {
+			None
+		};
+		let log_thread = if start_threads {
+			Some(Self::spawn_worker(&db, Worker::Log, Self::log_worker)?)
+		} else {
+			None
+		};
+		let cleanup_thread = if start_threads {
+			Some(Self::spawn_worker(&db, Worker::Cleanup, Self::cleanup_worker)?)
+		} else {
+			None
+		};
+		let sync_thread = match db.sync_period() {
+			Some(period) if start_threads =>
+				Some(Self::spawn_worker(&db, Worker::Sync, move |db| {
+					Self::sync_worker(db, period)
+				})?),
+			_ => None,
+		};
+		Ok(Db {
+			inner: db,
+			commit_thread,
+			flush_thread,
+			log_thread,
+			cleanup_thread,
+			sync_thread,
+			join_on_shutdown: inner_options.commit_stages.join_on_shutdown(),
+		})
+	}
+
+	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		self.inner.read_checked(col, key, || self.inner.get(col, key))
+	}
+
+	/// Get the values of `keys` in column `c`, in the same order. Values whose first parts are
+	/// in adjacent value table slots, e.g. written by a bulk import, are read from the file
+	/// together. Keys are looked up one by one with `get` unless `Options::on_corruption` is
+	/// `OnCorruption::Fail`.
+	pub fn get_many<K: AsRef<[u8]>>(&self, c: ColId, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		if self.inner.options.on_corruption != OnCorruption::Fail {
+			return keys.iter().map(|key| self.get(c, key.as_ref())).collect()
+		}
+		self.inner.get_many(c, keys)
+	}
+
+	/// Same as `get`, but the value is read into a buffer that is reused once the returned guard
+	/// is dropped, so that reads don't allocate. Values that are compressed or stored in btree
+	/// columns are still allocated.
+	pub fn get_ref(&self, col: ColId, key: &[u8]) -> Result<Option<ValueGuard<'_>>> {
+		let pool = &self.inner.read_buffers;
+		let mut value = pool.lock().pop().unwrap_or_default();
+		let found = self.inner.read_checked(col, key, || {
+			Ok(self.inner.get_into(col, key, &mut value)?.then_some(()))
+		});
+		match found {
+			Ok(Some(())) => Ok(Some(ValueGuard { value, pool })),
+			result => {
+				release_read_buffer(pool, value);
+				result.map(|_| None)
+			},
+		}
+	}
+
+	/// Same as `get`, but the value is read into `value`, replacing its contents, so that a
+	/// read loop can reuse one buffer. Returns the value size, or `None` if there is no such
+	/// key, in which case `value` is left empty.
+	pub fn get_into(&self, col: ColId, key: &[u8], value: &mut Vec<u8>) -> Result<Option<usize>> {
+		value.clear();
+		let found = self.inner.read_checked(col, key, || {
+			Ok(self.inner.get_into(col, key, value)?.then_some(value.len()))
+		});
+		if !matches!(found, Ok(Some(_))) {
+			value.clear();
+		}
+		found
+	}
+
+	/// Get a reader of the value of `key`. Parts of large values are read from the tables as
+	/// they are consumed, so that they are never held in memory in full. Compressed values and
+	/// values of btree columns or of columns with `ColumnOptions::store_keys` are read at once.
+	///
+	/// The reader does not hold back commits. Reads fail once a value of the column that is
+	/// split into parts is removed or replaced, since the remaining parts may then belong to
+	/// another value. Get a new reader to read the current value.
+	pub fn get_reader(&self, col: ColId, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+		self.inner.read_checked(col, key, || self.inner.get_reader(col, key))
+	}
+
+	/// Get `len` bytes of the value of `key`, starting at `offset`. The result is shorter if
+	/// the value ends before. Only the parts of a large value that overlap the range are read.
+	/// Compressed values, values of btree columns and of columns with
+	/// `ColumnOptions::store_keys` are read in full.
+	pub fn get_range(
+		&self,
+		col: ColId,
+		key: &[u8],
+		offset: u64,
+		len: u64,
+	) -> Result<Option<Value>> {
+		self.inner
+			.read_checked(col, key, || self.inner.get_range(col, key, offset, len))
+	}
+
+	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
+		self.inner.read_checked(col, key, || self.inner.get_size(col, key))
+	}
+
+	/// Get a counter of a column with `ColumnOptions::counters` set. Missing counters are zero.
+	/// See `Operation::Increment`.
+	pub fn get_counter(&self, col: ColId, key: &[u8]) -> Result<i64> {
+		if !self.column_options(col)?.counters {
+			return Err(Error::InvalidInput(format!("Column {} is not a counter column", col)))
+		}
+		match self.get(col, key)? {
+			Some(value) => Ok(i64::from_le_bytes(value.as_slice().try_into().map_err(|_| {
+				Error::Corruption(format!("Bad counter value size in column {}", col))
+			})?)),
+			None => Ok(0),
+		}
+	}
+
+	pub fn iter(&self, col: ColId) -> Result<BTreeIterator<'_>> {
+		self.inner.btree_iter(col)
+	}
+
+	/// Get all values of a key in a column created with `ColumnOptions::multimap`, in byte
+	/// order.
+	pub fn get_values(&self, col: ColId, key: &[u8]) -> Result<Vec<Value>> {
+		let mut iter = self.iter_multimap(col)?;
+		iter.seek(key)?;
+		let mut values = Vec::new();
+		while let Some((k, value)) = iter.next()? {
+			if k != key {
+				break
+			}
+			values.push(value);
+		}
+		Ok(values)
+	}
+
+	/// Iterate over a column created with `ColumnOptions::multimap`. In such columns a change
+	/// with a value adds it to the values of the key, and a removal removes the key with all
+	/// its values.
+	pub fn iter_multimap(&self, col: ColId) -> Result<MultimapIterator<'_>> {
+		if !self.column_options(col)?.multimap {
+			return Err(Error::InvalidInput(format!("Column {} is not a multimap column", col)))
+		}
+		Ok(MultimapIterator::new(self.inner.btree_iter(col)?))
+	}
+
+	pub fn commit<I, K>(&self, tx: I) -> Result<()>
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit(tx).map(|_| ())
+	}
+
+	/// Queue a commit like `commit` and return a handle that can be used to wait until the
+	/// changes are durable.
+	pub fn commit_with_ack<I, K>(&self, tx: I) -> Result<CommitHandle>
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		let commit_id = self.inner.commit(tx)?;
+		Ok(CommitHandle { db: self.inner.clone(), commit_id })
+	}
+
+	pub fn commit_raw(&self, commit: CommitChangeSet) -> Result<()> {
+		self.inner.commit_raw(commit)
+	}
+
+	/// Commit changes like `commit` and wait until they are written to the log. Returns the
+	/// effect of each change, in the order they were passed. Btree columns look up each key
+	/// before it is written, so this is slower than `commit` for them.
+	pub fn commit_with_outcomes<I, K>(&self, tx: I) -> Result<Vec<CommitOutcome>>
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_operations(tx.into_iter().map(|(c, k, v)| match v {
+			Some(v) => (c, Operation::Set(k, v)),
+			None => (c, Operation::Dereference(k)),
+		}))
+	}
+
+	/// Commit operations and wait until they are written to the log, like
+	/// `commit_with_outcomes`. `Operation::InsertIfAbsent`, `Operation::Append` and
+	/// `Operation::Increment` are decided when the commit is written, so no read is needed
+	/// beforehand. Commit hooks see them as regular inserts of the passed value, with the
+	/// increment as an 8 byte little endian value.
+	pub fn commit_operations<I, K>(&self, tx: I) -> Result<Vec<CommitOutcome>>
+	where
+		I: IntoIterator<Item = (ColId, Operation<K, Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_operations(tx)
+	}
+
+	/// Set `key` to a value of `len` bytes read from `reader`. The value is read in full before
+	/// it is written to the log, after all commits queued earlier, so a slow reader does not
+	/// block other writes. It is not held in the commit queue, but like any logged value it
+	/// stays in memory until the log record is enacted. Values are not compressed and commit
+	/// hooks are not called.
+	///
+	/// If `reader` fails or ends early, the error is returned and nothing is written. In
+	/// columns with `ColumnOptions::ref_counted` or `ColumnOptions::preimage` set, existing
+	/// values are handled as with `commit`. Not supported for btree columns.
+	pub fn put_stream(
+		&self,
+		col: ColId,
+		key: &[u8],
+		mut reader: impl std::io::Read,
+		len: u64,
+	) -> Result<()> {
+		self.inner.put_stream(col, key, &mut reader, len)
+	}
+
+	/// Start buffering changes that are committed together. See `Transaction`.
+	pub fn transaction(&self) -> Transaction<'_> {
+		Transaction { db: self, changes: Vec::new(), latest: HashMap::new() }
+	}
+
+	/// Register a hook that is called when a commit is queued and again when it is enacted.
+	/// Keys are passed as given to `commit`. Commits made with `commit_raw` pass hashed keys
+	/// for hash columns. Hooks run on the committing thread and on the commit worker thread,
+	/// so they should not block or commit to the database themselves.
+	pub fn on_commit(&self, hook: CommitHook) {
+		self.inner.commit_hooks.write().push(hook);
+	}
+
+	/// Commit changes and wait until they are applied to the tables. Returns the time spent
+	/// in each stage of the commit pipeline. The log is flushed right after the commit is
+	/// written, so this is slower than `commit` and is meant for diagnostics.
+	pub fn commit_with_timings<I, K>(&self, tx: I) -> Result<CommitTimings>
+	where
+		I: IntoIterator<Item = (ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.inner.commit_with_timings(tx)
+	}
+
+	pub fn num_columns(&self) -> ColId {
+		self.inner.columns.len() as ColId
+	}
+
+	pub fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		self.inner.iter_column_while(c, f)
+	}
+
+	/// Same as `iter_column_while`, for scanning a whole hash column. Queued commits are
+	/// enacted first, then the table files are read without looking up each entry in the log
+	/// overlay, in batches of up to a few thousand entries. Log enactment is paused while a
+	/// batch is read and resumed before its entries are passed to `f`, so `f` may commit. Like
+	/// `iter_column_while` this is not a snapshot: commits made meanwhile may or may not be
+	/// seen, and entries moved by a reindex that starts during the scan may be missed. Fails if
+	/// such a reindex completes before the scan does. Value table pages read by the scan that
+	/// were not in the OS page cache before are dropped from it again, so that a one-off scan
+	/// does not evict the working set.
+	pub fn bulk_iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
+		self.inner.bulk_iter_column_while(c, f)
+	}
+
+	/// Iterate over hash column `c` in parallel, over parts of the index and value tables. Items
+	/// come in no particular order, and like `iter_column_while` this is not a snapshot:
+	/// concurrent commits may or may not be seen. Errors are returned as items.
+	#[cfg(feature = "rayon")]
+	pub fn par_iter(
+		&self,
+		c: ColId,
+	) -> Result<impl rayon::iter::ParallelIterator<Item = Result<IterState>> + '_> {
+		use rayon::iter::{IntoParallelIterator, ParallelIterator};
+		let column = match self.inner.columns.get(c as usize) {
+			Some(Column::Hash(column)) => column,
+			Some(Column::Tree(_)) =>
+				return Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => return Err(Error::InvalidInput(format!("No such column {}", c))),
+		};
+		Ok(column.iter_shards().into_par_iter().flat_map_iter(move |shard| {
+			let mut items = Vec::new();
+			let result = column.iter_shard_while(&shard, &self.inner.log, |state| {
+				items.push(state);
+				true
+			});
+			items.into_iter().map(Ok).chain(result.err().map(Err))
+		}))
+	}
+
+	/// Iterate over the raw index entries of hash column `c` until `f` returns `false`. Meant
+	/// for investigating corruption and key collisions; values are not read.
+	pub fn iter_index_while(&self, c: ColId, f: impl FnMut(IndexEntry) -> bool) -> Result<()> {
+		match self.inner.columns.get(c as usize) {
+			Some(Column::Hash(column)) => column.iter_index_while(&self.inner.log, f),
+			Some(Column::Tree(_)) =>
+				Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => Err(Error::InvalidInput(format!("No such column {}", c))),
+		}
+	}
+
+	/// Iterate over a column created with `ColumnOptions::store_keys`, passing the original
+	/// keys and the values to `f` until it returns `false`.
+	pub fn iter_column_keys_while(
+		&self,
+		c: ColId,
+		mut f: impl FnMut(&[u8], &[u8]) -> bool,
+	) -> Result<()> {
+		if !self.column_options(c)?.store_keys {
+			return Err(Error::InvalidInput(format!("Column {} does not store keys", c)))
+		}
+		let mut result = Ok(());
+		self.inner
+			.iter_column_while(c, |state| match decode_keyed_value(&state.value) {
+				Ok((key, value)) => f(key, value),
+				Err(e) => {
+					result = Err(e);
+					false
+				},
+			})?;
+		result
+	}
+
+	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
+		let mut more_work = false;
+		while !db.shutdown.load(Ordering::SeqCst) || more_work {
+			if !more_work {
+				match db.disk_full_delay() {
+					Some(delay) => db.commit_worker_wait.wait_for(delay),
+					None => db.commit_worker_wait.wait(),
+				}
+			}
+			db.pause_point(Worker::Enact);
+			more_work = db.enact_logs(false)?;
+		}
+		log::debug!(target: "parity-db", "Commit worker shutdown");
+		Ok(())
+	}
+
+	// Start a background worker thread, named and pinned as configured in `Options`.
+	fn spawn_worker(
+		db: &Arc<DbInner>,
+		worker: Worker,
+		f: impl FnOnce(Arc<DbInner>) -> Result<()> + Send + 'static,
+	) -> Result<std::thread::JoinHandle<()>> {
+		let db = db.clone();
+		let cpu = db.options.worker_cpus.get(&worker).copied();
+		let handle = std::thread::Builder::new()
+			.name(format!("{}-{}", db.options.worker_thread_prefix, worker.name()))
+			.spawn(move || {
+				if let Some(cpu) = cpu {
+					pin_thread(cpu);
+				}
+				db.store_err(f(db.clone()))
+			})?;
+		Ok(handle)
+	}
+
+	fn log_worker(db: Arc<DbInner>) -> Result<()> {
+		// Start with pending reindex and bloom filter rebuilds.
+		let mut more_work = db.process_reindex()?;
+		more_work |= db.rebuild_bloom_filters()?;
+		while !db.shutdown.load(Ordering::SeqCst) || more_work {
+			if !more_work {
+				match db.reindex_delay() {
+					// Wake up to continue a throttled reindex.
+					Some(delay) if db.next_reindex.load(Ordering::SeqCst) != 0 =>
+						db.log_worker_wait.wait_for(delay),
+					_ => match db.vacuum_delay() {
+						Some(delay) => db.log_worker_wait.wait_for(delay),
+						None => db.log_worker_wait.wait(),
+					},
+				}
+			}
+
+			db.pause_point(Worker::Log);
+			let more_commits = db.process_commits()?;
+			let more_reindex = db.process_reindex()?;
+			db.process_checkpoint()?;
+			let more_vacuum = !db.shutdown.load(Ordering::SeqCst) && db.process_vacuum()?;
+			let more_bloom = !db.shutdown.load(Ordering::SeqCst) && db.rebuild_bloom_filters()?;
+			more_work = more_commits || more_reindex || more_vacuum || more_bloom;
+		}
+		log::debug!(target: "parity-db", "Log worker shutdown");
+		Ok(())
+	}
+
+	fn flush_worker(db: Arc<DbInner>, min_log_size: u64) -> Result<()> {
+		let mut more_work = false;
+		while !db.shutdown.load(Ordering::SeqCst) {
+			if !more_work {
+				db.flush_worker_wait.wait();
+			}
+			db.pause_point(Worker::Flush);
+			more_work = db.flush_logs(min_log_size)?;
+		}
+		log::debug!(target: "parity-db", "Flush worker shutdown");
+		Ok(())
+	}
+
+	fn cleanup_worker(db: Arc<DbInner>) -> Result<()> {
+		let mut more_work = true;
+		while !db.shutdown.load(Ordering::SeqCst) || more_work {
+			if !more_work {
+				db.cleanup_worker_wait.wait();
+			}
+			db.pause_point(Worker::Cleanup);
+			more_work = db.clean_logs()?;
+			db.release_vacuumed()?;
+		}
+		log::debug!(target: "parity-db", "Cleanup worker shutdown");
+		Ok(())
+	}
+
+	fn sync_worker(db: Arc<DbInner>, period: Duration) -> Result<()> {
+		while !db.shutdown.load(Ordering::SeqCst) {
+			{
+				let mut work = db.sync_worker_wait.work.lock();
+				if !*work {
+					db.sync_worker_wait.cv.wait_for(&mut work, period);
+				}
+				*work = false;
+			}
+			db.periodic_sync()?;
+		}
+		log::debug!(target: "parity-db", "Sync worker shutdown");
+		Ok(())
+	}
+
+	pub fn collect_stats(&self, writer: &mut impl std::io::Write, column: Option<ColId>) {
+		self.inner.collect_stats(writer, column)
+	}
+
+	pub fn clear_stats(&self, column: Option<ColId>) {
+		self.inner.clear_stats(column)
+	}
+
+	/// Store an opaque blob for a column, such as a schema version. It is kept in the metadata
+	/// file and can be checked on open with `Options::column_metadata_check`. Blobs are limited
+	/// to 4KiB.
+	pub fn set_column_metadata(&self, col: ColId, metadata: &[u8]) -> Result<()> {
+		self.column_options(col)?;
+		if metadata.len() > MAX_COLUMN_METADATA_SIZE {
+			return Err(Error::InvalidInput(format!(
+				"Column metadata is limited to {} bytes",
+				MAX_COLUMN_METADATA_SIZE
+			)))
+		}
+		let mut column_metadata = self.inner.column_metadata.write();
+		let mut updated = column_metadata.clone();
+		updated.insert(col, metadata.to_vec());
+		Options::write_column_metadata(&self.inner.options.path, &updated)?;
+		*column_metadata = updated;
+		Ok(())
+	}
+
+	/// Blob stored for a column with `set_column_metadata`.
+	pub fn get_column_metadata(&self, col: ColId) -> Result<Option<Vec<u8>>> {
+		self.column_options(col)?;
+		Ok(self.inner.column_metadata.read().get(&col).cloned())
+	}
+
+	/// Totals of values compressed when written to a hash column, to tell whether compression
+	/// pays off. Only collected with `Options::stats`.
+	pub fn compression_stats(&self, col: ColId) -> Result<CompressionStats> {
+		self.column_options(col)?;
+		Ok(self.inner.columns[col as usize].compression_stats())
+	}
+
+	/// Number of values in a hash column by uncompressed size. Only collected with
+	/// `Options::stats`. Kept in the index file across restarts.
+	pub fn value_size_histogram(&self, col: ColId) -> Result<ValueSizeHistogram> {
+		self.column_options(col)?;
+		Ok(self.inner.columns[col as usize].value_size_histogram())
+	}
+
+	/// Occupancy of the index of hash column `col`, to check how evenly keys are spread over
+	/// index chunks. Reads the whole index.
+	pub fn index_stats(&self, col: ColId) -> Result<IndexStats> {
+		match self.inner.columns.get(col as usize) {
+			Some(Column::Hash(column)) => Ok(column.index_stats(&self.inner.log)),
+			Some(Column::Tree(_)) =>
+				Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => Err(Error::InvalidInput(format!("No such column {}", col))),
+		}
+	}
+
+	/// Number of keys in hash column `col`. Reads the whole index. Commits that are not yet
+	/// written to the log are not counted.
+	pub fn column_len(&self, col: ColId) -> Result<u64> {
+		match self.inner.columns.get(col as usize) {
+			Some(Column::Hash(column)) => Ok(column.key_count(&self.inner.log)),
+			Some(Column::Tree(_)) =>
+				Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => Err(Error::InvalidInput(format!("No such column {}", col))),
+		}
+	}
+
+	/// Estimate of `column_len` for hash column `col`. Reads a fixed number of index chunks
+	/// regardless of the column size, relying on keys being spread evenly over the index.
+	pub fn approximate_column_len(&self, col: ColId) -> Result<u64> {
+		match self.inner.columns.get(col as usize) {
+			Some(Column::Hash(column)) => Ok(column.approximate_key_count(&self.inner.log)),
+			Some(Column::Tree(_)) =>
+				Err(Error::InvalidInput("Not supported for btree columns".into())),
+			None => Err(Error::InvalidInput(format!("No such column {}", col))),
+		}
+	}
+
+	/// Block until all commits made so far are written to the log and fsynced, regardless of
+	/// `Options::sync_mode`.
+	pub fn flush(&self) -> Result<()> {
+		self.inner.flush()
+	}
+
+	/// Create a consistent copy of the database at `path`. Commits queued before the call are
+	/// included. Table files are cloned or copied while log enactment is paused, the database
+	/// stays available for reads and writes.
+	pub fn checkpoint(&self, path: &std::path::Path) -> Result<()> {
+		self.inner.checkpoint(path)
+	}
+
+	/// Copy the database to `path` for offline inspection. Table files and metadata are copied
+	/// without sharing data blocks, then the copy is checked: file headers are compared and a
+	/// sample of entries of each column is read from the copy and compared with the database.
+	/// Commits queued before the call are included; log enactment is paused meanwhile.
+	pub fn copy_to(&self, path: &std::path::Path) -> Result<()> {
+		self.inner.copy_to(path)
+	}
+
+	/// Write a consistent snapshot of the database to `writer` as a single stream of the
+	/// database files, followed by a manifest with their checksums. Commits queued before the
+	/// call are included. Log enactment is paused while the files are streamed, so commits
+	/// stall once the log queue fills up if `writer` is slow. Returns the last commit record
+	/// included, see `restore_to`.
+	pub fn export_snapshot(&self, mut writer: impl std::io::Write) -> Result<u64> {
+		self.inner.export_snapshot(&mut writer)
+	}
+
+	/// Create a database at `path` from a stream written by `export_snapshot`. The files are
+	/// checked against the manifest and only moved in place once the whole stream has been
+	/// read. Files of all columns are placed in `path`. Returns the last commit record
+	/// included in the snapshot.
+	pub fn import_snapshot(reader: impl std::io::Read, path: &std::path::Path) -> Result<u64> {
+		if path.join("metadata").exists() {
+			return Err(Error::InvalidInput(format!(
+				"Path {} already contains a database",
+				path.display()
+			)))
+		}
+		std::fs::create_dir_all(path)?;
+		snapshot::unpack(reader, path)?;
+		read_backup_record(path)
+	}
+
+	/// Bring a backup made with `checkpoint`, `copy_to` or `import_snapshot` forward to the
+	/// state after commit record `record_id`, by replaying the logs archived with
+	/// `Options::archive_logs`.
+	///
+	/// `options.path` is the backup, which is modified in place and must not have been
+	/// opened since it was made, other than by earlier calls to this function. A commit is
+	/// restored in full or not at all, so the result may end before `record_id`. Returns
+	/// the last record restored.
+	pub fn restore_to(options: &Options, record_id: u64) -> Result<u64> {
+		if options.storage.is_some() {
+			return Err(Error::InvalidConfiguration(
+				"Not supported with a custom storage backend".into(),
+			))
+		}
+		let archive = options.archive_logs.as_ref().ok_or_else(|| {
+			Error::InvalidConfiguration("Options::archive_logs is not set".into())
+		})?;
+		let backup = read_backup_record(&options.path)?;
+		if record_id < backup {
+			return Err(Error::InvalidInput(format!(
+				"Backup is already at record {}, past {}",
+				backup, record_id
+			)))
+		}
+		if Log::has_logs(&options.path)? {
+			return Err(Error::InvalidInput(format!(
+				"Backup at {} has been opened",
+				options.path.display()
+			)))
+		}
+		let logs = archived_logs(archive)?;
+		// The first log to replay is the last one starting at or before the next record.
+		let first = logs.iter().rposition(|(first, _)| *first <= backup + 1).ok_or_else(|| {
+			Error::InvalidInput(format!("Archived logs do not contain record {}", backup + 1))
+		})?;
+		for (id, (_, path)) in
+			logs[first..].iter().take_while(|(first, _)| *first <= record_id).enumerate()
+		{
+			crate::file::copy_file(path, &options.path.join(format!("log{}", id)))?;
+		}
+		let mut options = options.clone();
+		options.archive_logs = None;
+		options.change_archive = None;
+		options.commit_trace = None;
+		let inner_options =
+			InternalOptions { restore: Some((backup, record_id)), ..Default::default() };
+		let db = Self::open_inner(&options, &inner_options)?;
+		let restored = db.replay_report().cutoff;
+		std::mem::drop(db);
+		write_backup_record(&options.path, restored)?;
+		log::info!(
+			target: "parity-db",
+			"Restored {} from record {} to {}",
+			options.path.display(),
+			backup,
+			restored,
+		);
+		Ok(restored)
+	}
+
+	/// Create a database at `options.path` by replaying a trace recorded with
+	/// `Options::commit_trace`. The same log records are written in the same order as in the
+	/// traced database, so a database opened with the same column options ends up with the same
+	/// table contents. Background work such as reindexing is only done where it was traced, and
+	/// each record is enacted before the next one is written. Returns the number of replayed
+	/// events.
+	pub fn replay_trace(trace: &std::path::Path, options: &Options) -> Result<u64> {
+		if options.path.join("metadata").exists() {
+			return Err(Error::InvalidInput(format!(
+				"Path {} already contains a database",
+				options.path.display()
+			)))
+		}
+		let mut reader = TraceReader::open(trace)?;
+		let mut options = options.clone();
+		options.salt = Some(reader.salt());
+		options.worker_threads = WorkerThreads::Manual;
+		options.commit_trace = None;
+		options.deterministic_seed = None;
+		options.auto_vacuum = None;
+		options.checkpoint_interval = None;
+		options.reindex_pause = None;
+		options.reindex_io_limit = None;
+		// Commits traced in one record are queued together and written in one record again.
+		options.coalesce_commit_bytes = Some(usize::MAX / 2);
+		options.coalesce_commit_window = None;
+		let db = Self::open_or_create(&options)?;
+		let mut events = 0;
+		while let Some(event) = reader.next()? {
+			db.inner.replay_event(event)?;
+			events += 1;
+		}
+		log::info!(
+			target: "parity-db",
+			"Replayed {} trace events to {}",
+			events,
+			options.path.display(),
+		);
+		Ok(events)
+	}
+
+	/// Copy the database to `path` while it stays in use, then switch writes over.
+	///
+	/// Table files are copied first, throttled to `rate_limit` bytes per second if given.
+	/// Regions written in the meantime are tracked. Then new commits are stopped, queued
+	/// commits are enacted and the changed regions are copied. From then on `commit` fails
+	/// with an error naming the new location, while reads keep being served. The database
+	/// should be reopened at `path`; the original directory is left as it is.
+	pub fn relocate(&self, path: &std::path::Path, rate_limit: Option<u64>) -> Result<()> {
+		self.inner.relocate(path, rate_limit)
+	}
+
+	/// Tombstone slot `index` of value table `tier` in hash column `col` and remove all index
+	/// entries pointing to it. This is a last-resort repair for a single corrupt entry; whatever
+	/// value the slot held is lost, and parts of a multipart value are not freed. The removal is
+	/// logged like a commit, appended to the `repair_journal` file in the database directory and
+	/// enacted before the call returns. A slot that is already free is left as it is, but index
+	/// entries still pointing to it are removed.
+	pub fn force_remove_at(&self, col: ColId, tier: u8, index: u64) -> Result<()> {
+		self.inner.force_remove_at(col, tier, index)
+	}
+
+	/// Do a round of background work: log queued commits, flush and enact the log, and continue
+	/// reindexing. Must be called periodically with `WorkerThreads::Manual`. Returns `true` if
+	/// there is more work to do.
+	pub fn tick(&self) -> Result<bool> {
+		if !self.inner.manual_workers() {
+			return Err(Error::InvalidConfiguration(
+				"Background work is done by worker threads".into(),
+			))
+		}
+		self.inner.tick()
+	}
+
+	/// Stop background work, such as writing commits to the log, enacting the log and
+	/// reindexing, until `resume_background` is called. Returns once all workers are idle, so
+	/// that table and log files are not modified while paused. Commits are still queued until
+	/// the queue is full. Calls that wait for them to be written or enacted block until resumed,
+	/// or fail with `WorkerThreads::Manual`. Periodic log syncs continue.
+	pub fn pause_background(&self) {
+		let mut running = Vec::new();
+		for (thread, worker) in [
+			(&self.log_thread, Worker::Log),
+			(&self.flush_thread, Worker::Flush),
+			(&self.commit_thread, Worker::Enact),
+			(&self.cleanup_thread, Worker::Cleanup),
+		] {
+			if thread.is_some() {
+				running.push(worker);
+			}
+		}
+		self.inner.pause_background(&running)
+	}
+
+	/// Continue background work stopped with `pause_background`.
+	pub fn resume_background(&self) {
+		self.inner.resume_background()
+	}
+
+	/// State of background work. Errors of background workers otherwise only show when calls
+	/// fail, and a pause for lack of disk space when commits block.
+	pub fn health(&self) -> Health {
+		if let Some(err) = &*self.inner.bg_err.lock() {
+			return Health::Failed(err.clone())
+		}
+		if self.inner.disk_full.lock().is_some() {
+			return Health::DiskFull
+		}
+		Health::Healthy
+	}
+
+	/// Start growing the index of hash column `col` to twice its size in the background, e.g.
+	/// ahead of a period of heavy inserts. Returns `false` if the column is already being
+	/// reindexed.
+	pub fn trigger_reindex(&self, col: ColId) -> Result<bool> {
+		self.inner.trigger_reindex(col)
+	}
+
+	/// Moved and total index chunks of the reindex in progress for column `col`, if any.
+	pub fn reindex_progress(&self, col: ColId) -> Option<(u64, u64)> {
+		self.inner.reindex_progress(col)
+	}
+
+	/// Bytes written to the log that are not yet enacted into the tables.
+	pub fn log_backlog_bytes(&self) -> u64 {
+		self.inner.log_backlog_bytes()
+	}
+
+	/// Estimate memory held by the database, by column, e.g. to keep an embedding application
+	/// within a memory budget. Walks the in-memory overlays, so the cost grows with the number
+	/// of pending changes.
+	pub fn memory_stats(&self) -> MemoryStats {
+		self.inner.memory_stats()
+	}
+
+	/// Report space used by each column. This reads table headers, file sizes and the index
+	/// of hash columns, but not the values. The list of removed entries of each value table is
+	/// followed once to count them.
+	pub fn column_sizes(&self) -> Result<Vec<ColumnSize>> {
+		self.inner.column_sizes()
+	}
+
+	/// Remove the lock of the database in `path` if the process that holds it no longer exists,
+	/// e.g. when the lock is kept by a descriptor inherited by a child process. Returns `false`
+	/// if the database is not locked. Fails if the lock belongs to a running process or to
+	/// another host.
+	pub fn break_lock(path: &std::path::Path) -> Result<bool> {
+		crate::lock::break_lock(path)
+	}
+
+	/// Database id, creation time and format version. Lets tools check that they operate on the
+	/// expected database.
+	pub fn info(&self) -> DbInfo {
+		self.inner.info.clone()
+	}
+
+	/// Format version of the database at `path`, read from the metadata without opening the
+	/// database. `None` if there is no database at `path`.
+	pub fn format_version(path: &std::path::Path) -> Result<Option<u32>> {
+		Ok(Options::load_metadata(path)?.map(|meta| meta.version))
+	}
+
+	/// Check if the database at `path` has an older format version than `CURRENT_VERSION`.
+	/// Such databases are still opened, and keep being written in their own format until
+	/// they are converted with `upgrade_format`. Options that need log records of a newer
+	/// format, such as `Options::compress_log`, have no effect on them until then.
+	pub fn needs_migration(path: &std::path::Path) -> Result<bool> {
+		Ok(Self::format_version(path)?.is_some_and(|version| version < CURRENT_VERSION))
+	}
+
+	/// Outcome of the log replay when the database was opened, including where the replay
+	/// stopped if the log ends with a damaged or partially written record.
+	pub fn replay_report(&self) -> ReplayReport {
+		self.inner.replay_report.clone()
+	}
+
+	/// Describe how column `col` is indexed and stored.
+	pub fn column_info(&self, col: ColId) -> Result<ColumnInfo> {
+		self.inner.column_info(col)
+	}
+
+	/// Stream enacted log records starting with `from_record_id`, or with the oldest archived
+	/// record if it is 0. Requires `Options::change_archive`. Records are returned exactly as
+	/// they were written to the log, so a follower can apply them to a replica with the same
+	/// column configuration.
+	pub fn tail_changes(&self, from_record_id: u64) -> Result<ChangeStream> {
+		match self.inner.log.archive() {
+			Some(archive) => archive.stream(from_record_id),
+			None => Err(Error::InvalidConfiguration("Change archive is not enabled".into())),
+		}
+	}
+
+	/// Validate and enact a log record of another database, as returned by `tail_changes` of
+	/// that database. Records must be applied in order, starting with the record that follows
+	/// the last one in this database, so that this database stays a copy of the other one. The
+	/// database must have the same salt and column configuration and must not be written to
+	/// otherwise. Record ids only continue after a restart with `Options::change_archive`.
+	///
+	/// Returns the record id once the record is enacted. Parts of a commit split over several
+	/// records are only logged until the last part is applied. Reads made meanwhile may see a
+	/// record partially enacted.
+	pub fn apply_log_record(&self, record: &[u8]) -> Result<u64> {
+		self.inner.apply_log_record(record)
+	}
+
+	/// Get a fence token for all commits queued so far. Pass it to `wait_enacted` to wait
+	/// until these commits are written to the table files.
+	pub fn read_fence(&self) -> FenceToken {
+		FenceToken { commit_id: self.inner.last_queued_commit() }
+	}
+
+	/// Block until all commits covered by `token` are written to the table files. Processes
+	/// that read the table files directly may then observe them. Later commits may also be
+	/// partially visible, since the writer is not stopped.
+	pub fn wait_enacted(&self, token: FenceToken) -> Result<()> {
+		self.inner.wait_for_commit(token.commit_id, false)
+	}
+
+	/// Write all entries of column `col` to `writer` using the portable export format.
+	/// Commits that are still queued and not yet logged are not included. Columns with
+	/// `ColumnOptions::store_keys` are exported with their original keys.
+	/// Returns the number of exported entries.
+	pub fn export(&self, col: ColId, writer: impl std::io::Write) -> Result<u64> {
+		let options = self.column_options(col)?;
+		let salt = self.inner.options.salt.unwrap_or_default();
+		let compression = Compress::new(options.compression, options.compression_threshold);
+		match &self.inner.columns[col as usize] {
+			Column::Hash(_) => {
+				let key_kind = if options.uniform || options.store_keys {
+					KeyKind::Plain
+				} else {
+					KeyKind::Hashed
+				};
+				let header = ExportHeader { key_kind, salt };
+				let mut out = ExportWriter::new(writer, &header, compression)?;
+				let mut result = Ok(());
+				self.iter_column_while(col, |state| {
+					let rc = if options.ref_counted { Some(state.rc) } else { None };
+					result = if options.store_keys {
+						decode_keyed_value(&state.value)
+							.and_then(|(key, value)| out.write_entry(key, rc, value))
+					} else if options.uniform {
+						let key = unhash_uniform_key(&state.key, &salt, self.inner.db_version);
+						out.write_entry(&key, rc, &state.value)
+					} else {
+						out.write_entry(&state.key, rc, &state.value)
+					};
+					result.is_ok()
+				})?;
+				result?;
+				out.finish()
+			},
+			Column::Tree(_) => self.export_prefix(col, &[], writer),
+		}
+	}
+
+	/// Same as `export`, for the keys of ordered column `col` that start with `prefix`.
+	pub fn export_prefix(
+		&self,
+		col: ColId,
+		prefix: &[u8],
+		writer: impl std::io::Write,
+	) -> Result<u64> {
+		let options = self.column_options(col)?;
+		if !options.btree_index {
+			return Err(Error::InvalidInput("Only supported for btree columns".into()))
+		}
+		let salt = self.inner.options.salt.unwrap_or_default();
+		let compression = Compress::new(options.compression, options.compression_threshold);
+		let header = ExportHeader { key_kind: KeyKind::Plain, salt };
+		let mut out = ExportWriter::new(writer, &header, compression)?;
+		let mut iter = self.iter(col)?;
+		iter.seek(prefix)?;
+		while let Some((key, value)) = iter.next()? {
+			if !key.starts_with(prefix) {
+				break
+			}
+			out.write_entry(&key, None, &value)?;
+		}
+		out.finish()
+	}
+
+	/// Export a hash column in value table slot order. This is faster than `export` for large
+	/// columns, but unless the column stores preimages or keys, most keys can't be fully
+	/// recovered. Such entries are written with the partial key flag and are skipped by `import`.
+	pub fn export_values(&self, col: ColId, writer: impl std::io::Write) -> Result<u64> {
+		let options = self.column_options(col)?;
+		let salt = self.inner.options.salt.unwrap_or_default();
+		let column = match &self.inner.columns[col as usize] {
+			Column::Hash(column) => column,
+			Column::Tree(_) =>
+				return Err(Error::InvalidInput("Not supported for btree columns".into())),
+		};
+		let compression = Compress::new(options.compression, options.compression_threshold);
+		let key_kind =
+			if options.uniform || options.store_keys { KeyKind::Plain } else { KeyKind::Hashed };
+		let header = ExportHeader { key_kind, salt };
+		let mut out = ExportWriter::new(writer, &header, compression)?;
+		let mut result = Ok(());
+		column.iter_values_while(&self.inner.log, |state| {
+			let key = if options.uniform {
+				unhash_uniform_key(&state.key, &salt, self.inner.db_version)
+			} else {
+				state.key
+			};
+			let rc = if options.ref_counted { Some(state.rc) } else { None };
+			result = if options.store_keys {
+				decode_keyed_value(&state.value)
+					.and_then(|(key, value)| out.write_entry(key, rc, value))
+			} else if state.partial_key {
+				out.write_partial_key_entry(&key[KEY_SIZE - PARTIAL_SIZE..], rc, &state.value)
+			} else {
+				out.write_entry(&key, rc, &state.value)
+			};
+			result.is_ok()
+		})?;
+		result?;
+		out.finish()
+	}
+
+	/// Insert all entries from an export stream into column `col`. The column options don't
+	/// have to match the exported column. Entries with a reference count above 2^20 are
+	/// rejected. Returns the number of imported entries.
+	pub fn import(&self, col: ColId, reader: impl std::io::Read) -> Result<u64> {
+		let options = self.column_options(col)?;
+		let salt = self.inner.options.salt.unwrap_or_default();
+		let (mut input, header) = ExportReader::new(reader)?;
+		if header.key_kind == KeyKind::Hashed {
+			if options.btree_index || options.uniform || options.store_keys {
+				return Err(Error::InvalidInput(
+					"Hashed keys can only be imported into a non-uniform hash column that does \
+					 not store keys"
+						.into(),
+				))
+			}
+			if header.salt != salt {
+				return Err(Error::InvalidInput("Exported keys are hashed with another salt".into()))
+			}
+		}
+		let entries = std::iter::from_fn(|| match input.next() {
+			Ok(Some(entry)) if entry.partial_key =>
+				Some(Err(Error::InvalidInput("Exported entry has an incomplete key".into()))),
+			Ok(Some(entry)) => {
+				let key = if header.key_kind == KeyKind::Hashed {
+					match Key::try_from(entry.key.as_slice()) {
+						Ok(key) => ImportKey::Hashed(key),
+						Err(_) => return Some(Err(Error::Corruption("Bad hashed key size".into()))),
+					}
+				} else {
+					ImportKey::Plain(entry.key)
+				};
+				Some(Ok((key, entry.rc.unwrap_or(1), entry.value)))
+			},
+			Ok(None) => None,
+			Err(e) => Some(Err(e)),
+		});
+		self.import_entries(col, entries, |_| ())
+	}
+
+	// Insert `entries` into column `col` with raw commits of up to `IMPORT_BATCH_SIZE` changes.
+	// Reference counts are ignored unless the column is reference counted. Values with plain keys
+	// are stored along with the key if the column has `ColumnOptions::store_keys`. `progress` is
+	// called after each batch with the number of entries imported so far.
+	pub(crate) fn import_entries(
+		&self,
+		col: ColId,
+		entries: impl Iterator<Item = Result<(ImportKey, u32, Value)>>,
+		mut progress: impl FnMut(u64),
+	) -> Result<u64> {
+		let options = self.column_options(col)?;
+		let salt = self.inner.options.salt.unwrap_or_default();
+		let mut commit = CommitChangeSet::default();
+		let mut pending = 0;
+		let mut count = 0;
+		let mut flush = |commit: &mut CommitChangeSet, count| -> Result<()> {
+			self.commit_raw(std::mem::take(commit))?;
+			progress(count);
+			Ok(())
+		};
+		for entry in entries {
+			let (key, rc, value) = entry?;
+			if rc > MAX_IMPORT_REFS {
+				return Err(Error::InvalidInput(format!(
+					"Reference count {} is above the import limit of {}",
+					rc, MAX_IMPORT_REFS
+				)))
+			}
+			let rc = if options.ref_counted { rc } else { 1 };
+			let (key, value) = match key {
+				ImportKey::Plain(key) if options.btree_index => {
+					let changes =
+						commit.btree_indexed.entry(col).or_insert_with(|| BTreeChangeSet::new(col));
+					changes.push(&key, Some(value));
+					count += 1;
+					pending += 1;
+					if pending == IMPORT_BATCH_SIZE {
+						flush(&mut commit, count)?;
+						pending = 0;
+					}
+					continue
+				},
+				ImportKey::Plain(key) => {
+					if options.uniform && key.len() < crate::KEY_SIZE {
+						return Err(Error::InvalidInput(
+							"Key is too short for a uniform column".into(),
+						))
+					}
+					let value =
+						if options.store_keys { encode_keyed_value(&key, &value) } else { value };
+					(hash_key(&key, &salt, options.uniform, self.inner.db_version), value)
+				},
+				ImportKey::Hashed(key) => (key, value),
+			};
+			// Each insert adds a reference. Large counts are spread over several batches, so
+			// that memory use does not depend on them.
+			let mut remaining = rc as usize;
+			while remaining > 0 {
+				let n = remaining.min(IMPORT_BATCH_SIZE - pending);
+				let changes =
+					commit.indexed.entry(col).or_insert_with(|| IndexedChangeSet::new(col));
+				changes.changes.extend((0..n).map(|_| (key, Some(value.clone()))));
+				remaining -= n;
+				pending += n;
+				if pending == IMPORT_BATCH_SIZE {
+					flush(&mut commit, count)?;
+					pending = 0;
+				}
+			}
+			count += 1;
+		}
+		flush(&mut commit, count)?;
+		Ok(count)
+	}
+
+	/// Run the compaction filters from `Options::compaction_filters` over their columns.
+	/// Removed and rewritten entries are committed as regular changes. Returns the number
+	/// of affected entries.
+	pub fn compact(&self) -> Result<u64> {
+		let mut total = 0;
+		for (col, filter) in self.inner.options.compaction_filters.iter() {
+			let count = self.compact_column(*col, filter.as_ref())?;
+			log::debug!(target: "parity-db", "Compacted column {}, {} entries changed", col, count);
+			total += count;
+		}
+		Ok(total)
+	}
+
+	/// Rewrite the list of removed entries of each value table in ascending order, so that new
+	/// values fill the start of the table files, and drop removed entries at the end of the
+	/// tables. Each vacuum record rewrites at most `Options::vacuum_batch` entries, and the
+	/// lists are read without blocking commits, keeping about 20 bytes per removed entry in
+	/// memory. Tables changed by commits meanwhile are left for a later vacuum. The disk space
+	/// past the last entry is given back to the file system once the vacuum records are enacted
+	/// and the log is cleaned up. Returns the number of entries dropped. See
+	/// `Options::auto_vacuum` for vacuuming in the background.
+	pub fn vacuum(&self) -> Result<u64> {
+		self.inner.check_bg_err()?;
+		self.inner.queue_vacuum(0.0, usize::MAX);
+		let mut released = 0;
+		while let Some(step) = self.inner.vacuum_step(self.inner.vacuum_batch())? {
+			released += step;
+		}
+		Ok(released)
+	}
+
+	fn compact_column(&self, col: ColId, filter: &dyn CompactionFilter) -> Result<u64> {
+		let options = self.column_options(col)?;
+		let mut commit = CommitChangeSet::default();
+		let mut pending = 0;
+		let mut count = 0;
+		match &self.inner.columns[col as usize] {
+			Column::Hash(_) => {
+				let salt = self.inner.options.salt.unwrap_or_default();
+				// Changes are committed once the iteration is done. The iteration holds the
+				// column tables lock, which the log worker needs to drain a full commit queue.
+				let mut changes = Vec::new();
+				let mut result = Ok(());
+				self.iter_column_while(col, |state| {
+					let decision = if options.store_keys {
+						match decode_keyed_value(&state.value) {
+							Ok((key, value)) => match filter.filter(key, value) {
+								CompactionDecision::Replace(value) =>
+									CompactionDecision::Replace(encode_keyed_value(key, &value)),
+								decision => decision,
+							},
+							Err(e) => {
+								result = Err(e);
+								return false
+							},
+						}
+					} else if options.uniform {
+						let key = unhash_uniform_key(&state.key, &salt, self.inner.db_version);
+						filter.filter(&key, &state.value)
+					} else {
+						filter.filter(&state.key, &state.value)
+					};
+					match decision {
+						CompactionDecision::Keep => (),
+						CompactionDecision::Remove =>
+							changes.push((state.key, state.rc.max(1), None)),
+						CompactionDecision::Replace(_) if options.ref_counted => {
+							result = Err(Error::InvalidInput(
+								"Values can't be replaced in reference counted columns".into(),
+							));
+							return false
+						},
+						CompactionDecision::Replace(value) =>
+							changes.push((state.key, 1, Some(value))),
+					}
+					true
+				})?;
+				result?;
+				count = changes.len() as u64;
+				for (key, rc, value) in changes {
+					// Each removal drops one reference.
+					for _ in 0..rc {
+						commit
+							.indexed
+							.entry(col)
+							.or_insert_with(|| IndexedChangeSet::new(col))
+							.changes
+							.push((key, value.clone()));
+						pending += 1;
+						if pending == IMPORT_BATCH_SIZE {
+							pending = 0;
+							self.commit_raw(std::mem::take(&mut commit))?;
+						}
+					}
+				}
+			},
+			Column::Tree(_) => {
+				let mut iter = self.iter(col)?;
+				iter.seek(&[])?;
+				while let Some((key, value)) = iter.next()? {
+					let changes =
+						commit.btree_indexed.entry(col).or_insert_with(|| BTreeChangeSet::new(col));
+					match filter.filter(&key, &value) {
+						CompactionDecision::Keep => continue,
+						CompactionDecision::Remove => changes.push(&key, None),
+						CompactionDecision::Replace(value) => changes.push(&key, Some(value)),
+					}
+					count += 1;
+					pending += 1;
+					if pending == IMPORT_BATCH_SIZE {
+						pending = 0;
+						self.commit_raw(std::mem::take(&mut commit))?;
+					}
+				}
+			},
+		}
+		if pending > 0 {
+			self.commit_raw(commit)?;
+		}
+		Ok(count)
+	}
+
+	pub(crate) fn column_options(&self, col: ColId) -> Result<crate::ColumnOptions> {
+		self.inner
+			.options
+			.columns
+			.get(col as usize)
+			.cloned()
+			.ok_or_else(|| Error::InvalidInput(format!("Column {} does not exist", col)))
+	}
+
+	pub fn dump(&self, check_param: check::CheckOptions) -> Result<()> {
+		self.check(check_param).map(|_| ())
+	}
+
+	/// Look up the value of every index entry in hash columns and report those that can't be
+	/// read. Corrupted entries can be removed with `force_remove_at`.
+	pub fn check(&self, check_param: check::CheckOptions) -> Result<check::CheckReport> {
+		let mut report = check::CheckReport::default();
+		if let Some(col) = check_param.column {
+			let column = self
+				.inner
+				.columns
+				.get(col as usize)
+				.ok_or_else(|| Error::InvalidInput(format!("No such column {}", col)))?;
+			column.check(&self.inner.log, &check_param, col, &mut report)?;
+		} else {
+			for (ix, c) in self.inner.columns.iter().enumerate() {
+				c.check(&self.inner.log, &check_param, ix as ColId, &mut report)?;
+			}
+		}
+		Ok(report)
+	}
+}
+
+impl Drop for Db {
+	fn drop(&mut self) {
+		if self.join_on_shutdown {
+			self.inner.shutdown();
+			self.log_thread.take().map(|t| t.join());
+			self.flush_thread.take().map(|t| t.join());
+			self.commit_thread.take().map(|t| t.join());
+			self.cleanup_thread.take().map(|t| t.join());
+			self.sync_thread.take().map(|t| t.join());
+			if let Err(e) = self.inner.kill_logs() {
+				log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
+			}
+		}
+	}
+}
+
+fn write_backup_record(path: &std::path::Path, record_id: u64) -> Result<()> {
+	std::fs::write(path.join(BACKUP_RECORD), format!("{}\n", record_id))?;
+	Ok(())
+}
+
+fn read_backup_record(path: &std::path::Path) -> Result<u64> {
+	let content = std::fs::read_to_string(path.join(BACKUP_RECORD))
+		.map_err(|_| Error::InvalidInput(format!("{} is not a database backup", path.display())))?;
+	content
+		.trim()
+		.parse()
+		.map_err(|_| Error::Corruption(format!("Bad {} file", BACKUP_RECORD)))
+}
+
+// Archived log files in `path` by their first record.
+fn archived_logs(path: &std::path::Path) -> Result<Vec<(u64, std::path::PathBuf)>> {
+	let mut logs = Vec::new();
+	for entry in std::fs::read_dir(path)? {
+		let entry = entry?;
+		if let Some(record_id) = entry
+			.file_name()
+			.to_str()
+			.and_then(|name| name.strip_prefix("log"))
+			.and_then(|id| id.parse::<u64>().ok())
+		{
+			logs.push((record_id, entry.path()));
+		}
+	}
+	logs.sort();
+	Ok(logs)
+}
+
+// Whether two files have the same size and the same first `COPY_HEADER_BYTES`.
+fn same_header(a: &std::path::Path, b: &std::path::Path) -> Result<bool> {
+	use std::io::Read;
+	if std::fs::metadata(a)?.len() != std::fs::metadata(b)?.len() {
+		return Ok(false)
+	}
+	let mut header_a = Vec::with_capacity(COPY_HEADER_BYTES);
+	let mut header_b = Vec::with_capacity(COPY_HEADER_BYTES);
+	std::fs::File::open(a)?
+		.take(COPY_HEADER_BYTES as u64)
+		.read_to_end(&mut header_a)?;
+	std::fs::File::open(b)?
+		.take(COPY_HEADER_BYTES as u64)
+		.read_to_end(&mut header_b)?;
+	Ok(header_a == header_b)
+}
+
+// Pin the calling thread to a CPU core.
+fn pin_thread(cpu: usize) {
+	#[cfg(target_os = "linux")]
+	unsafe {
+		let mut set: libc::cpu_set_t = std::mem::zeroed();
+		libc::CPU_SET(cpu, &mut set);
+		if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+			log::warn!(
+				target: "parity-db",
+				"Failed to pin thread to CPU {}: {}",
+				cpu,
+				std::io::Error::last_os_error(),
+			);
+		}
+	}
+	#[cfg(not(target_os = "linux"))]
+	log::warn!(target: "parity-db", "Pinning threads to CPU {} is not supported", cpu);
+}
+
+pub type IndexedCommitOverlay = HashMap<Key, (u64, Option<Value>), crate::IdentityBuildHasher>;
+pub type BTreeCommitOverlay = BTreeMap<Vec<u8>, (u64, Option<Value>)>;
+
+pub struct CommitOverlay {
+	indexed: IndexedCommitOverlay,
+	btree_indexed: BTreeCommitOverlay,
+}
+
+impl CommitOverlay {
+	fn new() -> Self {
+		CommitOverlay { indexed: Default::default(), btree_indexed: Default::default() }
+	}
+
+	#[cfg(test)]
+	fn is_empty(&self) -> bool {
+		self.indexed.is_empty() && self.btree_indexed.is_empty()
+	}
+
+	// Approximate memory held by the overlay.
+	fn bytes(&self) -> u64 {
+		let value_bytes = |v: &Option<Value>| v.as_ref().map_or(0, |v| v.capacity());
+		let entry = std::mem::size_of::<(u64, Option<Value>)>();
+		let indexed: usize = self
+			.indexed
+			.values()
+			.map(|(_, v)| std::mem::size_of::<Key>() + entry + value_bytes(v))
+			.sum();
+		let btree_indexed: usize = self
+			.btree_indexed
+			.iter()
+			.map(|(k, (_, v))| {
+				std::mem::size_of::<Vec<u8>>() + k.capacity() + entry + value_bytes(v)
+			})
+			.sum();
+		(indexed + btree_indexed) as u64
+	}
+}
+
+impl CommitOverlay {
+	fn get_ref(&self, key: &[u8]) -> Option<Option<&Value>> {
+		self.indexed.get(key).map(|(_, v)| v.as_ref())
+	}
+
+	fn get_size(&self, key: &[u8]) -> Option<Option<u32>> {
+		self.get_ref(key).map(|res| res.as_ref().map(|b| b.len() as u32))
+	}
+
+	fn btree_get(&self, key: &[u8]) -> Option<Option<&Value>> {
+		self.btree_indexed.get(key).map(|(_, v)| v.as_ref())
+	}
+
+	pub fn btree_next(
+		&self,
+		last_key: &Option<Vec<u8>>,
+		from_seek: bool,
+	) -> Option<(Value, Option<Value>)> {
+		if let Some(key) = last_key.as_ref() {
+			let mut iter = self.btree_indexed.range::<Vec<u8>, _>(key..);
+			if let Some((k, (_, v))) = iter.next() {
+				if from_seek || k != key {
+					return Some((k.clone(), v.clone()))
+				}
+			} else {
+				return None
+			}
+			iter.next().map(|(k, (_, v))| (k.clone(), v.clone()))
+		} else {
+			self.btree_indexed
+				.range::<Vec<u8>, _>(..)
+				.next()
+				.map(|(k, (_, v))| (k.clone(), v.clone()))
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct CommitChangeSet {
+	pub indexed: HashMap<ColId, IndexedChangeSet>,
+	pub btree_indexed: HashMap<ColId, BTreeChangeSet>,
+	// Value slots to tombstone, as (column, size tier, index). See `Db::force_remove_at`.
+	pub force_remove: Vec<(ColId, u8, u64)>,
+}
+
+// Key of an entry passed to `Db::import_entries`.
+pub(crate) enum ImportKey {
+	// Key as given to `Db::commit`.
+	Plain(Vec<u8>),
+	// Key of a hash column, already hashed with the salt of the database.
+	Hashed(Key),
+}
+
+pub struct IndexedChangeSet {
+	pub col: ColId,
+	pub changes: Vec<(Key, Option<Value>)>,
+	// Positions in `changes` of inserts that leave existing keys untouched.
+	pub if_absent: HashSet<usize>,
+	// Positions in `changes` of values appended to existing values.
+	pub appends: HashSet<usize>,
+	// Positions in `changes` of counter increments, stored as 8 byte little endian values.
+	pub increments: HashSet<usize>,
+}
+
+impl IndexedChangeSet {
+	pub fn new(col: ColId) -> Self {
+		IndexedChangeSet {
+			col,
+			changes: Default::default(),
+			if_absent: Default::default(),
+			appends: Default::default(),
+			increments: Default::default(),
+		}
+	}
+
+	fn push(&mut self, key: &[u8], v: Option<Value>, options: &Options, db_version: u32) {
+		let salt = options.salt.unwrap_or_default();
+		let column = &options.columns[self.col as usize];
+		let k = hash_key(key, &salt, column.uniform, db_version);
+		let v = if column.store_keys { v.map(|v| encode_keyed_value(key, &v)) } else { v };
+		self.changes.push((k, v));
+	}
+
+	fn copy_to_overlay(
+		&self,
+		overlay: &mut CommitOverlay,
+		record_id: u64,
+		bytes: &mut usize,
+		options: &Options,
+	) {
+		let ref_counted = options.columns[self.col as usize].ref_counted;
+		for (i, (k, v)) in self.changes.iter().enumerate() {
+			*bytes += k.len();
+			*bytes += v.as_ref().map_or(0, |v| v.len());
+			// Don't add removed ref-counted values to overlay. Conditional inserts, appends
+			// and increments are only visible once written.
+			if (!ref_counted || v.is_some()) &&
+				!self.if_absent.contains(&i) &&
+				!self.appends.contains(&i) &&
+				!self.increments.contains(&i)
+			{
+				overlay.indexed.insert(*k, (record_id, v.clone()));
+			}
+		}
+	}
+
+	fn write_plan(
+		&self,
+		column: &Column,
+		writer: &mut crate::log::LogWriter,
+		ops: &mut u64,
+		reindex: &mut bool,
+		begin_chunk: &mut impl FnMut(&mut crate::log::LogWriter, u64) -> Result<()>,
+		mut outcomes: Option<&mut Vec<CommitOutcome>>,
+	) -> Result<()> {
+		let column = match column {
+			Column::Hash(column) => column,
+			Column::Tree(_) => {
+				log::warn!(target: "parity-db", "Skipping unindex commit in indexed column");
+				return Ok(())
+			},
+		};
+		for (i, (key, value)) in self.changes.iter().enumerate() {
+			begin_chunk(writer, 1)?;
+			let (plan, outcome) = match value {
+				Some(value) if self.appends.contains(&i) =>
+					column.write_append_plan(key, value, writer)?,
+				Some(value) if self.increments.contains(&i) => {
+					let delta = i64::from_le_bytes(
+						value.as_slice().try_into().expect("Checked when queued"),
+					);
+					column.write_increment_plan(key, delta, writer)?
+				},
+				_ => column.write_plan(
+					key,
+					value.as_ref().map(|v| v.as_slice()),
+					self.if_absent.contains(&i),
+					writer,
+				)?,
+			};
+			if let PlanOutcome::NeedReindex = plan {
+				// Reindex has triggered another reindex.
+				*reindex = true;
+			}
+			if let Some(outcomes) = outcomes.as_mut() {
+				outcomes.push(outcome);
+			}
+			*ops += 1;
+		}
+		Ok(())
+	}
+
+	fn clean_overlay(&self, overlay: &mut CommitOverlay, record_id: u64) {
+		use std::collections::hash_map::Entry;
+		for (key, _) in self.changes.iter() {
+			if let Entry::Occupied(e) = overlay.indexed.entry(*key) {
+				if e.get().0 == record_id {
+					e.remove_entry();
+				}
+			}
+		}
+	}
+}
+
+/// Verification operation utilities.
+pub mod check {
+	use crate::column::ColId;
+
+	pub enum CheckDisplay {
+		None,
+		Full,
+		Short(u64),
+	}
+
+	pub struct CheckOptions {
+		pub column: Option<ColId>,
+		pub from: Option<u64>,
+		pub bound: Option<u64>,
+		pub display: CheckDisplay,
+	}
+
+	impl CheckOptions {
+		pub fn new(
+			column: Option<ColId>,
+			from: Option<u64>,
+			bound: Option<u64>,
+			display_content: bool,
+			truncate_value_display: Option<u64>,
+		) -> Self {
+			let display = if display_content {
+				match truncate_value_display {
+					Some(t) => CheckDisplay::Short(t),
+					None => CheckDisplay::Full,
+				}
+			} else {
+				CheckDisplay::None
+			};
+			CheckOptions { column, from, bound, display }
+		}
+	}
+
+	/// Index entry whose value could not be read.
+	#[derive(Debug, Clone, PartialEq, Eq)]
+	pub struct CorruptedEntry {
+		pub col: ColId,
+		/// Index chunk holding the entry.
+		pub chunk: u64,
+		/// Value table size tier the entry points to.
+		pub tier: u8,
+		/// Value table slot the entry points to.
+		pub index: u64,
+		/// Read error, or `None` if the slot is free.
+		pub error: Option<String>,
+	}
+
+	/// Result of `Db::check`.
+	#[derive(Debug, Default, Clone, PartialEq, Eq)]
+	pub struct CheckReport {
+		/// Number of index entries checked, including corrupted ones.
+		pub entries: u64,
+		pub corrupted: Vec<CorruptedEntry>,
+	}
+}
+
+#[derive(Default)]
+struct InternalOptions {
+	create: bool,
+	read_only: bool,
+	commit_stages: EnableCommitPipelineStages,
+	skip_check_lock: bool,
+	restore: Option<(u64, u64)>,
+}
+
+// This is used in tests to disable certain commit stages.
+#[derive(Debug, Clone, Copy, Default)]
+enum EnableCommitPipelineStages {
+	// No threads started, data stays in commit overlay.
+	#[allow(dead_code)]
+	CommitOverlay,
+	// Log worker run, data processed up to the log overlay.
+	#[allow(dead_code)]
+	LogOverlay,
+	// Runing all.
+	#[allow(dead_code)]
+	DbFile,
+	// Default run mode.
+	#[default]
+	Standard,
+}
+
+impl EnableCommitPipelineStages {
+	#[cfg(test)]
+	fn run_stages(&self, db: &Db) {
+		let db = &db.inner;
+		match self {
+			EnableCommitPipelineStages::DbFile | EnableCommitPipelineStages::LogOverlay => {
+				while db.process_commits().unwrap() {}
+				while db.process_reindex().unwrap() {}
+			},
+			_ => (),
+		}
+		if let EnableCommitPipelineStages::DbFile = self {
+			let _ = db.log.flush_one(0).unwrap();
+			let _ = db.log.flush_one(0).unwrap();
+			while db.enact_logs(false).unwrap() {}
+			let _ = db.log.flush_one(0).unwrap();
+			let _ = db.clean_logs().unwrap();
+		}
+	}
+
+	#[cfg(test)]
+	fn check_empty_overlay(&self, db: &DbInner, col: ColId) -> bool {
+		match self {
+			EnableCommitPipelineStages::DbFile | EnableCommitPipelineStages::LogOverlay => {
+				if let Some(overlay) = db.commit_overlay.read().get(col as usize) {
+					if !overlay.is_empty() {
+						let mut replayed = 5;
+						while !overlay.is_empty() {
+							if replayed > 0 {
+								replayed -= 1;
+								// the signal is triggered just before cleaning the overlay, so
+								// we wait a bit.
+								// Warning this is still rather flaky and should be ignored
+								// or removed.
+								std::thread::sleep(std::time::Duration::from_millis(100));
+							} else {
+								return false
+							}
+						}
+					}
+				}
+			},
+			_ => (),
+		}
+		true
+	}
+
+	fn join_on_shutdown(&self) -> bool {
+		matches!(self, EnableCommitPipelineStages::Standard)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{
+		Arc, AtomicU64, ColId, Column, ColumnKind, CommitStage, CompactionDecision,
+		CompactionFilter, CompressionStats, Db, Duration, EnableCommitPipelineStages, Error,
+		Health, InternalOptions, OnCorruption, OnDiskFull, Options, Ordering, ReplayStop, SyncMode,
+		Worker, WorkerThreads, CORRUPTION_JOURNAL, REPAIR_JOURNAL,
+	};
+	use crate::options::AutoVacuum;
+	use std::collections::BTreeMap;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_db_open_should_fail() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 5);
+		assert!(Db::open(&options).is_err(), "Database does not exist, so it should fail to open");
+		assert!(Db::open(&options)
+			.map(|_| ())
+			.unwrap_err()
+			.to_string()
+			.contains("use open_or_create"));
+	}
+
+	#[test]
+	fn test_db_open_or_create() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 5);
+		assert!(Db::open_or_create(&options).is_ok(), "New database should be created");
+		assert!(Db::open(&options).is_ok(), "Existing database should be reopened");
+	}
+
+	#[test]
+	fn test_checkpoint() {
+		let tmp = tempdir().unwrap();
+		let checkpoint_dir = tempdir().unwrap();
+		let checkpoint_path = checkpoint_dir.path().join("checkpoint");
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![
+			(0, b"key1".to_vec(), Some(b"value1".to_vec())),
+			(1, b"key2".to_vec(), Some(b"value2".to_vec())),
+		])
+		.unwrap();
+		db.checkpoint(&checkpoint_path).unwrap();
+		// Changes after the checkpoint are not included.
+		db.commit(vec![(0, b"key1".to_vec(), None)]).unwrap();
+		assert!(db.checkpoint(&checkpoint_path).is_err());
+
+		let mut options = options.clone();
+		options.path = checkpoint_path;
+		let checkpoint = Db::open(&options).unwrap();
+		assert_eq!(checkpoint.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(checkpoint.get(1, b"key2").unwrap(), Some(b"value2".to_vec()));
+		assert_eq!(db.get(0, b"key1").unwrap(), None);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let tmp = tempdir().unwrap();
+		let import_dir = tempdir().unwrap();
+		let import_path = import_dir.path().join("import");
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![
+			(0, b"key1".to_vec(), Some(b"value1".to_vec())),
+			(1, b"key2".to_vec(), Some(b"value2".to_vec())),
+		])
+		.unwrap();
+		let mut stream = Vec::new();
+		let record_id = db.export_snapshot(&mut stream).unwrap();
+		// Changes after the export are not included.
+		db.commit(vec![(0, b"key1".to_vec(), None)]).unwrap();
+
+		// Any damage is detected and leaves nothing that can be opened.
+		let mut damaged = stream.clone();
+		let middle = damaged.len() / 2;
+		damaged[middle] ^= 1;
+		assert!(Db::import_snapshot(damaged.as_slice(), &import_path).is_err());
+		assert!(Db::import_snapshot(&stream[..stream.len() - 1], &import_path).is_err());
+		assert!(!import_path.join("metadata").exists());
+
+		assert_eq!(Db::import_snapshot(stream.as_slice(), &import_path).unwrap(), record_id);
+		assert!(Db::import_snapshot(stream.as_slice(), &import_path).is_err());
+		let mut options = options.clone();
+		options.path = import_path;
+		let imported = Db::open(&options).unwrap();
+		assert_eq!(imported.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(imported.get(1, b"key2").unwrap(), Some(b"value2".to_vec()));
+		assert_eq!(db.get(0, b"key1").unwrap(), None);
+	}
+
+	#[cfg(feature = "rayon")]
+	#[test]
+	fn test_par_iter() {
+		use rayon::iter::ParallelIterator;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].preimage = true;
+		options.columns[1].uniform = true;
+		options.columns[2].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let hash = |value: &[u8]| blake2_rfc::blake2b::blake2b(32, &[], value).as_bytes().to_vec();
+		let mut values: Vec<Vec<u8>> = (0..10_000u32).map(|i| i.to_le_bytes().to_vec()).collect();
+		// Multipart values are found in the index.
+		values.push(vec![7; 100_000]);
+		db.commit(values.iter().flat_map(|value| {
+			[(0, hash(value), Some(value.clone())), (1, hash(value), Some(value.clone()))]
+		}))
+		.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+
+		for col in 0..2 {
+			let mut expected = Vec::new();
+			db.iter_column_while(col, |state| {
+				expected.push((state.key, state.value));
+				true
+			})
+			.unwrap();
+			let mut items: Vec<_> = db
+				.par_iter(col)
+				.unwrap()
+				.map(|state| state.map(|s| (s.key, s.value)))
+				.collect::<Result<_, _>>()
+				.unwrap();
+			assert_eq!(items.len(), values.len());
+			expected.sort();
+			items.sort();
+			assert_eq!(items, expected);
+		}
+		assert!(db.par_iter(2).is_err());
+		assert!(db.par_iter(3).is_err());
+	}
+
+	#[test]
+	fn test_get_many() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		options.columns[1].cache_size = 4096;
+		let db = Db::open_or_create(&options).unwrap();
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		let value = |i: u32| vec![i as u8; 10 + i as usize % 3 * 5000];
+		for col in 0..2 {
+			db.commit((0..500).map(|i| (col, key(i), Some(value(i))))).unwrap();
+		}
+		db.inner.wait_for_queued_commits().unwrap();
+		// Changed values in the commit overlay or the log overlay.
+		db.commit(vec![(0, key(10), Some(b"new".to_vec())), (0, key(11), None)])
+			.unwrap();
+		let keys: Vec<_> = (0..520).rev().chain(0..5).map(key).collect();
+		for col in 0..2 {
+			let expected: Vec<_> = keys.iter().map(|k| db.get(col, k).unwrap()).collect();
+			assert_eq!(db.get_many(col, &keys).unwrap(), expected);
+		}
+		assert_eq!(db.get_many(0, &[key(10), key(11)]).unwrap(), vec![Some(b"new".to_vec()), None]);
+	}
+
+	#[test]
+	fn test_bulk_iter() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].preimage = true;
+		options.columns[1].uniform = true;
+		options.columns[2].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let hash = |value: &[u8]| blake2_rfc::blake2b::blake2b(32, &[], value).as_bytes().to_vec();
+		let mut values: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_le_bytes().to_vec()).collect();
+		values.push(vec![7; 100_000]);
+		db.commit(values.iter().flat_map(|value| {
+			[(0, hash(value), Some(value.clone())), (1, hash(value), Some(value.clone()))]
+		}))
+		.unwrap();
+
+		let collect = |db: &Db, col| {
+			let mut items = Vec::new();
+			db.bulk_iter_column_while(col, |state| {
+				if items.is_empty() {
+					// May or may not be seen by the running scan.
+					db.commit([(col, hash(b"late"), Some(b"late".to_vec()))]).unwrap();
+				}
+				items.push((state.key, state.value));
+				true
+			})
+			.unwrap();
+			items.sort();
+			items.dedup();
+			items
+		};
+		for col in 0..2 {
+			let items = collect(&db, col);
+			assert!(items.len() == values.len() || items.len() == values.len() + 1);
+			db.inner.wait_for_queued_commits().unwrap();
+			let mut expected = Vec::new();
+			db.iter_column_while(col, |state| {
+				expected.push((state.key, state.value));
+				true
+			})
+			.unwrap();
+			expected.sort();
+			assert_eq!(expected.len(), values.len() + 1);
+			assert!(expected.iter().all(|item| item.1 == b"late" || items.contains(item)));
+			assert_eq!(collect(&db, col).len(), values.len() + 1);
+		}
+		assert!(db.bulk_iter_column_while(2, |_| true).is_err());
+		assert!(db.bulk_iter_column_while(3, |_| true).is_err());
+		drop(db);
+
+		// Commits from `f` enact logs on the same thread.
+		options.worker_threads = WorkerThreads::Manual;
+		let db = Db::open(&options).unwrap();
+		db.commit([(0, hash(b"late"), None)]).unwrap();
+		let items = collect(&db, 0);
+		assert!(items.len() == values.len() || items.len() == values.len() + 1);
+		assert_eq!(db.get(0, &hash(b"late")).unwrap(), Some(b"late".to_vec()));
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_bulk_iter_page_cache() {
+		use std::os::unix::io::AsRawFd;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.worker_threads = WorkerThreads::Manual;
+		options.columns[1].preimage = true;
+		options.columns[1].uniform = true;
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		let value =
+			|i: u32| if i < 2000 { i.to_le_bytes().repeat(25) } else { vec![i as u8; 100_000] };
+		let hash = |value: &[u8]| blake2_rfc::blake2b::blake2b(32, &[], value).as_bytes().to_vec();
+		let db = Db::open_or_create(&options).unwrap();
+		// Small slots and multipart values, by key and by hash of the value.
+		db.commit(
+			(0u32..2020)
+				.flat_map(|i| [(0, key(i), Some(value(i))), (1, hash(&value(i)), Some(value(i)))]),
+		)
+		.unwrap();
+		while db.tick().unwrap() {}
+		drop(db);
+		// Written back, so that the pages can be dropped.
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let file = std::fs::File::open(entry.unwrap().path()).unwrap();
+			file.sync_all().unwrap();
+			unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+		}
+
+		let db = Db::open(&options).unwrap();
+		let uncached = |db: &Db, col: usize| {
+			db.inner.columns[col].with_value_tables(|tables| {
+				tables
+					.iter()
+					.map(|table| table.residency().map(|r| r.uncached(0, u64::MAX)))
+					.collect::<Vec<_>>()
+			})
+		};
+		for col in 0..2 {
+			let cold = uncached(&db, col);
+			for i in [5u32, 2005] {
+				let key = if col == 0 { key(i) } else { hash(&value(i)) };
+				assert!(db.get(col as ColId, &key).unwrap().is_some());
+			}
+			let hot = uncached(&db, col);
+			assert_ne!(hot, cold);
+			let mut count = 0;
+			db.bulk_iter_column_while(col as ColId, |_| {
+				count += 1;
+				true
+			})
+			.unwrap();
+			assert_eq!(count, 2020);
+			// Pages that were cached before the scan still are, the others are dropped again.
+			assert_eq!(uncached(&db, col), hot);
+		}
+	}
+
+	#[test]
+	fn test_restore_to() {
+		let tmp = tempdir().unwrap();
+		let archive_dir = tempdir().unwrap();
+		let backup_dir = tempdir().unwrap();
+		let backup_path = backup_dir.path().join("backup");
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.archive_logs = Some(archive_dir.path().to_path_buf());
+		options.max_log_size = 1;
+		let db = Db::open_or_create(&options).unwrap();
+		let commit = |key: u8, value: Option<u8>| {
+			db.commit(vec![(0, vec![key], value.map(|v| vec![v; 100]))]).unwrap();
+			db.wait_enacted(db.read_fence()).unwrap();
+			db.inner.last_enacted.load(Ordering::SeqCst)
+		};
+		commit(0, Some(0));
+		db.checkpoint(&backup_path).unwrap();
+		let records: Vec<u64> = (1..=5).map(|i| commit(i, Some(i))).collect();
+		commit(0, None);
+		drop(db);
+		assert!(std::fs::read_dir(archive_dir.path()).unwrap().next().is_some());
+
+		let mut backup = options.clone();
+		backup.path = backup_path;
+		backup.archive_logs = None;
+		assert!(Db::restore_to(&backup, records[2]).is_err());
+		backup.archive_logs = options.archive_logs.clone();
+		assert_eq!(Db::restore_to(&backup, records[1]).unwrap(), records[1]);
+		// Can't go back, but can go further.
+		assert!(Db::restore_to(&backup, records[0]).is_err());
+		assert_eq!(Db::restore_to(&backup, records[2]).unwrap(), records[2]);
+		let db = Db::open(&backup).unwrap();
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(vec![0; 100]));
+		assert_eq!(db.get(0, &[3]).unwrap(), Some(vec![3; 100]));
+		assert_eq!(db.get(0, &[4]).unwrap(), None);
+	}
+
+	#[test]
+	fn test_copy_to() {
+		let tmp = tempdir().unwrap();
+		let copy_dir = tempdir().unwrap();
+		let copy_path = copy_dir.path().join("copy");
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..3000).flat_map(|i| {
+			[
+				(0, i.to_be_bytes().to_vec(), Some(vec![i as u8; 100 + i as usize])),
+				(1, i.to_be_bytes().to_vec(), Some(i.to_le_bytes().to_vec())),
+			]
+		}))
+		.unwrap();
+		db.copy_to(&copy_path).unwrap();
+		assert!(db.copy_to(&copy_path).is_err());
+		db.commit(vec![(0, 1u32.to_be_bytes().to_vec(), None)]).unwrap();
+
+		let mut options = options.clone();
+		options.path = copy_path;
+		let copy = Db::open(&options).unwrap();
+		for i in 0u32..3000 {
+			let key = i.to_be_bytes();
+			assert_eq!(copy.get(0, &key).unwrap(), Some(vec![i as u8; 100 + i as usize]));
+			assert_eq!(copy.get(1, &key).unwrap(), Some(i.to_le_bytes().to_vec()));
+		}
+		assert_eq!(db.get(0, &1u32.to_be_bytes()).unwrap(), None);
+	}
+
+	#[test]
+	fn test_iter_index() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0u32..100).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k.to_vec(), Some(k.to_vec())))).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+
+		let mut entries = Vec::new();
+		db.iter_index_while(0, |e| {
+			entries.push(e);
+			true
+		})
+		.unwrap();
+		assert_eq!(entries.len(), keys.len());
+		let column = match &db.inner.columns[0] {
+			Column::Hash(column) => column,
+			Column::Tree(_) => unreachable!(),
+		};
+		let index_bits = entries[0].index_bits;
+		let prefix_bits = 64 - crate::index::Entry::address_bits(index_bits) + index_bits;
+		let mut expected: Vec<_> = keys
+			.iter()
+			.map(|k| {
+				let hash = column.hash_key(k);
+				u64::from_be_bytes(hash[0..8].try_into().unwrap()) >> (64 - prefix_bits)
+			})
+			.collect();
+		let mut found: Vec<_> = entries
+			.iter()
+			.map(|e| {
+				assert_eq!(e.raw >> crate::index::Entry::address_bits(index_bits), e.partial_key);
+				assert_eq!(e.size_tier, 0);
+				u64::from_be_bytes(e.key_prefix) >> (64 - prefix_bits)
+			})
+			.collect();
+		expected.sort();
+		found.sort();
+		assert_eq!(found, expected);
+
+		let mut visited = 0;
+		db.iter_index_while(0, |_| {
+			visited += 1;
+			visited < 10
+		})
+		.unwrap();
+		assert_eq!(visited, 10);
+		assert!(db.iter_index_while(1, |_| true).is_err());
+	}
+
+	#[test]
+	fn test_index_stats() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let empty = db.index_stats(0).unwrap();
+		assert_eq!(empty.entries, 0);
+		assert_eq!(empty.average_chunk_entries(), 0.0);
+
+		db.commit((0u32..10000).map(|i| (0, i.to_le_bytes().to_vec(), Some(vec![1]))))
+			.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		let stats = db.index_stats(0).unwrap();
+		assert_eq!(stats.index_bits, 16);
+		assert_eq!(stats.chunks(), 1 << 16);
+		assert_eq!(stats.entries, 10000);
+		assert_eq!(stats.reindex_entries, 0);
+		assert_eq!(stats.full_chunks, 0);
+		let filled: u64 = stats.chunk_histogram.iter().enumerate().map(|(n, c)| n as u64 * c).sum();
+		assert_eq!(filled, 10000);
+		assert!(stats.max_chunk_entries >= 1 && stats.max_chunk_entries < 10);
+		assert_eq!(stats.load_factor(), 10000.0 / (64 << 16) as f64);
+		assert!(db.index_stats(1).is_err());
+	}
+
+	#[test]
+	fn test_max_overlay_bytes() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		options.max_overlay_bytes = Some(256 * 1024);
+		let db = Db::open_or_create(&options).unwrap();
+		for i in 0u32..200 {
+			db.commit(vec![(0, i.to_le_bytes().to_vec(), Some(vec![i as u8; 10000]))])
+				.unwrap();
+			// At most one commit over the limit.
+			assert!(db.inner.overlay_bytes() <= 256 * 1024 + 20000);
+		}
+		for i in 0u32..200 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 10000]));
+		}
+		drop(db);
+
+		// Worker threads are held back by the smaller queues.
+		options.worker_threads = WorkerThreads::Dedicated;
+		let db = Db::open(&options).unwrap();
+		for i in 200u32..400 {
+			db.commit(vec![(0, i.to_le_bytes().to_vec(), Some(vec![i as u8; 10000]))])
+				.unwrap();
+		}
+		db.inner.wait_for_queued_commits().unwrap();
+		for i in 0u32..400 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 10000]));
+		}
+	}
+
+	#[test]
+	fn test_max_total_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_total_size = Some(1024);
+		options.total_size_grace = u64::MAX;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert!(db.inner.total_size() > 1024);
+		assert!(matches!(db.commit([(0, b"b", Some(b"2".to_vec()))]), Err(Error::QuotaExceeded)));
+		assert!(matches!(db.put_stream(0, b"b", &b"2"[..], 1), Err(Error::QuotaExceeded)));
+		// Removals are accepted within the grace.
+		db.commit([(0, b"a", None)]).unwrap();
+		drop(db);
+
+		options.total_size_grace = 0;
+		let db = Db::open(&options).unwrap();
+		assert!(matches!(db.commit([(0, b"a", None)]), Err(Error::QuotaExceeded)));
+		drop(db);
+		options.max_total_size = None;
+		let db = Db::open(&options).unwrap();
+		db.commit([(0, b"b", Some(b"2".to_vec()))]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+	}
+
+	#[test]
+	fn test_vacuum() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		let filled = |db: &Db| {
+			db.inner.columns[0]
+				.with_value_tables(|tables| tables.iter().map(|t| t.filled_entries()).sum::<u64>())
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..50).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		db.commit((50u32..100).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		// Removed entries at the end of the table and in between.
+		db.commit((0u32..100).filter(|i| *i >= 50 || i % 2 == 0).map(|i| (0, key(i), None)))
+			.unwrap();
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), 100);
+		assert_eq!(db.vacuum().unwrap(), 50);
+		assert_eq!(db.vacuum().unwrap(), 0);
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), 50);
+		for i in 0u32..100 {
+			let expected = (i < 50 && i % 2 == 1).then(|| vec![i as u8; 100]);
+			assert_eq!(db.get(0, &key(i)).unwrap(), expected);
+		}
+		// Removed entries are reused.
+		db.commit((100u32..125).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), 50);
+		drop(db);
+
+		options.auto_vacuum =
+			Some(AutoVacuum { interval: Duration::ZERO, threshold: 0.5, ..Default::default() });
+		let db = Db::open(&options).unwrap();
+		assert_eq!(filled(&db), 50);
+		assert_eq!(db.get(0, &key(100)).unwrap(), Some(vec![100; 100]));
+		// Below the threshold.
+		db.commit((0u32..20).map(|i| (0, key(i * 2 + 1), None))).unwrap();
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), 50);
+		// The whole table is dropped once the rest is removed.
+		db.commit((20u32..25).map(|i| (0, key(i * 2 + 1), None))).unwrap();
+		db.commit((100u32..125).map(|i| (0, key(i), None))).unwrap();
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), 0);
+		db.commit([(0, key(1), Some(vec![1; 100]))]).unwrap();
+		while db.tick().unwrap() {}
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(filled(&db), 1);
+		assert_eq!(db.get(0, &key(1)).unwrap(), Some(vec![1; 100]));
+		assert_eq!(db.get(0, &key(100)).unwrap(), None);
+	}
+
+	#[test]
+	fn test_vacuum_steps() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		options.vacuum_batch = 4;
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		let filled = |db: &Db| {
+			db.inner.columns[0]
+				.with_value_tables(|tables| tables.iter().map(|t| t.filled_entries()).sum::<u64>())
+		};
+		let needs_vacuum = |db: &Db| {
+			db.inner.columns[0].with_value_tables(|tables| tables.iter().any(|t| t.needs_vacuum()))
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0u32..100).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		db.commit((0u32..100).filter(|i| *i >= 50 || i % 2 == 0).map(|i| (0, key(i), None)))
+			.unwrap();
+		while db.tick().unwrap() {}
+
+		// Reading the list is abandoned once a commit changes it.
+		db.inner.queue_vacuum(0.0, usize::MAX);
+		assert_eq!(db.inner.vacuum_step(4).unwrap(), Some(0));
+		db.commit([(0, key(1), None)]).unwrap();
+		while db.tick().unwrap() {}
+		let record = db.inner.last_logged_record.load(Ordering::SeqCst);
+		assert_eq!(db.inner.vacuum_step(4).unwrap(), Some(0));
+		assert_eq!(db.inner.vacuum_step(4).unwrap(), None);
+		assert_eq!(db.inner.last_logged_record.load(Ordering::SeqCst), record);
+		assert!(needs_vacuum(&db));
+
+		// Every step leaves a valid list, which commits keep using when they abandon the vacuum.
+		let before = filled(&db);
+		let mut released = 0;
+		db.inner.queue_vacuum(0.0, usize::MAX);
+		while db.inner.last_logged_record.load(Ordering::SeqCst) < record + 3 {
+			released += db.inner.vacuum_step(4).unwrap().unwrap();
+		}
+		db.commit((100u32..110).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		while db.tick().unwrap() {}
+		assert_eq!(db.inner.vacuum_step(4).unwrap(), Some(0));
+		assert_eq!(db.inner.vacuum_step(4).unwrap(), None);
+		assert!(needs_vacuum(&db));
+
+		// Records of at most `vacuum_batch` entries.
+		let record = db.inner.last_logged_record.load(Ordering::SeqCst);
+		released += db.vacuum().unwrap();
+		assert!(db.inner.last_logged_record.load(Ordering::SeqCst) >= record + 10);
+		assert!(!needs_vacuum(&db));
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), before - released);
+		for i in 0u32..110 {
+			let expected =
+				(i >= 100 || (i < 50 && i % 2 == 1 && i != 1)).then(|| vec![i as u8; 100]);
+			assert_eq!(db.get(0, &key(i)).unwrap(), expected);
+		}
+		// All removed entries are reused before the table grows. 34 values and the header.
+		let free = filled(&db) - 35;
+		assert!(free > 0);
+		db.commit((200..200 + free as u32).map(|i| (0, key(i), Some(vec![i as u8; 100]))))
+			.unwrap();
+		while db.tick().unwrap() {}
+		assert_eq!(filled(&db), before - released);
+		assert!(!needs_vacuum(&db));
+	}
+
+	#[test]
+	fn test_replay_trace() {
+		let tmp = tempdir().unwrap();
+		let trace = tmp.path().join("trace");
+		let mut options = Options::with_columns(&tmp.path().join("db"), 2);
+		options.worker_threads = WorkerThreads::Manual;
+		options.stats = false;
+		options.columns[1].btree_index = true;
+		options.commit_trace = Some(trace.clone());
+		options.coalesce_commit_bytes = Some(1024 * 1024);
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		let db = Db::open_or_create(&options).unwrap();
+		// Coalesced in a single record.
+		db.commit((0u32..100).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		db.commit((0u32..100).map(|i| (1, key(i), Some(vec![i as u8; 10])))).unwrap();
+		while db.tick().unwrap() {}
+		assert!(db.trigger_reindex(0).unwrap());
+		db.commit((100u32..150).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		db.commit((0u32..150).filter(|i| i % 3 == 0).map(|i| (0, key(i), None)))
+			.unwrap();
+		db.commit((0u32..50).map(|i| (1, key(i), None))).unwrap();
+		while db.tick().unwrap() {}
+		assert!(db.vacuum().unwrap() > 0);
+		db.put_stream(0, &key(1000), &[7u8; 5000][..], 5000).unwrap();
+		assert!(db.put_stream(0, &key(1001), &[7u8; 10][..], 5000).is_err());
+		// Enact and release the vacuumed space before closing.
+		db.inner.flush_logs(0).unwrap();
+		db.inner.flush_logs(0).unwrap();
+		while db.tick().unwrap() {}
+		drop(db);
+
+		let mut replay_options = options.clone();
+		replay_options.path = tmp.path().join("replay");
+		replay_options.commit_trace = None;
+		assert!(Db::replay_trace(&trace, &replay_options).unwrap() > 5);
+		assert!(Db::replay_trace(&trace, &replay_options).is_err());
+		let files = |path: &std::path::Path| {
+			let mut files = BTreeMap::new();
+			for entry in std::fs::read_dir(path).unwrap() {
+				let name = entry.unwrap().file_name().into_string().unwrap();
+				if name.starts_with("index_") || name.starts_with("table_") {
+					files.insert(name.clone(), std::fs::read(path.join(&name)).unwrap());
+				}
+			}
+			files
+		};
+		let (traced, replayed) = (files(&options.path), files(&replay_options.path));
+		assert!(traced.keys().any(|name| name.starts_with("index_00_17")));
+		assert_eq!(traced.keys().collect::<Vec<_>>(), replayed.keys().collect::<Vec<_>>());
+		for (name, content) in traced.iter() {
+			assert!(replayed[name] == *content, "{} differs", name);
+		}
+		options.commit_trace = None;
+		let db = Db::open(&options).unwrap();
+		let replayed = Db::open(&replay_options).unwrap();
+		for i in 0u32..150 {
+			assert_eq!(db.get(0, &key(i)).unwrap(), replayed.get(0, &key(i)).unwrap());
+			assert_eq!(db.get(1, &key(i)).unwrap(), replayed.get(1, &key(i)).unwrap());
+		}
+		assert_eq!(replayed.get(0, &key(1000)).unwrap(), Some(vec![7; 5000]));
+		assert_eq!(replayed.get(0, &key(1001)).unwrap(), None);
+	}
+
+	#[test]
+	fn test_deterministic_seed() {
+		let tmp = tempdir().unwrap();
+		let run = |name: &str, seed: u64| {
+			let mut options = Options::with_columns(&tmp.path().join(name), 1);
+			options.worker_threads = WorkerThreads::Manual;
+			options.deterministic_seed = Some(seed);
+			options.salt = Some([0; 32]);
+			options.reindex_batch = 64;
+			options.reindex_pause = Some(Duration::from_millis(3));
+			options.commit_trace = Some(tmp.path().join(format!("{}.trace", name)));
+			let db = Db::open_or_create(&options).unwrap();
+			for i in 0u32..20 {
+				db.commit((0..50).map(|k| (0, (i * 50 + k).to_le_bytes().to_vec(), Some(vec![1]))))
+					.unwrap();
+				if i == 5 {
+					db.trigger_reindex(0).unwrap();
+				}
+				db.tick().unwrap();
+			}
+			db.flush().unwrap();
+			while db.tick().unwrap() {}
+			assert_eq!(db.get(0, &999u32.to_le_bytes()).unwrap(), Some(vec![1]));
+			drop(db);
+			std::fs::read(options.commit_trace.unwrap()).unwrap()
+		};
+		let trace = run("first", 1);
+		assert_eq!(run("second", 1), trace);
+		assert_ne!(run("third", 2), trace);
+
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.deterministic_seed = Some(1);
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_multipart_values() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let values: Vec<Vec<u8>> = (0u32..4)
+			.map(|i| (0..1_000_000 + i * 5000).map(|b| (b * (i + 1)) as u8).collect())
+			.collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(values.iter().enumerate().map(|(i, v)| (0, vec![i as u8], Some(v.clone()))))
+				.unwrap();
+			// Replace with a shorter chain.
+			db.commit(vec![(0, vec![0], Some(values[1][..500_000].to_vec()))]).unwrap();
+			db.inner.wait_for_queued_commits().unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(values[1][..500_000].to_vec()));
+		for (i, value) in values.iter().enumerate().skip(1) {
+			assert_eq!(db.get(0, &[i as u8]).unwrap().as_ref(), Some(value));
+		}
+	}
+
+	#[test]
+	fn test_read_fence() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let empty = db.read_fence();
+		db.wait_enacted(empty).unwrap();
+		db.commit(vec![(0, b"key1".to_vec(), Some(b"value1".to_vec()))]).unwrap();
+		let token = db.read_fence();
+		assert!(token > empty);
+		db.wait_enacted(token).unwrap();
+		// Read the table files only, bypassing the log.
+		let column = match &db.inner.columns[0] {
+			crate::column::Column::Hash(column) => column,
+			crate::column::Column::Tree(_) => unreachable!(),
+		};
+		let value = column
+			.get(&column.hash_key(b"key1"), &crate::log::LogOverlays::default())
+			.unwrap();
+		assert_eq!(value, Some(b"value1".to_vec()));
+	}
+
+	#[test]
+	fn test_commit_timings() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let timings = db
+			.commit_with_timings(vec![(0, b"key1".to_vec(), Some(b"value1".to_vec()))])
+			.unwrap();
+		assert!(timings.log_append > Duration::default());
+		assert!(timings.enactment > Duration::default());
+		assert_eq!(
+			db.inner.last_enacted.load(Ordering::SeqCst),
+			db.inner.last_logged_record.load(Ordering::SeqCst)
+		);
+		assert!(db.inner.commit_timings.lock().is_empty());
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+	}
+
+	#[test]
+	fn test_column_sizes() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![
+			(0, b"key1".to_vec(), Some(vec![1; 100])),
+			(0, b"key2".to_vec(), Some(vec![2; 100])),
+			(1, b"key3".to_vec(), Some(vec![3; 100])),
+		])
+		.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		let sizes = db.column_sizes().unwrap();
+		assert_eq!(sizes.len(), 3);
+		assert_eq!(sizes[0].entries, 2);
+		assert_eq!(sizes[0].tier_entries.iter().sum::<u64>(), 2);
+		assert!(sizes[0].live_bytes >= 200);
+		assert!(sizes[0].disk_bytes >= sizes[0].live_bytes);
+		// Btree nodes are stored in the value tables as well.
+		assert!(sizes[1].entries > 1);
+		assert_eq!(sizes[1].keys, None);
+		assert_eq!((sizes[2].entries, sizes[2].live_bytes), (0, 0));
+		assert_eq!(sizes[0].keys, Some(2));
+
+		// Removed entries are not counted as live data.
+		db.commit(vec![(0, b"key2".to_vec(), None)]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		let removed = db.column_sizes().unwrap();
+		assert_eq!(removed[0].entries, 1);
+		assert_eq!(removed[0].keys, Some(1));
+		assert_eq!(removed[0].live_bytes * 2, sizes[0].live_bytes);
+		assert_eq!(removed[0].disk_bytes, sizes[0].disk_bytes);
+	}
+
+	#[test]
+	fn test_column_info() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].btree_index = true;
+		options.columns[2].uniform = true;
+		options.columns[2].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+
+		let info = db.column_info(0).unwrap();
+		assert_eq!(info.kind, ColumnKind::Hashed);
+		assert_eq!(info.index_bits, Some(16));
+		assert_eq!(info.tier_entry_sizes.len(), 256);
+		assert_eq!(info.tier_entry_sizes[0], 32);
+		assert_eq!(info.tier_entry_sizes[255], 4096);
+		assert_eq!(info.version, crate::options::CURRENT_VERSION);
+		let info = db.column_info(1).unwrap();
+		assert_eq!((info.kind, info.index_bits), (ColumnKind::BTree, None));
+		let info = db.column_info(2).unwrap();
+		assert_eq!(info.kind, ColumnKind::NoHash);
+		assert!(info.options.ref_counted);
+		assert!(matches!(db.column_info(3), Err(Error::InvalidInput(_))));
+	}
+
+	#[test]
+	fn test_compaction_filter() {
+		struct DropOdd;
+		impl CompactionFilter for DropOdd {
+			fn filter(&self, _key: &[u8], value: &[u8]) -> CompactionDecision {
+				match value[0] {
+					v if v % 2 == 1 => CompactionDecision::Remove,
+					0 => CompactionDecision::Replace(b"zero".to_vec()),
+					_ => CompactionDecision::Keep,
+				}
+			}
+		}
+
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[0].uniform = true;
+		options.columns[1].btree_index = true;
+		options.columns[2].ref_counted = true;
+		for col in 0..3 {
+			options.compaction_filters.insert(col, Arc::new(DropOdd));
+		}
+		let db = Db::open_or_create(&options).unwrap();
+		let key = |i: u8| [i; 32].to_vec();
+		db.commit((0..4u8).flat_map(|i| {
+			vec![
+				(0, key(i), Some(vec![i])),
+				(1, key(i), Some(vec![i])),
+				(2, key(i), Some(vec![i + 1])),
+			]
+		}))
+		.unwrap();
+		// Two references.
+		db.commit(vec![(2, key(0), Some(vec![1]))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert_eq!(db.compact().unwrap(), 8);
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		for col in 0..2 {
+			assert_eq!(db.get(col, &key(0)).unwrap(), Some(b"zero".to_vec()));
+			assert_eq!(db.get(col, &key(1)).unwrap(), None);
+			assert_eq!(db.get(col, &key(2)).unwrap(), Some(vec![2]));
+			assert_eq!(db.get(col, &key(3)).unwrap(), None);
+		}
+		assert_eq!(db.get(2, &key(0)).unwrap(), None);
+		assert_eq!(db.get(2, &key(1)).unwrap(), Some(vec![2]));
+		assert_eq!(db.get(2, &key(2)).unwrap(), None);
+
+		db.commit(vec![(2, key(4), Some(vec![0]))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert!(db.compact().is_err());
+	}
+
+	#[test]
+	fn test_metrics_sink() {
+		#[derive(Default)]
+		struct Counters {
+			commits: AtomicU64,
+			tier_reads: AtomicU64,
+			cache_hits: AtomicU64,
+			payload_bytes: AtomicU64,
+			log_bytes: AtomicU64,
+			table_bytes: AtomicU64,
+		}
+		impl crate::MetricsSink for Counters {
+			fn commit(&self, _latency: Duration) {
+				self.commits.fetch_add(1, Ordering::SeqCst);
+			}
+			fn tier_read(&self, _col: ColId, _tier: u8) {
+				self.tier_reads.fetch_add(1, Ordering::SeqCst);
+			}
+			fn cache_lookup(&self, _col: ColId, hit: bool) {
+				if hit {
+					self.cache_hits.fetch_add(1, Ordering::SeqCst);
+				}
+			}
+			fn log_written(&self, payload_bytes: u64, log_bytes: u64) {
+				self.payload_bytes.fetch_add(payload_bytes, Ordering::SeqCst);
+				self.log_bytes.fetch_add(log_bytes, Ordering::SeqCst);
+			}
+			fn tables_written(&self, bytes: u64) {
+				self.table_bytes.fetch_add(bytes, Ordering::SeqCst);
+			}
+		}
+
+		let tmp = tempdir().unwrap();
+		let counters = Arc::new(Counters::default());
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.metrics = Some(counters.clone());
+		options.columns[0].cache_size = 1024;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key1".to_vec(), Some(b"value1".to_vec()))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(counters.commits.load(Ordering::SeqCst), 1);
+		assert_eq!(counters.tier_reads.load(Ordering::SeqCst), 1);
+		assert_eq!(counters.cache_hits.load(Ordering::SeqCst), 1);
+		// An index chunk and a value table entry, each written to the log first.
+		assert_eq!(counters.payload_bytes.load(Ordering::SeqCst), 32 + 6);
+		let table_bytes = counters.table_bytes.load(Ordering::SeqCst);
+		assert!(table_bytes > 512, "{}", table_bytes);
+		assert!(counters.log_bytes.load(Ordering::SeqCst) > 10);
+	}
+
+	#[test]
+	fn test_commit_hooks() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+		let hook_events = events.clone();
+		db.on_commit(Box::new(move |stage, col, changes| {
+			hook_events.lock().push((stage, col, changes.to_vec()));
+		}));
+		db.commit(vec![
+			(0, b"key1".to_vec(), Some(b"value1".to_vec())),
+			(1, b"key2".to_vec(), None),
+		])
+		.unwrap();
+		assert_eq!(
+			*events.lock(),
+			vec![
+				(CommitStage::Queued, 0, vec![(b"key1".to_vec(), Some(b"value1".to_vec()))]),
+				(CommitStage::Queued, 1, vec![(b"key2".to_vec(), None)]),
+			]
+		);
+		db.inner.wait_for_queued_commits().unwrap();
+		let events = events.lock();
+		assert_eq!(events.len(), 4);
+		assert_eq!(events[2], (CommitStage::Enacted, 0, events[0].2.clone()));
+		assert_eq!(events[3], (CommitStage::Enacted, 1, events[1].2.clone()));
+	}
+
+	#[test]
+	fn test_sync_mode() {
+		for mode in
+			[SyncMode::Always, SyncMode::Periodic(Duration::from_millis(1)), SyncMode::OnDemand]
+		{
+			let tmp = tempdir().unwrap();
+			let mut options = Options::with_columns(tmp.path(), 1);
+			options.sync_mode = mode;
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![(0, b"key".to_vec(), Some(b"value".to_vec()))]).unwrap();
+			db.flush().unwrap();
+			std::thread::sleep(Duration::from_millis(5));
+			std::mem::drop(db);
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		}
+	}
+
+	#[test]
+	fn test_sync_interval() {
+		use crate::fail::{self, FailAction, FailPoint};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		// Tables are only flushed by the sync worker then.
+		options.sync_data = false;
+		options.sync_interval = Some(Duration::from_millis(1));
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		fail::arm(tmp.path(), FailPoint::TableSync, 0, FailAction::Error);
+		let start = std::time::Instant::now();
+		while db.inner.check_bg_err().is_ok() {
+			assert!(start.elapsed() < Duration::from_secs(5));
+			std::thread::sleep(Duration::from_millis(1));
+		}
+		fail::disarm(tmp.path());
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_log_backlog() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_log_size = 0;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		assert_eq!(db.log_backlog_bytes(), 0);
+		db.commit(vec![(0, b"key".to_vec(), Some(b"value".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		assert!(db.log_backlog_bytes() > 0);
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		assert_eq!(db.log_backlog_bytes(), 0);
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_enact_batch() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.enact_batch_records = Some(2);
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		for i in 0..3u8 {
+			db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+		}
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let start = db.inner.last_enacted.load(Ordering::SeqCst);
+		let mut enacted = Vec::new();
+		while db.inner.enact_logs(false).unwrap() {
+			enacted.push(db.inner.last_enacted.load(Ordering::SeqCst) - start);
+		}
+		// The last record is completed at the end of the log.
+		assert_eq!(enacted, vec![0, 2, 2]);
+		assert_eq!(db.inner.last_enacted.load(Ordering::SeqCst) - start, 3);
+		for i in 0..3u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+		}
+	}
+
+	#[test]
+	fn test_parallel_replay() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		let values: Vec<_> = (0..20u32).map(|i| vec![i as u8; 10 + i as usize * 100]).collect();
+		db.commit(values.iter().enumerate().flat_map(|(i, value)| {
+			let key = (i as u32).to_le_bytes().to_vec();
+			[(0, key.clone(), Some(value.clone())), (1, key, Some(value.clone()))]
+		}))
+		.unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		drop(db);
+
+		options.replay_threads = 4;
+		let db = Db::open(&options).unwrap();
+		for (i, value) in values.iter().enumerate() {
+			let key = (i as u32).to_le_bytes();
+			assert_eq!(db.get(0, &key).unwrap().as_ref(), Some(value));
+			assert_eq!(db.get(1, &key).unwrap().as_ref(), Some(value));
+		}
+	}
+
+	#[test]
+	fn test_compress_log() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.compress_log = true;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		let values: Vec<_> = (0..20u32).map(|i| vec![i as u8; 10 + i as usize * 1000]).collect();
+		db.commit(values.iter().enumerate().flat_map(|(i, value)| {
+			let key = (i as u32).to_le_bytes().to_vec();
+			[(0, key.clone(), Some(value.clone())), (1, key, Some(value.clone()))]
+		}))
+		.unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let log_bytes: u64 = std::fs::read_dir(tmp.path())
+			.unwrap()
+			.map(|e| e.unwrap())
+			.filter(|e| e.file_name().to_string_lossy().starts_with("log"))
+			.map(|e| e.metadata().unwrap().len())
+			.sum();
+		let value_bytes: u64 = values.iter().map(|v| v.len() as u64 * 2).sum();
+		assert!(log_bytes * 10 < value_bytes);
+		drop(db);
+
+		// Replayed without the option.
+		options.compress_log = false;
+		let db = Db::open(&options).unwrap();
+		for (i, value) in values.iter().enumerate() {
+			let key = (i as u32).to_le_bytes();
+			assert_eq!(db.get(0, &key).unwrap().as_ref(), Some(value));
+			assert_eq!(db.get(1, &key).unwrap().as_ref(), Some(value));
+		}
+		db.commit([(0, b"key", Some(b"value".to_vec()))]).unwrap();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_compress_log_length() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.compress_log = true;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit([(0, b"key", Some(vec![7u8; 10000]))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		drop(db);
+
+		// Write the record without a frame, which is only checked at the end, and claim that the
+		// compressed entries are 4GB long.
+		let path = tmp.path().join("log0");
+		let mut log = std::fs::read(&path).unwrap();
+		// Framed record start, then compressed entries after the record id and frame length.
+		assert_eq!((log[0], log[13]), (10, 9));
+		// Unframed record start.
+		log[0] = 1;
+		log.drain(9..13);
+		log[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+		std::fs::write(&path, log).unwrap();
+
+		let db = Db::open(&options).unwrap();
+		match db.replay_report().stop {
+			Some(ReplayStop::CorruptRecord { log: 0, offset: 0, reason }) => {
+				assert!(reason.contains("past the end of the file"));
+			},
+			stop => panic!("Unexpected replay stop {:?}", stop),
+		}
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+	}
+
+	#[test]
+	fn test_replay_report() {
+		let values: Vec<_> = (1..=3u8).map(|i| vec![0xa0 + i; 200]).collect();
+		// Logs three records and changes the log file with `damage` before it is replayed.
+		let replay = |damage: &dyn Fn(&mut Vec<u8>)| {
+			let tmp = tempdir().unwrap();
+			let options = Options::with_columns(tmp.path(), 1);
+			let inner_options = InternalOptions {
+				create: true,
+				commit_stages: EnableCommitPipelineStages::LogOverlay,
+				..Default::default()
+			};
+			let db = Db::open_inner(&options, &inner_options).unwrap();
+			for (i, value) in values.iter().enumerate() {
+				db.commit([(0, [i as u8], Some(value.clone()))]).unwrap();
+				EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+			}
+			let _ = db.inner.log.flush_one(0).unwrap();
+			drop(db);
+			let path = tmp.path().join("log0");
+			let mut log = std::fs::read(&path).unwrap();
+			damage(&mut log);
+			std::fs::write(&path, log).unwrap();
+
+			let db = Db::open(&options).unwrap();
+			let found: Vec<_> = (0..3u8).map(|i| db.get(0, &[i]).unwrap().is_some()).collect();
+			(db.replay_report(), found)
+		};
+
+		let (report, found) = replay(&|_| ());
+		assert_eq!(report.records, 3);
+		assert_eq!(report.stop, None);
+		assert_eq!(found, [true, true, true]);
+		let last = report.cutoff;
+
+		let (report, found) = replay(&|log| log.truncate(log.len() - 3));
+		assert_eq!((report.records, report.cutoff), (2, last - 1));
+		assert!(matches!(report.stop, Some(ReplayStop::TornRecord { log: 0, .. })));
+		assert_eq!(found, [true, true, false]);
+
+		let (report, found) = replay(&|log| {
+			let pos = log.windows(200).position(|w| w == values[1]).unwrap();
+			log[pos + 100] ^= 1;
+		});
+		assert_eq!((report.records, report.cutoff), (1, last - 2));
+		match report.stop {
+			Some(ReplayStop::CorruptRecord { log: 0, offset, reason }) => {
+				assert!(offset > 0);
+				assert!(reason.contains("CRC-32"));
+			},
+			stop => panic!("Unexpected replay stop {:?}", stop),
+		}
+		assert_eq!(found, [true, false, false]);
+	}
+
+	#[test]
+	fn test_get_ref() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 4);
+		options.columns[1].store_keys = true;
+		options.columns[1].cache_size = 1024;
+		options.columns[2].compression = crate::CompressionType::Lz4;
+		options.columns[3].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let value = vec![7u8; 1000];
+		db.commit((0..4).map(|col| (col, b"key".to_vec(), Some(value.clone()))))
+			.unwrap();
+		// From the commit overlay, then from the tables.
+		for _ in 0..2 {
+			for col in 0..4 {
+				assert_eq!(db.get_ref(col, b"key").unwrap().as_deref(), Some(&value[..]));
+				assert!(db.get_ref(col, b"missing").unwrap().is_none());
+			}
+			db.inner.wait_for_queued_commits().unwrap();
+		}
+		let mut buf = vec![1, 2, 3];
+		assert_eq!(db.get_into(2, b"key", &mut buf).unwrap(), Some(value.len()));
+		assert_eq!(buf, value);
+		assert_eq!(db.get_into(3, b"missing", &mut buf).unwrap(), None);
+		assert!(buf.is_empty());
+		assert_eq!(db.get_into(1, b"key", &mut buf).unwrap(), Some(value.len()));
+		assert_eq!(buf, value);
+
+		// The buffer is reused.
+		let first = db.get_ref(0, b"key").unwrap().unwrap().as_ptr();
+		assert_eq!(db.get_ref(0, b"key").unwrap().unwrap().as_ptr(), first);
+		let guard = db.get_ref(0, b"key").unwrap().unwrap();
+		assert_ne!(db.get_ref(0, b"key").unwrap().unwrap().as_ptr(), guard.as_ptr());
+	}
+
+	#[test]
+	fn test_get_reader() {
+		use std::io::Read;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		let db = Db::open_or_create(&options).unwrap();
+		let value: Vec<u8> = (0..1024 * 1024u32).flat_map(|i| i.to_le_bytes()).collect();
+		db.commit([(0, b"blob".to_vec(), Some(value.clone()))]).unwrap();
+		db.commit([(1, b"blob".to_vec(), Some(value.clone()))]).unwrap();
+		let mut read = Vec::new();
+		db.get_reader(0, b"blob").unwrap().unwrap().read_to_end(&mut read).unwrap();
+		assert_eq!(read, value);
+		db.inner.wait_for_queued_commits().unwrap();
+
+		let mut reader = db.get_reader(0, b"blob").unwrap().unwrap();
+		assert!(reader.next != 0);
+		assert!(reader.buf.len() < 64 * 1024);
+		let mut read = Vec::new();
+		let mut chunk = [0u8; 1000];
+		loop {
+			let len = reader.read(&mut chunk).unwrap();
+			if len == 0 {
+				break
+			}
+			read.extend_from_slice(&chunk[..len]);
+		}
+		assert_eq!(read, value);
+		let mut read = Vec::new();
+		db.get_reader(1, b"blob").unwrap().unwrap().read_to_end(&mut read).unwrap();
+		assert_eq!(read, value);
+		assert!(db.get_reader(0, b"missing").unwrap().is_none());
+
+		// Parts of the replaced value may be reused.
+		let mut reader = db.get_reader(0, b"blob").unwrap().unwrap();
+		let mut read = vec![0u8; 100_000];
+		reader.read_exact(&mut read).unwrap();
+		db.commit([(0, b"blob".to_vec(), Some(vec![1; value.len()]))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert!(reader.read_to_end(&mut Vec::new()).is_err());
+		let mut read = Vec::new();
+		db.get_reader(0, b"blob").unwrap().unwrap().read_to_end(&mut read).unwrap();
+		assert_eq!(read, vec![1; value.len()]);
+	}
+
+	#[test]
+	fn test_get_range() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		options.columns[2].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let value: Vec<u8> = (0..1024 * 1024u32).flat_map(|i| i.to_le_bytes()).collect();
+		let end = value.len() as u64;
+		let ranges = [(0, 10), (100_000, 5000), (123_457, 70_000), (end - 3, 10), (end + 5, 10)];
+		for col in 0..3 {
+			db.commit([(col, b"blob".to_vec(), Some(value.clone()))]).unwrap();
+		}
+		for enacted in [false, true] {
+			if enacted {
+				db.inner.wait_for_queued_commits().unwrap();
+			}
+			for col in 0..3 {
+				for (offset, len) in ranges {
+					let start = value.len().min(offset as usize);
+					let end = value.len().min((offset + len) as usize);
+					assert_eq!(
+						db.get_range(col, b"blob", offset, len).unwrap(),
+						Some(value[start..end].to_vec())
+					);
+				}
+				assert_eq!(db.get_range(col, b"missing", 0, 10).unwrap(), None);
+			}
+		}
+		let mut reader = db.inner.get_reader(0, b"blob").unwrap().unwrap();
+		reader.skip(500_000).unwrap();
+		assert!(reader.buf.len() < 64 * 1024);
+	}
+
+	#[test]
+	fn test_put_stream() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].store_keys = true;
+		options.columns[2].btree_index = true;
+		let value: Vec<u8> = (0..1024 * 1024u32).flat_map(|i| i.to_le_bytes()).collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			// Written after the queued commit.
+			db.commit([(0, b"blob".to_vec(), Some(b"old".to_vec()))]).unwrap();
+			db.put_stream(0, b"blob", value.as_slice(), value.len() as u64).unwrap();
+			assert_eq!(db.get(0, b"blob").unwrap(), Some(value.clone()));
+			db.put_stream(1, b"blob", value.as_slice(), value.len() as u64).unwrap();
+			db.put_stream(0, b"small", &b"value"[..], 5).unwrap();
+			assert_eq!(db.get(0, b"small").unwrap(), Some(b"value".to_vec()));
+			assert_eq!(db.get(1, b"blob").unwrap(), Some(value.clone()));
+
+			// The reader ends early.
+			assert!(db.put_stream(0, b"small", &b"other"[..], 6).is_err());
+			assert_eq!(db.get(0, b"small").unwrap(), Some(b"value".to_vec()));
+			assert!(db.put_stream(2, b"blob", &b"value"[..], 5).is_err());
+
+			// Other writes are not blocked while the value is read.
+			struct Probe<'a>(&'a Db, &'a [u8]);
+			impl std::io::Read for Probe<'_> {
+				fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+					assert!(self.0.inner.log_writer.try_lock().is_some());
+					self.1.read(buf)
+				}
+			}
+			db.put_stream(0, b"probe", Probe(&db, &value[..4096]), 4096).unwrap();
+			assert_eq!(db.get(0, b"probe").unwrap(), Some(value[..4096].to_vec()));
+
+			db.inner.flush_logs(0).unwrap();
+			db.inner.flush_logs(0).unwrap();
+			db.inner
+				.wait_enacted(db.inner.last_logged_record.load(Ordering::SeqCst))
+				.unwrap();
+			assert_eq!(db.get(0, b"blob").unwrap(), Some(value.clone()));
+			db.put_stream(0, b"blob", &value[..1000], 1000).unwrap();
+			assert_eq!(db.get(0, b"blob").unwrap(), Some(value[..1000].to_vec()));
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"blob").unwrap(), Some(value[..1000].to_vec()));
+		assert_eq!(db.get(1, b"blob").unwrap(), Some(value));
+		assert_eq!(db.get(0, b"small").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_value_cache() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].cache_size = 1024;
+		options.columns[1].cache_size = 1024;
+		options.columns[1].btree_index = true;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		let hash_key = match &db.inner.columns[0] {
+			Column::Hash(column) => column.hash_key(b"key"),
+			Column::Tree(_) => unreachable!(),
+		};
+		let cached = |col: u8, key: &[u8]| db.inner.value_cache[col as usize].as_ref()?.get(key);
+
+		db.commit(vec![
+			(0, &b"key"[..], Some(b"value1".to_vec())),
+			(1, &b"key"[..], Some(b"value1".to_vec())),
+		])
+		.unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		assert_eq!(cached(0, &hash_key), None);
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(1, b"key").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(cached(0, &hash_key), Some(b"value1".to_vec()));
+		assert_eq!(cached(1, b"key"), Some(b"value1".to_vec()));
+
+		db.commit(vec![(0, &b"key"[..], Some(b"value2".to_vec())), (1, b"key", None)])
+			.unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		assert_eq!(cached(0, &hash_key), None);
+		assert_eq!(cached(1, b"key"), None);
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value2".to_vec()));
+		assert_eq!(db.get_size(0, b"key").unwrap(), Some(6));
+		assert_eq!(db.get(1, b"key").unwrap(), None);
+		assert_eq!(cached(0, &hash_key), Some(b"value2".to_vec()));
+		drop(db);
+
+		// The cache size is not part of the metadata.
+		options.columns[0].cache_size = 0;
+		let db = Db::open(&options).unwrap();
+		assert!(db.inner.value_cache[0].is_none());
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_index_cache() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.index_cache_size = 64 * 1024;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::DbFile,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		let keys: Vec<_> = (0..100u32).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		for k in keys.iter() {
+			assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
+		}
+
+		// Chunks cached above are rewritten.
+		db.commit(keys.iter().step_by(2).map(|k| (0, k, None))).unwrap();
+		db.commit(vec![(0, b"new", Some(b"value".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		for (i, k) in keys.iter().enumerate() {
+			let expected = if i % 2 == 0 { None } else { Some(k.to_vec()) };
+			assert_eq!(db.get(0, k).unwrap(), expected);
+		}
+		assert_eq!(db.get(0, b"new").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_memory_stats() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.index_cache_size = 64 * 1024;
+		options.columns[0].cache_size = 64 * 1024;
+		options.columns[0].bloom_filter_bytes = 1024;
+		options.columns[1].btree_index = true;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::DbFile,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		let stats = db.memory_stats();
+		assert_eq!(stats.columns.len(), 3);
+		assert_eq!(stats.commit_queue_bytes, 0);
+		assert_eq!(stats.columns[0].bloom_filter_bytes, 1024);
+		assert_eq!(stats.columns[2].bloom_filter_bytes, 0);
+
+		let values = (0..100u32).map(|i| (i.to_le_bytes(), vec![i as u8; 100]));
+		db.commit(values.clone().flat_map(|(k, v)| [(0, k, Some(v.clone())), (1, k, Some(v))]))
+			.unwrap();
+		let stats = db.memory_stats();
+		assert!(stats.commit_queue_bytes >= 2 * 100 * 100);
+		assert!(stats.columns[0].commit_overlay_bytes >= 100 * 100);
+		assert!(stats.columns[1].commit_overlay_bytes >= 100 * 100);
+		assert_eq!(stats.columns[2].commit_overlay_bytes, 0);
+
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let stats = db.memory_stats();
+		assert_eq!(stats.commit_queue_bytes, 0);
+		assert_eq!(stats.columns[0].commit_overlay_bytes, 0);
+		assert!(stats.columns[0].log_overlay_bytes >= 100 * 100);
+		assert!(stats.columns[1].log_overlay_bytes >= 100 * 100);
+		assert_eq!(stats.columns[2].log_overlay_bytes, 0);
+
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		for (k, v) in values {
+			assert_eq!(db.get(0, &k).unwrap(), Some(v));
+		}
+		let stats = db.memory_stats();
+		assert_eq!(stats.columns[0].log_overlay_bytes, 0);
+		assert!(stats.columns[0].value_cache_bytes >= 100 * 100);
+		assert_eq!(stats.columns[1].value_cache_bytes, 0);
+		assert!(stats.index_cache_bytes > 0);
+		assert!(stats.columns[0].mapped_bytes > 0);
+		assert!(stats.total_bytes() >= stats.columns[0].total_bytes() + stats.index_cache_bytes);
+	}
+
+	#[test]
+	fn test_bloom_filter() {
+		let tmp = tempdir().unwrap();
+		let bloom_path = tmp.path().join("bloom_00");
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].bloom_filter_bytes = 1024;
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0..100u32).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+		drop(db);
+		assert!(bloom_path.exists());
+
+		// Loaded and removed, since it goes stale with writes.
+		let inner_options = InternalOptions {
+			commit_stages: EnableCommitPipelineStages::DbFile,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		assert!(!bloom_path.exists());
+		assert!(!db.inner.rebuild_bloom_filters().unwrap());
+		db.commit(vec![(0, b"new", Some(b"value".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::DbFile.run_stages(&db);
+		assert_eq!(db.get(0, b"new").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, b"missing").unwrap(), None);
+		drop(db);
+		assert!(!bloom_path.exists());
+
+		// Rebuilt from the index.
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		for k in keys.iter() {
+			assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
+		}
+		while db.inner.rebuild_bloom_filters().unwrap() {}
+		for k in keys.iter() {
+			assert_eq!(db.get(0, k).unwrap(), Some(k.to_vec()));
+		}
+		assert_eq!(db.get(0, b"new").unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get(0, b"missing").unwrap(), None);
+		drop(db);
+
+		// Stale filters are removed when opened without one.
+		std::fs::write(&bloom_path, b"stale").unwrap();
+		options.columns[0].bloom_filter_bytes = 0;
+		let db = Db::open(&options).unwrap();
+		assert!(!bloom_path.exists());
+		assert_eq!(db.get(0, b"new").unwrap(), Some(b"value".to_vec()));
+	}
+
+	#[test]
+	fn test_preopen() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 6);
+		options.columns[1].btree_index = true;
+		options.columns[4].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0..6u16).flat_map(|col| {
+			(0..10u8)
+				.map(move |i| (col, vec![col as u8, i], Some(vec![i; 10 + col as usize * 100])))
+		}))
+		.unwrap();
+		drop(db);
+
+		options.preopen_threads = Some(4);
+		let db = Db::open(&options).unwrap();
+		for col in 0..6u16 {
+			for i in 0..10u8 {
+				assert_eq!(
+					db.get(col, &[col as u8, i]).unwrap(),
+					Some(vec![i; 10 + col as usize * 100])
+				);
+			}
+		}
+		drop(db);
+
+		// Point the free list of column 0 past the filled entries.
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let entry = entry.unwrap();
+			if entry.file_name().to_string_lossy().starts_with("table_00_") {
+				use std::os::unix::fs::FileExt;
+				let file = std::fs::OpenOptions::new().write(true).open(entry.path()).unwrap();
+				file.write_all_at(&1000u64.to_le_bytes(), 0).unwrap();
+			}
+		}
+		assert!(matches!(Db::open(&options), Err(Error::Corruption(_))));
+		options.preopen_threads = None;
+		assert!(Db::open(&options).is_ok());
+	}
+
+	#[test]
+	fn test_direct_io() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.direct_io = true;
+		let values = |round: u8| {
+			(0..2u16).flat_map(move |col| {
+				(0..20u8).map(move |i| {
+					(col, vec![col as u8, i], Some(vec![i ^ round; 1 + i as usize * 500]))
+				})
+			})
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(values(0)).unwrap();
+		db.commit(values(1)).unwrap();
+		drop(db);
+
+		for direct_io in [true, false] {
+			options.direct_io = direct_io;
+			let db = Db::open(&options).unwrap();
+			for (col, key, value) in values(1) {
+				assert_eq!(db.get(col, &key).unwrap(), value);
+			}
+		}
+	}
+
+	#[test]
+	fn test_index_map_advice() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.index_huge_pages = true;
+		options.index_prefetch = true;
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		// Creates the index file, then maps the existing one.
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0..1000).map(|i| (0, key(i), Some(key(i))))).unwrap();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		for i in 0..1000 {
+			assert_eq!(db.get(0, &key(i)).unwrap(), Some(key(i)));
+		}
+	}
+
+	#[test]
+	fn test_memory_storage() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.storage = Some(Arc::new(crate::MemoryStorage::new()));
+		let values = |round: u8| {
+			(0..2u16).flat_map(move |col| {
+				(0..200u8).map(move |i| {
+					(col, vec![col as u8, i], Some(vec![i ^ round; 1 + i as usize * 50]))
+				})
+			})
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(values(0)).unwrap();
+		db.commit(values(1)).unwrap();
+		// Table files can't be copied.
+		assert!(db.checkpoint(&tmp.path().join("checkpoint")).is_err());
+		assert!(db.copy_to(&tmp.path().join("copy")).is_err());
+		assert!(db.export_snapshot(Vec::new()).is_err());
+		assert!(db.relocate(&tmp.path().join("relocated"), None).is_err());
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		for (col, key, value) in values(1) {
+			assert_eq!(db.get(col, &key).unwrap(), value);
+		}
+		// The btree column has no index file, all of its tables are in the storage.
+		let sizes = db.column_sizes().unwrap();
+		assert!(sizes[1].live_bytes > 0);
+		assert!(sizes[1].disk_bytes >= sizes[1].live_bytes);
+		drop(db);
+		assert!(!std::fs::read_dir(tmp.path()).unwrap().any(|e| e
+			.unwrap()
+			.file_name()
+			.to_str()
+			.unwrap()
+			.starts_with("table_")));
+	}
+
+	#[test]
+	fn test_fault_storage() {
+		use crate::{FaultConfig, FaultOp, FaultStorage, MemoryStorage};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		let storage =
+			Arc::new(FaultStorage::new(Arc::new(MemoryStorage::new()), Default::default(), 1));
+		options.storage = Some(storage.clone());
+		let key = |i: u32| i.to_le_bytes().to_vec();
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0..100).map(|i| (0, key(i), Some(vec![i as u8; 100])))).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		storage.fail_next(FaultOp::Read, 1);
+		assert!(db.get(0, &key(1)).is_err());
+		assert_eq!(db.get(0, &key(1)).unwrap(), Some(vec![1; 100]));
+		assert_eq!(storage.injected(), 1);
+		storage.set_config(FaultConfig { read_error_rate: 1.0, ..Default::default() });
+		assert!(db.get(0, &key(2)).is_err());
+		storage.set_config(FaultConfig { write_error_rate: 1.0, ..Default::default() });
+		db.commit([(0, key(100), Some(vec![0; 100]))]).unwrap();
+		assert!(db.wait_enacted(db.read_fence()).is_err());
+		assert!(db.commit([(0, key(101), None)]).is_err());
+		assert_eq!(storage.injected(), 3);
+	}
+
+	#[test]
+	fn test_mmap_tables() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.columns[0].mmap_tables = true;
+		options.columns[1].mmap_tables = true;
+		let db = Db::open_or_create(&options).unwrap();
+		// Enough values to grow the tables a few times.
+		for round in 0..4u16 {
+			db.commit((0..2u16).flat_map(|col| {
+				(0..200u16).map(move |i| {
+					let key = (round * 200 + i).to_le_bytes().to_vec();
+					(col, key, Some(vec![i as u8; 1000]))
+				})
+			}))
+			.unwrap();
+		}
+		let check = |db: &Db| {
+			for col in 0..2u16 {
+				for i in 0..800u16 {
+					assert_eq!(
+						db.get(col, &i.to_le_bytes()).unwrap(),
+						Some(vec![(i % 200) as u8; 1000])
+					);
+				}
+			}
+		};
+		check(&db);
+		drop(db);
+
+		options.columns[0].mmap_tables = false;
+		let db = Db::open(&options).unwrap();
+		check(&db);
+	}
+
+	#[test]
+	fn test_file_growth() {
+		use std::os::unix::fs::MetadataExt;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.file_growth = Some(1024 * 1024);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0..100u8).map(|i| (0, vec![i], Some(vec![i; 1000])))).unwrap();
+		db.wait_enacted(db.read_fence()).unwrap();
+		let mut tables = 0;
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let entry = entry.unwrap();
+			if entry.file_name().to_string_lossy().starts_with("table_") {
+				let metadata = entry.metadata().unwrap();
+				assert!(metadata.len() > 1000 * 1024);
+				// Space is allocated rather than left sparse.
+				assert!(metadata.blocks() * 512 >= metadata.len());
+				tables += 1;
+			}
+		}
+		assert_eq!(tables, 1);
+		drop(db);
+
+		options.file_growth = None;
+		let db = Db::open(&options).unwrap();
+		for i in 0..100u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i; 1000]));
+		}
+	}
+
+	#[test]
+	fn test_path_override() {
+		let tmp = tempdir().unwrap();
+		let db_path = tmp.path().join("db");
+		let column_path = tmp.path().join("column");
+		let mut options = Options::with_columns(&db_path, 2);
+		options.columns[1].path_override = Some(column_path.clone());
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(
+			(0..2u16)
+				.flat_map(|col| (0..10u8).map(move |i| (col, vec![i], Some(vec![col as u8; 100])))),
+		)
+		.unwrap();
+		db.wait_enacted(db.read_fence()).unwrap();
+		let is_column_file = |col: ColId, path: &std::path::Path| {
+			std::fs::read_dir(path).unwrap().any(|entry| {
+				let file = entry.unwrap().file_name();
+				let file = file.to_str().unwrap();
+				crate::index::TableId::is_file_name(col, file) ||
+					crate::table::TableId::is_file_name(col, file)
+			})
+		};
+		assert!(is_column_file(0, &db_path));
+		assert!(!is_column_file(1, &db_path));
+		assert!(is_column_file(1, &column_path));
+		assert!(db.column_sizes().unwrap()[1].disk_bytes > 0);
+
+		let checkpoint = tmp.path().join("checkpoint");
+		db.checkpoint(&checkpoint).unwrap();
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, &[5]).unwrap(), Some(vec![1; 100]));
+		drop(db);
+
+		let db = Db::open(&Options::with_columns(&checkpoint, 2)).unwrap();
+		assert_eq!(db.get(0, &[5]).unwrap(), Some(vec![0; 100]));
+		assert_eq!(db.get(1, &[5]).unwrap(), Some(vec![1; 100]));
+		drop(db);
+
+		options.columns[1].path_override = Some(tmp.path().join("missing"));
+		assert!(matches!(Db::open(&options), Err(Error::InvalidConfiguration(_))));
+	}
+
+	#[test]
+	fn test_many_columns() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 300);
+		options.columns[298].btree_index = true;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit(
+			[5, 298, 299]
+				.map(|col: ColId| (col, b"key".to_vec(), Some(col.to_le_bytes().to_vec()))),
+		)
+		.unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		drop(db);
+
+		// Records of columns past 255 are replayed from the log.
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.num_columns(), 300);
+		for col in [5, 298, 299] {
+			assert_eq!(db.get(col, b"key").unwrap(), Some(col.to_le_bytes().to_vec()));
+		}
+		assert_eq!(db.get(255, b"key").unwrap(), None);
+		drop(db);
+		assert!(std::fs::read_dir(tmp.path()).unwrap().any(|e| {
+			crate::table::TableId::is_file_name(299, e.unwrap().file_name().to_str().unwrap())
+		}));
+	}
+
+	#[test]
+	fn test_transaction() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec())), (1, b"a", Some(b"1".to_vec()))])
+			.unwrap();
+
+		let mut tx = db.transaction();
+		for col in 0..2 {
+			tx.set(col, b"b", b"2".to_vec());
+			tx.remove(col, b"a");
+			assert_eq!(tx.get(col, b"a").unwrap(), None);
+			assert_eq!(tx.get(col, b"b").unwrap(), Some(b"2".to_vec()));
+			assert_eq!(db.get(col, b"a").unwrap(), Some(b"1".to_vec()));
+			assert_eq!(db.get(col, b"b").unwrap(), None);
+		}
+		tx.set(0, b"a", b"3".to_vec());
+		assert_eq!(tx.get(0, b"a").unwrap(), Some(b"3".to_vec()));
+		assert_eq!(tx.len(), 5);
+		tx.commit().unwrap();
+
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"3".to_vec()));
+		assert_eq!(db.get(1, b"a").unwrap(), None);
+		assert_eq!(db.get(1, b"b").unwrap(), Some(b"2".to_vec()));
+
+		let mut tx = db.transaction();
+		tx.remove(0, b"b");
+		drop(tx);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+	}
+
+	#[test]
+	fn test_commit_chunks() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.commit_chunk_size = Some(2);
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit([(0, b"x", Some(b"x".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		while db.inner.enact_logs(false).unwrap() {}
+		let enacted = db.inner.last_enacted.load(Ordering::SeqCst);
+		let changes: Vec<_> = (0..5u8)
+			.flat_map(|i| [(0, vec![i], Some(vec![i])), (1, vec![i], Some(vec![i]))])
+			.collect();
+		db.commit(changes.clone()).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		for i in 0..5u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+			assert_eq!(db.get(1, &[i]).unwrap(), Some(vec![i]));
+		}
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		while db.inner.enact_logs(false).unwrap() {}
+		// Hash column changes in parts of 2, 2 and 1, then the btree changes.
+		assert_eq!(db.inner.last_enacted.load(Ordering::SeqCst), enacted + 4);
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		for i in 0..5u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+			assert_eq!(db.get(1, &[i]).unwrap(), Some(vec![i]));
+		}
+		drop(db);
+
+		// A split commit with the last part missing is dropped as a whole on replay.
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.commit_chunk_size = Some(2);
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit([(0, b"x", Some(b"x".to_vec()))]).unwrap();
+		db.commit(changes).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		drop(db);
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			if path.file_name().unwrap().to_str().unwrap().starts_with("log") {
+				let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+				let len = file.metadata().unwrap().len();
+				if len > 0 {
+					file.set_len(len - 8).unwrap();
+				}
+			}
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"x").unwrap(), Some(b"x".to_vec()));
+		for i in 0..5u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), None);
+			assert_eq!(db.get(1, &[i]).unwrap(), None);
+		}
+	}
+
+	#[test]
+	fn test_reindex_throttle() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].uniform = true;
+		options.reindex_batch = 1;
+		options.reindex_pause = Some(Duration::from_millis(100));
+		options.max_log_size = 0;
+		// Each tick advances the logical clock by a millisecond.
+		options.worker_threads = WorkerThreads::Manual;
+		options.deterministic_seed = Some(0);
+		let db = Db::open_or_create(&options).unwrap();
+		// Keys that fall into the same index chunk until the index grows.
+		let key = |i: u8| {
+			let mut key = [0u8; 32];
+			key[2] = (i % 2) * 128;
+			key[3] = i;
+			key
+		};
+		db.commit((0..100).map(|i| (0, key(i), Some(vec![i])))).unwrap();
+		let progress = || match &db.inner.columns[0] {
+			Column::Hash(column) => column.reindex_progress(),
+			Column::Tree(_) => None,
+		};
+		let mut ticks = 0;
+		while progress().is_none_or(|(done, _)| done == 0) {
+			assert!(ticks < 1000);
+			db.tick().unwrap();
+			ticks += 1;
+		}
+		// The first record moved the full chunk. The rest waits for the pause.
+		let (done, total) = progress().unwrap();
+		assert!(done < total);
+		for _ in 0..90 {
+			db.tick().unwrap();
+		}
+		assert_eq!(progress(), Some((done, total)));
+		while progress().is_some() {
+			assert!(ticks < 100_000);
+			db.tick().unwrap();
+			ticks += 1;
+		}
+		for i in 0..100 {
+			assert_eq!(db.get(0, &key(i)).unwrap(), Some(vec![i]));
+		}
+	}
+
+	#[test]
+	fn test_trigger_reindex() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		options.max_log_size = 0;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0..100u8).map(|i| (0, vec![i], Some(vec![i])))).unwrap();
+		assert_eq!(db.column_info(0).unwrap().index_bits, Some(16));
+		assert_eq!(db.reindex_progress(0), None);
+		assert!(db.trigger_reindex(0).unwrap());
+		assert!(!db.trigger_reindex(0).unwrap());
+		assert_eq!(db.reindex_progress(0).map(|(_, total)| total), Some(1 << 16));
+		assert!(matches!(db.trigger_reindex(1), Err(Error::InvalidInput(_))));
+		assert!(matches!(db.trigger_reindex(2), Err(Error::InvalidInput(_))));
+		assert_eq!(db.reindex_progress(1), None);
+
+		let start = std::time::Instant::now();
+		while db.reindex_progress(0).is_some() {
+			assert!(start.elapsed() < Duration::from_secs(5));
+			std::thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(db.column_info(0).unwrap().index_bits, Some(17));
+		drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.column_info(0).unwrap().index_bits, Some(17));
+		for i in 0..100u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+		}
+	}
+
+	#[test]
+	fn test_index_chunk_entries() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].uniform = true;
+		options.columns[0].index_chunk_entries = 256;
+		options.columns[1].uniform = true;
+		options.max_log_size = 0;
+		// Keys that share the bits that select the index chunk.
+		let key = |i: u8| {
+			let mut key = [0u8; 32];
+			key[2] = i;
+			key
+		};
+		let values = || (0..200).map(|i| (key(i), vec![i; 10]));
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(values().flat_map(|(k, v)| [(0, k, Some(v.clone())), (1, k, Some(v))]))
+				.unwrap();
+			db.inner.wait_for_queued_commits().unwrap();
+			let start = std::time::Instant::now();
+			while db.reindex_progress(1).is_some() {
+				assert!(start.elapsed() < Duration::from_secs(5));
+				std::thread::sleep(Duration::from_millis(1));
+			}
+			// Only the column with the default chunks had to grow its index.
+			assert_eq!(db.column_info(0).unwrap().index_bits, Some(16));
+			assert!(db.column_info(1).unwrap().index_bits.unwrap() > 16);
+			let stats = db.index_stats(0).unwrap();
+			assert_eq!(stats.chunk_histogram.len(), 257);
+			assert_eq!(stats.max_chunk_entries, 200);
+			assert_eq!(stats.chunks(), 1 << 14);
+			for (k, v) in values() {
+				assert_eq!(db.get(0, &k).unwrap(), Some(v));
+			}
+			db.commit((0..200).step_by(2).map(|i| (0, key(i), None))).unwrap();
+			db.commit([(0, key(1), Some(vec![0]))]).unwrap();
+			db.inner.wait_for_queued_commits().unwrap();
+			let mut keys = Vec::new();
+			db.iter_index_while(0, |entry| {
+				keys.push(entry.key_prefix);
+				true
+			})
+			.unwrap();
+			assert_eq!(keys.len(), 100);
+		}
+
+		// The chunk size is part of the column metadata.
+		options.columns[0].index_chunk_entries = 64;
+		assert!(Db::open(&options).is_err());
+		options.columns[0].index_chunk_entries = 256;
+		let db = Db::open(&options).unwrap();
+		for i in 0..200 {
+			let expected = match i {
+				1 => Some(vec![0]),
+				i if i % 2 == 0 => None,
+				i => Some(vec![i; 10]),
+			};
+			assert_eq!(db.get(0, &key(i)).unwrap(), expected);
+		}
+		drop(db);
+
+		let new_options =
+			crate::ColumnOptions { index_chunk_entries: 512, ..options.columns[1].clone() };
+		crate::migrate_column(tmp.path(), 1, new_options.clone()).unwrap();
+		options.columns[1] = new_options;
+		let db = Db::open(&options).unwrap();
+		for (k, v) in values() {
+			assert_eq!(db.get(1, &k).unwrap(), Some(v));
+		}
+		options.columns[1].index_chunk_entries = 100;
+		assert!(!options.columns[1].is_valid());
+	}
+
+	#[test]
+	fn test_column_len() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[0].uniform = true;
+		options.columns[2].btree_index = true;
+		options.max_log_size = 0;
+		let db = Db::open_or_create(&options).unwrap();
+		let key =
+			|i: u32| blake2_rfc::blake2b::blake2b(32, &[], &i.to_le_bytes()).as_bytes().to_vec();
+		db.commit((0..20_000).map(|i| (0, key(i), Some(vec![1])))).unwrap();
+		db.commit((0..5_000).map(|i| (0, key(i), None))).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert_eq!(db.column_len(0).unwrap(), 15_000);
+		let approximate = db.approximate_column_len(0).unwrap();
+		assert!((10_000..20_000).contains(&approximate), "{}", approximate);
+		assert_eq!(db.column_len(1).unwrap(), 0);
+		assert_eq!(db.approximate_column_len(1).unwrap(), 0);
+		assert!(db.column_len(2).is_err());
+		assert!(db.approximate_column_len(3).is_err());
+
+		assert!(db.trigger_reindex(0).unwrap());
+		let start = std::time::Instant::now();
+		while db.reindex_progress(0).is_some() {
+			assert!(start.elapsed() < Duration::from_secs(5));
+			std::thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(db.column_len(0).unwrap(), 15_000);
+	}
+
+	#[test]
+	fn test_manual_workers() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		options.max_log_size = 0;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		assert_eq!(db.inner.last_logged_commit.load(Ordering::SeqCst), 0);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		while db.tick().unwrap() {}
+		assert!(db.inner.last_enacted.load(Ordering::SeqCst) > 0);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+
+		db.commit_with_ack([(0, b"b", Some(b"2".to_vec()))]).unwrap().wait().unwrap();
+		db.flush().unwrap();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+		drop(db);
+
+		options.worker_threads = WorkerThreads::Dedicated;
+		options.worker_thread_prefix = "test-db".into();
+		options.worker_cpus.insert(Worker::Enact, 0);
+		let db = Db::open(&options).unwrap();
+		let names: Vec<_> = [&db.log_thread, &db.commit_thread]
+			.iter()
+			.map(|t| t.as_ref().unwrap().thread().name().unwrap().to_string())
+			.collect();
+		assert_eq!(names, ["test-db-log", "test-db-enact"]);
+		assert!(matches!(db.tick(), Err(Error::InvalidConfiguration(_))));
+		#[cfg(target_os = "linux")]
+		{
+			options.worker_cpus.insert(Worker::Log, libc::CPU_SETSIZE as usize);
+			assert!(!options.is_valid());
+		}
+	}
+
+	#[test]
+	fn test_coalesce_commits() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		options.coalesce_commit_bytes = Some(256);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		db.commit([(0, b"a", Some(b"2".to_vec())), (0, b"b", Some(b"3".to_vec()))])
+			.unwrap();
+		db.commit([(0, b"b", None)]).unwrap();
+		db.commit([(0, b"c", Some(vec![4; 300]))]).unwrap();
+		assert!(db.inner.process_commits().unwrap());
+		let record = db.inner.last_logged_record.load(Ordering::SeqCst);
+		assert_eq!(
+			db.inner.last_logged_commit.load(Ordering::SeqCst),
+			db.inner.last_queued_commit() - 1
+		);
+		// Too large to join the record.
+		assert!(db.inner.process_commits().unwrap());
+		assert_eq!(db.inner.last_logged_record.load(Ordering::SeqCst), record + 1);
+		assert!(!db.inner.process_commits().unwrap());
+		db.flush().unwrap();
+		drop(db);
+
+		options.worker_threads = WorkerThreads::Dedicated;
+		options.coalesce_commit_window = Some(Duration::from_millis(200));
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		assert_eq!(db.get(0, b"c").unwrap(), Some(vec![4; 300]));
+		db.commit_with_ack([(0, b"d", Some(b"5".to_vec()))]).unwrap().wait().unwrap();
+		let logged = db.inner.last_logged_record.load(Ordering::SeqCst);
+		let handles: Vec<_> = (0..3u8)
+			.map(|i| db.commit_with_ack([(0, [i], Some(vec![i]))]).unwrap())
+			.collect();
+		for handle in handles {
+			handle.wait().unwrap();
+		}
+		assert_eq!(db.inner.last_logged_record.load(Ordering::SeqCst), logged + 1);
+		for i in 0..3u8 {
+			assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+		}
+	}
+
+	#[test]
+	fn test_pause_background() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.max_log_size = 0;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit_with_ack([(0, b"a", Some(b"1".to_vec()))]).unwrap().wait().unwrap();
+		db.pause_background();
+		db.pause_background();
+		let logged = db.inner.last_logged_commit.load(Ordering::SeqCst);
+		let enacted = db.inner.last_enacted.load(Ordering::SeqCst);
+		let handle = db.commit_with_ack([(0, b"b", Some(b"2".to_vec()))]).unwrap();
+		std::thread::sleep(Duration::from_millis(50));
+		assert_eq!(db.inner.last_logged_commit.load(Ordering::SeqCst), logged);
+		assert_eq!(db.inner.last_enacted.load(Ordering::SeqCst), enacted);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+		db.resume_background();
+		handle.wait().unwrap();
+		assert!(db.inner.last_enacted.load(Ordering::SeqCst) > enacted);
+
+		// Dropping a paused database completes the queued work.
+		db.pause_background();
+		db.commit([(0, b"c", Some(b"3".to_vec()))]).unwrap();
+		drop(db);
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.worker_threads = WorkerThreads::Manual;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"c").unwrap(), Some(b"3".to_vec()));
+		db.pause_background();
+		db.commit([(0, b"d", Some(b"4".to_vec()))]).unwrap();
+		assert!(!db.tick().unwrap());
+		assert!(matches!(db.flush(), Err(Error::InvalidInput(_))));
+		db.resume_background();
+		db.flush().unwrap();
+		assert_eq!(
+			db.inner.last_logged_commit.load(Ordering::SeqCst),
+			db.inner.last_queued_commit()
+		);
+	}
+
+	#[test]
+	fn test_injected_failures() {
+		use crate::fail::{self, FailAction, FailPoint};
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+
+		// A record cut short while logging is dropped on restart.
+		fail::arm(tmp.path(), FailPoint::LogWrite, 0, FailAction::Partial(12));
+		db.commit([(0, b"b", Some(b"2".to_vec()))]).unwrap();
+		db.inner.store_err(db.inner.process_commits().map(|_| ()));
+		assert!(matches!(db.commit([(0, b"c", None)]), Err(Error::Background(_))));
+		drop(db);
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+
+		// A record that failed to be written to the tables is enacted again on restart.
+		db.commit([(0, b"c", Some(b"3".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		fail::arm(tmp.path(), FailPoint::TableWrite, 0, FailAction::Partial(3));
+		db.inner.store_err(db.inner.enact_logs(false).map(|_| ()));
+		drop(db);
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		assert_eq!(db.get(0, b"c").unwrap(), Some(b"3".to_vec()));
+
+		fail::arm(tmp.path(), FailPoint::TableRead, 1, FailAction::Partial(1));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert!(db.get(0, b"a").is_err());
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+
+		db.commit([(0, b"d", Some(b"4".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		fail::arm(tmp.path(), FailPoint::LogSync, 0, FailAction::Error);
+		assert!(db.inner.log.flush_one(0).is_err());
+		fail::arm(tmp.path(), FailPoint::LogRead, 5, FailAction::Error);
+		fail::disarm(tmp.path());
+		let _ = db.inner.log.flush_one(0).unwrap();
+		while db.inner.enact_logs(false).unwrap() {}
+		assert_eq!(db.get(0, b"d").unwrap(), Some(b"4".to_vec()));
+	}
+
+	#[test]
+	fn test_on_disk_full() {
+		use crate::fail::{self, FailAction, FailPoint};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.on_disk_full = OnDiskFull::Retry(Duration::from_millis(50));
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		fail::arm(tmp.path(), FailPoint::TableWrite, 0, FailAction::DiskFull);
+		assert!(!db.inner.enact_logs(false).unwrap());
+		assert!(matches!(db.health(), Health::DiskFull));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		// Not retried before the interval passes.
+		assert!(!db.inner.enact_logs(false).unwrap());
+		std::thread::sleep(Duration::from_millis(60));
+		assert!(db.inner.enact_logs(false).unwrap());
+		assert!(matches!(db.health(), Health::Healthy));
+		while db.inner.enact_logs(false).unwrap() {}
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+
+		// Records that can't be enacted on shutdown are replayed on the next open.
+		db.commit([(0, b"b", Some(b"2".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		fail::arm(tmp.path(), FailPoint::TableWrite, 0, FailAction::DiskFull);
+		while db.inner.enact_logs(false).unwrap() {}
+		assert!(matches!(db.health(), Health::DiskFull));
+		drop(db);
+		fail::disarm(tmp.path());
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+
+		// Without a retry interval the error stops the database.
+		options.on_disk_full = OnDiskFull::Fail;
+		drop(db);
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit([(0, b"c", Some(b"3".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		fail::arm(tmp.path(), FailPoint::TableWrite, 0, FailAction::DiskFull);
+		db.inner.store_err(db.inner.enact_logs(false).map(|_| ()));
+		assert!(matches!(db.health(), Health::Failed(_)));
+	}
+
+	#[test]
+	fn test_on_corruption() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		let value = vec![7u8; 5000];
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(value.clone())), (0, b"b", Some(b"2".to_vec()))])
+			.unwrap();
+		drop(db);
+		// Make sure the values are written to the tables.
+		drop(Db::open(&options).unwrap());
+
+		// Damage the size prefix of the compressed value.
+		let compressed =
+			lz4::block::compress(&value, Some(lz4::block::CompressionMode::DEFAULT), true).unwrap();
+		let mut damaged = false;
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			if path.file_name().unwrap().to_str().unwrap().starts_with("table_00") {
+				let mut data = std::fs::read(&path).unwrap();
+				if let Some(pos) = data.windows(compressed.len()).position(|w| w == compressed) {
+					data[pos..pos + 4].copy_from_slice(&[0xff; 4]);
+					std::fs::write(&path, data).unwrap();
+					damaged = true;
+				}
+			}
+		}
+		assert!(damaged);
+		let db = Db::open(&options).unwrap();
+		assert!(matches!(db.get(0, b"a"), Err(Error::Compression)));
+		drop(db);
+
+		options.on_corruption = OnCorruption::Skip;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		drop(db);
+		// Recorded once per open.
+		let journal = std::fs::read_to_string(tmp.path().join(CORRUPTION_JOURNAL)).unwrap();
+		assert_eq!(journal.lines().count(), 1);
+		assert!(journal.starts_with("col=0 key=61 action=skip"));
+
+		options.on_corruption = OnCorruption::Quarantine;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get_size(0, b"a").unwrap(), None);
+		assert_eq!(db.inner.quarantined.read().len(), 1);
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap();
+		assert!(db.inner.quarantined.read().is_empty());
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		drop(db);
+		let journal = std::fs::read_to_string(tmp.path().join(CORRUPTION_JOURNAL)).unwrap();
+		assert_eq!(journal.lines().count(), 2);
+	}
+
+	#[test]
+	fn test_check_report() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[1].btree_index = true;
+		let value = vec![7u8; 5000];
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(value.clone())), (0, b"b", Some(b"2".to_vec()))])
+			.unwrap();
+		drop(db);
+		drop(Db::open(&options).unwrap());
+		let check = || crate::CheckOptions::new(None, None, None, false, None);
+		let db = Db::open(&options).unwrap();
+		let report = db.check(check()).unwrap();
+		assert_eq!(report.entries, 2);
+		assert!(report.corrupted.is_empty());
+		assert!(db.check(crate::CheckOptions::new(Some(2), None, None, false, None)).is_err());
+		drop(db);
+
+		let compressed =
+			lz4::block::compress(&value, Some(lz4::block::CompressionMode::DEFAULT), true).unwrap();
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			if path.file_name().unwrap().to_str().unwrap().starts_with("table_00") {
+				let mut data = std::fs::read(&path).unwrap();
+				if let Some(pos) = data.windows(compressed.len()).position(|w| w == compressed) {
+					data[pos..pos + 4].copy_from_slice(&[0xff; 4]);
+					std::fs::write(&path, data).unwrap();
+				}
+			}
+		}
+		let db = Db::open(&options).unwrap();
+		let report = db.check(check()).unwrap();
+		assert_eq!(report.entries, 2);
+		assert_eq!(report.corrupted.len(), 1);
+		let corrupted = &report.corrupted[0];
+		assert_eq!(corrupted.col, 0);
+		assert!(corrupted.error.is_some());
+
+		db.force_remove_at(corrupted.col, corrupted.tier, corrupted.index).unwrap();
+		let report = db.check(check()).unwrap();
+		assert_eq!(report.entries, 1);
+		assert!(report.corrupted.is_empty());
+		assert_eq!(db.get(0, b"b").unwrap(), Some(b"2".to_vec()));
+	}
+
+	#[test]
+	fn test_checkpoint_replay() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.checkpoint_interval = Some(0);
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::LogOverlay,
+			..Default::default()
+		};
+		let db = Db::open_inner(&options, &inner_options).unwrap();
+		db.commit(vec![(0, b"key1".to_vec(), Some(b"value1".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		let _ = db.inner.log.flush_one(0).unwrap();
+		while db.inner.enact_logs(false).unwrap() {}
+		let covered = db.inner.last_enacted.load(Ordering::SeqCst);
+		db.inner.process_checkpoint().unwrap();
+		db.commit(vec![(0, b"key2".to_vec(), Some(b"value2".to_vec()))]).unwrap();
+		EnableCommitPipelineStages::LogOverlay.run_stages(&db);
+		let _ = db.inner.log.flush_one(0).unwrap();
+		drop(db);
+
+		// Lose all index writes. The first record is only recoverable from the checkpoint.
+		let index = std::fs::read_dir(tmp.path())
+			.unwrap()
+			.map(|e| e.unwrap().path())
+			.find(|p| p.file_name().unwrap().to_str().unwrap().starts_with("index_00_"))
+			.unwrap();
+		let file = std::fs::OpenOptions::new().write(true).open(&index).unwrap();
+		let len = file.metadata().unwrap().len();
+		file.set_len(16 * 1024).unwrap();
+		file.set_len(len).unwrap();
+		drop(file);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.inner.replay_checkpoint, covered);
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_commit_with_ack() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.sync_wal = false;
+		let db = Db::open_or_create(&options).unwrap();
+		let first = db
+			.commit_with_ack(vec![(0, b"key1".to_vec(), Some(b"value1".to_vec()))])
+			.unwrap();
+		let second = db
+			.commit_with_ack(vec![(0, b"key2".to_vec(), Some(b"value2".to_vec()))])
+			.unwrap();
+		assert!(first.fence() < second.fence());
+		second.wait().unwrap();
+		assert!(db.inner.last_enacted.load(Ordering::SeqCst) >= 2);
+		first.wait().unwrap();
+		assert_eq!(db.get(0, b"key1").unwrap(), Some(b"value1".to_vec()));
+	}
+
+	#[test]
+	fn test_force_remove_at() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![(0, b"key".to_vec(), Some(b"value".to_vec()))]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		let tier = match &db.inner.columns[0] {
+			Column::Hash(column) => (0..=255u8).find(|t| column.has_slot(*t, 1)).unwrap(),
+			Column::Tree(_) => unreachable!(),
+		};
+		assert!(db.force_remove_at(0, tier, 2).is_err());
+		assert!(db.force_remove_at(1, 0, 1).is_err());
+
+		db.force_remove_at(0, tier, 1).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+		db.force_remove_at(0, tier, 1).unwrap();
+		let journal = std::fs::read_to_string(tmp.path().join(REPAIR_JOURNAL)).unwrap();
+		let lines: Vec<_> = journal.lines().collect();
+		assert_eq!(lines.len(), 2);
+		assert!(lines[0].ends_with("action=force_remove index_entries=1"));
+		assert!(lines[1].ends_with("action=skipped reason=already_free"));
+
+		db.commit(vec![(0, b"key2".to_vec(), Some(b"value2".to_vec()))]).unwrap();
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+		assert_eq!(db.get(0, b"key2").unwrap(), Some(b"value2".to_vec()));
+	}
+
+	#[test]
+	fn test_store_keys() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].store_keys = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let long_key = vec![5u8; 100];
+		let mut other_key = long_key.clone();
+		other_key[99] = 6;
+		db.commit(vec![
+			(0, long_key.clone(), Some(b"value".to_vec())),
+			(0, b"short".to_vec(), Some(b"value2".to_vec())),
+		])
+		.unwrap();
+		assert_eq!(db.get(0, &long_key).unwrap(), Some(b"value".to_vec()));
+		assert_eq!(db.get_size(0, &long_key).unwrap(), Some(5));
+		assert_eq!(db.get(0, &other_key).unwrap(), None);
+		db.inner.wait_for_queued_commits().unwrap();
+		assert_eq!(db.get(0, &other_key).unwrap(), None);
+
+		let mut entries = Vec::new();
+		db.iter_column_keys_while(0, |key, value| {
+			entries.push((key.to_vec(), value.to_vec()));
+			true
+		})
+		.unwrap();
+		entries.sort();
+		assert_eq!(
+			entries,
+			vec![(long_key, b"value".to_vec()), (b"short".to_vec(), b"value2".to_vec())]
+		);
+
+		// Uniform columns only index the first 32 bytes of the key, so keys that share them
+		// would overwrite each other.
+		options.columns[0].uniform = true;
+		assert!(!options.is_valid());
+		options.columns[0].uniform = false;
+		options.columns[0].btree_index = true;
+		assert!(!options.is_valid());
+	}
+
+	#[test]
+	fn test_relocate() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(&tmp.path().join("db"), 1);
+		let db = Arc::new(Db::open_or_create(&options).unwrap());
+		let value = vec![7u8; 1000];
+		db.commit((0..1000u32).map(|i| (0, i.to_le_bytes().to_vec(), Some(value.clone()))))
+			.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+
+		let new_path = tmp.path().join("relocated");
+		let relocation = {
+			let db = db.clone();
+			let new_path = new_path.clone();
+			std::thread::spawn(move || db.relocate(&new_path, Some(64 * 1024 * 1024)))
+		};
+		// Keep writing until commits are rejected.
+		let mut committed = 1000u32;
+		while db.commit(vec![(0, committed.to_le_bytes().to_vec(), Some(vec![1]))]).is_ok() {
+			committed += 1;
+		}
+		relocation.join().unwrap().unwrap();
+		assert!(db.relocate(&new_path, None).is_err());
+		assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), Some(value.clone()));
+		std::mem::drop(db);
+
+		let db = Db::open(&Options::with_columns(&new_path, 1)).unwrap();
+		assert_eq!(db.get(0, &0u32.to_le_bytes()).unwrap(), Some(value));
+		for i in 1000..committed {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![1]));
+		}
+		assert_eq!(db.get(0, &committed.to_le_bytes()).unwrap(), None);
+	}
+
+	#[test]
+	fn test_old_format_log_records() {
+		let tmp = tempdir().unwrap();
+		let salt = [5; 32];
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.salt = Some(salt);
+		options.change_archive = Some(tmp.path().join("archive"));
+		options.compress_log = true;
+		options.commit_chunk_size = Some(2);
+		options.checkpoint_interval = Some(0);
+		options.write_metadata_with_version(tmp.path(), &salt, Some(7)).unwrap();
+		let value = vec![7u8; 1000];
+		let db = Db::open(&options).unwrap();
+		for i in 0..3u8 {
+			db.commit((0..5u8).map(|j| (0, vec![i, j], Some(value.clone())))).unwrap();
+			db.inner.wait_for_queued_commits().unwrap();
+		}
+		let records: Vec<_> = db.tail_changes(0).unwrap().map(|r| r.unwrap()).collect();
+		assert_eq!(records.len(), 3);
+		for (_, data) in &records {
+			// An unframed record that starts with an uncompressed insert, so it is neither a
+			// checkpoint nor a part of a split commit.
+			assert_eq!(data[0], 1);
+			assert!(matches!(data[9], 2 | 3));
+		}
+		std::mem::drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &[2, 4]).unwrap(), Some(value));
+		assert_eq!(Db::format_version(tmp.path()).unwrap(), Some(7));
+	}
+
+	#[test]
+	fn test_tail_changes() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		assert!(Db::open_or_create(&options).unwrap().tail_changes(0).is_err());
+		options.change_archive = Some(tmp.path().join("archive"));
+
+		let commit = |db: &Db, i: u8| {
+			db.commit(vec![(0, vec![i], Some(vec![i]))]).unwrap();
+			db.inner.wait_for_queued_commits().unwrap();
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		commit(&db, 1);
+		commit(&db, 2);
+		let records: Vec<_> = db.tail_changes(0).unwrap().map(|r| r.unwrap()).collect();
+		assert_eq!(records.len(), 2);
+		assert_eq!(records[1].0, records[0].0 + 1);
+		for (record_id, data) in &records {
+			assert_eq!(data[0], 10);
+			assert_eq!(&data[1..9], &record_id.to_le_bytes());
+		}
+
+		// Polling the same stream returns new records.
+		let mut stream = db.tail_changes(records[1].0).unwrap();
+		assert_eq!(stream.next().unwrap().unwrap(), records[1]);
+		assert!(stream.next().is_none());
+		commit(&db, 3);
+		let third = stream.next().unwrap().unwrap();
+		assert_eq!(third.0, records[1].0 + 1);
+		std::mem::drop(db);
+
+		// Record ids continue after reopening.
+		let db = Db::open_or_create(&options).unwrap();
+		commit(&db, 4);
+		let records: Vec<_> = db.tail_changes(third.0 + 1).unwrap().map(|r| r.unwrap()).collect();
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].0, third.0 + 1);
+		assert_eq!(db.tail_changes(0).unwrap().count(), 4);
+	}
+
+	#[test]
+	fn test_apply_log_record() {
+		let primary_dir = tempdir().unwrap();
+		let replica_dir = tempdir().unwrap();
+		let mut options = Options::with_columns(primary_dir.path(), 1);
+		options.change_archive = Some(primary_dir.path().join("archive"));
+		options.commit_chunk_size = Some(2);
+		options.salt = Some([1; 32]);
+		let primary = Db::open_or_create(&options).unwrap();
+		primary.commit(vec![(0, vec![0], Some(vec![0]))]).unwrap();
+		primary.commit((1u8..6).map(|i| (0, vec![i], Some(vec![i])))).unwrap();
+		primary.inner.wait_for_queued_commits().unwrap();
+		let records: Vec<_> = primary.tail_changes(0).unwrap().map(|r| r.unwrap()).collect();
+		assert_eq!(records.len(), 4);
+
+		let mut replica_options = Options::with_columns(replica_dir.path(), 1);
+		replica_options.salt = options.salt;
+		replica_options.change_archive = Some(replica_dir.path().join("archive"));
+		let replica = Db::open_or_create(&replica_options).unwrap();
+		assert!(replica.apply_log_record(&records[1].1).is_err());
+		let mut damaged = records[0].1.clone();
+		*damaged.last_mut().unwrap() ^= 1;
+		assert!(replica.apply_log_record(&damaged).is_err());
+		assert_eq!(replica.apply_log_record(&records[0].1).unwrap(), records[0].0);
+		assert_eq!(replica.get(0, &[0]).unwrap(), Some(vec![0]));
+		// The split commit is enacted once all parts are applied.
+		assert_eq!(replica.apply_log_record(&records[1].1).unwrap(), records[1].0);
+		assert!(replica.apply_log_record(&records[3].1).is_err());
+		assert_eq!(replica.apply_log_record(&records[2].1).unwrap(), records[2].0);
+		assert_eq!(replica.get(0, &[1]).unwrap(), None);
+		assert_eq!(replica.apply_log_record(&records[3].1).unwrap(), records[3].0);
+		for i in 0u8..6 {
+			assert_eq!(replica.get(0, &[i]).unwrap(), Some(vec![i]));
+		}
+		std::mem::drop(replica);
+
+		// The position is kept across restarts.
+		let replica = Db::open(&replica_options).unwrap();
+		assert_eq!(replica.get(0, &[5]).unwrap(), Some(vec![5]));
+		primary.commit(vec![(0, vec![0], None)]).unwrap();
+		primary.inner.wait_for_queued_commits().unwrap();
+		let (record_id, record) =
+			primary.tail_changes(records[3].0 + 1).unwrap().next().unwrap().unwrap();
+		assert_eq!(replica.apply_log_record(&record).unwrap(), record_id);
+		assert_eq!(replica.get(0, &[0]).unwrap(), None);
+	}
+
+	#[test]
+	fn test_apply_log_record_bloom() {
+		let primary_dir = tempdir().unwrap();
+		let replica_dir = tempdir().unwrap();
+		let mut options = Options::with_columns(primary_dir.path(), 1);
+		options.change_archive = Some(primary_dir.path().join("archive"));
+		options.columns[0].bloom_filter_bytes = 1024;
+		options.salt = Some([1; 32]);
+		let primary = Db::open_or_create(&options).unwrap();
+		primary.commit((0u8..20).map(|i| (0, vec![i], Some(vec![i])))).unwrap();
+		primary.inner.wait_for_queued_commits().unwrap();
+
+		let mut replica_options = options.clone();
+		replica_options.path = replica_dir.path().to_path_buf();
+		replica_options.change_archive = None;
+		let replica = Db::open_or_create(&replica_options).unwrap();
+		for change in primary.tail_changes(0).unwrap() {
+			let (_, record) = change.unwrap();
+			replica.apply_log_record(&record).unwrap();
+		}
+		let check = |db: &Db| {
+			for i in 0u8..20 {
+				assert_eq!(db.get(0, &[i]).unwrap(), Some(vec![i]));
+			}
+		};
+		check(&replica);
+		std::mem::drop(replica);
+
+		// The saved filter has the applied keys.
+		let replica = Db::open(&replica_options).unwrap();
+		check(&replica);
 	}
-}
 
-#[derive(Default)]
-pub struct CommitChangeSet {
-	pub indexed: HashMap<ColId, IndexedChangeSet>,
-	pub btree_indexed: HashMap<ColId, BTreeChangeSet>,
-}
+	#[test]
+	fn test_export_import() {
+		let source_dir = tempdir().unwrap();
+		let mut options = Options::with_columns(source_dir.path(), 3);
+		options.columns[0].uniform = true;
+		options.columns[0].ref_counted = true;
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[0].compression_threshold = 16;
+		options.columns[2].btree_index = true;
+		let source = Db::open_or_create(&options).unwrap();
+		let uniform_key = [1u8; 32];
+		let big_value = vec![3u8; 1000];
+		source
+			.commit(vec![
+				(0, uniform_key.to_vec(), Some(big_value.clone())),
+				(0, uniform_key.to_vec(), Some(big_value.clone())),
+				(1, b"key1".to_vec(), Some(b"value1".to_vec())),
+				(2, b"key2".to_vec(), Some(b"value2".to_vec())),
+			])
+			.unwrap();
+		let source_salt = source.inner.options.salt;
+		// Export only sees data that was written to the log.
+		source.inner.wait_for_queued_commits().unwrap();
+		let mut exported = [Vec::new(), Vec::new(), Vec::new()];
+		for (col, data) in exported.iter_mut().enumerate() {
+			assert_eq!(source.export(col as ColId, data).unwrap(), 1);
+		}
 
-pub struct IndexedChangeSet {
-	pub col: ColId,
-	pub changes: Vec<(Key, Option<Value>)>,
-}
+		// Different salt and column options.
+		let dest_dir = tempdir().unwrap();
+		let mut options = Options::with_columns(dest_dir.path(), 3);
+		options.columns[0].uniform = true;
+		options.columns[0].ref_counted = true;
+		let dest = Db::open_or_create(&options).unwrap();
+		assert_eq!(dest.import(0, exported[0].as_slice()).unwrap(), 1);
+		assert!(dest.import(1, exported[1].as_slice()).is_err());
+		assert_eq!(dest.import(2, exported[2].as_slice()).unwrap(), 1);
+		// Removing once leaves a single reference.
+		dest.commit(vec![(0, uniform_key.to_vec(), None)]).unwrap();
+		assert_eq!(dest.get(0, &uniform_key).unwrap(), Some(big_value));
+		assert_eq!(dest.get(2, b"key2").unwrap(), Some(b"value2".to_vec()));
+		std::mem::drop(dest);
+
+		// Same salt.
+		let dest_dir = tempdir().unwrap();
+		let mut options = Options::with_columns(dest_dir.path(), 2);
+		options.salt = source_salt;
+		let dest = Db::open_or_create(&options).unwrap();
+		assert_eq!(dest.import(1, exported[1].as_slice()).unwrap(), 1);
+		assert_eq!(dest.get(1, b"key1").unwrap(), Some(b"value1".to_vec()));
+	}
 
-impl IndexedChangeSet {
-	pub fn new(col: ColId) -> Self {
-		IndexedChangeSet { col, changes: Default::default() }
+	#[test]
+	fn test_export_import_store_keys() {
+		// Column 0 stores keys, column 1 does not.
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].store_keys = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit(vec![
+			(0, b"key0".to_vec(), Some(b"value0".to_vec())),
+			(1, b"key1".to_vec(), Some(b"value1".to_vec())),
+		])
+		.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+
+		let mut exported = Vec::new();
+		assert_eq!(db.export(0, &mut exported).unwrap(), 1);
+		let (mut input, header) = super::ExportReader::new(exported.as_slice()).unwrap();
+		assert_eq!(header.key_kind, super::KeyKind::Plain);
+		let entry = input.next().unwrap().unwrap();
+		assert_eq!((entry.key, entry.value), (b"key0".to_vec(), b"value0".to_vec()));
+		let mut exported_values = Vec::new();
+		assert_eq!(db.export_values(0, &mut exported_values).unwrap(), 1);
+
+		// Plain keys go both ways.
+		assert_eq!(db.import(1, exported.as_slice()).unwrap(), 1);
+		assert_eq!(db.get(1, b"key0").unwrap(), Some(b"value0".to_vec()));
+		db.commit(vec![(0, b"key0".to_vec(), None)]).unwrap();
+		assert_eq!(db.import(0, exported_values.as_slice()).unwrap(), 1);
+		assert_eq!(db.get(0, b"key0").unwrap(), Some(b"value0".to_vec()));
+		db.inner.wait_for_queued_commits().unwrap();
+		let mut exported = Vec::new();
+		assert_eq!(db.export(1, &mut exported).unwrap(), 2);
+		// Hashed keys can't be stored.
+		assert!(matches!(db.import(0, exported.as_slice()), Err(Error::InvalidInput(_))));
+		let mut keys = Vec::new();
+		db.iter_column_keys_while(0, |key, value| {
+			keys.push((key.to_vec(), value.to_vec()));
+			true
+		})
+		.unwrap();
+		assert_eq!(keys, vec![(b"key0".to_vec(), b"value0".to_vec())]);
 	}
 
-	fn push(&mut self, key: &[u8], v: Option<Value>, options: &Options, db_version: u32) {
-		let salt = options.salt.unwrap_or_default();
-		let k = hash_key(key, &salt, options.columns[self.col as usize].uniform, db_version);
-		self.changes.push((k, v));
+	#[test]
+	fn test_import_large_rc() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let rc = 2 * super::IMPORT_BATCH_SIZE + 3;
+		let mut data = Vec::new();
+		let header =
+			super::ExportHeader { key_kind: super::KeyKind::Plain, salt: Default::default() };
+		let mut out = super::ExportWriter::new(
+			&mut data,
+			&header,
+			super::Compress::new(crate::CompressionType::NoCompression, 0),
+		)
+		.unwrap();
+		out.write_entry(b"key", Some(rc as u32), b"value").unwrap();
+		out.finish().unwrap();
+		assert_eq!(db.import(0, data.as_slice()).unwrap(), 1);
+		db.commit((1..rc).map(|_| (0, b"key".to_vec(), None))).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		db.commit(vec![(0, b"key".to_vec(), None)]).unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+
+		// Counts that would take billions of inserts are rejected.
+		let mut data = Vec::new();
+		let mut out = super::ExportWriter::new(
+			&mut data,
+			&header,
+			super::Compress::new(crate::CompressionType::NoCompression, 0),
+		)
+		.unwrap();
+		out.write_entry(b"other", Some(super::MAX_IMPORT_REFS + 1), b"value").unwrap();
+		out.finish().unwrap();
+		assert!(matches!(db.import(0, data.as_slice()), Err(Error::InvalidInput(_))));
+		assert_eq!(db.get(0, b"other").unwrap(), None);
 	}
 
-	fn copy_to_overlay(
-		&self,
-		overlay: &mut CommitOverlay,
-		record_id: u64,
-		bytes: &mut usize,
-		options: &Options,
-	) {
-		let ref_counted = options.columns[self.col as usize].ref_counted;
-		for (k, v) in self.changes.iter() {
-			*bytes += k.len();
-			*bytes += v.as_ref().map_or(0, |v| v.len());
-			// Don't add removed ref-counted values to overlay.
-			if !ref_counted || v.is_some() {
-				overlay.indexed.insert(*k, (record_id, v.clone()));
+	#[test]
+	fn test_export_prefix() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([b"a1", b"b1", b"b2", b"c1"].map(|k| (1, k.to_vec(), Some(k.to_vec()))).to_vec())
+			.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+		let mut exported = Vec::new();
+		assert_eq!(db.export_prefix(1, b"b", &mut exported).unwrap(), 2);
+		assert!(matches!(db.export_prefix(0, b"b", Vec::new()), Err(Error::InvalidInput(_))));
+
+		let dest_dir = tempdir().unwrap();
+		options.path = dest_dir.path().into();
+		let dest = Db::open_or_create(&options).unwrap();
+		assert_eq!(dest.import(1, exported.as_slice()).unwrap(), 2);
+		assert_eq!(dest.get(1, b"b2").unwrap(), Some(b"b2".to_vec()));
+		assert_eq!(dest.get(1, b"c1").unwrap(), None);
+	}
+
+	#[test]
+	fn test_commit_with_outcomes() {
+		use crate::CommitOutcome::*;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[1].preimage = true;
+		options.columns[2].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let v = |v: &[u8]| Some(v.to_vec());
+		let outcomes = db
+			.commit_with_outcomes(vec![
+				(0, &b"key"[..], v(b"a")),
+				(1, &b"key"[..], v(b"a")),
+				(2, &b"key"[..], v(b"a")),
+				(0, &b"missing"[..], None),
+				(2, &b"missing"[..], None),
+				(2, &b"key"[..], v(b"b")),
+			])
+			.unwrap();
+		assert_eq!(outcomes, vec![Inserted, Inserted, Inserted, NotFound, NotFound, Replaced]);
+
+		let outcomes = db
+			.commit_with_outcomes(vec![
+				(0, &b"key"[..], v(b"b")),
+				(1, &b"key"[..], v(b"a")),
+				(1, &b"key"[..], None),
+				(1, &b"key"[..], None),
+				(0, &b"key"[..], None),
+				(2, &b"key"[..], None),
+			])
+			.unwrap();
+		assert_eq!(
+			outcomes,
+			vec![Replaced, RefIncremented, RefDecremented, Deleted, Deleted, Deleted]
+		);
+		assert_eq!(db.get(0, b"key").unwrap(), None);
+		assert_eq!(db.get(1, b"key").unwrap(), None);
+		assert_eq!(db.get(2, b"key").unwrap(), None);
+	}
+
+	#[test]
+	fn test_append() {
+		use crate::{CommitOutcome::*, Operation::*};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		options.columns[2].btree_index = true;
+		let mut expected = vec![Vec::new(); 2];
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for col in 0..2 {
+				let outcomes = db
+					.commit_operations(vec![
+						(col, Append(&b"log"[..], b"event0".to_vec())),
+						(col, Set(&b"set"[..], b"value".to_vec())),
+						(col, Append(&b"set"[..], b"+1".to_vec())),
+					])
+					.unwrap();
+				assert_eq!(outcomes, vec![Inserted, Inserted, Appended]);
+				assert_eq!(db.get(col, b"set").unwrap(), Some(b"value+1".to_vec()));
+				expected[col as usize].extend_from_slice(b"event0");
 			}
+			// Grows past the largest single entry tier.
+			for i in 1..100u32 {
+				let event: Vec<u8> = (0..1000).map(|j| (i + j) as u8).collect();
+				for col in 0..2 {
+					db.commit_operations(vec![(col, Append(&b"log"[..], event.clone()))]).unwrap();
+					expected[col as usize].extend_from_slice(&event);
+				}
+				if i % 30 == 0 {
+					db.inner.wait_for_queued_commits().unwrap();
+				}
+				assert_eq!(db.get(0, b"log").unwrap().as_ref(), Some(&expected[0]));
+			}
+			assert_eq!(db.get(1, b"log").unwrap().as_ref(), Some(&expected[1]));
+			assert!(db.commit_operations(vec![(2, Append(&b"log"[..], b"x".to_vec()))]).is_err());
+			assert_eq!(db.get(2, b"log").unwrap(), None);
+		}
+		let db = Db::open(&options).unwrap();
+		for col in 0..2 {
+			assert_eq!(db.get(col, b"log").unwrap().as_ref(), Some(&expected[col as usize]));
 		}
 	}
 
-	fn write_plan(
-		&self,
-		column: &Column,
-		writer: &mut crate::log::LogWriter,
-		ops: &mut u64,
-		reindex: &mut bool,
-	) -> Result<()> {
-		let column = match column {
-			Column::Hash(column) => column,
-			Column::Tree(_) => {
-				log::warn!(target: "parity-db", "Skipping unindex commit in indexed column");
-				return Ok(())
-			},
-		};
-		for (key, value) in self.changes.iter() {
-			if let PlanOutcome::NeedReindex =
-				column.write_plan(key, value.as_ref().map(|v| v.as_slice()), writer)?
-			{
-				// Reindex has triggered another reindex.
-				*reindex = true;
-			}
-			*ops += 1;
+	#[test]
+	fn test_counters() {
+		use crate::{CommitOutcome::*, Operation::*};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].counters = true;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			let outcomes = db
+				.commit_operations(vec![
+					(0, Increment(&b"a"[..], 2)),
+					(0, Increment(&b"a"[..], 3)),
+					(0, Increment(&b"b"[..], -1)),
+					(0, Increment(&b"c"[..], 0)),
+				])
+				.unwrap();
+			assert_eq!(outcomes, vec![Inserted, Replaced, Inserted, NotFound]);
+			assert_eq!(db.get_counter(0, b"a").unwrap(), 5);
+			assert_eq!(db.get_counter(0, b"b").unwrap(), -1);
+			assert_eq!(db.get(0, b"a").unwrap(), Some(5i64.to_le_bytes().to_vec()));
+
+			let outcomes = db.commit_operations(vec![(0, Increment(&b"a"[..], -5))]).unwrap();
+			assert_eq!(outcomes, vec![Deleted]);
+			assert_eq!(db.get(0, b"a").unwrap(), None);
+			assert_eq!(db.get_counter(0, b"a").unwrap(), 0);
+			db.commit([(0, b"d", Some(7i64.to_le_bytes().to_vec()))]).unwrap();
+			db.commit_operations(vec![(0, Increment(&b"d"[..], 1))]).unwrap();
+
+			assert!(db.commit([(0, b"e", Some(vec![1]))]).is_err());
+			assert!(db.commit_operations(vec![(1, Increment(&b"a"[..], 1))]).is_err());
+			assert!(db.commit_operations(vec![(0, Append(&b"a"[..], vec![1]))]).is_err());
+			assert!(db.get_counter(1, b"a").is_err());
 		}
-		Ok(())
+		// The option is part of the column metadata.
+		assert!(Db::open(&Options::with_columns(tmp.path(), 2)).is_err());
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get_counter(0, b"b").unwrap(), -1);
+		assert_eq!(db.get_counter(0, b"d").unwrap(), 8);
 	}
 
-	fn clean_overlay(&self, overlay: &mut CommitOverlay, record_id: u64) {
-		use std::collections::hash_map::Entry;
-		for (key, _) in self.changes.iter() {
-			if let Entry::Occupied(e) = overlay.indexed.entry(*key) {
-				if e.get().0 == record_id {
-					e.remove_entry();
-				}
+	#[test]
+	fn test_inline_values() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].inline_values = true;
+		options.columns[0].preimage = true;
+		options.columns[1].inline_values = true;
+		options.columns[1].preimage = true;
+		options.columns[1].uniform = true;
+		options.max_log_size = 0;
+		let v = |v: &[u8]| v.to_vec();
+		let h = |v: &[u8]| blake2_rfc::blake2b::blake2b(32, &[], v).as_bytes().to_vec();
+		let hashed = |db: &Db, col: usize, key: &[u8]| match &db.inner.columns[col] {
+			Column::Hash(column) => column.hash_key(key),
+			Column::Tree(_) => unreachable!(),
+		};
+		// Shares the bits held by the index entry with the key of "ab".
+		let mut colliding = h(b"ab");
+		colliding[31] ^= 1;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![
+				(0, h(b""), Some(v(b""))),
+				(0, h(b"ab"), Some(v(b"ab"))),
+				(0, h(b"abc"), Some(v(b"abc"))),
+				(0, v(b"other"), Some(v(b"ab"))),
+				(1, h(b"ab"), Some(v(b"ab"))),
+			])
+			.unwrap();
+			assert_eq!(db.get(0, &h(b"ab")).unwrap(), Some(v(b"ab")));
+			db.inner.wait_for_queued_commits().unwrap();
+			// Values of up to 2 bytes fit the entries of the initial index, if they are the
+			// preimage of their key.
+			let sizes = db.column_sizes().unwrap();
+			assert_eq!(sizes[0].entries, 2);
+			assert_eq!(sizes[0].tier_entries[0], 0);
+			assert_eq!(sizes[1].entries, 0);
+			assert_eq!(db.get(0, &h(b"")).unwrap(), Some(v(b"")));
+			assert_eq!(db.get(0, &h(b"ab")).unwrap(), Some(v(b"ab")));
+			assert_eq!(db.get(0, &h(b"abc")).unwrap(), Some(v(b"abc")));
+			assert_eq!(db.get(0, b"other").unwrap(), Some(v(b"ab")));
+			assert_eq!(db.get_size(0, &h(b"ab")).unwrap(), Some(2));
+			assert_eq!(db.get(0, &h(b"missing")).unwrap(), None);
+
+			// A key that only shares the index bits is not taken for the inline one.
+			assert_eq!(db.get(1, &colliding).unwrap(), None);
+			db.commit(vec![(1, colliding.clone(), Some(v(b"xy")))]).unwrap();
+			db.inner.wait_for_queued_commits().unwrap();
+			assert_eq!(db.get(1, &colliding).unwrap(), Some(v(b"xy")));
+			assert_eq!(db.get(1, &h(b"ab")).unwrap(), Some(v(b"ab")));
+			db.commit(vec![(1, h(b"ab"), None)]).unwrap();
+			assert_eq!(db.get(1, &h(b"ab")).unwrap(), None);
+			assert_eq!(db.get(1, &colliding).unwrap(), Some(v(b"xy")));
+			db.commit(vec![(1, h(b"ab"), Some(v(b"ab")))]).unwrap();
+
+			// Keys of inline values are recovered in full.
+			let mut values = Vec::new();
+			db.iter_column_while(0, |state| {
+				values.push((state.value, state.key));
+				true
+			})
+			.unwrap();
+			values.sort();
+			assert_eq!(values.len(), 4);
+			assert_eq!(values[0], (v(b""), hashed(&db, 0, &h(b""))));
+			assert!(values.contains(&(v(b"ab"), hashed(&db, 0, &h(b"ab")))));
+
+			// Inline values are moved to the grown index with their full keys.
+			assert!(db.trigger_reindex(1).unwrap());
+			let start = std::time::Instant::now();
+			while db.reindex_progress(1).is_some() {
+				assert!(start.elapsed() < Duration::from_secs(5));
+				std::thread::sleep(Duration::from_millis(1));
 			}
+			assert_eq!(db.column_info(1).unwrap().index_bits, Some(17));
+			assert_eq!(db.get(1, &h(b"ab")).unwrap(), Some(v(b"ab")));
+			assert_eq!(db.get(1, &colliding).unwrap(), Some(v(b"xy")));
 		}
+		// The option is part of the column metadata.
+		assert!(Db::open(&Options::with_columns(tmp.path(), 2)).is_err());
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &h(b"ab")).unwrap(), Some(v(b"ab")));
+		assert_eq!(db.get(0, &h(b"")).unwrap(), Some(v(b"")));
+		assert_eq!(db.get(1, &h(b"ab")).unwrap(), Some(v(b"ab")));
+		assert_eq!(db.get(1, &colliding).unwrap(), Some(v(b"xy")));
+		drop(db);
+
+		// Only preimage columns can hold inline values.
+		options.columns[0].preimage = false;
+		assert!(!options.columns[0].is_valid());
 	}
-}
 
-/// Verification operation utilities.
-pub mod check {
-	pub enum CheckDisplay {
-		None,
-		Full,
-		Short(u64),
+	#[test]
+	fn test_insert_if_absent() {
+		use crate::{CommitOutcome::*, Operation::*};
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[1].preimage = true;
+		options.columns[2].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let v = |v: &[u8]| v.to_vec();
+		db.commit(vec![(0, b"key", Some(v(b"a"))), (2, b"key", Some(v(b"a")))]).unwrap();
+		let outcomes = db
+			.commit_operations(vec![
+				(0, InsertIfAbsent(&b"key"[..], v(b"b"))),
+				(0, InsertIfAbsent(&b"new"[..], v(b"b"))),
+				(1, InsertIfAbsent(&b"key"[..], v(b"a"))),
+				(1, InsertIfAbsent(&b"key"[..], v(b"a"))),
+				(2, InsertIfAbsent(&b"key"[..], v(b"b"))),
+				(2, InsertIfAbsent(&b"new"[..], v(b"b"))),
+				(2, Dereference(&b"new"[..])),
+				(2, InsertIfAbsent(&b"new"[..], v(b"c"))),
+			])
+			.unwrap();
+		assert_eq!(
+			outcomes,
+			vec![
+				Unchanged,
+				Inserted,
+				Inserted,
+				RefIncremented,
+				Unchanged,
+				Inserted,
+				Deleted,
+				Inserted
+			]
+		);
+		assert_eq!(db.get(0, b"key").unwrap(), Some(v(b"a")));
+		assert_eq!(db.get(0, b"new").unwrap(), Some(v(b"b")));
+		assert_eq!(db.get(2, b"key").unwrap(), Some(v(b"a")));
+		assert_eq!(db.get(2, b"new").unwrap(), Some(v(b"c")));
+		let outcomes = db.commit_with_outcomes(vec![(1, b"key", None), (1, b"key", None)]).unwrap();
+		assert_eq!(outcomes, vec![RefDecremented, Deleted]);
 	}
 
-	pub struct CheckOptions {
-		pub column: Option<u8>,
-		pub from: Option<u64>,
-		pub bound: Option<u64>,
-		pub display: CheckDisplay,
-	}
+	#[test]
+	fn test_multimap() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		options.columns[0].multimap = true;
+		let v = |v: &[u8]| Some(v.to_vec());
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![
+				(0, &b"1"[..], v(b"tx2")),
+				(0, &b"1"[..], v(b"tx1")),
+				(0, &b"1"[..], v(b"tx1")),
+				(0, &b"1\0"[..], v(b"tx3")),
+				(0, &b"2"[..], v(b"")),
+			])
+			.unwrap();
+			assert_eq!(db.get_values(0, b"1").unwrap(), vec![b"tx1".to_vec(), b"tx2".to_vec()]);
+			db.inner.wait_for_queued_commits().unwrap();
 
-	impl CheckOptions {
-		pub fn new(
-			column: Option<u8>,
-			from: Option<u64>,
-			bound: Option<u64>,
-			display_content: bool,
-			truncate_value_display: Option<u64>,
-		) -> Self {
-			let display = if display_content {
-				match truncate_value_display {
-					Some(t) => CheckDisplay::Short(t),
-					None => CheckDisplay::Full,
-				}
-			} else {
-				CheckDisplay::None
-			};
-			CheckOptions { column, from, bound, display }
+			db.commit(vec![(0, &b"1"[..], v(b"tx4")), (0, b"1", None), (0, b"1", v(b"tx2"))])
+				.unwrap();
+			assert_eq!(db.get_values(0, b"1").unwrap(), vec![b"tx2".to_vec()]);
+			assert!(db.iter_multimap(1).is_err());
 		}
+
+		let db = Db::open_or_create(&options).unwrap();
+		let mut iter = db.iter_multimap(0).unwrap();
+		iter.seek(b"").unwrap();
+		let mut entries = Vec::new();
+		while let Some(entry) = iter.next().unwrap() {
+			entries.push(entry);
+		}
+		assert_eq!(
+			entries,
+			vec![
+				(b"1".to_vec(), b"tx2".to_vec()),
+				(b"1\0".to_vec(), b"tx3".to_vec()),
+				(b"2".to_vec(), b"".to_vec()),
+			]
+		);
 	}
-}
 
-#[derive(Default)]
-struct InternalOptions {
-	create: bool,
-	read_only: bool,
-	commit_stages: EnableCommitPipelineStages,
-	skip_check_lock: bool,
-}
+	#[test]
+	fn test_compression_stats() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[0].compression_threshold = 64;
+		let db = Db::open_or_create(&options).unwrap();
+		let random: Vec<u8> =
+			(0..200u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+		db.commit(vec![
+			(0, b"small".to_vec(), Some(vec![1; 16])),
+			(0, b"zeros".to_vec(), Some(vec![0; 1000])),
+			(0, b"random".to_vec(), Some(random)),
+			(1, b"zeros".to_vec(), Some(vec![0; 1000])),
+		])
+		.unwrap();
+		db.inner.wait_for_queued_commits().unwrap();
+
+		let stats = db.compression_stats(0).unwrap();
+		assert_eq!((stats.values, stats.raw_bytes), (2, 1200));
+		assert!(stats.stored_bytes < 300 && stats.stored_bytes > 200);
+		assert!(stats.ratio() < 0.25);
+		assert_eq!(db.compression_stats(1).unwrap(), CompressionStats::default());
+		assert!(db.compression_stats(2).is_err());
+
+		let mut summary = Vec::new();
+		db.collect_stats(&mut summary, Some(0));
+		assert!(String::from_utf8(summary).unwrap().contains("Compressed values: 2"));
+	}
 
-// This is used in tests to disable certain commit stages.
-#[derive(Debug, Clone, Copy)]
-enum EnableCommitPipelineStages {
-	// No threads started, data stays in commit overlay.
-	#[allow(dead_code)]
-	CommitOverlay,
-	// Log worker run, data processed up to the log overlay.
-	#[allow(dead_code)]
-	LogOverlay,
-	// Runing all.
-	#[allow(dead_code)]
-	DbFile,
-	// Default run mode.
-	Standard,
-}
+	#[test]
+	fn test_value_size_histogram() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(vec![
+				(0, b"a".to_vec(), Some(vec![0; 10])),
+				(0, b"b".to_vec(), Some(vec![0; 20])),
+				(0, b"c".to_vec(), Some(vec![0; 100])),
+				(0, b"d".to_vec(), Some(vec![0; 40000])),
+				(0, b"e".to_vec(), Some(vec![0; 10])),
+			])
+			.unwrap();
+			db.commit(vec![(0, b"e".to_vec(), None)]).unwrap();
+		}
 
-impl Default for EnableCommitPipelineStages {
-	fn default() -> Self {
-		EnableCommitPipelineStages::Standard
+		let db = Db::open(&options).unwrap();
+		let histogram = db.value_size_histogram(0).unwrap();
+		assert_eq!(histogram.bucket_size, 32);
+		assert_eq!(histogram.buckets, vec![2, 0, 0, 1]);
+		assert_eq!(histogram.oversized, 1);
+		assert_eq!(db.value_size_histogram(1).unwrap(), Default::default());
 	}
-}
 
-impl EnableCommitPipelineStages {
-	#[cfg(test)]
-	fn run_stages(&self, db: &Db) {
-		let db = &db.inner;
-		match self {
-			EnableCommitPipelineStages::DbFile | EnableCommitPipelineStages::LogOverlay => {
-				while db.process_commits().unwrap() {}
-				while db.process_reindex().unwrap() {}
-			},
-			_ => (),
-		}
-		match self {
-			EnableCommitPipelineStages::DbFile => {
-				let _ = db.log.flush_one(0).unwrap();
-				let _ = db.log.flush_one(0).unwrap();
-				while db.enact_logs(false).unwrap() {}
-				let _ = db.log.flush_one(0).unwrap();
-				let _ = db.clean_logs().unwrap();
-			},
-			_ => (),
+	#[test]
+	fn test_column_metadata() {
+		struct SchemaCheck(Vec<u8>);
+
+		impl crate::ColumnMetadataCheck for SchemaCheck {
+			fn check(&self, col: ColId, metadata: Option<&[u8]>) -> bool {
+				col != 0 || metadata == Some(&self.0[..])
+			}
 		}
-	}
 
-	#[cfg(test)]
-	fn check_empty_overlay(&self, db: &DbInner, col: ColId) -> bool {
-		match self {
-			EnableCommitPipelineStages::DbFile | EnableCommitPipelineStages::LogOverlay => {
-				if let Some(overlay) = db.commit_overlay.read().get(col as usize) {
-					if !overlay.is_empty() {
-						let mut replayed = 5;
-						while !overlay.is_empty() {
-							if replayed > 0 {
-								replayed -= 1;
-								// the signal is triggered just before cleaning the overlay, so
-								// we wait a bit.
-								// Warning this is still rather flaky and should be ignored
-								// or removed.
-								std::thread::sleep(std::time::Duration::from_millis(100));
-							} else {
-								return false
-							}
-						}
-					}
-				}
-			},
-			_ => (),
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			assert_eq!(db.get_column_metadata(0).unwrap(), None);
+			db.set_column_metadata(0, b"schema-1").unwrap();
+			db.set_column_metadata(1, &[]).unwrap();
+			assert!(db.set_column_metadata(2, b"x").is_err());
+			assert!(db.set_column_metadata(0, &[0; 4097]).is_err());
+			db.commit(vec![(0, b"key".to_vec(), Some(b"value".to_vec()))]).unwrap();
 		}
-		true
-	}
 
-	fn join_on_shutdown(&self) -> bool {
-		matches!(self, EnableCommitPipelineStages::Standard)
-	}
-}
+		options.column_metadata_check = Some(Arc::new(SchemaCheck(b"schema-2".to_vec())));
+		assert!(matches!(Db::open(&options), Err(Error::InvalidConfiguration(_))));
 
-#[cfg(test)]
-mod tests {
-	use super::{Db, EnableCommitPipelineStages, InternalOptions, Options};
-	use std::collections::BTreeMap;
-	use tempfile::tempdir;
+		options.column_metadata_check = Some(Arc::new(SchemaCheck(b"schema-1".to_vec())));
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get_column_metadata(0).unwrap(), Some(b"schema-1".to_vec()));
+		assert_eq!(db.get_column_metadata(1).unwrap(), Some(Vec::new()));
+		assert_eq!(db.get(0, b"key").unwrap(), Some(b"value".to_vec()));
+		db.set_column_metadata(0, b"schema-2").unwrap();
+		drop(db);
+
+		options.column_metadata_check = Some(Arc::new(SchemaCheck(b"schema-2".to_vec())));
+		Db::open(&options).unwrap();
+	}
 
 	#[test]
-	fn test_db_open_should_fail() {
+	fn test_db_info() {
 		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
-		assert!(Db::open(&options).is_err(), "Database does not exist, so it should fail to open");
-		assert!(Db::open(&options)
-			.map(|_| ())
-			.unwrap_err()
-			.to_string()
-			.contains("use open_or_create"));
+		let options = Options::with_columns(tmp.path(), 1);
+		let info = Db::open_or_create(&options).unwrap().info();
+		let id = info.id.clone().unwrap();
+		assert_eq!(id.len(), 36);
+		assert_eq!(&id[14..15], "4");
+		assert!(info.created.unwrap() > 0);
+		assert_eq!(info.version, crate::options::CURRENT_VERSION);
+		assert_eq!(Db::open(&options).unwrap().info(), info);
+		assert_ne!(
+			Db::open_or_create(&Options::with_columns(tempdir().unwrap().path(), 1))
+				.unwrap()
+				.info()
+				.id,
+			info.id
+		);
+
+		// Metadata written by older versions has no id. One is assigned on open.
+		let path = tmp.path().join("metadata");
+		let legacy: String = std::fs::read_to_string(&path)
+			.unwrap()
+			.lines()
+			.filter(|l| !l.starts_with("id=") && !l.starts_with("created="))
+			.map(|l| format!("{}\n", l))
+			.collect();
+		std::fs::write(&path, legacy).unwrap();
+		assert_eq!(Db::open_read_only(&options).unwrap().info().id, None);
+		let info = Db::open(&options).unwrap().info();
+		assert!(info.id.is_some());
+		assert_eq!(info.created, None);
+		assert_eq!(Db::open(&options).unwrap().info(), info);
 	}
 
 	#[test]
-	fn test_db_open_or_create() {
-		let tmp = tempdir().unwrap();
-		let options = Options::with_columns(tmp.path(), 5);
-		assert!(Db::open_or_create(&options).is_ok(), "New database should be created");
-		assert!(Db::open(&options).is_ok(), "Existing database should be reopened");
+	fn test_export_values() {
+		let source_dir = tempdir().unwrap();
+		let mut options = Options::with_columns(source_dir.path(), 2);
+		options.columns[0].preimage = true;
+		let source = Db::open_or_create(&options).unwrap();
+		let preimage = b"preimage".to_vec();
+		let preimage_key = blake2_rfc::blake2b::blake2b(32, &[], &preimage);
+		let large = vec![7u8; 40000];
+		source
+			.commit(vec![
+				(0, preimage_key.as_bytes().to_vec(), Some(preimage.clone())),
+				(1, b"small".to_vec(), Some(b"value".to_vec())),
+				(1, b"large".to_vec(), Some(large.clone())),
+			])
+			.unwrap();
+		source.inner.wait_for_queued_commits().unwrap();
+
+		let mut exported = Vec::new();
+		assert_eq!(source.export_values(1, &mut exported).unwrap(), 2);
+		let (mut reader, _) = crate::export::ExportReader::new(exported.as_slice()).unwrap();
+		let mut entries = Vec::new();
+		while let Some(entry) = reader.next().unwrap() {
+			entries.push((entry.partial_key, entry.value));
+		}
+		// Multipart values are found through the index, so the key is known.
+		assert_eq!(entries, vec![(true, b"value".to_vec()), (false, large)]);
+		let dest_dir = tempdir().unwrap();
+		let mut dest_options = Options::with_columns(dest_dir.path(), 2);
+		dest_options.columns[0].preimage = true;
+		dest_options.salt = source.inner.options.salt;
+		let dest = Db::open_or_create(&dest_options).unwrap();
+		assert!(dest.import(1, exported.as_slice()).is_err());
+
+		let mut exported = Vec::new();
+		assert_eq!(source.export_values(0, &mut exported).unwrap(), 1);
+		assert_eq!(dest.import(0, exported.as_slice()).unwrap(), 1);
+		assert_eq!(dest.get(0, preimage_key.as_bytes()).unwrap(), Some(preimage));
 	}
 
 	#[test]
@@ -1299,9 +9108,8 @@ mod tests {
 		let key2 = b"key2".to_vec();
 		let key3 = b"key3".to_vec();
 
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = true;
-		inner_options.commit_stages = db_test;
+		let inner_options =
+			InternalOptions { create: true, commit_stages: db_test, ..Default::default() };
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 		assert!(db.get(col_nb, key1.as_slice()).unwrap().is_none());
 
@@ -1348,9 +9156,8 @@ mod tests {
 		let key3 = b"key3".to_vec();
 
 		let db_test = EnableCommitPipelineStages::DbFile;
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = true;
-		inner_options.commit_stages = db_test;
+		let inner_options =
+			InternalOptions { create: true, commit_stages: db_test, ..Default::default() };
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 
 		db.commit(vec![
@@ -1366,10 +9173,12 @@ mod tests {
 		std::thread::sleep(std::time::Duration::from_millis(100));
 
 		let db_test = EnableCommitPipelineStages::CommitOverlay;
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = false;
-		inner_options.commit_stages = db_test;
-		inner_options.skip_check_lock = true;
+		let inner_options = InternalOptions {
+			create: false,
+			commit_stages: db_test,
+			skip_check_lock: true,
+			..Default::default()
+		};
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 		assert_eq!(db.get(col_nb, key1.as_slice()).unwrap(), Some(b"value1".to_vec()));
 		assert_eq!(db.get(col_nb, key2.as_slice()).unwrap(), Some(b"value2".to_vec()));
@@ -1399,7 +9208,7 @@ mod tests {
 	}
 	fn test_indexed_btree_inner(db_test: EnableCommitPipelineStages, long_key: bool) {
 		let tmp = tempdir().unwrap();
-		let col_nb = 0u8;
+		let col_nb = 0u16;
 		let mut options = Options::with_columns(tmp.path(), 5);
 		options.columns[col_nb as usize].btree_index = true;
 
@@ -1412,9 +9221,8 @@ mod tests {
 			(vec![1; 953], key2, key3, vec![4; 79])
 		};
 
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = true;
-		inner_options.commit_stages = db_test;
+		let inner_options =
+			InternalOptions { create: true, commit_stages: db_test, ..Default::default() };
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 		assert_eq!(db.get(col_nb, &key1).unwrap(), None);
 
@@ -1464,6 +9272,41 @@ mod tests {
 		assert_eq!(iter.next().unwrap(), None);
 	}
 
+	#[test]
+	fn test_btree_prefix_compression() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		options.columns[1].btree_index = true;
+		options.columns[1].prefix_compression = true;
+		let key = |i: u32| {
+			let mut key = vec![7u8; 300];
+			key.extend_from_slice(&i.to_be_bytes());
+			key
+		};
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			for col in 0..2 {
+				db.commit((0..500).map(|i| (col, key(i), Some(i.to_le_bytes().to_vec()))))
+					.unwrap();
+				db.commit((0..500).step_by(3).map(|i| (col, key(i), None))).unwrap();
+			}
+		}
+
+		let db = Db::open(&options).unwrap();
+		let mut iter = db.iter(1).unwrap();
+		iter.seek(&key(100)).unwrap();
+		assert_eq!(iter.next().unwrap(), Some((key(100), 100u32.to_le_bytes().to_vec())));
+		assert_eq!(iter.next().unwrap(), Some((key(101), 101u32.to_le_bytes().to_vec())));
+		assert_eq!(iter.next().unwrap(), Some((key(103), 103u32.to_le_bytes().to_vec())));
+		for i in 0..500 {
+			assert_eq!(db.get(1, &key(i)).unwrap(), db.get(0, &key(i)).unwrap());
+		}
+		db.inner.wait_for_queued_commits().unwrap();
+		let sizes = db.column_sizes().unwrap();
+		assert!(sizes[1].live_bytes * 2 < sizes[0].live_bytes);
+	}
+
 	#[test]
 	fn test_indexed_btree_2() {
 		test_indexed_btree_inner_2(EnableCommitPipelineStages::CommitOverlay);
@@ -1471,7 +9314,7 @@ mod tests {
 	}
 	fn test_indexed_btree_inner_2(db_test: EnableCommitPipelineStages) {
 		let tmp = tempdir().unwrap();
-		let col_nb = 0u8;
+		let col_nb = 0u16;
 		let mut options = Options::with_columns(tmp.path(), 5);
 		options.columns[col_nb as usize].btree_index = true;
 
@@ -1479,9 +9322,11 @@ mod tests {
 		let key2 = b"key2".to_vec();
 		let key3 = b"key3".to_vec();
 
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = true;
-		inner_options.commit_stages = EnableCommitPipelineStages::DbFile;
+		let inner_options = InternalOptions {
+			create: true,
+			commit_stages: EnableCommitPipelineStages::DbFile,
+			..Default::default()
+		};
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 		let mut iter = db.iter(col_nb).unwrap();
 		assert_eq!(db.get(col_nb, &key1).unwrap(), None);
@@ -1494,10 +9339,12 @@ mod tests {
 		// issue with some file reopening when no delay
 		std::thread::sleep(std::time::Duration::from_millis(100));
 
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = false;
-		inner_options.commit_stages = db_test;
-		inner_options.skip_check_lock = true;
+		let inner_options = InternalOptions {
+			create: false,
+			commit_stages: db_test,
+			skip_check_lock: true,
+			..Default::default()
+		};
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 
 		let mut iter = db.iter(col_nb).unwrap();
@@ -1525,13 +9372,12 @@ mod tests {
 
 	fn test_basic(change_set: &[(Vec<u8>, Option<Vec<u8>>)]) {
 		let tmp = tempdir().unwrap();
-		let col_nb = 0u8;
+		let col_nb = 0u16;
 		let mut options = Options::with_columns(tmp.path(), 5);
 		options.columns[col_nb as usize].btree_index = true;
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = true;
 		let db_test = EnableCommitPipelineStages::DbFile;
-		inner_options.commit_stages = db_test;
+		let inner_options =
+			InternalOptions { create: true, commit_stages: db_test, ..Default::default() };
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 
 		let mut iter = db.iter(col_nb).unwrap();
@@ -1816,10 +9662,12 @@ mod tests {
 			test_btree_iter_inner(stage, &data_start, &data_change, &start_state, &end_state, 1);
 		}
 	}
+	type TestChanges = [(ColId, Vec<u8>, Option<Vec<u8>>)];
+
 	fn test_btree_iter_inner(
 		db_test: EnableCommitPipelineStages,
-		data_start: &Vec<(u8, Vec<u8>, Option<Vec<u8>>)>,
-		data_change: &Vec<(u8, Vec<u8>, Option<Vec<u8>>)>,
+		data_start: &TestChanges,
+		data_change: &TestChanges,
 		start_state: &BTreeMap<Vec<u8>, Vec<u8>>,
 		end_state: &BTreeMap<Vec<u8>, Vec<u8>>,
 		commit_at: usize,
@@ -1828,9 +9676,8 @@ mod tests {
 		let mut options = Options::with_columns(tmp.path(), 5);
 		let col_nb = 0;
 		options.columns[col_nb as usize].btree_index = true;
-		let mut inner_options = InternalOptions::default();
-		inner_options.create = true;
-		inner_options.commit_stages = db_test;
+		let inner_options =
+			InternalOptions { create: true, commit_stages: db_test, ..Default::default() };
 		let db = Db::open_inner(&options, &inner_options).unwrap();
 
 		db.commit(data_start.iter().cloned()).unwrap();
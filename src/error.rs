@@ -30,6 +30,7 @@ pub enum Error {
 	Migration(String),
 	Compression,
 	DatabaseNotFound,
+	QuotaExceeded,
 }
 
 impl fmt::Display for Error {
@@ -45,6 +46,7 @@ impl fmt::Display for Error {
 			Error::Migration(e) => write!(f, "Migration error: {}", e),
 			Error::Compression => write!(f, "Compression error"),
 			Error::DatabaseNotFound => write!(f, "Database does not exist"),
+			Error::QuotaExceeded => write!(f, "Database size quota exceeded"),
 		}
 	}
 }
@@ -61,3 +63,10 @@ impl From<std::io::ErrorKind> for Error {
 		e.into()
 	}
 }
+
+impl Error {
+	/// Whether the error is a write that failed because the disk is full.
+	pub(crate) fn is_disk_full(&self) -> bool {
+		matches!(self, Error::Io(e) if e.kind() == std::io::ErrorKind::StorageFull)
+	}
+}
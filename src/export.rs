@@ -0,0 +1,270 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Portable column export format.
+//!
+//! The stream does not depend on the column options or the table layout, so it can be
+//! imported into a column configured differently. All integers are little endian.
+//!
+//! Header:
+//! [MAGIC: 4][VERSION: 1][KEY_KIND: 1][SALT: 32]
+//!
+//! Followed by a sequence of entries:
+//! [FLAGS: 1][KEY_LEN: 4][KEY][RC: 4][COMPRESSION: 1][VALUE_LEN: 4][VALUE]
+//! `RC` is only present with `FLAG_RC` and `COMPRESSION` only with `FLAG_COMPRESSED`.
+//! With `FLAG_PARTIAL_KEY` the key is missing the first 6 bytes. Such entries are produced
+//! by slot order export and can't be imported.
+//!
+//! The stream is terminated with a single `FLAG_END` byte.
+
+use crate::{
+	column::Salt,
+	compress::{Compress, CompressionType},
+	error::{Error, Result},
+};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"PDBX";
+const VERSION: u8 = 1;
+
+const FLAG_RC: u8 = 0x01;
+const FLAG_COMPRESSED: u8 = 0x02;
+const FLAG_PARTIAL_KEY: u8 = 0x04;
+const FLAG_END: u8 = 0x80;
+
+/// How keys are stored in the export stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyKind {
+	/// Keys are hashed with the salt in the header. These can only be imported into a
+	/// non-uniform hash column that uses the same salt.
+	Hashed = 0,
+	/// Keys as they were passed to `commit`.
+	Plain = 1,
+}
+
+pub struct Header {
+	pub key_kind: KeyKind,
+	pub salt: Salt,
+}
+
+pub struct Entry {
+	pub key: Vec<u8>,
+	/// Only the trailing bytes of the key are known.
+	pub partial_key: bool,
+	pub rc: Option<u32>,
+	pub value: Vec<u8>,
+}
+
+pub struct ExportWriter<W: Write> {
+	writer: W,
+	compression: Compress,
+	count: u64,
+}
+
+impl<W: Write> ExportWriter<W> {
+	pub fn new(mut writer: W, header: &Header, compression: Compress) -> Result<Self> {
+		writer.write_all(MAGIC)?;
+		writer.write_all(&[VERSION, header.key_kind as u8])?;
+		writer.write_all(&header.salt)?;
+		Ok(ExportWriter { writer, compression, count: 0 })
+	}
+
+	pub fn write_entry(&mut self, key: &[u8], rc: Option<u32>, value: &[u8]) -> Result<()> {
+		self.write(0, key, rc, value)
+	}
+
+	/// Write an entry for which only the trailing bytes of the key are known.
+	pub fn write_partial_key_entry(
+		&mut self,
+		key: &[u8],
+		rc: Option<u32>,
+		value: &[u8],
+	) -> Result<()> {
+		self.write(FLAG_PARTIAL_KEY, key, rc, value)
+	}
+
+	fn write(&mut self, mut flags: u8, key: &[u8], rc: Option<u32>, value: &[u8]) -> Result<()> {
+		let compression_type = CompressionType::from(&self.compression);
+		let compressed = if compression_type != CompressionType::NoCompression &&
+			value.len() >= self.compression.threshold as usize
+		{
+			let compressed = self.compression.compress(value);
+			if compressed.len() < value.len() {
+				Some(compressed)
+			} else {
+				None
+			}
+		} else {
+			None
+		};
+		if rc.is_some() {
+			flags |= FLAG_RC;
+		}
+		if compressed.is_some() {
+			flags |= FLAG_COMPRESSED;
+		}
+		self.writer.write_all(&[flags])?;
+		self.writer.write_all(&(key.len() as u32).to_le_bytes())?;
+		self.writer.write_all(key)?;
+		if let Some(rc) = rc {
+			self.writer.write_all(&rc.to_le_bytes())?;
+		}
+		let value = if let Some(compressed) = &compressed {
+			self.writer.write_all(&[compression_type as u8])?;
+			compressed.as_slice()
+		} else {
+			value
+		};
+		self.writer.write_all(&(value.len() as u32).to_le_bytes())?;
+		self.writer.write_all(value)?;
+		self.count += 1;
+		Ok(())
+	}
+
+	/// Write the end marker. Returns the number of exported entries.
+	pub fn finish(mut self) -> Result<u64> {
+		self.writer.write_all(&[FLAG_END])?;
+		self.writer.flush()?;
+		Ok(self.count)
+	}
+}
+
+pub struct ExportReader<R: Read> {
+	reader: R,
+	done: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+	pub fn new(mut reader: R) -> Result<(Self, Header)> {
+		let mut buf = [0u8; 6];
+		reader.read_exact(&mut buf)?;
+		if &buf[0..4] != MAGIC {
+			return Err(Error::Corruption("Bad export header".into()))
+		}
+		if buf[4] != VERSION {
+			return Err(Error::Corruption(format!("Unsupported export version {}", buf[4])))
+		}
+		let key_kind = match buf[5] {
+			0 => KeyKind::Hashed,
+			1 => KeyKind::Plain,
+			k => return Err(Error::Corruption(format!("Unknown export key kind {}", k))),
+		};
+		let mut salt = Salt::default();
+		reader.read_exact(&mut salt)?;
+		Ok((ExportReader { reader, done: false }, Header { key_kind, salt }))
+	}
+
+	fn read_u32(&mut self) -> Result<u32> {
+		let mut buf = [0u8; 4];
+		self.reader.read_exact(&mut buf)?;
+		Ok(u32::from_le_bytes(buf))
+	}
+
+	fn read_vec(&mut self) -> Result<Vec<u8>> {
+		let len = self.read_u32()? as usize;
+		let mut buf = Vec::new();
+		(&mut self.reader).take(len as u64).read_to_end(&mut buf)?;
+		if buf.len() != len {
+			return Err(std::io::ErrorKind::UnexpectedEof.into())
+		}
+		Ok(buf)
+	}
+
+	/// Read next entry. Values are returned uncompressed.
+	pub fn next(&mut self) -> Result<Option<Entry>> {
+		if self.done {
+			return Ok(None)
+		}
+		let mut flags = [0u8; 1];
+		self.reader.read_exact(&mut flags)?;
+		let flags = flags[0];
+		if flags == FLAG_END {
+			self.done = true;
+			return Ok(None)
+		}
+		if flags & !(FLAG_RC | FLAG_COMPRESSED | FLAG_PARTIAL_KEY) != 0 {
+			return Err(Error::Corruption(format!("Bad export entry flags {}", flags)))
+		}
+		let key = self.read_vec()?;
+		let rc = if flags & FLAG_RC != 0 { Some(self.read_u32()?) } else { None };
+		let compression = if flags & FLAG_COMPRESSED != 0 {
+			let mut buf = [0u8; 1];
+			self.reader.read_exact(&mut buf)?;
+			Some(match buf[0] {
+				t if t == CompressionType::Lz4 as u8 => CompressionType::Lz4,
+				t if t == CompressionType::Snappy as u8 => CompressionType::Snappy,
+				t => return Err(Error::Corruption(format!("Unknown export compression {}", t))),
+			})
+		} else {
+			None
+		};
+		let value = self.read_vec()?;
+		let value = match compression {
+			Some(compression) => Compress::new(compression, 0).decompress(&value)?,
+			None => value,
+		};
+		Ok(Some(Entry { key, partial_key: flags & FLAG_PARTIAL_KEY != 0, rc, value }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn export_format_roundtrip() {
+		let mut data = Vec::new();
+		let header = Header { key_kind: KeyKind::Plain, salt: [7; 32] };
+		let mut writer =
+			ExportWriter::new(&mut data, &header, Compress::new(CompressionType::Lz4, 16)).unwrap();
+		writer.write_entry(b"key1", None, b"short").unwrap();
+		writer.write_entry(b"key2", Some(3), &[5; 100]).unwrap();
+		writer.write_partial_key_entry(b"ey3", None, b"partial").unwrap();
+		assert_eq!(writer.finish().unwrap(), 3);
+
+		let (mut reader, header) = ExportReader::new(data.as_slice()).unwrap();
+		assert_eq!(header.key_kind, KeyKind::Plain);
+		assert_eq!(header.salt, [7; 32]);
+		let entry = reader.next().unwrap().unwrap();
+		assert_eq!((entry.key.as_slice(), entry.rc), (&b"key1"[..], None));
+		assert_eq!(entry.value, b"short");
+		let entry = reader.next().unwrap().unwrap();
+		assert_eq!((entry.key.as_slice(), entry.rc), (&b"key2"[..], Some(3)));
+		assert_eq!(entry.value, vec![5; 100]);
+		assert!(!entry.partial_key);
+		let entry = reader.next().unwrap().unwrap();
+		assert_eq!(entry.key, b"ey3");
+		assert!(entry.partial_key);
+		assert!(reader.next().unwrap().is_none());
+		assert!(reader.next().unwrap().is_none());
+	}
+
+	#[test]
+	fn export_format_truncated() {
+		let mut data = Vec::new();
+		let header = Header { key_kind: KeyKind::Hashed, salt: Default::default() };
+		let mut writer =
+			ExportWriter::new(&mut data, &header, Compress::new(CompressionType::NoCompression, 0))
+				.unwrap();
+		writer.write_entry(&[1; 32], None, b"value").unwrap();
+		writer.finish().unwrap();
+		data.truncate(data.len() - 3);
+
+		let (mut reader, _) = ExportReader::new(data.as_slice()).unwrap();
+		assert!(reader.next().is_err());
+		assert!(ExportReader::new(&b"PDBY"[..]).is_err());
+	}
+}
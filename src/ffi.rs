@@ -0,0 +1,534 @@
+// Copyright 2015-2021 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! C API, enabled with the `ffi` feature. The declarations are in `include/parity_db.h`.
+//!
+//! All functions return a `ParityDbStatus`. On failure, a description of the error can be
+//! obtained with `parity_db_last_error` on the same thread. Panics are caught and reported
+//! the same way. Values returned by `parity_db_get` are owned by the caller and must be
+//! released with `parity_db_free_value`.
+
+use crate::{
+	column::ColId,
+	compress::CompressionType,
+	options::{ColumnOptions, Options},
+	Db, Error,
+};
+use std::{
+	cell::RefCell,
+	ffi::{c_char, c_void, CStr, CString},
+	path::Path,
+};
+
+/// Result of a C API call. Values are stable.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParityDbStatus {
+	Ok = 0,
+	/// The key does not exist.
+	NotFound = 1,
+	/// A null pointer or invalid argument was passed.
+	InvalidArgument = 2,
+	Io = 3,
+	Corruption = 4,
+	InvalidConfiguration = 5,
+	InvalidInput = 6,
+	/// The database is opened by another process.
+	Locked = 7,
+	/// A background worker failed. The database should be closed.
+	Background = 8,
+	DatabaseNotFound = 9,
+	Other = 10,
+	/// A commit was rejected by the database size quota.
+	QuotaExceeded = 11,
+	/// The call panicked. The database should be closed.
+	Panic = 12,
+}
+
+/// Column configuration used when creating a database. Layout is stable.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ParityDbColumnOptions {
+	pub preimage: bool,
+	pub uniform: bool,
+	pub ref_counted: bool,
+	pub btree_index: bool,
+	/// 0 for no compression, 1 for lz4, 2 for snappy.
+	pub compression: u8,
+}
+
+/// Single change passed to `parity_db_commit`. Layout is stable.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ParityDbOp {
+	pub col: u16,
+	pub key: *const u8,
+	pub key_len: usize,
+	/// Value to insert, or null to remove the key.
+	pub value: *const u8,
+	pub value_len: usize,
+}
+
+/// Callback for `parity_db_iterate`. Key and value are only valid during the call. Return
+/// `false` to stop.
+pub type ParityDbIterCallback = extern "C" fn(
+	context: *mut c_void,
+	key: *const u8,
+	key_len: usize,
+	value: *const u8,
+	value_len: usize,
+) -> bool;
+
+/// Opaque database handle.
+pub struct ParityDb(Db);
+
+thread_local! {
+	static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail(status: ParityDbStatus, message: String) -> ParityDbStatus {
+	let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+	LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+	status
+}
+
+fn fail_with(error: Error) -> ParityDbStatus {
+	let status = match &error {
+		Error::Io(_) => ParityDbStatus::Io,
+		Error::Corruption(_) | Error::InvalidValueData | Error::Compression =>
+			ParityDbStatus::Corruption,
+		Error::InvalidConfiguration(_) => ParityDbStatus::InvalidConfiguration,
+		Error::InvalidInput(_) => ParityDbStatus::InvalidInput,
+		Error::Background(_) => ParityDbStatus::Background,
+		Error::Locked(_) => ParityDbStatus::Locked,
+		Error::DatabaseNotFound => ParityDbStatus::DatabaseNotFound,
+		Error::Migration(_) => ParityDbStatus::Other,
+		Error::QuotaExceeded => ParityDbStatus::QuotaExceeded,
+	};
+	fail(status, error.to_string())
+}
+
+// Run `f`, storing the message of a panic as the last error instead of unwinding into C code.
+fn catch_panic<R>(f: impl FnOnce() -> R) -> Option<R> {
+	match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+		Ok(result) => Some(result),
+		Err(panic) => {
+			let message = panic
+				.downcast_ref::<&str>()
+				.map(|m| m.to_string())
+				.or_else(|| panic.downcast_ref::<String>().cloned())
+				.unwrap_or_else(|| "Unknown panic".into());
+			fail(ParityDbStatus::Panic, format!("Panic: {}", message));
+			None
+		},
+	}
+}
+
+// Run the body of an entry point, reporting a panic with `ParityDbStatus::Panic`.
+fn guard(f: impl FnOnce() -> ParityDbStatus) -> ParityDbStatus {
+	catch_panic(f).unwrap_or(ParityDbStatus::Panic)
+}
+
+fn invalid_argument(message: &str) -> ParityDbStatus {
+	fail(ParityDbStatus::InvalidArgument, message.into())
+}
+
+// The Rust API panics on columns that don't exist.
+fn check_column(db: &Db, col: ColId) -> Result<ColId, ParityDbStatus> {
+	if col < db.num_columns() {
+		Ok(col)
+	} else {
+		Err(fail(ParityDbStatus::InvalidInput, format!("Column {} does not exist", col)))
+	}
+}
+
+unsafe fn slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+	if len == 0 {
+		Some(&[])
+	} else if data.is_null() {
+		None
+	} else {
+		Some(std::slice::from_raw_parts(data, len))
+	}
+}
+
+/// Description of the last error returned on this thread, or null. The string is valid until
+/// the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn parity_db_last_error() -> *const c_char {
+	std::panic::catch_unwind(|| {
+		LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+	})
+	.unwrap_or(std::ptr::null())
+}
+
+/// Open the database at `path`. If `columns` is null, the database must exist and is opened
+/// with the stored column configuration. Otherwise it is created with `num_columns` columns
+/// if it does not exist.
+///
+/// # Safety
+///
+/// `path` must be a null-terminated string, `columns` must be null or point to `num_columns`
+/// entries and `db` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn parity_db_open(
+	path: *const c_char,
+	columns: *const ParityDbColumnOptions,
+	num_columns: u16,
+	db: *mut *mut ParityDb,
+) -> ParityDbStatus {
+	guard(|| {
+		if path.is_null() || db.is_null() {
+			return invalid_argument("Null argument")
+		}
+		let path = match CStr::from_ptr(path).to_str() {
+			Ok(path) => Path::new(path),
+			Err(_) => return invalid_argument("Path is not valid UTF-8"),
+		};
+		let opened = if columns.is_null() {
+			match Options::load_metadata(path) {
+				Ok(Some(metadata)) => {
+					let mut options = Options::with_columns(path, 0);
+					options.columns = metadata.columns;
+					options.salt = Some(metadata.salt);
+					Db::open(&options)
+				},
+				Ok(None) => Err(Error::DatabaseNotFound),
+				Err(e) => Err(e),
+			}
+		} else {
+			let mut options = Options::with_columns(path, num_columns);
+			let columns = std::slice::from_raw_parts(columns, num_columns as usize);
+			for (options, c) in options.columns.iter_mut().zip(columns) {
+				if c.compression > CompressionType::Snappy as u8 {
+					return invalid_argument("Unknown compression")
+				}
+				*options = ColumnOptions {
+					preimage: c.preimage,
+					uniform: c.uniform,
+					ref_counted: c.ref_counted,
+					btree_index: c.btree_index,
+					compression: c.compression.into(),
+					..Default::default()
+				};
+			}
+			Db::open_or_create(&options)
+		};
+		match opened {
+			Ok(opened) => {
+				*db = Box::into_raw(Box::new(ParityDb(opened)));
+				ParityDbStatus::Ok
+			},
+			Err(e) => fail_with(e),
+		}
+	})
+}
+
+/// Close a database opened with `parity_db_open`. Null is ignored.
+///
+/// # Safety
+///
+/// `db` must be null or a handle returned by `parity_db_open` that was not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn parity_db_close(db: *mut ParityDb) {
+	catch_panic(|| {
+		if !db.is_null() {
+			drop(Box::from_raw(db));
+		}
+	});
+}
+
+/// Look up `key` in column `col`. Returns `NotFound` if the key does not exist.
+///
+/// # Safety
+///
+/// `db` must be an open handle, `key` must point to `key_len` bytes and `value` and
+/// `value_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn parity_db_get(
+	db: *const ParityDb,
+	col: u16,
+	key: *const u8,
+	key_len: usize,
+	value: *mut *mut u8,
+	value_len: *mut usize,
+) -> ParityDbStatus {
+	guard(|| {
+		if db.is_null() || value.is_null() || value_len.is_null() {
+			return invalid_argument("Null argument")
+		}
+		let db = &(*db).0;
+		let col = match check_column(db, col) {
+			Ok(col) => col,
+			Err(status) => return status,
+		};
+		let key = match slice(key, key_len) {
+			Some(key) => key,
+			None => return invalid_argument("Null key"),
+		};
+		match db.get(col, key) {
+			Ok(Some(v)) => {
+				let v = v.into_boxed_slice();
+				*value_len = v.len();
+				*value = Box::into_raw(v) as *mut u8;
+				ParityDbStatus::Ok
+			},
+			Ok(None) => ParityDbStatus::NotFound,
+			Err(e) => fail_with(e),
+		}
+	})
+}
+
+/// Release a value returned by `parity_db_get`.
+///
+/// # Safety
+///
+/// `value` and `value_len` must be as returned by `parity_db_get`, and the value must not be
+/// released twice.
+#[no_mangle]
+pub unsafe extern "C" fn parity_db_free_value(value: *mut u8, value_len: usize) {
+	catch_panic(|| {
+		if !value.is_null() {
+			drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(value, value_len)));
+		}
+	});
+}
+
+/// Commit `num_ops` changes atomically.
+///
+/// # Safety
+///
+/// `db` must be an open handle and `ops` must point to `num_ops` changes with valid key and
+/// value buffers.
+#[no_mangle]
+pub unsafe extern "C" fn parity_db_commit(
+	db: *const ParityDb,
+	ops: *const ParityDbOp,
+	num_ops: usize,
+) -> ParityDbStatus {
+	guard(|| {
+		if db.is_null() || (ops.is_null() && num_ops != 0) {
+			return invalid_argument("Null argument")
+		}
+		let db = &(*db).0;
+		let ops = if num_ops == 0 { &[] } else { std::slice::from_raw_parts(ops, num_ops) };
+		let mut tx = Vec::with_capacity(ops.len());
+		for op in ops {
+			let col = match check_column(db, op.col) {
+				Ok(col) => col,
+				Err(status) => return status,
+			};
+			let key = match slice(op.key, op.key_len) {
+				Some(key) => key,
+				None => return invalid_argument("Null key"),
+			};
+			let value = if op.value.is_null() {
+				None
+			} else {
+				Some(std::slice::from_raw_parts(op.value, op.value_len).to_vec())
+			};
+			tx.push((col, key, value));
+		}
+		match db.commit(tx) {
+			Ok(()) => ParityDbStatus::Ok,
+			Err(e) => fail_with(e),
+		}
+	})
+}
+
+/// Call `callback` for each entry of column `col` until it returns `false`. Btree columns are
+/// iterated in key order. Hash columns are iterated in index order and pass the hashed keys.
+///
+/// # Safety
+///
+/// `db` must be an open handle. `context` is passed to `callback` as is.
+#[no_mangle]
+pub unsafe extern "C" fn parity_db_iterate(
+	db: *const ParityDb,
+	col: u16,
+	callback: ParityDbIterCallback,
+	context: *mut c_void,
+) -> ParityDbStatus {
+	guard(|| {
+		if db.is_null() {
+			return invalid_argument("Null argument")
+		}
+		let db = &(*db).0;
+		let col = match check_column(db, col) {
+			Ok(col) => col,
+			Err(status) => return status,
+		};
+		let call = |key: &[u8], value: &[u8]| {
+			callback(context, key.as_ptr(), key.len(), value.as_ptr(), value.len())
+		};
+		let result = match db.column_options(col) {
+			Ok(options) if options.btree_index => (|| {
+				let mut iter = db.iter(col)?;
+				iter.seek(&[])?;
+				while let Some((key, value)) = iter.next()? {
+					if !call(&key, &value) {
+						break
+					}
+				}
+				Ok(())
+			})(),
+			Ok(_) => db.iter_column_while(col, |state| call(&state.key, &state.value)),
+			Err(e) => Err(e),
+		};
+		match result {
+			Ok(()) => ParityDbStatus::Ok,
+			Err(e) => fail_with(e),
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	extern "C" fn collect(
+		context: *mut c_void,
+		key: *const u8,
+		key_len: usize,
+		value: *const u8,
+		value_len: usize,
+	) -> bool {
+		let entries = unsafe { &mut *(context as *mut Vec<(Vec<u8>, Vec<u8>)>) };
+		let (key, value) = unsafe {
+			(std::slice::from_raw_parts(key, key_len), std::slice::from_raw_parts(value, value_len))
+		};
+		entries.push((key.to_vec(), value.to_vec()));
+		true
+	}
+
+	#[test]
+	fn open_commit_get_iterate() {
+		let tmp = tempfile::tempdir().unwrap();
+		let path = CString::new(tmp.path().to_str().unwrap()).unwrap();
+		let columns = [
+			ParityDbColumnOptions {
+				preimage: false,
+				uniform: false,
+				ref_counted: false,
+				btree_index: false,
+				compression: 0,
+			},
+			ParityDbColumnOptions {
+				preimage: false,
+				uniform: false,
+				ref_counted: false,
+				btree_index: true,
+				compression: 1,
+			},
+		];
+		unsafe {
+			let mut db = std::ptr::null_mut();
+			assert_eq!(
+				parity_db_open(path.as_ptr(), std::ptr::null(), 0, &mut db),
+				ParityDbStatus::DatabaseNotFound
+			);
+			assert!(!parity_db_last_error().is_null());
+			let mut unknown = columns;
+			unknown[1].compression = 3;
+			assert_eq!(
+				parity_db_open(path.as_ptr(), unknown.as_ptr(), 2, &mut db),
+				ParityDbStatus::InvalidArgument
+			);
+			assert_eq!(
+				parity_db_open(path.as_ptr(), columns.as_ptr(), 2, &mut db),
+				ParityDbStatus::Ok
+			);
+			let op = |col, key: &'static [u8], value: Option<&'static [u8]>| ParityDbOp {
+				col,
+				key: key.as_ptr(),
+				key_len: key.len(),
+				value: value.map_or(std::ptr::null(), |v| v.as_ptr()),
+				value_len: value.map_or(0, |v| v.len()),
+			};
+			let ops = [
+				op(0, b"a", Some(b"1")),
+				op(1, b"b", Some(b"2")),
+				op(1, b"c", Some(b"")),
+				op(0, b"d", None),
+			];
+			assert_eq!(parity_db_commit(db, ops.as_ptr(), ops.len()), ParityDbStatus::Ok);
+			assert_eq!(parity_db_commit(db, std::ptr::null(), 1), ParityDbStatus::InvalidArgument);
+			parity_db_close(db);
+
+			let mut db = std::ptr::null_mut();
+			assert_eq!(
+				parity_db_open(path.as_ptr(), std::ptr::null(), 0, &mut db),
+				ParityDbStatus::Ok
+			);
+			let mut value = std::ptr::null_mut();
+			let mut value_len = 0;
+			assert_eq!(
+				parity_db_get(db, 0, b"a".as_ptr(), 1, &mut value, &mut value_len),
+				ParityDbStatus::Ok
+			);
+			assert_eq!(std::slice::from_raw_parts(value, value_len), b"1");
+			parity_db_free_value(value, value_len);
+			assert_eq!(
+				parity_db_get(db, 0, b"d".as_ptr(), 1, &mut value, &mut value_len),
+				ParityDbStatus::NotFound
+			);
+			assert_eq!(
+				parity_db_get(db, 2, b"a".as_ptr(), 1, &mut value, &mut value_len),
+				ParityDbStatus::InvalidInput
+			);
+
+			let mut entries: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+			let context = &mut entries as *mut _ as *mut c_void;
+			assert_eq!(parity_db_iterate(db, 1, collect, context), ParityDbStatus::Ok);
+			assert_eq!(entries, vec![(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), Vec::new())]);
+			entries.clear();
+			assert_eq!(parity_db_iterate(db, 0, collect, context), ParityDbStatus::Ok);
+			assert_eq!(entries.len(), 1);
+			assert_eq!(entries[0].1, b"1");
+			parity_db_close(db);
+		}
+	}
+
+	#[test]
+	fn panic_is_reported() {
+		let tmp = tempfile::tempdir().unwrap();
+		let path = CString::new(tmp.path().to_str().unwrap()).unwrap();
+		let columns = [ParityDbColumnOptions {
+			preimage: false,
+			uniform: true,
+			ref_counted: false,
+			btree_index: false,
+			compression: 0,
+		}];
+		unsafe {
+			let mut db = std::ptr::null_mut();
+			assert_eq!(
+				parity_db_open(path.as_ptr(), columns.as_ptr(), 1, &mut db),
+				ParityDbStatus::Ok
+			);
+			// Keys of uniform columns must have at least 32 bytes.
+			let mut value = std::ptr::null_mut();
+			let mut value_len = 0;
+			assert_eq!(
+				parity_db_get(db, 0, b"a".as_ptr(), 1, &mut value, &mut value_len),
+				ParityDbStatus::Panic
+			);
+			let error = CStr::from_ptr(parity_db_last_error()).to_str().unwrap();
+			assert!(error.starts_with("Panic: "));
+			parity_db_close(db);
+		}
+	}
+}
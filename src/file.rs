@@ -15,9 +15,16 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{error::Result, table::TableId};
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 /// Utilites for db file.
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc,
+	},
+};
 
 #[cfg(target_os = "linux")]
 fn disable_read_ahead(file: &std::fs::File) -> Result<()> {
@@ -68,75 +75,1081 @@ fn fsync(file: &std::fs::File) -> Result<()> {
 	Ok(())
 }
 
+// Most buffers passed to a single `pwritev` call. This is the `IOV_MAX` of Linux.
+#[cfg(target_os = "linux")]
+const MAX_IOVECS: usize = 1024;
+
+// Write all of `bufs` one after another at `offset`, resuming after partial writes.
+#[cfg(target_os = "linux")]
+fn write_all_vectored_at(file: &std::fs::File, mut bufs: &[&[u8]], mut offset: u64) -> Result<()> {
+	use std::os::unix::io::AsRawFd;
+	// Bytes of `bufs[0]` already written.
+	let mut skip = 0;
+	loop {
+		while bufs.first().is_some_and(|b| b.len() == skip) {
+			bufs = &bufs[1..];
+			skip = 0;
+		}
+		if bufs.is_empty() {
+			return Ok(())
+		}
+		let iovecs: Vec<libc::iovec> = bufs
+			.iter()
+			.take(MAX_IOVECS)
+			.enumerate()
+			.map(|(i, b)| {
+				let b = if i == 0 { &b[skip..] } else { b };
+				libc::iovec { iov_base: b.as_ptr() as *mut libc::c_void, iov_len: b.len() }
+			})
+			.collect();
+		let written = unsafe {
+			libc::pwritev(
+				file.as_raw_fd(),
+				iovecs.as_ptr(),
+				iovecs.len() as libc::c_int,
+				offset as libc::off_t,
+			)
+		};
+		if written < 0 {
+			let e = std::io::Error::last_os_error();
+			if e.kind() == std::io::ErrorKind::Interrupted {
+				continue
+			}
+			return Err(e.into())
+		}
+		if written == 0 {
+			return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into())
+		}
+		let mut written = written as usize;
+		offset += written as u64;
+		while written > 0 {
+			let left = bufs[0].len() - skip;
+			if written >= left {
+				written -= left;
+				bufs = &bufs[1..];
+				skip = 0;
+			} else {
+				skip += written;
+				written = 0;
+			}
+		}
+	}
+}
+
+// Copy a file, sharing the data blocks with the source if the file system supports it.
+// Hard links can't be used for table files since these are modified in place.
+#[cfg(target_os = "linux")]
+fn clone_file_data(from: &std::fs::File, to: &std::fs::File) -> bool {
+	use std::os::unix::io::AsRawFd;
+	unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file_data(_from: &std::fs::File, _to: &std::fs::File) -> bool {
+	false
+}
+
+pub fn clone_file(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+	let mut source = std::fs::File::open(from)?;
+	let mut dest = std::fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+	if !clone_file_data(&source, &dest) {
+		std::io::copy(&mut source, &mut dest)?;
+	}
+	fsync(&dest)
+}
+
+/// Copy a file without sharing data blocks with the source, so that the copy does not depend on
+/// the source file system.
+pub fn copy_file(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+	let mut source = std::fs::File::open(from)?;
+	let mut dest = std::fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+	std::io::copy(&mut source, &mut dest)?;
+	fsync(&dest)
+}
+
+/// Failures injected at specific I/O points, for crash recovery tests. Failures are armed for
+/// files under a directory, so that tests running in parallel don't affect each other, and
+/// fire once.
+#[cfg(any(test, feature = "fail-points"))]
+pub mod fail {
+	use parking_lot::Mutex;
+	use std::path::{Path, PathBuf};
+
+	/// Where a failure is injected.
+	#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+	pub enum FailPoint {
+		/// Reading a value table file.
+		TableRead,
+		/// Writing a value table file.
+		TableWrite,
+		/// Syncing a value table file.
+		TableSync,
+		/// Reading a log record.
+		LogRead,
+		/// Writing a log record.
+		LogWrite,
+		/// Syncing a log file.
+		LogSync,
+	}
+
+	/// What happens at the failure point.
+	#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+	pub enum FailAction {
+		/// Fail without reading or writing anything.
+		Error,
+		/// Read or write only this many bytes and then fail. Same as `Error` for syncs.
+		Partial(usize),
+		/// Fail with a full disk error without reading or writing anything.
+		DiskFull,
+	}
+
+	struct Armed {
+		dir: PathBuf,
+		point: FailPoint,
+		skip: usize,
+		action: FailAction,
+	}
+
+	static ARMED: Mutex<Vec<Armed>> = parking_lot::const_mutex(Vec::new());
+
+	/// Fail at `point` for files under `dir`, after letting `skip` hits pass.
+	pub fn arm(dir: &Path, point: FailPoint, skip: usize, action: FailAction) {
+		ARMED.lock().push(Armed { dir: dir.into(), point, skip, action });
+	}
+
+	/// Remove failures armed for `dir` that did not fire yet.
+	pub fn disarm(dir: &Path) {
+		ARMED.lock().retain(|armed| armed.dir != dir);
+	}
+
+	// Action to take at `point` when accessing `path`, if a failure fires.
+	pub(crate) fn check(point: FailPoint, path: &Path) -> Option<FailAction> {
+		let mut armed = ARMED.lock();
+		let i = armed.iter().position(|a| a.point == point && path.starts_with(&a.dir))?;
+		if armed[i].skip > 0 {
+			armed[i].skip -= 1;
+			return None
+		}
+		let action = armed.remove(i).action;
+		log::debug!(target: "parity-db", "Injected {:?} at {:?} for {}", action, point, path.display());
+		Some(action)
+	}
+
+	// Whether a failure is armed at `point` for `path`, without counting a hit.
+	pub(crate) fn is_armed(point: FailPoint, path: &Path) -> bool {
+		ARMED.lock().iter().any(|a| a.point == point && path.starts_with(&a.dir))
+	}
+
+	pub(crate) fn error(point: FailPoint) -> crate::error::Error {
+		std::io::Error::other(format!("Injected failure at {:?}", point)).into()
+	}
+
+	// Error returned by `action` at `point`.
+	pub(crate) fn action_error(point: FailPoint, action: FailAction) -> crate::error::Error {
+		match action {
+			FailAction::DiskFull => std::io::Error::from(std::io::ErrorKind::StorageFull).into(),
+			_ => error(point),
+		}
+	}
+
+	// Apply `action` to a read of `buf.len()` bytes with `read`.
+	pub(crate) fn read(
+		point: FailPoint,
+		action: FailAction,
+		buf: &mut [u8],
+		read: impl FnOnce(&mut [u8]) -> crate::error::Result<()>,
+	) -> crate::error::Result<()> {
+		if let FailAction::Partial(len) = action {
+			let len = len.min(buf.len());
+			read(&mut buf[..len])?;
+			return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+		}
+		Err(action_error(point, action))
+	}
+
+	// Apply `action` to a write of `buf` with `write`.
+	pub(crate) fn write(
+		point: FailPoint,
+		action: FailAction,
+		buf: &[u8],
+		write: impl FnOnce(&[u8]) -> crate::error::Result<()>,
+	) -> crate::error::Result<()> {
+		if let FailAction::Partial(len) = action {
+			write(&buf[..len.min(buf.len())])?;
+		}
+		Err(action_error(point, action))
+	}
+
+	/// Writer that fails after passing `left` bytes to `inner`.
+	pub(crate) struct PartialWriter<'a, W: std::io::Write> {
+		pub inner: &'a mut W,
+		pub left: usize,
+	}
+
+	impl<W: std::io::Write> std::io::Write for PartialWriter<'_, W> {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			if self.left == 0 {
+				// Make sure the partial write reaches the file.
+				self.inner.flush()?;
+				return Err(std::io::Error::other(format!(
+					"Injected failure at {:?}",
+					FailPoint::LogWrite
+				)))
+			}
+			let written = self.inner.write(&buf[..buf.len().min(self.left)])?;
+			self.left -= written;
+			Ok(written)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			self.inner.flush()
+		}
+	}
+}
+
+// Return from the calling function with the result of `$handler` if a failure is injected at
+// `$point` for `$path`. The armed action is bound to `$action`.
+macro_rules! fail_point {
+	($point:ident, $path:expr, |$action:ident| $handler:expr) => {
+		#[cfg(any(test, feature = "fail-points"))]
+		if let Some($action) =
+			$crate::file::fail::check($crate::file::fail::FailPoint::$point, $path)
+		{
+			return $handler
+		}
+	};
+}
+pub(crate) use fail_point;
+
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Limits copy throughput to a number of bytes per second.
+pub struct RateLimit {
+	limit: Option<u64>,
+	started: std::time::Instant,
+	bytes: u64,
+}
+
+impl RateLimit {
+	pub fn new(limit: Option<u64>) -> Self {
+		RateLimit { limit, started: std::time::Instant::now(), bytes: 0 }
+	}
+
+	fn consume(&mut self, bytes: u64) {
+		self.bytes += bytes;
+		if let Some(limit) = self.limit {
+			let due = std::time::Duration::from_secs_f64(self.bytes as f64 / limit.max(1) as f64);
+			let elapsed = self.started.elapsed();
+			if due > elapsed {
+				std::thread::sleep(due - elapsed);
+			}
+		}
+	}
+}
+
+/// Copy `len` bytes at `offset` from one file to another. Copies less if the source is shorter.
+pub fn copy_region(
+	from: &mut std::fs::File,
+	to: &mut std::fs::File,
+	offset: u64,
+	len: u64,
+	rate: &mut RateLimit,
+) -> Result<()> {
+	use std::io::{Read, Seek, SeekFrom, Write};
+	let end = from.metadata()?.len().min(offset + len);
+	if end <= offset {
+		return Ok(())
+	}
+	from.seek(SeekFrom::Start(offset))?;
+	to.seek(SeekFrom::Start(offset))?;
+	let mut buf = vec![0; COPY_BUFFER_SIZE.min((end - offset) as usize)];
+	let mut pos = offset;
+	while pos < end {
+		let chunk = buf.len().min((end - pos) as usize);
+		from.read_exact(&mut buf[..chunk])?;
+		to.write_all(&buf[..chunk])?;
+		rate.consume(chunk as u64);
+		pos += chunk as u64;
+	}
+	Ok(())
+}
+
+pub fn sync_file(file: &std::fs::File) -> Result<()> {
+	fsync(file)
+}
+
+/// Make file creation and removal in `path` durable.
+#[cfg(unix)]
+pub fn sync_dir(path: &std::path::Path) -> Result<()> {
+	std::fs::File::open(path)?.sync_all()?;
+	Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &std::path::Path) -> Result<()> {
+	Ok(())
+}
+
+// Open a table file, bypassing the page cache if `direct` is set. Returns the file and whether
+// reads and writes need to be aligned.
+#[cfg(target_os = "linux")]
+fn open_table_file(path: &std::path::Path, direct: bool) -> Result<(std::fs::File, bool)> {
+	use std::os::unix::fs::OpenOptionsExt;
+	let mut options = std::fs::OpenOptions::new();
+	options.create(true).truncate(false).read(true).write(true);
+	if direct {
+		match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+			Ok(file) => return Ok((file, true)),
+			Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+				log::warn!(target: "parity-db", "Direct I/O is not supported for {:?}", path);
+			},
+			Err(e) => return Err(e.into()),
+		}
+	}
+	Ok((options.open(path)?, false))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_table_file(path: &std::path::Path, direct: bool) -> Result<(std::fs::File, bool)> {
+	let file = std::fs::OpenOptions::new()
+		.create(true)
+		.truncate(false)
+		.read(true)
+		.write(true)
+		.open(path)?;
+	#[cfg(target_os = "macos")]
+	if direct {
+		use std::os::unix::io::AsRawFd;
+		if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } != 0 {
+			return Err(std::io::Error::last_os_error().into())
+		}
+	}
+	#[cfg(not(target_os = "macos"))]
+	let _ = direct;
+	Ok((file, false))
+}
+
 const GROW_SIZE_BYTES: u64 = 256 * 1024;
 
+// Number of entries added each time the file grows.
+fn grow_step(options: &FileOptions, entry_size: u16) -> u64 {
+	(options.growth.unwrap_or(GROW_SIZE_BYTES) / entry_size as u64).max(1)
+}
+
+// With direct I/O files are extended to whole blocks, so that any block holding entries can
+// be read and written.
+fn file_len(len: u64, direct: bool) -> u64 {
+	if direct {
+		len.next_multiple_of(DIRECT_IO_ALIGN)
+	} else {
+		len
+	}
+}
+// Alignment of offsets, sizes and buffers for direct I/O.
+const DIRECT_IO_ALIGN: u64 = 4096;
+// Page size of the OS page cache assumed by scans that drop the pages they read.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Pages of a table file that were in the OS page cache at some point, e.g. when a scan
+/// started. See `TableFile::residency`. Pages past the end of the file at that time count as
+/// cached.
+pub struct Residency {
+	page_size: u64,
+	// One byte per page, the lowest bit is set for cached pages.
+	cached: Vec<u8>,
+}
+
+impl Residency {
+	/// Whole pages within `len` bytes at `offset` that were not cached, as ranges of bytes.
+	pub fn uncached(&self, offset: u64, len: u64) -> Vec<std::ops::Range<u64>> {
+		let first = offset.div_ceil(self.page_size);
+		let end = (offset.saturating_add(len) / self.page_size).min(self.cached.len() as u64);
+		let mut ranges: Vec<std::ops::Range<u64>> = Vec::new();
+		for page in first..end {
+			if self.cached[page as usize] & 1 != 0 {
+				continue
+			}
+			let start = page * self.page_size;
+			match ranges.last_mut() {
+				Some(range) if range.end == start => range.end += self.page_size,
+				_ => ranges.push(start..start + self.page_size),
+			}
+		}
+		ranges
+	}
+}
+
+// A zeroed buffer of `len` bytes aligned for direct I/O.
+#[cfg(unix)]
+fn aligned_buffer(storage: &mut Vec<u8>, len: usize) -> &mut [u8] {
+	*storage = vec![0; len + DIRECT_IO_ALIGN as usize];
+	let skip = storage.as_ptr().align_offset(DIRECT_IO_ALIGN as usize);
+	&mut storage[skip..skip + len]
+}
+
+/// A read of `buf.len()` bytes at `offset` in a table file.
+pub struct ReadRequest<'a> {
+	pub file: &'a TableFile,
+	pub offset: u64,
+	pub buf: &'a mut [u8],
+}
+
+/// Perform a number of reads, possibly from different files. With the `io-uring` feature on
+/// Linux the reads are submitted together and served concurrently.
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+pub fn read_batch(reads: &mut [ReadRequest]) -> Result<()> {
+	for read in reads.iter_mut() {
+		read.file.read_at(read.buf, read.offset)?;
+	}
+	Ok(())
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub fn read_batch(reads: &mut [ReadRequest]) -> Result<()> {
+	uring::read_batch(reads)
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+	use super::ReadRequest;
+	use crate::error::Result;
+	use io_uring::{opcode, types, IoUring};
+	use std::{
+		cell::RefCell,
+		os::unix::{fs::FileExt, io::AsRawFd},
+	};
+
+	const RING_ENTRIES: u32 = 64;
+
+	thread_local! {
+		// `None` if the kernel does not support io_uring, or the ring failed, in which case
+		// reads fall back to `pread`.
+		static RING: RefCell<Option<IoUring>> = RefCell::new(IoUring::new(RING_ENTRIES).ok());
+	}
+
+	fn read_exact(reads: &mut [ReadRequest]) -> Result<()> {
+		for read in reads.iter_mut() {
+			read.file
+				.file
+				.read_recursive()
+				.as_ref()
+				.unwrap()
+				.read_exact_at(read.buf, read.offset)?;
+		}
+		Ok(())
+	}
+
+	pub fn read_batch(reads: &mut [ReadRequest]) -> Result<()> {
+		if reads
+			.iter()
+			.any(|r| r.file.is_direct() || r.file.options.mmap || r.file.options.storage.is_some())
+		{
+			// Direct reads need aligned buffers, and mapped files and custom storage are not
+			// read with syscalls.
+			for read in reads.iter_mut() {
+				read.file.read_at(read.buf, read.offset)?;
+			}
+			return Ok(())
+		}
+		if reads.len() < 2 {
+			// Nothing to overlap.
+			return read_exact(reads)
+		}
+		RING.with(|ring| {
+			let mut ring = ring.borrow_mut();
+			for batch in reads.chunks_mut(RING_ENTRIES as usize) {
+				if ring.is_some() {
+					submit(&mut ring, batch)?;
+				} else {
+					read_exact(batch)?;
+				}
+			}
+			Ok(())
+		})
+	}
+
+	// Submit `reads` and wait for all of them. If the ring fails while reads are in flight,
+	// it is dropped along with the buffers the kernel may still write to, and later reads of
+	// this thread use `pread`.
+	fn submit(ring: &mut Option<IoUring>, reads: &mut [ReadRequest]) -> Result<()> {
+		let uring = ring.as_mut().expect("Checked by the caller");
+		// Files are kept locked until all reads complete. Recursive locks since several reads
+		// may be from the same file.
+		let files: Vec<_> = reads.iter().map(|r| r.file.file.read_recursive()).collect();
+		// The kernel reads into separate buffers, so that the caller's buffers are never
+		// written once this returns.
+		let mut bufs: Vec<Vec<u8>> = reads.iter().map(|r| vec![0; r.buf.len()]).collect();
+		for (i, (buf, file)) in bufs.iter_mut().zip(files.iter()).enumerate() {
+			let fd = types::Fd(file.as_ref().unwrap().as_raw_fd());
+			let entry = opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+				.offset(reads[i].offset)
+				.build()
+				.user_data(i as u64);
+			// Safety: the buffers outlive the submission, since they are either kept until
+			// all entries are completed below or leaked with the ring.
+			unsafe { uring.submission().push(&entry) }.expect("Ring is drained before each batch");
+		}
+		let mut pending = reads.len();
+		let mut result: Result<()> = Ok(());
+		let mut short = Vec::new();
+		while pending > 0 {
+			if let Err(e) = uring.submit_and_wait(1) {
+				if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)) {
+					continue
+				}
+				log::warn!(target: "parity-db", "io_uring failed, falling back to pread: {}", e);
+				std::mem::forget(bufs);
+				std::mem::forget(ring.take());
+				return Err(e.into())
+			}
+			for entry in uring.completion() {
+				pending -= 1;
+				let i = entry.user_data() as usize;
+				let read = entry.result();
+				if read < 0 {
+					result = Err(std::io::Error::from_raw_os_error(-read).into());
+				} else {
+					let read = read as usize;
+					reads[i].buf[..read].copy_from_slice(&bufs[i][..read]);
+					if read < reads[i].buf.len() {
+						short.push((i, read));
+					}
+				}
+			}
+		}
+		drop(files);
+		result?;
+		// Complete partial reads, which fail at the end of file same as `read_at`.
+		for (i, done) in short {
+			let read = &mut reads[i];
+			read_exact(&mut [ReadRequest {
+				file: read.file,
+				offset: read.offset + done as u64,
+				buf: &mut read.buf[done..],
+			}])?;
+		}
+		Ok(())
+	}
+}
+
+/// Storage for value tables, selected with `Options::storage`. By default each table is kept
+/// in a file in the column directory. Index tables, the log and the metadata are always kept
+/// in files.
+pub trait StorageBackend: Send + Sync {
+	/// Open the table stored at `path`. Returns `None` if it does not exist, unless `create` is
+	/// set, in which case an empty table is created.
+	fn open(&self, path: &Path, create: bool) -> Result<Option<Box<dyn StorageFile>>>;
+}
+
+impl std::fmt::Debug for dyn StorageBackend {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "StorageBackend")
+	}
+}
+
+/// A table opened with `StorageBackend::open`. Calls may come from several threads at once,
+/// but never for overlapping ranges.
+pub trait StorageFile: Send + Sync {
+	/// Current size in bytes.
+	fn size(&self) -> Result<u64>;
+	/// Fill `buf` with data at `offset`. Fails if the range extends past the end.
+	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+	/// Write `buf` at `offset`, within the current size.
+	fn write_at(&self, buf: &[u8], offset: u64) -> Result<()>;
+	/// Extend to `len` bytes. The new space reads as zeroes.
+	fn grow(&self, len: u64) -> Result<()>;
+	/// Make all written data durable.
+	fn sync(&self) -> Result<()>;
+}
+
+/// Storage that keeps tables in memory. Data is kept until the backend is dropped, so a
+/// database can be reopened with the same backend, e.g. in tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+	tables: Mutex<HashMap<PathBuf, Arc<MemoryFile>>>,
+}
+
+impl MemoryStorage {
+	pub fn new() -> MemoryStorage {
+		Default::default()
+	}
+}
+
+impl StorageBackend for MemoryStorage {
+	fn open(&self, path: &Path, create: bool) -> Result<Option<Box<dyn StorageFile>>> {
+		let mut tables = self.tables.lock();
+		let table = match tables.get(path) {
+			Some(table) => table.clone(),
+			None if create => tables.entry(path.into()).or_default().clone(),
+			None => return Ok(None),
+		};
+		Ok(Some(Box::new(table)))
+	}
+}
+
+#[derive(Default)]
+struct MemoryFile {
+	data: RwLock<Vec<u8>>,
+}
+
+impl StorageFile for Arc<MemoryFile> {
+	fn size(&self) -> Result<u64> {
+		Ok(self.data.read().len() as u64)
+	}
+
+	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+		let data = self.data.read();
+		let start = offset as usize;
+		match data.get(start..start + buf.len()) {
+			Some(src) => {
+				buf.copy_from_slice(src);
+				Ok(())
+			},
+			None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+		}
+	}
+
+	fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+		let mut data = self.data.write();
+		let start = offset as usize;
+		match data.get_mut(start..start + buf.len()) {
+			Some(dest) => {
+				dest.copy_from_slice(buf);
+				Ok(())
+			},
+			None => Err(std::io::ErrorKind::UnexpectedEof.into()),
+		}
+	}
+
+	fn grow(&self, len: u64) -> Result<()> {
+		let mut data = self.data.write();
+		if len as usize > data.len() {
+			data.resize(len as usize, 0);
+		}
+		Ok(())
+	}
+
+	fn sync(&self) -> Result<()> {
+		Ok(())
+	}
+}
+
+/// Kind of operation on a table opened with `FaultStorage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultOp {
+	/// `StorageFile::read_at`.
+	Read,
+	/// `StorageFile::write_at` and `StorageFile::grow`.
+	Write,
+	/// `StorageFile::sync`.
+	Sync,
+}
+
+/// Failures and latency injected by `FaultStorage`.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+	/// Chance that a read fails, from 0 to 1.
+	pub read_error_rate: f64,
+	/// Chance that a write or grow fails, from 0 to 1.
+	pub write_error_rate: f64,
+	/// Chance that a sync fails, from 0 to 1.
+	pub sync_error_rate: f64,
+	/// Fail writes and grows with a full disk error instead of a generic I/O error.
+	pub disk_full: bool,
+	/// Delay added to every operation.
+	pub latency: std::time::Duration,
+}
+
+struct FaultState {
+	config: RwLock<FaultConfig>,
+	rng: Mutex<rand::rngs::StdRng>,
+	// Operations that fail regardless of the rates, see `FaultStorage::fail_next`.
+	fail_next: Mutex<HashMap<FaultOp, usize>>,
+	injected: AtomicU64,
+}
+
+impl FaultState {
+	// Wait for the configured latency and decide whether `op` fails.
+	fn check(&self, op: FaultOp) -> Result<()> {
+		let (rate, latency, disk_full) = {
+			let config = self.config.read();
+			let rate = match op {
+				FaultOp::Read => config.read_error_rate,
+				FaultOp::Write => config.write_error_rate,
+				FaultOp::Sync => config.sync_error_rate,
+			};
+			(rate, config.latency, config.disk_full)
+		};
+		if !latency.is_zero() {
+			std::thread::sleep(latency);
+		}
+		let forced = match self.fail_next.lock().get_mut(&op) {
+			Some(count) if *count > 0 => {
+				*count -= 1;
+				true
+			},
+			_ => false,
+		};
+		if !forced && (rate <= 0.0 || !rand::Rng::gen_bool(&mut *self.rng.lock(), rate.min(1.0))) {
+			return Ok(())
+		}
+		self.injected.fetch_add(1, Ordering::Relaxed);
+		log::debug!(target: "parity-db", "Injected {:?} failure", op);
+		if disk_full && op == FaultOp::Write {
+			return Err(std::io::Error::from(std::io::ErrorKind::StorageFull).into())
+		}
+		Err(std::io::Error::other(format!("Injected {:?} failure", op)).into())
+	}
+}
+
+/// Storage that passes calls on to another backend, injecting failures and latency as set in a
+/// `FaultConfig`, for testing how an application handles I/O errors of the database. Only value
+/// tables are kept in the storage backend, the index and the log are not affected. Failures
+/// are drawn from a generator seeded on creation, and the configuration can be changed while
+/// the database is open.
+pub struct FaultStorage {
+	inner: Arc<dyn StorageBackend>,
+	state: Arc<FaultState>,
+}
+
+impl FaultStorage {
+	pub fn new(inner: Arc<dyn StorageBackend>, config: FaultConfig, seed: u64) -> FaultStorage {
+		FaultStorage {
+			inner,
+			state: Arc::new(FaultState {
+				config: RwLock::new(config),
+				rng: Mutex::new(rand::SeedableRng::seed_from_u64(seed)),
+				fail_next: Mutex::new(HashMap::new()),
+				injected: AtomicU64::new(0),
+			}),
+		}
+	}
+
+	pub fn config(&self) -> FaultConfig {
+		self.state.config.read().clone()
+	}
+
+	pub fn set_config(&self, config: FaultConfig) {
+		*self.state.config.write() = config;
+	}
+
+	/// Fail the next `count` operations of kind `op`, regardless of the configured rates.
+	pub fn fail_next(&self, op: FaultOp, count: usize) {
+		self.state.fail_next.lock().insert(op, count);
+	}
+
+	/// Number of failures injected so far.
+	pub fn injected(&self) -> u64 {
+		self.state.injected.load(Ordering::Relaxed)
+	}
+}
+
+impl StorageBackend for FaultStorage {
+	fn open(&self, path: &Path, create: bool) -> Result<Option<Box<dyn StorageFile>>> {
+		Ok(self.inner.open(path, create)?.map(|inner| {
+			Box::new(FaultFile { inner, state: self.state.clone() }) as Box<dyn StorageFile>
+		}))
+	}
+}
+
+struct FaultFile {
+	inner: Box<dyn StorageFile>,
+	state: Arc<FaultState>,
+}
+
+impl StorageFile for FaultFile {
+	fn size(&self) -> Result<u64> {
+		self.inner.size()
+	}
+
+	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+		self.state.check(FaultOp::Read)?;
+		self.inner.read_at(buf, offset)
+	}
+
+	fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+		self.state.check(FaultOp::Write)?;
+		self.inner.write_at(buf, offset)
+	}
+
+	fn grow(&self, len: u64) -> Result<()> {
+		self.state.check(FaultOp::Write)?;
+		self.inner.grow(len)
+	}
+
+	fn sync(&self) -> Result<()> {
+		self.state.check(FaultOp::Sync)?;
+		self.inner.sync()
+	}
+}
+
+/// How table files are accessed and grown.
+#[derive(Clone, Debug, Default)]
+pub struct FileOptions {
+	/// Bypass the page cache. See `Options::direct_io`.
+	pub direct_io: bool,
+	/// Read through a memory map. See `ColumnOptions::mmap_tables`.
+	pub mmap: bool,
+	/// Grow by this many bytes and allocate the space. Grows by 256KB without allocating if
+	/// `None`. See `Options::file_growth`.
+	pub growth: Option<u64>,
+	/// Custom storage. See `Options::storage`.
+	pub storage: Option<Arc<dyn StorageBackend>>,
+}
+
+// Extend the file to `len` bytes, allocating the new space if `allocate` is set.
+#[cfg(target_os = "linux")]
+fn extend_file(file: &std::fs::File, len: u64, allocate: bool) -> Result<()> {
+	use std::os::unix::io::AsRawFd;
+	let current = file.metadata()?.len();
+	if allocate && len > current {
+		let err = unsafe {
+			libc::fallocate(
+				file.as_raw_fd(),
+				0,
+				current as libc::off_t,
+				(len - current) as libc::off_t,
+			)
+		};
+		if err == 0 {
+			return Ok(())
+		}
+		let err = std::io::Error::last_os_error();
+		if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+			return Err(err.into())
+		}
+	}
+	file.set_len(len)?;
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn extend_file(file: &std::fs::File, len: u64, _allocate: bool) -> Result<()> {
+	file.set_len(len)?;
+	Ok(())
+}
+
 pub struct TableFile {
 	pub file: RwLock<Option<std::fs::File>>,
 	pub path: std::path::PathBuf,
 	pub capacity: AtomicU64,
 	pub dirty: AtomicBool,
 	pub id: TableId,
+	options: FileOptions,
+	// The file is opened with `O_DIRECT`. Set when the file is opened.
+	direct: AtomicBool,
+	// Serializes read-modify-write cycles of aligned blocks with direct I/O.
+	direct_write: Mutex<()>,
+	// Read only map of the whole file, replaced when the file grows.
+	map: RwLock<Option<memmap2::Mmap>>,
+	// The table opened with `FileOptions::storage`, which is used instead of `file` if set.
+	storage: RwLock<Option<Box<dyn StorageFile>>>,
+	// Number of read-ahead hints given by `prefetch`.
+	#[cfg(test)]
+	pub prefetched: AtomicU64,
 }
 
 impl TableFile {
-	pub fn open(filepath: std::path::PathBuf, entry_size: u16, id: TableId) -> Result<Self> {
+	pub fn open(
+		filepath: std::path::PathBuf,
+		entry_size: u16,
+		id: TableId,
+		options: FileOptions,
+	) -> Result<Self> {
 		let mut capacity = 0u64;
-		let file = if std::fs::metadata(&filepath).is_ok() {
-			let file = std::fs::OpenOptions::new()
-				.create(true)
-				.read(true)
-				.write(true)
-				.open(filepath.as_path())?;
+		let mut direct = false;
+		let storage = match &options.storage {
+			Some(backend) => backend.open(&filepath, false)?,
+			None => None,
+		};
+		if let Some(storage) = &storage {
+			let len = storage.size()?;
+			if len == 0 {
+				capacity += grow_step(&options, entry_size);
+				storage.grow(capacity * entry_size as u64)?;
+			} else {
+				capacity = len / entry_size as u64;
+			}
+		}
+		let file = if options.storage.is_none() && std::fs::metadata(&filepath).is_ok() {
+			let (file, is_direct) = open_table_file(filepath.as_path(), options.direct_io)?;
+			direct = is_direct;
 			disable_read_ahead(&file)?;
 			let len = file.metadata()?.len();
 			if len == 0 {
 				// Preallocate.
-				capacity += GROW_SIZE_BYTES / entry_size as u64;
-				file.set_len(capacity * entry_size as u64)?;
+				capacity += grow_step(&options, entry_size);
+				extend_file(
+					&file,
+					file_len(capacity * entry_size as u64, direct),
+					options.growth.is_some(),
+				)?;
 			} else {
 				capacity = len / entry_size as u64;
+				if file_len(len, direct) != len {
+					file.set_len(file_len(len, direct))?;
+				}
 			}
 			Some(file)
 		} else {
 			None
 		};
-		Ok(TableFile {
+		let table_file = TableFile {
 			path: filepath,
-			file: RwLock::new(file),
+			file: RwLock::new(None),
 			capacity: AtomicU64::new(capacity),
 			dirty: AtomicBool::new(false),
 			id,
-		})
+			options,
+			direct: AtomicBool::new(direct),
+			direct_write: Mutex::new(()),
+			map: RwLock::new(None),
+			storage: RwLock::new(storage),
+			#[cfg(test)]
+			prefetched: AtomicU64::new(0),
+		};
+		if let Some(file) = &file {
+			table_file.remap(file)?;
+		}
+		*table_file.file.write() = file;
+		Ok(table_file)
+	}
+
+	// Map the file again after it was resized.
+	#[cfg(unix)]
+	fn remap(&self, file: &std::fs::File) -> Result<()> {
+		if self.options.mmap && file.metadata()?.len() > 0 {
+			*self.map.write() = Some(unsafe { memmap2::Mmap::map(file)? });
+		}
+		Ok(())
+	}
+
+	#[cfg(not(unix))]
+	fn remap(&self, _file: &std::fs::File) -> Result<()> {
+		Ok(())
+	}
+
+	// Copy from the memory map. Returns `false` if the file is not mapped or the range is
+	// past the end of the map.
+	fn read_mapped(&self, buf: &mut [u8], offset: u64) -> bool {
+		if !self.options.mmap {
+			return false
+		}
+		match &*self.map.read() {
+			Some(map) if offset as usize + buf.len() <= map.len() => {
+				buf.copy_from_slice(&map[offset as usize..offset as usize + buf.len()]);
+				true
+			},
+			_ => false,
+		}
 	}
 
 	fn create_file(&self) -> Result<std::fs::File> {
 		log::debug!(target: "parity-db", "Created value table {}", self.id);
-		let file = std::fs::OpenOptions::new()
-			.create(true)
-			.read(true)
-			.write(true)
-			.open(self.path.as_path())?;
+		let (file, direct) = open_table_file(self.path.as_path(), self.options.direct_io)?;
+		self.direct.store(direct, Ordering::Relaxed);
 		disable_read_ahead(&file)?;
 		Ok(file)
 	}
 
+	/// Check if the file is opened with direct I/O.
+	pub fn is_direct(&self) -> bool {
+		self.direct.load(Ordering::Relaxed)
+	}
+
+	/// Check if the table was written to, or existed when opened.
+	/// Length of the memory map of the file, 0 if the file is not mapped.
+	pub fn mapped_bytes(&self) -> u64 {
+		self.map.read().as_ref().map_or(0, |map| map.len() as u64)
+	}
+
+	pub fn exists(&self) -> bool {
+		self.file.read().is_some() || self.storage.read().is_some()
+	}
+
+	/// Size of the table kept in the custom storage, 0 if it is kept in a file.
+	pub fn storage_size(&self) -> Result<u64> {
+		match &*self.storage.read() {
+			Some(storage) => storage.size(),
+			None => Ok(0),
+		}
+	}
+
+	// Read the aligned blocks covering the requested range and copy it out.
+	#[cfg(unix)]
+	fn read_direct(&self, file: &std::fs::File, buf: &mut [u8], offset: u64) -> Result<()> {
+		use std::os::unix::fs::FileExt;
+		let start = offset - offset % DIRECT_IO_ALIGN;
+		let end = (offset + buf.len() as u64).next_multiple_of(DIRECT_IO_ALIGN);
+		let mut storage = Vec::new();
+		let blocks = aligned_buffer(&mut storage, (end - start) as usize);
+		file.read_exact_at(blocks, start)?;
+		let skip = (offset - start) as usize;
+		buf.copy_from_slice(&blocks[skip..skip + buf.len()]);
+		Ok(())
+	}
+
+	// Update the aligned blocks covering the range.
+	#[cfg(unix)]
+	fn write_direct(&self, file: &std::fs::File, buf: &[u8], offset: u64) -> Result<()> {
+		use std::os::unix::fs::FileExt;
+		let _lock = self.direct_write.lock();
+		let start = offset - offset % DIRECT_IO_ALIGN;
+		let end = (offset + buf.len() as u64).next_multiple_of(DIRECT_IO_ALIGN);
+		let mut storage = Vec::new();
+		let blocks = aligned_buffer(&mut storage, (end - start) as usize);
+		file.read_exact_at(blocks, start)?;
+		let skip = (offset - start) as usize;
+		blocks[skip..skip + buf.len()].copy_from_slice(buf);
+		file.write_all_at(blocks, start)?;
+		Ok(())
+	}
+
 	#[cfg(unix)]
 	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
-		Ok(self.file.read().as_ref().unwrap().read_exact_at(buf, offset)?)
+		fail_point!(TableRead, &self.path, |action| {
+			fail::read(fail::FailPoint::TableRead, action, buf, |buf| self.read_at(buf, offset))
+		});
+		if let Some(storage) = &*self.storage.read() {
+			return storage.read_at(buf, offset)
+		}
+		if self.read_mapped(buf, offset) {
+			return Ok(())
+		}
+		let file = self.file.read();
+		let file = file.as_ref().unwrap();
+		if self.is_direct() {
+			return self.read_direct(file, buf, offset)
+		}
+		Ok(file.read_exact_at(buf, offset)?)
 	}
 
 	#[cfg(unix)]
 	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
+		fail_point!(TableWrite, &self.path, |action| {
+			fail::write(fail::FailPoint::TableWrite, action, buf, |buf| self.write_at(buf, offset))
+		});
 		self.dirty.store(true, Ordering::Relaxed);
-		self.file.read().as_ref().unwrap().write_all_at(buf, offset)?;
+		if let Some(storage) = &*self.storage.read() {
+			return storage.write_at(buf, offset)
+		}
+		let file = self.file.read();
+		let file = file.as_ref().unwrap();
+		if self.is_direct() {
+			return self.write_direct(file, buf, offset)
+		}
+		file.write_all_at(buf, offset)?;
 		Ok(())
 	}
 
 	#[cfg(windows)]
 	pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::windows::fs::FileExt;
+		if let Some(storage) = &*self.storage.read() {
+			return storage.read_at(buf, offset)
+		}
 		self.file.read().as_ref().unwrap().seek_read(buf, offset)?;
 		Ok(())
 	}
@@ -145,22 +1158,235 @@ impl TableFile {
 	pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
 		use std::os::windows::fs::FileExt;
 		self.dirty.store(true, Ordering::Relaxed);
+		if let Some(storage) = &*self.storage.read() {
+			return storage.write_at(buf, offset)
+		}
 		self.file.read().as_ref().unwrap().seek_write(buf, offset)?;
 		Ok(())
 	}
 
+	/// Write `bufs` one after another starting at `offset`. On Linux this is a single
+	/// `pwritev` call for up to 1024 buffers.
+	pub fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> Result<()> {
+		#[cfg(target_os = "linux")]
+		{
+			#[cfg(any(test, feature = "fail-points"))]
+			let armed = fail::is_armed(fail::FailPoint::TableWrite, &self.path);
+			#[cfg(not(any(test, feature = "fail-points")))]
+			let armed = false;
+			if !armed && !self.is_direct() && self.storage.read().is_none() {
+				self.dirty.store(true, Ordering::Relaxed);
+				let file = self.file.read();
+				return write_all_vectored_at(file.as_ref().unwrap(), bufs, offset)
+			}
+		}
+		let mut offset = offset;
+		for buf in bufs {
+			self.write_at(buf, offset)?;
+			offset += buf.len() as u64;
+		}
+		Ok(())
+	}
+
+	/// Hint that `len` bytes at `offset` are about to be read. Kernel read-ahead is disabled
+	/// for table files, so this is the only way sequential reads get ahead of the queries.
+	/// Errors are ignored, since this is only an optimization.
+	pub fn prefetch(&self, offset: u64, len: u64) {
+		#[cfg(target_os = "linux")]
+		if self.fadvise(offset, len, libc::POSIX_FADV_WILLNEED) {
+			#[cfg(test)]
+			self.prefetched.fetch_add(1, Ordering::Relaxed);
+		}
+		#[cfg(not(target_os = "linux"))]
+		let _ = (offset, len);
+	}
+
+	/// Hint that the file is about to be read from start to end, or with `false` that it is
+	/// back to random reads, which is the default for table files.
+	pub fn advise_sequential(&self, sequential: bool) {
+		#[cfg(target_os = "linux")]
+		{
+			let advice =
+				if sequential { libc::POSIX_FADV_SEQUENTIAL } else { libc::POSIX_FADV_RANDOM };
+			self.fadvise(0, 0, advice);
+		}
+		#[cfg(not(target_os = "linux"))]
+		let _ = sequential;
+	}
+
+	/// Pages of the file that are in the OS page cache now. `None` if this can't be told or
+	/// the pages can't be dropped anyway, i.e. the file is mapped, opened with direct I/O or
+	/// kept in custom storage.
+	pub fn residency(&self) -> Option<Residency> {
+		#[cfg(target_os = "linux")]
+		{
+			use std::os::unix::io::AsRawFd;
+			if self.options.mmap || self.is_direct() || self.options.storage.is_some() {
+				return None
+			}
+			let file = self.file.read();
+			let file = file.as_ref()?;
+			let len = file.metadata().ok()?.len() as usize;
+			let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+			let mut cached = vec![0u8; len.div_ceil(page_size)];
+			if len != 0 {
+				// Mapping the file does not read it, the pages are only looked up.
+				unsafe {
+					let map = libc::mmap(
+						std::ptr::null_mut(),
+						len,
+						libc::PROT_READ,
+						libc::MAP_SHARED,
+						file.as_raw_fd(),
+						0,
+					);
+					if map == libc::MAP_FAILED {
+						return None
+					}
+					let err = libc::mincore(map, len, cached.as_mut_ptr());
+					libc::munmap(map, len);
+					if err != 0 {
+						return None
+					}
+				}
+			}
+			Some(Residency { page_size: page_size as u64, cached })
+		}
+		#[cfg(not(target_os = "linux"))]
+		None
+	}
+
+	/// Drop the pages of `len` bytes at `offset` that were not cached according to `residency`
+	/// from the OS page cache, so that data read once by a scan does not evict data that is
+	/// read often. Only whole pages in the range are dropped.
+	pub fn drop_uncached(&self, residency: &Residency, offset: u64, len: u64) {
+		#[cfg(target_os = "linux")]
+		for range in residency.uncached(offset, len) {
+			self.fadvise(range.start, range.end - range.start, libc::POSIX_FADV_DONTNEED);
+		}
+		#[cfg(not(target_os = "linux"))]
+		let _ = (residency, offset, len);
+	}
+
+	// Errors are ignored, hints are only an optimization. Returns `false` if the hint does not
+	// apply, i.e. the file is mapped, opened with direct I/O or kept in custom storage.
+	#[cfg(target_os = "linux")]
+	fn fadvise(&self, offset: u64, len: u64, advice: libc::c_int) -> bool {
+		use std::os::unix::io::AsRawFd;
+		if self.options.mmap || self.is_direct() || self.options.storage.is_some() {
+			return false
+		}
+		match &*self.file.read() {
+			Some(file) => {
+				unsafe {
+					libc::posix_fadvise(
+						file.as_raw_fd(),
+						offset as libc::off_t,
+						len as libc::off_t,
+						advice,
+					);
+				}
+				true
+			},
+			None => false,
+		}
+	}
+
+	/// Give the disk space of `len` bytes at `offset` back to the file system. The file keeps
+	/// its size and the range reads as zeros. Only done for files on Linux, and ignored if the
+	/// file system does not support it.
+	pub fn release(&self, offset: u64, len: u64) -> Result<()> {
+		#[cfg(target_os = "linux")]
+		{
+			use std::os::unix::io::AsRawFd;
+			if self.options.storage.is_some() {
+				return Ok(())
+			}
+			if let Some(file) = &*self.file.read() {
+				let err = unsafe {
+					libc::fallocate(
+						file.as_raw_fd(),
+						libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+						offset as libc::off_t,
+						len as libc::off_t,
+					)
+				};
+				if err != 0 {
+					let err = std::io::Error::last_os_error();
+					if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+						return Err(err.into())
+					}
+				}
+			}
+		}
+		#[cfg(not(target_os = "linux"))]
+		let _ = (offset, len);
+		Ok(())
+	}
+
 	pub fn grow(&self, entry_size: u16) -> Result<()> {
 		let mut capacity = self.capacity.load(Ordering::Relaxed);
-		capacity += GROW_SIZE_BYTES / entry_size as u64;
+		capacity += grow_step(&self.options, entry_size);
 
-		self.capacity.store(capacity, Ordering::Relaxed);
+		if let Some(backend) = &self.options.storage {
+			let mut storage = self.storage.upgradable_read();
+			if storage.is_none() {
+				let mut wstorage = RwLockUpgradableReadGuard::upgrade(storage);
+				*wstorage = backend.open(&self.path, true)?;
+				storage = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wstorage);
+			}
+			match &*storage {
+				Some(storage) => storage.grow(capacity * entry_size as u64)?,
+				None => return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+			}
+			self.capacity.store(capacity, Ordering::Relaxed);
+			return Ok(())
+		}
 		let mut file = self.file.upgradable_read();
 		if file.is_none() {
 			let mut wfile = RwLockUpgradableReadGuard::upgrade(file);
 			*wfile = Some(self.create_file()?);
 			file = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wfile);
 		}
-		file.as_ref().unwrap().set_len(capacity * entry_size as u64)?;
+		let file = file.as_ref().unwrap();
+		// Capacity is only raised once the file is extended, so that growing can be retried
+		// after running out of disk space.
+		extend_file(
+			file,
+			file_len(capacity * entry_size as u64, self.is_direct()),
+			self.options.growth.is_some(),
+		)?;
+		self.capacity.store(capacity, Ordering::Relaxed);
+		self.remap(file)?;
+		Ok(())
+	}
+
+	/// Extend an existing file so that it holds at least `entries` entries.
+	pub fn reserve(&self, entries: u64, entry_size: u16) -> Result<()> {
+		if let Some(storage) = self.storage.read().as_ref() {
+			let capacity = self.capacity.load(Ordering::Relaxed);
+			if entries > capacity {
+				let step = grow_step(&self.options, entry_size);
+				let capacity = entries.div_ceil(step) * step;
+				storage.grow(capacity * entry_size as u64)?;
+				self.capacity.store(capacity, Ordering::Relaxed);
+			}
+			return Ok(())
+		}
+		if let Some(file) = self.file.read().as_ref() {
+			let capacity = self.capacity.load(Ordering::Relaxed);
+			if entries > capacity {
+				let step = grow_step(&self.options, entry_size);
+				let capacity = entries.div_ceil(step) * step;
+				extend_file(
+					file,
+					file_len(capacity * entry_size as u64, self.is_direct()),
+					self.options.growth.is_some(),
+				)?;
+				self.remap(file)?;
+				self.capacity.store(capacity, Ordering::Relaxed);
+			}
+		}
 		Ok(())
 	}
 
@@ -168,10 +1394,74 @@ impl TableFile {
 		if let Ok(true) =
 			self.dirty.compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
 		{
-			if let Some(file) = self.file.read().as_ref() {
+			if self.exists() {
+				fail_point!(TableSync, &self.path, |_action| {
+					Err(fail::error(fail::FailPoint::TableSync))
+				});
+			}
+			if let Some(storage) = self.storage.read().as_ref() {
+				storage.sync()?;
+			} else if let Some(file) = self.file.read().as_ref() {
 				fsync(file)?;
 			}
 		}
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{read_batch, ReadRequest, TableFile};
+	use crate::table::TableId;
+
+	#[test]
+	fn batch_reads_multiple_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let files: Vec<_> = (0..2u8)
+			.map(|tier| {
+				let id = TableId::new(0, tier);
+				let file =
+					TableFile::open(dir.path().join(id.file_name()), 64, id, Default::default())
+						.unwrap();
+				file.grow(64).unwrap();
+				file.write_at(&[tier + 1; 128], 64).unwrap();
+				file
+			})
+			.collect();
+
+		let mut bufs = [[0u8; 64]; 3];
+		let [a, b, c] = &mut bufs;
+		read_batch(&mut [
+			ReadRequest { file: &files[0], offset: 64, buf: a },
+			ReadRequest { file: &files[1], offset: 128, buf: b },
+			ReadRequest { file: &files[0], offset: 0, buf: c },
+		])
+		.unwrap();
+		assert_eq!(bufs, [[1; 64], [2; 64], [0; 64]]);
+
+		let end = files[0].capacity.load(std::sync::atomic::Ordering::Relaxed) * 64;
+		let mut buf = [0u8; 64];
+		assert!(read_batch(&mut [ReadRequest {
+			file: &files[0],
+			offset: end - 32,
+			buf: &mut buf
+		}])
+		.is_err());
+	}
+
+	#[test]
+	fn vectored_write() {
+		let dir = tempfile::tempdir().unwrap();
+		let id = TableId::new(0, 0);
+		let file =
+			TableFile::open(dir.path().join(id.file_name()), 64, id, Default::default()).unwrap();
+		file.grow(64).unwrap();
+		let parts: Vec<Vec<u8>> = (0..2000u32).map(|i| vec![i as u8; 1 + i as usize % 3]).collect();
+		let bufs: Vec<&[u8]> = parts.iter().map(|p| p.as_slice()).collect();
+		file.write_vectored_at(&bufs, 64).unwrap();
+		let expected = parts.concat();
+		let mut buf = vec![0u8; expected.len()];
+		file.read_at(&mut buf, 64).unwrap();
+		assert_eq!(buf, expected);
+	}
+}
@@ -15,6 +15,7 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
+	cache::ChunkCache,
 	column::ColId,
 	const_assert,
 	display::hex,
@@ -25,18 +26,24 @@ use crate::{
 	Key,
 };
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
-use std::convert::TryInto;
+use std::{convert::TryInto, sync::Arc};
 
 // Index chunk consists of 8 64-bit entries.
 const CHUNK_LEN: usize = CHUNK_ENTRIES * ENTRY_BYTES; // 512 bytes
-const CHUNK_ENTRIES: usize = 1 << CHUNK_ENTRIES_BITS;
+pub const CHUNK_ENTRIES: usize = 1 << CHUNK_ENTRIES_BITS;
 const CHUNK_ENTRIES_BITS: u8 = 6;
 const HEADER_SIZE: usize = 512;
 const META_SIZE: usize = 16 * 1024; // Contains header and column stats
 const ENTRY_LEN: u8 = 64;
 pub const ENTRY_BYTES: usize = ENTRY_LEN as usize / 8;
 
-const EMPTY_CHUNK: Chunk = [0u8; CHUNK_LEN];
+// Values stored in the index entry use size tier 0. The offset holds the value length plus one
+// in the low bits, so that it is never 0, followed by the value bytes.
+pub const INLINE_TIER: u8 = 0;
+const INLINE_LEN_BITS: u8 = 3;
+const MAX_INLINE_SIZE: usize = (1 << INLINE_LEN_BITS) - 2;
+
+pub const EMPTY_CHUNK: Chunk = [0u8; CHUNK_LEN];
 
 pub type Chunk = [u8; CHUNK_LEN];
 
@@ -72,9 +79,10 @@ impl Entry {
 		self.0 >> Self::address_bits(index_bits)
 	}
 
+	// `key_bits` leading bits of the key select the chunk and are not stored in the entry.
 	#[inline]
-	fn extract_key(key_prefix: u64, index_bits: u8) -> u64 {
-		(key_prefix << index_bits) >> Self::address_bits(index_bits)
+	fn extract_key(key_prefix: u64, key_bits: u8, index_bits: u8) -> u64 {
+		(key_prefix << key_bits) >> Self::address_bits(index_bits)
 	}
 
 	#[inline]
@@ -118,6 +126,27 @@ impl Address {
 	pub fn as_u64(&self) -> u64 {
 		self.0
 	}
+
+	/// Address holding `value` itself, which must fit `inline_capacity`.
+	pub fn inline(value: &[u8]) -> Address {
+		let mut data = [0u8; 8];
+		data[..value.len()].copy_from_slice(value);
+		let offset = (u64::from_le_bytes(data) << INLINE_LEN_BITS) | (value.len() as u64 + 1);
+		Address::new(offset, INLINE_TIER)
+	}
+
+	/// Value held by an address created with `inline`.
+	pub fn inline_value(&self) -> Vec<u8> {
+		let offset = self.offset();
+		let len = ((offset & ((1 << INLINE_LEN_BITS) - 1)) as usize).saturating_sub(1);
+		(offset >> INLINE_LEN_BITS).to_le_bytes()[..len].to_vec()
+	}
+
+	/// Largest value that fits an entry of an index table with `index_bits`.
+	pub fn inline_capacity(index_bits: u8) -> usize {
+		let bits = Entry::address_bits(index_bits) - SIZE_TIERS_BITS - INLINE_LEN_BITS;
+		(bits as usize / 8).min(MAX_INLINE_SIZE)
+	}
 }
 
 impl std::fmt::Display for Address {
@@ -132,10 +161,23 @@ pub enum PlanOutcome {
 	Skipped,
 }
 
+/// Kernel hints for index memory maps, see `Options::index_huge_pages` and
+/// `Options::index_prefetch`.
+#[derive(Clone, Copy, Default)]
+pub struct MapAdvice {
+	pub huge_pages: bool,
+	pub prefetch: bool,
+}
+
 pub struct IndexTable {
 	pub id: TableId,
+	// Keys are placed in groups of `1 << span_bits` consecutive chunks, which are searched as
+	// one. See `ColumnOptions::index_chunk_entries`.
+	span_bits: u8,
+	advice: MapAdvice,
 	map: RwLock<Option<memmap2::MmapMut>>,
 	path: std::path::PathBuf,
+	cache: Option<Arc<ChunkCache>>,
 }
 
 fn total_entries(index_bits: u8) -> u64 {
@@ -151,14 +193,14 @@ fn file_size(index_bits: u8) -> u64 {
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Hash)]
-pub struct TableId(u16);
+pub struct TableId(u32);
 
 impl TableId {
 	pub fn new(col: ColId, index_bits: u8) -> TableId {
-		TableId(((col as u16) << 8) | (index_bits as u16))
+		TableId(((col as u32) << 8) | (index_bits as u32))
 	}
 
-	pub fn from_u16(id: u16) -> TableId {
+	pub fn from_u32(id: u32) -> TableId {
 		TableId(id)
 	}
 
@@ -178,7 +220,7 @@ impl TableId {
 		name.starts_with(&format!("index_{:02}_", col))
 	}
 
-	pub fn as_u16(&self) -> u16 {
+	pub fn as_u32(&self) -> u32 {
 		self.0
 	}
 
@@ -198,7 +240,23 @@ impl std::fmt::Display for TableId {
 }
 
 impl IndexTable {
-	pub fn open_existing(path: &std::path::Path, id: TableId) -> Result<Option<IndexTable>> {
+	/// File region of chunk `index`, as `(offset, len)`.
+	pub fn chunk_region(index: u64) -> (u64, u64) {
+		(META_SIZE as u64 + index * CHUNK_LEN as u64, CHUNK_LEN as u64)
+	}
+
+	/// File region of the header and column stats, as `(offset, len)`.
+	pub fn meta_region() -> (u64, u64) {
+		(0, META_SIZE as u64)
+	}
+
+	pub fn open_existing(
+		path: &std::path::Path,
+		id: TableId,
+		span_bits: u8,
+		advice: MapAdvice,
+		cache: Option<Arc<ChunkCache>>,
+	) -> Result<Option<IndexTable>> {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
 
@@ -209,15 +267,45 @@ impl IndexTable {
 		};
 
 		file.set_len(file_size(id.index_bits()))?;
-		let map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+		let mut map = unsafe { memmap2::MmapMut::map_mut(&file)? };
+		Self::madvise(id, advice, &mut map, false);
 		log::debug!(target: "parity-db", "Opened existing index {}", id);
-		Ok(Some(IndexTable { id, path, map: RwLock::new(Some(map)) }))
+		Ok(Some(IndexTable { id, span_bits, advice, path, map: RwLock::new(Some(map)), cache }))
 	}
 
-	pub fn create_new(path: &std::path::Path, id: TableId) -> IndexTable {
+	pub fn create_new(
+		path: &std::path::Path,
+		id: TableId,
+		span_bits: u8,
+		advice: MapAdvice,
+		cache: Option<Arc<ChunkCache>>,
+	) -> IndexTable {
 		let mut path: std::path::PathBuf = path.into();
 		path.push(id.file_name());
-		IndexTable { id, path, map: RwLock::new(None) }
+		IndexTable { id, span_bits, advice, path, map: RwLock::new(None), cache }
+	}
+
+	pub fn span_bits(&self) -> u8 {
+		self.span_bits
+	}
+
+	pub fn advice(&self) -> MapAdvice {
+		self.advice
+	}
+
+	/// Entries that keys with the same chunk bits share.
+	pub fn chunk_entries(&self) -> usize {
+		CHUNK_ENTRIES << self.span_bits
+	}
+
+	/// Number of leading key bits that are known from an entry and its chunk.
+	pub fn recovered_key_bits(&self) -> u8 {
+		64 - Entry::address_bits(self.id.index_bits()) + self.key_bits()
+	}
+
+	// Leading key bits that select the group of chunks.
+	fn key_bits(&self) -> u8 {
+		self.id.index_bits() - self.span_bits
 	}
 
 	pub fn load_stats(&self) -> ColumnStats {
@@ -235,39 +323,68 @@ impl IndexTable {
 		}
 	}
 
+	// Write stats, creating the file if needed.
+	pub fn save_stats(&self, stats: &ColumnStats) -> Result<()> {
+		drop(self.map_for_write()?);
+		self.write_stats(stats);
+		Ok(())
+	}
+
 	fn chunk_at(index: u64, map: &memmap2::MmapMut) -> &[u8] {
 		let offset = META_SIZE + index as usize * CHUNK_LEN;
 		&map[offset..offset + CHUNK_LEN]
 	}
 
-	fn find_entry(&self, key_prefix: u64, sub_index: usize, chunk: &[u8]) -> (Entry, usize) {
-		let partial_key = Entry::extract_key(key_prefix, self.id.index_bits());
+	fn find_entry(
+		&self,
+		key_prefix: u64,
+		sub_index: usize,
+		chunk: &[u8],
+	) -> Option<(Entry, usize)> {
+		let partial_key = Entry::extract_key(key_prefix, self.key_bits(), self.id.index_bits());
 		for i in sub_index..CHUNK_ENTRIES {
 			let entry = Self::read_entry(chunk, i);
 			if !entry.is_empty() && entry.partial_key(self.id.index_bits()) == partial_key {
-				return (entry, i)
+				return Some((entry, i))
 			}
 		}
-		(Entry::empty(), 0)
+		None
 	}
 
-	// Only returns 54 bits of the actual key.
+	// Only returns `recovered_key_bits` of the actual key, 50 with single chunk groups.
 	pub fn recover_key_prefix(&self, chunk: u64, entry: Entry) -> Key {
-		// Restore first 54 bits of the key.
 		let partial_key = entry.partial_key(self.id.index_bits());
 		let k = 64 - Entry::address_bits(self.id.index_bits());
-		let index_key = (chunk << (64 - self.id.index_bits())) |
-			(partial_key << (64 - k - self.id.index_bits()));
+		let group = chunk >> self.span_bits;
+		let index_key =
+			(group << (64 - self.key_bits())) | (partial_key << (64 - k - self.key_bits()));
 		let mut key = Key::default();
 		key[0..8].copy_from_slice(&index_key.to_be_bytes());
 		key
 	}
 
+	/// Find the entry of `key`, starting at position `sub_index` of the group of chunks the key
+	/// belongs to. Returns the entry and its position in the group.
 	pub fn get(&self, key: &Key, sub_index: usize, log: &impl LogQuery) -> (Entry, usize) {
 		log::trace!(target: "parity-db", "{}: Querying {}", self.id, hex(key));
 		let key = TableKey::index_from_partial(key);
-		let chunk_index = self.chunk_index(key);
+		let first_chunk = self.chunk_index(key);
+		for c in sub_index / CHUNK_ENTRIES..1 << self.span_bits {
+			let start = if c == sub_index / CHUNK_ENTRIES { sub_index % CHUNK_ENTRIES } else { 0 };
+			if let Some((entry, i)) = self.get_in_chunk(key, first_chunk + c as u64, start, log) {
+				return (entry, c * CHUNK_ENTRIES + i)
+			}
+		}
+		(Entry::empty(), 0)
+	}
 
+	fn get_in_chunk(
+		&self,
+		key: u64,
+		chunk_index: u64,
+		sub_index: usize,
+		log: &impl LogQuery,
+	) -> Option<(Entry, usize)> {
 		if let Some(entry) = log.with_index(self.id, chunk_index, |chunk| {
 			log::trace!(target: "parity-db", "{}: Querying overlay at {}", self.id, chunk_index);
 			self.find_entry(key, sub_index, chunk)
@@ -275,12 +392,20 @@ impl IndexTable {
 			return entry
 		}
 
+		let epoch = match self.cache.as_ref().map(|cache| cache.get(self.id, chunk_index)) {
+			Some(Ok(chunk)) => return self.find_entry(key, sub_index, &chunk),
+			Some(Err(epoch)) => Some(epoch),
+			None => None,
+		};
 		if let Some(map) = &*self.map.read() {
 			log::trace!(target: "parity-db", "{}: Querying chunk at {}", self.id, chunk_index);
 			let chunk = Self::chunk_at(chunk_index, map);
+			if let (Some(cache), Some(epoch)) = (&self.cache, epoch) {
+				cache.insert(self.id, chunk_index, chunk.try_into().unwrap(), epoch);
+			}
 			return self.find_entry(key, sub_index, chunk)
 		}
-		(Entry::empty(), 0)
+		None
 	}
 
 	pub fn entries(&self, chunk_index: u64, log: &impl LogQuery) -> [Entry; CHUNK_ENTRIES] {
@@ -319,30 +444,46 @@ impl IndexTable {
 		Entry::from_u64(u64::from_le_bytes(chunk[at * 8..at * 8 + 8].try_into().unwrap()))
 	}
 
+	// First chunk of the group of `key_prefix`.
 	#[inline(always)]
 	fn chunk_index(&self, key_prefix: u64) -> u64 {
-		key_prefix >> (ENTRY_LEN - self.id.index_bits())
+		(key_prefix >> (ENTRY_LEN - self.key_bits())) << self.span_bits
 	}
 
-	fn plan_insert_chunk(
+	// Chunk content including changes in the log that are not enacted yet.
+	fn chunk_for_write(&self, chunk_index: u64, log: &LogWriter) -> Chunk {
+		if let Some(chunk) = log.with_index(self.id, chunk_index, |chunk| *chunk) {
+			return chunk
+		}
+		if let Some(map) = &*self.map.read() {
+			return Self::chunk_at(chunk_index, map).try_into().unwrap()
+		}
+		EMPTY_CHUNK
+	}
+
+	/// Insert an entry for `key`, or replace the one at position `sub_index` of its group of
+	/// chunks.
+	pub fn write_insert_plan(
 		&self,
-		key_prefix: u64,
+		key: &Key,
 		address: Address,
-		source: &[u8],
 		sub_index: Option<usize>,
 		log: &mut LogWriter,
 	) -> Result<PlanOutcome> {
-		let chunk_index = self.chunk_index(key_prefix);
+		log::trace!(target: "parity-db", "{}: Inserting {} -> {}", self.id, hex(key), address);
+		let key_prefix = TableKey::index_from_partial(key);
+		let first_chunk = self.chunk_index(key_prefix);
 		if address.as_u64() > Entry::last_address(self.id.index_bits()) {
 			// Address overflow
-			log::warn!(target: "parity-db", "{}: Address space overflow at {}: {}", self.id, chunk_index, address);
+			log::warn!(target: "parity-db", "{}: Address space overflow at {}: {}", self.id, first_chunk, address);
 			return Ok(PlanOutcome::NeedReindex)
 		}
-		let mut chunk = [0; CHUNK_LEN];
-		chunk.copy_from_slice(source);
-		let partial_key = Entry::extract_key(key_prefix, self.id.index_bits());
+		let partial_key = Entry::extract_key(key_prefix, self.key_bits(), self.id.index_bits());
 		let new_entry = Entry::new(address, partial_key, self.id.index_bits());
-		if let Some(i) = sub_index {
+		if let Some(sub_index) = sub_index {
+			let chunk_index = first_chunk + (sub_index / CHUNK_ENTRIES) as u64;
+			let i = sub_index % CHUNK_ENTRIES;
+			let mut chunk = self.chunk_for_write(chunk_index, log);
 			let entry = Self::read_entry(&chunk, i);
 			assert!(
 				entry.partial_key(self.id.index_bits()) ==
@@ -353,54 +494,33 @@ impl IndexTable {
 			log.insert_index(self.id, chunk_index, i as u8, &chunk);
 			return Ok(PlanOutcome::Written)
 		}
-		for i in 0..CHUNK_ENTRIES {
-			let entry = Self::read_entry(&chunk, i);
-			if entry.is_empty() {
-				Self::write_entry(&new_entry, i, &mut chunk);
-				log::trace!(target: "parity-db", "{}: Inserted at {}.{}: {}", self.id, chunk_index, i, new_entry.address(self.id.index_bits()));
-				log.insert_index(self.id, chunk_index, i as u8, &chunk);
-				return Ok(PlanOutcome::Written)
+		for chunk_index in first_chunk..first_chunk + (1 << self.span_bits) {
+			let mut chunk = self.chunk_for_write(chunk_index, log);
+			for i in 0..CHUNK_ENTRIES {
+				let entry = Self::read_entry(&chunk, i);
+				if entry.is_empty() {
+					Self::write_entry(&new_entry, i, &mut chunk);
+					log::trace!(target: "parity-db", "{}: Inserted at {}.{}: {}", self.id, chunk_index, i, new_entry.address(self.id.index_bits()));
+					log.insert_index(self.id, chunk_index, i as u8, &chunk);
+					return Ok(PlanOutcome::Written)
+				}
 			}
 		}
-		log::trace!(target: "parity-db", "{}: Full at {}", self.id, chunk_index);
+		log::trace!(target: "parity-db", "{}: Full at {}", self.id, first_chunk);
 		Ok(PlanOutcome::NeedReindex)
 	}
 
-	pub fn write_insert_plan(
-		&self,
-		key: &Key,
-		address: Address,
-		sub_index: Option<usize>,
-		log: &mut LogWriter,
-	) -> Result<PlanOutcome> {
-		log::trace!(target: "parity-db", "{}: Inserting {} -> {}", self.id, hex(key), address);
-		let key_prefix = TableKey::index_from_partial(key);
-		let chunk_index = self.chunk_index(key_prefix);
-
-		if let Some(chunk) = log.with_index(self.id, chunk_index, |chunk| *chunk) {
-			return self.plan_insert_chunk(key_prefix, address, &chunk, sub_index, log)
-		}
-
-		if let Some(map) = &*self.map.read() {
-			let chunk = Self::chunk_at(chunk_index, map);
-			return self.plan_insert_chunk(key_prefix, address, chunk, sub_index, log)
-		}
-
-		let chunk = &EMPTY_CHUNK;
-		self.plan_insert_chunk(key_prefix, address, chunk, sub_index, log)
-	}
-
 	fn plan_remove_chunk(
 		&self,
 		key_prefix: u64,
+		chunk_index: u64,
 		source: &[u8],
 		sub_index: usize,
 		log: &mut LogWriter,
 	) -> Result<PlanOutcome> {
 		let mut chunk = [0; CHUNK_LEN];
 		chunk.copy_from_slice(source);
-		let chunk_index = self.chunk_index(key_prefix);
-		let partial_key = Entry::extract_key(key_prefix, self.id.index_bits());
+		let partial_key = Entry::extract_key(key_prefix, self.key_bits(), self.id.index_bits());
 
 		let i = sub_index;
 		let entry = Self::read_entry(&chunk, i);
@@ -423,57 +543,137 @@ impl IndexTable {
 		log::trace!(target: "parity-db", "{}: Removing {}", self.id, hex(key));
 		let key_prefix = TableKey::index_from_partial(key);
 
-		let chunk_index = self.chunk_index(key_prefix);
+		let chunk_index = self.chunk_index(key_prefix) + (sub_index / CHUNK_ENTRIES) as u64;
+		let sub_index = sub_index % CHUNK_ENTRIES;
 
 		if let Some(chunk) = log.with_index(self.id, chunk_index, |chunk| *chunk) {
-			return self.plan_remove_chunk(key_prefix, &chunk, sub_index, log)
+			return self.plan_remove_chunk(key_prefix, chunk_index, &chunk, sub_index, log)
 		}
 
 		if let Some(map) = &*self.map.read() {
 			let chunk = Self::chunk_at(chunk_index, map);
-			return self.plan_remove_chunk(key_prefix, chunk, sub_index, log)
+			return self.plan_remove_chunk(key_prefix, chunk_index, chunk, sub_index, log)
 		}
 
 		Ok(PlanOutcome::Skipped)
 	}
 
-	pub fn enact_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
-		let mut map = self.map.upgradable_read();
-		if map.is_none() {
-			let mut wmap = RwLockUpgradableReadGuard::upgrade(map);
-			let file = std::fs::OpenOptions::new()
-				.write(true)
-				.read(true)
-				.create_new(true)
-				.open(self.path.as_path())?;
-			log::debug!(target: "parity-db", "Created new index {}", self.id);
-			//TODO: check for potential overflows on 32-bit platforms
-			file.set_len(file_size(self.id.index_bits()))?;
-			let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
-			self.madvise_random(&mut mmap);
-			*wmap = Some(mmap);
-			map = parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wmap);
-		}
-
-		let map = map.as_ref().unwrap();
+	/// Plan removal of all entries pointing to `address`. Returns the number of removed entries.
+	/// This scans the whole index and is only meant for repairs.
+	pub fn write_remove_address_plan(&self, address: Address, log: &mut LogWriter) -> Result<u64> {
+		let index_bits = self.id.index_bits();
+		let mut removed = 0;
+		for chunk_index in 0..self.id.total_chunks() {
+			let entries = self.entries(chunk_index, log);
+			let mut chunk = [0; CHUNK_LEN];
+			let mut found = Vec::new();
+			for (i, entry) in entries.iter().enumerate() {
+				if !entry.is_empty() && entry.address(index_bits) == address {
+					Self::write_entry(&Entry::empty(), i, &mut chunk);
+					found.push(i);
+				} else {
+					Self::write_entry(entry, i, &mut chunk);
+				}
+			}
+			for i in found {
+				log::debug!(target: "parity-db", "{}: Removed entry pointing to {} at {}.{}", self.id, address, chunk_index, i);
+				log.insert_index(self.id, chunk_index, i as u8, &chunk);
+				removed += 1;
+			}
+		}
+		Ok(removed)
+	}
+
+	// Map the file for writing, creating it if it does not exist yet.
+	fn map_for_write(&self) -> Result<RwLockUpgradableReadGuard<'_, Option<memmap2::MmapMut>>> {
+		let map = self.map.upgradable_read();
+		if map.is_some() {
+			return Ok(map)
+		}
+		let mut wmap = RwLockUpgradableReadGuard::upgrade(map);
+		let file = std::fs::OpenOptions::new()
+			.write(true)
+			.read(true)
+			.create_new(true)
+			.open(self.path.as_path())?;
+		log::debug!(target: "parity-db", "Created new index {}", self.id);
+		//TODO: check for potential overflows on 32-bit platforms
+		file.set_len(file_size(self.id.index_bits()))?;
+		let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
+		Self::madvise(self.id, self.advice, &mut mmap, true);
+		*wmap = Some(mmap);
+		Ok(parking_lot::RwLockWriteGuard::downgrade_to_upgradable(wmap))
+	}
+
+	#[allow(clippy::mut_from_ref)]
+	fn chunk_mut(index: u64, map: &memmap2::MmapMut) -> &mut [u8] {
 		let offset = META_SIZE + index as usize * CHUNK_LEN;
 		// Nasty mutable pointer cast. We do ensure that all chunks that are being written are
 		// accessed through the overlay in other threads.
 		let ptr: *mut u8 = map.as_ptr() as *mut u8;
-		let chunk: &mut [u8] = unsafe {
+		unsafe {
 			let ptr = ptr.add(offset);
 			std::slice::from_raw_parts_mut(ptr, CHUNK_LEN)
-		};
+		}
+	}
+
+	pub fn is_init(&self) -> bool {
+		self.map.read().is_some()
+	}
+
+	pub fn mapped_bytes(&self) -> u64 {
+		self.map.read().as_ref().map_or(0, |map| map.len() as u64)
+	}
+
+	/// Size of the index file, 0 if it was not created yet.
+	pub fn file_bytes(&self) -> u64 {
+		if self.is_init() {
+			file_size(self.id.index_bits())
+		} else {
+			0
+		}
+	}
+
+	/// Chunk content as written to the file, ignoring the log overlay.
+	pub fn chunk(&self, index: u64) -> Option<Chunk> {
+		self.map
+			.read()
+			.as_ref()
+			.map(|map| Self::chunk_at(index, map).try_into().unwrap())
+	}
+
+	/// Overwrite a chunk in the file. Used to restore index checkpoints on replay.
+	pub fn write_chunk(&self, index: u64, data: &Chunk) -> Result<()> {
+		if index >= self.id.total_chunks() {
+			return Err(Error::Corruption("Bad index".into()))
+		}
+		let map = self.map_for_write()?;
+		Self::chunk_mut(index, map.as_ref().unwrap()).copy_from_slice(data);
+		if let Some(cache) = &self.cache {
+			cache.invalidate(self.id, index);
+		}
+		log::trace!(target: "parity-db", "{}: Restored chunk {}", self.id, index);
+		Ok(())
+	}
+
+	/// Write a chunk logged with `write_insert_plan`. Returns the mask of the written entries.
+	pub fn enact_plan(&self, index: u64, log: &mut LogReader) -> Result<u64> {
+		let map = self.map_for_write()?;
+		let chunk = Self::chunk_mut(index, map.as_ref().unwrap());
 		let mut mask_buf = [0u8; 8];
 		log.read(&mut mask_buf)?;
-		let mut mask = u64::from_le_bytes(mask_buf);
+		let written = u64::from_le_bytes(mask_buf);
+		let mut mask = written;
 		while mask != 0 {
 			let i = mask.trailing_zeros();
 			mask &= !(1 << i);
 			log.read(&mut chunk[i as usize * ENTRY_BYTES..(i as usize + 1) * ENTRY_BYTES])?;
 		}
+		if let Some(cache) = &self.cache {
+			cache.invalidate(self.id, index);
+		}
 		log::trace!(target: "parity-db", "{}: Enacted chunk {}", self.id, index);
-		Ok(())
+		Ok(written)
 	}
 
 	pub fn validate_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
@@ -505,8 +705,14 @@ impl IndexTable {
 	}
 
 	pub fn drop_file(self) -> Result<()> {
-		std::mem::drop(self.map);
-		std::fs::remove_file(self.path.as_path())?;
+		// The file is only created on first write, so a table may be dropped without one.
+		let created = self.map.into_inner().is_some();
+		if let Some(cache) = &self.cache {
+			cache.invalidate_table(self.id);
+		}
+		if created {
+			std::fs::remove_file(self.path.as_path())?;
+		}
 		log::debug!(target: "parity-db", "{}: Dropped table", self.id);
 		Ok(())
 	}
@@ -519,19 +725,33 @@ impl IndexTable {
 		Ok(())
 	}
 
+	// Advise the kernel on a new mapping of a `created` or existing file.
 	#[cfg(unix)]
-	fn madvise_random(&self, map: &mut memmap2::MmapMut) {
-		unsafe {
-			libc::madvise(
-				map.as_mut_ptr() as _,
-				file_size(self.id.index_bits()) as usize,
-				libc::MADV_RANDOM,
-			);
+	fn madvise(id: TableId, advice: MapAdvice, map: &mut memmap2::MmapMut, created: bool) {
+		#[cfg(target_os = "linux")]
+		let huge_pages = advice.huge_pages.then_some(libc::MADV_HUGEPAGE);
+		#[cfg(not(target_os = "linux"))]
+		let huge_pages = None;
+		let prefetch = (advice.prefetch && !created).then_some(libc::MADV_WILLNEED);
+		for advice in [created.then_some(libc::MADV_RANDOM), huge_pages, prefetch]
+			.into_iter()
+			.flatten()
+		{
+			let err = unsafe { libc::madvise(map.as_mut_ptr() as _, map.len(), advice) };
+			if err != 0 {
+				log::warn!(
+					target: "parity-db",
+					"{}: madvise({}) failed: {}",
+					id,
+					advice,
+					std::io::Error::last_os_error(),
+				);
+			}
 		}
 	}
 
 	#[cfg(not(unix))]
-	fn madvise_random(&self, _map: &mut memmap2::MmapMut) {}
+	fn madvise(_id: TableId, _advice: MapAdvice, _map: &mut memmap2::MmapMut, _created: bool) {}
 }
 
 #[cfg(test)]
@@ -542,7 +762,7 @@ mod test {
 	fn test_entries() {
 		let mut chunk = IndexTable::transmute_chunk(EMPTY_CHUNK);
 		let mut chunk2 = EMPTY_CHUNK;
-		for i in 0..CHUNK_ENTRIES {
+		for (i, slot) in chunk.iter_mut().enumerate() {
 			use std::{
 				collections::hash_map::DefaultHasher,
 				hash::{Hash, Hasher},
@@ -550,9 +770,9 @@ mod test {
 			let mut hasher = DefaultHasher::new();
 			i.hash(&mut hasher);
 			let hash = hasher.finish();
-			let entry = Entry::from_u64(hash as u64);
+			let entry = Entry::from_u64(hash);
 			IndexTable::write_entry(&entry, i, &mut chunk2);
-			chunk[i] = entry;
+			*slot = entry;
 		}
 
 		assert!(IndexTable::transmute_chunk(chunk2) == chunk);
@@ -14,26 +14,60 @@
 // You should have received a copy of the GNU General Public License
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
+mod archive;
+mod bloom;
 mod btree;
+mod cache;
 mod column;
+mod commit_trace;
 mod compress;
 mod db;
 mod display;
 mod error;
+mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod file;
 mod index;
+mod lock;
 mod log;
+mod metrics;
 mod migration;
 mod options;
+mod snapshot;
 mod stats;
 mod table;
+mod trace;
+#[cfg(feature = "typed")]
+pub mod typed;
 
-pub use btree::BTreeIterator;
+pub use archive::ChangeStream;
+pub use btree::{BTreeIterator, MultimapIterator};
+pub use column::IndexEntry;
 pub use compress::CompressionType;
-pub use db::{check::CheckOptions, Db, Value};
+pub use db::{
+	check::{CheckOptions, CheckReport, CorruptedEntry},
+	ColumnInfo, ColumnKind, ColumnMemoryStats, ColumnSize, CommitHandle, CommitHook, CommitOutcome,
+	CommitStage, CommitTimings, Db, DbInfo, FenceToken, Health, MemoryStats, Operation,
+	ReplayReport, Transaction, Value, ValueGuard, ValueReader,
+};
 pub use error::{Error, Result};
-pub use migration::migrate;
-pub use options::{ColumnOptions, Options};
+#[cfg(any(test, feature = "fail-points"))]
+pub use file::fail;
+pub use file::{FaultConfig, FaultOp, FaultStorage, MemoryStorage, StorageBackend, StorageFile};
+pub use log::ReplayStop;
+pub use metrics::MetricsSink;
+#[cfg(feature = "metrics")]
+pub use metrics::PrometheusMetrics;
+#[cfg(feature = "rocksdb")]
+pub use migration::import_rocksdb;
+pub use migration::{migrate, migrate_column, upgrade_format};
+pub use options::{
+	AutoVacuum, ColumnMetadataCheck, ColumnOptions, CompactionDecision, CompactionFilter,
+	OnCorruption, OnDiskFull, Options, SlowOpThresholds, SyncMode, Worker, WorkerThreads,
+	CURRENT_VERSION, LAST_SUPPORTED_VERSION,
+};
+pub use stats::{CompressionStats, IndexStats, ValueSizeHistogram};
 
 #[derive(Default)]
 pub struct IdentityKeyHash(u64);
@@ -0,0 +1,212 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Database lock file. The lock is an exclusive `flock` on the `lock` file in the database
+//! directory. The owner process and host are written into the file, so that a lock kept by
+//! an inherited file descriptor or a network file system after the owner died can be told
+//! apart from a lock of a running process.
+
+use crate::error::{Error, Result};
+use fs2::FileExt;
+use std::{
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+const LOCK_FILE: &str = "lock";
+
+#[derive(Debug, PartialEq, Eq)]
+struct LockOwner {
+	pid: u32,
+	host: String,
+}
+
+impl LockOwner {
+	fn current() -> LockOwner {
+		LockOwner { pid: std::process::id(), host: hostname() }
+	}
+
+	fn read(path: &Path) -> Option<LockOwner> {
+		let content = std::fs::read_to_string(path).ok()?;
+		let mut pid = None;
+		let mut host = None;
+		for l in content.lines() {
+			match l.split_once('=') {
+				Some(("pid", v)) => pid = v.parse().ok(),
+				Some(("host", v)) => host = Some(v.to_owned()),
+				_ => {},
+			}
+		}
+		Some(LockOwner { pid: pid?, host: host? })
+	}
+
+	// A lock is stale if it was taken on this host by a process that no longer exists.
+	fn is_stale(&self) -> bool {
+		self.host == hostname() && process_exists(self.pid) == Some(false)
+	}
+}
+
+impl std::fmt::Display for LockOwner {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "process {} on {}", self.pid, self.host)
+	}
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+	let mut buf = [0u8; 256];
+	if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+		return String::new()
+	}
+	let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+	String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+	std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> Option<bool> {
+	let pid = libc::pid_t::try_from(pid).ok()?;
+	if unsafe { libc::kill(pid, 0) } == 0 {
+		return Some(true)
+	}
+	match std::io::Error::last_os_error().raw_os_error() {
+		Some(libc::ESRCH) => Some(false),
+		Some(libc::EPERM) => Some(true),
+		_ => None,
+	}
+}
+
+// Unknown, locks are never considered stale.
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> Option<bool> {
+	None
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+	path.join(LOCK_FILE)
+}
+
+fn open_lock_file(path: &Path) -> Result<std::fs::File> {
+	Ok(std::fs::OpenOptions::new()
+		.create(true)
+		.truncate(false)
+		.read(true)
+		.write(true)
+		.open(path)?)
+}
+
+fn locked_error(e: std::io::Error, owner: Option<&LockOwner>) -> Error {
+	match owner {
+		Some(owner) =>
+			Error::Locked(std::io::Error::new(e.kind(), format!("{}, locked by {}", e, owner))),
+		None => Error::Locked(e),
+	}
+}
+
+/// Lock the database in `path`. With `force_unlock`, a stale lock is removed first. The lock
+/// is released when the returned file is closed. With `check` unset the file is only opened.
+pub(crate) fn lock(path: &Path, check: bool, force_unlock: bool) -> Result<std::fs::File> {
+	let path = lock_path(path);
+	let mut file = open_lock_file(&path)?;
+	if !check {
+		return Ok(file)
+	}
+	if let Err(e) = file.try_lock_exclusive() {
+		let owner = LockOwner::read(&path);
+		match owner {
+			Some(owner) if force_unlock && owner.is_stale() => {
+				log::warn!(target: "parity-db", "Removing stale lock of {}", owner);
+				std::fs::remove_file(&path)?;
+				file = open_lock_file(&path)?;
+				file.try_lock_exclusive().map_err(|e| locked_error(e, None))?;
+			},
+			owner => return Err(locked_error(e, owner.as_ref())),
+		}
+	}
+	file.set_len(0)?;
+	let owner = LockOwner::current();
+	write!(file, "pid={}\nhost={}\n", owner.pid, owner.host)?;
+	Ok(file)
+}
+
+/// Remove the lock of the database in `path` if it was left behind by a process that no
+/// longer exists. Returns `false` if the database is not locked.
+pub(crate) fn break_lock(path: &Path) -> Result<bool> {
+	let path = lock_path(path);
+	if !path.exists() {
+		return Ok(false)
+	}
+	let file = open_lock_file(&path)?;
+	match file.try_lock_exclusive() {
+		Ok(()) => {
+			file.unlock()?;
+			Ok(false)
+		},
+		Err(e) => match LockOwner::read(&path) {
+			Some(owner) if owner.is_stale() => {
+				log::warn!(target: "parity-db", "Removing stale lock of {}", owner);
+				std::fs::remove_file(&path)?;
+				Ok(true)
+			},
+			owner => Err(locked_error(e, owner.as_ref())),
+		},
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::{break_lock, lock, lock_path, LockOwner};
+	use fs2::FileExt;
+
+	fn dead_pid() -> u32 {
+		let mut child = std::process::Command::new("true").spawn().unwrap();
+		let pid = child.id();
+		child.wait().unwrap();
+		pid
+	}
+
+	#[test]
+	fn stale_lock() {
+		let dir = tempfile::tempdir().unwrap();
+		let file = lock(dir.path(), true, false).unwrap();
+		assert_eq!(LockOwner::read(&lock_path(dir.path())), Some(LockOwner::current()));
+		// Held by a running process.
+		assert!(lock(dir.path(), true, true).is_err());
+		assert!(break_lock(dir.path()).is_err());
+
+		// The owner died but the lock is still held, e.g. by an inherited descriptor.
+		let owner = LockOwner { pid: dead_pid(), ..LockOwner::current() };
+		std::fs::write(lock_path(dir.path()), format!("pid={}\nhost={}\n", owner.pid, owner.host))
+			.unwrap();
+		assert!(lock(dir.path(), true, false).is_err());
+		let relocked = lock(dir.path(), true, true).unwrap();
+		drop(relocked);
+		assert!(!break_lock(dir.path()).unwrap());
+
+		let other = std::fs::File::open(lock_path(dir.path())).unwrap();
+		other.lock_exclusive().unwrap();
+		std::fs::write(lock_path(dir.path()), format!("pid={}\nhost={}\n", owner.pid, owner.host))
+			.unwrap();
+		assert!(break_lock(dir.path()).unwrap());
+		assert!(!lock_path(dir.path()).exists());
+		lock(dir.path(), true, false).unwrap();
+		drop(file);
+	}
+}
@@ -14,10 +14,12 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
+	archive::ChangeArchive,
 	column::ColId,
+	compress::{Compress, CompressionType},
 	error::{Error, Result},
 	index::{Chunk as IndexChunk, TableId as IndexTableId, ENTRY_BYTES},
-	options::Options,
+	options::{Options, SyncMode, COMPRESSED_LOG_VERSION, FRAMED_LOG_VERSION},
 	table::TableId as ValueTableId,
 };
 use parking_lot::{Condvar, MappedRwLockWriteGuard, Mutex, RwLock, RwLockWriteGuard};
@@ -34,6 +36,30 @@ const INSERT_INDEX: u8 = 2;
 const INSERT_VALUE: u8 = 3;
 const END_RECORD: u8 = 4;
 const DROP_TABLE: u8 = 5;
+const CHECKPOINT: u8 = 6;
+const CHECKPOINT_INDEX: u8 = 7;
+const TRANSACTION: u8 = 8;
+// Followed by the compressed length (u32 LE) and the lz4 compressed entries of the record.
+const COMPRESSED: u8 = 9;
+// Record header followed by the length of the record entries (u32 LE), up to `END_RECORD`.
+const BEGIN_FRAMED_RECORD: u8 = 10;
+// Set on entry types that are followed by a 4 byte table id. Ids of tables of the first 256
+// columns are written in 2 bytes.
+const WIDE_TABLE_ID: u8 = 0x80;
+
+// Entry type followed by the table id, and its length.
+fn entry_header(entry: u8, id: u32) -> ([u8; 5], usize) {
+	let mut header = [0u8; 5];
+	if id <= u16::MAX as u32 {
+		header[0] = entry;
+		header[1..3].copy_from_slice(&(id as u16).to_le_bytes());
+		(header, 3)
+	} else {
+		header[0] = entry | WIDE_TABLE_ID;
+		header[1..5].copy_from_slice(&id.to_le_bytes());
+		(header, 5)
+	}
+}
 
 pub struct InsertIndexAction {
 	pub table: IndexTableId,
@@ -50,9 +76,45 @@ pub enum LogAction {
 	InsertIndex(InsertIndexAction),
 	InsertValue(InsertValueAction),
 	DropTable(IndexTableId),
+	// Checkpoint covering all records up to the given id. Followed by `CheckpointIndex`
+	// actions.
+	Checkpoint(u64),
+	// Index chunk snapshot. Followed by the full chunk.
+	CheckpointIndex(InsertIndexAction),
 	EndRecord,
 }
 
+/// Why the log replay on open stopped before the end of the log, see `Db::replay_report`.
+/// The record it stopped at and all records after it are discarded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayStop {
+	/// The record at `offset` in log file `log` was only partially written, e.g. because the
+	/// process was killed while writing it.
+	TornRecord { log: u32, offset: u64 },
+	/// The record at `offset` in log file `log` failed the checksum or could not be parsed.
+	CorruptRecord { log: u32, offset: u64, reason: String },
+	/// Record `expected` is missing, the log continues with record `found`.
+	MissingRecord { expected: u64, found: u64 },
+	/// Not all records of the commit split over several records starting with `record_id`
+	/// were written.
+	IncompleteTransaction { record_id: u64 },
+}
+
+impl std::fmt::Display for ReplayStop {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ReplayStop::TornRecord { log, offset } =>
+				write!(f, "torn record in log {} at offset {}", log, offset),
+			ReplayStop::CorruptRecord { log, offset, reason } =>
+				write!(f, "corrupt record in log {} at offset {}: {}", log, offset, reason),
+			ReplayStop::MissingRecord { expected, found } =>
+				write!(f, "missing record {}, found {}", expected, found),
+			ReplayStop::IncompleteTransaction { record_id } =>
+				write!(f, "incomplete split commit starting with record {}", record_id),
+		}
+	}
+}
+
 pub trait LogQuery {
 	fn with_index<R, F: FnOnce(&IndexChunk) -> R>(
 		&self,
@@ -61,6 +123,9 @@ pub trait LogQuery {
 		f: F,
 	) -> Option<R>;
 	fn value(&self, table: ValueTableId, index: u64, dest: &mut [u8]) -> bool;
+	/// Changes each time overlay entries are dropped after being written to the tables. Table
+	/// data read before the change may be older than the overlay entries that are gone.
+	fn generation(&self) -> u64;
 }
 
 #[derive(Default)]
@@ -68,12 +133,33 @@ pub struct LogOverlays {
 	index: HashMap<IndexTableId, IndexLogOverlay>,
 	value: HashMap<ValueTableId, ValueLogOverlay>,
 	last_record_id: HashMap<ColId, u64>,
+	generation: u64,
 }
 
 impl LogOverlays {
 	pub fn last_record_id(&self, col: ColId) -> u64 {
 		self.last_record_id.get(&col).cloned().unwrap_or(u64::MAX)
 	}
+
+	/// Approximate memory held by overlays of tables of column `col`.
+	pub fn bytes(&self, col: ColId) -> u64 {
+		let index_entry = std::mem::size_of::<(u64, (u64, u64, IndexChunk))>();
+		let value_entry = std::mem::size_of::<(u64, (u64, Vec<u8>))>();
+		let index: usize = self
+			.index
+			.iter()
+			.filter(|(id, _)| id.col() == col)
+			.map(|(_, overlay)| overlay.map.len() * index_entry)
+			.sum();
+		let value: usize = self
+			.value
+			.iter()
+			.filter(|(id, _)| id.col() == col)
+			.flat_map(|(_, overlay)| overlay.map.values())
+			.map(|(_, data)| value_entry + data.capacity())
+			.sum();
+		(index + value) as u64
+	}
 }
 
 impl LogQuery for RwLock<LogOverlays> {
@@ -89,6 +175,32 @@ impl LogQuery for RwLock<LogOverlays> {
 	fn value(&self, table: ValueTableId, index: u64, dest: &mut [u8]) -> bool {
 		self.read().value(table, index, dest)
 	}
+
+	fn generation(&self) -> u64 {
+		self.read().generation
+	}
+}
+
+/// Log query that finds nothing, for reading the table files only.
+pub struct NoOverlays;
+
+impl LogQuery for NoOverlays {
+	fn with_index<R, F: FnOnce(&IndexChunk) -> R>(
+		&self,
+		_table: IndexTableId,
+		_index: u64,
+		_f: F,
+	) -> Option<R> {
+		None
+	}
+
+	fn value(&self, _table: ValueTableId, _index: u64, _dest: &mut [u8]) -> bool {
+		false
+	}
+
+	fn generation(&self) -> u64 {
+		0
+	}
 }
 
 impl LogQuery for LogOverlays {
@@ -114,12 +226,28 @@ impl LogQuery for LogOverlays {
 			false
 		}
 	}
+
+	fn generation(&self) -> u64 {
+		self.generation
+	}
 }
 
 #[derive(Default)]
 pub struct Cleared {
 	index: Vec<(IndexTableId, u64)>,
 	values: Vec<(ValueTableId, u64)>,
+	// Raw record bytes, when the record is archived.
+	record: Vec<u8>,
+}
+
+impl Cleared {
+	pub fn index(&self) -> &[(IndexTableId, u64)] {
+		&self.index
+	}
+
+	pub fn values(&self) -> &[(ValueTableId, u64)] {
+		&self.values
+	}
 }
 
 pub struct LogReader<'a> {
@@ -129,6 +257,17 @@ pub struct LogReader<'a> {
 	crc32: crc32fast::Hasher,
 	validate: bool,
 	cleared: Cleared,
+	archive: bool,
+	transaction: Option<(u64, bool)>,
+	// Decompressed entries of a compressed record, read before the rest of the file.
+	inflated: Option<std::io::Cursor<Vec<u8>>>,
+	// Log file id and offset of the current record. The offset is only tracked when
+	// validating.
+	log: u32,
+	offset: u64,
+	// Failure injected into the reads of this record.
+	#[cfg(any(test, feature = "fail-points"))]
+	fail: Option<crate::file::fail::FailAction>,
 }
 
 impl<'a> LogReader<'a> {
@@ -136,18 +275,59 @@ impl<'a> LogReader<'a> {
 		self.record_id
 	}
 
+	/// Transaction id and last part flag if the current record is a part of a commit split over
+	/// several records.
+	pub fn transaction(&self) -> Option<(u64, bool)> {
+		self.transaction
+	}
+
 	fn new(
-		file: MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>,
+		mut file: MappedRwLockWriteGuard<'a, std::io::BufReader<std::fs::File>>,
+		log: u32,
 		validate: bool,
-	) -> LogReader<'a> {
-		LogReader {
+		archive: bool,
+	) -> Result<LogReader<'a>> {
+		let offset = if validate { file.stream_position()? } else { 0 };
+		Ok(LogReader {
 			cleared: Default::default(),
 			file,
 			record_id: 0,
 			read_bytes: 0,
 			crc32: crc32fast::Hasher::new(),
 			validate,
+			archive,
+			transaction: None,
+			inflated: None,
+			log,
+			offset,
+			#[cfg(any(test, feature = "fail-points"))]
+			fail: None,
+		})
+	}
+
+	/// Log file id and offset of the current record. The offset is 0 unless validating.
+	pub fn position(&self) -> (u32, u64) {
+		(self.log, self.offset)
+	}
+
+	fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+		#[cfg(any(test, feature = "fail-points"))]
+		{
+			use crate::file::fail::{self, FailAction, FailPoint};
+			match self.fail {
+				Some(FailAction::Partial(left)) if left >= buf.len() =>
+					self.fail = Some(FailAction::Partial(left - buf.len())),
+				Some(action) => {
+					self.fail = None;
+					return fail::read(FailPoint::LogRead, action, buf, |buf| {
+						Ok(self.file.read_exact(buf)?)
+					})
+				},
+				None => (),
+			}
 		}
+		self.file.read_exact(buf)?;
+		Ok(())
 	}
 
 	pub fn reset(&mut self) -> Result<()> {
@@ -155,50 +335,162 @@ impl<'a> LogReader<'a> {
 		self.file.seek(std::io::SeekFrom::Current(-(self.read_bytes as i64)))?;
 		self.read_bytes = 0;
 		self.record_id = 0;
+		self.transaction = None;
+		self.inflated = None;
 		self.crc32 = crc32fast::Hasher::new();
 		Ok(())
 	}
 
-	pub fn next(&mut self) -> Result<LogAction> {
-		let mut read_buf = |size, buf: &mut [u8; 8]| -> Result<()> {
-			self.file.read_exact(&mut buf[0..size])?;
-			self.read_bytes += size as u64;
-			if self.validate {
-				self.crc32.update(&buf[0..size]);
+	// Read record bytes from the file.
+	fn read_raw(&mut self, buf: &mut [u8]) -> Result<()> {
+		self.read_exact(buf)?;
+		self.read_bytes += buf.len() as u64;
+		if self.validate {
+			self.crc32.update(buf);
+		}
+		if self.archive {
+			self.cleared.record.extend_from_slice(buf);
+		}
+		Ok(())
+	}
+
+	// Read record entries, from the decompressed ones first.
+	fn read_entries(&mut self, buf: &mut [u8]) -> Result<()> {
+		if let Some(inflated) = &mut self.inflated {
+			let left = inflated.get_ref().len() - inflated.position() as usize;
+			if left > 0 {
+				if left < buf.len() {
+					return Err(Error::Corruption("Bad compressed log record".into()))
+				}
+				inflated.read_exact(buf)?;
+				return Ok(())
 			}
-			Ok(())
-		};
+			self.inflated = None;
+		}
+		self.read_raw(buf)
+	}
+
+	// Check the checksum of a framed record before its entries are parsed, so that a damaged
+	// record is reported as such rather than as a parse error. Leaves the file at the first
+	// entry.
+	fn check_frame(&mut self, len: u32) -> Result<()> {
+		let mut crc32 = self.crc32.clone();
+		let mut buf = [0u8; 4096];
+		let mut left = len as usize;
+		while left > 0 {
+			let n = left.min(buf.len());
+			self.file.read_exact(&mut buf[..n])?;
+			crc32.update(&buf[..n]);
+			left -= n;
+		}
+		let mut end = [0u8; 5];
+		self.file.read_exact(&mut end)?;
+		self.file.seek(std::io::SeekFrom::Current(-(len as i64 + end.len() as i64)))?;
+		crc32.update(&end[..1]);
+		if end[0] != END_RECORD ||
+			u32::from_le_bytes(end[1..].try_into().unwrap()) != crc32.finalize()
+		{
+			return Err(Error::Corruption("Log record CRC-32 mismatch".into()))
+		}
+		Ok(())
+	}
 
+	// Read and decompress the entries of a compressed record.
+	fn inflate(&mut self) -> Result<()> {
+		if self.inflated.is_some() {
+			return Err(Error::Corruption("Nested compressed log entries".into()))
+		}
+		let mut len = [0u8; 4];
+		self.read_raw(&mut len)?;
+		let len = u32::from_le_bytes(len) as u64;
+		// The length is not verified by the checksum yet, don't allocate past the end of the
+		// file.
+		let file_len = self.file.get_ref().metadata()?.len();
+		if self.file.stream_position()? + len > file_len {
+			return Err(Error::Corruption("Compressed log record past the end of the file".into()))
+		}
+		let mut compressed = vec![0; len as usize];
+		self.read_raw(&mut compressed)?;
+		let entries = Compress::new(CompressionType::Lz4, 0)
+			.decompress(&compressed)
+			.map_err(|_| Error::Corruption("Bad compressed log record".into()))?;
+		self.inflated = Some(std::io::Cursor::new(entries));
+		Ok(())
+	}
+
+	pub fn next(&mut self) -> Result<LogAction> {
 		let mut buf = [0u8; 8];
-		read_buf(1, &mut buf)?;
-		match buf[0] {
-			BEGIN_RECORD => {
-				read_buf(8, &mut buf)?;
+		self.read_entries(&mut buf[0..1])?;
+		let entry = buf[0] & !WIDE_TABLE_ID;
+		let id_size = if buf[0] & WIDE_TABLE_ID != 0 {
+			if !matches!(entry, INSERT_INDEX | INSERT_VALUE | DROP_TABLE | CHECKPOINT_INDEX) {
+				return Err(Error::Corruption("Bad log entry type".into()))
+			}
+			4
+		} else {
+			2
+		};
+		let table_id = |buf: &[u8; 8]| {
+			if id_size == 4 {
+				u32::from_le_bytes(buf[0..4].try_into().unwrap())
+			} else {
+				u16::from_le_bytes(buf[0..2].try_into().unwrap()) as u32
+			}
+		};
+		match entry {
+			BEGIN_RECORD | BEGIN_FRAMED_RECORD => {
+				if self.validate {
+					self.offset = self.file.stream_position()? - 1;
+				}
+				self.read_entries(&mut buf[0..8])?;
 				let record_id = u64::from_le_bytes(buf);
 				self.record_id = record_id;
+				self.transaction = None;
+				if entry == BEGIN_FRAMED_RECORD {
+					self.read_entries(&mut buf[0..4])?;
+					if self.validate {
+						self.check_frame(u32::from_le_bytes(buf[0..4].try_into().unwrap()))?;
+					}
+				}
 				Ok(LogAction::BeginRecord)
 			},
+			TRANSACTION => {
+				self.read_entries(&mut buf[0..8])?;
+				let id = u64::from_le_bytes(buf);
+				self.read_entries(&mut buf[0..1])?;
+				self.transaction = Some((id, buf[0] != 0));
+				// Only tracked by the reader.
+				self.next()
+			},
+			COMPRESSED => {
+				self.inflate()?;
+				self.next()
+			},
 			INSERT_INDEX => {
-				read_buf(2, &mut buf)?;
-				let table =
-					IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
-				read_buf(8, &mut buf)?;
+				self.read_entries(&mut buf[0..id_size])?;
+				let table = IndexTableId::from_u32(table_id(&buf));
+				self.read_entries(&mut buf[0..8])?;
 				let index = u64::from_le_bytes(buf);
 				self.cleared.index.push((table, index));
 				Ok(LogAction::InsertIndex(InsertIndexAction { table, index }))
 			},
 			INSERT_VALUE => {
-				read_buf(2, &mut buf)?;
-				let table =
-					ValueTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
-				read_buf(8, &mut buf)?;
+				self.read_entries(&mut buf[0..id_size])?;
+				let table = ValueTableId::from_u32(table_id(&buf));
+				self.read_entries(&mut buf[0..8])?;
 				let index = u64::from_le_bytes(buf);
 				self.cleared.values.push((table, index));
 				Ok(LogAction::InsertValue(InsertValueAction { table, index }))
 			},
 			END_RECORD => {
-				self.file.read_exact(&mut buf[0..4])?;
+				if self.inflated.is_some() {
+					return Err(Error::Corruption("Bad compressed log record".into()))
+				}
+				self.read_exact(&mut buf[0..4])?;
 				self.read_bytes += 4;
+				if self.archive {
+					self.cleared.record.extend_from_slice(&buf[0..4]);
+				}
 				if self.validate {
 					let checksum = u32::from_le_bytes(buf[0..4].try_into().unwrap());
 					let expected = std::mem::take(&mut self.crc32).finalize();
@@ -216,22 +508,27 @@ impl<'a> LogReader<'a> {
 				Ok(LogAction::EndRecord)
 			},
 			DROP_TABLE => {
-				read_buf(2, &mut buf)?;
-				let table =
-					IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
+				self.read_entries(&mut buf[0..id_size])?;
+				let table = IndexTableId::from_u32(table_id(&buf));
 				Ok(LogAction::DropTable(table))
 			},
+			CHECKPOINT => {
+				self.read_entries(&mut buf[0..8])?;
+				Ok(LogAction::Checkpoint(u64::from_le_bytes(buf)))
+			},
+			CHECKPOINT_INDEX => {
+				self.read_entries(&mut buf[0..id_size])?;
+				let table = IndexTableId::from_u32(table_id(&buf));
+				self.read_entries(&mut buf[0..8])?;
+				let index = u64::from_le_bytes(buf);
+				Ok(LogAction::CheckpointIndex(InsertIndexAction { table, index }))
+			},
 			_ => Err(Error::Corruption("Bad log entry type".into())),
 		}
 	}
 
 	pub fn read(&mut self, buf: &mut [u8]) -> Result<()> {
-		self.file.read_exact(buf)?;
-		self.read_bytes += buf.len() as u64;
-		if self.validate {
-			self.crc32.update(buf);
-		}
-		Ok(())
+		self.read_entries(buf)
 	}
 
 	pub fn drain(self) -> Cleared {
@@ -243,11 +540,17 @@ impl<'a> LogReader<'a> {
 	}
 }
 
+// Index chunks captured by a checkpoint record.
+pub type CheckpointChunks = Vec<(IndexTableId, u64, IndexChunk)>;
+
 pub struct LogChange {
 	local_index: HashMap<IndexTableId, IndexLogOverlay>,
 	local_values: HashMap<ValueTableId, ValueLogOverlay>,
 	record_id: u64,
 	dropped_tables: Vec<IndexTableId>,
+	checkpoint: Option<(u64, CheckpointChunks)>,
+	// Transaction id and last part flag for commits split over several records.
+	transaction: Option<(u64, bool)>,
 }
 
 impl LogChange {
@@ -256,6 +559,8 @@ impl LogChange {
 			local_index: Default::default(),
 			local_values: Default::default(),
 			dropped_tables: Default::default(),
+			checkpoint: None,
+			transaction: None,
 			record_id,
 		}
 	}
@@ -264,24 +569,63 @@ impl LogChange {
 		self.local_values.get(&id)
 	}
 
-	fn flush_to_file(self, file: &mut std::io::BufWriter<std::fs::File>) -> Result<FlushedLog> {
-		let mut crc32 = crc32fast::Hasher::new();
-		let mut bytes: u64 = 0;
+	fn flush_to_file(
+		self,
+		file: &mut impl Write,
+		compress: bool,
+		framed: bool,
+	) -> Result<FlushedLog> {
+		let mut out =
+			RecordWriter { file, crc32: crc32fast::Hasher::new(), bytes: 0, body: None, framed };
+
+		if compress {
+			out.body = Some(Vec::new());
+		} else {
+			// Count the entries first, so that they don't have to be kept for the frame length.
+			let mut len = 0u64;
+			if framed {
+				self.write_entries(&mut |buf| {
+					len += buf.len() as u64;
+					Ok(())
+				})?;
+			}
+			out.begin(self.record_id, len)?;
+		}
+		self.write_entries(&mut |buf| out.write(buf))?;
+		out.write_body(self.record_id)?;
+		for id in self.dropped_tables.iter() {
+			log::debug!(target: "parity-db", "Finalizing drop {}", id);
+		}
+		out.write(&END_RECORD.to_le_bytes())?;
+		let checksum: u32 = out.crc32.finalize();
+		out.file.write_all(&checksum.to_le_bytes())?;
+		out.file.flush()?;
+		Ok(FlushedLog { index: self.local_index, values: self.local_values, bytes: out.bytes + 4 })
+	}
 
-		let mut write = |buf: &[u8]| -> Result<()> {
-			file.write_all(buf)?;
-			crc32.update(buf);
-			bytes += buf.len() as u64;
-			Ok(())
-		};
+	// Pass the entries of the record to `write`.
+	fn write_entries(&self, write: &mut impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+		if let Some((id, last)) = self.transaction {
+			write(&TRANSACTION.to_le_bytes())?;
+			write(&id.to_le_bytes())?;
+			write(&[last as u8])?;
+		}
 
-		write(&BEGIN_RECORD.to_le_bytes())?;
-		write(&self.record_id.to_le_bytes())?;
+		if let Some((covered, chunks)) = &self.checkpoint {
+			write(CHECKPOINT.to_le_bytes().as_ref())?;
+			write(&covered.to_le_bytes())?;
+			for (id, index, chunk) in chunks.iter() {
+				let (header, len) = entry_header(CHECKPOINT_INDEX, id.as_u32());
+				write(&header[..len])?;
+				write(&index.to_le_bytes())?;
+				write(chunk)?;
+			}
+		}
 
 		for (id, overlay) in self.local_index.iter() {
 			for (index, (_, modified_entries_mask, chunk)) in overlay.map.iter() {
-				write(INSERT_INDEX.to_le_bytes().as_ref())?;
-				write(&id.as_u16().to_le_bytes())?;
+				let (header, len) = entry_header(INSERT_INDEX, id.as_u32());
+				write(&header[..len])?;
 				write(&index.to_le_bytes())?;
 				write(&modified_entries_mask.to_le_bytes())?;
 				let mut mask = *modified_entries_mask;
@@ -294,23 +638,71 @@ impl LogChange {
 		}
 		for (id, overlay) in self.local_values.iter() {
 			for (index, (_, value)) in overlay.map.iter() {
-				write(INSERT_VALUE.to_le_bytes().as_ref())?;
-				write(&id.as_u16().to_le_bytes())?;
+				let (header, len) = entry_header(INSERT_VALUE, id.as_u32());
+				write(&header[..len])?;
 				write(&index.to_le_bytes())?;
 				write(value)?;
 			}
 		}
 		for id in self.dropped_tables.iter() {
-			log::debug!(target: "parity-db", "Finalizing drop {}", id);
-			write(DROP_TABLE.to_le_bytes().as_ref())?;
-			write(&id.as_u16().to_le_bytes())?;
+			let (header, len) = entry_header(DROP_TABLE, id.as_u32());
+			write(&header[..len])?;
+		}
+		Ok(())
+	}
+}
+
+// Writes a log record, keeping the entries in `body` while it is set.
+struct RecordWriter<'a, W: Write> {
+	file: &'a mut W,
+	crc32: crc32fast::Hasher,
+	bytes: u64,
+	body: Option<Vec<u8>>,
+	// Whether records may be framed, see `FRAMED_LOG_VERSION`.
+	framed: bool,
+}
+
+impl<'a, W: Write> RecordWriter<'a, W> {
+	fn write(&mut self, buf: &[u8]) -> Result<()> {
+		if let Some(body) = &mut self.body {
+			body.extend_from_slice(buf);
+			return Ok(())
+		}
+		self.file.write_all(buf)?;
+		self.crc32.update(buf);
+		self.bytes += buf.len() as u64;
+		Ok(())
+	}
+
+	// Start the record, framed with the length of the entries that follow. Records too large
+	// to frame, or written for older formats, are written the way they were before framing.
+	fn begin(&mut self, record_id: u64, len: u64) -> Result<()> {
+		match u32::try_from(len) {
+			Ok(len) if self.framed => {
+				self.write(&BEGIN_FRAMED_RECORD.to_le_bytes())?;
+				self.write(&record_id.to_le_bytes())?;
+				self.write(&len.to_le_bytes())
+			},
+			_ => {
+				self.write(&BEGIN_RECORD.to_le_bytes())?;
+				self.write(&record_id.to_le_bytes())
+			},
+		}
+	}
+
+	// Start the record and write the kept entries, compressed if that makes them smaller.
+	fn write_body(&mut self, record_id: u64) -> Result<()> {
+		let Some(body) = self.body.take() else { return Ok(()) };
+		let compressed = Compress::new(CompressionType::Lz4, 0).compress(&body);
+		if compressed.len() + 5 < body.len() && compressed.len() <= u32::MAX as usize {
+			self.begin(record_id, compressed.len() as u64 + 5)?;
+			self.write(&[COMPRESSED])?;
+			self.write(&(compressed.len() as u32).to_le_bytes())?;
+			self.write(&compressed)
+		} else {
+			self.begin(record_id, body.len() as u64)?;
+			self.write(&body)
 		}
-		write(&END_RECORD.to_le_bytes())?;
-		let checksum: u32 = crc32.finalize();
-		file.write_all(&checksum.to_le_bytes())?;
-		bytes += 4;
-		file.flush()?;
-		Ok(FlushedLog { index: self.local_index, values: self.local_values, bytes })
 	}
 }
 
@@ -358,6 +750,12 @@ impl<'a> LogWriter<'a> {
 		self.log.dropped_tables.push(id);
 	}
 
+	/// Make this a checkpoint record for all records up to `covered`, with the current content
+	/// of index chunks written since the last checkpoint.
+	pub fn checkpoint(&mut self, covered: u64, chunks: CheckpointChunks) {
+		self.log.checkpoint = Some((covered, chunks));
+	}
+
 	pub fn drain(self) -> LogChange {
 		self.log
 	}
@@ -395,6 +793,10 @@ impl<'a> LogQuery for LogWriter<'a> {
 			self.overlays.value(table, index, dest)
 		}
 	}
+
+	fn generation(&self) -> u64 {
+		self.overlays.generation()
+	}
 }
 
 // Identity hash.
@@ -456,6 +858,8 @@ struct Appending {
 	id: u32,
 	file: std::io::BufWriter<std::fs::File>,
 	size: u64,
+	// A split commit is being written. The file is not flushed until the last part is in.
+	transaction: bool,
 }
 
 struct Flushing {
@@ -480,6 +884,8 @@ pub struct Log {
 	reading: RwLock<Option<Reading>>,
 	reading_state: Mutex<ReadingState>,
 	done_reading_cv: Condvar,
+	// Flushes stop waiting for the reader, see `interrupt_flush`.
+	flush_interrupted: AtomicBool,
 	flushing: Mutex<Option<Flushing>>,
 	next_record_id: AtomicU64,
 	dirty: AtomicBool,
@@ -489,10 +895,18 @@ pub struct Log {
 	path: std::path::PathBuf,
 	next_log_id: AtomicU32,
 	sync: bool,
+	sync_records: bool,
+	compress: bool,
+	framed: bool,
+	archive: Option<ChangeArchive>,
+	archive_logs: Option<std::path::PathBuf>,
+	replay_stop: Mutex<Option<ReplayStop>>,
 }
 
 impl Log {
-	pub fn open(options: &Options) -> Result<Log> {
+	/// Open the log files of the database in `options.path`. Records are written in a way
+	/// that can be read back by releases that support format version `db_version`.
+	pub fn open(options: &Options, db_version: u32) -> Result<Log> {
 		let path = options.path.clone();
 		let mut logs = VecDeque::new();
 		let mut max_log_id = 0;
@@ -520,6 +934,10 @@ impl Log {
 		}
 		logs.make_contiguous().sort_by_key(|(_id, record_id, _)| *record_id);
 		let next_log_id = if logs.is_empty() { 0 } else { max_log_id + 1 };
+		let archive = ChangeArchive::open(options)?;
+		if let Some(path) = &options.archive_logs {
+			std::fs::create_dir_all(path)?;
+		}
 
 		Ok(Log {
 			overlays: Default::default(),
@@ -527,18 +945,41 @@ impl Log {
 			reading: RwLock::new(None),
 			reading_state: Mutex::new(ReadingState::Idle),
 			done_reading_cv: Condvar::new(),
+			flush_interrupted: AtomicBool::new(false),
 			flushing: Mutex::new(None),
 			next_record_id: AtomicU64::new(1),
 			next_log_id: AtomicU32::new(next_log_id),
 			dirty: AtomicBool::new(true),
 			sync: options.sync_wal,
+			sync_records: options.sync_mode == SyncMode::Always,
+			compress: options.compress_log && db_version >= COMPRESSED_LOG_VERSION,
+			framed: db_version >= FRAMED_LOG_VERSION,
+			archive_logs: options.archive_logs.clone(),
+			replay_stop: Mutex::new(None),
 			replay_queue: RwLock::new(logs),
 			cleanup_queue: RwLock::new(Default::default()),
 			log_pool: RwLock::new(Default::default()),
 			path,
+			archive,
 		})
 	}
 
+	/// Whether the database in `path` has any non-empty log files.
+	pub fn has_logs(path: &std::path::Path) -> Result<bool> {
+		for entry in std::fs::read_dir(path)? {
+			let entry = entry?;
+			let is_log = entry
+				.file_name()
+				.to_str()
+				.and_then(|name| name.strip_prefix("log"))
+				.is_some_and(|id| id.parse::<u32>().is_ok());
+			if is_log && entry.metadata()?.len() > 0 {
+				return Ok(true)
+			}
+		}
+		Ok(false)
+	}
+
 	fn log_path(root: &std::path::Path, id: u32) -> std::path::PathBuf {
 		let mut path: std::path::PathBuf = root.into();
 		path.push(format!("log{}", id));
@@ -590,6 +1031,7 @@ impl Log {
 		overlays.index.clear();
 		overlays.value.clear();
 		overlays.last_record_id.clear();
+		overlays.generation += 1;
 		*self.reading_state.lock() = ReadingState::Idle;
 		self.dirty.store(false, Ordering::Relaxed);
 		Ok(())
@@ -604,35 +1046,37 @@ impl Log {
 		assert!(log.record_id + 1 == self.next_record_id.load(Ordering::Relaxed));
 		let record_id = log.record_id;
 		let mut appending = self.appending.write();
-		if appending.is_none() {
-			// Find a log file in the pool or create a new one
-			let (id, file) = if let Some((id, file)) = self.log_pool.write().pop_front() {
-				log::debug!(target: "parity-db", "Flush: Activated pool writer {}", id);
-				(id, file)
-			} else {
-				// find a free id
-				let id = self.next_log_id.fetch_add(1, Ordering::SeqCst);
-				let path = Self::log_path(&self.path, id);
-				let file =
-					std::fs::OpenOptions::new().create(true).read(true).write(true).open(path)?;
-				log::debug!(target: "parity-db", "Flush: Activated new writer {}", id);
-				(id, file)
+		let appending = self.activate_writer(&mut appending)?;
+		appending.transaction = matches!(log.transaction, Some((_, false)));
+		crate::file::fail_point!(LogWrite, &Self::log_path(&self.path, appending.id), |action| {
+			use crate::file::fail::{self, FailAction, FailPoint};
+			let left = match action {
+				FailAction::Partial(len) => len,
+				FailAction::Error | FailAction::DiskFull => 0,
 			};
-			*appending = Some(Appending { size: 0, file: std::io::BufWriter::new(file), id });
+			log.flush_to_file(
+				&mut fail::PartialWriter { inner: &mut appending.file, left },
+				self.compress,
+				self.framed,
+			)
+			.and(Err(fail::action_error(FailPoint::LogWrite, action)))
+		});
+		let FlushedLog { index, values, bytes } =
+			log.flush_to_file(&mut appending.file, self.compress, self.framed)?;
+		if self.sync_records {
+			self.sync_log(appending.id, appending.file.get_ref())?;
 		}
-		let appending = appending.as_mut().unwrap();
-		let FlushedLog { index, values, bytes } = log.flush_to_file(&mut appending.file)?;
 		let mut overlays = self.overlays.write();
 		let mut total_index = 0;
 		for (id, overlay) in index.into_iter() {
 			total_index += overlay.map.len();
-			overlays.index.entry(id).or_default().map.extend(overlay.map.into_iter());
+			overlays.index.entry(id).or_default().map.extend(overlay.map);
 		}
 		let mut total_value = 0;
 		for (id, overlay) in values.into_iter() {
 			total_value += overlay.map.len();
 			overlays.last_record_id.insert(id.col(), record_id);
-			overlays.value.entry(id).or_default().map.extend(overlay.map.into_iter());
+			overlays.value.entry(id).or_default().map.extend(overlay.map);
 		}
 
 		log::debug!(
@@ -647,7 +1091,136 @@ impl Log {
 		Ok(bytes)
 	}
 
-	pub fn end_read(&self, cleared: Cleared, record_id: u64) {
+	// Find a log file in the pool or create a new one if no file is being appended to.
+	fn activate_writer<'a>(
+		&self,
+		appending: &'a mut Option<Appending>,
+	) -> Result<&'a mut Appending> {
+		if appending.is_none() {
+			// Find a log file in the pool or create a new one
+			let (id, file) = if let Some((id, file)) = self.log_pool.write().pop_front() {
+				log::debug!(target: "parity-db", "Flush: Activated pool writer {}", id);
+				(id, file)
+			} else {
+				// find a free id
+				let id = self.next_log_id.fetch_add(1, Ordering::SeqCst);
+				let path = Self::log_path(&self.path, id);
+				let file = std::fs::OpenOptions::new()
+					.create(true)
+					.truncate(false)
+					.read(true)
+					.write(true)
+					.open(path)?;
+				log::debug!(target: "parity-db", "Flush: Activated new writer {}", id);
+				(id, file)
+			};
+			*appending = Some(Appending {
+				size: 0,
+				file: std::io::BufWriter::new(file),
+				id,
+				transaction: false,
+			});
+		}
+		Ok(appending.as_mut().unwrap())
+	}
+
+	/// Append a record written by another database, for `Db::apply_log_record`. The record must
+	/// be the next one in this log. It is read back with `validate` and removed again if that
+	/// fails. Returns the record id and whether the record ends a commit.
+	pub fn append_record(
+		&self,
+		record: &[u8],
+		validate: impl FnOnce(&mut LogReader) -> Result<()>,
+	) -> Result<(u64, bool)> {
+		if record.len() < 9 || !matches!(record[0], BEGIN_RECORD | BEGIN_FRAMED_RECORD) {
+			return Err(Error::Corruption("Bad log record header".into()))
+		}
+		let record_id = u64::from_le_bytes(record[1..9].try_into().unwrap());
+		let mut appending = self.appending.write();
+		let expected = self.next_record_id.load(Ordering::Relaxed);
+		if record_id != expected {
+			return Err(Error::InvalidInput(format!(
+				"Expected log record {}, got {}",
+				expected, record_id
+			)))
+		}
+		let appending = self.activate_writer(&mut appending)?;
+		appending.file.flush()?;
+		let start = appending.file.get_mut().stream_position()?;
+		let end = start + record.len() as u64;
+		let check = |appending: &mut Appending| -> Result<Option<(u64, bool)>> {
+			appending.file.write_all(record)?;
+			appending.file.flush()?;
+			let mut file = appending.file.get_ref().try_clone()?;
+			file.seek(std::io::SeekFrom::Start(start))?;
+			let reading = RwLock::new(std::io::BufReader::new(file));
+			let mut reader = LogReader::new(
+				RwLockWriteGuard::map(reading.write(), |r| r),
+				appending.id,
+				true,
+				false,
+			)?;
+			if !matches!(reader.next()?, LogAction::BeginRecord) || reader.record_id() != record_id
+			{
+				return Err(Error::Corruption("Bad log record header".into()))
+			}
+			validate(&mut reader)?;
+			// Parts of a split commit must follow each other.
+			let continues = matches!(reader.transaction(), Some((id, _)) if id != record_id);
+			if continues != appending.transaction {
+				return Ok(None)
+			}
+			Ok(Some((record_id, !matches!(reader.transaction(), Some((_, false))))))
+		};
+		let result = check(appending);
+		let file = appending.file.get_mut();
+		match result {
+			Ok(Some((record_id, complete))) => {
+				file.seek(std::io::SeekFrom::Start(end))?;
+				if self.sync_records {
+					self.sync_log(appending.id, file)?;
+				}
+				appending.size += record.len() as u64;
+				appending.transaction = !complete;
+				self.next_record_id.store(record_id + 1, Ordering::Relaxed);
+				self.dirty.store(true, Ordering::Relaxed);
+				Ok((record_id, complete))
+			},
+			result => {
+				file.set_len(start)?;
+				file.seek(std::io::SeekFrom::Start(start))?;
+				result?;
+				Err(Error::InvalidInput(format!(
+					"Log record {} does not continue the split commit",
+					record_id
+				)))
+			},
+		}
+	}
+
+	/// Write the record being built by `writer` as a part of a split commit and continue with
+	/// the next part in a new record. All parts are kept in the same log file, so that none of
+	/// them is enacted before the last one is written. Returns the number of bytes written.
+	pub fn continue_record(&self, writer: &mut LogWriter) -> Result<u64> {
+		let record_id = writer.log.record_id;
+		let id = writer.log.transaction.map_or(record_id, |(id, _)| id);
+		let mut log = std::mem::replace(&mut writer.log, LogChange::new(record_id + 1));
+		log.transaction = Some((id, false));
+		let bytes = self.end_record(log)?;
+		let next = self.next_record_id.fetch_add(1, Ordering::Relaxed);
+		debug_assert_eq!(next, record_id + 1);
+		writer.log.transaction = Some((id, true));
+		Ok(bytes)
+	}
+
+	pub fn skip_record_ids(&self, record_id: u64) {
+		self.next_record_id.fetch_max(record_id + 1, Ordering::Relaxed);
+	}
+
+	pub fn end_read(&self, cleared: Cleared, record_id: u64) -> Result<()> {
+		if let Some(archive) = &self.archive {
+			archive.append(record_id, &cleared.record)?;
+		}
 		if record_id >= self.next_record_id.load(Ordering::Relaxed) {
 			self.next_record_id.store(record_id + 1, Ordering::Relaxed);
 		}
@@ -672,6 +1245,8 @@ impl Log {
 		}
 		// Cleanup index overlays
 		overlays.index.retain(|_, overlay| !overlay.map.is_empty());
+		overlays.generation += 1;
+		Ok(())
 	}
 
 	pub fn flush_one(&self, min_size: u64) -> Result<(bool, bool, bool)> {
@@ -683,6 +1258,9 @@ impl Log {
 			let mut reading_state = self.reading_state.lock();
 
 			while *reading_state == ReadingState::Reading {
+				if self.flush_interrupted.load(Ordering::Relaxed) {
+					return Ok((false, false, false))
+				}
 				log::debug!(target: "parity-db", "Flush: Awaiting log reader");
 				self.done_reading_cv.wait(&mut reading_state)
 			}
@@ -715,7 +1293,7 @@ impl Log {
 			let cur_size = self.appending.read().as_ref().map_or(0, |r| r.size);
 			if cur_size > 0 && cur_size > min_size {
 				let mut appending = self.appending.write();
-				let to_flush = appending.take();
+				let to_flush = appending.take_if(|a| !a.transaction);
 				*flushing = to_flush.map(|to_flush| Flushing {
 					file: to_flush.file.into_inner().unwrap(),
 					id: to_flush.id,
@@ -727,7 +1305,7 @@ impl Log {
 		if self.sync {
 			if let Some(flushing) = flushing.as_ref() {
 				log::debug!(target: "parity-db", "Flush: Flushing log to disk");
-				flushing.file.sync_data()?;
+				self.sync_log(flushing.id, &flushing.file)?;
 				log::debug!(target: "parity-db", "Flush: Flushing log completed");
 			}
 		}
@@ -735,6 +1313,95 @@ impl Log {
 		Ok((flushing.is_some(), read_next, cleanup))
 	}
 
+	/// Whether `flush_one` would wait for the log reader to finish the current log.
+	pub fn flush_waits_for_reader(&self) -> bool {
+		let flushing = self.flushing.lock();
+		flushing.is_some() && *self.reading_state.lock() == ReadingState::Reading
+	}
+
+	/// Make flushes that wait for the log reader return without flushing. Used on shutdown when
+	/// the reader is not going to finish the current log.
+	pub fn interrupt_flush(&self) {
+		let _reading_state = self.reading_state.lock();
+		self.flush_interrupted.store(true, Ordering::Relaxed);
+		self.done_reading_cv.notify_all();
+	}
+
+	/// fsync all log files that may contain records that were not cleaned up yet.
+	pub fn sync(&self) -> Result<()> {
+		if let Some(flushing) = self.flushing.lock().as_ref() {
+			self.sync_log(flushing.id, &flushing.file)?;
+		}
+		if let Some(appending) = self.appending.read().as_ref() {
+			self.sync_log(appending.id, appending.file.get_ref())?;
+		}
+		if let Some(reading) = self.reading.read().as_ref() {
+			self.sync_log(reading.id, reading.file.get_ref())?;
+		}
+		Ok(())
+	}
+
+	#[cfg_attr(not(any(test, feature = "fail-points")), allow(unused_variables))]
+	fn sync_log(&self, id: u32, file: &std::fs::File) -> Result<()> {
+		crate::file::fail_point!(LogSync, &Self::log_path(&self.path, id), |_action| {
+			Err(crate::file::fail::error(crate::file::fail::FailPoint::LogSync))
+		});
+		file.sync_data()?;
+		Ok(())
+	}
+
+	/// Read the logs queued for replay without activating them. `f` is called for each record
+	/// with the reader positioned after the record header and returns `false` to stop.
+	pub fn scan_replay_logs(
+		&self,
+		validate: bool,
+		mut f: impl FnMut(&mut LogReader) -> Result<bool>,
+	) -> Result<()> {
+		let queue = self.replay_queue.read();
+		for (id, _, file) in queue.iter() {
+			let reading = RwLock::new(std::io::BufReader::new(file.try_clone()?));
+			let mut done = false;
+			while !done {
+				let mut reader = LogReader::new(
+					RwLockWriteGuard::map(reading.write(), |r| r),
+					*id,
+					validate,
+					false,
+				)?;
+				match reader.next() {
+					Ok(LogAction::BeginRecord) => done = !f(&mut reader)?,
+					Ok(_) => {
+						log::debug!(target: "parity-db", "Scan: Bad log record structure in log {}", id);
+						done = true;
+					},
+					Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+					Err(e) => {
+						log::debug!(target: "parity-db", "Scan: Error reading log {}: {:?}", id, e);
+						done = true;
+					},
+				}
+			}
+			// The clone shares the file position.
+			reading.into_inner().into_inner().seek(std::io::SeekFrom::Start(0))?;
+			if done {
+				break
+			}
+		}
+		Ok(())
+	}
+
+	/// Move logs queued for replay that only contain records up to `record_id` to the cleanup
+	/// queue. Returns the first record to replay, if any.
+	pub fn skip_replay_logs(&self, record_id: u64) -> Option<u64> {
+		let mut queue = self.replay_queue.write();
+		while queue.len() > 1 && queue[1].1 <= record_id + 1 {
+			let (id, _, file) = queue.pop_front().unwrap();
+			log::debug!(target: "parity-db", "Replay: Skipped log {}", id);
+			self.cleanup_queue.write().push_back((id, file));
+		}
+		queue.front().map(|(_id, record_id, _)| *record_id)
+	}
+
 	pub fn replay_next(&mut self) -> Result<Option<u32>> {
 		let mut reading = self.reading.write();
 		{
@@ -756,7 +1423,20 @@ impl Log {
 	}
 
 	pub fn clean_logs(&self, count: usize) -> Result<bool> {
+		// Archived records must be on disk before the logs are gone.
+		if let Some(archive) = &self.archive {
+			archive.flush()?;
+		}
 		let mut cleaned: Vec<_> = { self.cleanup_queue.write().drain(0..count).collect() };
+		if let Some(path) = &self.archive_logs {
+			let mut kept = Vec::new();
+			for (id, file) in cleaned {
+				if let Some(file) = self.archive_log(path, id, file)? {
+					kept.push((id, file));
+				}
+			}
+			cleaned = kept;
+		}
 		for (id, ref mut file) in cleaned.iter_mut() {
 			log::debug!(target: "parity-db", "Cleaned: {}", id);
 			file.seek(std::io::SeekFrom::Start(0))?;
@@ -777,6 +1457,35 @@ impl Log {
 		Ok(!self.cleanup_queue.read().is_empty())
 	}
 
+	// Move an enacted log file to the log archive in `path`, named after its first record.
+	// Returns the file if it is empty and should be reused instead.
+	fn archive_log(
+		&self,
+		path: &std::path::Path,
+		id: u32,
+		mut file: std::fs::File,
+	) -> Result<Option<std::fs::File>> {
+		if file.metadata()?.len() == 0 {
+			return Ok(Some(file))
+		}
+		let mut buf = [0; 9];
+		file.seek(std::io::SeekFrom::Start(0))?;
+		file.read_exact(&mut buf)?;
+		file.sync_data()?;
+		std::mem::drop(file);
+		let first_record_id = u64::from_le_bytes(buf[1..].try_into().unwrap());
+		let from = Self::log_path(&self.path, id);
+		let to = path.join(format!("log{}", first_record_id));
+		log::debug!(target: "parity-db", "Archived log {} as {}", id, to.display());
+		if std::fs::rename(&from, &to).is_err() {
+			// Different file systems.
+			crate::file::copy_file(&from, &to)?;
+			std::fs::remove_file(&from)?;
+		}
+		crate::file::sync_dir(path)?;
+		Ok(None)
+	}
+
 	pub fn num_dirty_logs(&self) -> usize {
 		self.cleanup_queue.read().len()
 	}
@@ -793,25 +1502,82 @@ impl Log {
 			log::trace!(target: "parity-db", "No active reader");
 			return Ok(None)
 		}
+		#[cfg(any(test, feature = "fail-points"))]
+		let fail = crate::file::fail::check(
+			crate::file::fail::FailPoint::LogRead,
+			&Self::log_path(&self.path, reading.as_ref().unwrap().id),
+		);
+		let id = reading.as_ref().unwrap().id;
 		let reading = RwLockWriteGuard::map(reading, |r| &mut r.as_mut().unwrap().file);
-		let mut reader = LogReader::new(reading, validate);
+		let mut reader = LogReader::new(reading, id, validate, self.archive.is_some())?;
+		#[cfg(any(test, feature = "fail-points"))]
+		{
+			reader.fail = fail;
+		}
 		match reader.next() {
 			Ok(LogAction::BeginRecord) => Ok(Some(reader)),
-			Ok(_) => Err(Error::Corruption("Bad log record structure".into())),
+			Ok(_) => {
+				if validate {
+					self.stop_replay(ReplayStop::CorruptRecord {
+						log: id,
+						offset: reader.offset,
+						reason: "Bad log record structure".into(),
+					});
+				}
+				Err(Error::Corruption("Bad log record structure".into()))
+			},
+			// Part of a record is in the file.
+			Err(Error::Io(e))
+				if e.kind() == std::io::ErrorKind::UnexpectedEof &&
+					validate && reader.file.get_ref().metadata()?.len() > reader.offset =>
+			{
+				self.stop_replay(ReplayStop::TornRecord { log: id, offset: reader.offset });
+				Err(Error::Corruption("Torn log record".into()))
+			},
 			Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
 				*reading_state = ReadingState::Idle;
 				self.done_reading_cv.notify_one();
 				log::debug!(target: "parity-db", "Read: End of log");
 				Ok(None)
 			},
-			Err(e) => Err(e),
+			Err(e) => {
+				if validate {
+					self.stop_replay(ReplayStop::CorruptRecord {
+						log: id,
+						offset: reader.offset,
+						reason: e.to_string(),
+					});
+				}
+				Err(e)
+			},
 		}
 	}
 
+	/// Record why the replay stopped, unless it is already known.
+	pub fn stop_replay(&self, stop: ReplayStop) {
+		log::warn!(target: "parity-db", "Log replay stopped: {}", stop);
+		self.replay_stop.lock().get_or_insert(stop);
+	}
+
+	pub fn replay_stop(&self) -> Option<ReplayStop> {
+		self.replay_stop.lock().clone()
+	}
+
+	pub fn archive(&self) -> Option<&ChangeArchive> {
+		self.archive.as_ref()
+	}
+
 	pub fn overlays(&self) -> &RwLock<LogOverlays> {
 		&self.overlays
 	}
 
+	/// Capacity of the buffers of the log files being written and read.
+	pub fn buffer_bytes(&self) -> u64 {
+		let appending = self.appending.read().as_ref().map_or(0, |a| a.file.capacity());
+		let reading = self.reading.read().as_ref().map_or(0, |r| r.file.capacity());
+		(appending + reading) as u64
+	}
+
 	pub fn kill_logs(&self) -> Result<()> {
 		let mut log_pool = self.log_pool.write();
 		for (id, file) in log_pool.drain(..) {
@@ -0,0 +1,197 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Runtime metrics reporting.
+
+use crate::column::ColId;
+use std::time::Duration;
+
+/// Receiver for database metrics, set with `Options::metrics`. Methods are called from
+/// database worker threads and from readers, so implementations should be cheap.
+pub trait MetricsSink: Send + Sync {
+	/// A commit was written to the log. `latency` is measured from the `commit` call.
+	fn commit(&self, _latency: Duration) {}
+	/// Bytes written to the log but not yet enacted.
+	fn log_queue_bytes(&self, _bytes: i64) {}
+	/// Reindex of column `col` processed `done` of `total` index chunks.
+	fn reindex_progress(&self, _col: ColId, _done: u64, _total: u64) {}
+	/// A value was read from value table `tier` of column `col`.
+	fn tier_read(&self, _col: ColId, _tier: u8) {}
+	/// A lookup in the value cache of column `col` was served from the cache (`hit`) or had to
+	/// go to the tables. Only called for columns with `ColumnOptions::cache_size` set. Values
+	/// of commits that are not yet enacted are not looked up in the cache and not counted.
+	fn cache_lookup(&self, _col: ColId, _hit: bool) {}
+	/// `log_bytes` were written to the log for commits with `payload_bytes` of keys and values.
+	/// Keys of hash columns count as their 32 byte hash. Reindex and checkpoint records have no
+	/// payload.
+	fn log_written(&self, _payload_bytes: u64, _log_bytes: u64) {}
+	/// A log record was enacted, writing `bytes` to index and value table files. Index chunks
+	/// count in full.
+	fn tables_written(&self, _bytes: u64) {}
+}
+
+impl std::fmt::Debug for dyn MetricsSink {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "MetricsSink")
+	}
+}
+
+#[cfg(feature = "metrics")]
+pub use self::prometheus_sink::PrometheusMetrics;
+
+#[cfg(feature = "metrics")]
+mod prometheus_sink {
+	use super::MetricsSink;
+	use crate::column::ColId;
+	use prometheus::{
+		Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+	};
+	use std::time::Duration;
+
+	/// `MetricsSink` that exposes metrics through a prometheus registry.
+	pub struct PrometheusMetrics {
+		commit_latency: Histogram,
+		log_queue_bytes: IntGauge,
+		reindex_done: IntGaugeVec,
+		reindex_total: IntGaugeVec,
+		tier_reads: IntCounterVec,
+		cache_lookups: IntCounterVec,
+		payload_bytes: IntCounter,
+		log_bytes: IntCounter,
+		table_bytes: IntCounter,
+	}
+
+	impl PrometheusMetrics {
+		/// Create metrics and register them with `registry`.
+		pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+			let metrics = PrometheusMetrics {
+				commit_latency: Histogram::with_opts(HistogramOpts::new(
+					"parity_db_commit_latency_seconds",
+					"Time from commit to log write",
+				))?,
+				log_queue_bytes: IntGauge::new(
+					"parity_db_log_queue_bytes",
+					"Bytes logged but not yet enacted",
+				)?,
+				reindex_done: IntGaugeVec::new(
+					Opts::new("parity_db_reindex_done_chunks", "Reindexed index chunks"),
+					&["column"],
+				)?,
+				reindex_total: IntGaugeVec::new(
+					Opts::new("parity_db_reindex_total_chunks", "Index chunks to reindex"),
+					&["column"],
+				)?,
+				tier_reads: IntCounterVec::new(
+					Opts::new("parity_db_tier_reads", "Values read per size tier"),
+					&["column", "tier"],
+				)?,
+				cache_lookups: IntCounterVec::new(
+					Opts::new(
+						"parity_db_cache_lookups",
+						"Value cache lookups served from the cache or tables",
+					),
+					&["column", "result"],
+				)?,
+				payload_bytes: IntCounter::new(
+					"parity_db_payload_bytes",
+					"Bytes of keys and values committed",
+				)?,
+				log_bytes: IntCounter::new("parity_db_log_bytes", "Bytes written to the log")?,
+				table_bytes: IntCounter::new(
+					"parity_db_table_bytes",
+					"Bytes written to index and value tables",
+				)?,
+			};
+			registry.register(Box::new(metrics.commit_latency.clone()))?;
+			registry.register(Box::new(metrics.log_queue_bytes.clone()))?;
+			registry.register(Box::new(metrics.reindex_done.clone()))?;
+			registry.register(Box::new(metrics.reindex_total.clone()))?;
+			registry.register(Box::new(metrics.tier_reads.clone()))?;
+			registry.register(Box::new(metrics.cache_lookups.clone()))?;
+			registry.register(Box::new(metrics.payload_bytes.clone()))?;
+			registry.register(Box::new(metrics.log_bytes.clone()))?;
+			registry.register(Box::new(metrics.table_bytes.clone()))?;
+			Ok(metrics)
+		}
+	}
+
+	impl MetricsSink for PrometheusMetrics {
+		fn commit(&self, latency: Duration) {
+			self.commit_latency.observe(latency.as_secs_f64());
+		}
+
+		fn log_queue_bytes(&self, bytes: i64) {
+			self.log_queue_bytes.set(bytes);
+		}
+
+		fn reindex_progress(&self, col: ColId, done: u64, total: u64) {
+			let col = col.to_string();
+			self.reindex_done.with_label_values(&[&col]).set(done as i64);
+			self.reindex_total.with_label_values(&[&col]).set(total as i64);
+		}
+
+		fn tier_read(&self, col: ColId, tier: u8) {
+			self.tier_reads.with_label_values(&[&col.to_string(), &tier.to_string()]).inc();
+		}
+
+		fn cache_lookup(&self, col: ColId, hit: bool) {
+			let result = if hit { "hit" } else { "miss" };
+			self.cache_lookups.with_label_values(&[&col.to_string(), result]).inc();
+		}
+
+		fn log_written(&self, payload_bytes: u64, log_bytes: u64) {
+			self.payload_bytes.inc_by(payload_bytes);
+			self.log_bytes.inc_by(log_bytes);
+		}
+
+		fn tables_written(&self, bytes: u64) {
+			self.table_bytes.inc_by(bytes);
+		}
+	}
+
+	#[cfg(test)]
+	mod tests {
+		use super::*;
+
+		#[test]
+		fn prometheus_metrics() {
+			let registry = Registry::new();
+			let metrics = PrometheusMetrics::new(&registry).unwrap();
+			metrics.commit(Duration::from_millis(5));
+			metrics.tier_read(1, 3);
+			metrics.cache_lookup(1, true);
+			metrics.log_written(10, 30);
+			metrics.tables_written(20);
+			assert_eq!(metrics.log_bytes.get(), 30);
+			let families = registry.gather();
+			// Metric vectors without samples are not reported.
+			let names: Vec<_> = families.iter().map(|f| f.get_name()).collect();
+			assert_eq!(
+				names,
+				[
+					"parity_db_cache_lookups",
+					"parity_db_commit_latency_seconds",
+					"parity_db_log_bytes",
+					"parity_db_log_queue_bytes",
+					"parity_db_payload_bytes",
+					"parity_db_table_bytes",
+					"parity_db_tier_reads"
+				]
+			);
+			assert!(PrometheusMetrics::new(&registry).is_err());
+		}
+	}
+}
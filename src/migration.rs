@@ -17,7 +17,8 @@
 use crate::{
 	column::{ColId, IterState},
 	db::{CommitChangeSet, Db, IndexedChangeSet},
-	options::Options,
+	log::Log,
+	options::{ColumnOptions, Options, CURRENT_VERSION},
 	Error, Result,
 };
 /// Database migration.
@@ -25,8 +26,19 @@ use std::path::Path;
 
 const COMMIT_SIZE: usize = 10240;
 const OVERWRITE_TMP_PATH: &str = "to_revert_overwrite";
+const MIGRATE_COLUMN_TMP_PATH: &str = "to_revert_migrate_column";
+const UPGRADE_TMP_PATH: &str = "to_revert_upgrade";
+// Table files of this version and later are in the current format. Older databases are
+// rewritten by `upgrade_format`, newer ones only have the version in the metadata raised.
+// Version 6 changed hashing of uniform keys and version 7 the multipart value headers.
+const TABLE_FORMAT_VERSION: u32 = 7;
 
-pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u8]) -> Result<()> {
+pub fn migrate(
+	from: &Path,
+	mut to: Options,
+	overwrite: bool,
+	force_migrate: &[ColId],
+) -> Result<()> {
 	let source_meta = Options::load_metadata(from)?
 		.ok_or_else(|| Error::Migration("Error loading source metadata".into()))?;
 
@@ -45,9 +57,9 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u
 		return Err(Error::Migration("Changing salt need to update metadata at once.".into()))
 	}
 
-	let mut source_options = Options::with_columns(from, source_meta.columns.len() as u8);
+	let mut source_options = Options::with_columns(from, source_meta.columns.len() as ColId);
 	source_options.salt = Some(source_meta.salt);
-	source_options.columns = source_meta.columns;
+	source_options.columns = source_meta.columns.clone();
 
 	let mut source = Db::open(&source_options)?;
 	let mut dest = Db::open_or_create(&to)?;
@@ -128,6 +140,7 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u
 			source_options.columns[c as usize] = to.columns[c as usize].clone();
 			source_options
 				.write_metadata(from, &to.salt.expect("Migrate requires salt"))
+				.and_then(|_| source_meta.write_database_entries(from))
 				.map_err(|e| {
 					Error::Migration(format!(
 						"Error {:?}\nFail updating metadata of column {:?} \
@@ -146,6 +159,235 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &[u
 	Ok(())
 }
 
+/// Copy all entries of the RocksDB column family `column_family` at `source` into column
+/// `col` of `dest`. Entries go through the same raw commit batches as `Db::import`, and
+/// `progress` is called after each batch with the number of entries imported so far. Returns
+/// the total number of entries.
+#[cfg(feature = "rocksdb")]
+pub fn import_rocksdb(
+	source: &Path,
+	column_family: &str,
+	dest: &Db,
+	col: ColId,
+	progress: impl FnMut(u64),
+) -> Result<u64> {
+	let rocksdb_err = |e: rocksdb::Error| Error::Migration(format!("RocksDB error: {}", e));
+	if col >= dest.num_columns() {
+		return Err(Error::InvalidInput(format!("Column {} does not exist", col)))
+	}
+	let options = rocksdb::Options::default();
+	let source = rocksdb::DB::open_cf_for_read_only(&options, source, [column_family], false)
+		.map_err(rocksdb_err)?;
+	let cf = source.cf_handle(column_family).ok_or_else(|| {
+		Error::Migration(format!("Missing RocksDB column family {}", column_family))
+	})?;
+	let entries = source.iterator_cf(cf, rocksdb::IteratorMode::Start).map(|item| {
+		let (key, value) = item.map_err(rocksdb_err)?;
+		Ok((crate::db::ImportKey::Plain(key.into_vec()), 1, value.into_vec()))
+	});
+	let total = dest.import_entries(col, entries, progress)?;
+	log::info!("Imported {} entries from RocksDB column family {}", total, column_family);
+	Ok(total)
+}
+
+/// Rewrite column `col` of the database at `path` using `new_options`.
+///
+/// The column content is imported into a temporary database with the new options, then
+/// the column files are swapped in place and the metadata is updated. The database
+/// must not be open. Switching between hash and btree indexing is not supported, and neither
+/// is making a non-uniform column uniform, since original keys are not stored.
+pub fn migrate_column(path: &Path, col: ColId, new_options: ColumnOptions) -> Result<()> {
+	let meta = Options::load_metadata(path)?
+		.ok_or_else(|| Error::Migration("Error loading source metadata".into()))?;
+	let old_options = meta
+		.columns
+		.get(col as usize)
+		.cloned()
+		.ok_or_else(|| Error::Migration(format!("Column {} does not exist", col)))?;
+	if !new_options.is_valid() {
+		return Err(Error::Migration("Invalid column options".into()))
+	}
+	if old_options.btree_index != new_options.btree_index {
+		return Err(Error::Migration("Changing column index type is not supported".into()))
+	}
+	if old_options.store_keys != new_options.store_keys {
+		return Err(Error::Migration("Changing key storage is not supported".into()))
+	}
+	if old_options.multimap != new_options.multimap {
+		return Err(Error::Migration("Changing multimap columns is not supported".into()))
+	}
+	if old_options.counters != new_options.counters {
+		return Err(Error::Migration("Changing counter columns is not supported".into()))
+	}
+	if old_options.inline_values != new_options.inline_values {
+		return Err(Error::Migration("Changing inline values is not supported".into()))
+	}
+	if !old_options.uniform && new_options.uniform && !old_options.btree_index {
+		return Err(Error::Migration("Non-uniform column can't be made uniform".into()))
+	}
+	if old_options == new_options {
+		return Ok(())
+	}
+
+	let mut tmp_dir = path.to_path_buf();
+	tmp_dir.push(MIGRATE_COLUMN_TMP_PATH);
+	let mut old_dir = tmp_dir.clone();
+	old_dir.push("old");
+	let mut new_dir = tmp_dir.clone();
+	new_dir.push("new");
+	if old_dir.exists() {
+		return Err(Error::Migration(format!(
+			"Previous column migration was interrupted, restore column files from {:?} manually.",
+			old_dir
+		)))
+	}
+	if tmp_dir.exists() {
+		std::fs::remove_dir_all(&tmp_dir)?;
+	}
+	std::fs::create_dir_all(&old_dir)?;
+	std::fs::create_dir_all(&new_dir)?;
+
+	let mut source_options = Options::with_columns(path, meta.columns.len() as ColId);
+	source_options.salt = Some(meta.salt);
+	source_options.columns = meta.columns.clone();
+	let mut dest_options = source_options.clone();
+	dest_options.path = new_dir.clone();
+	dest_options.columns[col as usize] = new_options.clone();
+	// Keep the version, key hashing depends on it.
+	dest_options.write_metadata_with_version(&new_dir, &meta.salt, Some(meta.version))?;
+
+	log::info!("Migrating col {}", col);
+	let mut export_path = tmp_dir.clone();
+	export_path.push("export");
+	{
+		let source = Db::open(&source_options)?;
+		let file = std::fs::File::create(&export_path)?;
+		source.export(col, std::io::BufWriter::new(file))?;
+	}
+	{
+		let dest = Db::open(&dest_options)?;
+		let file = std::fs::File::open(&export_path)?;
+		let count = dest.import(col, std::io::BufReader::new(file))?;
+		log::info!("Imported {} entries into col {}", count, col);
+	}
+
+	move_column(col, path, &old_dir)?;
+	move_column(col, &new_dir, path)?;
+	source_options.columns[col as usize] = new_options;
+	let mut tmp_meta = tmp_dir.clone();
+	tmp_meta.push("metadata");
+	let mut meta_path = path.to_path_buf();
+	meta_path.push("metadata");
+	source_options
+		.write_metadata_file_with_version(&tmp_meta, &meta.salt, Some(meta.version))
+		.and_then(|_| meta.write_database_entries_file(&tmp_meta))
+		.and_then(|_| Ok(std::fs::rename(&tmp_meta, &meta_path)?))
+		.map_err(|e| {
+			Error::Migration(format!(
+				"Error {:?}\nFail updating metadata of column {:?}, \
+					please restore column files from {:?} manually before restarting.",
+				e, col, old_dir
+			))
+		})?;
+	std::fs::remove_dir_all(&tmp_dir)
+		.map_err(|e| Error::Migration(format!("Error removing migration tmp dir: {:?}", e)))?;
+	log::info!("Collection migrated {}", col);
+	Ok(())
+}
+
+/// Convert the database at `path` to `CURRENT_VERSION`, see `Db::needs_migration`.
+///
+/// The database must not be open. Its log is enacted first. If the table format changed since
+/// the version of the database, all columns are exported into a temporary database of the
+/// current version and the column files are swapped in place; otherwise only the metadata is
+/// updated. Returns the previous version.
+pub fn upgrade_format(path: &Path) -> Result<u32> {
+	let meta = Options::load_metadata(path)?
+		.ok_or_else(|| Error::Migration("Error loading source metadata".into()))?;
+	let version = meta.version;
+	if version == CURRENT_VERSION {
+		return Ok(version)
+	}
+	let num_columns = meta.columns.len() as ColId;
+	let mut options = Options::with_columns(path, num_columns);
+	options.salt = Some(meta.salt);
+	options.columns = meta.columns.clone();
+
+	let mut tmp_dir = path.to_path_buf();
+	tmp_dir.push(UPGRADE_TMP_PATH);
+	let mut old_dir = tmp_dir.clone();
+	old_dir.push("old");
+	let mut new_dir = tmp_dir.clone();
+	new_dir.push("new");
+	if old_dir.exists() {
+		return Err(Error::Migration(format!(
+			"Previous format upgrade was interrupted, restore column files from {:?} manually.",
+			old_dir
+		)))
+	}
+	if tmp_dir.exists() {
+		std::fs::remove_dir_all(&tmp_dir)?;
+	}
+
+	log::info!("Upgrading database from version {} to {}", version, CURRENT_VERSION);
+	if version < TABLE_FORMAT_VERSION {
+		std::fs::create_dir_all(&old_dir)?;
+		std::fs::create_dir_all(&new_dir)?;
+		let mut dest_options = options.clone();
+		dest_options.path = new_dir.clone();
+		let mut export_path = tmp_dir.clone();
+		export_path.push("export");
+		{
+			let source = Db::open(&options)?;
+			let dest = Db::open_or_create(&dest_options)?;
+			for col in 0..num_columns {
+				let file = std::fs::File::create(&export_path)?;
+				source.export(col, std::io::BufWriter::new(file))?;
+				let file = std::fs::File::open(&export_path)?;
+				let count = dest.import(col, std::io::BufReader::new(file))?;
+				log::info!("Upgraded col {}, {} entries", col, count);
+			}
+		}
+		if Log::has_logs(path)? {
+			return Err(Error::Migration("Database log was not enacted".into()))
+		}
+		for col in 0..num_columns {
+			move_column(col, path, &old_dir)?;
+			move_column(col, &new_dir, path)?;
+		}
+	} else {
+		// Tables are kept, but the log may be in the old format.
+		std::mem::drop(Db::open(&options)?);
+		if Log::has_logs(path)? {
+			return Err(Error::Migration("Database log was not enacted".into()))
+		}
+	}
+
+	// Opening the database may have assigned an id.
+	let meta = Options::load_metadata(path)?
+		.ok_or_else(|| Error::Migration("Error loading source metadata".into()))?;
+	let mut tmp_meta = tmp_dir.clone();
+	std::fs::create_dir_all(&tmp_meta)?;
+	tmp_meta.push("metadata");
+	let mut meta_path = path.to_path_buf();
+	meta_path.push("metadata");
+	options
+		.write_metadata_file_with_version(&tmp_meta, &meta.salt, None)
+		.and_then(|_| meta.write_database_entries_file(&tmp_meta))
+		.and_then(|_| Ok(std::fs::rename(&tmp_meta, &meta_path)?))
+		.map_err(|e| {
+			Error::Migration(format!(
+				"Error {:?}\nFail updating metadata to version {}, \
+					please restore column files from {:?} manually before restarting.",
+				e, CURRENT_VERSION, old_dir
+			))
+		})?;
+	std::fs::remove_dir_all(&tmp_dir)
+		.map_err(|e| Error::Migration(format!("Error removing upgrade tmp dir: {:?}", e)))?;
+	log::info!("Database upgraded to version {}", CURRENT_VERSION);
+	Ok(version)
+}
+
 fn move_column(c: ColId, from: &Path, to: &Path) -> Result<()> {
 	deplace_column(c, from, to, false)
 }
@@ -178,7 +420,10 @@ fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
 
 #[cfg(test)]
 mod test {
-	use crate::{migration::migrate, Db, Options};
+	use crate::{
+		migration::{migrate, migrate_column, upgrade_format},
+		ColumnOptions, CompressionType, Db, Options, CURRENT_VERSION,
+	};
 
 	struct TempDir(std::path::PathBuf);
 
@@ -228,4 +473,118 @@ mod test {
 		let dest = Db::with_columns(&dest_dir, 1).unwrap();
 		assert_eq!(dest.get(0, b"1").unwrap(), Some("value".as_bytes().to_vec()));
 	}
+
+	#[test]
+	fn migrate_column_options() {
+		let dir = TempDir::new("migrate_column_options");
+		let db_dir = dir.path("db");
+		let mut options = Options::with_columns(&db_dir, 2);
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit(
+				(0u32..1000).map(|i| (0, i.to_le_bytes().to_vec(), Some(vec![i as u8; 5000]))),
+			)
+			.unwrap();
+			db.commit([(1, b"1".to_vec(), Some(b"value".to_vec()))]).unwrap();
+			db.set_column_metadata(1, b"schema").unwrap();
+		}
+		let info = Db::open(&options).unwrap().info();
+
+		let new_options = ColumnOptions {
+			ref_counted: true,
+			compression: CompressionType::Lz4,
+			..Default::default()
+		};
+		let uniform = ColumnOptions { uniform: true, ..Default::default() };
+		assert!(migrate_column(&db_dir, 0, uniform).is_err());
+		assert!(migrate_column(&db_dir, 2, new_options.clone()).is_err());
+		migrate_column(&db_dir, 0, new_options.clone()).unwrap();
+
+		// Old options are rejected now.
+		assert!(Db::open(&options).is_err());
+		options.columns[0] = new_options;
+		let db = Db::open(&options).unwrap();
+		for i in 0u32..1000 {
+			assert_eq!(db.get(0, &i.to_le_bytes()).unwrap(), Some(vec![i as u8; 5000]));
+		}
+		assert_eq!(db.get(1, b"1").unwrap(), Some(b"value".to_vec()));
+		// Database identity and column metadata are kept.
+		assert_eq!(db.info(), info);
+		assert_eq!(db.get_column_metadata(1).unwrap(), Some(b"schema".to_vec()));
+		// Migrated values are reference counted.
+		let key = 7u32.to_le_bytes().to_vec();
+		db.commit([(0, key.clone(), Some(vec![7; 5000]))]).unwrap();
+		db.commit([(0, key.clone(), None)]).unwrap();
+		assert!(db.get(0, &key).unwrap().is_some());
+		db.commit([(0, key.clone(), None)]).unwrap();
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		assert!(db.get(0, &key).unwrap().is_none());
+		assert!(!dir.path("db").join("to_revert_migrate_column").exists());
+	}
+
+	#[test]
+	fn upgrade_format_versions() {
+		let dir = TempDir::new("upgrade_format_versions");
+		// Tables are rewritten for version 5 and kept for version 7.
+		for version in [5, 7] {
+			let db_dir = dir.path(&format!("v{}", version));
+			let salt = [7; 32];
+			let mut options = Options::with_columns(&db_dir, 2);
+			options.salt = Some(salt);
+			options.columns[0].uniform = true;
+			options.columns[1].btree_index = true;
+			std::fs::create_dir_all(&db_dir).unwrap();
+			options.write_metadata_with_version(&db_dir, &salt, Some(version)).unwrap();
+			{
+				let db = Db::open(&options).unwrap();
+				db.commit((0u8..100).map(|i| (0, vec![i; 32], Some(vec![i; 100 + i as usize]))))
+					.unwrap();
+				db.commit([(1, b"key".to_vec(), Some(b"value".to_vec()))]).unwrap();
+				db.set_column_metadata(1, b"schema").unwrap();
+			}
+			let info = Db::open(&options).unwrap().info();
+			assert_eq!(Db::format_version(&db_dir).unwrap(), Some(version));
+			assert!(Db::needs_migration(&db_dir).unwrap());
+
+			assert_eq!(upgrade_format(&db_dir).unwrap(), version);
+			assert_eq!(Db::format_version(&db_dir).unwrap(), Some(CURRENT_VERSION));
+			assert!(!Db::needs_migration(&db_dir).unwrap());
+			assert_eq!(upgrade_format(&db_dir).unwrap(), CURRENT_VERSION);
+			let db = Db::open(&options).unwrap();
+			assert_eq!(db.info().version, CURRENT_VERSION);
+			assert_eq!((db.info().id, db.info().created), (info.id, info.created));
+			for i in 0u8..100 {
+				assert_eq!(db.get(0, &[i; 32]).unwrap(), Some(vec![i; 100 + i as usize]));
+			}
+			assert_eq!(db.get(1, b"key").unwrap(), Some(b"value".to_vec()));
+			assert_eq!(db.get_column_metadata(1).unwrap(), Some(b"schema".to_vec()));
+			assert!(!db_dir.join("to_revert_upgrade").exists());
+		}
+		assert_eq!(Db::format_version(&dir.path("none")).unwrap(), None);
+	}
+
+	#[cfg(feature = "rocksdb")]
+	#[test]
+	fn import_rocksdb() {
+		let dir = TempDir::new("import_rocksdb");
+		let source_dir = dir.path("source");
+		let dest_dir = dir.path("dest");
+		{
+			let mut options = rocksdb::Options::default();
+			options.create_if_missing(true);
+			options.create_missing_column_families(true);
+			let source = rocksdb::DB::open_cf(&options, &source_dir, ["col1"]).unwrap();
+			let cf = source.cf_handle("col1").unwrap();
+			for i in 0u32..100 {
+				source.put_cf(cf, i.to_le_bytes(), i.to_be_bytes()).unwrap();
+			}
+		}
+
+		let dest = Db::with_columns(&dest_dir, 1).unwrap();
+		let mut reported = 0;
+		let total = super::import_rocksdb(&source_dir, "col1", &dest, 0, |n| reported = n).unwrap();
+		assert_eq!((total, reported), (100, 100));
+		assert_eq!(dest.get(0, &7u32.to_le_bytes()).unwrap(), Some(7u32.to_be_bytes().to_vec()));
+	}
 }
@@ -15,16 +15,92 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-	column::Salt,
+	column::{ColId, Salt},
 	compress::CompressionType,
 	error::{Error, Result},
+	file::StorageBackend,
+	index::CHUNK_ENTRIES,
+	metrics::MetricsSink,
 };
 use rand::Rng;
-use std::{collections::HashMap, io::Write, path::Path};
+use std::{
+	collections::{BTreeMap, HashMap},
+	io::Write,
+	path::Path,
+	sync::Arc,
+};
 
-pub const CURRENT_VERSION: u32 = 7;
+/// Format version of new databases. See `Db::needs_migration`.
+// Version 8 allows more than 256 columns, with wide table ids in the log, and adds checkpoint,
+// split commit, compressed and framed log records.
+pub const CURRENT_VERSION: u32 = 8;
+// Version that supports more than 256 columns.
+const WIDE_COLUMNS_VERSION: u32 = 8;
+// Version that supports index checkpoint records in the log.
+pub(crate) const CHECKPOINT_VERSION: u32 = 8;
+// Version that supports commits split into several log records.
+pub(crate) const SPLIT_COMMIT_VERSION: u32 = 8;
+// Version that supports compressed log records.
+pub(crate) const COMPRESSED_LOG_VERSION: u32 = 8;
+// Version that supports log records framed with their length.
+pub(crate) const FRAMED_LOG_VERSION: u32 = 8;
+/// Maximum number of columns in a database.
+pub const MAX_COLUMNS: usize = 4096;
+/// Oldest format version that can be opened.
 // TODO on last supported 5, remove MULTIHEAD_V4 and MULTIPART_V4
-const LAST_SUPPORTED_VERSION: u32 = 4;
+pub const LAST_SUPPORTED_VERSION: u32 = 4;
+// Metadata file key prefix for the application metadata of a column. Must not start with "col".
+const USER_METADATA_KEY: &str = "usermeta";
+
+/// Random version 4 UUID identifying a new database.
+pub(crate) fn new_database_id() -> String {
+	let mut id: [u8; 16] = rand::thread_rng().gen();
+	id[6] = (id[6] & 0x0f) | 0x40;
+	id[8] = (id[8] & 0x3f) | 0x80;
+	let id = hex::encode(id);
+	format!("{}-{}-{}-{}-{}", &id[0..8], &id[8..12], &id[12..16], &id[16..20], &id[20..32])
+}
+
+fn is_database_id(id: &str) -> bool {
+	id.len() == 36 &&
+		id.char_indices().all(|(i, c)| {
+			if matches!(i, 8 | 13 | 18 | 23) {
+				c == '-'
+			} else {
+				c.is_ascii_hexdigit()
+			}
+		})
+}
+
+// Rewrite the metadata file at `path` through a temporary file, replacing all lines with keys
+// matched by `replace` with `lines`.
+fn rewrite_metadata_file(
+	path: &Path,
+	replace: impl Fn(&str) -> bool,
+	lines: Vec<String>,
+) -> Result<()> {
+	let existing = std::fs::read_to_string(path)?;
+	let mut tmp_path = path.to_path_buf();
+	tmp_path.set_extension("tmp");
+	{
+		let mut file = std::fs::File::create(&tmp_path)?;
+		for l in existing.lines().filter(|l| !replace(l.split('=').next().unwrap_or_default())) {
+			writeln!(file, "{}", l)?;
+		}
+		for l in lines {
+			writeln!(file, "{}", l)?;
+		}
+		file.sync_all()?;
+	}
+	std::fs::rename(&tmp_path, path)?;
+	Ok(())
+}
+
+/// Byte order and pointer width of the platform the database was created on.
+fn platform_layout() -> String {
+	let endian = if cfg!(target_endian = "little") { "little" } else { "big" };
+	format!("{}-endian-{}", endian, usize::BITS)
+}
 
 /// Database configuration.
 #[derive(Clone, Debug)]
@@ -36,14 +112,326 @@ pub struct Options {
 	/// fsync WAL to disk before committing any changes. Provides extra consistency
 	/// guarantees. On by default.
 	pub sync_wal: bool,
+	/// When to fsync the log after commits are written to it.
+	pub sync_mode: SyncMode,
 	/// fsync/msync data to disk before removing logs. Provides crash resistance guarantee.
 	/// On by default.
 	pub sync_data: bool,
+	/// fsync the log and flush the column tables from a background thread at this interval,
+	/// so that the data on disk lags behind by at most this long even when commits are rare
+	/// and `Db::flush` is not called. Disabled if `None`.
+	pub sync_interval: Option<std::time::Duration>,
 	/// Collect database statistics. May have effect on performance.
 	pub stats: bool,
 	/// Override salt value. If `None` is specified salt is loaded from metadata
 	/// or randomly generated when creating a new database.
 	pub salt: Option<Salt>,
+	/// Compaction filters applied by `Db::compact`, per column.
+	pub compaction_filters: HashMap<ColId, Arc<dyn CompactionFilter>>,
+	/// Checks the metadata set with `Db::set_column_metadata` when an existing database is
+	/// opened. Opening fails if any column is rejected.
+	pub column_metadata_check: Option<Arc<dyn ColumnMetadataCheck>>,
+	/// Receiver for runtime metrics.
+	pub metrics: Option<Arc<dyn MetricsSink>>,
+	/// Keep enacted log records in this directory so that they can be read with
+	/// `Db::tail_changes`. Disabled if `None`.
+	pub change_archive: Option<std::path::PathBuf>,
+	/// Remove the oldest archived records once the archive grows beyond this many bytes.
+	/// Unlimited if `None`.
+	pub change_archive_max_bytes: Option<u64>,
+	/// Record every commit and every log record written by background work, such as reindex
+	/// and vacuum records, to this file, so that the database can be reproduced with
+	/// `Db::replay_trace`. Meant for debugging only: the trace is never pruned and values written
+	/// with `Db::put_stream` are buffered in memory. Disabled if `None`.
+	pub commit_trace: Option<std::path::PathBuf>,
+	/// Start a new log file once the current one grows beyond this many bytes. 64MB by
+	/// default.
+	pub max_log_size: u64,
+	/// Bound on the memory used by commits that are not enacted yet: the queue of commits to
+	/// be written to the log and the log overlay. 1/9 goes to the queue, the rest to the log
+	/// overlay. `commit` blocks while the queue is full, and with `WorkerThreads::Manual` does
+	/// background work itself until both fit. 144MB if `None`.
+	pub max_overlay_bytes: Option<u64>,
+	/// Commits that add data fail with `Error::QuotaExceeded` once the index and value table
+	/// files take more than this many bytes. No limit if `None`.
+	pub max_total_size: Option<u64>,
+	/// Commits that only remove values are still accepted until the table files exceed
+	/// `max_total_size` by this many bytes, so that space can be freed.
+	pub total_size_grace: u64,
+	/// Compress the entries of each log record with lz4 before they are written, which mostly
+	/// shrinks inserted values. Records that don't get smaller are written as is. Off by
+	/// default. Compressed records are read regardless of this option. Ignored for databases in
+	/// a format older than version 8, see `Db::needs_migration`.
+	pub compress_log: bool,
+	/// Number of enacted log files kept before they are cleaned up and reused. Only applies
+	/// when `sync_data` is off, otherwise log files are cleaned up as soon as the tables are
+	/// flushed. 16 by default.
+	pub log_rotation_count: usize,
+	/// Move log files to this directory once they are enacted, instead of reusing them. Each
+	/// archived file is named `log{id}` after its first record. Together with a backup made by
+	/// `Db::checkpoint` these allow restoring the database to any later record with
+	/// `Db::restore_to`. Archived files are never removed by the database.
+	pub archive_logs: Option<std::path::PathBuf>,
+	/// Number of log records enacted before the tables are flushed and the records are
+	/// reported as enacted to `Db::wait_enacted`, commit handles and commit hooks. A batch is
+	/// also completed when the end of the current log file is reached. If neither this nor
+	/// `enact_batch_bytes` is set, each record is reported as soon as it is enacted and the
+	/// tables are only flushed when log files are cleaned up.
+	pub enact_batch_records: Option<usize>,
+	/// Complete an enactment batch once it holds this many bytes of log records. See
+	/// `enact_batch_records`.
+	pub enact_batch_bytes: Option<u64>,
+	/// Number of threads used to write value tables when replaying the log on open. Records
+	/// are still validated and replayed in order; only writes within a record are spread over
+	/// the threads, by value table. 1 by default.
+	pub replay_threads: usize,
+	/// Open column table files on this many threads when the database is opened. This also
+	/// validates value table headers and extends files that are shorter than their filled
+	/// entries, so that the first accesses don't stall on it. `None` opens tables one at a
+	/// time without the extra checks.
+	pub preopen_threads: Option<usize>,
+	/// Size in bytes of an in-memory cache of index chunks, shared by all hash indexed columns.
+	/// Index lookups go through the memory map otherwise, which can be slow when the page cache
+	/// is small or the files are on network storage. 0 disables the cache.
+	pub index_cache_size: usize,
+	/// Ask the kernel to back index memory maps with transparent huge pages, reducing TLB
+	/// pressure for very large indexes. Linux only, and only effective where the file system
+	/// supports huge pages for file mappings.
+	pub index_huge_pages: bool,
+	/// Ask the kernel to read index files into memory in the background when they are opened,
+	/// instead of faulting chunks in on first access. Unix only.
+	pub index_prefetch: bool,
+	/// Write an index checkpoint to the log once this many bytes were logged since the last
+	/// one. A checkpoint holds the index chunks written since the previous checkpoint. On
+	/// replay the chunks are restored, records covered by the last checkpoint are not enacted
+	/// again and log files that only contain such records are skipped. Disabled if `None`, and
+	/// for databases in a format older than version 8, see `Db::needs_migration`.
+	pub checkpoint_interval: Option<u64>,
+	/// Bypass the page cache for value table files. Uses `O_DIRECT` on Linux, with reads and
+	/// writes done in whole aligned blocks, and `F_NOCACHE` on MacOS. Falls back to cached I/O
+	/// if the file system does not support it. Index tables are still memory mapped.
+	pub direct_io: bool,
+	/// Grow value table files by this many bytes at a time and allocate the space with
+	/// `fallocate` where supported. Large steps keep files contiguous under heavy inserts.
+	/// Files grow by 256KB at a time and are left sparse if `None`.
+	pub file_growth: Option<u64>,
+	/// Store value tables with a custom backend instead of files in the column directory,
+	/// e.g. `MemoryStorage`. `direct_io` and `ColumnOptions::mmap_tables` have no effect then.
+	/// Operations that copy table files are not supported: `Db::checkpoint`, `Db::copy_to`,
+	/// `Db::export_snapshot`, `Db::relocate` and `Db::restore_to`.
+	pub storage: Option<Arc<dyn StorageBackend>>,
+	/// Split commits with more changes than this into several log records. The records are
+	/// linked by a transaction id and enacted together, so the commit stays atomic. Btree
+	/// changes of a column are never split. Each commit is a single record if `None`, and for
+	/// databases in a format older than version 8, see `Db::needs_migration`.
+	pub commit_chunk_size: Option<usize>,
+	/// Write commits waiting in the queue as a single log record, as long as the record holds
+	/// at most this many bytes of commit data. Cuts the number of records, and of fsyncs with
+	/// `SyncMode::Always`, for workloads with many small commits. A record per commit if `None`.
+	pub coalesce_commit_bytes: Option<usize>,
+	/// Delay writing a record by up to this long after its first commit was queued, so that
+	/// more commits can join it. Only used with `coalesce_commit_bytes` and worker threads.
+	pub coalesce_commit_window: Option<std::time::Duration>,
+	/// Maximum number of index entries moved by a single reindex record.
+	pub reindex_batch: usize,
+	/// Time to wait between reindex records, so that growing a large index does not compete
+	/// with commits for the disk.
+	pub reindex_pause: Option<std::time::Duration>,
+	/// Limit reindex writes to about this many bytes per second. Measured in log record bytes,
+	/// which are written to the index again when enacted.
+	pub reindex_io_limit: Option<u64>,
+	/// Run background work on a thread for each worker, or leave it to the caller.
+	pub worker_threads: WorkerThreads,
+	/// Draw the order of background work in each `Db::tick`, when logs are flushed and the size
+	/// of reindex batches from a random generator seeded with this value. Background timers,
+	/// such as `reindex_pause` and `AutoVacuum::interval`, run on a logical clock that advances
+	/// by a millisecond with each tick. The same calls with the same seed then interleave the
+	/// same way on every run. Requires `WorkerThreads::Manual`. Meant for fuzzing and
+	/// differential tests.
+	pub deterministic_seed: Option<u64>,
+	/// Worker threads are named `<prefix>-<worker>`, e.g. `parity-db-log`.
+	pub worker_thread_prefix: String,
+	/// Pin worker threads to CPU cores. Only supported on Linux, for CPUs below `CPU_SETSIZE`.
+	pub worker_cpus: HashMap<Worker, usize>,
+	/// What `Db::get` and `Db::get_size` do when they find a damaged entry.
+	pub on_corruption: OnCorruption,
+	/// What log enactment does when writing a table fails because the disk is full.
+	pub on_disk_full: OnDiskFull,
+	/// Remove the database lock on open if the process that holds it no longer exists. See
+	/// `Db::break_lock`. Off by default.
+	pub force_unlock: bool,
+	/// Durations after which traced operations are reported as slow. Only used with the
+	/// `tracing` feature.
+	pub slow_ops: SlowOpThresholds,
+	/// Vacuum fragmented value tables in the background, as `Db::vacuum` does. Disabled if
+	/// `None`.
+	pub auto_vacuum: Option<AutoVacuum>,
+	/// Maximum number of removed entries read or rewritten by a single step of a vacuum, and so
+	/// written by a single vacuum record.
+	pub vacuum_batch: usize,
+}
+
+/// Report an operation with a `tracing` warning event when it takes at least this long. Not
+/// reported if `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlowOpThresholds {
+	/// Writing queued commits to the log.
+	pub commit: Option<std::time::Duration>,
+	/// Handing a written log file over to enactment.
+	pub log_flush: Option<std::time::Duration>,
+	/// Applying a log record to the tables.
+	pub enact: Option<std::time::Duration>,
+	/// Reading a value from a value table.
+	pub value_read: Option<std::time::Duration>,
+	/// Writing a reindex record for a column.
+	pub reindex: Option<std::time::Duration>,
+}
+
+/// Handling of damaged entries found by reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnCorruption {
+	/// Return the error.
+	Fail,
+	/// Read the entry as missing. The key is recorded in the `corruption_journal` file in the
+	/// database directory, once until it is written again or the database is reopened.
+	Skip,
+	/// Same as `Skip`. The key also keeps reading as missing without touching the damaged entry
+	/// until it is written again or the database is reopened.
+	Quarantine,
+}
+
+/// Handling of table writes that fail because the disk is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnDiskFull {
+	/// Stop background work with the error. The database has to be reopened.
+	Fail,
+	/// Pause log enactment and retry the record at this interval until it is written. Commits
+	/// are still accepted until the log queue is full. See `Db::health`.
+	Retry(std::time::Duration),
+}
+
+/// Schedule of background vacuuming. See `Db::vacuum`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoVacuum {
+	/// Time between checks of the value tables.
+	pub interval: std::time::Duration,
+	/// Vacuum tables where at least this fraction of the allocated entries is removed.
+	pub threshold: f64,
+	/// Hours of the day, in UTC, during which every table with removed entries is vacuumed
+	/// regardless of `threshold`. The range may wrap around midnight, e.g. `22..4`.
+	pub quiet_hours: Option<std::ops::Range<u8>>,
+	/// Maximum number of tables vacuumed per check, most fragmented first.
+	pub max_tables: usize,
+}
+
+impl Default for AutoVacuum {
+	fn default() -> Self {
+		AutoVacuum {
+			interval: std::time::Duration::from_secs(600),
+			threshold: 0.25,
+			quiet_hours: None,
+			max_tables: 4,
+		}
+	}
+}
+
+impl AutoVacuum {
+	/// Check if `hour` of the day is within `quiet_hours`.
+	pub fn is_quiet_hour(&self, hour: u8) -> bool {
+		match &self.quiet_hours {
+			Some(hours) if hours.start <= hours.end => hours.contains(&hour),
+			Some(hours) => hour >= hours.start || hour < hours.end,
+			None => false,
+		}
+	}
+}
+
+/// Background worker of a database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Worker {
+	/// Writes queued commits to the log. Also writes reindex records.
+	Log,
+	/// Moves written log files on to enactment.
+	Flush,
+	/// Applies log records to the tables.
+	Enact,
+	/// Recycles log files once their records are enacted.
+	Cleanup,
+	/// fsyncs the log with `SyncMode::Periodic` and the tables with `Options::sync_interval`.
+	Sync,
+}
+
+impl Worker {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Worker::Log => "log",
+			Worker::Flush => "flush",
+			Worker::Enact => "enact",
+			Worker::Cleanup => "cleanup",
+			Worker::Sync => "sync",
+		}
+	}
+}
+
+/// How background work is run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerThreads {
+	/// A dedicated thread for each worker.
+	Dedicated,
+	/// No threads are started. Background work is done by calling `Db::tick` periodically, and
+	/// by calls that wait for it, such as `Db::flush`. Commits are not throttled, since no one
+	/// would drain the queue.
+	Manual,
+}
+
+/// Log durability policy. Independent of `sync_wal`, which makes sure the log is on disk
+/// before it is applied to the tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+	/// fsync the log after each log record is written.
+	Always,
+	/// fsync the log periodically from a dedicated thread.
+	Periodic(std::time::Duration),
+	/// No extra fsyncs. The log is still fsynced before enactment when `sync_wal` is set, and
+	/// on `Db::flush`.
+	OnDemand,
+}
+
+/// Action to take for an entry visited by a `CompactionFilter`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+	/// Leave the entry as it is.
+	Keep,
+	/// Remove the entry. All references are dropped for reference counted columns.
+	Remove,
+	/// Replace the value. Not supported for reference counted columns.
+	Replace(Vec<u8>),
+}
+
+/// Application defined policy for dropping or rewriting column entries.
+pub trait CompactionFilter: Send + Sync {
+	/// Decide what to do with an entry. For hash columns that are not `uniform` and don't
+	/// `store_keys`, `key` is the hash of the original key.
+	fn filter(&self, key: &[u8], value: &[u8]) -> CompactionDecision;
+}
+
+impl std::fmt::Debug for dyn CompactionFilter {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "CompactionFilter")
+	}
+}
+
+/// Application defined check of the column metadata stored with `Db::set_column_metadata`.
+pub trait ColumnMetadataCheck: Send + Sync {
+	/// Return `false` to refuse opening the database. `metadata` is `None` if nothing was
+	/// stored for the column.
+	fn check(&self, col: ColId, metadata: Option<&[u8]>) -> bool;
+}
+
+impl std::fmt::Debug for dyn ColumnMetadataCheck {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "ColumnMetadataCheck")
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -62,8 +450,59 @@ pub struct ColumnOptions {
 	pub compression: CompressionType,
 	/// Minimal value size threshold to attempt compressing a value.
 	pub compression_threshold: u32,
+	/// Store values uncompressed unless compression saves at least this percentage of their
+	/// size. When recent values mostly did not compress that well, compression is only
+	/// attempted for a few values until they do again. 0 keeps any compressed value that is
+	/// smaller and always attempts compression. Not part of the column metadata.
+	pub compression_min_savings: u8,
 	/// Column is using a btree indexing.
 	pub btree_index: bool,
+	/// Store the original key along with the value in hash indexed columns. Keys can then
+	/// be listed with `Db::iter_column_keys_while` and are checked on lookup, so that keys
+	/// that only share the hashed part are told apart.
+	pub store_keys: bool,
+	/// Size in bytes of an in-memory LRU cache of values read from this column. The cache is
+	/// not part of the column metadata and can be changed between runs. 0 disables it.
+	pub cache_size: usize,
+	/// Size in bytes of a bloom filter of the keys in a hash indexed column. Lookups of keys
+	/// that the filter rules out return without touching the index. About 10 bits per key
+	/// give 1% false positives. The filter is saved on clean shutdown and rebuilt from the
+	/// index in the background otherwise. Not part of the column metadata. 0 disables it.
+	pub bloom_filter_bytes: usize,
+	/// Read value tables of this column through a memory map instead of `pread`. Writes still
+	/// go through the file. Ignored on platforms that can't resize mapped files, such as
+	/// Windows. Not part of the column metadata.
+	pub mmap_tables: bool,
+	/// Keep index and value tables of this column in a different directory, e.g. on another
+	/// disk. Not part of the column metadata, so the files have to be moved along with the
+	/// setting. Checkpoints and relocated copies hold all files in the database directory.
+	pub path_override: Option<std::path::PathBuf>,
+	/// Allow multiple values under the same key in a btree indexed column. Each value is
+	/// stored as a separate entry, so values under a key are unique and kept in byte order.
+	/// See `Db::get_values`.
+	pub multimap: bool,
+	/// Store the prefix shared by all keys of a btree node once per node. Saves space for
+	/// long structured keys. Part of the column metadata, existing columns can be converted
+	/// with `migrate_column`.
+	pub prefix_compression: bool,
+	/// Values are 8 byte little endian signed integers, changed with `Operation::Increment`.
+	/// A counter that reaches zero is removed. Requires a hash indexed column without
+	/// reference counting, preimage or stored keys. Part of the column metadata.
+	pub counters: bool,
+	/// Store values of up to a few bytes in the index entry instead of a value table slot,
+	/// saving a read on lookup. Between 2 and 6 bytes fit, depending on the index size. Index
+	/// entries only hold about 50 bits of the key, so a value is only stored there if its key
+	/// is the blake2b-256 hash of the value, which is checked on lookup. Other values are
+	/// stored in a value table. Requires a preimage column without reference counting. Part
+	/// of the column metadata.
+	pub inline_values: bool,
+	/// Number of entries in an index chunk of a hash indexed column: 64, 128, 256 or 512.
+	/// Keys are placed in chunks by the leading bits of their hash, and the index doubles in
+	/// size once any chunk is full, so columns with an uneven key distribution may grow their
+	/// index far earlier than its load factor suggests. Larger chunks take longer to search,
+	/// and each doubling leaves one bit less of the key in index entries. Part of the column
+	/// metadata, existing columns can be converted with `migrate_column`.
+	pub index_chunk_entries: usize,
 }
 
 /// Database metadata.
@@ -75,13 +514,30 @@ pub struct Metadata {
 	pub version: u32,
 	/// Column metadata.
 	pub columns: Vec<ColumnOptions>,
+	/// Application metadata set with `Db::set_column_metadata`, by column.
+	pub column_metadata: BTreeMap<ColId, Vec<u8>>,
+	/// Database UUID. Missing for databases created by older versions.
+	pub id: Option<String>,
+	/// Creation time in seconds since the unix epoch. Missing for databases created by older
+	/// versions.
+	pub created: Option<u64>,
 }
 
 impl ColumnOptions {
 	fn as_string(&self) -> String {
 		format!(
-			"preimage: {}, uniform: {}, refc: {}, compression: {}, ordered: {}",
-			self.preimage, self.uniform, self.ref_counted, self.compression as u8, self.btree_index,
+			"preimage: {}, uniform: {}, refc: {}, compression: {}, ordered: {}, storekeys: {}, multimap: {}, prefixcompression: {}, counters: {}, inline: {}, chunkentries: {}",
+			self.preimage,
+			self.uniform,
+			self.ref_counted,
+			self.compression as u8,
+			self.btree_index,
+			self.store_keys,
+			self.multimap,
+			self.prefix_compression,
+			self.counters,
+			self.inline_values,
+			self.index_chunk_entries,
 		)
 	}
 
@@ -90,6 +546,34 @@ impl ColumnOptions {
 			log::error!(target: "parity-db", "Using `preimage` option on an ordered column is not supported");
 			return false
 		}
+		if self.store_keys && (self.btree_index || self.preimage || self.uniform) {
+			log::error!(target: "parity-db", "Using `store_keys` option on an ordered, preimage or uniform column is not supported");
+			return false
+		}
+		if self.multimap && (!self.btree_index || self.ref_counted) {
+			log::error!(target: "parity-db", "Using `multimap` option requires an ordered column without reference counting");
+			return false
+		}
+		if self.prefix_compression && !self.btree_index {
+			log::error!(target: "parity-db", "Using `prefix_compression` option requires an ordered column");
+			return false
+		}
+		if self.counters &&
+			(self.btree_index || self.ref_counted || self.preimage || self.store_keys)
+		{
+			log::error!(target: "parity-db", "Using `counters` option on an ordered, reference counted, preimage or stored keys column is not supported");
+			return false
+		}
+		if self.inline_values && (self.btree_index || self.ref_counted || !self.preimage) {
+			log::error!(target: "parity-db", "Using `inline_values` option requires a hash indexed preimage column without reference counting");
+			return false
+		}
+		if !matches!(self.index_chunk_entries, 64 | 128 | 256 | 512) ||
+			(self.btree_index && self.index_chunk_entries != CHUNK_ENTRIES)
+		{
+			log::error!(target: "parity-db", "Index chunks of {} entries are not supported", self.index_chunk_entries);
+			return false
+		}
 		true
 	}
 
@@ -110,6 +594,14 @@ impl ColumnOptions {
 		let ref_counted = vals.get("refc")?.parse().ok()?;
 		let compression: u8 = vals.get("compression").and_then(|c| c.parse().ok()).unwrap_or(0);
 		let btree_index = vals.get("ordered").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let store_keys = vals.get("storekeys").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let multimap = vals.get("multimap").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let prefix_compression =
+			vals.get("prefixcompression").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let counters = vals.get("counters").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let inline_values = vals.get("inline").and_then(|c| c.parse().ok()).unwrap_or(false);
+		let index_chunk_entries =
+			vals.get("chunkentries").and_then(|c| c.parse().ok()).unwrap_or(CHUNK_ENTRIES);
 
 		Some(ColumnOptions {
 			preimage,
@@ -117,7 +609,18 @@ impl ColumnOptions {
 			ref_counted,
 			compression: compression.into(),
 			compression_threshold: ColumnOptions::default().compression_threshold,
+			compression_min_savings: 0,
 			btree_index,
+			store_keys,
+			cache_size: 0,
+			bloom_filter_bytes: 0,
+			mmap_tables: false,
+			path_override: None,
+			multimap,
+			prefix_compression,
+			counters,
+			inline_values,
+			index_chunk_entries,
 		})
 	}
 }
@@ -130,23 +633,84 @@ impl Default for ColumnOptions {
 			ref_counted: false,
 			compression: CompressionType::NoCompression,
 			compression_threshold: 4096,
+			compression_min_savings: 0,
 			btree_index: false,
+			store_keys: false,
+			cache_size: 0,
+			bloom_filter_bytes: 0,
+			mmap_tables: false,
+			path_override: None,
+			multimap: false,
+			prefix_compression: false,
+			counters: false,
+			inline_values: false,
+			index_chunk_entries: CHUNK_ENTRIES,
 		}
 	}
 }
 
 impl Options {
-	pub fn with_columns(path: &std::path::Path, num_columns: u8) -> Options {
+	pub fn with_columns(path: &std::path::Path, num_columns: ColId) -> Options {
 		Options {
 			path: path.into(),
 			sync_wal: true,
+			sync_mode: SyncMode::OnDemand,
 			sync_data: true,
+			sync_interval: None,
 			stats: true,
 			salt: None,
+			compaction_filters: HashMap::new(),
+			column_metadata_check: None,
+			metrics: None,
+			change_archive: None,
+			change_archive_max_bytes: None,
+			commit_trace: None,
+			max_log_size: 64 * 1024 * 1024,
+			max_overlay_bytes: None,
+			max_total_size: None,
+			total_size_grace: 16 * 1024 * 1024,
+			compress_log: false,
+			log_rotation_count: 16,
+			archive_logs: None,
+			enact_batch_records: None,
+			enact_batch_bytes: None,
+			replay_threads: 1,
+			preopen_threads: None,
+			index_cache_size: 0,
+			index_huge_pages: false,
+			index_prefetch: false,
+			checkpoint_interval: None,
+			direct_io: false,
+			file_growth: None,
+			storage: None,
+			commit_chunk_size: None,
+			coalesce_commit_bytes: None,
+			coalesce_commit_window: None,
+			reindex_batch: 8192,
+			reindex_pause: None,
+			reindex_io_limit: None,
+			worker_threads: WorkerThreads::Dedicated,
+			deterministic_seed: None,
+			worker_thread_prefix: "parity-db".into(),
+			worker_cpus: HashMap::new(),
+			on_corruption: OnCorruption::Fail,
+			on_disk_full: OnDiskFull::Fail,
+			force_unlock: false,
+			slow_ops: Default::default(),
+			auto_vacuum: None,
+			vacuum_batch: 8192,
 			columns: (0..num_columns).map(|_| Default::default()).collect(),
 		}
 	}
 
+	/// Directory holding the table files of a column.
+	pub fn column_path(&self, col: ColId) -> &Path {
+		self.columns
+			.get(col as usize)
+			.and_then(|c| c.path_override.as_deref())
+			.unwrap_or(&self.path)
+	}
+
 	// TODO on next major version remove in favor of write_metadata_with_version
 	pub fn write_metadata(&self, path: &std::path::Path, salt: &Salt) -> Result<()> {
 		let mut path = path.to_path_buf();
@@ -159,6 +723,7 @@ impl Options {
 		let mut file = std::fs::File::create(path)?;
 		writeln!(file, "version={}", CURRENT_VERSION)?;
 		writeln!(file, "salt={}", hex::encode(salt))?;
+		writeln!(file, "layout={}", platform_layout())?;
 		for i in 0..self.columns.len() {
 			writeln!(file, "col{}={}", i, self.columns[i].as_string())?;
 		}
@@ -186,21 +751,49 @@ impl Options {
 		let mut file = std::fs::File::create(path)?;
 		writeln!(file, "version={}", version)?;
 		writeln!(file, "salt={}", hex::encode(salt))?;
+		writeln!(file, "layout={}", platform_layout())?;
 		for i in 0..self.columns.len() {
 			writeln!(file, "col{}={}", i, self.columns[i].as_string())?;
 		}
 		Ok(())
 	}
 
+	/// Replace the application metadata of all columns in the metadata file at `path`.
+	pub fn write_column_metadata(
+		path: &Path,
+		column_metadata: &BTreeMap<ColId, Vec<u8>>,
+	) -> Result<()> {
+		let mut path = path.to_path_buf();
+		path.push("metadata");
+		Self::write_column_metadata_file(&path, column_metadata)
+	}
+
+	pub fn write_column_metadata_file(
+		path: &Path,
+		column_metadata: &BTreeMap<ColId, Vec<u8>>,
+	) -> Result<()> {
+		let lines = column_metadata
+			.iter()
+			.map(|(col, data)| format!("{}{}={}", USER_METADATA_KEY, col, hex::encode(data)))
+			.collect();
+		rewrite_metadata_file(path, |k| k.starts_with(USER_METADATA_KEY), lines)
+	}
+
 	pub fn load_and_validate_metadata(&self, create: bool) -> Result<Metadata> {
 		let meta = Self::load_metadata(&self.path)?;
 
-		if let Some(meta) = meta {
+		if let Some(mut meta) = meta {
 			if meta.columns.len() != self.columns.len() {
 				return Err(Error::InvalidConfiguration("Column config mismatch".into()))
 			}
 
 			for c in 0..meta.columns.len() {
+				// Not stored in the metadata.
+				meta.columns[c].cache_size = self.columns[c].cache_size;
+				meta.columns[c].bloom_filter_bytes = self.columns[c].bloom_filter_bytes;
+				meta.columns[c].mmap_tables = self.columns[c].mmap_tables;
+				meta.columns[c].compression_min_savings = self.columns[c].compression_min_savings;
+				meta.columns[c].path_override = self.columns[c].path_override.clone();
 				if meta.columns[c] != self.columns[c] {
 					return Err(Error::InvalidConfiguration(format!(
 						"Column config mismatch for column {}. Expected \"{}\", got \"{}\"",
@@ -210,11 +803,33 @@ impl Options {
 					)))
 				}
 			}
+			if let Some(check) = &self.column_metadata_check {
+				for c in 0..meta.columns.len() as ColId {
+					if !check.check(c, meta.column_metadata.get(&c).map(|m| m.as_slice())) {
+						return Err(Error::InvalidConfiguration(format!(
+							"Metadata of column {} was rejected",
+							c
+						)))
+					}
+				}
+			}
 			Ok(meta)
 		} else if create {
 			let s: Salt = self.salt.unwrap_or_else(|| rand::thread_rng().gen());
 			self.write_metadata(&self.path, &s)?;
-			Ok(Metadata { version: CURRENT_VERSION, columns: self.columns.clone(), salt: s })
+			let created = std::time::SystemTime::now()
+				.duration_since(std::time::UNIX_EPOCH)
+				.map_or(0, |d| d.as_secs());
+			let meta = Metadata {
+				version: CURRENT_VERSION,
+				columns: self.columns.clone(),
+				salt: s,
+				column_metadata: BTreeMap::new(),
+				id: Some(new_database_id()),
+				created: Some(created),
+			};
+			meta.write_database_entries(&self.path)?;
+			Ok(meta)
 		} else {
 			Err(Error::InvalidConfiguration(
 				"Database does not exist. To create a new one, use open_or_create".into(),
@@ -237,7 +852,11 @@ impl Options {
 		let file = std::io::BufReader::new(std::fs::File::open(path)?);
 		let mut salt = None;
 		let mut columns = Vec::new();
+		let mut column_metadata = BTreeMap::new();
+		let mut id = None;
+		let mut created = None;
 		let mut version = 0;
+		let mut layout = None;
 		for l in file.lines() {
 			let l = l?;
 			let mut vals = l.split('=');
@@ -252,23 +871,102 @@ impl Options {
 				let mut s = Salt::default();
 				s.copy_from_slice(&salt_slice);
 				salt = Some(s);
+			} else if k == "layout" {
+				layout = Some(v.to_owned());
+			} else if k == "id" {
+				if !is_database_id(v) {
+					return Err(Error::Corruption("Bad database id".into()))
+				}
+				id = Some(v.to_owned());
+			} else if k == "created" {
+				created = Some(
+					u64::from_str(v).map_err(|_| Error::Corruption("Bad creation time".into()))?,
+				);
+			} else if let Some(col) = k.strip_prefix(USER_METADATA_KEY) {
+				let col = ColId::from_str(col)
+					.map_err(|_| Error::Corruption("Bad column metadata".into()))?;
+				let data =
+					hex::decode(v).map_err(|_| Error::Corruption("Bad column metadata".into()))?;
+				column_metadata.insert(col, data);
 			} else if k.starts_with("col") {
 				let col = ColumnOptions::from_string(v)
 					.ok_or_else(|| Error::Corruption("Bad column metadata".into()))?;
 				columns.push(col);
 			}
 		}
-		if version < LAST_SUPPORTED_VERSION {
+		if !(LAST_SUPPORTED_VERSION..=CURRENT_VERSION).contains(&version) {
 			return Err(Error::InvalidConfiguration(format!(
 				"Unsupported database version {}. Expected {}",
 				version, CURRENT_VERSION
 			)))
 		}
+		// Databases created before the layout was recorded are accepted as is.
+		if let Some(layout) = layout.filter(|l| *l != platform_layout()) {
+			return Err(Error::InvalidConfiguration(format!(
+				"Database was created on a {} platform, this platform is {}. \
+				Use export and import to move the data",
+				layout,
+				platform_layout()
+			)))
+		}
+		if columns.len() > 256 && version < WIDE_COLUMNS_VERSION {
+			return Err(Error::Corruption(format!(
+				"Database version {} does not support {} columns",
+				version,
+				columns.len()
+			)))
+		}
 		let salt = salt.ok_or_else(|| Error::InvalidConfiguration("Missing salt value".into()))?;
-		Ok(Some(Metadata { version, columns, salt }))
+		Ok(Some(Metadata { version, columns, salt, column_metadata, id, created }))
 	}
 
 	pub fn is_valid(&self) -> bool {
+		if self.columns.len() > MAX_COLUMNS {
+			log::error!(target: "parity-db", "At most {} columns are supported", MAX_COLUMNS);
+			return false
+		}
+		if let Some(vacuum) = &self.auto_vacuum {
+			let hours_valid = vacuum
+				.quiet_hours
+				.as_ref()
+				.is_none_or(|hours| hours.start < 24 && hours.end <= 24);
+			if !(0.0..=1.0).contains(&vacuum.threshold) || vacuum.max_tables == 0 || !hours_valid {
+				log::error!(target: "parity-db", "Invalid auto vacuum settings {:?}", vacuum);
+				return false
+			}
+		}
+		if self.vacuum_batch == 0 {
+			log::error!(target: "parity-db", "Vacuum batch size must be positive");
+			return false
+		}
+		if self.commit_chunk_size == Some(0) {
+			log::error!(target: "parity-db", "Commit chunk size must be positive");
+			return false
+		}
+		if self.coalesce_commit_bytes == Some(0) ||
+			(self.coalesce_commit_window.is_some() && self.coalesce_commit_bytes.is_none())
+		{
+			log::error!(target: "parity-db", "Commit coalescing requires a positive byte limit");
+			return false
+		}
+		if self.max_overlay_bytes.is_some_and(|max| max < 9) {
+			log::error!(target: "parity-db", "Overlay limit is too small");
+			return false
+		}
+		if self.deterministic_seed.is_some() && self.worker_threads != WorkerThreads::Manual {
+			log::error!(target: "parity-db", "Deterministic scheduling requires manual workers");
+			return false
+		}
+		if self.reindex_batch == 0 || self.reindex_io_limit == Some(0) {
+			log::error!(target: "parity-db", "Reindex batch size and IO limit must be positive");
+			return false
+		}
+		#[cfg(target_os = "linux")]
+		if let Some(cpu) = self.worker_cpus.values().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize)
+		{
+			log::error!(target: "parity-db", "Can't pin a worker to CPU {}, the limit is {}", cpu, libc::CPU_SETSIZE);
+			return false
+		}
 		for option in self.columns.iter() {
 			if !option.is_valid() {
 				return false
@@ -279,7 +977,75 @@ impl Options {
 }
 
 impl Metadata {
-	pub fn columns_to_migrate(&self) -> std::collections::BTreeSet<u8> {
+	pub fn columns_to_migrate(&self) -> std::collections::BTreeSet<ColId> {
 		std::collections::BTreeSet::new()
 	}
+
+	/// Write the entries that are not derived from `Options` to the metadata file in `path`:
+	/// database id, creation time and column metadata. Used to keep them when the metadata is
+	/// rewritten with `Options::write_metadata`.
+	pub fn write_database_entries(&self, path: &Path) -> Result<()> {
+		let mut path = path.to_path_buf();
+		path.push("metadata");
+		self.write_database_entries_file(&path)
+	}
+
+	pub fn write_database_entries_file(&self, path: &Path) -> Result<()> {
+		let mut lines = Vec::new();
+		if let Some(id) = &self.id {
+			lines.push(format!("id={}", id));
+		}
+		if let Some(created) = self.created {
+			lines.push(format!("created={}", created));
+		}
+		lines.extend(
+			self.column_metadata
+				.iter()
+				.map(|(col, data)| format!("{}{}={}", USER_METADATA_KEY, col, hex::encode(data))),
+		);
+		rewrite_metadata_file(
+			path,
+			|k| k == "id" || k == "created" || k.starts_with(USER_METADATA_KEY),
+			lines,
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Options, Salt};
+
+	#[test]
+	fn layout_mismatch_is_rejected() {
+		let dir = tempfile::tempdir().unwrap();
+		let options = Options::with_columns(dir.path(), 1);
+		options.write_metadata(dir.path(), &Salt::default()).unwrap();
+		assert!(Options::load_metadata(dir.path()).unwrap().is_some());
+
+		let path = dir.path().join("metadata");
+		let metadata = std::fs::read_to_string(&path).unwrap();
+		let foreign =
+			if cfg!(target_endian = "little") { "big-endian-64" } else { "little-endian-64" };
+		let tampered: String = metadata
+			.lines()
+			.map(|l| {
+				if l.starts_with("layout=") {
+					format!("layout={}\n", foreign)
+				} else {
+					format!("{}\n", l)
+				}
+			})
+			.collect();
+		std::fs::write(&path, tampered).unwrap();
+		assert!(Options::load_metadata(dir.path()).is_err());
+
+		// Metadata without a layout line is still accepted.
+		let legacy: String = metadata
+			.lines()
+			.filter(|l| !l.starts_with("layout="))
+			.map(|l| format!("{}\n", l))
+			.collect();
+		std::fs::write(&path, legacy).unwrap();
+		assert!(Options::load_metadata(dir.path()).unwrap().is_some());
+	}
 }
@@ -0,0 +1,198 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Database snapshot stream, see `Db::export_snapshot`.
+//!
+//! The stream holds the database files one after another, so it can be written and read
+//! without seeking. All integers are little endian.
+//!
+//! Header:
+//! [MAGIC: 4][VERSION: 1]
+//!
+//! Followed by a sequence of files:
+//! [NAME_LEN: 2][NAME][SIZE: 8][DATA]
+//!
+//! The files are followed by the manifest, which starts with a zero `NAME_LEN`:
+//! [0: 2][COUNT: 4], then for each file in stream order [NAME_LEN: 2][NAME][SIZE: 8][CRC32: 4],
+//! and finally the CRC32 of the manifest from `COUNT` on.
+
+use crate::error::{Error, Result};
+use std::{
+	io::{Read, Write},
+	path::Path,
+};
+
+const MAGIC: &[u8; 4] = b"PDBS";
+const VERSION: u8 = 1;
+const BUFFER_SIZE: usize = 64 * 1024;
+// Files are unpacked under a temporary name until the manifest is checked.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+struct FileInfo {
+	name: String,
+	size: u64,
+	checksum: u32,
+}
+
+pub struct SnapshotWriter<W: Write> {
+	writer: W,
+	files: Vec<FileInfo>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+	pub fn new(mut writer: W) -> Result<Self> {
+		writer.write_all(MAGIC)?;
+		writer.write_all(&[VERSION])?;
+		Ok(SnapshotWriter { writer, files: Vec::new() })
+	}
+
+	/// Add the file at `path` under `name`. The file must not change meanwhile.
+	pub fn write_file(&mut self, name: &str, path: &Path) -> Result<()> {
+		let file = std::fs::File::open(path)?;
+		let size = file.metadata()?.len();
+		self.write(name, size, file)
+	}
+
+	/// Add a file with content `data`.
+	pub fn write_data(&mut self, name: &str, data: &[u8]) -> Result<()> {
+		self.write(name, data.len() as u64, data)
+	}
+
+	fn write(&mut self, name: &str, size: u64, data: impl Read) -> Result<()> {
+		let name_len = u16::try_from(name.len())
+			.ok()
+			.filter(|len| *len > 0)
+			.ok_or_else(|| Error::InvalidInput(format!("Bad snapshot file name {}", name)))?;
+		self.writer.write_all(&name_len.to_le_bytes())?;
+		self.writer.write_all(name.as_bytes())?;
+		self.writer.write_all(&size.to_le_bytes())?;
+		let checksum = copy_exact(data, &mut self.writer, size)?;
+		self.files.push(FileInfo { name: name.into(), size, checksum });
+		Ok(())
+	}
+
+	/// Write the manifest. Returns the number of files in the snapshot.
+	pub fn finish(mut self) -> Result<usize> {
+		let mut manifest = Vec::new();
+		manifest.extend_from_slice(&(self.files.len() as u32).to_le_bytes());
+		for file in &self.files {
+			manifest.extend_from_slice(&(file.name.len() as u16).to_le_bytes());
+			manifest.extend_from_slice(file.name.as_bytes());
+			manifest.extend_from_slice(&file.size.to_le_bytes());
+			manifest.extend_from_slice(&file.checksum.to_le_bytes());
+		}
+		self.writer.write_all(&0u16.to_le_bytes())?;
+		self.writer.write_all(&manifest)?;
+		self.writer.write_all(&crc32fast::hash(&manifest).to_le_bytes())?;
+		self.writer.flush()?;
+		Ok(self.files.len())
+	}
+}
+
+// Copy exactly `size` bytes. Returns their checksum.
+fn copy_exact(data: impl Read, writer: &mut impl Write, size: u64) -> Result<u32> {
+	let mut data = data.take(size);
+	let mut hasher = crc32fast::Hasher::new();
+	let mut buf = vec![0u8; BUFFER_SIZE];
+	let mut copied = 0;
+	while copied < size {
+		let read = data.read(&mut buf)?;
+		if read == 0 {
+			return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+		}
+		hasher.update(&buf[..read]);
+		writer.write_all(&buf[..read])?;
+		copied += read as u64;
+	}
+	Ok(hasher.finalize())
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+	let mut buf = [0u8; N];
+	reader.read_exact(&mut buf)?;
+	Ok(buf)
+}
+
+fn read_name(reader: &mut impl Read, len: u16) -> Result<String> {
+	let mut name = vec![0u8; len as usize];
+	reader.read_exact(&mut name)?;
+	let name =
+		String::from_utf8(name).map_err(|_| Error::Corruption("Bad snapshot file name".into()))?;
+	// Only plain file names, so that files can't be written outside of the target directory.
+	if Path::new(&name).file_name().and_then(|n| n.to_str()) != Some(name.as_str()) {
+		return Err(Error::Corruption(format!("Bad snapshot file name {}", name)))
+	}
+	Ok(name)
+}
+
+/// Write the files of a snapshot stream to `dir`. Files are moved in place once the whole
+/// stream has been read and checked against the manifest.
+pub fn unpack(mut reader: impl Read, dir: &Path) -> Result<()> {
+	let header: [u8; 5] = read_array(&mut reader)?;
+	if &header[0..4] != MAGIC {
+		return Err(Error::Corruption("Bad snapshot header".into()))
+	}
+	if header[4] != VERSION {
+		return Err(Error::Corruption(format!("Unsupported snapshot version {}", header[4])))
+	}
+	let mut files = Vec::new();
+	loop {
+		let name_len = u16::from_le_bytes(read_array(&mut reader)?);
+		if name_len == 0 {
+			break
+		}
+		let name = read_name(&mut reader, name_len)?;
+		let size = u64::from_le_bytes(read_array(&mut reader)?);
+		let mut file = std::fs::File::create(dir.join(format!("{}{}", name, PARTIAL_SUFFIX)))?;
+		let checksum = copy_exact(&mut reader, &mut file, size)?;
+		file.sync_all()?;
+		files.push(FileInfo { name, size, checksum });
+	}
+
+	let mut hasher = crc32fast::Hasher::new();
+	let count: [u8; 4] = read_array(&mut reader)?;
+	hasher.update(&count);
+	if u32::from_le_bytes(count) as usize != files.len() {
+		return Err(Error::Corruption("Snapshot manifest does not match the files".into()))
+	}
+	for file in &files {
+		let name_len: [u8; 2] = read_array(&mut reader)?;
+		let name = read_name(&mut reader, u16::from_le_bytes(name_len))?;
+		let size: [u8; 8] = read_array(&mut reader)?;
+		let checksum: [u8; 4] = read_array(&mut reader)?;
+		for part in [&name_len[..], name.as_bytes(), &size, &checksum] {
+			hasher.update(part);
+		}
+		if name != file.name ||
+			u64::from_le_bytes(size) != file.size ||
+			u32::from_le_bytes(checksum) != file.checksum
+		{
+			return Err(Error::Corruption(format!("Snapshot file {} does not match", name)))
+		}
+	}
+	let checksum: [u8; 4] = read_array(&mut reader)?;
+	if u32::from_le_bytes(checksum) != hasher.finalize() {
+		return Err(Error::Corruption("Bad snapshot manifest checksum".into()))
+	}
+	// In stream order, so that the last file is only in place once all others are.
+	for file in &files {
+		std::fs::rename(
+			dir.join(format!("{}{}", file.name, PARTIAL_SUFFIX)),
+			dir.join(&file.name),
+		)?;
+	}
+	Ok(())
+}
@@ -15,12 +15,9 @@
 // along with Parity.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{column::ColId, error::Result, table::SIZE_TIERS};
+use std::io::{Cursor, Read, Write};
 /// Database statistics.
 use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering};
-use std::{
-	io::{Cursor, Read, Write},
-	mem::MaybeUninit,
-};
 
 // store up to value of size HISTOGRAM_BUCKETS * 2 ^ HISTOGRAM_BUCKET_BITS,
 // that is 32ko
@@ -28,7 +25,83 @@ const HISTOGRAM_BUCKETS: usize = 1024;
 const HISTOGRAM_BUCKET_BITS: u8 = 5;
 
 pub const TOTAL_SIZE: usize =
-	4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 11;
+	4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 14;
+
+/// Number of values in a column by size, see `Db::value_size_histogram`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValueSizeHistogram {
+	/// Size range of each bucket, in bytes.
+	pub bucket_size: u32,
+	/// Number of values with a size in `[i * bucket_size, (i + 1) * bucket_size)`, up to the
+	/// last non-empty bucket.
+	pub buckets: Vec<u64>,
+	/// Number of values larger than the last possible bucket.
+	pub oversized: u64,
+}
+
+/// Totals of values passed to the compressor of a column, see `Db::compression_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+	/// Number of values large enough to be compressed.
+	pub values: u64,
+	/// Size of these values before compression.
+	pub raw_bytes: u64,
+	/// Size of these values as written. Values that did not get smaller are written as is.
+	pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+	/// Stored size relative to the raw size, 1.0 if nothing was compressed.
+	pub fn ratio(&self) -> f64 {
+		if self.raw_bytes == 0 {
+			1.0
+		} else {
+			self.stored_bytes as f64 / self.raw_bytes as f64
+		}
+	}
+}
+
+/// Occupancy of the index of a hash column, see `Db::index_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexStats {
+	/// Bits of the current index table. The table has `2^index_bits` chunks of 64 entries.
+	pub index_bits: u8,
+	/// Entries in the current index table.
+	pub entries: u64,
+	/// Most entries found in a single chunk.
+	pub max_chunk_entries: u64,
+	/// Chunks without free entries. Inserting a key that maps to one of them starts a reindex.
+	pub full_chunks: u64,
+	/// Number of chunks by entry count, `chunk_histogram[n]` chunks hold `n` entries.
+	pub chunk_histogram: Vec<u64>,
+	/// Entries still in older tables that are being reindexed. Lookups check these tables
+	/// after the current one.
+	pub reindex_entries: u64,
+}
+
+impl IndexStats {
+	/// Number of chunks in the current index table.
+	pub fn chunks(&self) -> u64 {
+		self.chunk_histogram.iter().sum()
+	}
+
+	/// Average number of entries per chunk.
+	pub fn average_chunk_entries(&self) -> f64 {
+		match self.chunks() {
+			0 => 0.0,
+			chunks => self.entries as f64 / chunks as f64,
+		}
+	}
+
+	/// Share of the entries of the current index table that are used.
+	pub fn load_factor(&self) -> f64 {
+		match self.chunks() {
+			0 => 0.0,
+			chunks =>
+				self.entries as f64 / (chunks * (self.chunk_histogram.len() as u64 - 1)) as f64,
+		}
+	}
+}
 
 // TODO: get rid of the struct and use index meta directly.
 pub struct ColumnStats {
@@ -46,6 +119,10 @@ pub struct ColumnStats {
 	queries_miss: AtomicU64,
 	uncompressed_bytes: AtomicU64,
 	compression_delta: [AtomicI64; HISTOGRAM_BUCKETS],
+	// Write path totals, including values that were later replaced or removed.
+	compressed_values: AtomicU64,
+	compression_raw_bytes: AtomicU64,
+	compression_stored_bytes: AtomicU64,
 }
 
 fn read_u32(cursor: &mut Cursor<&[u8]>) -> AtomicU32 {
@@ -94,20 +171,13 @@ fn value_histogram_index(size: u32) -> Option<usize> {
 }
 
 impl ColumnStats {
-	#[allow(clippy::uninit_assumed_init)]
 	pub fn from_slice(data: &[u8]) -> ColumnStats {
 		let mut cursor = Cursor::new(data);
-		let mut value_histogram: [AtomicU32; HISTOGRAM_BUCKETS] =
-			unsafe { MaybeUninit::uninit().assume_init() };
-		for item in value_histogram.iter_mut() {
-			*item = read_u32(&mut cursor);
-		}
-		let mut query_histogram: [AtomicU64; SIZE_TIERS] =
-			unsafe { MaybeUninit::uninit().assume_init() };
-		for item in query_histogram.iter_mut() {
-			*item = read_u64(&mut cursor);
-		}
-		let mut stats = ColumnStats {
+		let value_histogram: [AtomicU32; HISTOGRAM_BUCKETS] =
+			std::array::from_fn(|_| read_u32(&mut cursor));
+		let query_histogram: [AtomicU64; SIZE_TIERS] =
+			std::array::from_fn(|_| read_u64(&mut cursor));
+		ColumnStats {
 			value_histogram,
 			query_histogram,
 			oversized: read_u64(&mut cursor),
@@ -121,19 +191,17 @@ impl ColumnStats {
 			removed_miss: read_u64(&mut cursor),
 			queries_miss: read_u64(&mut cursor),
 			uncompressed_bytes: read_u64(&mut cursor),
-			compression_delta: unsafe { MaybeUninit::uninit().assume_init() },
-		};
-		for item in stats.compression_delta.iter_mut() {
-			*item = read_i64(&mut cursor);
+			compression_delta: std::array::from_fn(|_| read_i64(&mut cursor)),
+			compressed_values: read_u64(&mut cursor),
+			compression_raw_bytes: read_u64(&mut cursor),
+			compression_stored_bytes: read_u64(&mut cursor),
 		}
-		stats
 	}
 
 	pub fn empty() -> ColumnStats {
 		let value_histogram: [AtomicU32; HISTOGRAM_BUCKETS] =
-			unsafe { std::mem::transmute([0u32; HISTOGRAM_BUCKETS]) };
-		let query_histogram: [AtomicU64; SIZE_TIERS] =
-			unsafe { std::mem::transmute([0u64; SIZE_TIERS]) };
+			std::array::from_fn(|_| AtomicU32::new(0));
+		let query_histogram: [AtomicU64; SIZE_TIERS] = std::array::from_fn(|_| AtomicU64::new(0));
 		ColumnStats {
 			value_histogram,
 			query_histogram,
@@ -148,7 +216,10 @@ impl ColumnStats {
 			removed_miss: Default::default(),
 			queries_miss: Default::default(),
 			uncompressed_bytes: Default::default(),
-			compression_delta: unsafe { std::mem::transmute([0i64; HISTOGRAM_BUCKETS]) },
+			compression_delta: std::array::from_fn(|_| AtomicI64::new(0)),
+			compressed_values: Default::default(),
+			compression_raw_bytes: Default::default(),
+			compression_stored_bytes: Default::default(),
 		}
 	}
 
@@ -174,6 +245,9 @@ impl ColumnStats {
 		for item in &self.compression_delta {
 			write_i64(&mut cursor, item);
 		}
+		write_u64(&mut cursor, &self.compressed_values);
+		write_u64(&mut cursor, &self.compression_raw_bytes);
+		write_u64(&mut cursor, &self.compression_stored_bytes);
 	}
 
 	fn write_stats(&self, writer: &mut impl std::io::Write, col: ColId) -> Result<()> {
@@ -200,6 +274,15 @@ impl ColumnStats {
 			"Uncompressed bytes: {}",
 			self.uncompressed_bytes.load(Ordering::Relaxed)
 		)?;
+		let compression = self.compression();
+		writeln!(writer, "Compressed values: {}", compression.values)?;
+		writeln!(
+			writer,
+			"Compression: {} raw bytes, {} stored bytes, ratio {:.3}",
+			compression.raw_bytes,
+			compression.stored_bytes,
+			compression.ratio(),
+		)?;
 		writeln!(writer, "Compression deltas:")?;
 		for i in 0..HISTOGRAM_BUCKETS {
 			let count = self.value_histogram[i].load(Ordering::Relaxed);
@@ -303,4 +386,37 @@ impl ColumnStats {
 	pub fn commit(&self) {
 		self.commits.fetch_add(1, Ordering::Relaxed);
 	}
+
+	// No value was ever written.
+	pub fn is_empty(&self) -> bool {
+		self.inserted_new.load(Ordering::Relaxed) == 0 &&
+			self.inserted_overwrite.load(Ordering::Relaxed) == 0
+	}
+
+	pub fn compressed(&self, raw: u32, stored: u32) {
+		self.compressed_values.fetch_add(1, Ordering::Relaxed);
+		self.compression_raw_bytes.fetch_add(raw as u64, Ordering::Relaxed);
+		self.compression_stored_bytes.fetch_add(stored as u64, Ordering::Relaxed);
+	}
+
+	pub fn value_histogram(&self) -> ValueSizeHistogram {
+		let mut buckets: Vec<u64> =
+			self.value_histogram.iter().map(|c| c.load(Ordering::Relaxed) as u64).collect();
+		while buckets.last() == Some(&0) {
+			buckets.pop();
+		}
+		ValueSizeHistogram {
+			bucket_size: 1 << HISTOGRAM_BUCKET_BITS,
+			buckets,
+			oversized: self.oversized.load(Ordering::Relaxed),
+		}
+	}
+
+	pub fn compression(&self) -> CompressionStats {
+		CompressionStats {
+			values: self.compressed_values.load(Ordering::Relaxed),
+			raw_bytes: self.compression_raw_bytes.load(Ordering::Relaxed),
+			stored_bytes: self.compression_stored_bytes.load(Ordering::Relaxed),
+		}
+	}
 }
@@ -61,13 +61,13 @@ use crate::{
 	column::ColId,
 	display::hex,
 	error::Result,
+	file::{FileOptions, ReadRequest, Residency},
 	log::{LogQuery, LogReader, LogWriter},
 	options::ColumnOptions as Options,
 	table::key::{TableKey, TableKeyQuery, PARTIAL_SIZE},
 };
 use std::{
 	convert::TryInto,
-	io::Read,
 	mem::MaybeUninit,
 	sync::{
 		atomic::{AtomicBool, AtomicU64, Ordering},
@@ -84,6 +84,10 @@ const REFS_SIZE: usize = 4;
 const SIZE_SIZE: usize = 2;
 const INDEX_SIZE: usize = 8;
 const MAX_ENTRY_BUF_SIZE: usize = 0x8000;
+// Bytes read at once when iterating over table slots.
+pub const READ_BATCH_BYTES: u64 = 64 * 1024;
+// Number of slots prefetched ahead of a multipart value chain that continues in the next slot.
+const READ_AHEAD_PARTS: u64 = 8;
 
 const TOMBSTONE: &[u8] = &[0xff, 0xff];
 const MULTIPART_V4: &[u8] = &[0xff, 0xfe];
@@ -97,14 +101,14 @@ const LOCKED_REF: u32 = u32::MAX;
 pub type Value = Vec<u8>;
 
 #[derive(Clone, Copy, Eq, PartialEq, Hash)]
-pub struct TableId(u16);
+pub struct TableId(u32);
 
 impl TableId {
 	pub fn new(col: ColId, size_tier: u8) -> TableId {
-		TableId(((col as u16) << 8) | size_tier as u16)
+		TableId(((col as u32) << 8) | size_tier as u32)
 	}
 
-	pub fn from_u16(id: u16) -> TableId {
+	pub fn from_u32(id: u32) -> TableId {
 		TableId(id)
 	}
 
@@ -124,7 +128,7 @@ impl TableId {
 		name.starts_with(&format!("table_{:02}_", col))
 	}
 
-	pub fn as_u16(&self) -> u16 {
+	pub fn as_u32(&self) -> u32 {
 		self.0
 	}
 }
@@ -135,13 +139,196 @@ impl std::fmt::Display for TableId {
 	}
 }
 
+// Position in `FreeListVacuum::list` that is no entry.
+const NO_ENTRY: u32 = u32::MAX;
+// Next position of entries in `FreeListVacuum::list` that were rewritten already.
+const REWRITTEN: u32 = u32::MAX - 1;
+
+/// Removed entries rewritten by a single vacuum record, see `FreeListVacuum::plan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VacuumStep {
+	/// Removed entries and the removed entry each of them is followed by, 0 for none.
+	pub links: Vec<(u64, u64)>,
+	/// First removed entry of the list.
+	pub last_removed: u64,
+	/// Number of filled entries, which drops the removed entries at the end of the table.
+	pub filled: u64,
+	/// The list of removed entries is in ascending order after this step.
+	pub complete: bool,
+}
+
+/// Vacuum of the list of removed entries of a value table, in steps that each rewrite a
+/// bounded number of entries. The list is read first, without writing anything. The entries
+/// are then moved to a sorted tail of the list, starting with the highest one, so that every
+/// step leaves a valid list. Removed entries at the end of the table are dropped instead. The
+/// vacuum must be abandoned once the table's `free_list_version` changes. Memory use is about
+/// 20 bytes per removed entry.
+pub struct FreeListVacuum {
+	version: u64,
+	filled: u64,
+	// Next entry of the list to read, 0 once the whole list is read.
+	cursor: u64,
+	// Removed entries in list order.
+	list: Vec<u64>,
+	// Neighbours in the list of the entries that are not rewritten yet, as positions in `list`.
+	prev: Vec<u32>,
+	next: Vec<u32>,
+	// Positions in `list` by descending entry.
+	order: Vec<u32>,
+	// Number of entries in `order` that were rewritten.
+	done: usize,
+	// First and last entry that is not rewritten yet.
+	first: u32,
+	last: u32,
+	// First of the rewritten entries, which follow the other entries in the list.
+	sorted: u64,
+	// All rewritten entries were dropped from the end of the table so far.
+	truncating: bool,
+}
+
+impl FreeListVacuum {
+	/// `ValueTable::free_list_version` when the vacuum was started.
+	pub fn version(&self) -> u64 {
+		self.version
+	}
+
+	/// Check if the whole list was read.
+	pub fn is_read(&self) -> bool {
+		self.cursor == 0
+	}
+
+	/// Number of removed entries read so far.
+	pub fn free_entries(&self) -> u64 {
+		self.list.len() as u64
+	}
+
+	/// Number of filled entries when the vacuum was started.
+	pub fn filled_entries(&self) -> u64 {
+		self.filled
+	}
+
+	/// Read up to `max_entries` more entries of the list of removed entries of `table`.
+	/// Returns `true` once the whole list is read. Errors are only meaningful if the table's
+	/// `free_list_version` did not change meanwhile.
+	pub fn read(
+		&mut self,
+		table: &ValueTable,
+		max_entries: usize,
+		log: &impl LogQuery,
+	) -> Result<bool> {
+		let mut read = 0;
+		while self.cursor != 0 && read < max_entries {
+			if self.cursor >= self.filled ||
+				self.list.len() as u64 >= self.filled ||
+				self.list.len() >= REWRITTEN as usize
+			{
+				return Err(crate::error::Error::Corruption(format!(
+					"{}: bad removed entry list at {}, {} filled entries",
+					table.id, self.cursor, self.filled
+				)))
+			}
+			self.list.push(self.cursor);
+			self.cursor = table.read_next_free(self.cursor, log)?;
+			read += 1;
+		}
+		if self.cursor != 0 {
+			return Ok(false)
+		}
+		if self.order.len() != self.list.len() {
+			let len = self.list.len() as u32;
+			self.prev = (0..len).map(|i| if i == 0 { NO_ENTRY } else { i - 1 }).collect();
+			self.next = (0..len).map(|i| if i + 1 == len { NO_ENTRY } else { i + 1 }).collect();
+			self.order = (0..len).collect();
+			let list = &self.list;
+			self.order.sort_unstable_by(|a, b| list[*b as usize].cmp(&list[*a as usize]));
+			self.first = if len == 0 { NO_ENTRY } else { 0 };
+			self.last = if len == 0 { NO_ENTRY } else { len - 1 };
+		}
+		Ok(true)
+	}
+
+	/// Check if rewriting the list would change anything, once it is read. It does unless the
+	/// list is in ascending order and does not hold the last filled entry.
+	pub fn is_needed(&self) -> bool {
+		!(self.list.windows(2).all(|w| w[0] < w[1]) && self.list.last() != Some(&(self.filled - 1)))
+	}
+
+	/// Plan the next step, rewriting up to `max_entries` removed entries. Returns `None` once
+	/// the list is in order. The step must be written with `ValueTable::write_vacuum_step`.
+	pub fn plan(&mut self, max_entries: usize) -> Option<VacuumStep> {
+		if !self.is_read() || self.done == self.order.len() {
+			return None
+		}
+		let end = (self.done + max_entries.max(1)).min(self.order.len());
+		let mut links = Vec::new();
+		// Entries that are not rewritten and are followed by another entry now.
+		let mut relinked = Vec::new();
+		for &i in &self.order[self.done..end] {
+			let i = i as usize;
+			let (prev, next) = (self.prev[i], self.next[i]);
+			if prev == NO_ENTRY {
+				self.first = next;
+			} else {
+				self.next[prev as usize] = next;
+				relinked.push(prev);
+			}
+			if next == NO_ENTRY {
+				self.last = prev;
+			} else {
+				self.prev[next as usize] = prev;
+			}
+			self.next[i] = REWRITTEN;
+			let entry = self.list[i];
+			if self.truncating && entry + 1 == self.filled {
+				self.filled -= 1;
+			} else {
+				self.truncating = false;
+				links.push((entry, self.sorted));
+				self.sorted = entry;
+			}
+		}
+		self.done = end;
+		if self.last != NO_ENTRY {
+			relinked.push(self.last);
+		}
+		relinked.sort_unstable();
+		relinked.dedup();
+		for i in relinked {
+			let next = match self.next[i as usize] {
+				REWRITTEN => continue,
+				NO_ENTRY => self.sorted,
+				next => self.list[next as usize],
+			};
+			links.push((self.list[i as usize], next));
+		}
+		let last_removed =
+			if self.first == NO_ENTRY { self.sorted } else { self.list[self.first as usize] };
+		Some(VacuumStep {
+			links,
+			last_removed,
+			filled: self.filled,
+			complete: self.done == self.order.len(),
+		})
+	}
+}
+
 pub struct ValueTable {
 	pub id: TableId,
 	pub entry_size: u16,
 	file: crate::file::TableFile,
 	filled: AtomicU64,
 	last_removed: AtomicU64,
+	// Number of entries in the list of removed entries, `u64::MAX` until it is counted.
+	free_entries: AtomicU64,
+	// The list of removed entries is in ascending order and does not hold the last entry, so
+	// there is nothing to vacuum.
+	vacuumed: AtomicBool,
+	// Bumped whenever the list of removed entries or the number of filled entries changes,
+	// except by vacuum steps.
+	free_list_version: AtomicU64,
 	dirty_header: AtomicBool,
+	// Bumped before parts of existing multipart values are overwritten or freed.
+	rewritten: AtomicU64,
 	multipart: bool,
 	ref_counted: bool,
 	db_version: u32,
@@ -165,6 +352,15 @@ impl Header {
 	}
 }
 
+// Fill parts with consecutive slices of `value`.
+fn slice_filler(value: &[u8]) -> impl FnMut(&mut [u8]) + '_ {
+	let mut offset = 0;
+	move |part: &mut [u8]| {
+		part.copy_from_slice(&value[offset..offset + part.len()]);
+		offset += part.len();
+	}
+}
+
 pub struct Entry<B: AsRef<[u8]> + AsMut<[u8]>>(usize, B);
 pub type FullEntry = Entry<[u8; MAX_ENTRY_BUF_SIZE]>;
 type PartialEntry = Entry<[u8; 10]>;
@@ -351,6 +547,7 @@ impl ValueTable {
 		entry_size: Option<u16>,
 		options: &Options,
 		db_version: u32,
+		file_options: FileOptions,
 	) -> Result<ValueTable> {
 		let (multipart, entry_size) = match entry_size {
 			Some(s) => (false, s),
@@ -363,12 +560,12 @@ impl ValueTable {
 
 		let mut filepath: std::path::PathBuf = std::path::PathBuf::clone(&*path);
 		filepath.push(id.file_name());
-		let file = crate::file::TableFile::open(filepath, entry_size, id)?;
+		let file = crate::file::TableFile::open(filepath, entry_size, id, file_options)?;
 		let mut filled = 1;
 		let mut last_removed = 0;
-		if let Some(file) = &mut *file.file.write() {
+		if file.exists() {
 			let mut header = Header::default();
-			file.read_exact(&mut header.0)?;
+			file.read_at(&mut header.0, 0)?;
 			last_removed = header.last_removed();
 			filled = header.filled();
 			if filled == 0 {
@@ -383,13 +580,30 @@ impl ValueTable {
 			file,
 			filled: AtomicU64::new(filled),
 			last_removed: AtomicU64::new(last_removed),
+			free_entries: AtomicU64::new(if last_removed == 0 { 0 } else { u64::MAX }),
+			vacuumed: AtomicBool::new(last_removed == 0),
+			free_list_version: AtomicU64::new(0),
 			dirty_header: AtomicBool::new(false),
+			rewritten: AtomicU64::new(0),
 			multipart,
 			ref_counted: options.ref_counted,
 			db_version,
 		})
 	}
 
+	/// Validate the header against the file and extend the file to hold all filled entries.
+	pub fn preopen(&self) -> Result<()> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let last_removed = self.last_removed.load(Ordering::Relaxed);
+		if last_removed >= filled {
+			return Err(crate::error::Error::Corruption(format!(
+				"{}: last removed entry {} is out of {} filled entries",
+				self.id, last_removed, filled
+			)))
+		}
+		self.file.reserve(filled, self.entry_size)
+	}
+
 	pub fn value_size(&self, key: &TableKey) -> Option<u16> {
 		let base = self.entry_size - SIZE_SIZE as u16 - self.ref_size() as u16;
 		let k_encoded = key.encoded_size() as u16;
@@ -403,20 +617,51 @@ impl ValueTable {
 	// Return ref counter, partial key and if the value is compressed.
 	#[inline(always)]
 	fn for_parts(
+		&self,
+		key: &mut TableKeyQuery,
+		index: u64,
+		log: &impl LogQuery,
+		f: impl FnMut(&[u8]) -> bool,
+	) -> Result<(u32, bool)> {
+		self.for_parts_prefetched(key, index, None, log, f)
+	}
+
+	// Same as `for_parts`, with the file content of the first slot already read.
+	fn for_parts_prefetched(
+		&self,
+		key: &mut TableKeyQuery,
+		index: u64,
+		prefetched: Option<&[u8]>,
+		log: &impl LogQuery,
+		f: impl FnMut(&[u8]) -> bool,
+	) -> Result<(u32, bool)> {
+		let (rc, compressed, _next) = self.walk_parts(key, index, 0, prefetched, log, f)?;
+		Ok((rc, compressed))
+	}
+
+	// Same as `for_parts_prefetched`, starting with part number `part` of a multipart entry at
+	// `index`. The key is only checked for the first part. Also returns the index of the next
+	// part when `f` stops early, 0 if there are no more parts.
+	fn walk_parts(
 		&self,
 		key: &mut TableKeyQuery,
 		mut index: u64,
+		mut part: usize,
+		mut prefetched: Option<&[u8]>,
 		log: &impl LogQuery,
 		mut f: impl FnMut(&[u8]) -> bool,
-	) -> Result<(u32, bool)> {
+	) -> Result<(u32, bool, u64)> {
 		let mut buf = FullEntry::new_uninit();
-		let mut part = 0;
 		let mut compressed = false;
 		let mut rc = 1;
+		let mut read_ahead = 0;
 		let entry_size = self.entry_size as usize;
 		loop {
 			let buf = if log.value(self.id, index, buf.as_mut()) {
 				&mut buf
+			} else if let Some(data) = prefetched.take() {
+				buf[0..entry_size].copy_from_slice(data);
+				&mut buf
 			} else {
 				log::trace!(
 					target: "parity-db",
@@ -431,7 +676,7 @@ impl ValueTable {
 			buf.set_offset(0);
 
 			if buf.is_tombstone() {
-				return Ok((0, false))
+				return Ok((0, false, 0))
 			}
 
 			let (entry_end, next) = if self.multipart && buf.is_multi(self.db_version) {
@@ -470,22 +715,31 @@ impl ValueTable {
 								to_fetch,
 								self.entry_size,
 							);
-							return Ok((0, false))
+							return Ok((0, false, 0))
 						}
 					},
 				}
 			}
 			if !f(buf.remaining_to(entry_end)) {
-				break
+				return Ok((rc, compressed, next))
 			};
 
 			if next == 0 {
 				break
 			}
+			// Chains allocated from the end of the table are contiguous, read the next few
+			// parts ahead.
+			if next == index + 1 && next >= read_ahead {
+				read_ahead = next + READ_AHEAD_PARTS;
+				self.file.prefetch(
+					next * self.entry_size as u64,
+					READ_AHEAD_PARTS * self.entry_size as u64,
+				);
+			}
 			part += 1;
 			index = next;
 		}
-		Ok((rc, compressed))
+		Ok((rc, compressed, 0))
 	}
 
 	pub fn get(
@@ -510,31 +764,126 @@ impl ValueTable {
 		log: &impl LogQuery,
 	) -> Result<Option<(Value, bool, u32)>> {
 		let mut result = Vec::new();
-		let (rc, compressed) = self.for_parts(key, index, log, |buf| {
+		Ok(self
+			.query_into(key, index, log, &mut result)?
+			.map(|(compressed, rc)| (result, compressed, rc)))
+	}
+
+	/// Same as `query`, appending the value to `result`. `result` is left as it was if there is
+	/// no such entry.
+	pub fn query_into(
+		&self,
+		key: &mut TableKeyQuery,
+		index: u64,
+		log: &impl LogQuery,
+		result: &mut Vec<u8>,
+	) -> Result<Option<(bool, u32)>> {
+		let found = self.query_parts(key, index, 0, true, log, result)?;
+		Ok(found.map(|(compressed, rc, _next)| (compressed, rc)))
+	}
+
+	/// Append parts of the entry at `index` to `result`, starting with part number `part`. Only
+	/// one part is read unless `all` is set. Returns the compression flag, the reference
+	/// counter and the index of the next part, 0 if the last part was read. The flags are only
+	/// read from the first part. `result` is left as it was if there is no such entry.
+	pub fn query_parts(
+		&self,
+		key: &mut TableKeyQuery,
+		index: u64,
+		part: usize,
+		all: bool,
+		log: &impl LogQuery,
+		result: &mut Vec<u8>,
+	) -> Result<Option<(bool, u32, u64)>> {
+		let start = result.len();
+		let (rc, compressed, next) = self.walk_parts(key, index, part, None, log, |buf| {
+			result.extend_from_slice(buf);
+			all
+		})?;
+		if rc > 0 {
+			return Ok(Some((compressed, rc, next)))
+		}
+		result.truncate(start);
+		Ok(None)
+	}
+
+	/// Same as `query_into`, with the file content of the first slot already read into
+	/// `prefetched`, e.g. with `read_slots`.
+	pub fn query_prefetched(
+		&self,
+		key: &mut TableKeyQuery,
+		index: u64,
+		prefetched: Option<&[u8]>,
+		log: &impl LogQuery,
+		result: &mut Vec<u8>,
+	) -> Result<Option<(bool, u32)>> {
+		let start = result.len();
+		let (rc, compressed) = self.for_parts_prefetched(key, index, prefetched, log, |buf| {
 			result.extend_from_slice(buf);
 			true
 		})?;
 		if rc > 0 {
-			return Ok(Some((result, compressed, rc)))
+			return Ok(Some((compressed, rc)))
 		}
+		result.truncate(start);
 		Ok(None)
 	}
 
+	/// Read the file content of adjacent slots starting at `index` into `buf`, which holds a
+	/// whole number of slots, with a single read. Returns the number of slots read. Slots past
+	/// the end of the file are not read, those can only be in the log overlay.
+	pub fn read_slots(&self, index: u64, buf: &mut [u8]) -> Result<u64> {
+		let entry_size = self.entry_size as u64;
+		let capacity = self.file.capacity.load(Ordering::Relaxed);
+		let read = (buf.len() as u64 / entry_size).min(capacity.saturating_sub(index));
+		if read > 0 {
+			self.file
+				.read_at(&mut buf[..(read * entry_size) as usize], index * entry_size)?;
+		}
+		Ok(read)
+	}
+
+	/// Get value, reference counter, partial key and compression flag of the entry at `index`.
+	/// `prefetched` is the file content of the first slot, if already read with `slot_read`.
 	#[allow(clippy::type_complexity)]
 	pub fn get_with_meta(
 		&self,
 		index: u64,
+		prefetched: Option<&[u8]>,
 		log: &impl LogQuery,
 	) -> Result<Option<(Value, u32, [u8; PARTIAL_SIZE], bool)>> {
 		let mut query_key = Default::default();
-		if let Some((value, compressed, rc)) =
-			self.query(&mut TableKeyQuery::Fetch(Some(&mut query_key)), index, log)?
-		{
-			return Ok(Some((value, rc, query_key, compressed)))
+		let mut result = Vec::new();
+		let (rc, compressed) = self.for_parts_prefetched(
+			&mut TableKeyQuery::Fetch(Some(&mut query_key)),
+			index,
+			prefetched,
+			log,
+			|buf| {
+				result.extend_from_slice(buf);
+				true
+			},
+		)?;
+		if rc > 0 {
+			return Ok(Some((result, rc, query_key, compressed)))
 		}
 		Ok(None)
 	}
 
+	/// Request to read the file content of adjacent slots starting at `index` into `buf`, which
+	/// holds a whole number of slots. Slots that are not in the file yet are left out of the
+	/// request, `None` if there are none.
+	pub fn slot_read<'a>(&'a self, index: u64, buf: &'a mut [u8]) -> Option<ReadRequest<'a>> {
+		let entry_size = self.entry_size as u64;
+		let capacity = self.file.capacity.load(Ordering::Relaxed);
+		let slots = (buf.len() as u64 / entry_size).min(capacity.saturating_sub(index));
+		if slots == 0 || !self.is_init() {
+			return None
+		}
+		let buf = &mut buf[..(slots * entry_size) as usize];
+		Some(ReadRequest { file: &self.file, offset: index * entry_size, buf })
+	}
+
 	pub fn size(
 		&self,
 		key: &TableKey,
@@ -587,7 +936,7 @@ impl ValueTable {
 		Ok(buf.is_tombstone())
 	}
 
-	pub fn read_next_free(&self, index: u64, log: &LogWriter) -> Result<u64> {
+	pub fn read_next_free(&self, index: u64, log: &impl LogQuery) -> Result<u64> {
 		let mut buf = PartialEntry::new_uninit();
 		if !log.value(self.id, index, buf.as_mut()) {
 			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
@@ -596,7 +945,7 @@ impl ValueTable {
 		Ok(buf.read_next())
 	}
 
-	pub fn read_next_part(&self, index: u64, log: &LogWriter) -> Result<Option<u64>> {
+	pub fn read_next_part(&self, index: u64, log: &impl LogQuery) -> Result<Option<u64>> {
 		let mut buf = PartialEntry::new_uninit();
 		if !log.value(self.id, index, buf.as_mut()) {
 			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
@@ -609,6 +958,13 @@ impl ValueTable {
 		Ok(None)
 	}
 
+	/// Index of the next part and size of the data of the part at `index`, without reading the
+	/// data. Returns `None` for the last part of a value. Not for the first part.
+	pub fn skip_part(&self, index: u64, log: &impl LogQuery) -> Result<Option<(u64, usize)>> {
+		let next = self.read_next_part(index, log)?;
+		Ok(next.map(|next| (next, self.entry_size as usize - SIZE_SIZE - INDEX_SIZE)))
+	}
+
 	pub fn next_free(&self, log: &mut LogWriter) -> Result<u64> {
 		let filled = self.filled.load(Ordering::Relaxed);
 		let last_removed = self.last_removed.load(Ordering::Relaxed);
@@ -621,6 +977,9 @@ impl ValueTable {
 				last_removed,
 			);
 			self.last_removed.store(next_removed, Ordering::Relaxed);
+			let _ = self.free_entries.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+				(n != u64::MAX).then(|| n.saturating_sub(1))
+			});
 			last_removed
 		} else {
 			log::trace!(
@@ -632,22 +991,47 @@ impl ValueTable {
 			self.filled.store(filled + 1, Ordering::Relaxed);
 			filled
 		};
+		self.free_list_version.fetch_add(1, Ordering::SeqCst);
 		self.dirty_header.store(true, Ordering::Relaxed);
 		Ok(index)
 	}
 
+	// Write a value of `len` bytes, passing the space for each part to `fill`.
 	fn overwrite_chain(
 		&self,
 		key: &TableKey,
-		value: &[u8],
+		len: usize,
+		fill: impl FnMut(&mut [u8]),
+		log: &mut LogWriter,
+		at: Option<u64>,
+		compressed: bool,
+	) -> Result<u64> {
+		self.write_chain(key, len, fill, log, at, compressed, true)
+	}
+
+	// Same as `overwrite_chain`. Without `head` the parts continue an existing chain and the
+	// key is not written.
+	#[allow(clippy::too_many_arguments)]
+	fn write_chain(
+		&self,
+		key: &TableKey,
+		len: usize,
+		mut fill: impl FnMut(&mut [u8]),
 		log: &mut LogWriter,
 		at: Option<u64>,
 		compressed: bool,
+		head: bool,
 	) -> Result<u64> {
-		let mut remainder = value.len() + self.ref_size() + key.encoded_size();
+		let mut remainder = len;
+		if head {
+			remainder += self.ref_size() + key.encoded_size();
+		}
 		let mut offset = 0;
 		let mut start = 0;
-		assert!(self.multipart || value.len() <= self.value_size(key).unwrap() as usize);
+		assert!(self.multipart || len <= self.value_size(key).unwrap() as usize);
+		if self.multipart && at.is_some() {
+			self.rewritten.fetch_add(1, Ordering::SeqCst);
+		}
 		let (mut index, mut follow) = match at {
 			Some(index) => (index, true),
 			None => (self.next_free(log)?, false),
@@ -678,7 +1062,7 @@ impl ValueTable {
 				if !follow {
 					next_index = self.next_free(log)?
 				}
-				if start == 0 {
+				if start == 0 && head {
 					if compressed {
 						buf.write_multihead_compressed();
 					} else {
@@ -694,7 +1078,7 @@ impl ValueTable {
 				remainder
 			};
 			let init_offset = buf.offset();
-			if offset == 0 {
+			if offset == 0 && head {
 				if self.ref_counted {
 					// first rc.
 					buf.write_rc(1u32);
@@ -702,7 +1086,10 @@ impl ValueTable {
 				key.write(&mut buf);
 			}
 			let written = buf.offset() - init_offset;
-			buf.write_slice(&value[offset..offset + value_len - written]);
+			let part_start = buf.offset();
+			let part_end = part_start + value_len - written;
+			fill(&mut buf[part_start..part_end]);
+			buf.set_offset(part_end);
 			offset += value_len - written;
 			log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
 			remainder -= value_len;
@@ -723,6 +1110,7 @@ impl ValueTable {
 	}
 
 	fn clear_chain(&self, mut index: u64, log: &mut LogWriter) -> Result<()> {
+		self.rewritten.fetch_add(1, Ordering::SeqCst);
 		loop {
 			match self.read_next_part(index, log)? {
 				Some(next) => {
@@ -739,6 +1127,11 @@ impl ValueTable {
 
 	fn clear_slot(&self, index: u64, log: &mut LogWriter) -> Result<()> {
 		let last_removed = self.last_removed.load(Ordering::Relaxed);
+		if (last_removed != 0 && index > last_removed) ||
+			index + 1 == self.filled.load(Ordering::Relaxed)
+		{
+			self.vacuumed.store(false, Ordering::Relaxed);
+		}
 		log::trace!(
 			target: "parity-db",
 			"{}: Freeing slot {}",
@@ -752,10 +1145,165 @@ impl ValueTable {
 
 		log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
 		self.last_removed.store(index, Ordering::Relaxed);
+		self.free_list_version.fetch_add(1, Ordering::SeqCst);
+		let _ = self
+			.free_entries
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n != u64::MAX).then(|| n + 1));
 		self.dirty_header.store(true, Ordering::Relaxed);
 		Ok(())
 	}
 
+	// Count the removed entries by following the list.
+	fn count_free(&self, log: &impl LogQuery) -> Result<u64> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let mut count = 0;
+		let mut index = self.last_removed.load(Ordering::Relaxed);
+		while index != 0 {
+			if index >= filled || count >= filled {
+				return Err(crate::error::Error::Corruption(format!(
+					"{}: bad removed entry list at {}, {} filled entries",
+					self.id, index, filled
+				)))
+			}
+			count += 1;
+			index = self.read_next_free(index, log)?;
+		}
+		Ok(count)
+	}
+
+	/// Number of removed entries that are not reused yet. Counted by following the list of
+	/// removed entries the first time. The list may change while it is followed, in which case
+	/// it is followed again.
+	pub fn free_entries(&self, log: &impl LogQuery) -> Result<u64> {
+		loop {
+			let count = self.free_entries.load(Ordering::Relaxed);
+			if count != u64::MAX {
+				return Ok(count)
+			}
+			let version = self.free_list_version();
+			let counted = self.count_free(log);
+			if self.free_list_version() != version {
+				continue
+			}
+			let count = counted?;
+			let _ = self.free_entries.compare_exchange(
+				u64::MAX,
+				count,
+				Ordering::Relaxed,
+				Ordering::Relaxed,
+			);
+			if self.free_list_version() != version {
+				// Changed before the count was stored, so it may have missed the change.
+				self.free_entries.store(u64::MAX, Ordering::Relaxed);
+			}
+			return Ok(count)
+		}
+	}
+
+	/// Number of removed entries if they were counted already.
+	pub fn counted_free_entries(&self) -> Option<u64> {
+		let count = self.free_entries.load(Ordering::Relaxed);
+		(count != u64::MAX).then_some(count)
+	}
+
+	/// Number of changes to the list of removed entries or the number of filled entries, other
+	/// than by vacuum steps. See `FreeListVacuum`.
+	pub fn free_list_version(&self) -> u64 {
+		self.free_list_version.load(Ordering::SeqCst)
+	}
+
+	/// Number of times parts of existing multipart values were overwritten or freed. Parts
+	/// that follow a head read before are still parts of the same value if this did not change
+	/// meanwhile.
+	pub fn rewritten(&self) -> u64 {
+		self.rewritten.load(Ordering::SeqCst)
+	}
+
+	/// Size of the table kept in `Options::storage`, 0 if it is kept in a file.
+	pub fn storage_size(&self) -> Result<u64> {
+		self.file.storage_size()
+	}
+
+	/// Check if the table was changed since it was last vacuumed in a way that vacuuming
+	/// could improve.
+	pub fn needs_vacuum(&self) -> bool {
+		!self.vacuumed.load(Ordering::Relaxed)
+	}
+
+	/// Start a vacuum of the list of removed entries. Must not be called while a record is
+	/// being written, so that the list is consistent.
+	pub fn start_vacuum(&self) -> FreeListVacuum {
+		FreeListVacuum {
+			version: self.free_list_version(),
+			filled: self.filled.load(Ordering::Relaxed),
+			cursor: self.last_removed.load(Ordering::Relaxed),
+			list: Vec::new(),
+			prev: Vec::new(),
+			next: Vec::new(),
+			order: Vec::new(),
+			done: 0,
+			first: NO_ENTRY,
+			last: NO_ENTRY,
+			sorted: 0,
+			truncating: true,
+		}
+	}
+
+	/// Keep what a vacuum found once it read the whole list of removed entries: the number of
+	/// removed entries, and whether the list needs to be rewritten. Must not be called while a
+	/// record is being written. Ignored if the list changed since the vacuum started.
+	pub fn read_vacuum(&self, vacuum: &FreeListVacuum) {
+		if !vacuum.is_read() || vacuum.version != self.free_list_version() {
+			return
+		}
+		self.free_entries.store(vacuum.free_entries(), Ordering::Relaxed);
+		if !vacuum.is_needed() {
+			self.vacuumed.store(true, Ordering::Relaxed);
+		}
+	}
+
+	/// Write a step of a vacuum planned with `FreeListVacuum::plan`. Returns the number of
+	/// released entries.
+	pub fn write_vacuum_step(&self, step: &VacuumStep, log: &mut LogWriter) -> u64 {
+		for (index, next) in &step.links {
+			let mut buf = PartialEntry::new_uninit();
+			buf.write_tombstone();
+			buf.write_next(*next);
+			log.insert_value(self.id, *index, buf[0..buf.offset()].to_vec());
+		}
+		let released = self.filled.load(Ordering::Relaxed).saturating_sub(step.filled);
+		self.filled.store(step.filled, Ordering::Relaxed);
+		self.last_removed.store(step.last_removed, Ordering::Relaxed);
+		let _ = self.free_entries.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+			(n != u64::MAX).then(|| n.saturating_sub(released))
+		});
+		if step.complete {
+			self.vacuumed.store(true, Ordering::Relaxed);
+		}
+		self.dirty_header.store(true, Ordering::Relaxed);
+		log::debug!(
+			target: "parity-db",
+			"{}: Vacuumed {} removed entries, {} entries released",
+			self.id,
+			step.links.len(),
+			released,
+		);
+		released
+	}
+
+	/// Give the disk space of the entries past the filled entries back to the file system. The
+	/// table is flushed first, so that the header no longer refers to them.
+	pub fn release_free_space(&self) -> Result<()> {
+		let filled = self.filled.load(Ordering::Relaxed);
+		let capacity = self.file.capacity.load(Ordering::Relaxed);
+		if filled >= capacity {
+			return Ok(())
+		}
+		self.file.flush()?;
+		let entry_size = self.entry_size as u64;
+		self.file.release(filled * entry_size, (capacity - filled) * entry_size)
+	}
+
 	pub fn write_insert_plan(
 		&self,
 		key: &TableKey,
@@ -763,7 +1311,69 @@ impl ValueTable {
 		log: &mut LogWriter,
 		compressed: bool,
 	) -> Result<u64> {
-		self.overwrite_chain(key, value, log, None, compressed)
+		self.overwrite_chain(key, value.len(), slice_filler(value), log, None, compressed)
+	}
+
+	/// Same as `write_insert_plan` for an uncompressed value of `len` bytes, passing the space
+	/// for each part to `fill`.
+	pub fn write_insert_stream_plan(
+		&self,
+		key: &TableKey,
+		len: usize,
+		fill: impl FnMut(&mut [u8]),
+		log: &mut LogWriter,
+	) -> Result<u64> {
+		self.overwrite_chain(key, len, fill, log, None, false)
+	}
+
+	/// Append `value` to the uncompressed value of `key` at `index`, rewriting only the last
+	/// part of the chain and adding parts as needed. Returns the previous value size, or `None`
+	/// if the value is not stored in several parts, in which case nothing is written.
+	pub fn write_append_plan(
+		&self,
+		index: u64,
+		key: &TableKey,
+		value: &[u8],
+		log: &mut LogWriter,
+	) -> Result<Option<usize>> {
+		if !self.multipart || self.db_version <= 4 {
+			return Ok(None)
+		}
+		let mut buf = PartialEntry::new_uninit();
+		if !log.value(self.id, index, buf.as_mut()) {
+			self.file.read_at(buf.as_mut(), index * self.entry_size as u64)?;
+		}
+		if !buf.is_multihead() || buf.is_multihead_compressed() {
+			return Ok(None)
+		}
+		let part_size = self.entry_size as usize - SIZE_SIZE - INDEX_SIZE;
+		let mut len = part_size - self.ref_size() - key.encoded_size();
+		let mut last = index;
+		while let Some(next) = self.read_next_part(last, log)? {
+			if last != index {
+				len += part_size;
+			}
+			last = next;
+		}
+		let mut tail = FullEntry::new_uninit();
+		if !log.value(self.id, last, tail.as_mut()) {
+			self.file
+				.read_at(&mut tail[0..self.entry_size as usize], last * self.entry_size as u64)?;
+		}
+		let (tail_size, _) = tail.read_size();
+		let mut data = tail.read_slice(tail_size as usize).to_vec();
+		let tail_size = data.len();
+		data.extend_from_slice(value);
+		log::trace!(
+			target: "parity-db",
+			"{}: Appending {} bytes at slot {}: {}",
+			self.id,
+			value.len(),
+			last,
+			key,
+		);
+		self.write_chain(key, data.len(), slice_filler(&data), log, Some(last), false, false)?;
+		Ok(Some(len + tail_size))
 	}
 
 	pub fn write_replace_plan(
@@ -774,7 +1384,7 @@ impl ValueTable {
 		log: &mut LogWriter,
 		compressed: bool,
 	) -> Result<()> {
-		self.overwrite_chain(key, value, log, Some(index), compressed)?;
+		self.overwrite_chain(key, value.len(), slice_filler(value), log, Some(index), compressed)?;
 		Ok(())
 	}
 
@@ -787,6 +1397,11 @@ impl ValueTable {
 		Ok(())
 	}
 
+	/// Tombstone a single slot without following multipart chains. Used for repairs.
+	pub fn write_force_remove_plan(&self, index: u64, log: &mut LogWriter) -> Result<()> {
+		self.clear_slot(index, log)
+	}
+
 	pub fn write_inc_ref(&self, index: u64, log: &mut LogWriter) -> Result<()> {
 		self.change_ref(index, 1, log)?;
 		Ok(())
@@ -846,38 +1461,63 @@ impl ValueTable {
 	}
 
 	pub fn enact_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
-		while index >= self.file.capacity.load(Ordering::Relaxed) {
-			self.file.grow(self.entry_size)?;
-		}
-		if index == 0 {
-			let mut header = Header::default();
-			log.read(&mut header.0)?;
-			self.file.write_at(&header.0, 0)?;
-			return Ok(())
-		}
+		let mut buf = FullEntry::new_uninit();
+		let len = self.read_plan(index, log, &mut buf)?;
+		self.write_plan_data(index, &buf[0..len])
+	}
 
+	/// Read the data planned for slot `index` from the log without writing it. The data can be
+	/// written later with `write_plan_data`. Used to enact log records in parallel.
+	pub fn read_plan_data(&self, index: u64, log: &mut LogReader) -> Result<Vec<u8>> {
 		let mut buf = FullEntry::new_uninit();
+		let len = self.read_plan(index, log, &mut buf)?;
+		Ok(buf[0..len].to_vec())
+	}
+
+	// Read the data planned for slot `index` into `buf`. Returns the data length.
+	fn read_plan(&self, index: u64, log: &mut LogReader, buf: &mut FullEntry) -> Result<usize> {
+		if index == 0 {
+			let len = Header::default().0.len();
+			log.read(&mut buf[0..len])?;
+			return Ok(len)
+		}
 		log.read(&mut buf[0..SIZE_SIZE])?;
 		if buf.is_tombstone() {
 			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + INDEX_SIZE])?;
-			self.file
-				.write_at(&buf[0..SIZE_SIZE + INDEX_SIZE], index * (self.entry_size as u64))?;
 			log::trace!(target: "parity-db", "{}: Enacted tombstone in slot {}", self.id, index);
+			Ok(SIZE_SIZE + INDEX_SIZE)
 		} else if self.multipart && buf.is_multi(self.db_version) {
 			let entry_size = self.entry_size as usize;
 			log.read(&mut buf[SIZE_SIZE..entry_size])?;
-			self.file.write_at(&buf[0..entry_size], index * (entry_size as u64))?;
 			log::trace!(target: "parity-db", "{}: Enacted multipart in slot {}", self.id, index);
+			Ok(entry_size)
 		} else {
 			let (len, _compressed) = buf.read_size();
 			log.read(&mut buf[SIZE_SIZE..SIZE_SIZE + len as usize])?;
-			self.file
-				.write_at(&buf[0..(SIZE_SIZE + len as usize)], index * (self.entry_size as u64))?;
 			log::trace!(target: "parity-db", "{}: Enacted {}: {}, {} bytes", self.id, index, hex(&buf.1[6..32]), len);
+			Ok(SIZE_SIZE + len as usize)
+		}
+	}
+
+	/// Write data read with `read_plan_data` to slot `index`.
+	pub fn write_plan_data(&self, index: u64, data: &[u8]) -> Result<()> {
+		while index >= self.file.capacity.load(Ordering::Relaxed) {
+			self.file.grow(self.entry_size)?;
 		}
+		self.file.write_at(data, index * (self.entry_size as u64))?;
 		Ok(())
 	}
 
+	/// Write data read with `read_plan_data` to consecutive slots starting at `index` with a
+	/// single vectored write. All but the last slot must be written in full.
+	pub fn write_plans_data(&self, index: u64, data: &[Vec<u8>]) -> Result<()> {
+		while index + data.len() as u64 > self.file.capacity.load(Ordering::Relaxed) {
+			self.file.grow(self.entry_size)?;
+		}
+		let bufs: Vec<&[u8]> = data.iter().map(|d| d.as_slice()).collect();
+		self.file.write_vectored_at(&bufs, index * (self.entry_size as u64))
+	}
+
 	pub fn validate_plan(&self, index: u64, log: &mut LogReader) -> Result<()> {
 		if index == 0 {
 			let mut header = Header::default();
@@ -904,7 +1544,7 @@ impl ValueTable {
 	}
 
 	pub fn refresh_metadata(&self) -> Result<()> {
-		if self.file.file.read().is_none() {
+		if !self.file.exists() {
 			return Ok(())
 		}
 		let mut header = Header::default();
@@ -915,7 +1555,11 @@ impl ValueTable {
 			filled = 1;
 		}
 		self.last_removed.store(last_removed, Ordering::Relaxed);
+		self.free_entries
+			.store(if last_removed == 0 { 0 } else { u64::MAX }, Ordering::Relaxed);
+		self.vacuumed.store(last_removed == 0, Ordering::Relaxed);
 		self.filled.store(filled, Ordering::Relaxed);
+		self.free_list_version.fetch_add(1, Ordering::SeqCst);
 		Ok(())
 	}
 
@@ -947,38 +1591,142 @@ impl ValueTable {
 		}
 	}
 
+	/// Iterate over all entries in slot order. The callback receives slot index, reference
+	/// counter, value, compression flag and the stored partial key.
 	pub fn iter_while(
 		&self,
 		log: &impl LogQuery,
-		mut f: impl FnMut(u64, u32, Vec<u8>, bool) -> bool,
+		f: impl FnMut(u64, u32, Vec<u8>, bool, &[u8; PARTIAL_SIZE]) -> bool,
 	) -> Result<()> {
-		let filled = self.filled.load(Ordering::Relaxed);
-		for index in 1..filled {
-			let mut result = Vec::new();
-			// expect only indexed key.
-			let mut _fetch_key = Default::default();
-			match self.for_parts(
-				&mut TableKeyQuery::Fetch(Some(&mut _fetch_key)),
-				index,
-				log,
-				|buf| {
-					result.extend_from_slice(buf);
-					true
-				},
-			) {
-				Ok((rc, compressed)) =>
-					if rc > 0 && !f(index, rc, result, compressed) {
-						break
-					},
-				Err(crate::error::Error::InvalidValueData) => (), // ignore, can be external index.
-				Err(e) => return Err(e),
+		self.iter_range_while(1..u64::MAX, log, f)
+	}
+
+	/// Same as `iter_while`, over slots `slots` only.
+	pub fn iter_range_while(
+		&self,
+		slots: std::ops::Range<u64>,
+		log: &impl LogQuery,
+		mut f: impl FnMut(u64, u32, Vec<u8>, bool, &[u8; PARTIAL_SIZE]) -> bool,
+	) -> Result<()> {
+		let filled = self.filled.load(Ordering::Relaxed).min(slots.end);
+		let entry_size = self.entry_size as u64;
+		// Adjacent slots are read from the file in batches.
+		let batch_entries = (READ_BATCH_BYTES / entry_size).max(1);
+		let mut batch = vec![0u8; (batch_entries * entry_size) as usize];
+		let mut index = slots.start.max(1);
+		while index < filled {
+			let count = batch_entries.min(filled - index);
+			// The batch is only used while no overlay entries were dropped since it was read.
+			// Otherwise a slot that was missing from the overlay may have been written after
+			// the batch was read.
+			let generation = log.generation();
+			let read = self.read_slots(index, &mut batch[..(count * entry_size) as usize])?;
+			let mut done = count;
+			for i in 0..count {
+				let offset = (i * entry_size) as usize;
+				let prefetched = (i < read).then(|| &batch[offset..offset + entry_size as usize]);
+				let mut result = Vec::new();
+				// expect only indexed key.
+				let mut fetch_key = Default::default();
+				let mut read_slot = |prefetched| {
+					result.clear();
+					self.for_parts_prefetched(
+						&mut TableKeyQuery::Fetch(Some(&mut fetch_key)),
+						index + i,
+						prefetched,
+						log,
+						|buf| {
+							result.extend_from_slice(buf);
+							true
+						},
+					)
+				};
+				let mut outcome = read_slot(prefetched);
+				if log.generation() != generation {
+					// Read the slot again from the file and start a new batch after it.
+					outcome = read_slot(None);
+					done = i + 1;
+				}
+				match outcome {
+					Ok((rc, compressed)) =>
+						if rc > 0 && !f(index + i, rc, result, compressed, &fetch_key) {
+							return Ok(())
+						},
+					// ignore, can be external index.
+					Err(crate::error::Error::InvalidValueData) => (),
+					Err(e) => return Err(e),
+				}
+				if done != count {
+					break
+				}
 			}
+			index += done;
 		}
 		Ok(())
 	}
 
+	/// Hint that slots are about to be read in order, or with `false` that reads are back to
+	/// random.
+	pub fn advise_sequential(&self, sequential: bool) {
+		self.file.advise_sequential(sequential);
+	}
+
+	/// Which pages of the table file are in the OS page cache now, see `TableFile::residency`.
+	pub fn residency(&self) -> Option<Residency> {
+		self.file.residency()
+	}
+
+	/// Drop the pages that only hold slots `slots` and were not cached according to
+	/// `residency` from the OS page cache.
+	pub fn drop_uncached(&self, residency: &Residency, slots: std::ops::Range<u64>) {
+		let entry_size = self.entry_size as u64;
+		self.file.drop_uncached(
+			residency,
+			slots.start * entry_size,
+			(slots.end - slots.start) * entry_size,
+		);
+	}
+
+	/// Drop all pages of the table that were not cached according to `residency` from the OS
+	/// page cache.
+	pub fn drop_all_uncached(&self, residency: &Residency) {
+		self.file.drop_uncached(residency, 0, u64::MAX);
+	}
+
+	/// Drop the pages of all parts of the value at `index` that were not cached according to
+	/// `residency` from the OS page cache. Does nothing for slots smaller than a page, since
+	/// their pages also hold other values.
+	pub fn drop_uncached_value(&self, residency: &Residency, mut index: u64, log: &impl LogQuery) {
+		if (self.entry_size as u64) < crate::file::PAGE_SIZE {
+			return
+		}
+		loop {
+			// Read before the part is dropped, so that it is not read back into the cache.
+			let next = self.read_next_part(index, log);
+			self.drop_uncached(residency, index..index + 1);
+			match next {
+				Ok(Some(next)) if next != 0 => index = next,
+				_ => break,
+			}
+		}
+	}
+
+	/// Number of allocated entries, including removed entries that are not reused yet.
+	pub fn filled_entries(&self) -> u64 {
+		self.filled.load(Ordering::Relaxed).saturating_sub(1)
+	}
+
 	pub fn is_init(&self) -> bool {
-		self.file.file.read().is_some()
+		self.file.exists()
+	}
+
+	pub fn mapped_bytes(&self) -> u64 {
+		self.file.mapped_bytes()
+	}
+
+	/// Size of the table file.
+	pub fn file_bytes(&self) -> u64 {
+		self.file.capacity.load(Ordering::Relaxed) * self.entry_size as u64
 	}
 
 	pub fn init_with_entry(&self, entry: &[u8]) -> Result<()> {
@@ -986,7 +1734,14 @@ impl ValueTable {
 
 		let empty_overlays = parking_lot::RwLock::new(Default::default());
 		let mut log = LogWriter::new(&empty_overlays, 0);
-		let at = self.overwrite_chain(&TableKey::NoHash, entry, &mut log, None, false)?;
+		let at = self.overwrite_chain(
+			&TableKey::NoHash,
+			entry.len(),
+			slice_filler(entry),
+			&mut log,
+			None,
+			false,
+		)?;
 		self.complete_plan(&mut log)?;
 		assert!(at == 1);
 		let log = log.drain();
@@ -1112,12 +1867,13 @@ mod test {
 
 		fn table(&self, size: Option<u16>, options: &ColumnOptions) -> ValueTable {
 			let id = TableId::new(0, 0);
-			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION).unwrap()
+			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION, Default::default())
+				.unwrap()
 		}
 
 		fn log(&self) -> Log {
-			let options = Options::with_columns(&*self.0, 1);
-			Log::open(&options).unwrap()
+			let options = Options::with_columns(&self.0, 1);
+			Log::open(&options, CURRENT_VERSION).unwrap()
 		}
 	}
 
@@ -1143,7 +1899,9 @@ mod test {
 			match reader.next().unwrap() {
 				LogAction::BeginRecord |
 				LogAction::InsertIndex { .. } |
-				LogAction::DropTable { .. } => {
+				LogAction::DropTable { .. } |
+				LogAction::Checkpoint { .. } |
+				LogAction::CheckpointIndex { .. } => {
 					panic!("Unexpected log entry");
 				},
 				LogAction::EndRecord => {
@@ -1174,16 +1932,13 @@ mod test {
 
 	fn value(size: usize) -> Value {
 		use rand::RngCore;
-		let mut result = Vec::with_capacity(size);
-		result.resize(size, 0);
+		let mut result = vec![0; size];
 		rand::thread_rng().fill_bytes(&mut result);
 		result
 	}
 
 	fn rc_options() -> ColumnOptions {
-		let mut result = ColumnOptions::default();
-		result.ref_counted = true;
-		result
+		ColumnOptions { ref_counted: true, ..Default::default() }
 	}
 
 	#[test]
@@ -1392,6 +2147,79 @@ mod test {
 		}
 	}
 
+	#[test]
+	fn multipart_read_ahead() {
+		use crate::{file::FileOptions, MemoryStorage};
+		use std::sync::atomic::Ordering;
+
+		// Number of read-ahead hints given while reading a contiguous chain and a chain of
+		// reused parts.
+		fn read_ahead(name: &'static str, file_options: FileOptions) -> (u64, u64) {
+			let dir = TempDir::new(name);
+			let table = ValueTable::open(
+				dir.0.clone(),
+				TableId::new(0, 0),
+				None,
+				&Default::default(),
+				CURRENT_VERSION,
+				file_options,
+			)
+			.unwrap();
+			let log = dir.log();
+			let prefetched = || table.file.prefetched.load(Ordering::Relaxed);
+			let key1 = &TableKey::Partial(key(1));
+			let key2 = &TableKey::Partial(key(2));
+			let val1 = value(20000);
+			let val2 = value(10000);
+
+			let mut index = 0;
+			write_ops(&table, &log, |writer| {
+				index = table.write_insert_plan(key1, &val1, writer, false).unwrap();
+			});
+			let start = prefetched();
+			assert_eq!(table.get(key1, index, log.overlays()).unwrap(), Some((val1, false)));
+			let contiguous = prefetched() - start;
+
+			// Parts are taken from the list of removed entries in reverse order.
+			write_ops(&table, &log, |writer| {
+				table.write_remove_plan(index, writer).unwrap();
+				index = table.write_insert_plan(key2, &val2, writer, false).unwrap();
+			});
+			let start = prefetched();
+			assert_eq!(table.get(key2, index, log.overlays()).unwrap(), Some((val2, false)));
+			(contiguous, prefetched() - start)
+		}
+
+		let (contiguous, reused) = read_ahead("multipart_read_ahead", Default::default());
+		if cfg!(target_os = "linux") {
+			assert_eq!(contiguous, 1);
+		}
+		assert_eq!(reused, 0);
+
+		// Hints don't apply to mapped files, direct I/O and custom storage.
+		let mmap = FileOptions { mmap: true, ..Default::default() };
+		assert_eq!(read_ahead("multipart_read_ahead_mmap", mmap), (0, 0));
+		let storage = FileOptions {
+			storage: Some(std::sync::Arc::new(MemoryStorage::new())),
+			..Default::default()
+		};
+		assert_eq!(read_ahead("multipart_read_ahead_storage", storage), (0, 0));
+		let direct = FileOptions { direct_io: true, ..Default::default() };
+		let dir = TempDir::new("multipart_read_ahead_probe");
+		let probe = crate::file::TableFile::open(
+			dir.0.join("probe"),
+			64,
+			TableId::new(0, 0),
+			direct.clone(),
+		)
+		.unwrap();
+		probe.grow(64).unwrap();
+		// Falls back to cached I/O if the file system does not support direct I/O.
+		if probe.is_direct() {
+			assert_eq!(read_ahead("multipart_read_ahead_direct", direct), (0, 0));
+		}
+	}
+
 	#[test]
 	fn ref_underflow() {
 		let dir = TempDir::new("ref_underflow");
@@ -1455,4 +2283,46 @@ mod test {
 		});
 		assert_eq!(table.get(key, 1, log.overlays()).unwrap(), Some((val, compressed)));
 	}
+
+	#[test]
+	fn iter_reads_in_batches() {
+		let dir = TempDir::new("iter_reads_in_batches");
+		let table = dir.table(Some(ENTRY_SIZE), &Default::default());
+		let log = dir.log();
+
+		let values: Vec<_> = (0..2510u32).map(|k| (TableKey::Partial(key(k)), value(20))).collect();
+		write_ops(&table, &log, |writer| {
+			for (key, value) in &values[..2500] {
+				table.write_insert_plan(key, value, writer, false).unwrap();
+			}
+		});
+		// Not enacted, only in the log overlay.
+		let mut writer = log.begin_record();
+		for (key, value) in &values[2500..] {
+			table.write_insert_plan(key, value, &mut writer, false).unwrap();
+		}
+		log.end_record(writer.drain()).unwrap();
+
+		let mut found = Vec::new();
+		table
+			.iter_while(log.overlays(), |index, _rc, value, _compressed, _key| {
+				found.push((index, value));
+				true
+			})
+			.unwrap();
+		assert_eq!(found.len(), values.len());
+		for (i, (index, value)) in found.into_iter().enumerate() {
+			assert_eq!(index, i as u64 + 1);
+			assert_eq!(value, values[i].1);
+		}
+
+		let mut count = 0;
+		table
+			.iter_while(log.overlays(), |_, _, _, _, _| {
+				count += 1;
+				count < 1500
+			})
+			.unwrap();
+		assert_eq!(count, 1500);
+	}
 }
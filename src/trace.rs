@@ -0,0 +1,203 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `tracing` spans around database operations, with a warning event for operations that take
+//! longer than their threshold in `Options::slow_ops`. Without the `tracing` feature the
+//! timers do nothing.
+
+use crate::options::SlowOpThresholds;
+
+/// Traced operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+	/// Checking the changes of queued commits and writing them to the log.
+	Commit,
+	/// Handing written log files over to enactment.
+	LogFlush,
+	/// Applying a log record to the tables.
+	Enact,
+	/// Reading a value from a value table.
+	ValueRead,
+	/// Writing a reindex record.
+	Reindex,
+}
+
+#[cfg(feature = "tracing")]
+impl Op {
+	fn name(&self) -> &'static str {
+		match self {
+			Op::Commit => "commit",
+			Op::LogFlush => "log_flush",
+			Op::Enact => "enact",
+			Op::ValueRead => "value_read",
+			Op::Reindex => "reindex",
+		}
+	}
+
+	fn threshold(&self, thresholds: &SlowOpThresholds) -> Option<std::time::Duration> {
+		match self {
+			Op::Commit => thresholds.commit,
+			Op::LogFlush => thresholds.log_flush,
+			Op::Enact => thresholds.enact,
+			Op::ValueRead => thresholds.value_read,
+			Op::Reindex => thresholds.reindex,
+		}
+	}
+
+	fn span(&self) -> tracing::Span {
+		match self {
+			Op::Commit => tracing::debug_span!(target: "parity-db", "commit"),
+			Op::LogFlush => tracing::debug_span!(target: "parity-db", "log_flush"),
+			Op::Enact => tracing::debug_span!(target: "parity-db", "enact"),
+			Op::ValueRead => tracing::debug_span!(target: "parity-db", "value_read"),
+			Op::Reindex => tracing::debug_span!(target: "parity-db", "reindex"),
+		}
+	}
+}
+
+/// Span of an operation, entered until the timer is finished or dropped. Operations that
+/// fail are dropped and not reported.
+#[cfg(feature = "tracing")]
+pub struct OpTimer {
+	op: Op,
+	start: std::time::Instant,
+	threshold: Option<std::time::Duration>,
+	_span: tracing::span::EnteredSpan,
+}
+
+#[cfg(feature = "tracing")]
+impl OpTimer {
+	pub fn start(op: Op, thresholds: &SlowOpThresholds) -> OpTimer {
+		OpTimer {
+			op,
+			start: std::time::Instant::now(),
+			threshold: op.threshold(thresholds),
+			_span: op.span().entered(),
+		}
+	}
+
+	// Time taken, if the operation is slow.
+	fn slow(&self) -> Option<std::time::Duration> {
+		let elapsed = self.start.elapsed();
+		self.threshold.filter(|threshold| elapsed >= *threshold).map(|_| elapsed)
+	}
+
+	/// Finish an operation on entry `index` of `table`.
+	pub fn finish(self, table: &dyn std::fmt::Display, index: u64) {
+		if let Some(elapsed) = self.slow() {
+			tracing::warn!(
+				target: "parity-db",
+				op = self.op.name(),
+				elapsed_us = elapsed.as_micros() as u64,
+				table = %table,
+				index,
+				"Slow operation",
+			);
+		}
+	}
+
+	/// Finish an operation on log record `record`.
+	pub fn finish_record(self, record: u64) {
+		if let Some(elapsed) = self.slow() {
+			tracing::warn!(
+				target: "parity-db",
+				op = self.op.name(),
+				elapsed_us = elapsed.as_micros() as u64,
+				record,
+				"Slow operation",
+			);
+		}
+	}
+
+	/// Finish an operation that is not tied to a table entry or log record.
+	pub fn finish_op(self) {
+		if let Some(elapsed) = self.slow() {
+			tracing::warn!(
+				target: "parity-db",
+				op = self.op.name(),
+				elapsed_us = elapsed.as_micros() as u64,
+				"Slow operation",
+			);
+		}
+	}
+}
+
+#[cfg(not(feature = "tracing"))]
+pub struct OpTimer;
+
+#[cfg(not(feature = "tracing"))]
+impl OpTimer {
+	#[inline]
+	pub fn start(_op: Op, _thresholds: &SlowOpThresholds) -> OpTimer {
+		OpTimer
+	}
+
+	#[inline]
+	pub fn finish(self, _table: &dyn std::fmt::Display, _index: u64) {}
+
+	#[inline]
+	pub fn finish_record(self, _record: u64) {}
+
+	#[inline]
+	pub fn finish_op(self) {}
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+	use super::{Op, OpTimer};
+	use crate::options::SlowOpThresholds;
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+		time::Duration,
+	};
+
+	// Counts warning events.
+	struct Subscriber(Arc<AtomicUsize>);
+
+	impl tracing::Subscriber for Subscriber {
+		fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+			true
+		}
+		fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+			tracing::span::Id::from_u64(1)
+		}
+		fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+		fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+		fn event(&self, event: &tracing::Event<'_>) {
+			if *event.metadata().level() == tracing::Level::WARN {
+				self.0.fetch_add(1, Ordering::SeqCst);
+			}
+		}
+		fn enter(&self, _span: &tracing::span::Id) {}
+		fn exit(&self, _span: &tracing::span::Id) {}
+	}
+
+	#[test]
+	fn slow_ops() {
+		let events = Arc::new(AtomicUsize::new(0));
+		let thresholds =
+			SlowOpThresholds { value_read: Some(Duration::ZERO), ..Default::default() };
+		tracing::subscriber::with_default(Subscriber(events.clone()), || {
+			OpTimer::start(Op::ValueRead, &thresholds).finish(&"01-06", 7);
+			OpTimer::start(Op::Enact, &thresholds).finish_record(1);
+			drop(OpTimer::start(Op::ValueRead, &thresholds));
+		});
+		assert_eq!(events.load(Ordering::SeqCst), 1);
+	}
+}
@@ -0,0 +1,123 @@
+// Copyright 2015-2022 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Typed access to a column, enabled with the `typed` feature.
+//!
+//! Keys are SCALE encoded. Columns with `ColumnOptions::uniform` set require uniformly
+//! distributed 32 byte keys, so there the encoded key is hashed with blake2b-256 and can't be
+//! recovered from the database. Values are serialized with `postcard`.
+
+use crate::{
+	column::ColId,
+	error::{Error, Result},
+	Db, Value,
+};
+use parity_scale_codec::Encode;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+/// Column of `db` storing values of type `V` under keys of type `K`.
+pub struct TypedColumn<'a, K, V> {
+	db: &'a Db,
+	col: ColId,
+	hash_keys: bool,
+	_marker: PhantomData<fn(&K) -> V>,
+}
+
+impl<'a, K: Encode, V: Serialize + DeserializeOwned> TypedColumn<'a, K, V> {
+	/// Access column `col` of `db`.
+	pub fn new(db: &'a Db, col: ColId) -> Result<Self> {
+		let options = db.column_options(col)?;
+		Ok(TypedColumn { db, col, hash_keys: options.uniform, _marker: PhantomData })
+	}
+
+	pub fn col(&self) -> ColId {
+		self.col
+	}
+
+	/// Database key for `key`.
+	pub fn key(&self, key: &K) -> Vec<u8> {
+		let encoded = key.encode();
+		if self.hash_keys {
+			blake2_rfc::blake2b::blake2b(32, &[], &encoded).as_bytes().to_vec()
+		} else {
+			encoded
+		}
+	}
+
+	pub fn get(&self, key: &K) -> Result<Option<V>> {
+		self.db.get(self.col, &self.key(key))?.map(|value| decode(&value)).transpose()
+	}
+
+	pub fn contains(&self, key: &K) -> Result<bool> {
+		Ok(self.db.get_size(self.col, &self.key(key))?.is_some())
+	}
+
+	/// Insert a value in a commit of its own. Use `change` to commit several changes at once.
+	pub fn insert(&self, key: &K, value: &V) -> Result<()> {
+		self.db.commit(std::iter::once(self.change(key, Some(value))?))
+	}
+
+	/// Remove a value in a commit of its own.
+	pub fn remove(&self, key: &K) -> Result<()> {
+		self.db.commit(std::iter::once(self.change(key, None)?))
+	}
+
+	/// Change to pass to `Db::commit` that sets `key` to `value`, or removes it if `None`.
+	pub fn change(&self, key: &K, value: Option<&V>) -> Result<(ColId, Vec<u8>, Option<Value>)> {
+		Ok((self.col, self.key(key), value.map(encode).transpose()?))
+	}
+}
+
+fn encode<V: Serialize>(value: &V) -> Result<Value> {
+	postcard::to_stdvec(value)
+		.map_err(|e| Error::InvalidInput(format!("Can't serialize value: {}", e)))
+}
+
+fn decode<V: DeserializeOwned>(value: &[u8]) -> Result<V> {
+	postcard::from_bytes(value)
+		.map_err(|e| Error::Corruption(format!("Can't deserialize value: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::TypedColumn;
+	use crate::{Db, Options};
+	use parity_scale_codec::Encode;
+
+	#[test]
+	fn typed_column() {
+		let tmp = tempfile::tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		for col in 0..2 {
+			let column = TypedColumn::<(u32, String), Vec<u64>>::new(&db, col).unwrap();
+			let key = (7, "seven".to_string());
+			assert_eq!(column.key(&key).len(), if col == 1 { 32 } else { 10 });
+			assert_eq!(column.get(&key).unwrap(), None);
+			column.insert(&key, &vec![1, 2, 3]).unwrap();
+			assert_eq!(column.get(&key).unwrap(), Some(vec![1, 2, 3]));
+			assert!(column.contains(&key).unwrap());
+			db.commit([column.change(&key, None).unwrap()]).unwrap();
+			assert!(!column.contains(&key).unwrap());
+		}
+		db.commit([(0, 1u8.encode(), Some(vec![0xff]))]).unwrap();
+		let column = TypedColumn::<u8, String>::new(&db, 0).unwrap();
+		assert!(column.get(&1).is_err());
+		assert!(TypedColumn::<u8, String>::new(&db, 2).is_err());
+	}
+}